@@ -0,0 +1,25 @@
+use std::env;
+use std::process::Command;
+
+// Versions of LLVM which changed a function of the C API we use, after LLVM 7 which llvm-sys 70 is written for
+const API_CHANGES: &[u32] = &[8, 10, 12];
+
+fn llvm_major_version() -> Option<u32>
+{
+    // llvm-sys passes the llvm-config it found on to the crates depending on it
+    let llvm_config = env::var("DEP_LLVM_7_CONFIG_PATH").unwrap_or_else(|_| "llvm-config".into());
+    let output = Command::new(llvm_config).arg("--version").output().ok()?;
+    let version = String::from_utf8(output.stdout).ok()?;
+    version.split('.').next()?.trim().parse().ok()
+}
+
+fn main()
+{
+    let major = llvm_major_version().unwrap_or(7);
+    for version in API_CHANGES {
+        println!("cargo:rustc-check-cfg=cfg(llvm_at_least_{})", version);
+        if major >= *version {
+            println!("cargo:rustc-cfg=llvm_at_least_{}", version);
+        }
+    }
+}
@@ -46,4 +46,8 @@ done
 echo "Tests:"
 echo "  fail:    ${fail_count}"
 echo "  success: ${success_count}"
+
+if ! cargo run ${mode} -- test --run-examples testcode/examples; then
+	fail_count=$((fail_count + 1))
+fi
 exit ${fail_count}
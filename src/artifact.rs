@@ -0,0 +1,154 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use toml;
+
+use compileerror::{CompileResult, CompileError};
+use llvmbackend::OutputType;
+use packagebuild::output_file_name;
+
+/*
+A precompiled library package is a directory named <name>-<version>, containing:
+- menhir-package.toml: the metadata
+- <name>.mhr.exports: the interface of the library
+- lib<name>.a or lib<name>.so: the code
+
+Packages are looked up per target triplet, in <import directory>/<triplet>/<name>-<version>,
+so libraries can be distributed without their source code.
+*/
+
+pub const METADATA_FILE: &str = "menhir-package.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactMetadata
+{
+    pub name: String,
+    pub version: String,
+    pub target: String,
+    pub output_type: OutputType,
+}
+
+impl ArtifactMetadata
+{
+    pub fn load(dir: &Path) -> CompileResult<ArtifactMetadata>
+    {
+        let path = dir.join(METADATA_FILE);
+        let mut data = String::new();
+        File::open(&path)?.read_to_string(&mut data)?;
+        toml::from_str(&data)
+            .map_err(|e| CompileError::Other(format!("Failed to decode {}: {}", path.to_string_lossy(), e)))
+    }
+
+    pub fn exports_file(&self) -> String
+    {
+        format!("{}.mhr.exports", self.name)
+    }
+
+    pub fn library_file(&self) -> String
+    {
+        output_file_name(&self.name, self.output_type)
+    }
+
+    pub fn dir_name(&self) -> String
+    {
+        format!("{}-{}", self.name, self.version)
+    }
+}
+
+// A dependency of a package target, either name or name@version
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Dependency
+{
+    pub name: String,
+    pub version: Option<String>,
+}
+
+pub fn parse_dependency(dep: &str) -> Dependency
+{
+    match dep.find('@') {
+        Some(idx) => Dependency{name: dep[..idx].trim().into(), version: Some(dep[idx + 1..].trim().into())},
+        None => Dependency{name: dep.trim().into(), version: None},
+    }
+}
+
+// Versions are compared component by component, so 1.10 is newer then 1.9
+fn version_key(version: &str) -> Vec<u64>
+{
+    version.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+// Copy the output of a library build into a package directory, next to the build directory
+pub fn create_artifact(build_dir: &Path, meta: &ArtifactMetadata) -> CompileResult<PathBuf>
+{
+    if meta.version.is_empty() {
+        return Err(CompileError::Other(format!("Cannot create a package for {} without a version", meta.name)));
+    }
+
+    let dir = build_dir.parent().unwrap_or(build_dir).join(meta.dir_name());
+    fs::create_dir_all(&dir)?;
+    fs::copy(build_dir.join(meta.exports_file()), dir.join(meta.exports_file()))?;
+    fs::copy(build_dir.join(meta.library_file()), dir.join(meta.library_file()))?;
+
+    let data = toml::to_string(meta)
+        .map_err(|e| CompileError::Other(format!("Failed to encode package metadata: {}", e)))?;
+    let mut file = File::create(dir.join(METADATA_FILE))?;
+    file.write_all(data.as_bytes())?;
+    Ok(dir)
+}
+
+// Find the newest package in import_dir matching dep, returns the package directory and its metadata
+pub fn find_artifact(import_dir: &Path, target_triplet: &str, dep: &Dependency) -> CompileResult<Option<(PathBuf, ArtifactMetadata)>>
+{
+    let dir = import_dir.join(target_triplet);
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+
+    let prefix = format!("{}-", dep.name);
+    let mut best: Option<(PathBuf, ArtifactMetadata)> = None;
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        let is_candidate = path.file_name()
+            .map(|name| name.to_string_lossy().starts_with(&prefix))
+            .unwrap_or(false);
+        if !is_candidate || !path.join(METADATA_FILE).exists() {
+            continue;
+        }
+
+        let meta = ArtifactMetadata::load(&path)?;
+        if meta.name != dep.name || dep.version.as_ref().map(|v| *v != meta.version).unwrap_or(false) {
+            continue;
+        }
+
+        if meta.target != target_triplet {
+            return Err(CompileError::Other(format!("Package {} in {} is built for {}, not for {}",
+                meta.name, path.to_string_lossy(), meta.target, target_triplet)));
+        }
+
+        let newer = match best {
+            Some((_, ref b)) => version_key(&meta.version) > version_key(&b.version),
+            None => true,
+        };
+
+        if newer {
+            best = Some((path, meta));
+        }
+    }
+
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{parse_dependency, version_key, Dependency};
+
+    #[test]
+    fn test_dependencies()
+    {
+        assert_eq!(parse_dependency("foo"), Dependency{name: "foo".into(), version: None});
+        assert_eq!(parse_dependency("foo@1.2.0"), Dependency{name: "foo".into(), version: Some("1.2.0".into())});
+        assert!(version_key("1.10.0") > version_key("1.9.3"));
+        assert!(version_key("2") > version_key("1.9"));
+    }
+}
@@ -1,5 +1,6 @@
+use std::fmt;
 use itertools::free::join;
-use ast::{Expression, Type, TreePrinter, prefix, StructPattern};
+use ast::{Expression, Type, TreePrinter, prefix, StructPattern, StructPatternBinding};
 use span::{Span};
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -7,6 +8,70 @@ pub enum BindingType
 {
     Name(String),
     Struct(StructPattern),
+    Tuple(TuplePattern),
+}
+
+// let (quot, rem) = divmod(a, b), the elements are bound to the members of a struct in order
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct TuplePattern
+{
+    pub elements: Vec<BindingType>,
+    pub typ: Type,
+    pub span: Span,
+}
+
+impl BindingType
+{
+    // The names a binding defines, _ doesn't define anything
+    pub fn names(&self) -> Vec<&str>
+    {
+        match *self {
+            BindingType::Name(ref name) if name == "_" => Vec::new(),
+            BindingType::Name(ref name) => vec![&name[..]],
+            BindingType::Struct(ref s) => s.bindings.iter().map(|b| &b.name[..]).filter(|name| *name != "_").collect(),
+            BindingType::Tuple(ref t) => t.elements.iter().flat_map(|e| e.names()).collect(),
+        }
+    }
+
+    // The same pattern, which only binds name
+    pub fn only_binding(&self, name: &str) -> BindingType
+    {
+        match *self {
+            BindingType::Name(ref n) if n == name => BindingType::Name(n.clone()),
+            BindingType::Name(_) => BindingType::Name("_".into()),
+            BindingType::Struct(ref s) => {
+                let mut s = s.clone();
+                s.bindings = s.bindings.into_iter()
+                    .map(|b| if b.name == name {b} else {StructPatternBinding{name: "_".into(), ..b}})
+                    .collect();
+                BindingType::Struct(s)
+            },
+            BindingType::Tuple(ref t) => {
+                BindingType::Tuple(tuple_pattern(t.elements.iter().map(|e| e.only_binding(name)).collect(), t.span.clone()))
+            },
+        }
+    }
+}
+
+impl fmt::Display for BindingType
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            BindingType::Name(ref name) => write!(f, "{}", name),
+            BindingType::Struct(ref s) => write!(f, "{}{{{}}}", s.name, join(s.bindings.iter(), ", ")),
+            BindingType::Tuple(ref t) => write!(f, "({})", join(t.elements.iter(), ", ")),
+        }
+    }
+}
+
+pub fn tuple_pattern(elements: Vec<BindingType>, span: Span) -> TuplePattern
+{
+    TuplePattern{
+        elements: elements,
+        typ: Type::Unknown,
+        span: span,
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -27,6 +92,7 @@ pub struct GlobalBinding
     pub init: Expression,
     pub typ: Type,
     pub span: Span,
+    pub thread_local: bool,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -75,6 +141,7 @@ pub fn global_binding(name: String, init: Expression, mutable: bool, span: Span)
         init: init,
         typ: Type::Unknown,
         span: span,
+        thread_local: false,
     }
 }
 
@@ -101,6 +168,10 @@ impl TreePrinter for Binding
                 println!("{}struct binding {{{}}} =",
                     p, join(s.bindings.iter(), ","));
             },
+
+            BindingType::Tuple(_) => {
+                println!("{}tuple binding {} ({}) =", p, self.binding_type, self.span);
+            },
         }
 
         self.init.print(level + 1);
@@ -112,7 +183,7 @@ impl TreePrinter for GlobalBinding
     fn print(&self, level: usize)
     {
         let p = prefix(level);
-        println!("{}global {} ({}) =", p, self.name, self.span);
+        println!("{}global {}{} ({}) =", p, if self.thread_local {"thread_local "} else {""}, self.name, self.span);
         self.init.print(level + 1);
     }
 }
@@ -1,11 +1,248 @@
-use ast::{Type, IntSize, Expression, TreePrinter, prefix};
+use std::fmt;
+use ast::{Type, IntSize, Expression, TreePrinter, prefix, slice_type};
 use span::Span;
 
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum FieldQuery
+{
+    Names,
+    Types,
+    Count,
+}
+
+impl fmt::Display for FieldQuery
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            FieldQuery::Names => write!(f, "field_names"),
+            FieldQuery::Types => write!(f, "field_types"),
+            FieldQuery::Count => write!(f, "field_count"),
+        }
+    }
+}
+
+// Calls to @debug, @info, @warn and @error below the log level of the build are left out at compile time
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel
+{
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel
+{
+    pub fn from_name(name: &str) -> Option<LogLevel>
+    {
+        match name {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LogLevel
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            LogLevel::Debug => write!(f, "debug"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Error => write!(f, "error"),
+        }
+    }
+}
+
+// Environment variable with the seed of the random number generator behind @random
+pub const RANDOM_SEED_VARIABLE: &str = "MENHIR_SEED";
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Intrinsic
+{
+    Likely,
+    Unlikely,
+    Prefetch,
+    Select,
+    ConstantTimeEq,
+    ConstantTimeSelect,
+    ToLittleEndian,
+    ToBigEndian,
+    FromLittleEndian,
+    FromBigEndian,
+    LoadLittleEndian,
+    LoadBigEndian,
+    StoreLittleEndian,
+    StoreBigEndian,
+    CountOnes,
+    LeadingZeros,
+    TrailingZeros,
+    RotateLeft,
+    RotateRight,
+    Random,
+    ParseInt,
+    ParseFloat,
+    IntToString,
+    FloatToString,
+    HexEncode,
+    HexDecode,
+    FromUtf8,
+}
+
+impl Intrinsic
+{
+    pub fn from_name(name: &str) -> Option<Intrinsic>
+    {
+        match name {
+            "likely" => Some(Intrinsic::Likely),
+            "unlikely" => Some(Intrinsic::Unlikely),
+            "prefetch" => Some(Intrinsic::Prefetch),
+            "select" => Some(Intrinsic::Select),
+            "ct_eq" => Some(Intrinsic::ConstantTimeEq),
+            "ct_select" => Some(Intrinsic::ConstantTimeSelect),
+            "to_le" => Some(Intrinsic::ToLittleEndian),
+            "to_be" => Some(Intrinsic::ToBigEndian),
+            "from_le" => Some(Intrinsic::FromLittleEndian),
+            "from_be" => Some(Intrinsic::FromBigEndian),
+            "load_le" => Some(Intrinsic::LoadLittleEndian),
+            "load_be" => Some(Intrinsic::LoadBigEndian),
+            "store_le" => Some(Intrinsic::StoreLittleEndian),
+            "store_be" => Some(Intrinsic::StoreBigEndian),
+            "count_ones" => Some(Intrinsic::CountOnes),
+            "leading_zeros" => Some(Intrinsic::LeadingZeros),
+            "trailing_zeros" => Some(Intrinsic::TrailingZeros),
+            "rotate_left" => Some(Intrinsic::RotateLeft),
+            "rotate_right" => Some(Intrinsic::RotateRight),
+            "random" => Some(Intrinsic::Random),
+            "parse_int" => Some(Intrinsic::ParseInt),
+            "parse_float" => Some(Intrinsic::ParseFloat),
+            "int_to_string" => Some(Intrinsic::IntToString),
+            "float_to_string" => Some(Intrinsic::FloatToString),
+            "hex_encode" => Some(Intrinsic::HexEncode),
+            "hex_decode" => Some(Intrinsic::HexDecode),
+            "from_utf8" => Some(Intrinsic::FromUtf8),
+            _ => None,
+        }
+    }
+
+    pub fn num_args(&self) -> usize
+    {
+        match *self {
+            Intrinsic::Random => 0,
+            Intrinsic::Likely |
+            Intrinsic::Unlikely |
+            Intrinsic::Prefetch |
+            Intrinsic::ToLittleEndian |
+            Intrinsic::ToBigEndian |
+            Intrinsic::FromLittleEndian |
+            Intrinsic::FromBigEndian |
+            Intrinsic::CountOnes |
+            Intrinsic::LeadingZeros |
+            Intrinsic::TrailingZeros |
+            Intrinsic::ParseInt |
+            Intrinsic::ParseFloat |
+            Intrinsic::HexEncode |
+            Intrinsic::HexDecode |
+            Intrinsic::FromUtf8 => 1,
+            Intrinsic::ConstantTimeEq |
+            Intrinsic::LoadLittleEndian |
+            Intrinsic::LoadBigEndian |
+            Intrinsic::RotateLeft |
+            Intrinsic::RotateRight |
+            Intrinsic::IntToString |
+            Intrinsic::FloatToString => 2,
+            Intrinsic::Select |
+            Intrinsic::ConstantTimeSelect |
+            Intrinsic::StoreLittleEndian |
+            Intrinsic::StoreBigEndian => 3,
+        }
+    }
+
+    // Intrinsics which can also be called as a method on an integer, the integer becomes the first argument
+    pub fn from_integer_method(name: &str) -> Option<Intrinsic>
+    {
+        match Intrinsic::from_name(name) {
+            Some(Intrinsic::CountOnes) |
+            Some(Intrinsic::LeadingZeros) |
+            Some(Intrinsic::TrailingZeros) |
+            Some(Intrinsic::RotateLeft) |
+            Some(Intrinsic::RotateRight) => Intrinsic::from_name(name),
+            _ => None,
+        }
+    }
+
+    // Whether the intrinsic expects a type before the other arguments
+    pub fn has_type_argument(&self) -> bool
+    {
+        match *self {
+            Intrinsic::LoadLittleEndian |
+            Intrinsic::LoadBigEndian => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Intrinsic
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            Intrinsic::Likely => write!(f, "likely"),
+            Intrinsic::Unlikely => write!(f, "unlikely"),
+            Intrinsic::Prefetch => write!(f, "prefetch"),
+            Intrinsic::Select => write!(f, "select"),
+            Intrinsic::ConstantTimeEq => write!(f, "ct_eq"),
+            Intrinsic::ConstantTimeSelect => write!(f, "ct_select"),
+            Intrinsic::ToLittleEndian => write!(f, "to_le"),
+            Intrinsic::ToBigEndian => write!(f, "to_be"),
+            Intrinsic::FromLittleEndian => write!(f, "from_le"),
+            Intrinsic::FromBigEndian => write!(f, "from_be"),
+            Intrinsic::LoadLittleEndian => write!(f, "load_le"),
+            Intrinsic::LoadBigEndian => write!(f, "load_be"),
+            Intrinsic::StoreLittleEndian => write!(f, "store_le"),
+            Intrinsic::StoreBigEndian => write!(f, "store_be"),
+            Intrinsic::CountOnes => write!(f, "count_ones"),
+            Intrinsic::LeadingZeros => write!(f, "leading_zeros"),
+            Intrinsic::TrailingZeros => write!(f, "trailing_zeros"),
+            Intrinsic::RotateLeft => write!(f, "rotate_left"),
+            Intrinsic::RotateRight => write!(f, "rotate_right"),
+            Intrinsic::Random => write!(f, "random"),
+            Intrinsic::ParseInt => write!(f, "parse_int"),
+            Intrinsic::ParseFloat => write!(f, "parse_float"),
+            Intrinsic::IntToString => write!(f, "int_to_string"),
+            Intrinsic::FloatToString => write!(f, "float_to_string"),
+            Intrinsic::HexEncode => write!(f, "hex_encode"),
+            Intrinsic::HexDecode => write!(f, "hex_decode"),
+            Intrinsic::FromUtf8 => write!(f, "from_utf8"),
+        }
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum CompilerCall
 {
     SizeOf(Type, Span),
-    Slice{data: Box<Expression>, len: Box<Expression>, typ: Type, span: Span}
+    Slice{data: Box<Expression>, len: Box<Expression>, typ: Type, span: Span},
+    Fields(FieldQuery, Type, Span),
+    // The members of a struct value, each formatted like @format, in the order of @field_names
+    FieldValues(Box<Expression>, Span),
+    OffsetOf{typ: Type, field: String, index: usize, span: Span},
+    Intrinsic{intrinsic: Intrinsic, args: Vec<Expression>, typ: Type, span: Span},
+    Format{parts: Vec<Expression>, span: Span},
+    // Runtime error showing both values, and for strings, arrays and slices where they first differ, unless left == right
+    AssertEq{left: Box<Expression>, right: Box<Expression>, span: Span},
+    // Runtime error with the source location and the message, formatted like @format, unless cond is true
+    Assert{cond: Box<Expression>, parts: Vec<Expression>, span: Span},
+    // Stops the program with the source location and the message, formatted like @format
+    Panic{parts: Vec<Expression>, span: Span},
+    // Writes the parts, formatted like @format, to stderr, with the level and the source location in front
+    Log{level: LogLevel, parts: Vec<Expression>, span: Span},
 }
 
 
@@ -16,6 +253,16 @@ impl CompilerCall
         match *self {
             CompilerCall::SizeOf(_, _) => Type::UInt(int_size),
             CompilerCall::Slice{ref typ, ..} => typ.clone(),
+            CompilerCall::Fields(FieldQuery::Count, _, _) => Type::UInt(int_size),
+            CompilerCall::Fields(_, _, _) |
+            CompilerCall::FieldValues(_, _) => slice_type(Type::String),
+            CompilerCall::OffsetOf{..} => Type::UInt(int_size),
+            CompilerCall::Intrinsic{ref typ, ..} => typ.clone(),
+            CompilerCall::Format{..} => Type::String,
+            CompilerCall::AssertEq{..} |
+            CompilerCall::Assert{..} |
+            CompilerCall::Panic{..} |
+            CompilerCall::Log{..} => Type::Void,
         }
     }
 }
@@ -32,6 +279,48 @@ impl TreePrinter for CompilerCall
                 data.print(level + 1);
                 len.print(level + 1);
             }
+            CompilerCall::Fields(query, ref typ, ref span) => println!("{}@{}({}) (span: {})", p, query, typ, span),
+            CompilerCall::FieldValues(ref value, ref span) => {
+                println!("{}@field_values (span: {})", p, span);
+                value.print(level + 1);
+            }
+            CompilerCall::OffsetOf{ref typ, ref field, ref span, ..} => println!("{}@offsetof({}, {}) (span: {})", p, typ, field, span),
+            CompilerCall::Intrinsic{intrinsic, ref args, ref typ, ref span} => {
+                println!("{}@{} (span: {}, type: {})", p, intrinsic, span, typ);
+                for a in args {
+                    a.print(level + 1);
+                }
+            }
+            CompilerCall::Format{ref parts, ref span} => {
+                println!("{}@format (span: {})", p, span);
+                for part in parts {
+                    part.print(level + 1);
+                }
+            }
+            CompilerCall::Log{level: log_level, ref parts, ref span} => {
+                println!("{}@{} (span: {})", p, log_level, span);
+                for part in parts {
+                    part.print(level + 1);
+                }
+            }
+            CompilerCall::AssertEq{ref left, ref right, ref span} => {
+                println!("{}@assert_eq (span: {})", p, span);
+                left.print(level + 1);
+                right.print(level + 1);
+            }
+            CompilerCall::Assert{ref cond, ref parts, ref span} => {
+                println!("{}@assert (span: {})", p, span);
+                cond.print(level + 1);
+                for part in parts {
+                    part.print(level + 1);
+                }
+            }
+            CompilerCall::Panic{ref parts, ref span} => {
+                println!("{}@panic (span: {})", p, span);
+                for part in parts {
+                    part.print(level + 1);
+                }
+            }
         }
     }
-}
\ No newline at end of file
+}
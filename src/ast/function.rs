@@ -1,12 +1,18 @@
-use ast::{Type, Expression, TreePrinter, prefix, func_type};
+use ast::{Type, Expression, AssignTarget, BindingType, TreePrinter, prefix, func_type};
+use compileerror::{CompileResult, CompileError};
 use span::{Span};
 
+// Maximum number of expressions in a function, whose body is exported so other packages can inline it
+const INLINE_SIZE_LIMIT: usize = 16;
+
 #[derive(Debug, Eq, PartialEq, Clone, Hash, Serialize, Deserialize)]
 pub struct Argument
 {
     pub name: String,
     pub typ: Type,
     pub mutable: bool,
+    // A ref argument has a pointer type, the caller passes the address of one of its variables
+    pub by_ref: bool,
     pub span: Span,
 }
 
@@ -18,6 +24,7 @@ impl Argument
             name: name.into(),
             typ: typ,
             mutable: mutable,
+            by_ref: false,
             span: span,
         }
     }
@@ -68,6 +75,18 @@ impl FunctionSignature
         }
     }
 
+    // Name of the symbol of an @export function, without the namespace, so it can be called from C
+    pub fn exported_name(&self) -> &str
+    {
+        self.name.rsplit("::").next().unwrap_or(&self.name)
+    }
+
+    // Indices of the ref arguments
+    pub fn ref_args(&self) -> Vec<usize>
+    {
+        self.args.iter().enumerate().filter(|&(_, a)| a.by_ref).map(|(idx, _)| idx).collect()
+    }
+
     pub fn get_type(&self) -> Type
     {
         func_type(
@@ -100,6 +119,17 @@ pub struct Function
     pub span: Span,
     pub type_checked: bool,
     pub generics_resolved: bool,
+    pub deprecated: Option<String>,
+    pub must_use: bool,
+    pub constant_time: bool,
+    pub unsafe_code: bool,
+    pub init: bool,
+    pub fini: bool,
+    pub export: bool,
+    // Run by cobrac test, instead of being called by the program
+    pub test: bool,
+    // Arguments declared as a pattern, by argument name, the type checker destructures them at the start of the body
+    pub arg_patterns: Vec<(String, BindingType)>,
 }
 
 impl Function
@@ -113,6 +143,15 @@ impl Function
             span: span,
             type_checked: false,
             generics_resolved: false,
+            deprecated: None,
+            must_use: false,
+            constant_time: false,
+            unsafe_code: false,
+            init: false,
+            fini: false,
+            export: false,
+            test: false,
+            arg_patterns: Vec::new(),
         }
     }
 
@@ -120,6 +159,38 @@ impl Function
     {
         self.sig.return_type.is_generic() || self.sig.args.iter().any(|a| a.typ.is_generic())
     }
+
+    /*
+    Small functions which only use their arguments and local variables, like min, max or a member accessor.
+    A copy of their body only needs the types it uses, so it can be compiled in another package.
+    */
+    pub fn is_inlinable(&self) -> bool
+    {
+        if self.is_generic() || self.init || self.fini || self.export || self.constant_time {
+            return false;
+        }
+
+        // Globals and functions have names with a namespace, local variables don't
+        let not_local = || Err(CompileError::Other(format!("{} uses names which are not local", self.sig.name)));
+        let mut size = 0;
+        let self_contained = self.expression.visit(&mut |e: &Expression| -> CompileResult<()> {
+            size += 1;
+            match *e {
+                Expression::Call(_) |
+                Expression::Lambda(_) |
+                Expression::New(_) |
+                Expression::Delete(_) => not_local(),
+                Expression::NameRef(ref nr) if nr.name.contains("::") => not_local(),
+                Expression::Assign(ref a) => match a.left {
+                    AssignTarget::Var(ref nr) if nr.name.contains("::") => not_local(),
+                    _ => Ok(()),
+                },
+                _ => Ok(()),
+            }
+        });
+
+        self_contained.is_ok() && size <= INLINE_SIZE_LIMIT
+    }
 }
 
 impl TreePrinter for Function
@@ -144,11 +215,12 @@ pub fn sig(name: &str, ret: Type, args: Vec<Argument>, span: Span) -> FunctionSi
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ExternalFunction
 {
     pub sig: FunctionSignature,
     pub span: Span,
+    pub public: bool,
 }
 
 impl ExternalFunction
@@ -158,6 +230,7 @@ impl ExternalFunction
         ExternalFunction{
             sig: sig,
             span: span,
+            public: false,
         }
     }
 }
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::path::PathBuf;
 use std::rc::Rc;
 use itertools::join;
 use span::Span;
 use ast::Function;
+use query::{hash_input, INITIAL_HASH};
 use super::{Type};
 
 
@@ -25,6 +27,14 @@ impl ImportName
     {
         join(self.namespace.iter(), "::")
     }
+
+    // The source file of the module, relative to the directory of the package: collections::map is collections/map.mhr
+    pub fn to_path(&self) -> PathBuf
+    {
+        let mut path: PathBuf = self.namespace.iter().collect();
+        path.set_extension("mhr");
+        path
+    }
 }
 
 impl fmt::Display for ImportName
@@ -40,7 +50,9 @@ pub enum SymbolType
 {
     Normal,
     Global,
-    External
+    External,
+    // A ref argument, a pointer which is dereferenced wherever its name is used
+    Reference,
 }
 
 
@@ -52,6 +64,11 @@ pub struct Symbol
     pub mutable: bool,
     pub span: Span,
     pub symbol_type: SymbolType,
+    // Private symbols of an import can only be used by the generic functions of that import
+    pub public: bool,
+    // Indices of the ref arguments of a function
+    #[serde(default)]
+    pub ref_args: Vec<usize>,
 }
 
 impl Symbol
@@ -63,9 +80,23 @@ impl Symbol
             typ: typ.clone(),
             mutable: mutable,
             span: span.clone(),
-            symbol_type: symbol_type
+            symbol_type: symbol_type,
+            public: true,
+            ref_args: Vec::new(),
         }
     }
+
+    pub fn with_visibility(mut self, public: bool) -> Symbol
+    {
+        self.public = public;
+        self
+    }
+
+    pub fn with_ref_args(mut self, ref_args: Vec<usize>) -> Symbol
+    {
+        self.ref_args = ref_args;
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -74,8 +105,10 @@ pub struct Import
     pub namespace: String,
     pub symbols: HashMap<String, Symbol>,
     pub generics: HashMap<String, Function>,
-    pub imported_symbols: HashMap<String, Symbol>
-
+    // Bodies of small functions, so packages importing them can inline them
+    pub inline_functions: HashMap<String, Function>,
+    pub imported_symbols: HashMap<String, Symbol>,
+    pub abi_hash: u64,
 }
 
 impl Import
@@ -86,10 +119,25 @@ impl Import
             namespace,
             symbols: HashMap::new(),
             generics: HashMap::new(),
+            inline_functions: HashMap::new(),
             imported_symbols: HashMap::new(),
+            abi_hash: 0,
         }
     }
 
+    // Hash of all exported signatures, and of the bodies of generic and inline functions,
+    // because importing modules instantiate or inline those bodies themselves
+    pub fn compute_abi_hash(&self) -> u64
+    {
+        let mut lines: Vec<String> = self.symbols.values()
+            .map(|s| format!("S {}: {}", s.name, s.typ))
+            .chain(self.generics.values().map(|g| format!("G {}: {:?}", g.sig.name, g)))
+            .chain(self.inline_functions.values().map(|i| format!("I {}: {:?}", i.sig.name, i)))
+            .collect();
+        lines.sort();
+        lines.iter().fold(INITIAL_HASH, |hash, line| hash_input(hash, line))
+    }
+
     pub fn resolve(&self, name: &str, allow_imported_symbols: bool) -> Option<Symbol>
     {
         let resolve = |symbols: &HashMap<String, Symbol>| {
@@ -119,7 +167,7 @@ impl fmt::Display for Import
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
     {
-        writeln!(f, "Module {}:", self.namespace)?;
+        writeln!(f, "Module {} (ABI hash {:016x}):", self.namespace, self.abi_hash)?;
         for symbol in self.symbols.values() {
             writeln!(f, " S {}: {}", symbol.name, symbol.typ)?;
         }
@@ -128,6 +176,10 @@ impl fmt::Display for Import
             writeln!(f, " G {}: {}", function.sig.name, function.sig.typ)?;
         }
 
+        for function in self.inline_functions.values() {
+            writeln!(f, " I {}: {}", function.sig.name, function.sig.typ)?;
+        }
+
         for symbol in self.imported_symbols.values() {
             writeln!(f, " U {}: {}", symbol.name, symbol.typ)?;
         }
@@ -35,4 +35,44 @@ impl TreePrinter for IndexOperation
         println!("{} index:", p);
         self.index_expr.print(level + 2);
     }
-}
\ No newline at end of file
+}
+// target[start..end], the elements from start up to end
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SliceOperation
+{
+    pub target: Expression,
+    pub start: Expression,
+    pub end: Expression,
+    pub span: Span,
+    pub typ: Type,
+}
+
+pub fn slice_op(target: Expression, start: Expression, end: Expression, span: Span) -> Expression
+{
+    Expression::SliceOperation(
+        Box::new(
+            SliceOperation{
+                target: target,
+                start: start,
+                end: end,
+                span: span,
+                typ: Type::Unknown,
+            }
+        )
+    )
+}
+
+impl TreePrinter for SliceOperation
+{
+    fn print(&self, level: usize)
+    {
+        let p = prefix(level);
+        println!("{}slice operation ({}) (type: {})", p, self.span, self.typ);
+        println!("{} target:", p);
+        self.target.print(level + 2);
+        println!("{} start:", p);
+        self.start.print(level + 2);
+        println!("{} end:", p);
+        self.end.print(level + 2);
+    }
+}
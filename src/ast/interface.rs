@@ -8,6 +8,7 @@ pub struct Interface
     pub functions: Vec<FunctionSignature>,
     pub typ: Type,
     pub span: Span,
+    pub public: bool,
 }
 
 pub fn interface(name: String, functions: Vec<FunctionSignature>, span: Span) -> Interface
@@ -17,6 +18,7 @@ pub fn interface(name: String, functions: Vec<FunctionSignature>, span: Span) ->
         functions: functions,
         typ: Type::Unknown,
         span: span,
+        public: false,
     }
 }
 
@@ -31,3 +33,35 @@ impl TreePrinter for Interface
         }
     }
 }
+
+// impl <interface> for <type>, the functions are added to the module as member functions of the type
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct InterfaceImpl
+{
+    pub interface: String,
+    pub typ: String,
+    pub functions: Vec<String>,
+    pub span: Span,
+}
+
+pub fn interface_impl(interface: String, typ: String, functions: Vec<String>, span: Span) -> InterfaceImpl
+{
+    InterfaceImpl{
+        interface: interface,
+        typ: typ,
+        functions: functions,
+        span: span,
+    }
+}
+
+impl TreePrinter for InterfaceImpl
+{
+    fn print(&self, level: usize)
+    {
+        let p = prefix(level);
+        println!("{}impl {} for {} ({})", p, self.interface, self.typ, self.span);
+        for func in &self.functions {
+            println!("{} {}", p, func);
+        }
+    }
+}
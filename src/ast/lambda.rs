@@ -7,6 +7,8 @@ pub struct Lambda
 {
     pub sig: FunctionSignature,
     pub expr: Expression,
+    // Variables of the enclosing function used in the body, filled in by the typechecker
+    pub captures: Vec<Argument>,
     pub span: Span,
 }
 
@@ -15,6 +17,7 @@ pub fn lambda(args: Vec<Argument>, expr: Expression, span: Span) -> Expression
     Expression::Lambda(Box::new(Lambda{
         sig: sig("lambda", generic_type("$ret$"), args, span.clone()),
         expr: expr,
+        captures: Vec::new(),
         span: span,
     }))
 }
@@ -69,6 +72,9 @@ impl TreePrinter for Lambda
         let p = prefix(level);
         println!("{}lambda ({})", p, self.span);
         self.sig.print(level + 1);
+        for c in &self.captures {
+            println!("{} capture {}: {}", p, c.name, c.typ);
+        }
         self.expr.print(level + 1);
     }
 }
@@ -15,6 +15,27 @@ pub enum Literal
     NullPtr(Span, Type),
 }
 
+// The smallest and largest value of a signed integer type, computed without overflowing for 64 bit integers
+fn int_range(int_size: IntSize) -> (i64, i64)
+{
+    match int_size {
+        IntSize::I8 => (i8::MIN as i64, i8::MAX as i64),
+        IntSize::I16 => (i16::MIN as i64, i16::MAX as i64),
+        IntSize::I32 => (i32::MIN as i64, i32::MAX as i64),
+        IntSize::I64 => (i64::MIN, i64::MAX),
+    }
+}
+
+fn uint_max(int_size: IntSize) -> u64
+{
+    match int_size {
+        IntSize::I8 => u8::MAX as u64,
+        IntSize::I16 => u16::MAX as u64,
+        IntSize::I32 => u32::MAX as u64,
+        IntSize::I64 => u64::MAX,
+    }
+}
+
 impl Literal
 {
     pub fn get_type(&self) -> Type
@@ -51,9 +72,7 @@ impl Literal
     {
         match (self, typ) {
             (&Literal::Int(ref span, value, _), &Type::Int(int_size)) => {
-                let target_bit_size = int_size.size_in_bits();
-                let target_min = -2i64.pow(target_bit_size - 1);
-                let target_max = (2u64.pow(target_bit_size - 1) - 1) as i64;
+                let (target_min, target_max) = int_range(int_size);
                 if value >= target_min && value <= target_max {
                     Some(Literal::Int(span.clone(), value, int_size))
                 } else {
@@ -71,8 +90,7 @@ impl Literal
             }
 
             (&Literal::UInt(ref span, value, _), &Type::Int(int_size)) => {
-                let target_bit_size = int_size.size_in_bits();
-                if value < 2u64.pow(target_bit_size) {
+                if value <= uint_max(int_size) {
                     Some(Literal::Int(span.clone(), value as i64, int_size))
                 } else {
                     None
@@ -80,8 +98,7 @@ impl Literal
             }
 
             (&Literal::UInt(ref span, value, _), &Type::UInt(int_size)) => {
-                let target_bit_size = int_size.size_in_bits();
-                if value < 2u64.pow(target_bit_size) {
+                if value <= uint_max(int_size) {
                     Some(Literal::UInt(span.clone(), value, int_size))
                 } else {
                     None
@@ -1,19 +1,56 @@
 use ast::{Expression, TreePrinter, Type, prefix};
+use std::fmt;
 use span::{Span};
 
+// Optimization hints given with @vectorize(width) and @unroll(count) in front of a loop
+#[derive(Debug, Eq, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct LoopHints
+{
+    pub vectorize: bool,
+    pub vectorize_width: Option<usize>,
+    pub unroll_count: Option<usize>,
+}
+
+impl LoopHints
+{
+    pub fn is_empty(&self) -> bool
+    {
+        !self.vectorize && self.vectorize_width.is_none() && self.unroll_count.is_none()
+    }
+}
+
+impl fmt::Display for LoopHints
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        if self.vectorize {
+            write!(f, " vectorize")?;
+        }
+        if let Some(width) = self.vectorize_width {
+            write!(f, " width={}", width)?;
+        }
+        if let Some(count) = self.unroll_count {
+            write!(f, " unroll={}", count)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct WhileLoop
 {
     pub cond: Expression,
     pub body: Expression,
+    pub hints: LoopHints,
     pub span: Span,
 }
 
-pub fn while_loop(cond: Expression, body: Expression, span: Span) -> Expression
+pub fn while_loop(cond: Expression, body: Expression, hints: LoopHints, span: Span) -> Expression
 {
     Expression::While(Box::new(WhileLoop{
         cond: cond,
         body: body,
+        hints: hints,
         span: span,
     }))
 }
@@ -24,7 +61,7 @@ impl TreePrinter for WhileLoop
     fn print(&self, level: usize)
     {
         let p = prefix(level);
-        println!("{}while (span: {})", p, self.span);
+        println!("{}while (span: {}){}", p, self.span, self.hints);
         self.cond.print(level + 1);
         self.body.print(level + 1);
     }
@@ -37,16 +74,18 @@ pub struct ForLoop
     pub loop_variable_type: Type,
     pub iterable: Expression,
     pub body: Expression,
+    pub hints: LoopHints,
     pub span: Span,
 }
 
-pub fn for_loop(loop_variable: &str, iterable: Expression, body: Expression, span: Span) -> Expression
+pub fn for_loop(loop_variable: &str, iterable: Expression, body: Expression, hints: LoopHints, span: Span) -> Expression
 {
     Expression::For(Box::new(ForLoop{
         loop_variable: loop_variable.into(),
         loop_variable_type: Type::Unknown,
         iterable: iterable,
         body: body,
+        hints: hints,
         span: span,
     }))
 }
@@ -57,7 +96,7 @@ impl TreePrinter for ForLoop
     fn print(&self, level: usize)
     {
         let p = prefix(level);
-        println!("{}for {} (span: {})", p, self.loop_variable, self.span);
+        println!("{}for {} (span: {}){}", p, self.loop_variable, self.span, self.hints);
         self.iterable.print(level + 1);
         self.body.print(level + 1);
     }
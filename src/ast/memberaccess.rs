@@ -51,6 +51,8 @@ pub struct MemberAccess
     pub right: MemberAccessType,
     pub span: Span,
     pub typ: Type,
+    // a?.b, which is nil when a is nil
+    pub optional: bool,
 }
 
 pub fn member_access(left: Expression, right: MemberAccessType, span: Span) -> Expression
@@ -61,7 +63,23 @@ pub fn member_access(left: Expression, right: MemberAccessType, span: Span) -> E
                 left,
                 right,
                 span,
-                typ: Type::Unknown
+                typ: Type::Unknown,
+                optional: false,
+            }
+        )
+    )
+}
+
+pub fn optional_member_access(left: Expression, right: MemberAccessType, span: Span) -> Expression
+{
+    Expression::MemberAccess(
+        Box::new(
+            MemberAccess {
+                left,
+                right,
+                span,
+                typ: Type::Unknown,
+                optional: true,
             }
         )
     )
@@ -73,7 +91,7 @@ impl TreePrinter for MemberAccess
     fn print(&self, level: usize)
     {
         let p = prefix(level);
-        println!("{}member access (span: {}, type: {})", p, self.span, self.typ);
+        println!("{}{}member access (span: {}, type: {})", p, if self.optional {"optional "} else {""}, self.span, self.typ);
         self.left.print(level + 1);
         match self.right
         {
@@ -25,6 +25,7 @@ mod structs;
 mod sumtype;
 mod typedeclaration;
 mod types;
+mod visitor;
 
 pub use self::arrays::*;
 pub use self::assign::*;
@@ -53,6 +54,7 @@ pub use self::structs::*;
 pub use self::sumtype::*;
 pub use self::typedeclaration::*;
 pub use self::types::*;
+pub use self::visitor::*;
 
 
 pub fn prefix(level: usize) -> String
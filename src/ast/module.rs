@@ -1,8 +1,9 @@
 use std::collections::{HashMap, HashSet};
-use super::{Expression, Call, TreePrinter, TypeDeclaration, Import, ImportName, Symbol, SymbolType, GlobalBinding, Function, ExternalFunction, prefix};
+use super::{Expression, Call, TreePrinter, TypeDeclaration, InterfaceImpl, Import, ImportName, Symbol, Type, SymbolType, GlobalBinding, Function, ExternalFunction, Visitor, prefix};
 use target::Target;
 use compileerror::CompileResult;
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Module
 {
     pub name: String,
@@ -10,6 +11,7 @@ pub struct Module
     pub functions: HashMap<String, Function>,
     pub externals: HashMap<String, ExternalFunction>,
     pub types: HashMap<String, TypeDeclaration>,
+    pub impls: Vec<InterfaceImpl>,
     pub import_names: HashSet<ImportName>,
     pub type_checked: bool,
 }
@@ -24,6 +26,7 @@ impl Module
             functions: HashMap::new(),
             externals: HashMap::new(),
             types: HashMap::new(),
+            impls: Vec::new(),
             import_names: HashSet::new(),
             type_checked: false,
         }
@@ -64,26 +67,95 @@ impl Module
         }
 
         for (name, function) in &self.functions {
-            import.symbols.insert(name.clone(), Symbol::new(name, &function.sig.typ, false, &function.span, SymbolType::Normal));
+            let symbol = Symbol::new(name, &function.sig.typ, false, &function.span, SymbolType::Normal)
+                .with_visibility(function.public)
+                .with_ref_args(function.sig.ref_args());
+            import.symbols.insert(name.clone(), symbol);
             if function.is_generic() {
                 import.generics.insert(name.clone(), function.clone());
+            } else if function.is_inlinable() {
+                import.inline_functions.insert(name.clone(), function.clone());
             }
         }
 
         for (name, function) in &self.externals {
-            import.symbols.insert(name.clone(), Symbol::new(name, &function.sig.typ, false, &function.span, SymbolType::External));
+            import.symbols.insert(name.clone(), Symbol::new(name, &function.sig.typ, false, &function.span, SymbolType::External).with_visibility(function.public));
         }
 
         for (name, type_decl) in &self.types {
-            import.symbols.insert(name.clone(), Symbol::new(name, &type_decl.get_type(), false, &type_decl.span(), SymbolType::Normal));
+            let typ = type_decl.get_type();
+            let public = type_decl.is_public();
+            import.symbols.insert(name.clone(), Symbol::new(name, &typ, false, &type_decl.span(), SymbolType::Normal).with_visibility(public));
+
+            // The cases are needed to construct and match values of the type in the importing module,
+            // they are found as module::Case, like in the module itself
+            let case_names: Vec<String> = match typ {
+                Type::Sum(ref st) => st.cases.iter().map(|c| c.name.clone()).collect(),
+                Type::Enum(ref et) => et.cases.clone(),
+                _ => Vec::new(),
+            };
+
+            for case_name in case_names {
+                let short_name = case_name.rsplit("::").next().unwrap_or(&case_name);
+                let key = format!("{}::{}", self.name, short_name);
+                import.symbols.insert(key, Symbol::new(&case_name, &typ, false, &type_decl.span(), SymbolType::Normal).with_visibility(public));
+            }
         }
 
 
         import.imported_symbols = self.get_imported_symbols(target);
+        import.abi_hash = import.compute_abi_hash();
         import
     }
 }
 
+// Prints all declarations of a module, separated by an empty line
+struct ModulePrinter
+{
+    level: usize,
+}
+
+impl ModulePrinter
+{
+    fn separator(&self)
+    {
+        println!("{}", prefix(self.level));
+    }
+}
+
+impl Visitor for ModulePrinter
+{
+    type Error = ();
+
+    fn visit_type_declaration(&mut self, td: &TypeDeclaration) -> Result<(), ()>
+    {
+        td.print(self.level + 1);
+        self.separator();
+        Ok(())
+    }
+
+    fn visit_global(&mut self, g: &GlobalBinding) -> Result<(), ()>
+    {
+        g.print(self.level + 1);
+        self.separator();
+        Ok(())
+    }
+
+    fn visit_external_function(&mut self, f: &ExternalFunction) -> Result<(), ()>
+    {
+        f.print(self.level + 1);
+        self.separator();
+        Ok(())
+    }
+
+    fn visit_function(&mut self, f: &Function) -> Result<(), ()>
+    {
+        f.print(self.level + 1);
+        self.separator();
+        Ok(())
+    }
+}
+
 impl TreePrinter for Module
 {
     fn print(&self, level: usize)
@@ -95,24 +167,10 @@ impl TreePrinter for Module
         }
 
         println!("{}", p);
-        for t in self.types.values() {
-            t.print(level + 1);
-            println!("{}", p);
-        }
-
-        for global in self.globals.values() {
-            global.print(level + 1);
-            println!("{}", p);
-        }
-
-        for func in self.externals.values() {
-            func.print(level + 1);
-            println!("{}", p);
-        }
-
-        for func in self.functions.values() {
-            func.print(level + 1);
+        for i in &self.impls {
+            i.print(level + 1);
             println!("{}", p);
         }
+        let _ = ModulePrinter{level: level}.visit_module(self);
     }
 }
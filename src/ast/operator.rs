@@ -2,6 +2,17 @@ use std::fmt;
 
 pub const TOP_PRECEDENCE: usize = 2000;
 
+// Unary operators bind tighter than every binary operator, except member access and casts
+pub const UNARY_PRECEDENCE: usize = TOP_PRECEDENCE - 50;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Associativity
+{
+    Left,
+    // Cannot be chained without parentheses, like comparisons: a < b < c
+    None,
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub enum BinaryOperator
 {
@@ -48,20 +59,46 @@ impl fmt::Display for BinaryOperator
     }
 }
 
+/*
+    The precedence and associativity of every binary operator, the parser only looks at this table,
+    so changing the way an operator binds is a matter of changing its entry.
+*/
+const OPERATOR_TABLE: &[(BinaryOperator, usize, Associativity)] = &[
+    (BinaryOperator::Dot, TOP_PRECEDENCE, Associativity::Left),
+    (BinaryOperator::As, TOP_PRECEDENCE, Associativity::Left),
+    (BinaryOperator::Mul, TOP_PRECEDENCE - 100, Associativity::Left),
+    (BinaryOperator::Div, TOP_PRECEDENCE - 100, Associativity::Left),
+    (BinaryOperator::Mod, TOP_PRECEDENCE - 100, Associativity::Left),
+    (BinaryOperator::Add, TOP_PRECEDENCE - 200, Associativity::Left),
+    (BinaryOperator::Sub, TOP_PRECEDENCE - 200, Associativity::Left),
+    (BinaryOperator::LessThan, TOP_PRECEDENCE - 300, Associativity::None),
+    (BinaryOperator::GreaterThan, TOP_PRECEDENCE - 300, Associativity::None),
+    (BinaryOperator::LessThanEquals, TOP_PRECEDENCE - 300, Associativity::None),
+    (BinaryOperator::GreaterThanEquals, TOP_PRECEDENCE - 300, Associativity::None),
+    (BinaryOperator::Equals, TOP_PRECEDENCE - 300, Associativity::None),
+    (BinaryOperator::NotEquals, TOP_PRECEDENCE - 300, Associativity::None),
+    (BinaryOperator::And, TOP_PRECEDENCE - 400, Associativity::Left),
+    (BinaryOperator::Or, TOP_PRECEDENCE - 500, Associativity::Left),
+];
+
 impl BinaryOperator
 {
+    fn table_entry(&self) -> (usize, Associativity)
+    {
+        OPERATOR_TABLE.iter()
+            .find(|&&(op, _, _)| op == *self)
+            .map(|&(_, precedence, associativity)| (precedence, associativity))
+            .expect("Operator missing from the operator table")
+    }
+
     pub fn precedence(&self) -> usize
     {
-        match *self
-        {
-            BinaryOperator::Dot | BinaryOperator::As => TOP_PRECEDENCE,
-            BinaryOperator::Mul | BinaryOperator::Div | BinaryOperator::Mod => TOP_PRECEDENCE - 100,
-            BinaryOperator::Add | BinaryOperator::Sub => TOP_PRECEDENCE - 200,
-            BinaryOperator::LessThan | BinaryOperator::GreaterThan | BinaryOperator::LessThanEquals |
-            BinaryOperator::GreaterThanEquals | BinaryOperator::Equals | BinaryOperator::NotEquals => TOP_PRECEDENCE - 300,
-            BinaryOperator::And => TOP_PRECEDENCE - 400,
-            BinaryOperator::Or => TOP_PRECEDENCE - 500,
-        }
+        self.table_entry().0
+    }
+
+    pub fn associativity(&self) -> Associativity
+    {
+        self.table_entry().1
     }
 }
 
@@ -25,6 +25,10 @@ pub struct StructDeclaration
     pub members: Vec<StructMemberDeclaration>,
     pub span: Span,
     pub typ: Type,
+    pub derives: Vec<String>,
+    pub deprecated: Option<String>,
+    pub repr_c: bool,
+    pub public: bool,
 }
 
 pub fn struct_declaration(name: &str, members: Vec<StructMemberDeclaration>, span: Span) -> StructDeclaration
@@ -34,6 +38,29 @@ pub fn struct_declaration(name: &str, members: Vec<StructMemberDeclaration>, spa
         members: members,
         span: span,
         typ: Type::Unknown,
+        derives: Vec::new(),
+        deprecated: None,
+        repr_c: false,
+        public: false,
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OpaqueTypeDeclaration
+{
+    pub name: String,
+    pub span: Span,
+    pub typ: Type,
+    pub public: bool,
+}
+
+pub fn opaque_type_declaration(name: &str, span: Span) -> OpaqueTypeDeclaration
+{
+    OpaqueTypeDeclaration{
+        name: name.into(),
+        span: span,
+        typ: Type::Unknown,
+        public: false,
     }
 }
 
@@ -71,6 +98,14 @@ impl TreePrinter for StructDeclaration
     }
 }
 
+impl TreePrinter for OpaqueTypeDeclaration
+{
+    fn print(&self, level: usize)
+    {
+        println!("{}extern type {} ({})", prefix(level), self.name, self.span);
+    }
+}
+
 impl TreePrinter for StructInitializer
 {
     fn print(&self, level: usize)
@@ -2,7 +2,7 @@ use ast::{TreePrinter, StructDeclaration, Type, prefix};
 use span::{Span};
 
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct SumTypeCaseDeclaration
 {
     pub name: String,
@@ -21,13 +21,18 @@ pub fn sum_type_case_decl(name: &str, data: Option<StructDeclaration>, span: Spa
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct SumTypeDeclaration
 {
     pub name: String,
     pub cases: Vec<SumTypeCaseDeclaration>,
+    // Type parameters declared with the type, empty when they are implied by the cases
+    pub generic_params: Vec<Type>,
     pub span: Span,
     pub typ: Type,
+    pub derives: Vec<String>,
+    pub deprecated: Option<String>,
+    pub public: bool,
 }
 
 pub fn sum_type_decl(name: &str, cases: Vec<SumTypeCaseDeclaration>, span: Span) -> SumTypeDeclaration
@@ -35,8 +40,12 @@ pub fn sum_type_decl(name: &str, cases: Vec<SumTypeCaseDeclaration>, span: Span)
     SumTypeDeclaration{
         name: name.into(),
         cases: cases,
+        generic_params: Vec::new(),
         span: span,
         typ: Type::Unknown,
+        derives: Vec::new(),
+        deprecated: None,
+        public: false,
     }
 }
 
@@ -45,7 +54,12 @@ impl TreePrinter for SumTypeDeclaration
     fn print(&self, level: usize)
     {
         let p = prefix(level);
-        println!("{}sum {} ({})", p, self.name, self.span);
+        if self.generic_params.is_empty() {
+            println!("{}sum {} ({})", p, self.name, self.span);
+        } else {
+            let params: Vec<String> = self.generic_params.iter().map(|t| t.to_string()).collect();
+            println!("{}sum {}<{}> ({})", p, self.name, params.join(", "), self.span);
+        }
         for case in &self.cases {
             case.print(level + 1);
         }
@@ -1,12 +1,14 @@
-use super::{Type, Interface, StructDeclaration, SumTypeDeclaration, TreePrinter};
+use super::{Type, Interface, StructDeclaration, SumTypeDeclaration, OpaqueTypeDeclaration, TreePrinter};
 use span::Span;
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub enum TypeDeclaration
 {
     Interface(Interface),
     Struct(StructDeclaration),
+    Union(StructDeclaration),
     Sum(SumTypeDeclaration),
+    Opaque(OpaqueTypeDeclaration),
     //Alias(TypeAlias),
 }
 
@@ -17,19 +19,36 @@ impl TypeDeclaration
         match *self
         {
             TypeDeclaration::Interface(ref i) => i.span.clone(),
-            TypeDeclaration::Struct(ref sd) => sd.span.clone(),
+            TypeDeclaration::Struct(ref sd) |
+            TypeDeclaration::Union(ref sd) => sd.span.clone(),
             TypeDeclaration::Sum(ref s) => s.span.clone(),
+            TypeDeclaration::Opaque(ref o) => o.span.clone(),
             //TypeDeclaration::Alias(ref t) => &t.typ.clone(),
         }
     }
 
+    // Whether other modules can use the type, it has to be declared with pub
+    pub fn is_public(&self) -> bool
+    {
+        match *self
+        {
+            TypeDeclaration::Interface(ref i) => i.public,
+            TypeDeclaration::Struct(ref sd) |
+            TypeDeclaration::Union(ref sd) => sd.public,
+            TypeDeclaration::Sum(ref s) => s.public,
+            TypeDeclaration::Opaque(ref o) => o.public,
+        }
+    }
+
     pub fn get_type(&self) -> Type
     {
         match *self
         {
             TypeDeclaration::Interface(ref i) => i.typ.clone(),
-            TypeDeclaration::Struct(ref sd) => sd.typ.clone(),
+            TypeDeclaration::Struct(ref sd) |
+            TypeDeclaration::Union(ref sd) => sd.typ.clone(),
             TypeDeclaration::Sum(ref s) => s.typ.clone(),
+            TypeDeclaration::Opaque(ref o) => o.typ.clone(),
             //TypeDeclaration::Alias(ref t) => &t.typ.clone(),
         }
     }
@@ -42,8 +61,10 @@ impl TreePrinter for TypeDeclaration
         match *self
         {
             TypeDeclaration::Interface(ref i) => i.print(level),
-            TypeDeclaration::Struct(ref sd) => sd.print(level),
+            TypeDeclaration::Struct(ref sd) |
+            TypeDeclaration::Union(ref sd) => sd.print(level),
             TypeDeclaration::Sum(ref s) => s.print(level),
+            TypeDeclaration::Opaque(ref o) => o.print(level),
             //TypeDeclaration::Alias(ref t) => t.print(level),
         }
     }
@@ -26,6 +26,45 @@ pub struct SumType
     pub cases: Vec<SumTypeCase>,
 }
 
+/*
+Niches are values which a type never uses, so they can encode something else without extra memory.
+An optional pointer uses the null pointer as nil. A sum type with two cases, where one case has no
+data and the other has a pointer member, has no case index: a null pointer in that member means it
+is the case without data. So in these types, a null pointer cannot be told apart from nil.
+*/
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SumTypeNiche
+{
+    pub data_case: usize,
+    // Index of the pointer member in the struct of the data case
+    pub member: usize,
+    pub empty_case: usize,
+}
+
+impl SumType
+{
+    pub fn niche(&self) -> Option<SumTypeNiche>
+    {
+        if self.cases.len() != 2 {
+            return None;
+        }
+
+        for &(data_case, empty_case) in &[(0, 1), (1, 0)] {
+            // Cases without data have an integer type
+            if let Type::Struct(_) = self.cases[empty_case].typ {
+                continue;
+            }
+
+            if let Type::Struct(ref st) = self.cases[data_case].typ {
+                if let Some(member) = st.members.iter().position(|m| m.typ.is_pointer()) {
+                    return Some(SumTypeNiche{data_case, member, empty_case});
+                }
+            }
+        }
+        None
+    }
+}
+
 impl SumTypeCaseIndexOf for SumType
 {
     fn index_of(&self, case_name: &str) -> Option<usize>
@@ -71,6 +110,33 @@ pub struct StructType
 {
     pub name: String,
     pub members: Vec<StructMember>,
+    // Members are laid out in declaration order, as in C
+    pub repr_c: bool,
+}
+
+impl StructType
+{
+    /*
+    Order of the members in memory, as indices into members. Unless the struct is @repr(C), the members
+    are sorted by decreasing alignment, so there is no padding between them. Member indices always refer
+    to the declaration order, only code generation and memory layouts use this order.
+    */
+    pub fn member_order<F: Fn(&Type) -> usize>(&self, align_of: F) -> Vec<usize>
+    {
+        let mut order: Vec<usize> = (0..self.members.len()).collect();
+        if !self.repr_c {
+            let alignments: Vec<usize> = self.members.iter().map(|m| align_of(&m.typ)).collect();
+            // Stable, so members with the same alignment keep their declaration order
+            order.sort_by(|a, b| alignments[*b].cmp(&alignments[*a]));
+        }
+        order
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Hash, Serialize, Deserialize)]
+pub struct OpaqueType
+{
+    pub name: String,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Hash, Serialize, Deserialize)]
@@ -85,6 +151,9 @@ pub struct ArrayType
 {
     pub element_type: Type,
     pub len: usize,
+    // $N in int[$N], the length is only known when the function is instantiated
+    #[serde(default)]
+    pub generic_len: Option<String>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Hash, Serialize, Deserialize)]
@@ -112,7 +181,10 @@ pub struct InterfaceType
 pub enum GenericType
 {
     Any(String),
-    Restricted(Vec<Type>),
+    // The name is empty for $(A + B) and $A, which are the same generic wherever they are used
+    Restricted(String, Vec<Type>),
+    // The length of an array, only used as key in a GenericMapping, see generic_length
+    Length(String),
 }
 
 
@@ -183,6 +255,8 @@ pub enum Type
     Generic(Rc<GenericType>),
     Func(Rc<FuncType>),
     Struct(Rc<StructType>),
+    Union(Rc<StructType>),
+    Opaque(Rc<OpaqueType>),
     Sum(Rc<SumType>),
     Enum(Rc<EnumType>),
     Optional(Rc<Type>),
@@ -281,6 +355,10 @@ impl Type
                 Some(type_cast(expr.clone(), Type::Bool, expr.span()))
             }
 
+            (&Type::Interface(_), &Type::Pointer(_)) => {
+                Some(type_cast(expr.clone(), self.clone(), expr.span()))
+            }
+
             _ => None,
         }
     }
@@ -290,7 +368,7 @@ impl Type
         match (self, dst_type)
         {
             (&Type::Array(ref at), &Type::Slice(ref st)) => at.element_type == st.element_type,
-            (_, &Type::Optional(ref inner)) => *inner.deref() == *dst_type,
+            (_, &Type::Optional(ref inner)) => *inner.deref() == *self,
             _ => false,
         }
     }
@@ -315,6 +393,9 @@ impl Type
             Type::Char=> COMPARISON_OPERATORS.contains(&op),
             Type::Bool => COMPARISON_OPERATORS.contains(&op) || op == BinaryOperator::And || op == BinaryOperator::Or,
             Type::String | Type::Pointer(_) | Type::Optional(_) => op == BinaryOperator::Equals || op == BinaryOperator::NotEquals,
+            // Compared element by element
+            Type::Array(ref at) => at.element_type.is_simple_value() && (op == BinaryOperator::Equals || op == BinaryOperator::NotEquals),
+            Type::Slice(ref st) => st.element_type.is_simple_value() && (op == BinaryOperator::Equals || op == BinaryOperator::NotEquals),
             _ => false,
         }
     }
@@ -324,13 +405,14 @@ impl Type
         match *self
         {
             Type::Generic(_) => true,
-            Type::Array(ref at) => at.element_type.is_generic(),
+            Type::Array(ref at) => at.generic_len.is_some() || at.element_type.is_generic(),
             Type::Slice(ref st) => st.element_type.is_generic(),
             Type::Func(ref ft) => ft.return_type.is_generic() || ft.args.iter().any(|a| a.is_generic()),
             Type::Struct(ref st) => st.members.iter().any(|m| m.typ.is_generic()),
             Type::Sum(ref st) => st.cases.iter().any(|c| c.typ.is_generic()),
             Type::Unresolved(ref ut) => ut.generic_args.iter().any(|t| t.is_generic()),
-            Type::Pointer(ref inner) => inner.is_generic(),
+            Type::Pointer(ref inner) |
+            Type::Optional(ref inner) => inner.is_generic(),
             Type::Interface(ref i) => !i.generic_args.is_empty(),
             _ => false,
         }
@@ -345,6 +427,38 @@ impl Type
         }
     }
 
+    pub fn is_integer(&self) -> bool
+    {
+        match *self
+        {
+            Type::Int(_) | Type::UInt(_) => true,
+            _ => false,
+        }
+    }
+
+    // Integers, characters and booleans, which are equal when their bits are
+    pub fn is_simple_value(&self) -> bool
+    {
+        match *self
+        {
+            Type::Int(_) | Type::UInt(_) | Type::Char | Type::Bool => true,
+            _ => false,
+        }
+    }
+
+    // Opaque types have no known layout, so they can only be used behind a pointer
+    pub fn contains_opaque_value(&self) -> bool
+    {
+        match *self
+        {
+            Type::Opaque(_) => true,
+            Type::Array(ref at) => at.element_type.contains_opaque_value(),
+            Type::Slice(ref st) => st.element_type.contains_opaque_value(),
+            Type::Optional(ref inner) => inner.contains_opaque_value(),
+            _ => false,
+        }
+    }
+
     pub fn is_unknown(&self) -> bool
     {
         match *self
@@ -391,6 +505,24 @@ impl Type
         }
     }
 
+    pub fn is_sum(&self) -> bool
+    {
+        if let Type::Sum(_) = *self {
+            true
+        } else {
+            false
+        }
+    }
+
+    // Types whose members are accessed by their index
+    pub fn has_members(&self) -> bool
+    {
+        match *self {
+            Type::Struct(_) | Type::Union(_) | Type::Sum(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn is_pointer(&self) -> bool
     {
         if let Type::Pointer(_) = *self {
@@ -408,6 +540,17 @@ impl Type
         }
     }
 
+    // Optionals of this type use the null pointer as nil
+    pub fn has_null_niche(&self) -> bool
+    {
+        match *self
+        {
+            Type::Pointer(_) |
+            Type::Func(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn is_pointer_to_optional(&self) -> bool
     {
         if let Type::Pointer(ref inner) = *self {
@@ -422,6 +565,8 @@ impl Type
         match *self
         {
             Type::Struct(ref st) => st.name.clone(),
+            Type::Union(ref ut) => ut.name.clone(),
+            Type::Opaque(ref ot) => ot.name.clone(),
             Type::Sum(ref st) => st.name.clone(),
             Type::Enum(ref st) => st.name.clone(),
             Type::Interface(ref i) => i.name.clone(),
@@ -472,10 +617,31 @@ pub fn array_type(element_type: Type, len: usize) -> Type
 {
     Type::Array(Rc::new(ArrayType{
         element_type: element_type,
-        len: len
+        len: len,
+        generic_len: None,
+    }))
+}
+
+pub fn generic_array_type(element_type: Type, len_name: &str) -> Type
+{
+    Type::Array(Rc::new(ArrayType{
+        element_type: element_type,
+        len: 0,
+        generic_len: Some(len_name.into()),
     }))
 }
 
+// A GenericMapping maps generic_length(name) on array_length(len), the length $name stands for
+pub fn generic_length(name: &str) -> Type
+{
+    Type::Generic(Rc::new(GenericType::Length(name.into())))
+}
+
+pub fn array_length(len: usize) -> Type
+{
+    array_type(Type::Void, len)
+}
+
 pub fn slice_type(element_type: Type) -> Type
 {
     Type::Slice(Rc::new(SliceType{
@@ -495,7 +661,8 @@ pub fn string_type_representation(native_int_size: IntSize) -> StructType
         members: vec![
             struct_member("data", ptr_type(Type::UInt(IntSize::I8))),
             struct_member("len", Type::UInt(native_int_size))
-        ]
+        ],
+        repr_c: true,
     }
 }
 
@@ -524,10 +691,32 @@ pub fn enum_type(name: &str, cases: Vec<String>) -> Type
 }
 
 pub fn struct_type(name: &str, members: Vec<StructMember>) -> Type
+{
+    struct_type_with_layout(name, members, false)
+}
+
+pub fn struct_type_with_layout(name: &str, members: Vec<StructMember>, repr_c: bool) -> Type
 {
     Type::Struct(Rc::new(StructType{
         name: name.into(),
         members: members,
+        repr_c: repr_c,
+    }))
+}
+
+pub fn union_type(name: &str, members: Vec<StructMember>) -> Type
+{
+    Type::Union(Rc::new(StructType{
+        name: name.into(),
+        members: members,
+        repr_c: false,
+    }))
+}
+
+pub fn opaque_type(name: &str) -> Type
+{
+    Type::Opaque(Rc::new(OpaqueType{
+        name: name.into(),
     }))
 }
 
@@ -546,11 +735,11 @@ pub fn generic_type(name: &str) -> Type
     Type::Generic(Rc::new(GenericType::Any(name.into())))
 }
 
-pub fn generic_type_with_constraints(constraints: Vec<Type>) -> Type
+pub fn generic_type_with_constraints(name: &str, constraints: Vec<Type>) -> Type
 {
     Type::Generic(
         Rc::new(
-            GenericType::Restricted(constraints)
+            GenericType::Restricted(name.into(), constraints)
         )
     )
 }
@@ -609,11 +798,16 @@ impl fmt::Display for Type
                 } else {
                     write!(f, "{}<{}>", s.name, join(s.generic_args.iter(), ","))
                 },
-            Type::Array(ref at) => write!(f, "{}[{}]", at.element_type, at.len),
+            Type::Array(ref at) => match at.generic_len {
+                Some(ref name) => write!(f, "{}[${}]", at.element_type, name),
+                None => write!(f, "{}[{}]", at.element_type, at.len),
+            },
             Type::Slice(ref at) => write!(f, "{}[]", at.element_type),
             Type::Generic(ref g) => write!(f, "${}", g),
             Type::Func(ref ft) => write!(f, "({}) -> {}", join(ft.args.iter(), ", "), ft.return_type),
             Type::Struct(ref st) => write!(f, "{{{}}}", join(st.members.iter(), ", ")),
+            Type::Union(ref ut) => write!(f, "union {{{}}}", join(ut.members.iter(), ", ")),
+            Type::Opaque(ref ot) => write!(f, "extern type {}", ot.name),
             Type::Sum(ref st) => write!(f, "{}", join(st.cases.iter().map(|m| &m.typ), " | ")),
             Type::Enum(ref st) => write!(f, "{}", join(st.cases.iter(), " | ")),
             Type::Optional(ref inner) => write!(f, "?{}", inner),
@@ -642,7 +836,9 @@ impl fmt::Display for GenericType
         match *self
         {
             GenericType::Any(ref name) => write!(f, "{}", name),
-            GenericType::Restricted(ref constraints) => write!(f, "({})", join(constraints.iter(), " + ")),
+            GenericType::Restricted(ref name, ref constraints) if name.is_empty() => write!(f, "({})", join(constraints.iter(), " + ")),
+            GenericType::Restricted(ref name, _) => write!(f, "{}", name),
+            GenericType::Length(ref name) => write!(f, "{}", name),
         }
     }
 }
@@ -0,0 +1,624 @@
+use super::*;
+
+/*
+Visitor and MutVisitor walk over the AST. Every visit method by default calls the corresponding walk function,
+which visits all children of the node. Override a visit method to act on a node, and call the walk function
+from it to continue with the children.
+*/
+
+pub trait Visitor
+{
+    type Error;
+
+    fn visit_module(&mut self, m: &Module) -> Result<(), Self::Error>
+    {
+        walk_module(self, m)
+    }
+
+    fn visit_type_declaration(&mut self, td: &TypeDeclaration) -> Result<(), Self::Error>
+    {
+        walk_type_declaration(self, td)
+    }
+
+    fn visit_global(&mut self, g: &GlobalBinding) -> Result<(), Self::Error>
+    {
+        walk_global(self, g)
+    }
+
+    fn visit_external_function(&mut self, f: &ExternalFunction) -> Result<(), Self::Error>
+    {
+        walk_function_signature(self, &f.sig)
+    }
+
+    fn visit_function(&mut self, f: &Function) -> Result<(), Self::Error>
+    {
+        walk_function(self, f)
+    }
+
+    fn visit_expression(&mut self, e: &Expression) -> Result<(), Self::Error>
+    {
+        walk_expression(self, e)
+    }
+
+    fn visit_pattern(&mut self, p: &Pattern) -> Result<(), Self::Error>
+    {
+        walk_pattern(self, p)
+    }
+
+    fn visit_name_ref(&mut self, _nr: &NameRef) -> Result<(), Self::Error>
+    {
+        Ok(())
+    }
+
+    fn visit_type(&mut self, t: &Type) -> Result<(), Self::Error>
+    {
+        walk_type(self, t)
+    }
+}
+
+pub fn walk_module<V: Visitor + ?Sized>(v: &mut V, m: &Module) -> Result<(), V::Error>
+{
+    for td in m.types.values() {
+        v.visit_type_declaration(td)?;
+    }
+
+    for g in m.globals.values() {
+        v.visit_global(g)?;
+    }
+
+    for f in m.externals.values() {
+        v.visit_external_function(f)?;
+    }
+
+    for f in m.functions.values() {
+        v.visit_function(f)?;
+    }
+    Ok(())
+}
+
+pub fn walk_type_declaration<V: Visitor + ?Sized>(v: &mut V, td: &TypeDeclaration) -> Result<(), V::Error>
+{
+    match *td
+    {
+        TypeDeclaration::Interface(ref i) => {
+            for f in &i.functions {
+                walk_function_signature(v, f)?;
+            }
+            Ok(())
+        },
+        TypeDeclaration::Struct(ref sd) |
+        TypeDeclaration::Union(ref sd) => {
+            for m in &sd.members {
+                v.visit_type(&m.typ)?;
+            }
+            Ok(())
+        },
+        TypeDeclaration::Sum(ref st) => {
+            for c in &st.cases {
+                if let Some(ref sd) = c.data {
+                    for m in &sd.members {
+                        v.visit_type(&m.typ)?;
+                    }
+                }
+            }
+            Ok(())
+        },
+        TypeDeclaration::Opaque(_) => Ok(()),
+    }
+}
+
+pub fn walk_global<V: Visitor + ?Sized>(v: &mut V, g: &GlobalBinding) -> Result<(), V::Error>
+{
+    v.visit_type(&g.typ)?;
+    v.visit_expression(&g.init)
+}
+
+pub fn walk_function_signature<V: Visitor + ?Sized>(v: &mut V, sig: &FunctionSignature) -> Result<(), V::Error>
+{
+    for arg in &sig.args {
+        v.visit_type(&arg.typ)?;
+    }
+    v.visit_type(&sig.return_type)
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(v: &mut V, f: &Function) -> Result<(), V::Error>
+{
+    walk_function_signature(v, &f.sig)?;
+    v.visit_expression(&f.expression)
+}
+
+fn walk_call<V: Visitor + ?Sized>(v: &mut V, call: &Call) -> Result<(), V::Error>
+{
+    v.visit_name_ref(&call.callee)?;
+    for a in &call.args {
+        v.visit_expression(a)?;
+    }
+    Ok(())
+}
+
+fn walk_member_access<V: Visitor + ?Sized>(v: &mut V, ma: &MemberAccess) -> Result<(), V::Error>
+{
+    v.visit_expression(&ma.left)?;
+    if let MemberAccessType::Call(ref call) = ma.right {
+        for a in &call.args {
+            v.visit_expression(a)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn walk_expression<V: Visitor + ?Sized>(v: &mut V, e: &Expression) -> Result<(), V::Error>
+{
+    match *e
+    {
+        Expression::UnaryOp(ref uop) => v.visit_expression(&uop.expression),
+
+        Expression::BinaryOp(ref bop) => {
+            v.visit_expression(&bop.left)?;
+            v.visit_expression(&bop.right)
+        },
+
+        Expression::Literal(Literal::Array(ref a)) => {
+            for el in &a.elements {
+                v.visit_expression(el)?;
+            }
+            Ok(())
+        },
+
+        Expression::Call(ref call) => walk_call(v, call),
+
+        Expression::NameRef(ref nr) => v.visit_name_ref(nr),
+
+        Expression::Lambda(ref l) => {
+            walk_function_signature(v, &l.sig)?;
+            v.visit_expression(&l.expr)
+        },
+
+        Expression::Match(ref m) => {
+            v.visit_expression(&m.target)?;
+            for c in &m.cases {
+                v.visit_pattern(&c.pattern)?;
+                v.visit_expression(&c.to_execute)?;
+            }
+            Ok(())
+        },
+
+        Expression::Bindings(ref l) => {
+            for b in &l.bindings {
+                v.visit_expression(&b.init)?;
+            }
+            Ok(())
+        },
+
+        Expression::Block(ref b) => {
+            for e in &b.expressions {
+                v.visit_expression(e)?;
+            }
+            Ok(())
+        },
+
+        Expression::New(ref n) => v.visit_expression(&n.inner),
+        Expression::Delete(ref d) => v.visit_expression(&d.inner),
+        Expression::ArrayToSlice(ref ats) => v.visit_expression(&ats.inner),
+        Expression::Return(ref r) => v.visit_expression(&r.expression),
+
+        Expression::If(ref i) => {
+            v.visit_expression(&i.condition)?;
+            v.visit_expression(&i.on_true)?;
+            if let Some(ref e) = i.on_false {
+                v.visit_expression(e)?;
+            }
+            Ok(())
+        },
+
+        Expression::StructInitializer(ref si) => {
+            for e in &si.member_initializers {
+                v.visit_expression(e)?;
+            }
+            Ok(())
+        },
+
+        Expression::AddressOf(ref a) => v.visit_expression(&a.inner),
+        Expression::Dereference(ref d) => v.visit_expression(&d.inner),
+
+        Expression::While(ref w) => {
+            v.visit_expression(&w.cond)?;
+            v.visit_expression(&w.body)
+        },
+
+        Expression::Assign(ref a) => {
+            match a.left {
+                AssignTarget::Var(ref nr) => v.visit_name_ref(nr)?,
+                AssignTarget::Dereference(ref d) => v.visit_expression(&d.inner)?,
+                AssignTarget::IndexOperation(ref iop) => {
+                    v.visit_expression(&iop.target)?;
+                    v.visit_expression(&iop.index_expr)?;
+                },
+                AssignTarget::MemberAccess(ref ma) => walk_member_access(v, ma)?,
+            }
+            v.visit_expression(&a.right)
+        },
+
+        Expression::For(ref f) => {
+            v.visit_expression(&f.iterable)?;
+            v.visit_expression(&f.body)
+        },
+
+        Expression::OptionalToBool(ref o) => v.visit_expression(o),
+        Expression::MemberAccess(ref ma) => walk_member_access(v, ma),
+        Expression::ToOptional(ref t) => v.visit_expression(&t.inner),
+
+        Expression::Cast(ref c) => {
+            v.visit_expression(&c.inner)?;
+            v.visit_type(&c.destination_type)
+        },
+
+        Expression::IndexOperation(ref iop) => {
+            v.visit_expression(&iop.target)?;
+            v.visit_expression(&iop.index_expr)
+        },
+
+        Expression::SliceOperation(ref sop) => {
+            v.visit_expression(&sop.target)?;
+            v.visit_expression(&sop.start)?;
+            v.visit_expression(&sop.end)
+        },
+
+        Expression::CompilerCall(CompilerCall::Slice{ref data, ref len, ..}) => {
+            v.visit_expression(data)?;
+            v.visit_expression(len)
+        },
+
+        Expression::CompilerCall(CompilerCall::AssertEq{ref left, ref right, ..}) => {
+            v.visit_expression(left)?;
+            v.visit_expression(right)
+        },
+
+        Expression::CompilerCall(CompilerCall::FieldValues(ref value, _)) => v.visit_expression(value),
+
+        Expression::CompilerCall(CompilerCall::Assert{ref cond, ref parts, ..}) => {
+            v.visit_expression(cond)?;
+            for p in parts {
+                v.visit_expression(p)?;
+            }
+            Ok(())
+        },
+
+        Expression::CompilerCall(CompilerCall::Intrinsic{ref args, ..}) |
+        Expression::CompilerCall(CompilerCall::Format{parts: ref args, ..}) |
+        Expression::CompilerCall(CompilerCall::Log{parts: ref args, ..}) |
+        Expression::CompilerCall(CompilerCall::Panic{parts: ref args, ..}) => {
+            for a in args {
+                v.visit_expression(a)?;
+            }
+            Ok(())
+        },
+
+        Expression::Literal(_) |
+        Expression::Void |
+        Expression::CompilerCall(_) |
+        Expression::Nil(_) => Ok(()),
+    }
+}
+
+pub fn walk_pattern<V: Visitor + ?Sized>(v: &mut V, p: &Pattern) -> Result<(), V::Error>
+{
+    match *p
+    {
+        Pattern::Literal(Literal::Array(ref al)) => {
+            for el in &al.elements {
+                v.visit_expression(el)?;
+            }
+            Ok(())
+        },
+        Pattern::Name(ref nr) => v.visit_name_ref(nr),
+        _ => Ok(()),
+    }
+}
+
+pub fn walk_type<V: Visitor + ?Sized>(v: &mut V, t: &Type) -> Result<(), V::Error>
+{
+    match *t
+    {
+        Type::Pointer(ref inner) |
+        Type::Optional(ref inner) => v.visit_type(inner),
+        Type::Array(ref at) => v.visit_type(&at.element_type),
+        Type::Slice(ref st) => v.visit_type(&st.element_type),
+        Type::Func(ref ft) => {
+            for a in &ft.args {
+                v.visit_type(a)?;
+            }
+            v.visit_type(&ft.return_type)
+        },
+        _ => Ok(()),
+    }
+}
+
+pub trait MutVisitor
+{
+    type Error;
+
+    // Nothing rewrites a whole module yet, these are kept so MutVisitor mirrors Visitor
+    #[allow(dead_code)]
+    fn visit_module(&mut self, m: &mut Module) -> Result<(), Self::Error>
+    {
+        walk_module_mut(self, m)
+    }
+
+    #[allow(dead_code)]
+    fn visit_global(&mut self, g: &mut GlobalBinding) -> Result<(), Self::Error>
+    {
+        walk_global_mut(self, g)
+    }
+
+    fn visit_function(&mut self, f: &mut Function) -> Result<(), Self::Error>
+    {
+        walk_function_mut(self, f)
+    }
+
+    fn visit_expression(&mut self, e: &mut Expression) -> Result<(), Self::Error>
+    {
+        walk_expression_mut(self, e)
+    }
+
+    fn visit_pattern(&mut self, p: &mut Pattern) -> Result<(), Self::Error>
+    {
+        walk_pattern_mut(self, p)
+    }
+
+    fn visit_name_ref(&mut self, _nr: &mut NameRef) -> Result<(), Self::Error>
+    {
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
+pub fn walk_module_mut<V: MutVisitor + ?Sized>(v: &mut V, m: &mut Module) -> Result<(), V::Error>
+{
+    for g in m.globals.values_mut() {
+        v.visit_global(g)?;
+    }
+
+    for f in m.functions.values_mut() {
+        v.visit_function(f)?;
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn walk_global_mut<V: MutVisitor + ?Sized>(v: &mut V, g: &mut GlobalBinding) -> Result<(), V::Error>
+{
+    v.visit_expression(&mut g.init)
+}
+
+pub fn walk_function_mut<V: MutVisitor + ?Sized>(v: &mut V, f: &mut Function) -> Result<(), V::Error>
+{
+    v.visit_expression(&mut f.expression)
+}
+
+fn walk_call_mut<V: MutVisitor + ?Sized>(v: &mut V, call: &mut Call) -> Result<(), V::Error>
+{
+    v.visit_name_ref(&mut call.callee)?;
+    for a in &mut call.args {
+        v.visit_expression(a)?;
+    }
+    Ok(())
+}
+
+fn walk_member_access_mut<V: MutVisitor + ?Sized>(v: &mut V, ma: &mut MemberAccess) -> Result<(), V::Error>
+{
+    v.visit_expression(&mut ma.left)?;
+    if let MemberAccessType::Call(ref mut call) = ma.right {
+        for a in &mut call.args {
+            v.visit_expression(a)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn walk_expression_mut<V: MutVisitor + ?Sized>(v: &mut V, e: &mut Expression) -> Result<(), V::Error>
+{
+    match *e
+    {
+        Expression::UnaryOp(ref mut uop) => v.visit_expression(&mut uop.expression),
+
+        Expression::BinaryOp(ref mut bop) => {
+            v.visit_expression(&mut bop.left)?;
+            v.visit_expression(&mut bop.right)
+        },
+
+        Expression::Literal(Literal::Array(ref mut a)) => {
+            for el in &mut a.elements {
+                v.visit_expression(el)?;
+            }
+            Ok(())
+        },
+
+        Expression::Call(ref mut call) => walk_call_mut(v, call),
+
+        Expression::NameRef(ref mut nr) => v.visit_name_ref(nr),
+
+        Expression::Lambda(ref mut l) => v.visit_expression(&mut l.expr),
+
+        Expression::Match(ref mut m) => {
+            v.visit_expression(&mut m.target)?;
+            for c in &mut m.cases {
+                v.visit_pattern(&mut c.pattern)?;
+                v.visit_expression(&mut c.to_execute)?;
+            }
+            Ok(())
+        },
+
+        Expression::Bindings(ref mut l) => {
+            for b in &mut l.bindings {
+                v.visit_expression(&mut b.init)?;
+            }
+            Ok(())
+        },
+
+        Expression::Block(ref mut b) => {
+            for e in &mut b.expressions {
+                v.visit_expression(e)?;
+            }
+            Ok(())
+        },
+
+        Expression::New(ref mut n) => v.visit_expression(&mut n.inner),
+        Expression::Delete(ref mut d) => v.visit_expression(&mut d.inner),
+        Expression::ArrayToSlice(ref mut ats) => v.visit_expression(&mut ats.inner),
+        Expression::Return(ref mut r) => v.visit_expression(&mut r.expression),
+
+        Expression::If(ref mut i) => {
+            v.visit_expression(&mut i.condition)?;
+            v.visit_expression(&mut i.on_true)?;
+            if let Some(ref mut e) = i.on_false {
+                v.visit_expression(e)?;
+            }
+            Ok(())
+        },
+
+        Expression::StructInitializer(ref mut si) => {
+            for e in &mut si.member_initializers {
+                v.visit_expression(e)?;
+            }
+            Ok(())
+        },
+
+        Expression::AddressOf(ref mut a) => v.visit_expression(&mut a.inner),
+        Expression::Dereference(ref mut d) => v.visit_expression(&mut d.inner),
+
+        Expression::While(ref mut w) => {
+            v.visit_expression(&mut w.cond)?;
+            v.visit_expression(&mut w.body)
+        },
+
+        Expression::Assign(ref mut a) => {
+            match a.left {
+                AssignTarget::Var(ref mut nr) => v.visit_name_ref(nr)?,
+                AssignTarget::Dereference(ref mut d) => v.visit_expression(&mut d.inner)?,
+                AssignTarget::IndexOperation(ref mut iop) => {
+                    v.visit_expression(&mut iop.target)?;
+                    v.visit_expression(&mut iop.index_expr)?;
+                },
+                AssignTarget::MemberAccess(ref mut ma) => walk_member_access_mut(v, ma)?,
+            }
+            v.visit_expression(&mut a.right)
+        },
+
+        Expression::For(ref mut f) => {
+            v.visit_expression(&mut f.iterable)?;
+            v.visit_expression(&mut f.body)
+        },
+
+        Expression::OptionalToBool(ref mut o) => v.visit_expression(o),
+        Expression::MemberAccess(ref mut ma) => walk_member_access_mut(v, ma),
+        Expression::ToOptional(ref mut t) => v.visit_expression(&mut t.inner),
+        Expression::Cast(ref mut c) => v.visit_expression(&mut c.inner),
+
+        Expression::IndexOperation(ref mut iop) => {
+            v.visit_expression(&mut iop.target)?;
+            v.visit_expression(&mut iop.index_expr)
+        },
+
+        Expression::SliceOperation(ref mut sop) => {
+            v.visit_expression(&mut sop.target)?;
+            v.visit_expression(&mut sop.start)?;
+            v.visit_expression(&mut sop.end)
+        },
+
+        Expression::CompilerCall(CompilerCall::Slice{ref mut data, ref mut len, ..}) => {
+            v.visit_expression(data)?;
+            v.visit_expression(len)
+        },
+
+        Expression::CompilerCall(CompilerCall::AssertEq{ref mut left, ref mut right, ..}) => {
+            v.visit_expression(left)?;
+            v.visit_expression(right)
+        },
+
+        Expression::CompilerCall(CompilerCall::FieldValues(ref mut value, _)) => v.visit_expression(value),
+
+        Expression::CompilerCall(CompilerCall::Assert{ref mut cond, ref mut parts, ..}) => {
+            v.visit_expression(cond)?;
+            for p in parts.iter_mut() {
+                v.visit_expression(p)?;
+            }
+            Ok(())
+        },
+
+        Expression::CompilerCall(CompilerCall::Intrinsic{ref mut args, ..}) |
+        Expression::CompilerCall(CompilerCall::Format{parts: ref mut args, ..}) |
+        Expression::CompilerCall(CompilerCall::Log{parts: ref mut args, ..}) |
+        Expression::CompilerCall(CompilerCall::Panic{parts: ref mut args, ..}) => {
+            for a in args.iter_mut() {
+                v.visit_expression(a)?;
+            }
+            Ok(())
+        },
+
+        Expression::Literal(_) |
+        Expression::Void |
+        Expression::CompilerCall(_) |
+        Expression::Nil(_) => Ok(()),
+    }
+}
+
+pub fn walk_pattern_mut<V: MutVisitor + ?Sized>(v: &mut V, p: &mut Pattern) -> Result<(), V::Error>
+{
+    match *p
+    {
+        Pattern::Literal(Literal::Array(ref mut al)) => {
+            for el in &mut al.elements {
+                v.visit_expression(el)?;
+            }
+            Ok(())
+        },
+        Pattern::Name(ref mut nr) => v.visit_name_ref(nr),
+        _ => Ok(()),
+    }
+}
+
+// Adapters so that closures can be used to visit all expressions
+pub struct ExpressionVisitor<'a, Op: 'a>
+{
+    op: &'a mut Op,
+}
+
+impl<'a, E, Op> Visitor for ExpressionVisitor<'a, Op>
+    where Op: FnMut(&Expression) -> Result<(), E>
+{
+    type Error = E;
+
+    fn visit_expression(&mut self, e: &Expression) -> Result<(), E>
+    {
+        (self.op)(e)?;
+        walk_expression(self, e)
+    }
+}
+
+pub struct ExpressionMutVisitor<'a, Op: 'a>
+{
+    op: &'a mut Op,
+}
+
+impl<'a, E, Op> MutVisitor for ExpressionMutVisitor<'a, Op>
+    where Op: FnMut(&mut Expression) -> Result<(), E>
+{
+    type Error = E;
+
+    fn visit_expression(&mut self, e: &mut Expression) -> Result<(), E>
+    {
+        (self.op)(e)?;
+        walk_expression_mut(self, e)
+    }
+}
+
+pub fn expression_visitor<Op>(op: &mut Op) -> ExpressionVisitor<'_, Op>
+{
+    ExpressionVisitor{op}
+}
+
+pub fn expression_mut_visitor<Op>(op: &mut Op) -> ExpressionMutVisitor<'_, Op>
+{
+    ExpressionMutVisitor{op}
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::env;
+use std::process::Command;
+use time;
+use compileerror::{CompileResult, CompileError};
+
+/*
+    Information about the build, which programs get with @build_info("key"). The driver adds it to every
+    module as constant string globals before type checking, --define key=value adds keys or overrides them.
+*/
+
+// No namespace, so the globals can only be reached with @build_info
+pub fn build_info_global_name(key: &str) -> String
+{
+    format!("build_info.{}", key)
+}
+
+// Commit of the repository the compiler runs in, if any
+fn git_hash() -> Option<String>
+{
+    let output = try_opt!(Command::new("git").args(&["rev-parse", "--short", "HEAD"]).output().ok());
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        None
+    }
+}
+
+// SOURCE_DATE_EPOCH overrides the current date, so builds can be reproduced
+fn build_date() -> String
+{
+    let tm = match env::var("SOURCE_DATE_EPOCH").ok().and_then(|secs| secs.parse::<i64>().ok()) {
+        Some(secs) => time::at_utc(time::Timespec::new(secs, 0)),
+        None => time::now_utc(),
+    };
+
+    time::strftime("%Y-%m-%d", &tm).unwrap_or_default()
+}
+
+pub fn build_info(version: &str, profile: &str, target: &str, defines: &HashMap<String, String>) -> HashMap<String, String>
+{
+    let mut info = HashMap::new();
+    info.insert("version".to_string(), version.to_string());
+    info.insert("git_hash".to_string(), git_hash().unwrap_or_default());
+    info.insert("build_date".to_string(), build_date());
+    info.insert("profile".to_string(), profile.to_string());
+    info.insert("target".to_string(), target.to_string());
+    info.extend(defines.iter().map(|(key, value)| (key.clone(), value.clone())));
+    info
+}
+
+// The names and values of the globals @build_info refers to
+pub fn build_info_globals(info: &HashMap<String, String>) -> HashMap<String, String>
+{
+    info.iter()
+        .map(|(key, value)| (build_info_global_name(key), value.clone()))
+        .collect()
+}
+
+// Parse the argument of --define, key=value
+pub fn parse_define(define: &str) -> CompileResult<(String, String)>
+{
+    let mut parts = define.splitn(2, '=');
+    let key = parts.next().unwrap_or("");
+    let value = parts.next()
+        .ok_or_else(|| CompileError::Other(format!("Invalid --define {}, expecting key=value", define)))?;
+
+    let valid_key = key.chars().next().map(|c| c.is_alphabetic() || c == '_').unwrap_or(false) &&
+        key.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if !valid_key {
+        return Err(CompileError::Other(format!("Invalid --define key {}, it must be an identifier", key)));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn test_parse_define()
+    {
+        assert_eq!(parse_define("version=1.2.3").unwrap(), ("version".to_string(), "1.2.3".to_string()));
+        assert_eq!(parse_define("empty=").unwrap(), ("empty".to_string(), String::new()));
+        assert_eq!(parse_define("url=a=b").unwrap(), ("url".to_string(), "a=b".to_string()));
+        assert!(parse_define("version").is_err());
+        assert!(parse_define("1st=a").is_err());
+        assert!(parse_define("a-b=c").is_err());
+    }
+
+    #[test]
+    fn test_defines_override_build_info()
+    {
+        let mut defines = HashMap::new();
+        defines.insert("version".to_string(), "2.0".to_string());
+        defines.insert("channel".to_string(), "beta".to_string());
+        let info = build_info("1.0", "release", "x86_64-linux-gnu", &defines);
+        assert_eq!(info["version"], "2.0");
+        assert_eq!(info["channel"], "beta");
+        assert_eq!(info["profile"], "release");
+        assert_eq!(info["target"], "x86_64-linux-gnu");
+        assert_eq!(info["build_date"].len(), 10);
+    }
+}
@@ -1,10 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use ast::*;
 use target::{Target};
-use bytecode::{ByteCodeModule, ByteCodeFunction};
-use compileerror::{CompileResult, type_error_result};
+use bytecode::{ByteCodeModule, ByteCodeFunction, RuntimeGlobal};
+use compileerror::CompileResult;
 use package::Package;
+use span::Span;
 use super::consteval::expr_to_const;
+use super::constanttime::check_constant_time;
+use super::matchlowering::{MatchLowering, match_lowering};
 use super::function::*;
 use super::instruction::*;
 
@@ -34,12 +37,72 @@ fn get_dst(func: &mut ByteCodeFunction, typ: &Type) -> Var
     stack_alloc(func, typ, None)
 }
 
+/*
+    Memory allocated with new is reference counted. A local pointer variable owns a reference, which is released
+    when the variable gets another value, or at the end of its scope. Functions return a reference which the caller owns.
+    Pointers stored in struct members, array elements or globals are retained, they are never released.
+*/
+
+// A new allocation or the result of a call is a reference which is owned already
+fn is_new_reference(e: &Expression) -> bool
+{
+    match *e {
+        Expression::New(_) |
+        Expression::Call(_) => true,
+        Expression::Block(ref b) => b.expressions.last().map(is_new_reference).unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn retain_to_bc(func: &mut ByteCodeFunction, v: &Var, e: &Expression)
+{
+    if let Type::Pointer(_) = v.typ {
+        if !is_new_reference(e) {
+            func.add(Instruction::IncRef(v.clone()));
+        }
+    }
+}
+
+// Store the value of a pointer expression in a local variable, which owns it
+fn owned_pointer_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, dst: &Var, e: &Expression, target: &Target)
+{
+    // The expression can refer to the variable itself, so the old value is released after evaluating it
+    let value = stack_alloc(func, &dst.typ, None);
+    func.push_destination(Some(value.clone()));
+    expr_to_bc(bc_mod, func, e, target);
+    func.pop_destination();
+    retain_to_bc(func, &value, e);
+
+    func.add_cleanup_target(dst);
+    func.add(Instruction::DecRef(dst.clone()));
+    func.add(store_instr(dst, &value));
+}
+
+// The caller owns the returned reference, so it must survive releasing the local variables
+fn returned_reference_to_bc(func: &mut ByteCodeFunction, v: Var, e: &Expression) -> Var
+{
+    if let Type::Pointer(_) = v.typ {
+        let ret = if func.is_cleanup_target(&v) {
+            let ret = stack_alloc(func, &v.typ, None);
+            func.add(store_instr(&ret, &v));
+            ret
+        } else {
+            v
+        };
+        retain_to_bc(func, &ret, e);
+        ret
+    } else {
+        v
+    }
+}
+
 fn array_lit_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, a: &ArrayLiteral, dst: &Var, target: &Target)
 {
     for (idx, element) in a.elements.iter().enumerate() {
         func.push_destination(None);
         let v = to_bc(bc_mod, func, element, target);
         func.pop_destination();
+        retain_to_bc(func, &v, element);
         func.add(store_member_instr(dst, idx, v, target.int_size));
     }
 }
@@ -77,6 +140,7 @@ fn struct_initializer_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunc
     let init_members = |bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, si: &StructInitializer, dst: &Var| {
         for (idx, expr) in si.member_initializers.iter().enumerate() {
             let v = to_bc(bc_mod, func, expr, target);
+            retain_to_bc(func, &v, expr);
             func.add(store_member_instr(dst, idx, v, target.int_size));
         }
     };
@@ -97,6 +161,7 @@ fn block_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, b: &Blo
 {
     let do_block = |bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, b: &Block| {
         for (idx, e) in b.expressions.iter().enumerate() {
+            func.add(Instruction::SourceLocation(e.span().start));
             if idx == b.expressions.len() - 1 {
                 expr_to_bc(bc_mod, func, e, target);
             } else {
@@ -142,15 +207,43 @@ fn add_struct_pattern_bindings(p: &StructPattern, struct_var: &Var, func: &mut B
     }
 }
 
+fn add_tuple_pattern_bindings(p: &TuplePattern, tuple_var: &Var, func: &mut ByteCodeFunction, target: &Target)
+{
+    let members = match p.typ {
+        Type::Struct(ref st) => &st.members,
+        _ => return,
+    };
+
+    for (idx, (element, member)) in p.elements.iter().zip(members.iter()).enumerate() {
+        let name = match *element {
+            BindingType::Name(ref name) if name == "_" => continue,
+            BindingType::Name(ref name) => Some(&name[..]),
+            _ => None,
+        };
+
+        let v = stack_alloc(func, &member.typ, name);
+        func.add(load_member_instr(&v, tuple_var, idx, target.int_size));
+        match *element {
+            BindingType::Name(_) => func.add_named_var(v),
+            BindingType::Struct(ref s) => add_struct_pattern_bindings(s, &v, func, target),
+            BindingType::Tuple(ref t) => add_tuple_pattern_bindings(t, &v, func, target),
+        }
+    }
+}
+
 fn add_binding(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, b: &Binding, target: &Target)
 {
     match b.binding_type
     {
         BindingType::Name(ref name) => {
             let dst = stack_alloc(func, &b.typ, Some(name));
-            func.push_destination(Some(dst));
-            expr_to_bc(bc_mod, func, &b.init, target);
-            func.pop_destination();
+            if let Type::Pointer(_) = b.typ {
+                owned_pointer_to_bc(bc_mod, func, &dst, &b.init, target);
+            } else {
+                func.push_destination(Some(dst));
+                expr_to_bc(bc_mod, func, &b.init, target);
+                func.pop_destination();
+            }
         },
 
         BindingType::Struct(ref s) => {
@@ -160,6 +253,14 @@ fn add_binding(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, b: &Bin
             add_struct_pattern_bindings(s, &dst, func, target);
             func.pop_destination();
         },
+
+        BindingType::Tuple(ref t) => {
+            let dst = stack_alloc(func, &b.typ, None);
+            func.push_destination(Some(dst.clone()));
+            expr_to_bc(bc_mod, func, &b.init, target);
+            add_tuple_pattern_bindings(t, &dst, func, target);
+            func.pop_destination();
+        },
     }
 }
 
@@ -233,7 +334,8 @@ fn member_store_lhs_to_bc(func: &mut ByteCodeFunction, lhs: &Expression, target:
             };
 
             match (inner_ma_typ, &inner_ma.right) {
-                (&Type::Struct(_), &MemberAccessType::Name(ref field)) => {
+                (&Type::Struct(_), &MemberAccessType::Name(ref field)) |
+                (&Type::Union(_), &MemberAccessType::Name(ref field)) => {
                     fields.push((field.index, inner_ma.typ.clone()));
                     (var, fields)
                 },
@@ -284,7 +386,8 @@ fn member_access_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction,
 
     match (var_typ, &sma.right)
     {
-        (&Type::Struct(_), &MemberAccessType::Name(ref field)) => {
+        (&Type::Struct(_), &MemberAccessType::Name(ref field)) |
+        (&Type::Union(_), &MemberAccessType::Name(ref field)) => {
             if dst.typ.pass_by_value() {
                 func.add(load_member_instr(dst, &var, field.index, target.int_size));
             } else {
@@ -570,6 +673,48 @@ fn match_case_to_bc(
     }
 }
 
+fn switch_match_to_bc(
+    bc_mod: &mut ByteCodeModule,
+    func: &mut ByteCodeFunction,
+    m: &MatchExpression,
+    cases: &[(Constant, usize)],
+    default: Option<usize>,
+    target_var: &Var,
+    match_end_bb: BasicBlockRef,
+    target: &Target)
+{
+    // Sum types switch on the index of the case
+    let value = match m.cases[cases[0].1].pattern {
+        Pattern::Name(NameRef{typ: Type::Sum(_), ..}) => {
+            let sum_type_index = stack_alloc(func, &target.native_uint_type, None);
+            func.add(get_prop_instr(&sum_type_index, target_var, ByteCodeProperty::SumTypeIndex));
+            sum_type_index
+        },
+        _ => target_var.clone(),
+    };
+
+    let mut case_bbs = Vec::new();
+    for &(ref value, idx) in cases {
+        case_bbs.push((value.clone(), func.create_basic_block(), idx));
+    }
+
+    let default_bb = func.create_basic_block();
+    func.add(switch_instr(&value, case_bbs.iter().map(|&(ref value, bb, _)| (value.clone(), bb)).collect(), default_bb));
+
+    for &(_, bb, idx) in &case_bbs {
+        func.set_current_bb(bb);
+        expr_to_bc(bc_mod, func, &m.cases[idx].to_execute, target);
+        func.add(Instruction::Branch(match_end_bb));
+    }
+
+    // Without a _ case the match is exhaustive, so the default is never taken
+    func.set_current_bb(default_bb);
+    if let Some(idx) = default {
+        expr_to_bc(bc_mod, func, &m.cases[idx].to_execute, target);
+    }
+    func.add(Instruction::Branch(match_end_bb));
+}
+
 fn match_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, m: &MatchExpression, target: &Target) -> Option<Var>
 {
     func.push_destination(None);
@@ -601,12 +746,21 @@ fn match_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, m: &Mat
 
     func.push_scope();
     func.push_destination(dst.clone());
-    for mc in &m.cases {
-        match_case_to_bc(bc_mod, func, mc, &target_var, match_end_bb, target);
+    match match_lowering(m, target)
+    {
+        MatchLowering::Switch{cases, default} => {
+            switch_match_to_bc(bc_mod, func, m, &cases, default, &target_var, match_end_bb, target);
+        },
+
+        MatchLowering::IfChain(_) => {
+            for mc in &m.cases {
+                match_case_to_bc(bc_mod, func, mc, &target_var, match_end_bb, target);
+            }
+            func.add(Instruction::Branch(match_end_bb));
+        },
     }
     func.pop_destination();
 
-    func.add(Instruction::Branch(match_end_bb));
     func.set_current_bb(match_end_bb);
     func.pop_scope();
     dst
@@ -624,6 +778,9 @@ fn while_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, w: &Whi
     func.add(branch_if_instr(&cond, body_bb, post_while_bb));
     func.set_current_bb(body_bb);
     expr_to_bc(bc_mod, func, &w.body, target);
+    if !w.hints.is_empty() {
+        func.add(Instruction::LoopHint(w.hints.clone()));
+    }
     func.add(Instruction::Branch(cond_bb));
 
     func.set_current_bb(post_while_bb);
@@ -666,6 +823,16 @@ fn for_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, f: &ForLo
     expr_to_bc(bc_mod, func, &f.body, target);
     func.pop_destination();
     func.add(binary_op_instr(&index, BinaryOperator::Add, var_op(&index), Operand::const_uint(1, target.int_size)));
+
+    // Loops over arrays and slices are good candidates for vectorization
+    let mut hints = f.hints.clone();
+    match iterable.typ {
+        Type::Array(_) | Type::Slice(_) => hints.vectorize = true,
+        _ => (),
+    }
+    if !hints.is_empty() {
+        func.add(Instruction::LoopHint(hints));
+    }
     func.add(Instruction::Branch(cond_bb));
 
     func.set_current_bb(post_for_bb);
@@ -678,7 +845,52 @@ fn cast_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, c: &Type
     func.push_destination(None);
     let inner = to_bc(bc_mod, func, &c.inner, target);
     func.pop_destination();
-    func.add(cast_instr(&dst, &inner));
+
+    match (&c.destination_type, &inner.typ)
+    {
+        (&Type::Interface(ref it), &Type::Pointer(ref concrete)) => {
+            let functions = it.functions.iter()
+                .map(|f| format!("{}.{}", concrete.name(), f.name))
+                .collect();
+            func.add(make_interface_instr(&dst, &inner, functions));
+        },
+
+        _ => func.add(cast_instr(&dst, &inner)),
+    }
+    dst
+}
+
+fn is_interface_object(typ: &Type) -> bool
+{
+    match *typ
+    {
+        Type::Interface(_) => true,
+        Type::Pointer(ref inner) => is_interface_object(inner),
+        _ => false,
+    }
+}
+
+fn interface_call_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, sma: &MemberAccess, call: &Call, target: &Target) -> Option<Var>
+{
+    let dst = if call.return_type == Type::Void {None} else {Some(get_dst(func, &call.return_type))};
+    func.push_destination(None);
+    let mut obj = to_bc(bc_mod, func, &sma.left, target);
+    if let Type::Pointer(ref inner) = obj.typ.clone() {
+        let loaded = stack_alloc(func, inner, None);
+        func.add(load_instr(&loaded, &obj));
+        obj = loaded;
+    }
+    func.pop_destination();
+
+    let index = if let Type::Interface(ref it) = obj.typ {
+        it.functions.iter().position(|f| f.name == call.callee.name)
+            .expect("Internal Compiler Error: unknown interface function")
+    } else {
+        panic!("Internal Compiler Error: interface call on a value of type {}", obj.typ)
+    };
+
+    let args = call_args_to_bc(bc_mod, func, call, None, target);
+    func.add(interface_call_instr(dst.as_ref(), &obj, index, args));
     dst
 }
 
@@ -698,6 +910,7 @@ fn optional_compare_to_bc(
     let set_to_true_bb = func.create_basic_block();
     let set_to_false_bb = func.create_basic_block();
     let compare_inner_bb = func.create_basic_block();
+    let check_nil_bb = func.create_basic_block();
     let end_bb = func.create_basic_block();
 
     let l_is_ok = stack_alloc(func, &Type::Bool, None);
@@ -706,8 +919,13 @@ fn optional_compare_to_bc(
     func.add(load_optional_flag_instr(&l_is_ok, l));
     func.add(load_optional_flag_instr(&r_is_ok, r));
     func.add(binary_op_instr(&both_ok, BinaryOperator::And, var_op(&l_is_ok), var_op(&r_is_ok)));
-    func.add(branch_if_instr(&both_ok, compare_inner_bb, set_to_false_bb));
+    func.add(branch_if_instr(&both_ok, compare_inner_bb, check_nil_bb));
 
+    // Two nils are equal, a nil and a value are not
+    func.set_current_bb(check_nil_bb);
+    let one_ok = stack_alloc(func, &Type::Bool, None);
+    func.add(binary_op_instr(&one_ok, BinaryOperator::Or, var_op(&l_is_ok), var_op(&r_is_ok)));
+    func.add(branch_if_instr(&one_ok, set_to_false_bb, set_to_true_bb));
 
     func.set_current_bb(compare_inner_bb);
     let l_inner = stack_alloc(func, inner_type, None);
@@ -729,11 +947,58 @@ fn optional_compare_to_bc(
     func.set_current_bb(end_bb);
 }
 
+// The right hand side of && and || is only evaluated when the left hand side doesn't decide the result
+fn short_circuit_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, op: &BinaryOp, target: &Target) -> Var
+{
+    let dst = get_dst(func, &op.typ);
+    let right_bb = func.create_basic_block();
+    let end_bb = func.create_basic_block();
+
+    func.push_destination(None);
+    let l = to_bc(bc_mod, func, &op.left, target);
+    func.pop_destination();
+    func.add(store_instr(&dst, &l));
+    if op.operator == BinaryOperator::And {
+        func.add(branch_if_instr(&dst, right_bb, end_bb));
+    } else {
+        func.add(branch_if_instr(&dst, end_bb, right_bb));
+    }
+
+    func.set_current_bb(right_bb);
+    func.push_destination(None);
+    let r = to_bc(bc_mod, func, &op.right, target);
+    func.pop_destination();
+    func.add(store_instr(&dst, &r));
+    func.add(Instruction::Branch(end_bb));
+
+    func.set_current_bb(end_bb);
+    dst
+}
+
+// Members which are not passed by value are loaded as their address, operators need what it points to
+fn load_if_address_of(func: &mut ByteCodeFunction, v: Var, typ: &Type) -> Var
+{
+    match v.typ.clone() {
+        Type::Pointer(ref inner) if **inner == *typ => {
+            let loaded = stack_alloc(func, inner, None);
+            func.add(load_instr(&loaded, &v));
+            loaded
+        },
+        _ => v,
+    }
+}
+
 fn binary_op_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, op: &BinaryOp, target: &Target) -> Var
 {
+    if (op.operator == BinaryOperator::And || op.operator == BinaryOperator::Or) && op.typ == Type::Bool {
+        return short_circuit_to_bc(bc_mod, func, op, target);
+    }
+
     func.push_destination(None);
     let l = to_bc(bc_mod, func, &op.left, target);
+    let l = load_if_address_of(func, l, &op.left.get_type(target.int_size));
     let r = to_bc(bc_mod, func, &op.right, target);
+    let r = load_if_address_of(func, r, &op.right.get_type(target.int_size));
     func.pop_destination();
 
     let dst = get_dst(func, &op.typ);
@@ -771,6 +1036,13 @@ fn binary_op_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, op:
             }
         },
 
+        Type::String | Type::Array(_) | Type::Slice(_) => {
+            let element_type = sequence_element_type(&l.typ).expect("Internal Compiler Error: expecting a sequence");
+            let equal = stack_alloc(func, &Type::Bool, None);
+            sequence_equality_to_bc(func, &l, &r, &element_type, &equal, target);
+            func.add(binary_op_instr(&dst, op.operator, var_op(&equal), Operand::const_bool(true)));
+        },
+
         _ => {
             func.add(binary_op_instr(&dst, op.operator, var_op(&l), var_op(&r)));
         }
@@ -815,26 +1087,42 @@ fn assign_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, assign
     // During type checking, other assigns, will be converted in a regular assign
     assert!(assign.operator == AssignOperator::Assign);
 
+    if let AssignTarget::Var(ref nr) = assign.left {
+        let var = Var::named(&nr.name, nr.typ.clone());
+        if func.is_cleanup_target(&var) {
+            owned_pointer_to_bc(bc_mod, func, &var, &assign.right, target);
+            return;
+        }
+    }
+
     func.push_destination(None);
     let r = to_bc(bc_mod, func, &assign.right, target);
     match assign.left {
         AssignTarget::Var(ref nr) => {
             let var = Var::named(&nr.name, nr.typ.clone());
+            // Globals have a namespace in their name
+            if nr.name.contains("::") {
+                retain_to_bc(func, &r, &assign.right);
+            }
             func.add(store_instr(&var, &r));
         },
 
         AssignTarget::MemberAccess(ref ma) => {
+            retain_to_bc(func, &r, &assign.right);
             member_store_to_bc(func, ma, r, target);
         },
 
         AssignTarget::Dereference(ref d) => {
+            retain_to_bc(func, &r, &assign.right);
             let var = to_bc(bc_mod, func, &d.inner, target);
             func.add(store_instr(&var, &r));
         },
 
         AssignTarget::IndexOperation(ref iop) => {
+            retain_to_bc(func, &r, &assign.right);
             let tgt = to_bc(bc_mod, func, &iop.target, target);
             let idx = to_bc(bc_mod, func, &iop.index_expr, target);
+            bounds_check_to_bc(func, &tgt, &idx, &iop.span, target);
             func.add(store_member_with_var_instr(tgt, idx, r));
         }
     }
@@ -842,9 +1130,271 @@ fn assign_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, assign
     func.pop_destination();
 }
 
+// The length of an array, slice or string, None for pointers
+fn sequence_len_to_bc(func: &mut ByteCodeFunction, seq: &Var, target: &Target) -> Option<Operand>
+{
+    match seq.typ {
+        Type::Array(ref at) => Some(Operand::const_uint(at.len as u64, target.int_size)),
+        Type::Slice(_) | Type::String => {
+            let len = stack_alloc(func, &target.native_uint_type, None);
+            func.add(get_prop_instr(&len, seq, ByteCodeProperty::Len));
+            Some(var_op(&len))
+        },
+        _ => None,
+    }
+}
+
+// Indexing an array, slice or string checks the index against the length, pointers are not checked
+fn bounds_check_to_bc(func: &mut ByteCodeFunction, tgt: &Var, idx: &Var, span: &Span, target: &Target)
+{
+    if let Some(len) = sequence_len_to_bc(func, tgt, target) {
+        func.add(Instruction::BoundsCheck{
+            index: var_op(idx),
+            len: len,
+            inclusive: false,
+            location: format!("{}:{}", span.file, span.start),
+        });
+    }
+}
+
+fn to_native_uint(func: &mut ByteCodeFunction, var: Var, target: &Target) -> Var
+{
+    if var.typ == target.native_uint_type {
+        return var;
+    }
+
+    let dst = stack_alloc(func, &target.native_uint_type, None);
+    func.add(cast_instr(&dst, &var));
+    dst
+}
+
+// target[start..end] becomes a slice of end - start elements at start, after checking that start <= end <= len
+fn slice_operation_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, sop: &SliceOperation, target: &Target) -> Var
+{
+    func.push_destination(None);
+    let tgt = to_bc(bc_mod, func, &sop.target, target);
+    let start = to_bc(bc_mod, func, &sop.start, target);
+    let start = to_native_uint(func, start, target);
+    let end = to_bc(bc_mod, func, &sop.end, target);
+    let end = to_native_uint(func, end, target);
+    func.pop_destination();
+
+    let location = format!("{}:{}", sop.span.file, sop.span.start);
+    if let Some(len) = sequence_len_to_bc(func, &tgt, target) {
+        func.add(Instruction::BoundsCheck{index: var_op(&end), len: len, inclusive: true, location: location.clone()});
+        func.add(Instruction::BoundsCheck{index: var_op(&start), len: var_op(&end), inclusive: true, location: location});
+    }
+
+    let len = stack_alloc(func, &target.native_uint_type, None);
+    func.add(binary_op_instr(&len, BinaryOperator::Sub, var_op(&end), var_op(&start)));
+    let dst = get_dst(func, &sop.typ);
+    func.add(slice_instr(&dst, &tgt, var_op(&start), var_op(&len)));
+    dst
+}
+
+fn format_parts_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, parts: &[Expression], target: &Target) -> Vec<Operand>
+{
+    func.push_destination(None);
+    let mut operands = Vec::with_capacity(parts.len());
+    for part in parts {
+        match *part {
+            Expression::Literal(Literal::String(_, ref s)) => operands.push(Operand::const_string(s.clone())),
+            _ => {
+                let v = to_bc(bc_mod, func, part, target);
+                operands.push(Operand::Var(load_if_address_of(func, v, &part.get_type(target.int_size))));
+            },
+        }
+    }
+    func.pop_destination();
+    operands
+}
+
+// Index of the first element where left and right differ, or the length of the shortest when one starts with the other
+fn first_difference_to_bc(func: &mut ByteCodeFunction, left: &Var, left_len: &Operand, right: &Var, right_len: &Operand, element_type: &Type, target: &Target) -> Var
+{
+    let shorter = stack_alloc(func, &Type::Bool, None);
+    func.add(binary_op_instr(&shorter, BinaryOperator::LessThan, left_len.clone(), right_len.clone()));
+    let min_len = stack_alloc(func, &target.native_uint_type, None);
+    func.add(intrinsic_instr(Some(&min_len), Intrinsic::Select, vec![var_op(&shorter), left_len.clone(), right_len.clone()]));
+
+    let index = stack_alloc(func, &target.native_uint_type, None);
+    func.add(store_operand_instr(&index, Operand::const_uint(0, target.int_size)));
+
+    let cond_bb = func.create_basic_block();
+    let body_bb = func.create_basic_block();
+    let next_bb = func.create_basic_block();
+    let post_bb = func.create_basic_block();
+
+    func.add(Instruction::Branch(cond_bb));
+    func.set_current_bb(cond_bb);
+    let in_range = stack_alloc(func, &Type::Bool, None);
+    func.add(binary_op_instr(&in_range, BinaryOperator::LessThan, var_op(&index), var_op(&min_len)));
+    func.add(branch_if_instr(&in_range, body_bb, post_bb));
+
+    func.set_current_bb(body_bb);
+    let left_element = stack_alloc(func, element_type, None);
+    func.add(load_member_instr_with_var(&left_element, left, &index));
+    let right_element = stack_alloc(func, element_type, None);
+    func.add(load_member_instr_with_var(&right_element, right, &index));
+    let same = stack_alloc(func, &Type::Bool, None);
+    func.add(binary_op_instr(&same, BinaryOperator::Equals, var_op(&left_element), var_op(&right_element)));
+    func.add(branch_if_instr(&same, next_bb, post_bb));
+
+    func.set_current_bb(next_bb);
+    func.add(binary_op_instr(&index, BinaryOperator::Add, var_op(&index), Operand::const_uint(1, target.int_size)));
+    func.add(Instruction::Branch(cond_bb));
+
+    func.set_current_bb(post_bb);
+    index
+}
+
+fn sequence_element_type(typ: &Type) -> Option<Type>
+{
+    match *typ {
+        Type::String => Some(Type::UInt(IntSize::I8)),
+        Type::Array(ref at) => Some(at.element_type.clone()),
+        Type::Slice(ref st) => Some(st.element_type.clone()),
+        _ => None,
+    }
+}
+
+// Sets equal to whether two sequences have the same elements, returns the first difference and both lengths
+fn sequence_equality_to_bc(func: &mut ByteCodeFunction, left: &Var, right: &Var, element_type: &Type, equal: &Var, target: &Target) -> (Var, Operand, Operand)
+{
+    let left_len = sequence_len_to_bc(func, left, target).expect("Internal Compiler Error: expecting a sequence");
+    let right_len = sequence_len_to_bc(func, right, target).expect("Internal Compiler Error: expecting a sequence");
+    let index = first_difference_to_bc(func, left, &left_len, right, &right_len, element_type, target);
+    let at_end = stack_alloc(func, &Type::Bool, None);
+    func.add(binary_op_instr(&at_end, BinaryOperator::Equals, var_op(&index), left_len.clone()));
+    let same_len = stack_alloc(func, &Type::Bool, None);
+    func.add(binary_op_instr(&same_len, BinaryOperator::Equals, left_len.clone(), right_len.clone()));
+    func.add(binary_op_instr(equal, BinaryOperator::And, var_op(&at_end), var_op(&same_len)));
+    (index, left_len, right_len)
+}
+
+// Strings and characters are quoted, so the failure message of @assert_eq shows where they begin and end
+fn quoted_parts(value: Operand, typ: &Type) -> Vec<Operand>
+{
+    match *typ {
+        Type::String => vec![Operand::const_string("\""), value, Operand::const_string("\"")],
+        Type::Char => vec![Operand::const_string("'"), value, Operand::const_string("'")],
+        _ => vec![value],
+    }
+}
+
+// Builds the string [a, b, c] out of an array or slice, by formatting one element at a time
+fn sequence_to_string_bc(func: &mut ByteCodeFunction, seq: &Var, len: &Operand, element_type: &Type, target: &Target) -> Var
+{
+    let s = stack_alloc(func, &Type::String, None);
+    func.add(store_operand_instr(&s, Operand::const_string("[")));
+    let separator = stack_alloc(func, &Type::String, None);
+    func.add(store_operand_instr(&separator, Operand::const_string("")));
+    let index = stack_alloc(func, &target.native_uint_type, None);
+    func.add(store_operand_instr(&index, Operand::const_uint(0, target.int_size)));
+
+    let cond_bb = func.create_basic_block();
+    let body_bb = func.create_basic_block();
+    let post_bb = func.create_basic_block();
+
+    func.add(Instruction::Branch(cond_bb));
+    func.set_current_bb(cond_bb);
+    let in_range = stack_alloc(func, &Type::Bool, None);
+    func.add(binary_op_instr(&in_range, BinaryOperator::LessThan, var_op(&index), len.clone()));
+    func.add(branch_if_instr(&in_range, body_bb, post_bb));
+
+    func.set_current_bb(body_bb);
+    let element = stack_alloc(func, element_type, None);
+    func.add(load_member_instr_with_var(&element, seq, &index));
+    let mut parts = vec![var_op(&s), var_op(&separator)];
+    parts.extend(quoted_parts(var_op(&element), element_type));
+    let next = stack_alloc(func, &Type::String, None);
+    func.add(format_instr(&next, parts));
+    func.add(store_instr(&s, &next));
+    func.add(store_operand_instr(&separator, Operand::const_string(", ")));
+    func.add(binary_op_instr(&index, BinaryOperator::Add, var_op(&index), Operand::const_uint(1, target.int_size)));
+    func.add(Instruction::Branch(cond_bb));
+
+    func.set_current_bb(post_bb);
+    let dst = stack_alloc(func, &Type::String, None);
+    func.add(format_instr(&dst, vec![var_op(&s), Operand::const_string("]")]));
+    dst
+}
+
+// @assert_eq(left, right) panics with both values when they differ, for strings, arrays and slices
+// together with the index of the first difference
+fn assert_eq_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, left: &Expression, right: &Expression, span: &Span, target: &Target)
+{
+    func.push_destination(None);
+    let left = to_bc(bc_mod, func, left, target);
+    let right = to_bc(bc_mod, func, right, target);
+    func.pop_destination();
+
+    let element_type = sequence_element_type(&left.typ);
+    let equal = stack_alloc(func, &Type::Bool, None);
+    let difference = if let Some(ref element_type) = element_type {
+        Some(sequence_equality_to_bc(func, &left, &right, element_type, &equal, target))
+    } else {
+        func.add(binary_op_instr(&equal, BinaryOperator::Equals, var_op(&left), var_op(&right)));
+        None
+    };
+
+    let fail_bb = func.create_basic_block();
+    let ok_bb = func.create_basic_block();
+    func.add(branch_if_instr(&equal, ok_bb, fail_bb));
+
+    func.set_current_bb(fail_bb);
+    let mut parts = vec![Operand::const_string(format!("{}:{}: assertion failed: left == right\n  left: ", span.file, span.start))];
+    match (difference, element_type) {
+        (Some((index, left_len, right_len)), Some(element_type)) => {
+            if left.typ == Type::String {
+                parts.extend(quoted_parts(var_op(&left), &Type::String));
+                parts.push(Operand::const_string("\n right: "));
+                parts.extend(quoted_parts(var_op(&right), &Type::String));
+                parts.push(Operand::const_string("\n first difference at byte "));
+            } else {
+                let left_str = sequence_to_string_bc(func, &left, &left_len, &element_type, target);
+                let right_str = sequence_to_string_bc(func, &right, &right_len, &element_type, target);
+                parts.push(var_op(&left_str));
+                parts.push(Operand::const_string("\n right: "));
+                parts.push(var_op(&right_str));
+                parts.push(Operand::const_string("\n first difference at index "));
+            }
+            parts.push(var_op(&index));
+        },
+        _ => {
+            parts.extend(quoted_parts(var_op(&left), &left.typ));
+            parts.push(Operand::const_string("\n right: "));
+            parts.extend(quoted_parts(var_op(&right), &right.typ));
+        },
+    }
+    parts.push(Operand::const_string("\n"));
+
+    let msg = stack_alloc(func, &Type::String, None);
+    func.add(format_instr(&msg, parts));
+    func.add(Instruction::Panic(var_op(&msg)));
+    func.set_current_bb(ok_bb);
+}
+
+// Stops the program with the source location, what happened and the message, if there is one
+fn panic_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, what: &str, parts: &[Expression], span: &Span, target: &Target)
+{
+    let mut msg_parts = vec![Operand::const_string(format!("{}:{}: {}", span.file, span.start, what))];
+    if !parts.is_empty() {
+        msg_parts.push(Operand::const_string(": "));
+        msg_parts.extend(format_parts_to_bc(bc_mod, func, parts, target));
+    }
+    msg_parts.push(Operand::const_string("\n"));
+
+    let msg = stack_alloc(func, &Type::String, None);
+    func.add(format_instr(&msg, msg_parts));
+    func.add(Instruction::Panic(var_op(&msg)));
+}
+
 fn dereference_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, d: &DereferenceExpression, target: &Target) -> Var
 {
+    func.push_destination(None);
     let inner_var = to_bc(bc_mod, func, &d.inner, target);
+    func.pop_destination();
     let dst = get_dst(func, &d.typ);
     func.add(store_operand_instr(&dst, Operand::Dereference(inner_var)));
     dst
@@ -953,15 +1503,24 @@ fn expr_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, expr: &E
 
         Expression::Delete(ref d) => {
             let to_delete = to_bc(bc_mod, func, &d.inner, target);
-            func.add(Instruction::Delete(to_delete));
+            func.add(Instruction::Delete(to_delete.clone()));
+            if func.is_cleanup_target(&to_delete) {
+                // So it isn't released again at the end of the scope
+                let pointee = to_delete.typ.get_pointer_element_type().expect("Delete of a non pointer").clone();
+                func.add(store_operand_instr(&to_delete, Operand::Const(Constant::NullPtr(pointee))));
+            }
             None
         },
 
         Expression::Lambda(ref l) => {
-            let lambda = func_to_bc(&l.sig, bc_mod, &l.expr, target);
             let dst = get_dst(func, &l.sig.get_type());
-            func.add(store_func_instr(&dst, &lambda.sig.name));
-            bc_mod.functions.insert(l.sig.name.clone(), lambda);
+            if l.captures.is_empty() {
+                let lambda = func_to_bc(&l.sig, bc_mod, &l.expr, target);
+                func.add(store_func_instr(&dst, &lambda.sig.name));
+                bc_mod.functions.insert(l.sig.name.clone(), lambda);
+            } else {
+                closure_to_bc(bc_mod, func, l, &dst, target);
+            }
             Some(dst)
         },
 
@@ -970,6 +1529,12 @@ fn expr_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, expr: &E
         },
 
         Expression::MemberAccess(ref sma) => {
+            if let MemberAccessType::Call(ref call) = sma.right {
+                if is_interface_object(&sma.left.get_type(target.int_size)) {
+                    return interface_call_to_bc(bc_mod, func, sma, call, target);
+                }
+            }
+
             let dst = if sma.typ.pass_by_value() {
                 get_dst(func, &sma.typ)
             } else {
@@ -990,7 +1555,10 @@ fn expr_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, expr: &E
 
         Expression::ArrayToSlice(ref ats) => {
             let dst = get_dst(func, &ats.slice_type);
+            // The array needs a variable of its own, the destination is for the slice
+            func.push_destination(None);
             let array_var = to_bc(bc_mod, func, &ats.inner, target);
+            func.pop_destination();
             let end = stack_alloc(func, &target.native_uint_type, None);
             func.add(get_prop_instr(&end, &array_var, ByteCodeProperty::Len));
             func.add(slice_instr(&dst, &array_var, Operand::const_uint(0, target.int_size), var_op(&end)));
@@ -1057,6 +1625,12 @@ fn expr_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, expr: &E
             Some(dst)
         }
 
+        Expression::CompilerCall(CompilerCall::OffsetOf{ref typ, index, ..}) => {
+            let dst = get_dst(func, &target.native_uint_type);
+            func.add(store_operand_instr(&dst, Operand::OffsetOf(typ.clone(), index)));
+            Some(dst)
+        }
+
         Expression::CompilerCall(CompilerCall::Slice{ref data, ref len, ref typ, ..}) => {
             func.push_destination(None);
             let data_ptr = to_bc(bc_mod, func, data, target);
@@ -1067,19 +1641,98 @@ fn expr_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, expr: &E
             Some(dst)
         }
 
+        Expression::CompilerCall(CompilerCall::Intrinsic{intrinsic, ref args, ref typ, ..}) => {
+            func.push_destination(None);
+            let args = args.iter().map(|arg| Operand::Var(to_bc(bc_mod, func, arg, target))).collect();
+            func.pop_destination();
+            if let Type::Void = *typ {
+                func.add(intrinsic_instr(None, intrinsic, args));
+                None
+            } else {
+                let dst = get_dst(func, typ);
+                func.add(intrinsic_instr(Some(&dst), intrinsic, args));
+                Some(dst)
+            }
+        }
+
+        Expression::CompilerCall(CompilerCall::Format{ref parts, ..}) => {
+            let parts = format_parts_to_bc(bc_mod, func, parts, target);
+            let dst = get_dst(func, &Type::String);
+            func.add(format_instr(&dst, parts));
+            Some(dst)
+        }
+
+        Expression::CompilerCall(CompilerCall::Log{level, ref parts, ref span}) => {
+            // Below the log level nothing is generated, not even the evaluation of the parts
+            if level >= bc_mod.log_level {
+                let mut msg_parts = vec![Operand::const_string(format!("[{}] {}:{}: ", level, span.file, span.start))];
+                msg_parts.extend(format_parts_to_bc(bc_mod, func, parts, target));
+                msg_parts.push(Operand::const_string("\n"));
+                let msg = stack_alloc(func, &Type::String, None);
+                func.add(format_instr(&msg, msg_parts));
+                func.add(Instruction::Log(var_op(&msg)));
+            }
+            None
+        }
+
+        Expression::CompilerCall(CompilerCall::AssertEq{ref left, ref right, ref span}) => {
+            assert_eq_to_bc(bc_mod, func, left, right, span, target);
+            None
+        }
+
+        Expression::CompilerCall(CompilerCall::Assert{ref cond, ref parts, ref span}) => {
+            func.push_destination(None);
+            let cond = to_bc(bc_mod, func, cond, target);
+            func.pop_destination();
+            let fail_bb = func.create_basic_block();
+            let ok_bb = func.create_basic_block();
+            func.add(branch_if_instr(&cond, ok_bb, fail_bb));
+
+            func.set_current_bb(fail_bb);
+            panic_to_bc(bc_mod, func, "assertion failed", parts, span, target);
+            func.set_current_bb(ok_bb);
+            None
+        }
+
+        Expression::CompilerCall(CompilerCall::Panic{ref parts, ref span}) => {
+            panic_to_bc(bc_mod, func, "panic", parts, span, target);
+            // Whatever follows is unreachable, but still needs a block to go in
+            let after_bb = func.create_basic_block();
+            func.set_current_bb(after_bb);
+            None
+        }
+
+        Expression::CompilerCall(CompilerCall::Fields(query, ref typ, _)) => {
+            panic!("Internal Compiler Error: @{}({}) should have been expanded by the type checker", query, typ)
+        }
+
+        Expression::CompilerCall(CompilerCall::FieldValues(_, ref span)) => {
+            panic!("Internal Compiler Error: @field_values at {} should have been expanded by the type checker", span)
+        }
+
         Expression::IndexOperation(ref iop) => {
+            func.push_destination(None);
             let tgt = to_bc(bc_mod, func, &iop.target, target);
             let idx = to_bc(bc_mod, func, &iop.index_expr, target);
+            func.pop_destination();
+            bounds_check_to_bc(func, &tgt, &idx, &iop.span, target);
             let dst = get_dst(func, &iop.typ);
             func.add(load_member_instr_with_var(&dst, &tgt, &idx));
             Some(dst)
         }
 
+        Expression::SliceOperation(ref sop) => {
+            Some(slice_operation_to_bc(bc_mod, func, sop, target))
+        }
+
         Expression::Return(ref r) => {
             func.push_destination(None);
             if let Some(var) = expr_to_bc(bc_mod, func, &r.expression, target) {
+                let var = returned_reference_to_bc(func, var, &r.expression);
+                func.cleanup_all_scopes();
                 func.add(Instruction::Return(Operand::Var(var)));
             } else {
+                func.cleanup_all_scopes();
                 func.add(Instruction::ReturnVoid)
             }
             func.pop_destination();
@@ -1088,15 +1741,50 @@ fn expr_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, expr: &E
     }
 }
 
+/*
+A lambda which captures variables, gets a pointer to a struct with a copy of them as extra first argument ($env).
+On entry the captured values are loaded into local variables with the same names, so the body can use them as usual.
+At the place of the lambda expression the struct is filled in, and turned together with the function into a closure.
+*/
+fn closure_to_bc(bc_mod: &mut ByteCodeModule, func: &mut ByteCodeFunction, l: &Lambda, dst: &Var, target: &Target)
+{
+    let env_type = struct_type("", l.captures.iter().map(|c| struct_member(&c.name, c.typ.clone())).collect());
+    let env = stack_alloc(func, &env_type, None);
+    for (idx, c) in l.captures.iter().enumerate() {
+        func.add(store_member_instr(&env, idx, Var::named(&c.name, c.typ.clone()), target.int_size));
+    }
+
+    let env_ptr = Var::named("$env", ptr_type(env_type));
+    let mut sig = l.sig.clone();
+    sig.args.insert(0, Argument::new(env_ptr.name.clone(), env_ptr.typ.clone(), false, l.span.clone()));
+
+    let mut lambda = ByteCodeFunction::new(&sig, false);
+    for (idx, c) in l.captures.iter().enumerate() {
+        let member = stack_alloc(&mut lambda, &c.typ, None);
+        lambda.add(load_member_instr(&member, &env_ptr, idx, target.int_size));
+        let var = stack_alloc(&mut lambda, &c.typ, Some(&c.name));
+        lambda.add(store_instr(&var, &member));
+    }
+
+    let lambda = func_body_to_bc(lambda, bc_mod, &l.expr, target);
+    func.add(make_closure_instr(dst, &l.sig.name, &env));
+    bc_mod.functions.insert(l.sig.name.clone(), lambda);
+}
+
 fn func_to_bc(sig: &FunctionSignature, bc_mod: &mut ByteCodeModule, expression: &Expression, target: &Target) -> ByteCodeFunction
 {
-    let mut llfunc = ByteCodeFunction::new(sig, false);
+    func_body_to_bc(ByteCodeFunction::new(sig, false), bc_mod, expression, target)
+}
+
+fn func_body_to_bc(mut llfunc: ByteCodeFunction, bc_mod: &mut ByteCodeModule, expression: &Expression, target: &Target) -> ByteCodeFunction
+{
     match expr_to_bc(bc_mod, &mut llfunc, expression, target)
     {
         Some(ref var) if var.typ != Type::Void => {
+            let var = returned_reference_to_bc(&mut llfunc, var.clone(), expression);
             // Pop final scope before returning
             llfunc.pop_scope();
-            llfunc.add(ret_instr(var));
+            llfunc.add(ret_instr(&var));
         },
 
         _ => {
@@ -1108,45 +1796,130 @@ fn func_to_bc(sig: &FunctionSignature, bc_mod: &mut ByteCodeModule, expression:
     llfunc
 }
 
-pub fn compile_to_byte_code(pkg: &Package, target: &Target) -> CompileResult<ByteCodeModule>
+fn global_init_to_bc(bc_mod: &mut ByteCodeModule, global: &GlobalBinding, init_name: &str, target: &Target) -> ByteCodeFunction
+{
+    let init_sig = sig(init_name, Type::Void, Vec::new(), global.span.clone());
+    let mut llfunc = ByteCodeFunction::new(&init_sig, false);
+    let v = to_bc(bc_mod, &mut llfunc, &global.init, target);
+    llfunc.add(store_instr(&Var::named(&global.name, global.typ.clone()), &v));
+    llfunc.pop_scope();
+    llfunc.add(Instruction::ReturnVoid);
+    llfunc
+}
+
+// A main function which only calls a @test function, and returns 0 when it does, for cobrac test
+pub fn test_main_function(bc_mod: &ByteCodeModule, test_name: &str, target: &Target) -> Option<ByteCodeFunction>
+{
+    let span = bc_mod.get_function(test_name)?.sig.span.clone();
+    let main_sig = sig(&bc_mod.main_function_name(), target.native_int_type.clone(), Vec::new(), span);
+    let mut llfunc = ByteCodeFunction::new(&main_sig, false);
+    llfunc.add(Instruction::Call{dst: None, func: test_name.into(), args: Vec::new()});
+    llfunc.pop_scope();
+    llfunc.add(Instruction::Return(Operand::const_int(0, target.int_size)));
+    Some(llfunc)
+}
+
+pub fn compile_to_byte_code(pkg: &Package, target: &Target, eager_globals: bool, log_level: LogLevel) -> CompileResult<ByteCodeModule>
 {
     let mut ll_mod = ByteCodeModule{
         name: pkg.name.clone(),
         functions: HashMap::new(),
         globals: HashMap::new(),
+        runtime_globals: HashMap::new(),
+        thread_local_globals: HashSet::new(),
+        exported_functions: HashSet::new(),
         imported_functions: Vec::new(),
+        init_functions: Vec::new(),
+        fini_functions: Vec::new(),
+        log_level,
     };
 
+    // Within a module, @init functions run in declaration order, and @fini functions in the reverse order
+    let mut module_names: Vec<&String> = pkg.modules.keys().collect();
+    module_names.sort();
+    for name in module_names {
+        let mut funcs: Vec<&Function> = pkg.modules[name].functions.values().filter(|f| f.init || f.fini).collect();
+        funcs.sort_by_key(|f| f.span.start);
+        for func in funcs {
+            if func.init {
+                ll_mod.init_functions.push(func.sig.name.clone());
+            } else {
+                ll_mod.fini_functions.insert(0, func.sig.name.clone());
+            }
+        }
+    }
+
 
+    let mut num_eager_inits = 0;
     for md in pkg.modules.values() {
         for func in md.externals.values() {
             ll_mod.functions.insert(func.sig.name.clone(), ByteCodeFunction::new(&func.sig, true));
         }
 
+        let mut runtime_inits = Vec::new();
         for global in md.globals.values() {
+            if global.thread_local {
+                ll_mod.thread_local_globals.insert(global.name.clone());
+            }
+
             if let Some(cst) = expr_to_const(&global.init) {
                 ll_mod.globals.insert(global.name.clone(), cst);
             } else {
-                return type_error_result(&global.span, format!("Global {} must be initialized with a constant expression", global.name));
+                let init_name = format!("{}.init", global.name);
+                let init_func = global_init_to_bc(&mut ll_mod, global, &init_name, target);
+                ll_mod.functions.insert(init_name.clone(), init_func);
+                // Thread local globals have to be initialized on every thread, so they are always lazy
+                let lazy = !eager_globals || global.thread_local;
+                ll_mod.runtime_globals.insert(global.name.clone(), RuntimeGlobal{
+                    typ: global.typ.clone(),
+                    init_function: init_name.clone(),
+                    lazy: lazy,
+                });
+
+                if !lazy {
+                    runtime_inits.push((global.span.start, init_name));
+                }
             }
         }
 
+        // Eager globals are initialized in declaration order, before any @init function
+        runtime_inits.sort();
+        for (_, init_name) in runtime_inits {
+            ll_mod.init_functions.insert(num_eager_inits, init_name);
+            num_eager_inits += 1;
+        }
+
         for func in md.functions.values() {
             if !func.is_generic() {
                 let new_func = func_to_bc(&func.sig, &mut ll_mod, &func.expression, target);
+                if func.constant_time {
+                    check_constant_time(&new_func, &func.span)?;
+                }
+                if func.export {
+                    ll_mod.exported_functions.insert(func.sig.name.clone());
+                }
                 ll_mod.functions.insert(func.sig.name.clone(), new_func);
             }
         }
     }
 
 
-    for import in pkg.import_data.imports.values() {
+    let library_imports = pkg.import_data.libraries.iter().flat_map(|lib| lib.imports.iter());
+    for import in pkg.import_data.imports.values().chain(library_imports) {
         for symbol in import.symbols.values() {
             if let Some(s) = FunctionSignature::from_type(&symbol.name, &symbol.typ) {
                 if ll_mod.functions.contains_key(&symbol.name) || symbol.typ.is_generic() {
                     continue;
                 }
-                ll_mod.imported_functions.push(ByteCodeFunction::new(&s, true));
+
+                // Functions from other packages with an exported body get a copy, so they can be inlined
+                match import.inline_functions.get(&symbol.name) {
+                    Some(func) => {
+                        let imported_func = func_to_bc(&func.sig, &mut ll_mod, &func.expression, target);
+                        ll_mod.imported_functions.push(imported_func);
+                    },
+                    None => ll_mod.imported_functions.push(ByteCodeFunction::new(&s, true)),
+                }
             }
         }
     }
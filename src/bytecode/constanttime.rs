@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+use compileerror::{CompileResult, type_error_result};
+use span::Span;
+use super::function::{ByteCodeFunction, Var};
+use super::instruction::{Instruction, Operand, ByteCodeProperty};
+
+fn operand_vars<'a>(operands: &[&'a Operand]) -> Vec<&'a Var>
+{
+    operands.iter()
+        .filter_map(|op| match **op {
+            Operand::Var(ref v) |
+            Operand::AddressOf(ref v) |
+            Operand::Dereference(ref v) => Some(v),
+            _ => None,
+        })
+        .collect()
+}
+
+// Returns the variable written by an instruction, and the variables its value is derived from
+fn data_flow(instr: &Instruction) -> Option<(&Var, Vec<&Var>)>
+{
+    match *instr
+    {
+        Instruction::Store{ref dst, ref src} |
+        Instruction::UnaryOp{ref dst, ref src, ..} |
+        Instruction::Cast{ref dst, ref src} => Some((dst, operand_vars(&[src]))),
+        Instruction::Load{ref dst, ref ptr} => Some((dst, vec![ptr])),
+        Instruction::LoadMember{ref dst, ref obj, ref member_index} |
+        Instruction::AddressOfMember{ref dst, ref obj, ref member_index} => {
+            let mut sources = operand_vars(&[member_index]);
+            sources.push(obj);
+            Some((dst, sources))
+        },
+        Instruction::StoreMember{ref obj, ref member_index, ref src} => Some((obj, operand_vars(&[member_index, src]))),
+        Instruction::AddressOf{ref dst, ref obj} |
+        Instruction::MakeInterface{ref dst, ref obj, ..} |
+        Instruction::MakeClosure{ref dst, env: ref obj, ..} |
+        Instruction::LoadOptionalFlag{ref dst, ref obj} => Some((dst, vec![obj])),
+        // The length of a slice is not considered a secret
+        Instruction::GetProperty{prop: ByteCodeProperty::Len, ..} => None,
+        Instruction::GetProperty{ref dst, ref obj, ..} => Some((dst, vec![obj])),
+        Instruction::BinaryOp{ref dst, ref left, ref right, ..} => Some((dst, operand_vars(&[left, right]))),
+        Instruction::Call{dst: Some(ref dst), ref args, ..} |
+        Instruction::InterfaceCall{dst: Some(ref dst), ref args, ..} |
+        Instruction::Intrinsic{dst: Some(ref dst), ref args, ..} |
+        Instruction::Format{ref dst, parts: ref args} => {
+            let args: Vec<&Operand> = args.iter().collect();
+            Some((dst, operand_vars(&args)))
+        },
+        Instruction::Slice{ref dst, ref src, ref start, ref len} => {
+            let mut sources = operand_vars(&[start, len]);
+            sources.push(src);
+            Some((dst, sources))
+        },
+        Instruction::MakeSlice{ref dst, ref data, ref len} => Some((dst, vec![data, len])),
+        _ => None,
+    }
+}
+
+fn is_secret(secrets: &HashSet<String>, op: &Operand) -> bool
+{
+    operand_vars(&[op]).iter().any(|v| secrets.contains(&v.name))
+}
+
+fn find_leak(instr: &Instruction, secrets: &HashSet<String>) -> Option<&'static str>
+{
+    match *instr
+    {
+        Instruction::BranchIf{ref cond, ..} |
+        Instruction::Switch{ref cond, ..} if is_secret(secrets, cond) => Some("branches on a secret value"),
+        Instruction::LoadMember{ref member_index, ..} |
+        Instruction::StoreMember{ref member_index, ..} |
+        Instruction::AddressOfMember{ref member_index, ..} if is_secret(secrets, member_index) => Some("indexes memory with a secret value"),
+        Instruction::Slice{ref start, ref len, ..} if is_secret(secrets, start) || is_secret(secrets, len) => Some("slices memory with a secret value"),
+        _ => None,
+    }
+}
+
+// Taint analysis of a function marked @constant_time, all arguments are considered to be secret,
+// and every value derived from them as well. Branches or memory accesses depending on a secret are rejected.
+pub fn check_constant_time(func: &ByteCodeFunction, span: &Span) -> CompileResult<()>
+{
+    let mut secrets: HashSet<String> = func.sig.args.iter().map(|arg| arg.name.clone()).collect();
+    let mut changed = true;
+    while changed
+    {
+        changed = false;
+        func.for_each_instruction(|instr| {
+            if let Some((dst, sources)) = data_flow(instr) {
+                if !secrets.contains(&dst.name) && sources.iter().any(|v| secrets.contains(&v.name)) {
+                    secrets.insert(dst.name.clone());
+                    changed = true;
+                }
+            }
+            true
+        });
+    }
+
+    let mut leak = None;
+    func.for_each_instruction(|instr| {
+        leak = find_leak(instr, &secrets);
+        leak.is_none()
+    });
+
+    match leak {
+        Some(msg) => type_error_result(span, format!("Function {} is marked @constant_time, but it {}", func.sig.name, msg)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use bytecode::test::generate_byte_code;
+    use super::check_constant_time;
+    use span::Span;
+
+    fn check(prog: &str) -> bool
+    {
+        let bc_mod = generate_byte_code(prog, false).expect("Compilation failed");
+        let func = bc_mod.get_function("test::foo").expect("Missing function foo");
+        check_constant_time(func, &Span::default()).is_ok()
+    }
+
+    #[test]
+    fn test_secret_branch()
+    {
+        assert!(check(r#"
+fn foo(a: int, b: int) -> int: @ct_select(a > b, a, b)
+"#));
+        assert!(!check(r#"
+fn foo(a: int, b: int) -> int: if a > b: a else b
+"#));
+    }
+
+    #[test]
+    fn test_secret_index()
+    {
+        assert!(check(r#"
+fn foo(key: int[]) -> int:
+    var acc = 0
+    for k in key:
+        acc += k
+    acc
+"#));
+        assert!(!check(r#"
+fn foo(key: int[], idx: uint) -> int: key[idx]
+"#));
+    }
+}
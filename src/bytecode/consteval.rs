@@ -55,7 +55,7 @@ fn unary_op_to_const(uop: &UnaryOp) -> Option<Constant>
     }
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(float_cmp))]
+#[allow(clippy::float_cmp)]
 fn binary_op_to_const(bop: &BinaryOp) -> Option<Constant>
 {
     let left = try_opt!(expr_to_const(&bop.left));
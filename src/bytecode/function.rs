@@ -1,8 +1,9 @@
 use std::fmt;
+use std::mem;
 use std::collections::{BTreeMap, HashMap};
 use itertools::free::join;
 use ast::{Type, FunctionSignature};
-use bytecode::instruction::Instruction;
+use bytecode::instruction::{Instruction, Operand, Constant};
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Var
@@ -64,7 +65,7 @@ impl Scope
         self.named_vars.insert(var.name.clone(), var);
     }
 
-/*
+    // Returns false if the variable doesn't belong to this scope
     pub fn add_cleanup_target(&mut self, v: &Var) -> bool
     {
         if self.named_vars.get(&v.name).is_none() {
@@ -75,6 +76,7 @@ impl Scope
         }
     }
 
+/*
     pub fn remove_cleanup_target(&mut self, v: &Var) -> bool
     {
         let len = self.to_cleanup.len();
@@ -82,11 +84,11 @@ impl Scope
         self.to_cleanup.len() < len
     }
 */
-    pub fn cleanup(&self, _func: &mut ByteCodeFunction)
+    pub fn cleanup(&self, func: &mut ByteCodeFunction)
     {
         // Cleanup in reverse construction order
-        for _v in self.to_cleanup.iter().rev() {
-            panic!("TODO: add destructor calls");
+        for v in self.to_cleanup.iter().rev() {
+            func.add(Instruction::DecRef(v.clone()));
         }
     }
 }
@@ -122,11 +124,18 @@ impl BasicBlock
 
     pub fn add(&mut self, inst: Instruction)
     {
-        if inst.is_terminator() && self.instructions.last().map(|i| i.is_terminator()).unwrap_or(false) {
+        if inst.is_terminator() && self.instructions.iter().any(|i| i.is_terminator()) {
             // Already a terminator drop this, this only happens with an early return
             return;
         }
 
+        match inst {
+            // An early return already released everything
+            Instruction::IncRef(_) |
+            Instruction::DecRef(_) if self.instructions.iter().any(|i| i.is_terminator()) => return,
+            _ => (),
+        }
+
         self.instructions.push(inst);
     }
 }
@@ -194,6 +203,31 @@ impl ByteCodeFunction
         self.current_bb = bb_ref;
     }
 
+    // Renumbers the blocks after bb_ref, so that count new empty blocks follow it directly
+    pub fn insert_blocks_after(&mut self, bb_ref: BasicBlockRef, count: usize)
+    {
+        let shift = |b: BasicBlockRef| if b > bb_ref {b + count} else {b};
+        let blocks = mem::replace(&mut self.blocks, BTreeMap::new());
+        for (old_ref, mut block) in blocks {
+            for instr in &mut block.instructions {
+                for target in instr.branch_targets_mut() {
+                    *target = shift(*target);
+                }
+            }
+
+            let new_ref = shift(old_ref);
+            block.name = bb_name(new_ref);
+            self.blocks.insert(new_ref, block);
+        }
+
+        for new_ref in bb_ref + 1..bb_ref + 1 + count {
+            self.blocks.insert(new_ref, BasicBlock::new(bb_name(new_ref)));
+        }
+
+        self.current_bb = shift(self.current_bb);
+        self.bb_counter += count;
+    }
+
     pub fn new_var(&mut self, typ: Type) -> Var
     {
         let idx = self.var_counter;
@@ -289,23 +323,59 @@ impl ByteCodeFunction
         }
     }
 
+    pub fn remove_instruction<Pred: Fn(&Instruction) -> bool>(&mut self, pred: Pred)
+    {
+        for block in self.blocks.values_mut() {
+            block.instructions.retain(|instr| !pred(instr));
+        }
+    }
 
     /*
-        pub fn remove_instruction<Pred: Fn(&Instruction) -> bool>(&mut self, pred: Pred) {
-            for block in self.blocks.values_mut() {
-                block.instructions.retain(|instr| !pred(instr));
-            }
-        }
+        Release v at the end of the scope it is declared in. It is set to null at the start of the scope,
+        so that it can be released before it gets a new value, when the binding is in a loop.
+    */
+    pub fn add_cleanup_target(&mut self, v: &Var)
+    {
+        let inner = match v.typ {
+            Type::Pointer(ref inner) => (**inner).clone(),
+            _ => panic!("Internal Compiler Error: only pointers can be released"),
+        };
 
-        pub fn add_cleanup_target(&mut self, v: &Var)
-        {
-            for scope in self.scopes.iter_mut().rev() {
-                if scope.add_cleanup_target(v) {
-                    break;
+        let position = match self.scopes.iter_mut().rev().find(|scope| scope.named_vars.contains_key(&v.name)) {
+            Some(scope) => {
+                if scope.to_cleanup.contains(v) {
+                    return;
                 }
+                scope.add_cleanup_target(v);
+                (scope.insert_block, scope.insert_position)
+            },
+            None => return,
+        };
+
+        let null = Instruction::Store{dst: v.clone(), src: Operand::Const(Constant::NullPtr(inner))};
+        self.blocks.get_mut(&position.0).expect("Unknown block").instructions.insert(position.1, null);
+        for scope in &mut self.scopes {
+            if scope.insert_block == position.0 && scope.insert_position > position.1 {
+                scope.insert_position += 1;
             }
         }
-        */
+    }
+
+    pub fn is_cleanup_target(&self, v: &Var) -> bool
+    {
+        self.scopes.iter().any(|scope| scope.to_cleanup.contains(v))
+    }
+
+    // Release the variables of all scopes, before returning from the function
+    pub fn cleanup_all_scopes(&mut self)
+    {
+        let vars: Vec<Var> = self.scopes.iter().rev()
+            .flat_map(|scope| scope.to_cleanup.iter().rev().cloned())
+            .collect();
+        for v in vars {
+            self.add(Instruction::DecRef(v));
+        }
+    }
 }
 
 impl fmt::Display for ByteCodeFunction
@@ -1,7 +1,8 @@
 use std::fmt;
 use itertools::free::join;
-use ast::{UnaryOperator, BinaryOperator, Type, IntSize, FloatSize, ptr_type, array_type};
+use ast::{UnaryOperator, BinaryOperator, Intrinsic, LoopHints, Type, IntSize, FloatSize, ptr_type, array_type};
 use bytecode::function::{BasicBlockRef, Var};
+use span::Pos;
 
 
 #[derive(Debug, Clone, Copy)]
@@ -86,6 +87,7 @@ pub enum Operand
     Const(Constant),
     Func(String),
     SizeOf(Type),
+    OffsetOf(Type, usize),
 }
 
 impl Operand
@@ -129,7 +131,19 @@ impl Operand
             Operand::Dereference(ref var) => var.typ.get_pointer_element_type().expect("Dereference on a non pointer").clone(),
             Operand::Const(ref c) => c.get_type(),
             Operand::Func(_) => Type::Unknown,
-            Operand::SizeOf(_) => Type::UInt(int_size),
+            Operand::SizeOf(_) |
+            Operand::OffsetOf(_, _) => Type::UInt(int_size),
+        }
+    }
+
+    // The variable an operand refers to, if any
+    pub fn var_mut(&mut self) -> Option<&mut Var>
+    {
+        match *self {
+            Operand::Var(ref mut v) |
+            Operand::AddressOf(ref mut v) |
+            Operand::Dereference(ref mut v) => Some(v),
+            _ => None,
         }
     }
 }
@@ -146,6 +160,7 @@ impl fmt::Display for Operand
             Operand::Const(ref c) => write!(f, "{}", c),
             Operand::Func(ref func) => write!(f, "(func {})", func),
             Operand::SizeOf(ref typ) => write!(f, "@size({})", typ),
+            Operand::OffsetOf(ref typ, index) => write!(f, "@offsetof({}, {})", typ, index),
         }
     }
 }
@@ -179,20 +194,45 @@ pub enum Instruction
     UnaryOp{dst: Var, op: UnaryOperator, src: Operand},
     BinaryOp{dst: Var, op: BinaryOperator, left: Operand, right: Operand},
     Call{dst: Option<Var>, func: String, args: Vec<Operand>},
+    Intrinsic{dst: Option<Var>, intrinsic: Intrinsic, args: Vec<Operand>},
     Slice{dst: Var, src: Var, start: Operand, len: Operand},
     MakeSlice{dst: Var, data: Var, len: Var},
+    // Converts all parts to a string and concatenates them into a newly allocated string
+    Format{dst: Var, parts: Vec<Operand>},
     Cast{dst: Var, src: Operand},
+    // Turns a pointer to a concrete type into an interface object, using a table of the given functions
+    MakeInterface{dst: Var, obj: Var, functions: Vec<String>},
+    // Calls the function at index in the table of an interface object, the object's data pointer is passed as first argument
+    InterfaceCall{dst: Option<Var>, obj: Var, index: usize, args: Vec<Operand>},
+    // Turns a function which takes a pointer to env as first argument, into a function pointer which passes env itself
+    MakeClosure{dst: Var, func: String, env: Var},
     LoadOptionalFlag{dst: Var, obj: Var},
     StoreNil(Var),
     StackAlloc(Var),
     HeapAlloc(Var),
     StartScope,
     EndScope,
+    // Position in the source file of the instructions which follow
+    SourceLocation(Pos),
+    // Optimization hints for the loop, which apply to the branch back to the start of the loop that follows
+    LoopHint(LoopHints),
     Return(Operand),
     ReturnVoid,
     Branch(BasicBlockRef),
     BranchIf{cond: Operand, on_true: BasicBlockRef, on_false: BasicBlockRef},
+    // Branch to the block of the case equal to cond, or to default if there is none
+    Switch{cond: Operand, cases: Vec<(Constant, BasicBlockRef)>, default: BasicBlockRef},
     Delete(Var),
+    // Reference counting of the memory a pointer points to, only memory allocated with new is counted.
+    // DecRef frees the memory when the count drops to zero, and sets the variable to null.
+    IncRef(Var),
+    DecRef(Var),
+    // Runtime error at location (file:line:offset), unless index is smaller than len, or with inclusive not larger than len
+    BoundsCheck{index: Operand, len: Operand, inclusive: bool, location: String},
+    // Runtime error with the string operand as message
+    Panic(Operand),
+    // Writes the string operand to stderr
+    Log(Operand),
 }
 
 impl Instruction
@@ -202,11 +242,80 @@ impl Instruction
         match *self {
             Instruction::Branch(_) |
             Instruction::BranchIf{..} |
+            Instruction::Switch{..} |
             Instruction::ReturnVoid |
-            Instruction::Return(_) => true,
+            Instruction::Return(_) |
+            Instruction::Panic(_) => true,
             _ => false
         }
     }
+
+    // The blocks a terminator can branch to
+    pub fn branch_targets_mut(&mut self) -> Vec<&mut BasicBlockRef>
+    {
+        match *self {
+            Instruction::Branch(ref mut bb_ref) => vec![bb_ref],
+            Instruction::BranchIf{ref mut on_true, ref mut on_false, ..} => vec![on_true, on_false],
+            Instruction::Switch{ref mut cases, ref mut default, ..} => {
+                let mut targets: Vec<&mut BasicBlockRef> = cases.iter_mut().map(|&mut (_, ref mut bb_ref)| bb_ref).collect();
+                targets.push(default);
+                targets
+            },
+            _ => Vec::new(),
+        }
+    }
+    // All variables an instruction writes to or refers to
+    pub fn vars_mut(&mut self) -> Vec<&mut Var>
+    {
+        let (vars, operands): (Vec<&mut Var>, Vec<&mut Operand>) = match *self {
+            Instruction::Store{ref mut dst, ref mut src} |
+            Instruction::UnaryOp{ref mut dst, ref mut src, ..} |
+            Instruction::Cast{ref mut dst, ref mut src} => (vec![dst], vec![src]),
+            Instruction::Load{ref mut dst, ptr: ref mut obj} |
+            Instruction::AddressOf{ref mut dst, ref mut obj} |
+            Instruction::GetProperty{ref mut dst, ref mut obj, ..} |
+            Instruction::MakeInterface{ref mut dst, ref mut obj, ..} |
+            Instruction::MakeClosure{ref mut dst, env: ref mut obj, ..} |
+            Instruction::LoadOptionalFlag{ref mut dst, ref mut obj} => (vec![dst, obj], Vec::new()),
+            Instruction::LoadMember{ref mut dst, ref mut obj, ref mut member_index} |
+            Instruction::AddressOfMember{ref mut dst, ref mut obj, ref mut member_index} => (vec![dst, obj], vec![member_index]),
+            Instruction::StoreMember{ref mut obj, ref mut member_index, ref mut src} => (vec![obj], vec![member_index, src]),
+            Instruction::SetProperty{ref mut obj, ..} => (vec![obj], Vec::new()),
+            Instruction::BinaryOp{ref mut dst, ref mut left, ref mut right, ..} => (vec![dst], vec![left, right]),
+            Instruction::Call{ref mut dst, ref mut args, ..} |
+            Instruction::Intrinsic{ref mut dst, ref mut args, ..} => (dst.iter_mut().collect(), args.iter_mut().collect()),
+            Instruction::InterfaceCall{ref mut dst, ref mut obj, ref mut args, ..} => {
+                let mut vars: Vec<&mut Var> = dst.iter_mut().collect();
+                vars.push(obj);
+                (vars, args.iter_mut().collect())
+            },
+            Instruction::Format{ref mut dst, ref mut parts} => (vec![dst], parts.iter_mut().collect()),
+            Instruction::Slice{ref mut dst, ref mut src, ref mut start, ref mut len} => (vec![dst, src], vec![start, len]),
+            Instruction::MakeSlice{ref mut dst, ref mut data, ref mut len} => (vec![dst, data, len], Vec::new()),
+            Instruction::StoreNil(ref mut v) |
+            Instruction::StackAlloc(ref mut v) |
+            Instruction::HeapAlloc(ref mut v) |
+            Instruction::Delete(ref mut v) |
+            Instruction::IncRef(ref mut v) |
+            Instruction::DecRef(ref mut v) => (vec![v], Vec::new()),
+            Instruction::Return(ref mut op) |
+            Instruction::Panic(ref mut op) |
+            Instruction::Log(ref mut op) |
+            Instruction::BranchIf{cond: ref mut op, ..} |
+            Instruction::Switch{cond: ref mut op, ..} => (Vec::new(), vec![op]),
+            Instruction::BoundsCheck{ref mut index, ref mut len, ..} => (Vec::new(), vec![index, len]),
+            Instruction::StartScope |
+            Instruction::EndScope |
+            Instruction::SourceLocation(_) |
+            Instruction::LoopHint(_) |
+            Instruction::ReturnVoid |
+            Instruction::Branch(_) => (Vec::new(), Vec::new()),
+        };
+
+        vars.into_iter()
+            .chain(operands.into_iter().filter_map(|op| op.var_mut()))
+            .collect()
+    }
 }
 
 pub fn store_instr(dst: &Var, src: &Var) -> Instruction
@@ -328,6 +437,15 @@ pub fn branch_if_instr(cond: &Var, on_true: BasicBlockRef, on_false: BasicBlockR
     }
 }
 
+pub fn switch_instr(cond: &Var, cases: Vec<(Constant, BasicBlockRef)>, default: BasicBlockRef) -> Instruction
+{
+    Instruction::Switch{
+        cond: var_op(cond),
+        cases: cases,
+        default: default,
+    }
+}
+
 pub fn call_instr(dst: &Var, func: &str, args: Vec<Operand>) -> Instruction
 {
     Instruction::Call{
@@ -346,6 +464,15 @@ pub fn void_call_instr(func: &str, args: Vec<Operand>) -> Instruction
     }
 }
 
+pub fn intrinsic_instr(dst: Option<&Var>, intrinsic: Intrinsic, args: Vec<Operand>) -> Instruction
+{
+    Instruction::Intrinsic{
+        dst: dst.cloned(),
+        intrinsic: intrinsic,
+        args: args
+    }
+}
+
 pub fn set_prop_instr(obj: &Var, prop: ByteCodeProperty, value: usize) -> Instruction
 {
     Instruction::SetProperty{
@@ -383,6 +510,14 @@ pub fn make_slice_instr(dst: &Var, data: Var, len: Var) -> Instruction
     }
 }
 
+pub fn format_instr(dst: &Var, parts: Vec<Operand>) -> Instruction
+{
+    Instruction::Format{
+        dst: dst.clone(),
+        parts,
+    }
+}
+
 pub fn cast_instr(dst: &Var, src: &Var) -> Instruction
 {
     Instruction::Cast{
@@ -391,6 +526,34 @@ pub fn cast_instr(dst: &Var, src: &Var) -> Instruction
     }
 }
 
+pub fn make_interface_instr(dst: &Var, obj: &Var, functions: Vec<String>) -> Instruction
+{
+    Instruction::MakeInterface{
+        dst: dst.clone(),
+        obj: obj.clone(),
+        functions,
+    }
+}
+
+pub fn interface_call_instr(dst: Option<&Var>, obj: &Var, index: usize, args: Vec<Operand>) -> Instruction
+{
+    Instruction::InterfaceCall{
+        dst: dst.cloned(),
+        obj: obj.clone(),
+        index,
+        args,
+    }
+}
+
+pub fn make_closure_instr(dst: &Var, func: &str, env: &Var) -> Instruction
+{
+    Instruction::MakeClosure{
+        dst: dst.clone(),
+        func: func.into(),
+        env: env.clone(),
+    }
+}
+
 pub fn load_optional_flag_instr(dst: &Var, obj: &Var) -> Instruction
 {
     Instruction::LoadOptionalFlag{
@@ -452,10 +615,32 @@ impl fmt::Display for Instruction
                 }
             },
 
+            Instruction::Intrinsic{ref dst, ref intrinsic, ref args} => {
+                match *dst {
+                    Some(ref dst) => writeln!(f, "  intrinsic {} {} {}", dst, intrinsic, join(args.iter(), " ")),
+                    None => writeln!(f, "  intrinsic {} {}", intrinsic, join(args.iter(), " ")),
+                }
+            },
+
             Instruction::Cast{ref dst, ref src} => {
                 writeln!(f, "  cast {} {}", dst, src)
             },
 
+            Instruction::MakeInterface{ref dst, ref obj, ref functions} => {
+                writeln!(f, "  mkiface {} {} [{}]", dst, obj, join(functions.iter(), ", "))
+            },
+
+            Instruction::InterfaceCall{ref dst, ref obj, index, ref args} => {
+                match *dst {
+                    Some(ref dst) => writeln!(f, "  icall {} {}.{} {}", dst, obj, index, join(args.iter(), " ")),
+                    None => writeln!(f, "  icall {}.{} {}", obj, index, join(args.iter(), " ")),
+                }
+            },
+
+            Instruction::MakeClosure{ref dst, ref func, ref env} => {
+                writeln!(f, "  mkclosure {} {} {}", dst, func, env)
+            },
+
             Instruction::StackAlloc(ref var) => {
                 writeln!(f, "  salloc {}", var)
             },
@@ -472,6 +657,14 @@ impl fmt::Display for Instruction
                 writeln!(f, "  scope end")
             },
 
+            Instruction::SourceLocation(pos) => {
+                writeln!(f, "  loc {}", pos)
+            },
+
+            Instruction::LoopHint(ref hints) => {
+                writeln!(f, "  loophint{}", hints)
+            },
+
             Instruction::Return(ref var) => {
                 writeln!(f, "  ret {}", var)
             },
@@ -488,10 +681,35 @@ impl fmt::Display for Instruction
                 writeln!(f, "  brif {} ? {} : {} ", cond, on_true, on_false)
             },
 
+            Instruction::Switch{ref cond, ref cases, default} => {
+                let cases = cases.iter().map(|&(ref value, bb)| format!("{} => {}", value, bb));
+                writeln!(f, "  switch {} [{}] default {}", cond, join(cases, ", "), default)
+            },
+
             Instruction::Delete(ref var) => {
                 writeln!(f, "  delete {}", var)
             },
 
+            Instruction::IncRef(ref var) => {
+                writeln!(f, "  incref {}", var)
+            },
+
+            Instruction::DecRef(ref var) => {
+                writeln!(f, "  decref {}", var)
+            },
+
+            Instruction::BoundsCheck{ref index, ref len, inclusive, ref location} => {
+                writeln!(f, "  boundscheck {} {} {} ({})", index, if inclusive {"<="} else {"<"}, len, location)
+            },
+
+            Instruction::Panic(ref msg) => {
+                writeln!(f, "  panic {}", msg)
+            },
+
+            Instruction::Log(ref msg) => {
+                writeln!(f, "  log {}", msg)
+            },
+
             Instruction::Slice{ref dst, ref src, ref start, ref len} => {
                 writeln!(f, "  slice {} {} {} {}", dst, src, start, len)
             },
@@ -500,6 +718,10 @@ impl fmt::Display for Instruction
                 writeln!(f, "  mkslice {} {} {}", dst, data, len)
             }
 
+            Instruction::Format{ref dst, ref parts} => {
+                writeln!(f, "  format {} {}", dst, join(parts.iter(), " "))
+            }
+
             Instruction::LoadOptionalFlag{ref dst, ref obj} => {
                 writeln!(f, "  loadoptf {} {}", dst, obj)
             }
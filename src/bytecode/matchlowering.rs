@@ -0,0 +1,223 @@
+use std::fmt;
+use ast::*;
+use package::Package;
+use target::Target;
+use super::instruction::Constant;
+
+/*
+Matches over integers, chars, enums and sum types, where every case is a single value and the values
+cover a dense range, are lowered to a switch. LLVM turns a switch of this size and density into a jump
+table, so the match is a single indirect branch instead of a comparison per case. All other matches are
+lowered to a chain of comparisons, tried in the order of the cases.
+*/
+
+// Below this LLVM prefers comparisons over a jump table
+const MIN_SWITCH_CASES: usize = 4;
+
+pub enum MatchLowering
+{
+    // The value of every case with the index of the case, and the index of the _ case if there is one
+    Switch{cases: Vec<(Constant, usize)>, default: Option<usize>},
+    // Reason why no switch could be used
+    IfChain(String),
+}
+
+impl fmt::Display for MatchLowering
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error>
+    {
+        match *self
+        {
+            MatchLowering::Switch{ref cases, default} => {
+                write!(f, "jump table with {} cases", cases.len())?;
+                if default.is_some() {
+                    write!(f, " and a default case")?;
+                }
+                Ok(())
+            },
+            MatchLowering::IfChain(ref reason) => write!(f, "if-chain, {}", reason),
+        }
+    }
+}
+
+fn case_value(p: &Pattern, target: &Target) -> Result<(Constant, i128), String>
+{
+    match *p
+    {
+        Pattern::Literal(Literal::Int(_, v, int_size)) => Ok((Constant::Int(v, int_size), v as i128)),
+        Pattern::Literal(Literal::UInt(_, v, int_size)) => Ok((Constant::UInt(v, int_size), v as i128)),
+        Pattern::Literal(Literal::Char(_, c)) => Ok((Constant::Char(c), c as i128)),
+        Pattern::Name(ref nr) => {
+            let idx = match nr.typ
+            {
+                Type::Enum(ref et) => et.index_of(&nr.name),
+                Type::Sum(ref st) => st.index_of(&nr.name),
+                _ => None,
+            };
+
+            match idx {
+                Some(idx) => Ok((Constant::UInt(idx as u64, target.int_size), idx as i128)),
+                None => Err(format!("{} is not a case of an enum or sum type", nr.name)),
+            }
+        },
+        Pattern::Struct(_) => Err("a case binds the members of a sum type case".into()),
+        _ => Err("a case is not a single integer, char, enum or sum type value".into()),
+    }
+}
+
+pub fn match_lowering(m: &MatchExpression, target: &Target) -> MatchLowering
+{
+    let mut cases: Vec<(Constant, usize)> = Vec::new();
+    let mut values: Vec<i128> = Vec::new();
+    let mut default = None;
+    for (idx, mc) in m.cases.iter().enumerate() {
+        if let Pattern::Any(_) = mc.pattern {
+            // Later cases can never be reached
+            default = Some(idx);
+            break;
+        }
+
+        match case_value(&mc.pattern, target) {
+            Ok((cst, value)) => {
+                // The first case with a value wins, like in a chain of comparisons
+                if !values.contains(&value) {
+                    values.push(value);
+                    cases.push((cst, idx));
+                }
+            },
+            Err(reason) => return MatchLowering::IfChain(reason),
+        }
+    }
+
+    if cases.len() < MIN_SWITCH_CASES {
+        return MatchLowering::IfChain(format!("only {} cases, a jump table needs at least {}", cases.len(), MIN_SWITCH_CASES));
+    }
+
+    // At least half of the values in the range must have a case, otherwise the table is mostly empty
+    let min = values.iter().cloned().min().unwrap_or(0);
+    let max = values.iter().cloned().max().unwrap_or(0);
+    if max - min + 1 > 2 * cases.len() as i128 {
+        return MatchLowering::IfChain(format!("{} cases spread over a range of {} values", cases.len(), max - min + 1));
+    }
+
+    MatchLowering::Switch{cases, default}
+}
+
+struct MatchLoweringPrinter<'a>
+{
+    target: &'a Target,
+    lines: Vec<(String, usize, usize, String)>,
+}
+
+impl<'a> Visitor for MatchLoweringPrinter<'a>
+{
+    type Error = ();
+
+    fn visit_expression(&mut self, e: &Expression) -> Result<(), ()>
+    {
+        if let Expression::Match(ref m) = *e {
+            let line = format!("{}:{}: match on {}: {}", m.span.file, m.span.start, m.target.get_type(self.target.int_size), match_lowering(m, self.target));
+            self.lines.push((m.span.file.clone(), m.span.start.line, m.span.start.offset, line));
+        }
+        walk_expression(self, e)
+    }
+}
+
+// Print how every match expression in the package is lowered, in source order, for --print-match-lowering
+pub fn print_match_lowering(pkg: &Package, target: &Target)
+{
+    let mut printer = MatchLoweringPrinter{target, lines: Vec::new()};
+    for md in pkg.modules.values() {
+        let _ = printer.visit_module(md);
+    }
+
+    printer.lines.sort();
+    for &(_, _, _, ref line) in &printer.lines {
+        println!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use bytecode::test::generate_byte_code;
+    use bytecode::Instruction;
+
+    fn uses_switch(prog: &str) -> bool
+    {
+        let bc_mod = generate_byte_code(prog, false).expect("Compilation failed");
+        let func = bc_mod.get_function("test::foo").expect("Missing function foo");
+        let mut found = false;
+        func.for_each_instruction(|instr| {
+            if let Instruction::Switch{..} = *instr {
+                found = true;
+            }
+            !found
+        });
+        found
+    }
+
+    #[test]
+    fn test_dense_match()
+    {
+        assert!(uses_switch(r#"
+fn foo(op: int) -> int:
+    match op:
+        0 => 10
+        1 => 11
+        2 => 12
+        4 => 14
+        _ => 0
+"#));
+
+        assert!(uses_switch(r#"
+enum Op:
+    Push
+    Pop
+    Add
+    Jump
+
+fn foo(op: Op) -> int:
+    match op:
+        Push => 1
+        Pop => 2
+        Add => 3
+        Jump => 4
+"#));
+    }
+
+    #[test]
+    fn test_sparse_match()
+    {
+        // Too few cases
+        assert!(!uses_switch(r#"
+fn foo(op: int) -> int:
+    match op:
+        0 => 10
+        1 => 11
+        _ => 0
+"#));
+
+        // Range mostly empty
+        assert!(!uses_switch(r#"
+fn foo(op: int) -> int:
+    match op:
+        0 => 10
+        10 => 11
+        100 => 12
+        1000 => 14
+        _ => 0
+"#));
+
+        // Not a single value per case
+        assert!(!uses_switch(r#"
+fn foo(s: string) -> int:
+    match s:
+        "a" => 1
+        "b" => 2
+        "c" => 3
+        "d" => 4
+        _ => 0
+"#));
+    }
+}
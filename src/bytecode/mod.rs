@@ -1,16 +1,30 @@
 mod compiler;
 mod consteval;
+mod constanttime;
 mod function;
 mod instruction;
+mod matchlowering;
 mod optimizer;
 
 use std::fmt;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use ast::{Type, LogLevel};
 
 pub use self::instruction::*;
 pub use self::function::*;
-pub use self::compiler::{compile_to_byte_code};
-pub use self::optimizer::{OptimizationLevel, optimize_module};
+pub use self::compiler::{compile_to_byte_code, test_main_function};
+pub use self::matchlowering::{print_match_lowering};
+pub use self::optimizer::{OptimizationLevel, optimize_module, devirtualize_interface_calls, remove_bounds_checks, DEFAULT_INLINE_THRESHOLD};
+
+// Global whose initializer cannot be evaluated at compile time, so it is initialized by a function at runtime
+#[derive(Debug)]
+pub struct RuntimeGlobal
+{
+    pub typ: Type,
+    pub init_function: String,
+    // Lazy globals are initialized on first use, the others before main
+    pub lazy: bool,
+}
 
 #[derive(Debug)]
 pub struct ByteCodeModule
@@ -19,6 +33,15 @@ pub struct ByteCodeModule
     pub functions: HashMap<String, ByteCodeFunction>,
     pub imported_functions: Vec<ByteCodeFunction>,
     pub globals: HashMap<String, Constant>,
+    pub runtime_globals: HashMap<String, RuntimeGlobal>,
+    pub thread_local_globals: HashSet<String>,
+    // Functions callable from C, under their name without namespace
+    pub exported_functions: HashSet<String>,
+    // Functions to run before and after main, in the order they have to be executed
+    pub init_functions: Vec<String>,
+    pub fini_functions: Vec<String>,
+    // Calls to @debug, @info, @warn and @error below this level are left out
+    pub log_level: LogLevel,
 }
 
 impl ByteCodeModule
@@ -42,6 +65,26 @@ impl fmt::Display for ByteCodeModule
             writeln!(f, "glob {} = {}", name, value)?;
         }
 
+        for (name, rg) in &self.runtime_globals {
+            writeln!(f, "glob {}: {} = {}{}()", name, rg.typ, if rg.lazy {"lazy "} else {""}, rg.init_function)?;
+        }
+
+        for name in &self.thread_local_globals {
+            writeln!(f, "thread_local {}", name)?;
+        }
+
+        for name in &self.exported_functions {
+            writeln!(f, "export {}", name)?;
+        }
+
+        for name in &self.init_functions {
+            writeln!(f, "init {}", name)?;
+        }
+
+        for name in &self.fini_functions {
+            writeln!(f, "fini {}", name)?;
+        }
+
         writeln!(f, " ")?;
 
         for func in self.functions.values() {
@@ -57,11 +100,16 @@ pub mod test
 {
     use compileerror::CompileResult;
     use parser::{parse_str};
-    use bytecode::{ByteCodeModule, compile_to_byte_code};
-    use ast::{TreePrinter, IntSize};
+    use bytecode::{ByteCodeModule, Instruction, compile_to_byte_code};
+    use ast::{TreePrinter, IntSize, LogLevel};
     use target::Target;
 
     pub fn generate_byte_code(prog: &str, dump: bool) -> CompileResult<ByteCodeModule>
+    {
+        generate_byte_code_with_log_level(prog, dump, LogLevel::Debug)
+    }
+
+    pub fn generate_byte_code_with_log_level(prog: &str, dump: bool, log_level: LogLevel) -> CompileResult<ByteCodeModule>
     {
         let target = Target::new(IntSize::I32, "");
         let mut pkg = parse_str(prog, "test", &target)?;
@@ -80,7 +128,7 @@ pub mod test
             println!("-----------------");
         }
 
-        let bc_mod = compile_to_byte_code(&pkg, &target)?;
+        let bc_mod = compile_to_byte_code(&pkg, &target, false, log_level)?;
         if dump {
             println!("ByteCode:");
             println!("{}", bc_mod);
@@ -89,6 +137,52 @@ pub mod test
 
         Ok(bc_mod)
     }
+
+    fn count_instructions(bc_mod: &ByteCodeModule, pred: fn(&Instruction) -> bool) -> usize
+    {
+        let mut count = 0;
+        for func in bc_mod.functions.values() {
+            func.for_each_instruction(|instr| {
+                if pred(instr) {
+                    count += 1;
+                }
+                true
+            });
+        }
+        count
+    }
+
+    #[test]
+    fn test_log_levels()
+    {
+        let prog = r#"
+fn value() -> int: 4
+
+fn main() -> int:
+    @debug("value is ", value())
+    @info("starting")
+    @warn("almost done")
+    @error("failed with ", 3)
+    0
+"#;
+        let is_log = |instr: &Instruction| match *instr {
+            Instruction::Log(_) => true,
+            _ => false,
+        };
+        let is_call = |instr: &Instruction| match *instr {
+            Instruction::Call{..} => true,
+            _ => false,
+        };
+
+        let bc_mod = generate_byte_code_with_log_level(prog, false, LogLevel::Debug).expect("Compilation failed");
+        assert_eq!(count_instructions(&bc_mod, is_log), 4);
+        assert_eq!(count_instructions(&bc_mod, is_call), 1);
+
+        // The arguments of stripped calls are not evaluated either
+        let bc_mod = generate_byte_code_with_log_level(prog, false, LogLevel::Warn).expect("Compilation failed");
+        assert_eq!(count_instructions(&bc_mod, is_log), 2);
+        assert_eq!(count_instructions(&bc_mod, is_call), 0);
+    }
 }
 
 
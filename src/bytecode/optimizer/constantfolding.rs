@@ -0,0 +1,299 @@
+/*
+    Constant folding and propagation, one basic block at a time.
+
+    A variable has a known value after a store of a constant, until it is written again. Known values
+    replace the variable in the operands of following instructions, and unary and binary operations
+    with only constant operands become a store of their result. Variables whose address is taken can
+    change behind our back, so they never get a known value, and neither does anything across a call.
+    Stores to temporaries which are no longer read anywhere are removed afterwards.
+*/
+use std::collections::{HashMap, HashSet};
+use ast::{BinaryOperator, UnaryOperator, IntSize, FloatSize};
+use bytecode::function::{ByteCodeFunction, Var};
+use bytecode::instruction::{Instruction, Operand, Constant};
+
+fn wrap_int(v: i64, int_size: IntSize) -> i64
+{
+    match int_size {
+        IntSize::I8 => i64::from(v as i8),
+        IntSize::I16 => i64::from(v as i16),
+        IntSize::I32 => i64::from(v as i32),
+        IntSize::I64 => v,
+    }
+}
+
+fn wrap_uint(v: u64, int_size: IntSize) -> u64
+{
+    match int_size {
+        IntSize::I8 => u64::from(v as u8),
+        IntSize::I16 => u64::from(v as u16),
+        IntSize::I32 => u64::from(v as u32),
+        IntSize::I64 => v,
+    }
+}
+
+fn round_float(v: f64, float_size: FloatSize) -> f64
+{
+    match float_size {
+        FloatSize::F32 => f64::from(v as f32),
+        FloatSize::F64 => v,
+    }
+}
+
+// Comparisons of floats are unordered in LLVM, so with a NaN involved they are left alone
+#[allow(clippy::float_cmp)]
+fn fold_binary_op(op: BinaryOperator, left: &Constant, right: &Constant) -> Option<Constant>
+{
+    use ast::BinaryOperator::*;
+    match (op, left, right) {
+        (Add, &Constant::Int(l, s), &Constant::Int(r, _)) => Some(Constant::Int(wrap_int(l.wrapping_add(r), s), s)),
+        (Sub, &Constant::Int(l, s), &Constant::Int(r, _)) => Some(Constant::Int(wrap_int(l.wrapping_sub(r), s), s)),
+        (Mul, &Constant::Int(l, s), &Constant::Int(r, _)) => Some(Constant::Int(wrap_int(l.wrapping_mul(r), s), s)),
+
+        (Add, &Constant::UInt(l, s), &Constant::UInt(r, _)) => Some(Constant::UInt(wrap_uint(l.wrapping_add(r), s), s)),
+        (Sub, &Constant::UInt(l, s), &Constant::UInt(r, _)) => Some(Constant::UInt(wrap_uint(l.wrapping_sub(r), s), s)),
+        (Mul, &Constant::UInt(l, s), &Constant::UInt(r, _)) => Some(Constant::UInt(wrap_uint(l.wrapping_mul(r), s), s)),
+
+        (Add, &Constant::Float(l, s), &Constant::Float(r, _)) => Some(Constant::Float(round_float(l + r, s), s)),
+        (Sub, &Constant::Float(l, s), &Constant::Float(r, _)) => Some(Constant::Float(round_float(l - r, s), s)),
+        (Mul, &Constant::Float(l, s), &Constant::Float(r, _)) => Some(Constant::Float(round_float(l * r, s), s)),
+
+        (_, &Constant::Float(l, _), &Constant::Float(r, _)) if l.is_nan() || r.is_nan() => None,
+
+        (LessThan, l, r) => compare(l, r).map(|o| Constant::Bool(o < 0)),
+        (GreaterThan, l, r) => compare(l, r).map(|o| Constant::Bool(o > 0)),
+        (LessThanEquals, l, r) => compare(l, r).map(|o| Constant::Bool(o <= 0)),
+        (GreaterThanEquals, l, r) => compare(l, r).map(|o| Constant::Bool(o >= 0)),
+        (Equals, &Constant::Bool(l), &Constant::Bool(r)) => Some(Constant::Bool(l == r)),
+        (NotEquals, &Constant::Bool(l), &Constant::Bool(r)) => Some(Constant::Bool(l != r)),
+        (Equals, l, r) => compare(l, r).map(|o| Constant::Bool(o == 0)),
+        (NotEquals, l, r) => compare(l, r).map(|o| Constant::Bool(o != 0)),
+
+        (And, &Constant::Bool(l), &Constant::Bool(r)) => Some(Constant::Bool(l && r)),
+        (Or, &Constant::Bool(l), &Constant::Bool(r)) => Some(Constant::Bool(l || r)),
+
+        _ => None,
+    }
+}
+
+// -1, 0 or 1, when left is smaller, equal or larger than right
+fn compare(left: &Constant, right: &Constant) -> Option<i32>
+{
+    let ordering = match (left, right) {
+        (&Constant::Int(l, _), &Constant::Int(r, _)) => l.partial_cmp(&r),
+        (&Constant::UInt(l, _), &Constant::UInt(r, _)) => l.partial_cmp(&r),
+        (&Constant::Float(l, _), &Constant::Float(r, _)) => l.partial_cmp(&r),
+        (&Constant::Char(l), &Constant::Char(r)) => l.partial_cmp(&r),
+        _ => None,
+    };
+
+    ordering.map(|o| o as i32)
+}
+
+fn fold_unary_op(op: UnaryOperator, src: &Constant) -> Option<Constant>
+{
+    match (op, src) {
+        (UnaryOperator::Not, &Constant::Bool(v)) => Some(Constant::Bool(!v)),
+        (UnaryOperator::Sub, &Constant::Int(v, s)) => Some(Constant::Int(wrap_int(v.wrapping_neg(), s), s)),
+        (UnaryOperator::Sub, &Constant::Float(v, s)) => Some(Constant::Float(-v, s)),
+        _ => None,
+    }
+}
+
+fn fold(instr: &Instruction) -> Option<Instruction>
+{
+    let (dst, value) = match *instr {
+        Instruction::BinaryOp{ref dst, op, left: Operand::Const(ref l), right: Operand::Const(ref r)} => (dst, try_opt!(fold_binary_op(op, l, r))),
+        Instruction::UnaryOp{ref dst, op, src: Operand::Const(ref s)} => (dst, try_opt!(fold_unary_op(op, s))),
+        _ => return None,
+    };
+
+    if value.get_type() == dst.typ {
+        Some(Instruction::Store{dst: dst.clone(), src: Operand::Const(value)})
+    } else {
+        None
+    }
+}
+
+fn propagate_operand(op: &mut Operand, known: &HashMap<String, Constant>)
+{
+    let replacement = match *op {
+        Operand::Var(ref v) => known.get(&v.name).cloned(),
+        _ => None,
+    };
+
+    if let Some(c) = replacement {
+        *op = Operand::Const(c);
+    }
+}
+
+fn propagate(instr: &mut Instruction, known: &HashMap<String, Constant>)
+{
+    match *instr {
+        Instruction::BinaryOp{ref mut left, ref mut right, ..} => {
+            propagate_operand(left, known);
+            propagate_operand(right, known);
+        },
+        Instruction::UnaryOp{ref mut src, ..} |
+        Instruction::BranchIf{cond: ref mut src, ..} => propagate_operand(src, known),
+        // A store to a variable of another type, stores through a pointer or converts the value
+        Instruction::Store{ref dst, ref mut src} => {
+            let same_type = match *src {
+                Operand::Var(ref v) => v.typ == dst.typ,
+                _ => false,
+            };
+            if same_type {
+                propagate_operand(src, known);
+            }
+        },
+        _ => (),
+    }
+}
+
+fn operand_vars<'a, I: IntoIterator<Item=&'a Operand>>(operands: I) -> Vec<&'a Var>
+{
+    operands.into_iter()
+        .filter_map(|op| match *op {
+            Operand::Var(ref v) |
+            Operand::AddressOf(ref v) |
+            Operand::Dereference(ref v) => Some(v),
+            _ => None,
+        })
+        .collect()
+}
+
+// The variable an instruction writes to, and all the variables it refers to
+pub fn instruction_vars(instr: &Instruction) -> (Option<&Var>, Vec<&Var>)
+{
+    match *instr {
+        Instruction::Store{ref dst, ref src} |
+        Instruction::UnaryOp{ref dst, ref src, ..} |
+        Instruction::Cast{ref dst, ref src} => (Some(dst), operand_vars(vec![src])),
+        Instruction::Load{ref dst, ptr: ref obj} |
+        Instruction::AddressOf{ref dst, ref obj} |
+        Instruction::GetProperty{ref dst, ref obj, ..} |
+        Instruction::MakeInterface{ref dst, ref obj, ..} |
+        Instruction::MakeClosure{ref dst, env: ref obj, ..} |
+        Instruction::LoadOptionalFlag{ref dst, ref obj} => (Some(dst), vec![obj]),
+        Instruction::LoadMember{ref dst, ref obj, ref member_index} |
+        Instruction::AddressOfMember{ref dst, ref obj, ref member_index} => {
+            let mut vars = operand_vars(vec![member_index]);
+            vars.push(obj);
+            (Some(dst), vars)
+        },
+        Instruction::StoreMember{ref obj, ref member_index, ref src} => (Some(obj), operand_vars(vec![member_index, src])),
+        Instruction::SetProperty{ref obj, ..} => (Some(obj), Vec::new()),
+        Instruction::BinaryOp{ref dst, ref left, ref right, ..} => (Some(dst), operand_vars(vec![left, right])),
+        Instruction::Call{ref dst, ref args, ..} |
+        Instruction::Intrinsic{ref dst, ref args, ..} => (dst.as_ref(), operand_vars(args)),
+        Instruction::InterfaceCall{ref dst, ref obj, ref args, ..} => {
+            let mut vars = operand_vars(args);
+            vars.push(obj);
+            (dst.as_ref(), vars)
+        },
+        Instruction::Format{ref dst, ref parts} => (Some(dst), operand_vars(parts)),
+        Instruction::Slice{ref dst, ref src, ref start, ref len} => {
+            let mut vars = operand_vars(vec![start, len]);
+            vars.push(src);
+            (Some(dst), vars)
+        },
+        Instruction::MakeSlice{ref dst, ref data, ref len} => (Some(dst), vec![data, len]),
+        Instruction::StoreNil(ref v) |
+        Instruction::StackAlloc(ref v) |
+        Instruction::HeapAlloc(ref v) => (Some(v), Vec::new()),
+        Instruction::Delete(ref v) |
+        Instruction::IncRef(ref v) => (None, vec![v]),
+        // Releasing sets the variable to null
+        Instruction::DecRef(ref v) => (Some(v), vec![v]),
+        Instruction::Return(ref op) |
+        Instruction::Panic(ref op) |
+        Instruction::Log(ref op) |
+        Instruction::BranchIf{cond: ref op, ..} |
+        Instruction::Switch{cond: ref op, ..} => (None, operand_vars(vec![op])),
+        Instruction::BoundsCheck{ref index, ref len, ..} => (None, operand_vars(vec![index, len])),
+        Instruction::StartScope |
+        Instruction::EndScope |
+        Instruction::SourceLocation(_) |
+        Instruction::LoopHint(_) |
+        Instruction::ReturnVoid |
+        Instruction::Branch(_) => (None, Vec::new()),
+    }
+}
+
+// Variables whose address is taken, pointers to them are only passed to calls or stored
+fn escaped_vars(func: &ByteCodeFunction) -> HashSet<String>
+{
+    let mut escaped = HashSet::new();
+    func.for_each_instruction(|instr| {
+        let operands: Vec<&Operand> = match *instr {
+            Instruction::AddressOf{ref obj, ..} |
+            Instruction::AddressOfMember{ref obj, ..} => {
+                escaped.insert(obj.name.clone());
+                Vec::new()
+            },
+            Instruction::Call{ref args, ..} |
+            Instruction::Intrinsic{ref args, ..} |
+            Instruction::InterfaceCall{ref args, ..} => args.iter().collect(),
+            Instruction::Store{ref src, ..} => vec![src],
+            _ => Vec::new(),
+        };
+
+        for op in operands {
+            if let Operand::AddressOf(ref v) = *op {
+                escaped.insert(v.name.clone());
+            }
+        }
+        true
+    });
+    escaped
+}
+
+fn remove_unused_temporaries(func: &mut ByteCodeFunction)
+{
+    let mut used = HashSet::new();
+    func.for_each_instruction(|instr| {
+        let (_, vars) = instruction_vars(instr);
+        for v in vars {
+            used.insert(v.name.clone());
+        }
+        true
+    });
+
+    func.remove_instruction(|instr| match *instr {
+        Instruction::Store{ref dst, src: Operand::Const(_)} => dst.name.starts_with("$var") && !used.contains(&dst.name),
+        _ => false,
+    });
+}
+
+pub fn fold_constants(func: &mut ByteCodeFunction)
+{
+    let escaped = escaped_vars(func);
+    for block in func.blocks.values_mut() {
+        let mut known: HashMap<String, Constant> = HashMap::new();
+        for instr in &mut block.instructions {
+            propagate(instr, &known);
+            if let Some(folded) = fold(instr) {
+                *instr = folded;
+            }
+
+            match *instr {
+                Instruction::Call{..} |
+                Instruction::InterfaceCall{..} |
+                Instruction::EndScope => known.clear(),
+                _ => (),
+            }
+
+            if let (Some(dst), _) = instruction_vars(instr) {
+                known.remove(&dst.name);
+            }
+
+            if let Instruction::Store{ref dst, src: Operand::Const(ref c)} = *instr {
+                if c.get_type() == dst.typ && !escaped.contains(&dst.name) {
+                    known.insert(dst.name.clone(), c.clone());
+                }
+            }
+        }
+    }
+
+    remove_unused_temporaries(func);
+}
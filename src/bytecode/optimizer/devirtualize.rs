@@ -0,0 +1,176 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use ast::{Type, TypeDeclaration, IntSize, ptr_type};
+use bytecode::{ByteCodeModule};
+use bytecode::function::Var;
+use bytecode::instruction::*;
+use package::Package;
+
+/*
+An interface call goes through the function table of the object, which LLVM cannot see through, so
+the called function is never inlined. When the whole program is known, and only one type is ever
+turned into an object of an interface, every call on that interface must end up in a function of that
+type. Those calls are replaced by direct calls, passing the data pointer of the object as self.
+
+Only interfaces declared in the package being built qualify: objects of an interface from a library
+can also be made inside the library, where we cannot see it.
+*/
+
+// The function table of the only type implementing an interface, with the type of the self argument of each function
+struct Implementation
+{
+    functions: Vec<String>,
+    self_types: Vec<Type>,
+}
+
+fn interface_name(typ: &Type) -> Option<&str>
+{
+    match *typ
+    {
+        Type::Interface(ref it) => Some(&it.name[..]),
+        _ => None,
+    }
+}
+
+fn find_implementations(module: &ByteCodeModule, pkg: &Package) -> HashMap<String, Implementation>
+{
+    let declared: HashSet<&str> = pkg.modules.values()
+        .flat_map(|md| md.types.values())
+        .filter_map(|t| if let TypeDeclaration::Interface(ref i) = *t {Some(&i.name[..])} else {None})
+        .collect();
+
+    let mut tables: HashMap<String, HashSet<Vec<String>>> = HashMap::new();
+    for func in module.functions.values() {
+        func.for_each_instruction(|instr| {
+            if let Instruction::MakeInterface{ref dst, ref functions, ..} = *instr {
+                if let Some(name) = interface_name(&dst.typ) {
+                    tables.entry(name.into()).or_insert_with(HashSet::new).insert(functions.clone());
+                }
+            }
+            true
+        });
+    }
+
+    let mut implementations = HashMap::new();
+    for (name, tables) in tables {
+        if tables.len() != 1 || !declared.contains(&name[..]) {
+            continue;
+        }
+
+        let functions = tables.into_iter().next().expect("Internal Compiler Error: empty set of tables");
+        let self_types: Option<Vec<Type>> = functions.iter()
+            .map(|f| module.get_function(f).and_then(|func| func.sig.args.first()).map(|arg| arg.typ.clone()))
+            .collect();
+
+        if let Some(self_types) = self_types {
+            implementations.insert(name, Implementation{functions, self_types});
+        }
+    }
+
+    implementations
+}
+
+pub fn devirtualize_interface_calls(module: &mut ByteCodeModule, pkg: &Package)
+{
+    let implementations = find_implementations(module, pkg);
+    if implementations.is_empty() {
+        return;
+    }
+
+    let counter = Cell::new(0);
+    for func in module.functions.values_mut() {
+        func.replace_instruction(|instr| {
+            match *instr
+            {
+                Instruction::InterfaceCall{ref dst, ref obj, index, ref args} => {
+                    let imp = match interface_name(&obj.typ).and_then(|name| implementations.get(name)) {
+                        Some(imp) => imp,
+                        None => return Vec::new(),
+                    };
+
+                    let id = counter.get();
+                    counter.set(id + 1);
+                    let data = Var::named(&format!("$devirt_data{}", id), ptr_type(Type::UInt(IntSize::I8)));
+                    let self_ptr = Var::named(&format!("$devirt_self{}", id), imp.self_types[index].clone());
+
+                    let mut call_args = vec![var_op(&self_ptr)];
+                    call_args.extend(args.iter().cloned());
+                    vec![
+                        get_prop_instr(&data, obj, ByteCodeProperty::Data),
+                        cast_instr(&self_ptr, &data),
+                        Instruction::Call{dst: dst.clone(), func: imp.functions[index].clone(), args: call_args},
+                    ]
+                },
+
+                _ => Vec::new(),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use ast::LogLevel;
+    use bytecode::compile_to_byte_code;
+    use parser::parse_str;
+    use target::Target;
+
+    const SHAPES: &str = r#"
+interface Shape:
+    fn area(self) -> int
+
+struct Square:
+    side: int
+
+fn Square.area(self) -> int: self.side * self.side
+
+struct Rect:
+    w: int
+    h: int
+
+fn Rect.area(self) -> int: self.w * self.h
+
+fn total(s: Shape) -> int: s.area()
+"#;
+
+    fn interface_calls(prog: &str) -> usize
+    {
+        let target = Target::new(IntSize::I64, "");
+        let mut pkg = parse_str(prog, "test", &target).expect("Parsing failed");
+        pkg.type_check(&target).expect("Type checking failed");
+        let mut bc_mod = compile_to_byte_code(&pkg, &target, false, LogLevel::Debug).expect("Compilation failed");
+        devirtualize_interface_calls(&mut bc_mod, &pkg);
+
+        let mut count = 0;
+        bc_mod.get_function("test::total").expect("Missing function total").for_each_instruction(|instr| {
+            if let Instruction::InterfaceCall{..} = *instr {
+                count += 1;
+            }
+            true
+        });
+        count
+    }
+
+    #[test]
+    fn test_single_implementation()
+    {
+        assert_eq!(interface_calls(&format!(r#"{}
+fn main() -> int:
+    let s = Square{{4}}
+    total(&s)
+"#, SHAPES)), 0);
+    }
+
+    #[test]
+    fn test_multiple_implementations()
+    {
+        assert_eq!(interface_calls(&format!(r#"{}
+fn main() -> int:
+    let s = Square{{4}}
+    let r = Rect{{2, 3}}
+    total(&s) + total(&r)
+"#, SHAPES)), 1);
+    }
+}
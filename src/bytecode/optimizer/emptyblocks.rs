@@ -33,18 +33,42 @@ fn replace_branch_target(func: &mut ByteCodeFunction, to_replace: BasicBlockRef,
                     *on_false = replacement
                 }
             },
+            Instruction::Switch{ref mut cases, ref mut default, ..} => {
+                for &mut (_, ref mut bb_ref) in cases.iter_mut() {
+                    if *bb_ref == to_replace {
+                        *bb_ref = replacement;
+                    }
+                }
+                if *default == to_replace {
+                    *default = replacement;
+                }
+            },
             _ => (),
         }
         true
     })
 }
 
+// Where a branch to bb_ref ends up, after going through the empty blocks
+fn final_destination(candidates: &[(BasicBlockRef, BasicBlockRef)], bb_ref: BasicBlockRef) -> BasicBlockRef
+{
+    let mut dst = bb_ref;
+    // Bounded, empty blocks branching to each other would go on forever
+    for _ in 0..candidates.len() {
+        match candidates.iter().find(|&&(empty, _)| empty == dst) {
+            Some(&(_, next)) => dst = next,
+            None => break,
+        }
+    }
+    dst
+}
+
 pub fn remove_empty_blocks(func: &mut ByteCodeFunction)
 {
     let mut candidates = Vec::new();
-    for (idx, bb) in func.blocks.values().enumerate() {
+    for (&bb_ref, bb) in &func.blocks {
         if let Some(next_bb) = empty_block(bb) {
-            candidates.push((idx, next_bb));
+            candidates.push((bb_ref, next_bb));
         }
     }
 
@@ -52,8 +76,7 @@ pub fn remove_empty_blocks(func: &mut ByteCodeFunction)
         func.blocks.remove(&to_replace);
     }
 
-    for &(to_replace, replacement) in &candidates {
-        replace_branch_target(func, to_replace, replacement);
+    for &(to_replace, _) in &candidates {
+        replace_branch_target(func, to_replace, final_destination(&candidates, to_replace));
     }
-
 }
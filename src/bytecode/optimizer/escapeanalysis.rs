@@ -0,0 +1,208 @@
+/*
+    Escape analysis of memory allocated with new.
+
+    The pointer of a HeapAlloc is followed through the local variables it is copied to, and through pointers
+    to its members. When it is only used to read and write the memory, compared, or released, it never escapes
+    the function, and the memory can be a stack allocation instead. Its reference counting and deletes are removed.
+
+    Anything else, passing it to a call, returning it, storing it in memory or a global, or taking its address,
+    lets it escape. So does an allocation in a loop, because a StackAlloc reuses the same memory every iteration,
+    and a variable which can also hold another pointer, because releasing it can't be removed then.
+*/
+use std::collections::HashSet;
+use bytecode::ByteCodeModule;
+use bytecode::function::{BasicBlockRef, ByteCodeFunction, Var};
+use bytecode::instruction::{Instruction, Operand, Constant};
+use super::constantfolding::instruction_vars;
+
+fn successors(instr: &Instruction) -> Vec<BasicBlockRef>
+{
+    match *instr {
+        Instruction::Branch(bb_ref) => vec![bb_ref],
+        Instruction::BranchIf{on_true, on_false, ..} => vec![on_true, on_false],
+        Instruction::Switch{ref cases, default, ..} => {
+            let mut targets: Vec<BasicBlockRef> = cases.iter().map(|&(_, bb_ref)| bb_ref).collect();
+            targets.push(default);
+            targets
+        },
+        _ => Vec::new(),
+    }
+}
+
+// Whether the block can be reached again after leaving it
+fn in_loop(func: &ByteCodeFunction, bb_ref: BasicBlockRef) -> bool
+{
+    let block_successors = |bb_ref: BasicBlockRef| -> Vec<BasicBlockRef> {
+        func.blocks.get(&bb_ref)
+            .map(|bb| bb.instructions.iter().flat_map(successors).collect())
+            .unwrap_or_default()
+    };
+
+    let mut visited = HashSet::new();
+    let mut todo = block_successors(bb_ref);
+    while let Some(next) = todo.pop() {
+        if next == bb_ref {
+            return true;
+        }
+
+        if visited.insert(next) {
+            todo.extend(block_successors(next));
+        }
+    }
+    false
+}
+
+// The variables pointing into the memory of an allocation: copies of the pointer and pointers to its members
+fn pointers_into(func: &ByteCodeFunction, ptr: &Var) -> HashSet<String>
+{
+    let mut pointers = HashSet::new();
+    pointers.insert(ptr.name.clone());
+    loop {
+        let mut found = Vec::new();
+        func.for_each_instruction(|instr| {
+            match *instr {
+                Instruction::Store{ref dst, src: Operand::Var(ref src)} if dst.typ == src.typ && pointers.contains(&src.name) => found.push(dst.name.clone()),
+                Instruction::AddressOfMember{ref dst, ref obj, ..} if pointers.contains(&obj.name) => found.push(dst.name.clone()),
+                _ => (),
+            }
+            true
+        });
+
+        let len = pointers.len();
+        pointers.extend(found);
+        if pointers.len() == len {
+            return pointers;
+        }
+    }
+}
+
+fn refers_to(op: &Operand, pointers: &HashSet<String>) -> bool
+{
+    match *op {
+        Operand::Var(ref v) |
+        Operand::AddressOf(ref v) => pointers.contains(&v.name),
+        _ => false,
+    }
+}
+
+// Whether an instruction lets the pointers escape, or can give one of them a value from somewhere else
+fn escapes(instr: &Instruction, pointers: &HashSet<String>) -> bool
+{
+    let is_pointer = |v: &Var| pointers.contains(&v.name);
+    match *instr {
+        Instruction::StackAlloc(_) |
+        Instruction::Delete(_) |
+        Instruction::IncRef(_) |
+        Instruction::DecRef(_) => false,
+
+        // Either a new value of the pointer, or a store through it
+        Instruction::Store{ref dst, ref src} if is_pointer(dst) => match *src {
+            Operand::Var(ref v) => (v.typ == dst.typ) != is_pointer(v),
+            Operand::Dereference(ref v) => v.typ.get_pointer_element_type() == Some(&dst.typ),
+            Operand::AddressOf(_) => true,
+            _ => false,
+        },
+        Instruction::Store{ref dst, src: Operand::Var(ref v)} if is_pointer(v) => !is_pointer(dst),
+        Instruction::Store{ref src, ..} => refers_to(src, pointers),
+
+        Instruction::Load{ref dst, ..} => is_pointer(dst),
+        Instruction::LoadMember{ref dst, ref member_index, ..} => is_pointer(dst) || refers_to(member_index, pointers),
+        Instruction::StoreMember{ref member_index, ref src, ..} => refers_to(member_index, pointers) || refers_to(src, pointers),
+        Instruction::AddressOfMember{ref member_index, ..} => refers_to(member_index, pointers),
+        Instruction::BinaryOp{ref dst, ..} => is_pointer(dst),
+
+        _ => {
+            let (dst, vars) = instruction_vars(instr);
+            dst.into_iter().chain(vars.into_iter()).any(|v| is_pointer(v))
+        },
+    }
+}
+
+fn can_be_stack_allocated(func: &ByteCodeFunction, bb_ref: BasicBlockRef, ptr: &Var, globals: &HashSet<String>) -> bool
+{
+    if !ptr.typ.is_pointer() || in_loop(func, bb_ref) {
+        return false;
+    }
+
+    let pointers = pointers_into(func, ptr);
+    let is_local = |name: &String| !name.contains("::") && !globals.contains(name) && !func.sig.args.iter().any(|arg| arg.name == *name);
+    if !pointers.iter().all(is_local) {
+        return false;
+    }
+
+    let mut allocations = 0;
+    let mut escaped = false;
+    func.for_each_instruction(|instr| {
+        if let Instruction::HeapAlloc(ref v) = *instr {
+            if pointers.contains(&v.name) {
+                allocations += 1;
+            }
+        } else if escapes(instr, &pointers) {
+            escaped = true;
+        }
+        !escaped
+    });
+
+    !escaped && allocations == 1
+}
+
+fn stack_allocate(func: &mut ByteCodeFunction, ptr: &Var)
+{
+    let pointers = pointers_into(func, ptr);
+    let inner = ptr.typ.get_pointer_element_type().expect("Internal Compiler Error: HeapAlloc of a non pointer").clone();
+    let obj = Var::named(&format!("{}.obj", ptr.name), inner);
+    func.replace_instruction(|instr| match *instr {
+        Instruction::HeapAlloc(ref v) if v == ptr => vec![
+            Instruction::StackAlloc(obj.clone()),
+            Instruction::AddressOf{dst: ptr.clone(), obj: obj.clone()},
+        ],
+        _ => Vec::new(),
+    });
+
+    func.remove_instruction(|instr| match *instr {
+        Instruction::Delete(ref v) |
+        Instruction::IncRef(ref v) => pointers.contains(&v.name),
+        _ => false,
+    });
+
+    // Releasing also sets the variable to null, which is kept
+    func.replace_instruction(|instr| match *instr {
+        Instruction::DecRef(ref v) if pointers.contains(&v.name) => {
+            let pointee = v.typ.get_pointer_element_type().expect("Internal Compiler Error: DecRef of a non pointer").clone();
+            vec![Instruction::Store{dst: v.clone(), src: Operand::Const(Constant::NullPtr(pointee))}]
+        },
+        _ => Vec::new(),
+    });
+}
+
+fn stack_allocate_function(func: &mut ByteCodeFunction, globals: &HashSet<String>)
+{
+    let mut candidates = Vec::new();
+    for (bb_ref, bb) in &func.blocks {
+        for instr in &bb.instructions {
+            if let Instruction::HeapAlloc(ref ptr) = *instr {
+                if can_be_stack_allocated(func, *bb_ref, ptr, globals) {
+                    candidates.push(ptr.clone());
+                }
+            }
+        }
+    }
+
+    for ptr in &candidates {
+        stack_allocate(func, ptr);
+    }
+}
+
+pub fn escape_analysis(module: &mut ByteCodeModule)
+{
+    let globals: HashSet<String> = module.globals.keys()
+        .chain(module.runtime_globals.keys())
+        .cloned()
+        .collect();
+
+    for func in module.functions.values_mut() {
+        if !func.external {
+            stack_allocate_function(func, &globals);
+        }
+    }
+}
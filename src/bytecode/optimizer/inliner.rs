@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use ast::{Type, BinaryOperator};
+use bytecode::ByteCodeModule;
+use bytecode::function::{BasicBlockRef, ByteCodeFunction, Var};
+use bytecode::instruction::{Instruction, Operand};
+
+// Maximum number of instructions of a function which gets inlined, unless --inline-threshold says otherwise
+pub const DEFAULT_INLINE_THRESHOLD: usize = 20;
+
+// Copy of the body of a function which can be inlined, blocks are in order, so the first one is the entry block
+struct InlineCandidate
+{
+    args: Vec<Var>,
+    return_type: Type,
+    blocks: Vec<Vec<Instruction>>,
+    block_index: HashMap<BasicBlockRef, usize>,
+}
+
+// Scope markers and source locations don't end up as code
+fn function_size(func: &ByteCodeFunction) -> usize
+{
+    let mut size = 0;
+    func.for_each_instruction(|instr| {
+        match *instr {
+            Instruction::StartScope |
+            Instruction::EndScope |
+            Instruction::SourceLocation(_) => (),
+            _ => size += 1,
+        }
+        true
+    });
+    size
+}
+
+fn calls_itself(func: &ByteCodeFunction) -> bool
+{
+    let mut found = false;
+    func.for_each_instruction(|instr| {
+        match *instr {
+            Instruction::Call{func: ref callee, ..} if *callee == func.sig.name => found = true,
+            _ => (),
+        }
+        !found
+    });
+    found
+}
+
+/*
+    Runtime errors report the function they occur in, an inlined body would report the caller instead.
+    So functions which can fail at runtime, or which call other functions, are not inlined.
+*/
+fn can_trap(func: &ByteCodeFunction) -> bool
+{
+    let mut found = false;
+    func.for_each_instruction(|instr| {
+        match *instr {
+            Instruction::BinaryOp{op: BinaryOperator::Div, ..} |
+            Instruction::BinaryOp{op: BinaryOperator::Mod, ..} |
+            Instruction::BoundsCheck{..} |
+            Instruction::Slice{..} |
+            Instruction::Panic(_) |
+            Instruction::Call{..} |
+            Instruction::InterfaceCall{..} |
+            Instruction::Intrinsic{..} => found = true,
+            _ => (),
+        }
+        !found
+    });
+    found
+}
+
+// Arguments become local variables of the caller, in the callee pointers and functions are not stored in one
+pub fn can_be_local(typ: &Type) -> bool
+{
+    match *typ {
+        Type::Pointer(_) |
+        Type::Func(_) => false,
+        _ => typ.pass_by_value(),
+    }
+}
+
+// Only small functions whose arguments and return value are plain values are inlined
+fn inline_candidate(func: &ByteCodeFunction, threshold: usize) -> Option<InlineCandidate>
+{
+    if func.external ||
+        func.blocks.is_empty() ||
+        !(can_be_local(&func.sig.return_type) || func.sig.return_type == Type::Void) ||
+        !func.sig.args.iter().all(|arg| can_be_local(&arg.typ)) ||
+        function_size(func) > threshold ||
+        calls_itself(func) ||
+        can_trap(func)
+    {
+        return None;
+    }
+
+    Some(InlineCandidate{
+        args: func.sig.args.iter().map(|arg| Var::named(&arg.name, arg.typ.clone())).collect(),
+        return_type: func.sig.return_type.clone(),
+        blocks: func.blocks.values().map(|bb| bb.instructions.clone()).collect(),
+        block_index: func.blocks.keys().enumerate().map(|(idx, bb_ref)| (*bb_ref, idx)).collect(),
+    })
+}
+
+// Position of the first call to an inline candidate, at or after the given position
+fn find_call(func: &ByteCodeFunction, start: (BasicBlockRef, usize), candidates: &HashMap<String, InlineCandidate>) -> Option<(BasicBlockRef, usize)>
+{
+    for (bb_ref, bb) in func.blocks.range(start.0..) {
+        let first = if *bb_ref == start.0 {start.1} else {0};
+        for (idx, instr) in bb.instructions.iter().enumerate().skip(first) {
+            if let Instruction::Call{ref func, ..} = *instr {
+                if candidates.contains_key(func) {
+                    return Some((*bb_ref, idx));
+                }
+            }
+        }
+    }
+    None
+}
+
+/*
+    The block with the call is split in two, and the blocks of the callee are put in between,
+    so the blocks stay in the order in which their scopes are nested.
+    Returns the block with the instructions after the call.
+*/
+fn inline_call(func: &mut ByteCodeFunction, bb_ref: BasicBlockRef, idx: usize, candidate: &InlineCandidate, local_name: &dyn Fn(&str) -> Option<String>) -> BasicBlockRef
+{
+    let num_blocks = candidate.blocks.len();
+    func.insert_blocks_after(bb_ref, num_blocks + 1);
+    let continue_bb = bb_ref + num_blocks + 1;
+
+    let mut rest = func.blocks.get_mut(&bb_ref).expect("Unknown block").instructions.split_off(idx);
+    let (dst, args) = match rest.remove(0) {
+        Instruction::Call{dst, args, ..} => (dst, args),
+        _ => panic!("Internal Compiler Error: Expecting a call instruction"),
+    };
+    let rename = |v: &mut Var| if let Some(name) = local_name(&v.name) {
+        v.name = name;
+    };
+
+    let ret = if candidate.return_type == Type::Void {
+        None
+    } else {
+        let mut ret = Var::named("$ret", candidate.return_type.clone());
+        rename(&mut ret);
+        Some(ret)
+    };
+
+    {
+        let call_block = &mut func.blocks.get_mut(&bb_ref).expect("Unknown block").instructions;
+        if let Some(ref ret) = ret {
+            // Allocated before the callee's scopes, so the value is still there in the continue block
+            call_block.push(Instruction::StackAlloc(ret.clone()));
+        }
+
+        for (arg, op) in candidate.args.iter().zip(args.into_iter()) {
+            let mut arg = arg.clone();
+            rename(&mut arg);
+            call_block.push(Instruction::Store{dst: arg, src: op});
+        }
+        call_block.push(Instruction::Branch(bb_ref + 1));
+    }
+
+    for (block_idx, instructions) in candidate.blocks.iter().enumerate() {
+        let block = &mut func.blocks.get_mut(&(bb_ref + 1 + block_idx)).expect("Unknown block").instructions;
+        for instr in instructions {
+            let mut instr = instr.clone();
+            for v in instr.vars_mut() {
+                rename(v);
+            }
+
+            for target in instr.branch_targets_mut() {
+                let block_idx = candidate.block_index[&*target];
+                *target = bb_ref + 1 + block_idx;
+            }
+
+            match instr {
+                // The source locations of the caller still apply
+                Instruction::SourceLocation(_) => (),
+                Instruction::Return(op) => {
+                    if let Some(ref ret) = ret {
+                        block.push(Instruction::Store{dst: ret.clone(), src: op});
+                    }
+                    block.push(Instruction::Branch(continue_bb));
+                },
+                Instruction::ReturnVoid => block.push(Instruction::Branch(continue_bb)),
+                _ => block.push(instr),
+            }
+        }
+    }
+
+    let continue_block = &mut func.blocks.get_mut(&continue_bb).expect("Unknown block").instructions;
+    if let (Some(dst), Some(ret)) = (dst, ret) {
+        continue_block.push(Instruction::Store{dst: dst, src: Operand::Var(ret)});
+    }
+    continue_block.extend(rest);
+    continue_bb
+}
+
+/*
+    Replace calls to small functions by a copy of their body. Only calls in the original code of a
+    function are inlined, and not the calls in inlined bodies, so mutually recursive functions stop.
+*/
+pub fn inline_functions(module: &mut ByteCodeModule, threshold: usize)
+{
+    let candidates: HashMap<String, InlineCandidate> = module.functions.values()
+        .filter_map(|func| inline_candidate(func, threshold).map(|c| (func.sig.name.clone(), c)))
+        .collect();
+    if candidates.is_empty() {
+        return;
+    }
+
+    let globals: HashSet<String> = module.globals.keys()
+        .chain(module.runtime_globals.keys())
+        .cloned()
+        .collect();
+
+    let mut counter = 0;
+    for func in module.functions.values_mut().filter(|func| !func.external) {
+        let mut pos = (0, 0);
+        while let Some((bb_ref, idx)) = find_call(func, pos, &candidates) {
+            let callee = match func.blocks[&bb_ref].instructions[idx] {
+                Instruction::Call{ref func, ..} => func.clone(),
+                _ => unreachable!(),
+            };
+
+            counter += 1;
+            // Globals, also the ones from other packages, have a namespace in their name, local variables don't
+            let local_name = |name: &str| if name.contains("::") || globals.contains(name) {
+                None
+            } else {
+                Some(format!("{}.inline{}", name, counter))
+            };
+
+            let continue_bb = inline_call(func, bb_ref, idx, &candidates[&callee], &local_name);
+            pos = (continue_bb, 0);
+        }
+    }
+}
@@ -1,13 +1,25 @@
 use bytecode::{ByteCodeModule};
 use bytecode::function::{ByteCodeFunction};
+use bytecode::instruction::Instruction;
 
+mod constantfolding;
+mod devirtualize;
 mod emptyblocks;
+mod escapeanalysis;
+mod inliner;
 mod unusedfunctions;
 mod returnvalueoptimization;
+mod tailcalls;
 
+use self::constantfolding::fold_constants;
 use self::emptyblocks::remove_empty_blocks;
+use self::escapeanalysis::escape_analysis;
+use self::inliner::inline_functions;
 use self::unusedfunctions::eliminate_unused_functions;
 use self::returnvalueoptimization::return_value_optimization;
+use self::tailcalls::tail_call_optimization;
+pub use self::devirtualize::devirtualize_interface_calls;
+pub use self::inliner::DEFAULT_INLINE_THRESHOLD;
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum OptimizationLevel
@@ -16,14 +28,35 @@ pub enum OptimizationLevel
     Normal,
 }
 
-pub fn optimize_function(func: &mut ByteCodeFunction, _lvl: OptimizationLevel)
+pub fn optimize_function(func: &mut ByteCodeFunction, lvl: OptimizationLevel)
 {
+    if lvl == OptimizationLevel::Normal {
+        fold_constants(func);
+    }
     remove_empty_blocks(func);
 }
 
-pub fn optimize_module(module: &mut ByteCodeModule, lvl: OptimizationLevel)
+// For profiles with bounds_checks = false, indexing is not checked at runtime
+pub fn remove_bounds_checks(module: &mut ByteCodeModule)
+{
+    for func in module.functions.values_mut() {
+        func.remove_instruction(|instr| match *instr {
+            Instruction::BoundsCheck{..} => true,
+            _ => false,
+        });
+    }
+}
+
+// Functions with at most inline_threshold instructions are inlined, and new expressions which don't escape use the stack,
+// at OptimizationLevel::Normal, tail calls are always turned into loops, so deep recursion works in debug builds too
+pub fn optimize_module(module: &mut ByteCodeModule, lvl: OptimizationLevel, inline_threshold: usize)
 {
     eliminate_unused_functions(module);
+    tail_call_optimization(module);
+    if lvl == OptimizationLevel::Normal {
+        inline_functions(module, inline_threshold);
+        escape_analysis(module);
+    }
     return_value_optimization(module);
     for func in module.functions.values_mut() {
         if !func.external {
@@ -37,7 +70,7 @@ mod test
 {
     use super::*;
     use bytecode::test::generate_byte_code;
-    use bytecode::instruction::Instruction;
+    use bytecode::instruction::{Instruction, Operand, Constant};
     use bytecode::function::ByteCodeFunction;
     use ast::{sig, Type};
     use span::Span;
@@ -74,10 +107,222 @@ mod test
         assert!(m.get_function("test::bar").is_some());
         assert!(m.get_function("test::main").is_some());
 
-        optimize_module(&mut m, OptimizationLevel::Normal);
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
 
         assert!(m.get_function("test::foo").is_none());
         assert!(m.get_function("test::bar").is_some());
         assert!(m.get_function("test::main").is_some());
     }
+
+    #[test]
+    fn test_instantiation_elimination()
+    {
+        let mut m = generate_byte_code(r#"
+            fn id(x: $a) -> $a: x
+            fn foo() -> int: id(6)
+            fn main() -> bool: id(true)
+        "#, false).expect("Parsing succeeded");
+
+        assert_eq!(m.functions.keys().filter(|name| name.starts_with("test::id<")).count(), 2);
+
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+
+        assert!(m.get_function("test::foo").is_none());
+        let ids: Vec<&String> = m.functions.keys().filter(|name| name.starts_with("test::id<")).collect();
+        assert_eq!(ids, vec!["test::id<bool>"]);
+    }
+
+    #[test]
+    fn test_library_function_elimination()
+    {
+        let mut m = generate_byte_code(r#"
+            fn foo() -> int: 6
+            fn bar() -> int: 7
+        "#, false).expect("Parsing succeeded");
+
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+
+        // Without a main function, other packages can still call foo and bar
+        assert!(m.get_function("test::foo").is_some());
+        assert!(m.get_function("test::bar").is_some());
+    }
+
+    fn calls_to(func: &ByteCodeFunction, callee: &str) -> usize
+    {
+        let mut count = 0;
+        func.for_each_instruction(|instr| {
+            match *instr {
+                Instruction::Call{ref func, ..} if func == callee => count += 1,
+                _ => (),
+            }
+            true
+        });
+        count
+    }
+
+    #[test]
+    fn test_inlining()
+    {
+        let prog = r#"
+            fn add(a: int, b: int) -> int: a + b
+            fn fac(n: int) -> int: if n <= 1: 1 else n * fac(n - 1)
+            fn main() -> int: add(1, 2) + add(3, fac(4))
+        "#;
+
+        let mut m = generate_byte_code(prog, false).expect("Parsing succeeded");
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+        let main = m.get_function("test::main").expect("main must exist");
+        assert_eq!(calls_to(main, "test::add"), 0);
+        // Recursive functions are never inlined
+        assert_eq!(calls_to(main, "test::fac"), 1);
+
+        let mut m = generate_byte_code(prog, false).expect("Parsing succeeded");
+        optimize_module(&mut m, OptimizationLevel::Normal, 0);
+        assert_eq!(calls_to(m.get_function("test::main").expect("main must exist"), "test::add"), 2);
+
+        let mut m = generate_byte_code(prog, false).expect("Parsing succeeded");
+        optimize_module(&mut m, OptimizationLevel::Minimal, DEFAULT_INLINE_THRESHOLD);
+        assert_eq!(calls_to(m.get_function("test::main").expect("main must exist"), "test::add"), 2);
+
+        // Functions which can fail at runtime keep their own frame, so errors report where they happened
+        let prog = r#"
+            fn div(a: int, b: int) -> int: a / b
+            fn main() -> int: div(4, 2)
+        "#;
+
+        let mut m = generate_byte_code(prog, false).expect("Parsing succeeded");
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+        assert_eq!(calls_to(m.get_function("test::main").expect("main must exist"), "test::div"), 1);
+    }
+
+    #[test]
+    fn test_tail_calls()
+    {
+        let prog = r#"
+            fn sum(n: int, acc: int) -> int: if n == 0: acc else sum(n - 1, acc + n)
+            fn fac(n: int) -> int: if n <= 1: 1 else n * fac(n - 1)
+            fn main() -> int: sum(10, 0) + fac(4)
+        "#;
+
+        for &lvl in &[OptimizationLevel::Minimal, OptimizationLevel::Normal] {
+            let mut m = generate_byte_code(prog, false).expect("Parsing succeeded");
+            optimize_module(&mut m, lvl, DEFAULT_INLINE_THRESHOLD);
+            assert_eq!(calls_to(m.get_function("test::sum").expect("sum must exist"), "test::sum"), 0);
+            // The result of the recursive call of fac is still needed
+            assert_eq!(calls_to(m.get_function("test::fac").expect("fac must exist"), "test::fac"), 1);
+        }
+    }
+
+    fn binary_ops(func: &ByteCodeFunction) -> usize
+    {
+        let mut count = 0;
+        func.for_each_instruction(|instr| {
+            if let Instruction::BinaryOp{..} = *instr {
+                count += 1;
+            }
+            true
+        });
+        count
+    }
+
+    #[test]
+    fn test_constant_folding()
+    {
+        let mut m = generate_byte_code(r#"
+            fn foo(x: int) -> int:
+                let a = 2 * 3 + 1
+                let b = a - 4
+                let c = x + a
+                if b == 3 && !false: c else b
+            fn main() -> int: foo(1)
+        "#, false).expect("Parsing succeeded");
+
+        // The && becomes a branch, because it short circuits
+        assert_eq!(binary_ops(m.get_function("test::foo").expect("foo must exist")), 5);
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+
+        // Only x + a remains, with a replaced by 7
+        let foo = m.get_function("test::foo").expect("foo must exist");
+        assert_eq!(binary_ops(foo), 1);
+        let mut folded = false;
+        foo.for_each_instruction(|instr| {
+            if let Instruction::BinaryOp{right: Operand::Const(Constant::Int(7, _)), ..} = *instr {
+                folded = true;
+            }
+            true
+        });
+        assert!(folded);
+    }
+
+    #[test]
+    fn test_no_folding_of_escaped_variables()
+    {
+        let mut m = generate_byte_code(r#"
+            fn set(var p: *int):
+                *p = 5
+            fn main() -> int:
+                var a = 2
+                set(&a)
+                a + 1
+        "#, false).expect("Parsing succeeded");
+
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+        assert_eq!(binary_ops(m.get_function("test::main").expect("main must exist")), 1);
+    }
+
+    fn heap_allocs(func: &ByteCodeFunction) -> usize
+    {
+        let mut count = 0;
+        func.for_each_instruction(|instr| {
+            if let Instruction::HeapAlloc(_) = *instr {
+                count += 1;
+            }
+            true
+        });
+        count
+    }
+
+    #[test]
+    fn test_escape_analysis()
+    {
+        let prog = r#"
+            struct Point:
+                x: int
+                y: int
+
+            fn x_of(p: *Point) -> int: p.x
+
+            fn main() -> int:
+                var total = 0, i = 0
+                var a = new Point{1, 2}
+                a.y = 5
+                let b = new Point{3, 4}
+                while i < 3:
+                    let c = new Point{i, i}
+                    total = total + c.x
+                    i = i + 1
+                delete a
+                total + x_of(b)
+        "#;
+
+        let mut m = generate_byte_code(prog, false).expect("Parsing succeeded");
+        assert_eq!(heap_allocs(m.get_function("test::main").expect("main must exist")), 3);
+        optimize_module(&mut m, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+
+        // b is passed to a call, and c is allocated in a loop
+        let main = m.get_function("test::main").expect("main must exist");
+        assert_eq!(heap_allocs(main), 2);
+        let mut deletes = 0;
+        main.for_each_instruction(|instr| {
+            if let Instruction::Delete(_) = *instr {
+                deletes += 1;
+            }
+            true
+        });
+        assert_eq!(deletes, 0);
+
+        let mut m = generate_byte_code(prog, false).expect("Parsing succeeded");
+        optimize_module(&mut m, OptimizationLevel::Minimal, DEFAULT_INLINE_THRESHOLD);
+        assert_eq!(heap_allocs(m.get_function("test::main").expect("main must exist")), 3);
+    }
 }
@@ -8,6 +8,7 @@ use bytecode::{
     Var,
     store_operand_instr,
     void_call_instr,
+    interface_call_instr,
     Operand,
 };
 
@@ -49,6 +50,15 @@ fn rvo_replace_calls(bc_func: &mut ByteCodeFunction, rvo_calls: &[String])
                 }
             }
 
+            // The functions in an interface table follow the same convention
+            Instruction::InterfaceCall{dst: Some(ref dst), ref obj, index, ref args} if !dst.typ.pass_by_value() => {
+                let mut new_args = args.clone();
+                new_args.push(Operand::AddressOf(dst.clone()));
+                vec![
+                    interface_call_instr(None, obj, index, new_args)
+                ]
+            }
+
             _ => Vec::new(),
         }
     })
@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+use bytecode::ByteCodeModule;
+use bytecode::function::{BasicBlockRef, ByteCodeFunction, Var};
+use bytecode::instruction::{Instruction, Operand};
+use super::inliner::can_be_local;
+
+// The block the entry block branches to, tail calls jump back to it
+const LOOP_BLOCK: BasicBlockRef = 1;
+
+fn is_scope_marker(instr: &Instruction) -> bool
+{
+    match *instr {
+        Instruction::StartScope |
+        Instruction::EndScope => true,
+        _ => false,
+    }
+}
+
+/*
+    A call to the function itself is a tail call, when nothing happens with the result
+    but copying it around and returning it. Scope markers and branches along the way are fine.
+*/
+fn is_tail_call(func: &ByteCodeFunction, bb_ref: BasicBlockRef, idx: usize, globals: &HashSet<String>) -> bool
+{
+    let mut result = match func.blocks[&bb_ref].instructions[idx] {
+        Instruction::Call{ref dst, func: ref callee, ..} if *callee == func.sig.name => dst.clone(),
+        _ => return false,
+    };
+
+    let mut visited = HashSet::new();
+    let (mut bb_ref, mut idx) = (bb_ref, idx + 1);
+    loop {
+        let instr = match func.blocks[&bb_ref].instructions.get(idx) {
+            Some(instr) => instr,
+            None => return false,
+        };
+
+        match *instr {
+            Instruction::StartScope |
+            Instruction::EndScope |
+            Instruction::SourceLocation(_) => idx += 1,
+            Instruction::Store{ref dst, src: Operand::Var(ref src)} if Some(src) == result.as_ref() => {
+                // Skipping a store to a global would lose it
+                if dst.typ != src.typ || dst.name.contains("::") || globals.contains(&dst.name) {
+                    return false;
+                }
+                result = Some(dst.clone());
+                idx += 1;
+            },
+            Instruction::Branch(target) => {
+                if !visited.insert(target) {
+                    return false;
+                }
+                bb_ref = target;
+                idx = 0;
+            },
+            Instruction::Return(Operand::Var(ref v)) => return Some(v) == result.as_ref(),
+            Instruction::ReturnVoid => return result.is_none(),
+            _ => return false,
+        }
+    }
+}
+
+fn find_tail_calls(func: &ByteCodeFunction, globals: &HashSet<String>) -> Vec<(BasicBlockRef, usize)>
+{
+    let mut calls = Vec::new();
+    for (bb_ref, bb) in &func.blocks {
+        for idx in 0..bb.instructions.len() {
+            if is_tail_call(func, *bb_ref, idx, globals) {
+                calls.push((*bb_ref, idx));
+            }
+        }
+    }
+    calls
+}
+
+fn loop_var(arg: &Var) -> Var
+{
+    Var::named(&format!("{}.tail", arg.name), arg.typ.clone())
+}
+
+// The new values of the arguments are computed first, because they can refer to the old ones
+fn replace_tail_call(func: &mut ByteCodeFunction, bb_ref: BasicBlockRef, idx: usize, loop_vars: &[Var])
+{
+    let block = &mut func.blocks.get_mut(&bb_ref).expect("Unknown block").instructions;
+    let mut rest = block.split_off(idx);
+    let args = match rest.remove(0) {
+        Instruction::Call{args, ..} => args,
+        _ => panic!("Internal Compiler Error: Expecting a call instruction"),
+    };
+
+    let next_vars: Vec<Var> = loop_vars.iter()
+        .map(|v| Var::named(&format!("{}.next", v.name), v.typ.clone()))
+        .collect();
+    for (next, op) in next_vars.iter().zip(args.into_iter()) {
+        block.push(Instruction::Store{dst: next.clone(), src: op});
+    }
+
+    for (v, next) in loop_vars.iter().zip(next_vars.into_iter()) {
+        block.push(Instruction::Store{dst: v.clone(), src: Operand::Var(next)});
+    }
+
+    block.push(Instruction::Branch(LOOP_BLOCK));
+    // Keep the scopes balanced for the backends
+    block.extend(rest.into_iter().filter(is_scope_marker));
+}
+
+/*
+    Turn tail recursive functions into loops. The body of the entry block moves to a new block,
+    and the entry block copies the arguments into local variables, which the body uses instead.
+    A tail call then stores the new arguments in those variables, and branches back to the body.
+*/
+fn eliminate_tail_calls(func: &mut ByteCodeFunction, globals: &HashSet<String>)
+{
+    if func.external || func.blocks.is_empty() || !func.sig.args.iter().all(|arg| can_be_local(&arg.typ)) {
+        return;
+    }
+
+    let tail_calls = find_tail_calls(func, globals);
+    if tail_calls.is_empty() {
+        return;
+    }
+
+    func.insert_blocks_after(0, 1);
+    let body = func.blocks.get_mut(&0).expect("No entry block").instructions.split_off(0);
+    func.blocks.get_mut(&LOOP_BLOCK).expect("No loop block").instructions = body;
+
+    let arg_names: HashSet<String> = func.sig.args.iter().map(|arg| arg.name.clone()).collect();
+    for bb in func.blocks.values_mut() {
+        for instr in &mut bb.instructions {
+            for v in instr.vars_mut() {
+                if arg_names.contains(&v.name) {
+                    *v = loop_var(v);
+                }
+            }
+        }
+    }
+
+    // The entry block isn't shifted, so its tail calls are in the loop block now
+    for (bb_ref, idx) in tail_calls.into_iter().rev() {
+        let bb_ref = if bb_ref == 0 {LOOP_BLOCK} else {bb_ref + 1};
+        let loop_vars: Vec<Var> = func.sig.args.iter()
+            .map(|arg| loop_var(&Var::named(&arg.name, arg.typ.clone())))
+            .collect();
+        replace_tail_call(func, bb_ref, idx, &loop_vars);
+    }
+
+    let entry = &mut func.blocks.get_mut(&0).expect("No entry block").instructions;
+    for arg in &func.sig.args {
+        let arg = Var::named(&arg.name, arg.typ.clone());
+        entry.push(Instruction::Store{dst: loop_var(&arg), src: Operand::Var(arg)});
+    }
+    entry.push(Instruction::Branch(LOOP_BLOCK));
+}
+
+pub fn tail_call_optimization(module: &mut ByteCodeModule)
+{
+    let globals: HashSet<String> = module.globals.keys()
+        .chain(module.runtime_globals.keys())
+        .cloned()
+        .collect();
+
+    for func in module.functions.values_mut() {
+        eliminate_tail_calls(func, &globals);
+    }
+}
@@ -2,7 +2,7 @@ use std::collections::HashSet;
 use bytecode::{ByteCodeModule};
 use bytecode::function::{ByteCodeFunction};
 use bytecode::instruction::{Instruction, Operand};
-use compileerror::print_message;
+use compileerror::print_warning;
 
 
 // Find all calls recursively, and remove them from the unused_calls HashSet
@@ -27,31 +27,66 @@ fn find_used_calls(module: &ByteCodeModule, func: &ByteCodeFunction, unused_call
                 }
             }
 
+            Instruction::MakeClosure{ref func, ..} => {
+                handle_func(func);
+            }
+
+            Instruction::MakeInterface{ref functions, ..} => {
+                for func in functions {
+                    handle_func(func);
+                }
+            }
+
             _ => ()
         }
         true
     })
 }
 
+// Copies of generic functions made by instantiate_generics have the generic arguments in their name
+fn is_instantiation(name: &str) -> bool
+{
+    name.ends_with('>')
+}
+
 pub fn eliminate_unused_functions(module: &mut ByteCodeModule)
 {
-    let mut unused_calls: HashSet<String> = module.functions.keys().cloned().collect();
-    let mut unused_imported: HashSet<String> = module.imported_functions.iter().map(|func| func.sig.name.clone()).collect();
-    if let Some(main) = module.get_function(&module.main_function_name()) {
-        unused_calls.remove(&main.sig.name);
-        find_used_calls(module, main, &mut unused_calls);
-        find_used_calls(module, main, &mut unused_imported);
-    } else {
-        return;
+    // Imported functions are tracked in the same set, so calls to them from any used function are found
+    let mut unused_calls: HashSet<String> = module.functions.keys()
+        .cloned()
+        .chain(module.imported_functions.iter().map(|func| func.sig.name.clone()))
+        .collect();
+
+    // Functions called before and after main, the ones initializing globals and the ones called from C are used as well
+    let mut roots: Vec<String> = Some(module.main_function_name()).into_iter()
+        .chain(module.exported_functions.iter().cloned())
+        .chain(module.init_functions.iter().cloned())
+        .chain(module.fini_functions.iter().cloned())
+        .chain(module.runtime_globals.values().map(|rg| rg.init_function.clone()))
+        .collect();
+
+    // Without a main function, this is a library, and other packages can call any of its functions,
+    // except for the instantiated generics, which they instantiate themselves
+    if module.get_function(&module.main_function_name()).is_none() {
+        roots.extend(module.functions.keys().filter(|name| !is_instantiation(name)).cloned());
+    }
+
+    for root in &roots {
+        if let Some(func) = module.get_function(root) {
+            unused_calls.remove(&func.sig.name);
+            find_used_calls(module, func, &mut unused_calls);
+        }
     }
 
 
     for call in &unused_calls {
-        module.functions
-            .get(call)
-            .map(|func| print_message(&format!("Warning: unused function {}", func.sig.name), &func.sig.span));
-        module.functions.remove(call);
+        if let Some(func) = module.functions.remove(call) {
+            // The functions @derive generates are not the programmer's to remove
+            if !is_instantiation(call) && !func.sig.span.file.starts_with('<') {
+                print_warning(&format!("unused function {}", func.sig.name), &func.sig.span);
+            }
+        }
     }
 
-    module.imported_functions.retain(|func: &ByteCodeFunction| !unused_imported.contains(&func.sig.name));
+    module.imported_functions.retain(|func: &ByteCodeFunction| !unused_calls.contains(&func.sig.name));
 }
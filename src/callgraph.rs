@@ -0,0 +1,228 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use ast::{Expression, MemberAccessType};
+use compileerror::CompileResult;
+use package::Package;
+
+pub struct CallGraph
+{
+    calls: BTreeMap<String, BTreeSet<String>>,
+}
+
+struct TarjanState
+{
+    index: usize,
+    indices: HashMap<String, usize>,
+    low_links: HashMap<String, usize>,
+    stack: Vec<String>,
+    components: Vec<Vec<String>>,
+}
+
+fn collect_calls(e: &Expression, callees: &mut BTreeSet<String>)
+{
+    let _ = e.visit(&mut |e: &Expression| -> CompileResult<()> {
+        match *e
+        {
+            Expression::Call(ref c) => {
+                callees.insert(c.callee.name.clone());
+            },
+            Expression::MemberAccess(ref ma) => {
+                if let MemberAccessType::Call(ref c) = ma.right {
+                    callees.insert(c.callee.name.clone());
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    });
+}
+
+impl CallGraph
+{
+    pub fn new(pkg: &Package) -> CallGraph
+    {
+        let mut calls = BTreeMap::new();
+        for module in pkg.modules.values() {
+            for func in module.functions.values() {
+                if func.is_generic() {
+                    continue;
+                }
+
+                let mut callees = BTreeSet::new();
+                collect_calls(&func.expression, &mut callees);
+                calls.insert(func.sig.name.clone(), callees);
+            }
+        }
+
+        // Only keep calls to functions we know about, externals can't call back into us
+        let known: BTreeSet<String> = calls.keys().cloned().collect();
+        for callees in calls.values_mut() {
+            *callees = callees.intersection(&known).cloned().collect();
+        }
+
+        CallGraph{calls}
+    }
+
+    fn strong_connect(&self, func: &str, state: &mut TarjanState)
+    {
+        state.indices.insert(func.into(), state.index);
+        state.low_links.insert(func.into(), state.index);
+        state.index += 1;
+        state.stack.push(func.into());
+
+        for callee in &self.calls[func] {
+            if !state.indices.contains_key(callee) {
+                self.strong_connect(callee, state);
+                let low_link = cmp::min(state.low_links[func], state.low_links[callee]);
+                state.low_links.insert(func.into(), low_link);
+            } else if state.stack.contains(callee) {
+                let low_link = cmp::min(state.low_links[func], state.indices[callee]);
+                state.low_links.insert(func.into(), low_link);
+            }
+        }
+
+        if state.low_links[func] == state.indices[func] {
+            let mut component = Vec::new();
+            while let Some(f) = state.stack.pop() {
+                let done = f == func;
+                component.push(f);
+                if done {
+                    break;
+                }
+            }
+            component.sort();
+            state.components.push(component);
+        }
+    }
+
+    // Returns the strongly connected components, callees always come before their callers
+    pub fn strongly_connected_components(&self) -> Vec<Vec<String>>
+    {
+        let mut state = TarjanState{
+            index: 0,
+            indices: HashMap::new(),
+            low_links: HashMap::new(),
+            stack: Vec::new(),
+            components: Vec::new(),
+        };
+
+        for func in self.calls.keys() {
+            if !state.indices.contains_key(func) {
+                self.strong_connect(func, &mut state);
+            }
+        }
+
+        state.components
+    }
+
+    fn is_recursive(&self, component: &[String]) -> bool
+    {
+        component.len() > 1 || self.calls[&component[0]].contains(&component[0])
+    }
+
+    // Maximum number of nested calls starting from each function, None if recursion makes it unbounded
+    pub fn max_call_depths(&self) -> BTreeMap<String, Option<usize>>
+    {
+        let mut depths: BTreeMap<String, Option<usize>> = BTreeMap::new();
+        for component in self.strongly_connected_components() {
+            let mut depth = if self.is_recursive(&component) {None} else {Some(1)};
+            for func in &component {
+                for callee in &self.calls[func] {
+                    if component.contains(callee) {
+                        continue;
+                    }
+
+                    depth = match (depth, depths[callee]) {
+                        (Some(d), Some(callee_depth)) => Some(cmp::max(d, callee_depth + 1)),
+                        _ => None,
+                    };
+                }
+            }
+
+            for func in component {
+                depths.insert(func, depth);
+            }
+        }
+
+        depths
+    }
+
+    pub fn print_recursion_report(&self, main: &str)
+    {
+        let recursive: Vec<Vec<String>> = self.strongly_connected_components()
+            .into_iter()
+            .filter(|c| self.is_recursive(c))
+            .collect();
+
+        if recursive.is_empty() {
+            println!("No recursive functions found");
+        } else {
+            println!("Recursive call cycles:");
+            for component in &recursive {
+                println!("  {}", component.join(" -> "));
+            }
+        }
+
+        let depths = self.max_call_depths();
+        println!("Maximum static call depth:");
+        for (func, depth) in &depths {
+            match *depth {
+                Some(d) => println!("  {}: {}", func, d),
+                None => println!("  {}: unbounded", func),
+            }
+        }
+
+        match depths.get(main) {
+            Some(&Some(d)) => println!("Maximum call depth from {}: {}", main, d),
+            Some(&None) => println!("Maximum call depth from {}: unbounded (recursion)", main),
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::CallGraph;
+    use parser::parse_str;
+    use ast::IntSize;
+    use target::Target;
+
+    fn call_graph(prog: &str) -> CallGraph
+    {
+        let target = Target::new(IntSize::I32, "");
+        let mut pkg = parse_str(prog, "test", &target).expect("Parsing failed");
+        pkg.type_check(&target).expect("Type check failed");
+        CallGraph::new(&pkg)
+    }
+
+    #[test]
+    fn test_call_depth()
+    {
+        let cg = call_graph(r#"
+fn bar(a: int) -> int: a + 1
+
+fn foo(a: int) -> int: bar(a) * 2
+
+fn main() -> int: foo(4)
+"#);
+        let depths = cg.max_call_depths();
+        assert_eq!(depths["test::bar"], Some(1));
+        assert_eq!(depths["test::main"], Some(3));
+    }
+
+    #[test]
+    fn test_recursion()
+    {
+        let cg = call_graph(r#"
+fn even(a: int) -> bool: if a == 0: true else odd(a - 1)
+
+fn odd(a: int) -> bool: if a == 0: false else even(a - 1)
+
+fn main() -> int: if even(4): 1 else 0
+"#);
+        let components = cg.strongly_connected_components();
+        assert!(components.contains(&vec![String::from("test::even"), String::from("test::odd")]));
+        assert_eq!(cg.max_call_depths()["test::main"], None);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::rc::Rc;
+use ast::{Type, IntSize, FloatSize, StructType, Function, ptr_type};
+use package::Package;
+
+// Collects the C declarations of all types used by the exported functions
+struct CHeaderTypes
+{
+    declared: HashSet<String>,
+    defined: HashSet<String>,
+    forward_declarations: Vec<String>,
+    definitions: Vec<String>,
+    // Types behind a pointer, they can be defined after their users
+    pending: Vec<Type>,
+}
+
+pub fn c_name(name: &str) -> String
+{
+    name.replace("::", "_")
+}
+
+fn c_type_name(typ: &Type) -> String
+{
+    match *typ
+    {
+        Type::Void => "void".into(),
+        Type::Int(IntSize::I8) => "int8_t".into(),
+        Type::Int(IntSize::I16) => "int16_t".into(),
+        Type::Int(IntSize::I32) => "int32_t".into(),
+        Type::Int(IntSize::I64) => "int64_t".into(),
+        Type::UInt(IntSize::I8) => "uint8_t".into(),
+        Type::UInt(IntSize::I16) => "uint16_t".into(),
+        Type::UInt(IntSize::I32) => "uint32_t".into(),
+        Type::UInt(IntSize::I64) => "uint64_t".into(),
+        Type::Float(FloatSize::F32) => "float".into(),
+        Type::Float(FloatSize::F64) => "double".into(),
+        Type::Char => "char".into(),
+        Type::Bool => "bool".into(),
+        Type::Struct(ref st) |
+        Type::Union(ref st) => c_name(&st.name),
+        Type::Opaque(ref ot) => c_name(&ot.name),
+        _ => panic!("Internal Compiler Error: type {} has no C equivalent", typ),
+    }
+}
+
+// Wrap a declarator (the name being declared) in the C syntax for typ
+fn c_declarator(typ: &Type, declarator: String) -> String
+{
+    match *typ
+    {
+        Type::Pointer(ref inner) => {
+            match *inner.deref() {
+                Type::Array(_) => c_declarator(inner, format!("(*{})", declarator)),
+                _ => c_declarator(inner, format!("*{}", declarator)),
+            }
+        },
+        Type::Array(ref at) => c_declarator(&at.element_type, format!("{}[{}]", declarator, at.len)),
+        _ if declarator.is_empty() => c_type_name(typ),
+        _ => format!("{} {}", c_type_name(typ), declarator),
+    }
+}
+
+impl CHeaderTypes
+{
+    fn new() -> CHeaderTypes
+    {
+        CHeaderTypes{
+            declared: HashSet::new(),
+            defined: HashSet::new(),
+            forward_declarations: Vec::new(),
+            definitions: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn declare(&mut self, typ: &Type)
+    {
+        let (keyword, name) = match *typ
+        {
+            Type::Pointer(ref inner) => return self.declare(inner),
+            Type::Array(ref at) => return self.declare(&at.element_type),
+            Type::Struct(ref st) => ("struct", &st.name),
+            Type::Union(ref st) => ("union", &st.name),
+            Type::Opaque(ref ot) => ("struct", &ot.name),
+            _ => return,
+        };
+
+        if self.declared.insert(name.clone()) {
+            let name = c_name(name);
+            self.forward_declarations.push(format!("typedef {} {} {};", keyword, name, name));
+        }
+    }
+
+    fn define_struct(&mut self, keyword: &str, st: &Rc<StructType>)
+    {
+        if !self.defined.insert(st.name.clone()) {
+            return;
+        }
+
+        // Members stored by value have to be defined first
+        for m in &st.members {
+            self.define(&m.typ);
+        }
+
+        let mut definition = format!("{} {}\n{{\n", keyword, c_name(&st.name));
+        for m in &st.members {
+            definition.push_str(&format!("    {};\n", c_declarator(&m.typ, m.name.clone())));
+        }
+        definition.push_str("};\n");
+        self.definitions.push(definition);
+    }
+
+    fn define(&mut self, typ: &Type)
+    {
+        self.declare(typ);
+        match *typ
+        {
+            Type::Pointer(ref inner) => self.pending.push(inner.deref().clone()),
+            Type::Array(ref at) => self.define(&at.element_type),
+            Type::Struct(ref st) => self.define_struct("struct", st),
+            Type::Union(ref st) => self.define_struct("union", st),
+            _ => (),
+        }
+    }
+
+    fn define_pending(&mut self)
+    {
+        while let Some(typ) = self.pending.pop() {
+            self.define(&typ);
+        }
+    }
+}
+
+// Arguments which are not passed by value, are passed as a pointer
+fn c_argument_type(typ: &Type) -> Type
+{
+    if typ.pass_by_value() {
+        typ.clone()
+    } else {
+        ptr_type(typ.clone())
+    }
+}
+
+fn c_prototype(func: &Function) -> String
+{
+    let args = if func.sig.args.is_empty() {
+        String::from("void")
+    } else {
+        func.sig.args.iter()
+            .map(|arg| c_declarator(&c_argument_type(&arg.typ), arg.name.clone()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    format!("{};", c_declarator(&func.sig.return_type, format!("{}({})", func.sig.exported_name(), args)))
+}
+
+fn exported_functions(pkg: &Package) -> Vec<&Function>
+{
+    let mut functions: Vec<&Function> = pkg.modules.values()
+        .flat_map(|md| md.functions.values())
+        .filter(|f| f.export)
+        .collect();
+    functions.sort_by(|a, b| a.sig.name.cmp(&b.sig.name));
+    functions
+}
+
+// Generate a C header with the prototypes of all @export functions, and the types they use
+pub fn generate_c_header(pkg: &Package) -> String
+{
+    let functions = exported_functions(pkg);
+    let mut types = CHeaderTypes::new();
+    for func in &functions {
+        for arg in &func.sig.args {
+            types.define(&c_argument_type(&arg.typ));
+        }
+        types.define(&func.sig.return_type);
+        types.define_pending();
+    }
+
+    let guard: String = format!("MENHIR_{}_H", pkg.name)
+        .chars()
+        .map(|c| if c.is_alphanumeric() {c.to_ascii_uppercase()} else {'_'})
+        .collect();
+
+    let mut header = String::new();
+    header.push_str(&format!("/* Generated by the menhir compiler from package {}, do not edit */\n", pkg.name));
+    header.push_str(&format!("#ifndef {}\n#define {}\n\n", guard, guard));
+    header.push_str("#include <stdbool.h>\n#include <stdint.h>\n\n");
+    header.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+
+    for decl in &types.forward_declarations {
+        header.push_str(decl);
+        header.push('\n');
+    }
+
+    if !types.forward_declarations.is_empty() {
+        header.push('\n');
+    }
+
+    for def in &types.definitions {
+        header.push_str(def);
+        header.push('\n');
+    }
+
+    for func in &functions {
+        header.push_str(&c_prototype(func));
+        header.push('\n');
+    }
+
+    header.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n");
+    header.push_str(&format!("#endif /* {} */\n", guard));
+    header
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::generate_c_header;
+    use parser::parse_str;
+    use ast::IntSize;
+    use target::Target;
+
+    fn header(prog: &str) -> String
+    {
+        let target = Target::new(IntSize::I32, "");
+        let mut pkg = parse_str(prog, "test", &target).expect("Parsing failed");
+        pkg.type_check(&target).expect("Type check failed");
+        generate_c_header(&pkg)
+    }
+
+    #[test]
+    fn test_exported_functions()
+    {
+        let h = header(r#"
+@export
+fn add(a: int32, b: int32) -> int32: a + b
+
+@export
+fn origin(p: *int32) -> *int32: p
+
+fn not_exported() -> int: 7
+"#);
+        assert!(h.contains("int32_t add(int32_t a, int32_t b);"));
+        assert!(h.contains("int32_t *origin(int32_t *p);"));
+        assert!(!h.contains("not_exported"));
+    }
+
+    #[test]
+    fn test_exported_structs()
+    {
+        let h = header(r#"
+@repr(C)
+struct Point:
+    x: int32
+    y: int32
+
+@repr(C)
+struct Line:
+    from: Point
+    to: Point
+
+@export
+fn length(l: Line) -> int32: l.to.x - l.from.x
+"#);
+        assert!(h.contains("typedef struct test_Line test_Line;"));
+        assert!(h.contains("struct test_Point\n{\n    int32_t x;\n    int32_t y;\n};"));
+        assert!(h.contains("struct test_Line\n{\n    test_Point from;\n    test_Point to;\n};"));
+        assert!(h.find("struct test_Point\n").unwrap() < h.find("struct test_Line\n").unwrap());
+        assert!(h.contains("int32_t length(test_Line *l);"));
+    }
+}
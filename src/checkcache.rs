@@ -0,0 +1,121 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use bincode;
+
+use query::{Queries, hash_input, INITIAL_HASH};
+use target::Target;
+
+/*
+The results of the type check query, saved between runs of interactive tools like the ide command,
+so they don't have to type check every module, and the built-in modules it imports, on every start.
+The cache of another compiler version is ignored.
+*/
+
+const CACHE_VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "-1");
+
+// A missing or unreadable cache gives queries without results
+pub fn load_check_cache(path: &Path) -> Queries
+{
+    let cache: Option<(String, Queries)> = File::open(path)
+        .ok()
+        .and_then(|mut file| bincode::deserialize_from(&mut file, bincode::Infinite).ok());
+    match cache {
+        Some((version, queries)) if version == CACHE_VERSION => queries,
+        _ => Queries::new(),
+    }
+}
+
+// Written to a temporary file first, so a tool running at the same time never reads half a cache
+pub fn save_check_cache(queries: &Queries, path: &Path) -> Result<(), String>
+{
+    let tmp_path = path.with_extension("tmp");
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let mut file = File::create(&tmp_path).map_err(|e| e.to_string())?;
+    bincode::serialize_into(&mut file, &(CACHE_VERSION, queries), bincode::Infinite)
+        .map_err(|e| format!("Serialization error: {}", e))?;
+    fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+}
+
+// One cache file per package and target, in the cache directory
+pub fn check_cache_file(cache_dir: &Path, package_path: &Path, target: &Target) -> PathBuf
+{
+    let package_path = package_path.canonicalize().unwrap_or_else(|_| package_path.to_path_buf());
+    let hash = hash_input(hash_input(INITIAL_HASH, &package_path.to_string_lossy()), &target.triplet);
+    let name = package_path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    cache_dir.join(format!("{}-{:016x}.check", name, hash))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::process;
+    use std::rc::Rc;
+    use ast::IntSize;
+    use compileerror::CompileResult;
+    use package::Package;
+
+    fn check_files(files: &[(&str, &str)], queries: Queries, target: &Target) -> (CompileResult<Package>, Queries)
+    {
+        let queries = Rc::new(RefCell::new(queries));
+        let mut pkg = Package::new("cached");
+        for &(path, code) in files {
+            pkg.source_buffers.insert(path.into(), code.into());
+        }
+        pkg.queries = Some(queries.clone());
+        pkg.parse_files(Path::new(files[0].0), target).expect("Parsing failed");
+        let result = pkg.type_check(target);
+        pkg.queries = None;
+        let queries = Rc::try_unwrap(queries).ok().expect("Queries still in use").into_inner();
+        (result.map(|_| pkg), queries)
+    }
+
+    fn check(code: &str, queries: Queries, target: &Target) -> (Package, Queries)
+    {
+        let (result, queries) = check_files(&[("cached.mhr", code)], queries, target);
+        (result.expect("Type check failed"), queries)
+    }
+
+    #[test]
+    fn test_check_cache()
+    {
+        let target = Target::new(IntSize::I64, "");
+        let code = "import path\n\nfn main() -> int: extension(\"a.mhr\").len as int\n";
+        let (pkg, queries) = check(code, Queries::new(), &target);
+        assert_eq!(queries.type_check.hits, 0);
+        assert_eq!(queries.type_check.len(), pkg.modules.len());
+
+        let file = env::temp_dir().join(format!("menhir-check-{}", process::id())).join("cached.check");
+        save_check_cache(&queries, &file).expect("Saving failed");
+
+        // Unchanged modules come from the cache
+        let (pkg, queries) = check(code, load_check_cache(&file), &target);
+        assert_eq!(queries.type_check.hits, pkg.modules.len());
+
+        // A changed module is checked again, the modules it imports are not
+        let (pkg, queries) = check("import path\n\nfn main() -> int: basename(\"a.mhr\").len as int\n", load_check_cache(&file), &target);
+        assert_eq!(queries.type_check.hits, pkg.modules.len() - 1);
+
+        let _ = fs::remove_dir_all(file.parent().expect("No parent directory"));
+        assert!(load_check_cache(&file).type_check.is_empty());
+    }
+
+    #[test]
+    fn test_check_cache_generic_body_changed()
+    {
+        // The body of a generic function is checked when a module calls it, so a changed body must check its callers again
+        let target = Target::new(IntSize::I64, "");
+        let main = "import util\n\nfn main() -> int: twice(\"a\").len as int\n";
+        let (result, queries) = check_files(&[("cached.mhr", main), ("util.mhr", "pub fn twice(x: $T) -> $T: x\n")], Queries::new(), &target);
+        assert!(result.is_ok());
+
+        let (result, queries) = check_files(&[("cached.mhr", main), ("util.mhr", "pub fn twice(x: $T) -> $T: x + x\n")], queries, &target);
+        assert!(result.is_err());
+        assert_eq!(queries.type_check.hits, 0);
+    }
+}
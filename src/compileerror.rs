@@ -1,10 +1,15 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
 use std::error::Error;
 use std::convert::From;
 use std::iter::repeat;
 use std::fs::File;
 use std::io;
-use std::io::BufRead;
+use std::io::Read;
 use std::fmt;
+use std::rc::Rc;
+use libc::{isatty, STDOUT_FILENO};
 use ast::Type;
 use span::Span;
 
@@ -42,13 +47,62 @@ pub enum CompileError
     IO(String),
     Parse(ErrorData),
     Type(ErrorData),
-    UnknownName(ErrorData),
+    UnknownName(ErrorData, String), // Error and the name which could not be resolved
     UnknownType(String, Type), // Name and expected type
     Many(Vec<CompileError>),
 }
 
+// Number of errors printed, unless another limit is given with --error-limit
+pub const DEFAULT_ERROR_LIMIT: usize = 20;
+
 impl CompileError
 {
+    // All errors, with the errors in Many flattened
+    pub fn into_errors(self) -> Vec<CompileError>
+    {
+        match self {
+            CompileError::Many(errors) => errors.into_iter().flat_map(|e| e.into_errors()).collect(),
+            e => vec![e],
+        }
+    }
+
+    fn span(&self) -> Option<&Span>
+    {
+        match *self
+        {
+            CompileError::Parse(ref ed) |
+            CompileError::Type(ref ed) |
+            CompileError::UnknownName(ref ed, _) => Some(&ed.span),
+            _ => None,
+        }
+    }
+
+    // All errors sorted by file and position, so the first limit errors are the same on every run.
+    // Modules are checked in hash map order, so their errors don't arrive in any order.
+    fn sorted_errors(&self) -> Vec<CompileError>
+    {
+        let mut errors = self.clone().into_errors();
+        errors.sort_by(|a, b| {
+            let key = |e: &CompileError| e.span().map(|s| (s.file.clone(), s.start, s.end));
+            key(a).cmp(&key(b))
+        });
+        errors
+    }
+
+    // Print at most limit errors, 0 means all errors are printed
+    pub fn print_limited(&self, limit: usize)
+    {
+        let errors = self.sorted_errors();
+        let shown = if limit == 0 {errors.len()} else {limit};
+        for e in errors.iter().take(shown) {
+            e.print();
+        }
+
+        if errors.len() > shown {
+            println!("{} more errors not shown, use --error-limit to show more", errors.len() - shown);
+        }
+    }
+
     pub fn print(&self)
     {
         match *self
@@ -57,7 +111,7 @@ impl CompileError
             CompileError::IO(ref msg) => println!("{}", msg),
             CompileError::Parse(ref ed) |
             CompileError::Type(ref ed) |
-            CompileError::UnknownName(ref ed) => print_message(&ed.msg, &ed.span),
+            CompileError::UnknownName(ref ed, _) => print_message(&ed.msg, &ed.span),
             CompileError::UnknownType(ref name, ref typ) => println!("{} has unknown type, expecting {}", name, typ),
             CompileError::Many(ref errors) => {
                 for e in errors {
@@ -83,7 +137,7 @@ impl fmt::Display for CompileError
             CompileError::IO(ref msg) => writeln!(f, "{}", msg),
             CompileError::Parse(ref ed) |
             CompileError::Type(ref ed) |
-            CompileError::UnknownName(ref ed) => ed.fmt(f),
+            CompileError::UnknownName(ref ed, _) => ed.fmt(f),
             CompileError::UnknownType(ref name, ref typ) => writeln!(f, "{} has unknown type, expecting {}", name, typ),
             CompileError::Many(ref errors) => {
                 for err in errors {
@@ -95,49 +149,233 @@ impl fmt::Display for CompileError
     }
 }
 
-pub fn print_message(msg: &str, span: &Span)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode
+{
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode
 {
-    fn repeat_string(s: &str, count: usize) -> String
+    pub fn from_name(name: &str) -> Option<ColorMode>
     {
-        repeat(s).take(count).collect()
+        match name
+        {
+            "auto" => Some(ColorMode::Auto),
+            "always" => Some(ColorMode::Always),
+            "never" => Some(ColorMode::Never),
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    // Source text of every parsed file, so errors can show the offending lines of unsaved buffers too
+    static SOURCES: RefCell<HashMap<String, Rc<String>>> = RefCell::new(HashMap::new());
+    static USE_COLOR: Cell<bool> = Cell::new(false);
+}
+
+pub fn register_source(file: &str, source: &str)
+{
+    SOURCES.with(|sources| sources.borrow_mut().insert(file.into(), Rc::new(source.into())));
+}
+
+fn source_of(file: &str) -> Option<Rc<String>>
+{
+    if let Some(source) = SOURCES.with(|sources| sources.borrow().get(file).cloned()) {
+        return Some(source);
     }
 
-    let prefix = "| ";
-    println!("{}: {}", span, msg);
-    if let Ok(file) = File::open(&span.file) {
-        let start_line = if span.start.line >= 4 {span.start.line - 4} else {0};
-        let reader = io::BufReader::new(file);
+    let mut source = String::new();
+    File::open(file).and_then(|mut f| f.read_to_string(&mut source)).ok()?;
+    Some(Rc::new(source))
+}
+
+// Auto only uses colors when stdout is a terminal
+pub fn set_color_mode(mode: ColorMode)
+{
+    let color = match mode
+    {
+        ColorMode::Auto => unsafe {isatty(STDOUT_FILENO) != 0},
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+    };
+    USE_COLOR.with(|c| c.set(color));
+}
 
-        for (idx, line) in reader.lines().enumerate().skip(start_line)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Severity
+{
+    Error,
+    Warning,
+}
+
+impl Severity
+{
+    fn label(&self) -> &'static str
+    {
+        match *self
         {
-            let line = line.unwrap();
-            let line_idx = idx + 1;
-            println!("{:>4} {}{}", line_idx, prefix, line);
-            if line_idx == span.start.line
-            {
-                let end = if line_idx == span.end.line {span.end.offset} else {line.len()};
-                let carets = repeat_string("^", end - span.start.offset + 1);
-                let whitespace = repeat_string(" ", span.start.offset - 1);
-                println!("     {}{}{}", prefix, whitespace, carets);
-            }
-            else if line_idx == span.end.line
-            {
-                let carets = repeat_string("^", span.end.offset);
-                println!("     {}{}", prefix, carets);
-            }
-            else if line_idx > span.start.line && line_idx < span.end.line && !line.is_empty()
-            {
-                let carets = repeat_string("^", line.len());
-                println!("     {}{}", prefix, carets);
-            }
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
 
-            if line_idx >= span.end.line + 3 {break;}
+    fn color(&self) -> &'static str
+    {
+        match *self
+        {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
         }
     }
 }
 
+const BOLD: &str = "\x1b[1m";
+const GUTTER: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+struct Style
+{
+    color: bool,
+}
+
+impl Style
+{
+    fn paint(&self, style: &str, text: &str) -> String
+    {
+        if self.color {format!("{}{}{}", style, text, RESET)} else {text.into()}
+    }
+}
+
+// Spaces up to a column of line, tabs are kept so the carets line up with the source
+fn indentation(line: &str, column: usize) -> String
+{
+    line.chars().chain(repeat(' ')).take(column).map(|c| if c == '\t' {'\t'} else {' '}).collect()
+}
+
+// The message followed by the lines around the span, with the span underlined with carets
+fn render_message(severity: Severity, msg: &str, span: &Span, source: Option<&str>, color: bool) -> String
+{
+    let style = Style{color: color};
+    let mut out = format!("{}: {}: {}\n",
+        style.paint(BOLD, &span.to_string()),
+        style.paint(severity.color(), severity.label()),
+        style.paint(BOLD, msg));
+
+    let source = match source {
+        Some(source) => source,
+        None => return out,
+    };
+
+    let gutter = style.paint(GUTTER, "|");
+    let start_line = if span.start.line >= 4 {span.start.line - 4} else {0};
+    for (idx, line) in source.lines().enumerate().skip(start_line)
+    {
+        let line_idx = idx + 1;
+        out.push_str(&format!("{} {} {}\n", style.paint(GUTTER, &format!("{:>4}", line_idx)), gutter, line));
+
+        let underline = if line_idx == span.start.line
+        {
+            let start = if span.start.offset > 0 {span.start.offset - 1} else {0};
+            let end = if line_idx == span.end.line {span.end.offset} else {line.chars().count()};
+            Some((start, cmp::max(end, start + 1) - start))
+        }
+        else if line_idx == span.end.line
+        {
+            Some((0, span.end.offset))
+        }
+        else if line_idx > span.start.line && line_idx < span.end.line && !line.is_empty()
+        {
+            Some((0, line.chars().count()))
+        }
+        else
+        {
+            None
+        };
+
+        if let Some((start, len)) = underline {
+            let carets: String = repeat('^').take(len).collect();
+            out.push_str(&format!("     {} {}{}\n", gutter, indentation(line, start), style.paint(severity.color(), &carets)));
+        }
+
+        if line_idx >= span.end.line + 3 {break;}
+    }
+    out
+}
+
+fn print_with_severity(severity: Severity, msg: &str, span: &Span)
+{
+    let source = source_of(&span.file);
+    let color = USE_COLOR.with(|c| c.get());
+    print!("{}", render_message(severity, msg, span, source.as_ref().map(|s| s.as_str()), color));
+}
+
+pub fn print_message(msg: &str, span: &Span)
+{
+    print_with_severity(Severity::Error, msg, span)
+}
+
+pub fn print_warning(msg: &str, span: &Span)
+{
+    print_with_severity(Severity::Warning, msg, span)
+}
+
 pub type CompileResult<T> = Result<T, CompileError>;
 
+/*
+Errors collected during a compilation stage, so the stage can continue after an error, for example
+with the next function, and all errors are reported at the end.
+*/
+#[derive(Debug, Default)]
+pub struct Diagnostics
+{
+    errors: Vec<CompileError>,
+}
+
+impl Diagnostics
+{
+    pub fn new() -> Diagnostics
+    {
+        Diagnostics{
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, e: CompileError)
+    {
+        self.errors.extend(e.into_errors());
+    }
+
+    // Record the error of a result, the value is returned if there is no error
+    pub fn check<T>(&mut self, r: CompileResult<T>) -> Option<T>
+    {
+        match r {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.add(e);
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.errors.is_empty()
+    }
+
+    pub fn into_result(mut self) -> CompileResult<()>
+    {
+        match self.errors.len() {
+            0 => Ok(()),
+            1 => Err(self.errors.remove(0)),
+            _ => Err(CompileError::Many(self.errors)),
+        }
+    }
+}
+
 pub fn parse_error_result<T, Msg: Into<String>>(span: &Span, msg: Msg) -> CompileResult<T>
 {
     Err(CompileError::Parse(ErrorData::new(span, msg.into())))
@@ -153,14 +391,14 @@ pub fn type_error<Msg: Into<String>>(span: &Span, msg: Msg) -> CompileError
     CompileError::Type(ErrorData::new(span, msg))
 }
 
-pub fn unknown_name<Msg: Into<String>>(span: &Span, msg: Msg) -> CompileError
+pub fn unknown_name<Msg: Into<String>>(span: &Span, name: &str, msg: Msg) -> CompileError
 {
-    CompileError::UnknownName(ErrorData::new(span, msg))
+    CompileError::UnknownName(ErrorData::new(span, msg), name.into())
 }
 
-pub fn unknown_name_result<T, Msg: Into<String>>(span: &Span, msg: Msg) -> CompileResult<T>
+pub fn unknown_name_result<T, Msg: Into<String>>(span: &Span, name: &str, msg: Msg) -> CompileResult<T>
 {
-    Err(CompileError::UnknownName(ErrorData::new(span, msg)))
+    Err(CompileError::UnknownName(ErrorData::new(span, msg), name.into()))
 }
 
 pub fn unknown_type_result<T>(name: &str, typ: &Type) -> CompileResult<T>
@@ -184,3 +422,37 @@ impl From<String> for CompileError
     }
 }
 
+
+#[cfg(test)]
+mod tests
+{
+    use super::{render_message, Severity, CompileError, ErrorData};
+    use span::{Span, Pos};
+
+    #[test]
+    fn test_render_message()
+    {
+        let source = "fn main() -> int:\n\tlet x = foo\n    x\n";
+        let span = Span::new("test.mhr", Pos::new(2, 10), Pos::new(2, 12));
+        assert_eq!(render_message(Severity::Error, "Unknown name foo", &span, Some(source), false),
+            "test.mhr:2:10 -> 2:12: error: Unknown name foo\n   1 | fn main() -> int:\n   2 | \tlet x = foo\n     | \t        ^^^\n   3 |     x\n");
+
+        let colored = render_message(Severity::Warning, "binding x is never used", &span, Some(source), true);
+        assert!(colored.contains("\x1b[1;33mwarning\x1b[0m"));
+        assert!(colored.contains("\x1b[1;33m^^^\x1b[0m"));
+    }
+
+    #[test]
+    fn test_sorted_errors()
+    {
+        let error = |file: &str, line: usize| CompileError::Type(ErrorData::new(&Span::new(file, Pos::new(line, 1), Pos::new(line, 4)), "error"));
+        let e = CompileError::Many(vec![
+            CompileError::Many(vec![error("b.mhr", 3), error("b.mhr", 1)]),
+            error("a.mhr", 7),
+            CompileError::Other("other".into()),
+        ]);
+
+        let lines: Vec<String> = e.sorted_errors().iter().take(3).map(|e| e.to_string()).collect();
+        assert_eq!(lines, vec!["other\n", "a.mhr:7:1 -> 7:4: error\n", "b.mhr:1:1 -> 1:4: error\n"]);
+    }
+}
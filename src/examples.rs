@@ -0,0 +1,136 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use ast::RANDOM_SEED_VARIABLE;
+use compileerror::{CompileResult, CompileError};
+use llvmbackend::OutputType;
+use packagebuild::{PackageData, BuildOptions};
+
+/*
+Golden output tests: every program in a directory is built and run, and its standard output is
+compared with the <name>.expected file next to it. Programs without an expected file are skipped.
+
+All programs run with the same seed for @random, which is printed, so a failure depending on random
+data can be reproduced with --seed.
+*/
+
+enum ExampleResult
+{
+    Passed,
+    Failed(String),
+    Skipped,
+}
+
+// Describe the first difference between the expected and actual output
+fn compare_output(expected: &str, actual: &str) -> Option<String>
+{
+    if expected == actual {
+        return None;
+    }
+
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    for idx in 0..expected_lines.len().max(actual_lines.len()) {
+        match (expected_lines.get(idx), actual_lines.get(idx)) {
+            (Some(e), Some(a)) if e == a => continue,
+            (Some(e), Some(a)) => return Some(format!("line {}: expected `{}`, got `{}`", idx + 1, e, a)),
+            (Some(e), None) => return Some(format!("line {}: expected `{}`, got end of output", idx + 1, e)),
+            (None, Some(a)) => return Some(format!("line {}: expected end of output, got `{}`", idx + 1, a)),
+            (None, None) => break,
+        }
+    }
+
+    Some("output differs in trailing newlines".into())
+}
+
+fn read_file(path: &Path) -> CompileResult<String>
+{
+    let mut data = String::new();
+    File::open(path)?.read_to_string(&mut data)?;
+    Ok(data)
+}
+
+fn run_example(path: &Path, build_options: &mut BuildOptions, seed: u64) -> CompileResult<ExampleResult>
+{
+    let expected_path = path.with_extension("expected");
+    if !expected_path.exists() {
+        return Ok(ExampleResult::Skipped);
+    }
+
+    let expected = read_file(&expected_path)?;
+    let pkg = PackageData::single_file(path, OutputType::Binary)?;
+    let outputs = match pkg.build(build_options) {
+        Ok(outputs) => outputs,
+        Err(e) => return Ok(ExampleResult::Failed(format!("build failed: {}", e))),
+    };
+
+    let output = Command::new(&outputs[0])
+        .env(RANDOM_SEED_VARIABLE, seed.to_string())
+        .output()
+        .map_err(|e| CompileError::Other(format!("Unable to run {}: {}", outputs[0].to_string_lossy(), e)))?;
+    if output.status.code().is_none() {
+        return Ok(ExampleResult::Failed(format!("program crashed: {}", output.status)));
+    }
+
+    let actual = String::from_utf8_lossy(&output.stdout);
+    match compare_output(&expected, &actual) {
+        Some(diff) => Ok(ExampleResult::Failed(diff)),
+        None => Ok(ExampleResult::Passed),
+    }
+}
+
+// Run all examples in dir, returns the number of failed examples
+pub fn run_examples(dir: &Path, build_options: &mut BuildOptions, seed: u64) -> CompileResult<usize>
+{
+    let mut programs: Vec<PathBuf> = dir.read_dir()
+        .map_err(|e| CompileError::Other(format!("Cannot read directory {}: {}", dir.to_string_lossy(), e)))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map(|ext| ext == "mhr").unwrap_or(false))
+        .collect();
+    programs.sort();
+
+    println!("random seed: {}", seed);
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in &programs {
+        let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        match run_example(path, build_options, seed)? {
+            ExampleResult::Passed => {
+                println!("example {} ... ok", name);
+                passed += 1;
+            },
+            ExampleResult::Failed(msg) => {
+                println!("example {} ... FAILED: {}", name, msg);
+                failed += 1;
+            },
+            ExampleResult::Skipped => {
+                println!("example {} ... skipped, no {}.expected file", name, name);
+                skipped += 1;
+            },
+        }
+    }
+
+    let _ = fs::remove_dir_all(&build_options.build_directory);
+    println!("\nExamples: {} passed, {} failed, {} skipped", passed, failed, skipped);
+    if failed > 0 {
+        println!("To run with the same random seed, pass --seed {}", seed);
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::compare_output;
+
+    #[test]
+    fn test_compare_output()
+    {
+        assert_eq!(compare_output("a\nb\n", "a\nb\n"), None);
+        assert_eq!(compare_output("a\nb\n", "a\nc\n"), Some("line 2: expected `b`, got `c`".into()));
+        assert_eq!(compare_output("a\nb\n", "a\n"), Some("line 2: expected `b`, got end of output".into()));
+        assert_eq!(compare_output("a\n", "a\nb\n"), Some("line 2: expected end of output, got `b`".into()));
+        assert_eq!(compare_output("a\n", "a"), Some("output differs in trailing newlines".into()));
+    }
+}
@@ -1,4 +1,5 @@
 use std::rc::Rc;
+use std::collections::HashMap;
 use std::io;
 use std::fmt;
 use ast::{Import};
@@ -12,6 +13,8 @@ pub struct ExportLibrary
     pub name: String,
     pub imports: Vec<Rc<Import>>,
     pub output_type: OutputType,
+    // ABI hashes of the modules from other libraries, at the time this library was built
+    pub dependencies: HashMap<String, u64>,
 }
 
 impl ExportLibrary
@@ -22,9 +25,27 @@ impl ExportLibrary
             name: pkg.name.clone(),
             output_type,
             imports: pkg.import_data.imports.values().cloned().collect(),
+            dependencies: pkg.import_data.libraries.iter()
+                .flat_map(|lib| lib.imports.iter())
+                .map(|import| (import.namespace.clone(), import.abi_hash))
+                .collect(),
         }
     }
 
+    pub fn check_compatibility(&self, other: &ExportLibrary) -> Result<(), String>
+    {
+        for import in &other.imports {
+            match self.dependencies.get(&import.namespace) {
+                Some(hash) if *hash != import.abi_hash => {
+                    return Err(format!("Library {} was compiled against an older interface of module {}, rebuild {}",
+                        self.name, import.namespace, self.name));
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
     pub fn load<R: io::Read>(reader: &mut R) -> Result<ExportLibrary, String>
     {
         bincode::deserialize_from(reader, bincode::Infinite)
@@ -55,6 +76,9 @@ impl fmt::Display for ExportLibrary
     {
         writeln!(f, "Name: {}", self.name)?;
         writeln!(f, "Type: {}", self.output_type)?;
+        for (name, hash) in &self.dependencies {
+            writeln!(f, "Depends on: {} (ABI hash {:016x})", name, hash)?;
+        }
         for import in &self.imports {
             write!(f, "{}", import)?;
         }
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use ast::*;
+use json::json_string;
+use package::Package;
+use span::Pos;
+use super::symbols::{SymbolIndex, SymbolKind};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Completion
+{
+    pub label: String,
+    pub kind: SymbolKind,
+    pub detail: String,
+}
+
+// What is in front of the cursor
+#[derive(Debug, Eq, PartialEq)]
+enum CompletionContext
+{
+    Scope,
+    // name. with the position of the last character of name
+    Member(Pos),
+    // a::b::
+    Namespace(String),
+}
+
+fn is_identifier_char(c: char) -> bool
+{
+    c.is_alphanumeric() || c == '_'
+}
+
+// Determine the completion context, and the part of the identifier which is already typed
+fn completion_context(source: &str, pos: Pos) -> (CompletionContext, String)
+{
+    let line: Vec<char> = match source.lines().nth(pos.line.saturating_sub(1)) {
+        Some(line) => line.chars().take(pos.offset.saturating_sub(1)).collect(),
+        None => return (CompletionContext::Scope, String::new()),
+    };
+
+    let partial_start = line.iter().rposition(|c| !is_identifier_char(*c)).map(|idx| idx + 1).unwrap_or(0);
+    let partial: String = line[partial_start..].iter().collect();
+    let before = &line[..partial_start];
+
+    if before.ends_with(&['.']) && partial_start >= 2 && is_identifier_char(before[partial_start - 2]) {
+        (CompletionContext::Member(Pos::new(pos.line, partial_start - 1)), partial)
+    } else if before.ends_with(&[':', ':']) {
+        let path_end = partial_start - 2;
+        let path_start = before[..path_end].iter()
+            .rposition(|c| !is_identifier_char(*c) && *c != ':')
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        (CompletionContext::Namespace(before[path_start..path_end].iter().collect()), partial)
+    } else {
+        (CompletionContext::Scope, partial)
+    }
+}
+
+fn short_name(name: &str) -> &str
+{
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn signature_detail(sig: &FunctionSignature) -> String
+{
+    let args: Vec<String> = sig.args.iter().map(|arg| format!("{}: {}", arg.name, arg.typ)).collect();
+    format!("fn {}({}) -> {}", short_name(&sig.name), args.join(", "), sig.return_type)
+}
+
+fn signatures(pkg: &Package) -> HashMap<&str, &FunctionSignature>
+{
+    let mut sigs = HashMap::new();
+    for md in pkg.modules.values() {
+        for f in md.functions.values() {
+            sigs.insert(&f.sig.name[..], &f.sig);
+        }
+
+        for f in md.externals.values() {
+            sigs.insert(&f.sig.name[..], &f.sig);
+        }
+    }
+    sigs
+}
+
+fn member_completions(pkg: &Package, typ: &Type, int_size: IntSize) -> Vec<Completion>
+{
+    let member = |name: &str, typ: &Type| Completion{label: name.into(), kind: SymbolKind::Member, detail: typ.to_string()};
+    match *typ
+    {
+        Type::Pointer(ref inner) => member_completions(pkg, inner.deref(), int_size),
+        Type::Array(_) => vec![member("len", &Type::UInt(int_size))],
+        Type::Slice(ref st) => vec![member("len", &Type::UInt(int_size)), member("data", &ptr_type(st.element_type.clone()))],
+        Type::String => vec![member("len", &Type::UInt(int_size)), member("data", &ptr_type(Type::Char))],
+        Type::Struct(ref st) |
+        Type::Union(ref st) => {
+            let mut completions: Vec<Completion> = st.members.iter().map(|m| member(&m.name, &m.typ)).collect();
+
+            // Member functions are named Type.function
+            let prefix = format!("{}.", st.name);
+            for sig in signatures(pkg).values() {
+                if sig.name.starts_with(&prefix) {
+                    completions.push(Completion{
+                        label: sig.name[prefix.len()..].into(),
+                        kind: SymbolKind::Function,
+                        detail: signature_detail(sig),
+                    });
+                }
+            }
+            completions
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn detail(sigs: &HashMap<&str, &FunctionSignature>, name: &str, typ: &Type) -> String
+{
+    sigs.get(name).map(|sig| signature_detail(sig)).unwrap_or_else(|| typ.to_string())
+}
+
+fn scope_completions(pkg: &Package, index: &SymbolIndex, file: &str, pos: Pos) -> Vec<Completion>
+{
+    let sigs = signatures(pkg);
+    let mut completions: HashMap<String, Completion> = HashMap::new();
+    for o in index.occurrences.iter().filter(|o| o.is_definition() && !o.name.contains('<') && !o.name.contains('.')) {
+        let visible = match o.scope {
+            None => true,
+            // Locals are only visible after their definition
+            Some(ref scope) => scope.file == file && scope.contains(pos) && o.span.start < pos,
+        };
+
+        if visible {
+            let label = short_name(&o.name).to_string();
+            completions.insert(label.clone(), Completion{
+                label: label,
+                kind: o.kind,
+                detail: detail(&sigs, &o.name, &o.typ),
+            });
+        }
+    }
+    completions.into_iter().map(|(_, c)| c).collect()
+}
+
+fn namespace_completions(pkg: &Package, index: &SymbolIndex, path: &str) -> Vec<Completion>
+{
+    let sigs = signatures(pkg);
+    let suffix = format!("::{}", path);
+    index.occurrences.iter()
+        .filter(|o| o.is_definition() && o.scope.is_none() && !o.name.contains('<') && !o.name.contains('.'))
+        .filter(|o| match o.name.rfind("::") {
+            Some(idx) => &o.name[..idx] == path || o.name[..idx].ends_with(&suffix),
+            None => false,
+        })
+        .map(|o| Completion{
+            label: short_name(&o.name).into(),
+            kind: o.kind,
+            detail: detail(&sigs, &o.name, &o.typ),
+        })
+        .collect()
+}
+
+// Complete the identifier at pos, using the last type checked state of the package
+pub fn complete(pkg: &Package, index: &SymbolIndex, source: &str, file: &str, pos: Pos, int_size: IntSize) -> Vec<Completion>
+{
+    let (context, partial) = completion_context(source, pos);
+    let mut completions = match context
+    {
+        CompletionContext::Scope => scope_completions(pkg, index, file, pos),
+        CompletionContext::Namespace(ref path) => namespace_completions(pkg, index, path),
+        CompletionContext::Member(receiver_pos) => {
+            index.occurrence_at(file, receiver_pos)
+                .map(|o| member_completions(pkg, &o.typ, int_size))
+                .unwrap_or_else(Vec::new)
+        },
+    };
+
+    completions.retain(|c| c.label.starts_with(&partial));
+    completions.sort_by(|a, b| a.label.cmp(&b.label));
+    completions.dedup();
+    completions
+}
+
+pub fn completions_to_json(completions: &[Completion]) -> String
+{
+    let items: Vec<String> = completions.iter()
+        .map(|c| format!(r#"  {{"label": {}, "kind": "{}", "detail": {}}}"#, json_string(&c.label), c.kind, json_string(&c.detail)))
+        .collect();
+    format!("[\n{}\n]\n", items.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{complete, completion_context, CompletionContext};
+    use ide::symbols::{SymbolIndex, SymbolKind};
+    use ast::IntSize;
+    use parser::parse_str;
+    use span::Pos;
+    use target::Target;
+
+    const PROG: &'static str = r#"
+struct Point:
+    x: int
+    y: int
+
+fn distance(p: Point) -> int:
+    let dx = p.x
+    dx + p.y
+
+fn main() -> int: distance(Point{1, 2})
+"#;
+
+    #[test]
+    fn test_completion_context()
+    {
+        assert_eq!(completion_context("    foo.ba", Pos::new(1, 11)), (CompletionContext::Member(Pos::new(1, 7)), "ba".into()));
+        assert_eq!(completion_context("a::b::c", Pos::new(1, 8)), (CompletionContext::Namespace("a::b".into()), "c".into()));
+        assert_eq!(completion_context("let x = y", Pos::new(1, 10)), (CompletionContext::Scope, "y".into()));
+    }
+
+    #[test]
+    fn test_complete()
+    {
+        let target = Target::new(IntSize::I32, "");
+        let mut pkg = parse_str(PROG, "test", &target).expect("Parsing failed");
+        pkg.type_check(&target).expect("Type check failed");
+        let index = SymbolIndex::new(&pkg);
+
+        // p.y on line 8
+        let members = complete(&pkg, &index, PROG, "", Pos::new(8, 13), target.int_size);
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].label, "y");
+        assert_eq!(members[0].kind, SymbolKind::Member);
+
+        // dx on line 8
+        let names = complete(&pkg, &index, PROG, "", Pos::new(8, 6), target.int_size);
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[0].label, "distance");
+        assert!(names[0].detail.starts_with("fn distance(p: "));
+        assert_eq!(names[1].label, "dx");
+    }
+}
@@ -0,0 +1,152 @@
+use ast::*;
+use json::{json_string, json_span};
+use package::Package;
+use span::{Span, Pos};
+
+// The type of the innermost expression at a position
+#[derive(Debug)]
+pub struct Hover
+{
+    pub span: Span,
+    pub name: Option<String>,
+    pub typ: Type,
+    // For calls to generic functions, the types the generic arguments were resolved to
+    pub generic_args: Vec<(Type, Type)>,
+}
+
+impl Hover
+{
+    fn new(e: &Expression, int_size: IntSize) -> Hover
+    {
+        let mut hover = Hover{
+            span: e.span(),
+            name: None,
+            typ: e.get_type(int_size),
+            generic_args: Vec::new(),
+        };
+
+        match *e
+        {
+            Expression::NameRef(ref nr) => hover.name = Some(nr.name.clone()),
+            Expression::Call(ref c) => {
+                let name = c.callee.name.split('<').next().unwrap_or(&c.callee.name);
+                hover.name = Some(name.into());
+                hover.generic_args = c.generic_args.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                hover.generic_args.sort_by_key(|&(ref k, _)| k.to_string());
+            },
+            _ => (),
+        }
+
+        hover
+    }
+
+    pub fn to_json(&self) -> String
+    {
+        let generic_args: Vec<String> = self.generic_args.iter()
+            .map(|&(ref k, ref v)| format!(r#"{{"param": {}, "type": {}}}"#, json_string(&k.to_string()), json_string(&v.to_string())))
+            .collect();
+
+        format!("{{\"span\": {}, \"name\": {}, \"type\": {}, \"generic_args\": [{}]}}\n",
+            json_span(&self.span),
+            self.name.as_ref().map(|n| json_string(n)).unwrap_or_else(|| "null".into()),
+            json_string(&self.typ.to_string()),
+            generic_args.join(", "))
+    }
+}
+
+struct HoverFinder<'a>
+{
+    file: &'a str,
+    pos: Pos,
+    int_size: IntSize,
+    found: Option<Hover>,
+}
+
+impl<'a> HoverFinder<'a>
+{
+    fn contains(&self, span: &Span) -> bool
+    {
+        span.file == self.file && span.contains(self.pos)
+    }
+}
+
+impl<'a> Visitor for HoverFinder<'a>
+{
+    type Error = ();
+
+    fn visit_global(&mut self, g: &GlobalBinding) -> Result<(), ()>
+    {
+        if self.contains(&g.span) {
+            walk_global(self, g)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn visit_function(&mut self, f: &Function) -> Result<(), ()>
+    {
+        // Instantiations of generic functions have the same spans as the generic function
+        if self.contains(&f.span) && !f.sig.name.contains('<') {
+            walk_function(self, f)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn visit_expression(&mut self, e: &Expression) -> Result<(), ()>
+    {
+        if self.contains(&e.span()) {
+            self.found = Some(Hover::new(e, self.int_size));
+        }
+        walk_expression(self, e)
+    }
+}
+
+pub fn hover(pkg: &Package, file: &str, pos: Pos, int_size: IntSize) -> Option<Hover>
+{
+    let mut finder = HoverFinder{
+        file: file,
+        pos: pos,
+        int_size: int_size,
+        found: None,
+    };
+
+    for md in pkg.modules.values() {
+        let _ = finder.visit_module(md);
+    }
+    finder.found
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::hover;
+    use ast::{Type, IntSize};
+    use parser::parse_str;
+    use span::Pos;
+    use target::Target;
+
+    #[test]
+    fn test_hover_generic_call()
+    {
+        let target = Target::new(IntSize::I32, "");
+        let mut pkg = parse_str(r#"
+fn id(x: $a) -> $a: x
+
+fn main() -> int:
+    let v = 7
+    id(v)
+"#, "test", &target).expect("Parsing failed");
+        pkg.type_check(&target).expect("Type check failed");
+
+        let h = hover(&pkg, "", Pos::new(6, 5), target.int_size).expect("Nothing found");
+        assert_eq!(h.name, Some("test::id".into()));
+        assert_eq!(h.typ, Type::Int(IntSize::I32));
+        assert_eq!(h.generic_args.len(), 1);
+        assert_eq!(h.generic_args[0].1, Type::Int(IntSize::I32));
+
+        let h = hover(&pkg, "", Pos::new(6, 8), target.int_size).expect("Nothing found");
+        assert_eq!(h.name, Some("v".into()));
+        assert_eq!(h.typ, Type::Int(IntSize::I32));
+    }
+}
@@ -0,0 +1,26 @@
+/*
+Queries on the source code, for editor integration.
+*/
+mod completion;
+mod hover;
+mod references;
+mod symbols;
+mod workspace;
+
+pub use self::completion::*;
+pub use self::workspace::*;
+
+use compileerror::{CompileResult, CompileError};
+use span::Pos;
+
+// Parse a position in the line:offset format
+pub fn parse_position(s: &str) -> CompileResult<Pos>
+{
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 2 {
+        return Err(CompileError::Other(format!("Invalid position {}, expecting line:offset", s)));
+    }
+
+    let number = |p: &str| p.parse::<usize>().map_err(|_| CompileError::Other(format!("Invalid position {}, expecting line:offset", s)));
+    Ok(Pos::new(number(parts[0])?, number(parts[1])?))
+}
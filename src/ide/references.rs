@@ -0,0 +1,239 @@
+use compileerror::{CompileResult, CompileError};
+use span::{Span, Pos};
+use super::symbols::{SymbolIndex, SymbolOccurrence, SymbolKind};
+
+// Replace the text at span with new_text
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TextEdit
+{
+    pub span: Span,
+    pub new_text: String,
+}
+
+fn short_name(name: &str) -> &str
+{
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn namespace(name: &str) -> &str
+{
+    match name.rfind("::") {
+        Some(idx) => &name[..idx],
+        None => "",
+    }
+}
+
+fn is_module_level(kind: SymbolKind) -> bool
+{
+    match kind
+    {
+        SymbolKind::Function |
+        SymbolKind::External |
+        SymbolKind::Global |
+        SymbolKind::Type => true,
+        _ => false,
+    }
+}
+
+fn is_valid_identifier(name: &str) -> bool
+{
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+fn is_identifier_char(c: Option<&char>) -> bool
+{
+    c.map(|c| c.is_alphanumeric() || *c == '_').unwrap_or(false)
+}
+
+/*
+Spans of declarations cover the whole declaration, so look up where exactly the identifier is.
+Offsets are 1 based and the end of a span is inclusive, like the spans of the lexer.
+*/
+fn identifier_span(source: &str, span: &Span, ident: &str) -> Option<Span>
+{
+    let ident: Vec<char> = ident.chars().collect();
+    for (idx, line) in source.lines().enumerate() {
+        let line_nr = idx + 1;
+        if line_nr < span.start.line || line_nr > span.end.line {
+            continue;
+        }
+
+        let line: Vec<char> = line.chars().collect();
+        if line.len() < ident.len() {
+            continue;
+        }
+
+        for start in 0..(line.len() - ident.len() + 1) {
+            let end = start + ident.len();
+            let first = Pos::new(line_nr, start + 1);
+            let last = Pos::new(line_nr, end);
+            if line[start..end] == ident[..] &&
+                span.contains(first) && span.contains(last) &&
+                (start == 0 || !is_identifier_char(line.get(start - 1))) &&
+                !is_identifier_char(line.get(end))
+            {
+                return Some(Span::new(&span.file, first, last));
+            }
+        }
+    }
+
+    None
+}
+
+impl SymbolIndex
+{
+    // The innermost identifier at a position
+    pub fn occurrence_at(&self, file: &str, pos: Pos) -> Option<&SymbolOccurrence>
+    {
+        self.occurrences.iter()
+            .filter(|o| o.span.file == file && o.span.contains(pos))
+            .max_by_key(|o| (o.span.start, -(o.span.end.line as isize), -(o.span.end.offset as isize)))
+    }
+
+    // All occurrences referring to the same thing as occ, including its definition
+    pub fn references(&self, occ: &SymbolOccurrence) -> Vec<&SymbolOccurrence>
+    {
+        if occ.definition.is_none() {
+            return self.occurrences.iter().filter(|o| o.definition.is_none() && o.name == occ.name).collect();
+        }
+
+        self.occurrences.iter()
+            .filter(|o| o.definition == occ.definition && o.kind == occ.kind)
+            .collect()
+    }
+
+    fn check_rename_collisions(&self, occ: &SymbolOccurrence, references: &[&SymbolOccurrence], new_name: &str) -> CompileResult<()>
+    {
+        let collision = |o: &SymbolOccurrence| {
+            Err(CompileError::Other(format!("Cannot rename {} to {}, {} is already defined at {}", occ.name, new_name, new_name, o.span)))
+        };
+
+        if is_module_level(occ.kind) {
+            let new_full_name = if namespace(&occ.name).is_empty() {
+                new_name.to_string()
+            } else {
+                format!("{}::{}", namespace(&occ.name), new_name)
+            };
+
+            for o in &self.occurrences {
+                if o.is_definition() && is_module_level(o.kind) && o.name == new_full_name {
+                    return collision(o);
+                }
+            }
+        }
+
+        // A local with the new name, would hide the renamed symbol or be hidden by it
+        for r in references {
+            for o in &self.occurrences {
+                if o.is_definition() && o.scope.is_some() && o.scope == r.scope && short_name(&o.name) == new_name {
+                    return collision(o);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Rename the thing occ refers to everywhere it is used
+    pub fn rename<F>(&self, occ: &SymbolOccurrence, new_name: &str, read_source: F) -> CompileResult<Vec<TextEdit>>
+        where F: Fn(&str) -> CompileResult<String>
+    {
+        if !is_valid_identifier(new_name) {
+            return Err(CompileError::Other(format!("{} is not a valid identifier", new_name)));
+        }
+
+        if occ.definition.is_none() {
+            return Err(CompileError::Other(format!("Cannot rename {}, it is not defined in this package", occ.name)));
+        }
+
+        let references = self.references(occ);
+        self.check_rename_collisions(occ, &references, new_name)?;
+
+        let old_name = short_name(&occ.name);
+        let mut edits = Vec::new();
+        for r in references {
+            let source = read_source(&r.span.file)?;
+            let span = identifier_span(&source, &r.span, old_name)
+                .ok_or_else(|| CompileError::Other(format!("Cannot find {} at {}", old_name, r.span)))?;
+            let edit = TextEdit{span: span, new_text: new_name.into()};
+            if !edits.contains(&edit) {
+                edits.push(edit);
+            }
+        }
+
+        Ok(edits)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{TextEdit, identifier_span};
+    use ide::symbols::SymbolIndex;
+    use ast::IntSize;
+    use parser::parse_str;
+    use span::{Span, Pos};
+    use target::Target;
+
+    const PROG: &'static str = r#"
+fn add(a: int, b: int) -> int:
+    let c = a + b
+    c * 2
+
+fn main() -> int:
+    let b = 5
+    add(b, 7)
+"#;
+
+    fn index(prog: &str) -> SymbolIndex
+    {
+        let target = Target::new(IntSize::I32, "");
+        let mut pkg = parse_str(prog, "test", &target).expect("Parsing failed");
+        pkg.type_check(&target).expect("Type check failed");
+        SymbolIndex::new(&pkg)
+    }
+
+    #[test]
+    fn test_identifier_span()
+    {
+        let span = Span::new("", Pos::new(3, 5), Pos::new(3, 17));
+        assert_eq!(identifier_span(PROG, &span, "c"), Some(Span::new("", Pos::new(3, 9), Pos::new(3, 9))));
+        assert_eq!(identifier_span(PROG, &span, "d"), None);
+    }
+
+    #[test]
+    fn test_references()
+    {
+        let idx = index(PROG);
+        let occ = idx.occurrence_at("", Pos::new(4, 5)).expect("No symbol found");
+        assert_eq!(occ.name, "c");
+        assert_eq!(idx.references(occ).len(), 2);
+
+        let occ = idx.occurrence_at("", Pos::new(8, 5)).expect("No symbol found");
+        assert_eq!(occ.name, "test::add");
+        assert_eq!(idx.references(occ).len(), 2);
+    }
+
+    #[test]
+    fn test_rename()
+    {
+        let idx = index(PROG);
+        let occ = idx.occurrence_at("", Pos::new(8, 5)).expect("No symbol found");
+        let edits = idx.rename(occ, "sum", |_| Ok(PROG.into())).expect("Rename failed");
+        assert_eq!(edits, vec![
+            TextEdit{span: Span::new("", Pos::new(2, 4), Pos::new(2, 6)), new_text: "sum".into()},
+            TextEdit{span: Span::new("", Pos::new(8, 5), Pos::new(8, 7)), new_text: "sum".into()},
+        ]);
+
+        // c is already defined in add
+        let occ = idx.occurrence_at("", Pos::new(3, 13)).expect("No symbol found");
+        assert_eq!(occ.name, "a");
+        assert!(idx.rename(occ, "c", |_| Ok(PROG.into())).is_err());
+        assert!(idx.rename(occ, "x", |_| Ok(PROG.into())).is_ok());
+        assert!(idx.rename(occ, "1x", |_| Ok(PROG.into())).is_err());
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use std::fmt;
+use ast::*;
+use json::{json_string, json_span};
+use package::Package;
+use span::Span;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SymbolKind
+{
+    Function,
+    External,
+    Global,
+    Argument,
+    Local,
+    Type,
+    GenericParam,
+    Member,
+}
+
+impl fmt::Display for SymbolKind
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error>
+    {
+        match *self
+        {
+            SymbolKind::Function => write!(f, "function"),
+            SymbolKind::External => write!(f, "external"),
+            SymbolKind::Global => write!(f, "global"),
+            SymbolKind::Argument => write!(f, "argument"),
+            SymbolKind::Local => write!(f, "local"),
+            SymbolKind::Type => write!(f, "type"),
+            SymbolKind::GenericParam => write!(f, "generic"),
+            SymbolKind::Member => write!(f, "member"),
+        }
+    }
+}
+
+// An identifier in the source code, and where the thing it refers to is defined
+#[derive(Debug, Clone)]
+pub struct SymbolOccurrence
+{
+    pub name: String,
+    pub kind: SymbolKind,
+    pub typ: Type,
+    pub span: Span,
+    pub definition: Option<Span>,
+    // The function the occurrence is part of, None for module level declarations
+    pub scope: Option<Span>,
+}
+
+impl SymbolOccurrence
+{
+    pub fn is_definition(&self) -> bool
+    {
+        self.definition.as_ref() == Some(&self.span)
+    }
+}
+
+#[derive(Clone)]
+struct Definition
+{
+    kind: SymbolKind,
+    typ: Type,
+    span: Span,
+}
+
+pub struct SymbolIndex
+{
+    pub occurrences: Vec<SymbolOccurrence>,
+}
+
+struct IndexBuilder<'a>
+{
+    module_definitions: &'a HashMap<String, Definition>,
+    scopes: Vec<HashMap<String, Definition>>,
+    function: Option<Span>,
+    occurrences: Vec<SymbolOccurrence>,
+}
+
+// Collects the names of the generic arguments used in a type
+struct GenericParams
+{
+    names: Vec<String>,
+}
+
+impl Visitor for GenericParams
+{
+    type Error = ();
+
+    fn visit_type(&mut self, t: &Type) -> Result<(), ()>
+    {
+        if let Type::Generic(ref g) = *t {
+            if let GenericType::Any(ref name) = **g {
+                if !self.names.contains(name) {
+                    self.names.push(name.clone());
+                }
+            }
+        }
+        walk_type(self, t)
+    }
+}
+
+fn generic_params(typ: &Type) -> Vec<String>
+{
+    let mut gp = GenericParams{names: Vec::new()};
+    let _ = gp.visit_type(typ);
+    gp.names
+}
+
+// Instantiated generic functions are named foo<int>, they refer to foo
+fn strip_generic_args(name: &str) -> &str
+{
+    name.split('<').next().unwrap_or(name)
+}
+
+fn definition(kind: SymbolKind, typ: &Type, span: &Span) -> Definition
+{
+    Definition{
+        kind: kind,
+        typ: typ.clone(),
+        span: span.clone(),
+    }
+}
+
+fn module_definitions(pkg: &Package) -> HashMap<String, Definition>
+{
+    let mut definitions = HashMap::new();
+    for md in pkg.modules.values() {
+        for f in md.functions.values() {
+            definitions.insert(f.sig.name.clone(), definition(SymbolKind::Function, &f.sig.typ, &f.sig.span));
+        }
+
+        for f in md.externals.values() {
+            definitions.insert(f.sig.name.clone(), definition(SymbolKind::External, &f.sig.typ, &f.sig.span));
+        }
+
+        for g in md.globals.values() {
+            definitions.insert(g.name.clone(), definition(SymbolKind::Global, &g.typ, &g.span));
+        }
+
+        for (name, td) in &md.types {
+            definitions.insert(name.clone(), definition(SymbolKind::Type, &td.get_type(), &td.span()));
+            if let TypeDeclaration::Sum(ref st) = *td {
+                for c in &st.cases {
+                    definitions.insert(c.name.clone(), definition(SymbolKind::Type, &c.typ, &c.span));
+                }
+            }
+        }
+    }
+
+    definitions
+}
+
+impl<'a> IndexBuilder<'a>
+{
+    fn add_occurrence(&mut self, name: &str, def: &Definition, typ: &Type, span: &Span)
+    {
+        self.occurrences.push(SymbolOccurrence{
+            name: name.into(),
+            kind: def.kind,
+            typ: if typ.is_unknown() {def.typ.clone()} else {typ.clone()},
+            span: span.clone(),
+            definition: Some(def.span.clone()),
+            scope: self.function.clone(),
+        });
+    }
+
+    // A module level declaration
+    fn declare(&mut self, name: &str, kind: SymbolKind, typ: &Type, span: &Span)
+    {
+        let def = definition(kind, typ, span);
+        self.add_occurrence(name, &def, typ, span);
+    }
+
+    // A name which is only visible in the current scope
+    fn define(&mut self, name: &str, kind: SymbolKind, typ: &Type, span: &Span)
+    {
+        if name == "_" {
+            return;
+        }
+
+        let def = definition(kind, typ, span);
+        self.add_occurrence(name, &def, typ, span);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.into(), def);
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Definition>
+    {
+        for scope in self.scopes.iter().rev() {
+            if let Some(def) = scope.get(name) {
+                return Some(def.clone());
+            }
+        }
+
+        if let Some(def) = self.module_definitions.get(name) {
+            return Some(def.clone());
+        }
+
+        let name_with_double_colons = format!("::{}", name);
+        self.module_definitions.iter()
+            .find(|&(def_name, _)| def_name.ends_with(&name_with_double_colons))
+            .map(|(_, def)| def.clone())
+    }
+
+    fn reference(&mut self, name: &str, typ: &Type, span: &Span)
+    {
+        let name = strip_generic_args(name);
+        if name == "_" {
+            return;
+        }
+
+        match self.lookup(name)
+        {
+            Some(def) => self.add_occurrence(name, &def, typ, span),
+            None => {
+                // Imported from another package, so we don't know where it is defined
+                let kind = if let Type::Func(_) = *typ {SymbolKind::Function} else {SymbolKind::Global};
+                self.occurrences.push(SymbolOccurrence{
+                    name: name.into(),
+                    kind: kind,
+                    typ: typ.clone(),
+                    span: span.clone(),
+                    definition: None,
+                    scope: self.function.clone(),
+                });
+            }
+        }
+    }
+
+    fn function_signature(&mut self, sig: &FunctionSignature)
+    {
+        let mut generic_types: Vec<(Vec<String>, &Span)> = sig.args.iter()
+            .map(|arg| (generic_params(&arg.typ), &arg.span))
+            .collect();
+        generic_types.push((generic_params(&sig.return_type), &sig.span));
+
+        for (names, span) in generic_types {
+            for name in names {
+                match self.scopes.last().and_then(|scope| scope.get(&name)).cloned() {
+                    Some(def) => self.add_occurrence(&name, &def, &def.typ, span),
+                    None => self.define(&name, SymbolKind::GenericParam, &generic_type(&name), span),
+                }
+            }
+        }
+
+        for arg in &sig.args {
+            self.define(&arg.name, SymbolKind::Argument, &arg.typ, &arg.span);
+        }
+    }
+
+    fn struct_pattern(&mut self, p: &StructPattern)
+    {
+        self.reference(&p.name, &p.typ, &p.span);
+        for b in &p.bindings {
+            self.define(&b.name, SymbolKind::Local, &b.typ, &p.span);
+        }
+    }
+
+    fn tuple_pattern(&mut self, p: &TuplePattern)
+    {
+        let member_types: Vec<Type> = match p.typ {
+            Type::Struct(ref st) => st.members.iter().map(|m| m.typ.clone()).collect(),
+            _ => Vec::new(),
+        };
+
+        for (idx, e) in p.elements.iter().enumerate() {
+            match *e {
+                BindingType::Name(ref name) => {
+                    let typ = member_types.get(idx).cloned().unwrap_or(Type::Unknown);
+                    self.define(name, SymbolKind::Local, &typ, &p.span);
+                },
+                BindingType::Struct(ref s) => self.struct_pattern(s),
+                BindingType::Tuple(ref t) => self.tuple_pattern(t),
+            }
+        }
+    }
+
+    fn in_scope<F>(&mut self, f: F) -> Result<(), ()>
+        where F: FnOnce(&mut IndexBuilder<'a>) -> Result<(), ()>
+    {
+        self.scopes.push(HashMap::new());
+        let result = f(self);
+        self.scopes.pop();
+        result
+    }
+}
+
+impl<'a> Visitor for IndexBuilder<'a>
+{
+    type Error = ();
+
+    fn visit_type_declaration(&mut self, td: &TypeDeclaration) -> Result<(), ()>
+    {
+        let name = match *td
+        {
+            TypeDeclaration::Interface(ref i) => &i.name,
+            TypeDeclaration::Struct(ref sd) |
+            TypeDeclaration::Union(ref sd) => &sd.name,
+            TypeDeclaration::Sum(ref st) => {
+                for c in &st.cases {
+                    self.declare(&c.name, SymbolKind::Type, &c.typ, &c.span);
+                }
+                &st.name
+            },
+            TypeDeclaration::Opaque(ref ot) => &ot.name,
+        };
+
+        self.declare(name, SymbolKind::Type, &td.get_type(), &td.span());
+        Ok(())
+    }
+
+    fn visit_global(&mut self, g: &GlobalBinding) -> Result<(), ()>
+    {
+        self.declare(&g.name, SymbolKind::Global, &g.typ, &g.span);
+        self.visit_expression(&g.init)
+    }
+
+    fn visit_external_function(&mut self, f: &ExternalFunction) -> Result<(), ()>
+    {
+        self.declare(&f.sig.name, SymbolKind::External, &f.sig.typ, &f.sig.span);
+        self.function = Some(f.span.clone());
+        let result = self.in_scope(|b| {
+            b.function_signature(&f.sig);
+            Ok(())
+        });
+        self.function = None;
+        result
+    }
+
+    fn visit_function(&mut self, f: &Function) -> Result<(), ()>
+    {
+        if f.sig.name != strip_generic_args(&f.sig.name) {
+            return Ok(()); // Instantiations have the same source code as the generic function
+        }
+
+        self.declare(&f.sig.name, SymbolKind::Function, &f.sig.typ, &f.sig.span);
+        self.function = Some(f.span.clone());
+        let result = self.in_scope(|b| {
+            b.function_signature(&f.sig);
+            b.visit_expression(&f.expression)
+        });
+        self.function = None;
+        result
+    }
+
+    fn visit_expression(&mut self, e: &Expression) -> Result<(), ()>
+    {
+        match *e
+        {
+            Expression::Block(_) => self.in_scope(|b| walk_expression(b, e)),
+
+            Expression::Bindings(ref l) => {
+                for binding in &l.bindings {
+                    self.visit_expression(&binding.init)?;
+                    match binding.binding_type {
+                        BindingType::Name(ref name) => self.define(name, SymbolKind::Local, &binding.typ, &binding.span),
+                        BindingType::Struct(ref p) => self.struct_pattern(p),
+                        BindingType::Tuple(ref p) => self.tuple_pattern(p),
+                    }
+                }
+                Ok(())
+            },
+
+            Expression::Match(ref m) => {
+                self.visit_expression(&m.target)?;
+                for c in &m.cases {
+                    self.in_scope(|b| {
+                        b.visit_pattern(&c.pattern)?;
+                        b.visit_expression(&c.to_execute)
+                    })?;
+                }
+                Ok(())
+            },
+
+            Expression::For(ref f) => {
+                self.visit_expression(&f.iterable)?;
+                self.in_scope(|b| {
+                    b.define(&f.loop_variable, SymbolKind::Local, &f.loop_variable_type, &f.span);
+                    b.visit_expression(&f.body)
+                })
+            },
+
+            Expression::Lambda(ref l) => {
+                self.in_scope(|b| {
+                    b.function_signature(&l.sig);
+                    b.visit_expression(&l.expr)
+                })
+            },
+
+            Expression::StructInitializer(ref si) => {
+                if !si.struct_name.is_empty() {
+                    self.reference(&si.struct_name, &si.typ, &si.span);
+                }
+                walk_expression(self, e)
+            },
+
+            _ => walk_expression(self, e),
+        }
+    }
+
+    fn visit_pattern(&mut self, p: &Pattern) -> Result<(), ()>
+    {
+        match *p
+        {
+            Pattern::Array(ref ap) => {
+                self.define(&ap.head, SymbolKind::Local, &Type::Unknown, &ap.span);
+                self.define(&ap.tail, SymbolKind::Local, &Type::Unknown, &ap.span);
+                Ok(())
+            },
+            Pattern::Struct(ref sp) => {
+                self.struct_pattern(sp);
+                Ok(())
+            },
+            Pattern::Optional(ref o) => {
+                self.define(&o.binding, SymbolKind::Local, &o.inner_type, &o.span);
+                Ok(())
+            },
+            _ => walk_pattern(self, p),
+        }
+    }
+
+    fn visit_name_ref(&mut self, nr: &NameRef) -> Result<(), ()>
+    {
+        self.reference(&nr.name, &nr.typ, &nr.span);
+        Ok(())
+    }
+}
+
+impl SymbolIndex
+{
+    // Index all identifiers of a type checked package
+    pub fn new(pkg: &Package) -> SymbolIndex
+    {
+        let definitions = module_definitions(pkg);
+        let mut builder = IndexBuilder{
+            module_definitions: &definitions,
+            scopes: Vec::new(),
+            function: None,
+            occurrences: Vec::new(),
+        };
+
+        for md in pkg.modules.values() {
+            let _ = builder.visit_module(md);
+        }
+
+        let mut occurrences = builder.occurrences;
+        occurrences.sort_by(|a, b| (&a.span.file, a.span.start).cmp(&(&b.span.file, b.span.start)));
+        SymbolIndex{
+            occurrences: occurrences,
+        }
+    }
+
+    pub fn to_json(&self) -> String
+    {
+        let occurrences: Vec<String> = self.occurrences.iter()
+            .map(|o| format!(r#"  {{"name": {}, "kind": "{}", "type": {}, "span": {}, "definition": {}}}"#,
+                json_string(&o.name),
+                o.kind,
+                json_string(&format!("{}", o.typ)),
+                json_span(&o.span),
+                o.definition.as_ref().map(json_span).unwrap_or_else(|| "null".into())))
+            .collect();
+
+        format!("[\n{}\n]\n", occurrences.join(",\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{SymbolIndex, SymbolKind, SymbolOccurrence};
+    use ast::IntSize;
+    use parser::parse_str;
+    use target::Target;
+
+    fn index(prog: &str) -> SymbolIndex
+    {
+        let target = Target::new(IntSize::I32, "");
+        let mut pkg = parse_str(prog, "test", &target).expect("Parsing failed");
+        pkg.type_check(&target).expect("Type check failed");
+        SymbolIndex::new(&pkg)
+    }
+
+    #[test]
+    fn test_symbol_kinds()
+    {
+        let idx = index(r#"
+var counter = 0
+
+fn add(a: int, b: int) -> int:
+    let c = a + b
+    counter = counter + c
+    c
+
+fn main() -> int: add(1, 2)
+"#);
+
+        let kind_of = |name: &str| idx.occurrences.iter().find(|o| o.name == name).map(|o| o.kind);
+        assert_eq!(kind_of("test::add"), Some(SymbolKind::Function));
+        assert_eq!(kind_of("a"), Some(SymbolKind::Argument));
+        assert_eq!(kind_of("c"), Some(SymbolKind::Local));
+        assert_eq!(kind_of("test::counter"), Some(SymbolKind::Global));
+
+        // Every use of c refers to the let binding
+        let uses: Vec<&SymbolOccurrence> = idx.occurrences.iter().filter(|o| o.name == "c").collect();
+        assert_eq!(uses.len(), 3);
+        assert!(uses[0].is_definition());
+        assert!(uses.iter().all(|o| o.definition == uses[0].definition));
+    }
+}
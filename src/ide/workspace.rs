@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+use compileerror::{CompileResult, CompileError};
+use package::Package;
+use checkcache::{load_check_cache, save_check_cache, check_cache_file};
+use query::Queries;
+use importindex::suggest_imports;
+use buildinfo::{build_info, build_info_globals};
+use span::Pos;
+use target::Target;
+use super::completion::{Completion, complete};
+use super::hover::{Hover, hover};
+use super::references::TextEdit;
+use super::symbols::SymbolIndex;
+
+// Parse and type check a file, or a directory of files, without generating any code
+pub fn check_package(path: &Path, target: &Target, source_buffers: &HashMap<PathBuf, String>) -> CompileResult<Package>
+{
+    check_package_with_queries(path, target, source_buffers, None)
+}
+
+// Files and modules which haven't changed since an earlier check with the same queries, are not parsed or type checked again
+pub fn check_package_with_queries(path: &Path, target: &Target, source_buffers: &HashMap<PathBuf, String>, queries: Option<Rc<RefCell<Queries>>>) -> CompileResult<Package>
+{
+    let name = path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("Cannot determine file stem of {}", path.to_string_lossy()))?;
+
+    let mut pkg = Package::new(&name);
+    pkg.source_buffers = source_buffers.clone();
+    pkg.queries = queries;
+    pkg.parse_files(path, target)?;
+    pkg.add_constant_globals(&build_info_globals(&build_info("", "debug", &target.triplet, &HashMap::new())));
+    if let Err(e) = pkg.type_check(target) {
+        return Err(suggest_imports(e, &pkg, &[], &target.triplet));
+    }
+    Ok(pkg)
+}
+
+struct CheckedPackage
+{
+    modified: Option<SystemTime>,
+    buffers_generation: usize,
+    pkg: Package,
+    index: SymbolIndex,
+}
+
+/*
+Keeps the type checked AST of every file queried by an editor, so queries only have to parse and
+type check a file again, when it has been modified on disk or in an editor buffer. Even then, only the
+modules which changed, or import a module whose interface changed, are type checked again.
+
+With a check cache directory, the type checked modules are saved there, so the next workspace, for
+example of the next ide command, can start from them.
+*/
+pub struct Workspace
+{
+    target: Target,
+    packages: HashMap<PathBuf, CheckedPackage>,
+    // Contents of files, which are modified in the editor but not saved
+    buffers: HashMap<PathBuf, String>,
+    buffers_generation: usize,
+    queries: HashMap<PathBuf, Rc<RefCell<Queries>>>,
+    check_cache_dir: Option<PathBuf>,
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime>
+{
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Workspace
+{
+    pub fn new(target: Target) -> Workspace
+    {
+        Workspace{
+            target: target,
+            packages: HashMap::new(),
+            buffers: HashMap::new(),
+            buffers_generation: 0,
+            queries: HashMap::new(),
+            check_cache_dir: None,
+        }
+    }
+
+    // Keep the type checked modules in dir, so a new workspace doesn't have to check them again
+    pub fn set_check_cache_dir(&mut self, dir: Option<PathBuf>)
+    {
+        self.check_cache_dir = dir;
+    }
+
+    pub fn set_buffer(&mut self, path: &Path, contents: String)
+    {
+        self.buffers.insert(path.to_path_buf(), contents);
+        self.buffers_generation += 1;
+    }
+
+    // The contents of a file as the editor sees it
+    pub fn source(&self, path: &Path) -> CompileResult<String>
+    {
+        if let Some(contents) = self.buffers.get(path) {
+            return Ok(contents.clone());
+        }
+
+        let mut contents = String::new();
+        fs::File::open(path)?.read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    // Type check the package again, if any of its files has been changed. On errors, the previous state is kept.
+    fn update(&mut self, path: &Path) -> CompileResult<()>
+    {
+        let modified = modification_time(path);
+        let up_to_date = self.packages.get(path)
+            .map(|cp| cp.modified.is_some() && cp.modified == modified && cp.buffers_generation == self.buffers_generation)
+            .unwrap_or(false);
+
+        if !up_to_date {
+            let cache_file = self.check_cache_dir.as_ref().map(|dir| check_cache_file(dir, path, &self.target));
+            let queries = self.queries.entry(path.to_path_buf())
+                .or_insert_with(|| Rc::new(RefCell::new(cache_file.as_ref().map(|file| load_check_cache(file)).unwrap_or_default())))
+                .clone();
+
+            let misses = queries.borrow().type_check.misses;
+            let pkg = check_package_with_queries(path, &self.target, &self.buffers, Some(queries.clone()))?;
+
+            // Only a faster start next time, so failing to save it is not an error
+            if let Some(file) = cache_file {
+                if queries.borrow().type_check.misses > misses {
+                    let _ = save_check_cache(&queries.borrow(), &file);
+                }
+            }
+
+            let index = SymbolIndex::new(&pkg);
+            self.packages.insert(path.to_path_buf(), CheckedPackage{
+                modified: modified,
+                buffers_generation: self.buffers_generation,
+                pkg: pkg,
+                index: index,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn diagnostics(&mut self, path: &Path) -> Vec<CompileError>
+    {
+        match self.update(path) {
+            Ok(()) => Vec::new(),
+            Err(e) => e.into_errors(),
+        }
+    }
+
+    pub fn package(&mut self, path: &Path) -> CompileResult<&Package>
+    {
+        self.update(path)?;
+        Ok(&self.packages[path].pkg)
+    }
+
+    pub fn symbol_index(&mut self, path: &Path) -> CompileResult<&SymbolIndex>
+    {
+        self.update(path)?;
+        Ok(&self.packages[path].index)
+    }
+
+    pub fn hover(&mut self, path: &Path, pos: Pos) -> CompileResult<Option<Hover>>
+    {
+        let int_size = self.target.int_size;
+        let file = path.to_string_lossy().into_owned();
+        self.package(path).map(|pkg| hover(pkg, &file, pos, int_size))
+    }
+
+    pub fn rename(&mut self, path: &Path, pos: Pos, new_name: &str) -> CompileResult<Vec<TextEdit>>
+    {
+        self.update(path)?;
+        let file = path.to_string_lossy();
+        let index = &self.packages[path].index;
+        let occ = index.occurrence_at(&file, pos)
+            .ok_or_else(|| CompileError::Other(format!("No symbol found at {}:{}", file, pos)))?;
+        index.rename(occ, new_name, |file| self.source(Path::new(file)))
+    }
+
+    // Code being typed often doesn't compile, so completions use the last state which type checked
+    pub fn complete(&mut self, path: &Path, pos: Pos) -> CompileResult<Vec<Completion>>
+    {
+        if let Err(e) = self.update(path) {
+            if !self.packages.contains_key(path) {
+                return Err(e);
+            }
+        }
+
+        let source = self.source(path)?;
+        let file = path.to_string_lossy();
+        let cp = &self.packages[path];
+        Ok(complete(&cp.pkg, &cp.index, &source, &file, pos, self.target.int_size))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::path::Path;
+    use super::Workspace;
+    use ast::IntSize;
+    use span::Pos;
+    use target::Target;
+
+    #[test]
+    fn test_unsaved_buffer()
+    {
+        let path = Path::new("unsaved_buffer_test.mhr");
+        let mut ws = Workspace::new(Target::new(IntSize::I32, ""));
+        ws.set_buffer(path, "fn main() -> int:\n    let x = 5\n    x\n".into());
+        assert!(ws.diagnostics(path).is_empty());
+
+        let hover = ws.hover(path, Pos::new(3, 5)).expect("Type check failed").expect("Nothing found");
+        assert_eq!(hover.name, Some("x".into()));
+
+        ws.set_buffer(path, "fn main() -> int:\n    y\n".into());
+        assert_eq!(ws.diagnostics(path).len(), 1);
+
+        // Completion still works using the last version which type checked
+        let completions = ws.complete(path, Pos::new(2, 6)).expect("Completion failed");
+        assert!(completions.is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+use std::cmp::min;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use ast::Import;
+use compileerror::CompileError;
+use exportlibrary::ExportLibrary;
+use package::Package;
+use packagebuild::import_search_path;
+
+/*
+Suggestions for names and imports which cannot be resolved. When a name is exported by a module which
+is not imported, the error suggests the import, and when there is no such name, the most similar
+exported name is suggested.
+
+The index of exported symbols is only built when type checking fails with an unknown name. It contains
+the modules of the package itself, its libraries, and the libraries found in the import search path,
+whose interfaces are loaded from their exports files.
+*/
+
+struct IndexEntry
+{
+    name: String,
+    namespace: String,
+    // Library of the module, None for modules of the package and its dependencies
+    library: Option<String>,
+}
+
+pub struct ImportIndex
+{
+    entries: Vec<IndexEntry>,
+    namespaces: Vec<(String, Option<String>)>,
+}
+
+// Number of single character insertions, deletions and substitutions to turn a into b
+pub fn levenshtein(a: &str, b: &str) -> usize
+{
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev + if ca == *cb {0} else {1};
+            prev = row[j + 1];
+            row[j + 1] = min(substitution, min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// The name without its namespace
+fn short_name(name: &str) -> &str
+{
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+// Names shorter then 3 characters are too short for a meaningful suggestion
+fn max_distance(name: &str) -> usize
+{
+    name.len() / 3
+}
+
+// Exports files of the libraries in an import directory, either built or installed as a package
+fn exports_files(import_dir: &Path, target_triplet: &str) -> Vec<PathBuf>
+{
+    let mut files = Vec::new();
+    if let Ok(entries) = import_dir.join(target_triplet).read_dir() {
+        for lib_dir in entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()) {
+            if let Ok(lib_entries) = lib_dir.read_dir() {
+                files.extend(lib_entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| p.to_string_lossy().ends_with(".mhr.exports")));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+impl ImportIndex
+{
+    pub fn new() -> ImportIndex
+    {
+        ImportIndex{
+            entries: Vec::new(),
+            namespaces: Vec::new(),
+        }
+    }
+
+    pub fn load(pkg: &Package, import_directories: &[PathBuf], target_triplet: &str) -> ImportIndex
+    {
+        let mut index = ImportIndex::new();
+        for import in pkg.import_data.imports.values() {
+            index.add_import(import, None);
+        }
+
+        for lib in &pkg.import_data.libraries {
+            for import in &lib.imports {
+                index.add_import(import, None);
+            }
+        }
+
+        for import_path in import_search_path(import_directories) {
+            for file in exports_files(&import_path.path, target_triplet) {
+                // A broken exports file only means there are less suggestions
+                if let Ok(lib) = File::open(&file).map_err(|e| e.to_string()).and_then(|mut f| ExportLibrary::load(&mut f)) {
+                    if pkg.import_data.libraries.iter().any(|l| l.name == lib.name) {
+                        continue;
+                    }
+
+                    for import in &lib.imports {
+                        index.add_import(import, Some(&lib.name));
+                    }
+                }
+            }
+        }
+
+        index.entries.sort_by(|a, b| a.name.cmp(&b.name));
+        index
+    }
+
+    pub fn add_import(&mut self, import: &Import, library: Option<&str>)
+    {
+        if self.namespaces.iter().any(|&(ref ns, _)| *ns == import.namespace) {
+            return;
+        }
+
+        self.namespaces.push((import.namespace.clone(), library.map(|l| l.to_string())));
+        let public_symbols = import.symbols.values().filter(|s| s.public).map(|s| &s.name);
+        let public_generics = import.generics.values().filter(|g| g.public).map(|g| &g.sig.name);
+        for name in public_symbols.chain(public_generics) {
+            self.entries.push(IndexEntry{
+                name: name.clone(),
+                namespace: import.namespace.clone(),
+                library: library.map(|l| l.to_string()),
+            });
+        }
+    }
+
+    fn library_hint(library: &Option<String>) -> String
+    {
+        match *library {
+            Some(ref lib) => format!(", and add {} to the dependencies of the target", lib),
+            None => String::new(),
+        }
+    }
+
+    // Suggestion for a name which cannot be resolved
+    pub fn suggest_name(&self, name: &str) -> Option<String>
+    {
+        let exact = self.entries.iter().find(|e| e.name == name || short_name(&e.name) == name);
+        if let Some(e) = exact {
+            return Some(format!("{} is exported by module {}, use import {}{}",
+                name, e.namespace, e.namespace, ImportIndex::library_hint(&e.library)));
+        }
+
+        let short = short_name(name);
+        self.entries.iter()
+            .map(|e| (levenshtein(short, short_name(&e.name)), e))
+            .filter(|&(distance, _)| distance <= max_distance(short))
+            .min_by_key(|&(distance, _)| distance)
+            .map(|(_, e)| format!("did you mean {} from module {}?", short_name(&e.name), e.namespace))
+    }
+
+    // Suggestion for an import which cannot be resolved
+    pub fn suggest_import(&self, namespace: &str) -> Option<String>
+    {
+        if let Some(&(_, ref library)) = self.namespaces.iter().find(|&&(ref ns, _)| ns == namespace) {
+            return library.as_ref().map(|lib| format!("module {} is part of library {}, add {} to the dependencies of the target", namespace, lib, lib));
+        }
+
+        self.namespaces.iter()
+            .map(|&(ref ns, _)| (levenshtein(namespace, ns), ns))
+            .filter(|&(distance, _)| distance <= max_distance(namespace))
+            .min_by_key(|&(distance, _)| distance)
+            .map(|(_, ns)| format!("did you mean import {}?", ns))
+    }
+}
+
+fn add_suggestions(e: CompileError, index: &ImportIndex) -> CompileError
+{
+    match e {
+        CompileError::UnknownName(mut ed, name) => {
+            let suggestion = if ed.msg.starts_with("Unknown import") {
+                index.suggest_import(&name)
+            } else if ed.msg.starts_with("Unknown struct member") {
+                None
+            } else {
+                index.suggest_name(&name)
+            };
+
+            if let Some(suggestion) = suggestion {
+                ed.msg = format!("{}\n  help: {}", ed.msg, suggestion);
+            }
+            CompileError::UnknownName(ed, name)
+        },
+        CompileError::Many(errors) => CompileError::Many(errors.into_iter().map(|e| add_suggestions(e, index)).collect()),
+        e => e,
+    }
+}
+
+fn has_unknown_names(e: &CompileError) -> bool
+{
+    match *e {
+        CompileError::UnknownName(..) => true,
+        CompileError::Many(ref errors) => errors.iter().any(has_unknown_names),
+        _ => false,
+    }
+}
+
+// Add import suggestions to the unknown names in a type check error of pkg
+pub fn suggest_imports(e: CompileError, pkg: &Package, import_directories: &[PathBuf], target_triplet: &str) -> CompileError
+{
+    if !has_unknown_names(&e) {
+        return e;
+    }
+
+    let index = ImportIndex::load(pkg, import_directories, target_triplet);
+    add_suggestions(e, &index)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{levenshtein, ImportIndex};
+    use ast::{Import, Symbol, SymbolType, Type};
+    use span::Span;
+
+    fn import(namespace: &str, names: &[&str]) -> Import
+    {
+        let mut import = Import::new(namespace.into());
+        for name in names {
+            let full_name = format!("{}::{}", namespace, name);
+            import.symbols.insert(full_name.clone(), Symbol::new(&full_name, &Type::Bool, false, &Span::default(), SymbolType::Normal));
+        }
+        import
+    }
+
+    #[test]
+    fn test_levenshtein()
+    {
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("abc", "abc"), 0);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("prnitln", "println"), 2);
+    }
+
+    #[test]
+    fn test_suggestions()
+    {
+        let mut index = ImportIndex::new();
+        index.add_import(&import("std::io", &["println", "print"]), None);
+        index.add_import(&import("json", &["parse_json"]), Some("jsonlib"));
+        index.entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        assert_eq!(index.suggest_name("println"), Some("println is exported by module std::io, use import std::io".into()));
+        assert_eq!(index.suggest_name("parse_json"),
+            Some("parse_json is exported by module json, use import json, and add jsonlib to the dependencies of the target".into()));
+        assert_eq!(index.suggest_name("prntln"), Some("did you mean println from module std::io?".into()));
+        assert_eq!(index.suggest_name("foo"), None);
+        assert_eq!(index.suggest_import("std::oi"), Some("did you mean import std::io?".into()));
+        assert_eq!(index.suggest_import("json"), Some("module json is part of library jsonlib, add jsonlib to the dependencies of the target".into()));
+        assert_eq!(index.suggest_import("std::io"), None);
+    }
+}
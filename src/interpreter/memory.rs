@@ -0,0 +1,237 @@
+use compileerror::{CompileResult, CompileError};
+
+/*
+Memory of the interpreter, a list of allocations. An address is the allocation number in
+the upper 32 bits, and an offset in that allocation in the lower 32 bits, so 0 is never valid.
+Functions get addresses in a reserved allocation, so function pointers can be stored in memory.
+Heap allocations have a reference count, which starts at one.
+*/
+
+const FUNCTION_ALLOCATION: u64 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AllocationKind
+{
+    Stack,
+    Heap,
+    Static,
+}
+
+struct Allocation
+{
+    data: Vec<u8>,
+    kind: AllocationKind,
+    ref_count: usize,
+}
+
+pub struct Memory
+{
+    allocations: Vec<Option<Allocation>>,
+    free_slots: Vec<usize>,
+}
+
+pub fn function_address(idx: usize) -> u64
+{
+    (FUNCTION_ALLOCATION << 32) | idx as u64
+}
+
+pub fn function_index(addr: u64) -> Option<usize>
+{
+    if addr >> 32 == FUNCTION_ALLOCATION {
+        Some((addr & 0xFFFF_FFFF) as usize)
+    } else {
+        None
+    }
+}
+
+fn memory_error<T>(msg: String) -> CompileResult<T>
+{
+    Err(CompileError::Other(format!("Runtime error: {}", msg)))
+}
+
+impl Memory
+{
+    pub fn new() -> Memory
+    {
+        Memory{
+            allocations: vec![None],
+            free_slots: Vec::new(),
+        }
+    }
+
+    pub fn allocate(&mut self, size: usize, kind: AllocationKind) -> u64
+    {
+        let alloc = Allocation{
+            data: vec![0; size],
+            kind: kind,
+            ref_count: 1,
+        };
+
+        let idx = match self.free_slots.pop() {
+            Some(idx) => {
+                self.allocations[idx] = Some(alloc);
+                idx
+            },
+            None => {
+                self.allocations.push(Some(alloc));
+                self.allocations.len() - 1
+            }
+        };
+
+        (idx as u64) << 32
+    }
+
+    pub fn free(&mut self, addr: u64, kind: AllocationKind) -> CompileResult<()>
+    {
+        let idx = (addr >> 32) as usize;
+        let valid = addr & 0xFFFF_FFFF == 0 && match self.allocations.get(idx) {
+            Some(&Some(ref alloc)) => alloc.kind == kind,
+            _ => false,
+        };
+
+        if !valid {
+            return memory_error(format!("cannot free address 0x{:x}, it is not the start of a {:?} allocation", addr, kind));
+        }
+
+        self.allocations[idx] = None;
+        self.free_slots.push(idx);
+        Ok(())
+    }
+
+    // The heap allocation starting at addr, other addresses aren't reference counted
+    fn counted_allocation(&mut self, addr: u64) -> Option<&mut Allocation>
+    {
+        if addr & 0xFFFF_FFFF != 0 {
+            return None;
+        }
+
+        let alloc = match self.allocations.get_mut((addr >> 32) as usize) {
+            Some(&mut Some(ref mut alloc)) => alloc,
+            _ => return None,
+        };
+
+        if alloc.kind == AllocationKind::Heap {
+            Some(alloc)
+        } else {
+            None
+        }
+    }
+
+    pub fn retain(&mut self, addr: u64)
+    {
+        if let Some(alloc) = self.counted_allocation(addr) {
+            alloc.ref_count += 1;
+        }
+    }
+
+    // Frees the allocation when the last reference is released
+    pub fn release(&mut self, addr: u64) -> CompileResult<()>
+    {
+        let last = match self.counted_allocation(addr) {
+            Some(alloc) => {
+                alloc.ref_count -= 1;
+                alloc.ref_count == 0
+            },
+            None => false,
+        };
+
+        if last {
+            self.free(addr, AllocationKind::Heap)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check(&self, addr: u64, size: usize) -> CompileResult<(usize, usize)>
+    {
+        if addr == 0 {
+            return memory_error("null pointer dereference".into());
+        }
+
+        if function_index(addr).is_some() {
+            return memory_error("a function pointer cannot be dereferenced".into());
+        }
+
+        let idx = (addr >> 32) as usize;
+        let offset = (addr & 0xFFFF_FFFF) as usize;
+        match self.allocations.get(idx) {
+            Some(&Some(ref alloc)) => {
+                if offset + size > alloc.data.len() {
+                    memory_error(format!("access of {} bytes at offset {} is out of bounds, the allocation is {} bytes", size, offset, alloc.data.len()))
+                } else {
+                    Ok((idx, offset))
+                }
+            },
+            _ => memory_error(format!("access of address 0x{:x}, which is not allocated (use after free?)", addr)),
+        }
+    }
+
+    pub fn read(&self, addr: u64, size: usize) -> CompileResult<&[u8]>
+    {
+        if size == 0 {
+            return Ok(&[]);
+        }
+
+        let (idx, offset) = self.check(addr, size)?;
+        let alloc = self.allocations[idx].as_ref().expect("Allocation disappeared");
+        Ok(&alloc.data[offset..offset + size])
+    }
+
+    pub fn write(&mut self, addr: u64, bytes: &[u8]) -> CompileResult<()>
+    {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let (idx, offset) = self.check(addr, bytes.len())?;
+        let alloc = self.allocations[idx].as_mut().expect("Allocation disappeared");
+        alloc.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{Memory, AllocationKind, function_address, function_index};
+
+    #[test]
+    fn test_memory()
+    {
+        let mut mem = Memory::new();
+        let a = mem.allocate(8, AllocationKind::Heap);
+        assert!(a != 0);
+        mem.write(a + 4, &[1, 2, 3, 4]).expect("Write failed");
+        assert_eq!(mem.read(a, 8).expect("Read failed"), &[0, 0, 0, 0, 1, 2, 3, 4]);
+        assert!(mem.read(a + 6, 4).is_err());
+        assert!(mem.read(0, 4).is_err());
+        assert!(mem.free(a, AllocationKind::Stack).is_err());
+        assert!(mem.free(a + 4, AllocationKind::Heap).is_err());
+        mem.free(a, AllocationKind::Heap).expect("Free failed");
+        assert!(mem.read(a, 8).is_err());
+        assert!(mem.free(a, AllocationKind::Heap).is_err());
+        assert_eq!(function_index(function_address(3)), Some(3));
+        assert_eq!(function_index(a), None);
+    }
+
+    #[test]
+    fn test_reference_counting()
+    {
+        let mut mem = Memory::new();
+        let a = mem.allocate(8, AllocationKind::Heap);
+        mem.retain(a);
+        mem.release(a).expect("Release failed");
+        assert!(mem.read(a, 8).is_ok());
+        mem.release(a).expect("Release failed");
+        assert!(mem.read(a, 8).is_err());
+
+        // Only the start of a heap allocation is counted
+        let s = mem.allocate(8, AllocationKind::Stack);
+        mem.release(s).expect("Release failed");
+        assert!(mem.read(s, 8).is_ok());
+        let b = mem.allocate(8, AllocationKind::Heap);
+        mem.release(b + 4).expect("Release failed");
+        mem.release(0).expect("Release failed");
+        assert!(mem.read(b, 8).is_ok());
+    }
+}
@@ -0,0 +1,1785 @@
+mod memory;
+mod value;
+
+use std::collections::HashMap;
+use std::env;
+use std::str;
+use ast::{Type, IntSize, SumType, SumTypeNiche, UnaryOperator, BinaryOperator, Intrinsic, RANDOM_SEED_VARIABLE, ptr_type};
+use bytecode::{ByteCodeModule, ByteCodeFunction, ByteCodeProperty, BasicBlockRef, Instruction, Operand, Constant, Var};
+use compileerror::{CompileResult, CompileError};
+use self::memory::{Memory, AllocationKind, function_address, function_index};
+use self::value::{Value, Layout, format_float, format_fixed, format_int, hex_encode, hex_decode};
+
+/*
+Executes a ByteCodeModule directly, so programs can be run without LLVM.
+
+Like in the code generated by the LLVM backend, every variable lives in memory, so taking
+the address of variables, members and heap allocations works the same way. Calls don't use
+the native stack, the interpreter keeps its own stack of frames.
+*/
+
+const MAX_CALL_DEPTH: usize = 100_000;
+
+struct Frame<'a>
+{
+    func: &'a ByteCodeFunction,
+    block: BasicBlockRef,
+    index: usize,
+    // Address and size of each local, a name can be bound again with a bigger type in another scope
+    vars: HashMap<String, (u64, usize)>,
+    allocations: Vec<u64>,
+    // Like an alloca, a StackAlloc instruction reuses the same memory every time it is executed
+    stack_allocs: HashMap<*const Instruction, u64>,
+    // Where the return value of the function has to be stored in the calling frame
+    dst: Option<Var>,
+}
+
+enum Flow<'a>
+{
+    Next,
+    Branch(BasicBlockRef),
+    Call(&'a ByteCodeFunction, Vec<(Value, Type)>, Option<Var>),
+    Return(Value, Type),
+}
+
+struct Global
+{
+    addr: u64,
+    // Lazy globals are initialized by this function on first use
+    init_function: Option<String>,
+}
+
+fn runtime_error<T>(msg: String) -> CompileResult<T>
+{
+    Err(CompileError::Other(format!("Runtime error: {}", msg)))
+}
+
+fn compare<T: PartialOrd>(op: BinaryOperator, a: T, b: T) -> Option<Value>
+{
+    let result = match op
+    {
+        BinaryOperator::LessThan => a < b,
+        BinaryOperator::GreaterThan => a > b,
+        BinaryOperator::LessThanEquals => a <= b,
+        BinaryOperator::GreaterThanEquals => a >= b,
+        BinaryOperator::Equals => a == b,
+        BinaryOperator::NotEquals => a != b,
+        _ => return None,
+    };
+    Some(Value::Bool(result))
+}
+
+pub struct Interpreter<'a>
+{
+    module: &'a ByteCodeModule,
+    layout: Layout,
+    memory: Memory,
+    frames: Vec<Frame<'a>>,
+    globals: HashMap<String, Global>,
+    strings: HashMap<String, u64>,
+    function_names: Vec<String>,
+    // Function tables of interface objects, by the functions they contain
+    vtables: HashMap<Vec<String>, u64>,
+    // Environment pointers of closures, by the index of their function address
+    closures: HashMap<usize, u64>,
+    // Seed of @random, and the state of the generator once it is used
+    random_seed: u64,
+    random_state: Option<u64>,
+}
+
+impl<'a> Interpreter<'a>
+{
+    pub fn new(module: &'a ByteCodeModule, int_size: IntSize) -> CompileResult<Interpreter<'a>>
+    {
+        let mut interpreter = Interpreter{
+            module: module,
+            layout: Layout{int_size: int_size},
+            memory: Memory::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            strings: HashMap::new(),
+            function_names: Vec::new(),
+            vtables: HashMap::new(),
+            closures: HashMap::new(),
+            random_seed: 0,
+            random_state: None,
+        };
+
+        for (name, cst) in &module.globals {
+            let typ = cst.get_type();
+            let value = interpreter.constant(cst)?;
+            let addr = interpreter.memory.allocate(interpreter.layout.size_of(&typ), AllocationKind::Static);
+            interpreter.write(addr, &typ, &value)?;
+            interpreter.globals.insert(name.clone(), Global{addr: addr, init_function: None});
+        }
+
+        for (name, rg) in &module.runtime_globals {
+            let addr = interpreter.memory.allocate(interpreter.layout.size_of(&rg.typ), AllocationKind::Static);
+            let init_function = if rg.lazy {Some(rg.init_function.clone())} else {None};
+            interpreter.globals.insert(name.clone(), Global{addr: addr, init_function: init_function});
+        }
+
+        Ok(interpreter)
+    }
+
+    // Run the init functions, main and the fini functions, returns the return value of main
+    pub fn run(&mut self) -> CompileResult<i64>
+    {
+        let module = self.module;
+        for name in &module.init_functions {
+            self.call(name, Vec::new())?;
+        }
+
+        let ret = self.call(&module.main_function_name(), Vec::new())?;
+
+        for name in &module.fini_functions {
+            self.call(name, Vec::new())?;
+        }
+
+        Ok(match ret {
+            Value::Int(v) => v,
+            Value::UInt(v) => v as i64,
+            _ => 0,
+        })
+    }
+
+    fn get_function(&self, name: &str) -> Option<&'a ByteCodeFunction>
+    {
+        let module = self.module;
+        module.functions.get(name).or_else(|| module.imported_functions.iter().find(|f| f.sig.name == name))
+    }
+
+    fn function_address(&mut self, name: &str) -> u64
+    {
+        let idx = match self.function_names.iter().enumerate().position(|(idx, n)| n == name && !self.closures.contains_key(&idx)) {
+            Some(idx) => idx,
+            None => {
+                self.function_names.push(name.into());
+                self.function_names.len() - 1
+            }
+        };
+        function_address(idx)
+    }
+
+    // Each environment of a closure gets its own function address
+    fn closure_address(&mut self, name: &str, env: u64) -> u64
+    {
+        let existing = self.function_names.iter().enumerate().position(|(idx, n)| n == name && self.closures.get(&idx) == Some(&env));
+        let idx = match existing {
+            Some(idx) => idx,
+            None => {
+                self.function_names.push(name.into());
+                self.closures.insert(self.function_names.len() - 1, env);
+                self.function_names.len() - 1
+            }
+        };
+        function_address(idx)
+    }
+
+    fn vtable(&mut self, functions: &[String]) -> CompileResult<u64>
+    {
+        if let Some(&addr) = self.vtables.get(functions) {
+            return Ok(addr);
+        }
+
+        let addr = self.memory.allocate(functions.len() * 8, AllocationKind::Static);
+        for (idx, name) in functions.iter().enumerate() {
+            let func_addr = self.function_address(name);
+            self.write(addr + idx as u64 * 8, &ptr_type(Type::Void), &Value::Pointer(func_addr))?;
+        }
+        self.vtables.insert(functions.to_vec(), addr);
+        Ok(addr)
+    }
+
+    // Call a function and run until it returns
+    pub fn call(&mut self, name: &str, args: Vec<(Value, Type)>) -> CompileResult<Value>
+    {
+        let func = match self.get_function(name) {
+            Some(func) => func,
+            None => return runtime_error(format!("unknown function {}", name)),
+        };
+
+        let base = self.frames.len();
+        self.push_frame(func, args, None)?;
+        loop {
+            let (func, block, index) = {
+                let frame = self.frame();
+                (frame.func, frame.block, frame.index)
+            };
+
+            let instr = match func.blocks.get(&block).and_then(|bb| bb.instructions.get(index)) {
+                Some(instr) => instr,
+                None => return Err(self.backtrace(CompileError::Other(format!("Runtime error: block {} of {} has no terminator", block, func.sig.name)), base)),
+            };
+
+            self.frame().index += 1;
+            let flow = match self.execute(instr) {
+                Ok(flow) => flow,
+                Err(e) => return Err(self.backtrace(e, base)),
+            };
+
+            match flow
+            {
+                Flow::Next => (),
+
+                Flow::Branch(block) => {
+                    let frame = self.frame();
+                    frame.block = block;
+                    frame.index = 0;
+                },
+
+                Flow::Call(func, args, dst) => {
+                    if let Err(e) = self.push_frame(func, args, dst) {
+                        return Err(self.backtrace(e, base));
+                    }
+                },
+
+                Flow::Return(value, typ) => {
+                    let frame = self.frames.pop().expect("Frame stack is empty");
+                    for addr in frame.allocations {
+                        self.memory.free(addr, AllocationKind::Stack)?;
+                    }
+
+                    if self.frames.len() == base {
+                        return Ok(value);
+                    }
+
+                    if let Some(ref dst) = frame.dst {
+                        if let Err(e) = self.store(dst, &typ, value) {
+                            return Err(self.backtrace(e, base));
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    // Add the functions on the stack to an error, and unwind the stack
+    fn backtrace(&mut self, e: CompileError, base: usize) -> CompileError
+    {
+        let mut msg = match e {
+            CompileError::Other(msg) => msg,
+            _ => return e,
+        };
+
+        while self.frames.len() > base {
+            let frame = self.frames.pop().expect("Frame stack is empty");
+            msg.push_str(&format!("\n  in {}", frame.func.sig.name));
+        }
+        CompileError::Other(msg)
+    }
+
+    fn push_frame(&mut self, func: &'a ByteCodeFunction, args: Vec<(Value, Type)>, dst: Option<Var>) -> CompileResult<()>
+    {
+        if func.external {
+            return runtime_error(format!("cannot call external function {}, only functions with a body can be interpreted", func.sig.name));
+        }
+
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return runtime_error(format!("stack overflow, more then {} nested calls", MAX_CALL_DEPTH));
+        }
+
+        self.frames.push(Frame{
+            func: func,
+            block: func.blocks.keys().next().cloned().unwrap_or(0),
+            index: 0,
+            vars: HashMap::new(),
+            allocations: Vec::new(),
+            stack_allocs: HashMap::new(),
+            dst: dst,
+        });
+
+        for (arg, (value, typ)) in func.sig.args.iter().zip(args.into_iter()) {
+            let addr = self.allocate_var(&arg.name, &arg.typ);
+            self.store_value(addr, &arg.typ, &typ, value)?;
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self) -> &mut Frame<'a>
+    {
+        self.frames.last_mut().expect("Frame stack is empty")
+    }
+
+    fn allocate_var(&mut self, name: &str, typ: &Type) -> u64
+    {
+        let size = self.layout.size_of(typ);
+        let addr = self.memory.allocate(size, AllocationKind::Stack);
+        let frame = self.frame();
+        frame.vars.insert(name.into(), (addr, size));
+        frame.allocations.push(addr);
+        addr
+    }
+
+    // Address of a local or global variable, locals are allocated on first use
+    fn var_address(&mut self, var: &Var) -> CompileResult<u64>
+    {
+        let size = self.layout.size_of(&var.typ);
+        match self.frame().vars.get(&var.name) {
+            Some(&(addr, var_size)) if var_size >= size => return Ok(addr),
+            Some(_) => return Ok(self.allocate_var(&var.name, &var.typ)),
+            None => (),
+        }
+
+        let global = self.globals.get_mut(&var.name).map(|g| (g.addr, g.init_function.take()));
+        if let Some((addr, init_function)) = global {
+            if let Some(init_function) = init_function {
+                self.call(&init_function, Vec::new())?;
+            }
+            return Ok(addr);
+        }
+
+        Ok(self.allocate_var(&var.name, &var.typ))
+    }
+
+    fn read(&self, addr: u64, typ: &Type) -> CompileResult<Value>
+    {
+        let bytes = self.memory.read(addr, self.layout.size_of(typ))?;
+        Ok(self.layout.decode(bytes, typ))
+    }
+
+    fn write(&mut self, addr: u64, typ: &Type, value: &Value) -> CompileResult<()>
+    {
+        let mut bytes = vec![0; self.layout.size_of(typ)];
+        self.layout.encode(value, typ, &mut bytes);
+        self.memory.write(addr, &bytes)
+    }
+
+    fn read_pointer(&self, addr: u64) -> CompileResult<u64>
+    {
+        self.read(addr, &ptr_type(Type::Void)).map(|v| v.to_bits())
+    }
+
+    fn read_var(&mut self, var: &Var) -> CompileResult<Value>
+    {
+        // A function used as a value
+        if !self.frame().vars.contains_key(&var.name) && !self.globals.contains_key(&var.name) && self.get_function(&var.name).is_some() {
+            return Ok(Value::Pointer(self.function_address(&var.name)));
+        }
+
+        let addr = self.var_address(var)?;
+        self.read(addr, &var.typ)
+    }
+
+    // Store a value of type src_type in memory of type typ, wrapping it in an optional if needed.
+    // Members which are not passed by value are loaded as their address, so a copy of what it points to is stored.
+    fn store_value(&mut self, addr: u64, typ: &Type, src_type: &Type, value: Value) -> CompileResult<()>
+    {
+        if let (&Type::Pointer(ref inner), &Value::Pointer(ptr)) = (src_type, &value) {
+            if **inner == *typ {
+                let value = self.read(ptr, inner)?;
+                return self.write(addr, inner, &value);
+            }
+        }
+
+        match *typ
+        {
+            Type::Optional(ref inner) if *src_type != *typ && *src_type != Type::Unknown => {
+                if !inner.has_null_niche() {
+                    self.write(addr, &Type::Bool, &Value::Bool(true))?;
+                }
+                self.write(addr + self.layout.offset_of(typ, 1) as u64, inner, &value)
+            },
+            _ => self.write(addr, typ, &value),
+        }
+    }
+
+    // Store in a variable, or in what it points to, when the value has the type it points to
+    fn store(&mut self, dst: &Var, src_type: &Type, value: Value) -> CompileResult<()>
+    {
+        let addr = self.var_address(dst)?;
+        match dst.typ
+        {
+            Type::Pointer(ref inner) if *src_type != dst.typ && **inner == *src_type => {
+                let ptr = self.read_pointer(addr)?;
+                self.store_value(ptr, inner, src_type, value)
+            },
+            _ => self.store_value(addr, &dst.typ, src_type, value),
+        }
+    }
+
+    // Address and type of what a variable refers to, following pointers
+    fn object_address(&mut self, var: &Var) -> CompileResult<(u64, Type)>
+    {
+        let mut addr = self.var_address(var)?;
+        let mut typ = var.typ.clone();
+        while let Type::Pointer(inner) = typ.clone() {
+            addr = self.read_pointer(addr)?;
+            typ = (*inner).clone();
+        }
+        Ok((addr, typ))
+    }
+
+    fn slice_value(&self, data: u64, len: u64) -> Value
+    {
+        let mut bytes = vec![0; self.layout.size_of(&Type::String)];
+        self.layout.encode(&Value::Pointer(data), &ptr_type(Type::Void), &mut bytes[..8]);
+        self.layout.encode(&Value::UInt(len), &Type::UInt(self.layout.int_size), &mut bytes[8..]);
+        Value::Bytes(bytes)
+    }
+
+    // Data pointer and length of a slice or string
+    fn slice_parts(&self, value: &Value) -> (u64, u64)
+    {
+        match *value
+        {
+            Value::Bytes(ref bytes) => (
+                self.layout.decode(&bytes[..8], &ptr_type(Type::Void)).to_bits(),
+                self.layout.decode(&bytes[8..], &Type::UInt(self.layout.int_size)).to_bits(),
+            ),
+            _ => panic!("Internal Compiler Error: {:?} is not a slice", value),
+        }
+    }
+
+    // Data pointer, length and element type of a sequence operand
+    fn sequence_operand(&mut self, op: &Operand) -> CompileResult<(u64, u64, Type)>
+    {
+        let (addr, typ) = match *op
+        {
+            Operand::Var(ref v) => self.object_address(v)?,
+            _ => {
+                let typ = op.get_type(self.layout.int_size);
+                let value = self.operand(op)?;
+                let addr = self.memory.allocate(self.layout.size_of(&typ), AllocationKind::Stack);
+                self.write(addr, &typ, &value)?;
+                self.frame().allocations.push(addr);
+                (addr, typ)
+            },
+        };
+
+        match typ
+        {
+            Type::Array(ref at) => Ok((addr, at.len as u64, at.element_type.clone())),
+            Type::Slice(ref st) => {
+                let (data, len) = self.slice_parts(&self.read(addr, &typ)?);
+                Ok((data, len, st.element_type.clone()))
+            },
+            Type::String => {
+                let (data, len) = self.slice_parts(&self.read(addr, &typ)?);
+                Ok((data, len, Type::UInt(IntSize::I8)))
+            },
+            _ => runtime_error(format!("expected an array, slice or string, not a {}", typ)),
+        }
+    }
+
+    fn string_data(&mut self, s: &str) -> CompileResult<u64>
+    {
+        if let Some(&addr) = self.strings.get(s) {
+            return Ok(addr);
+        }
+
+        // Zero terminated, so it can be passed to C
+        let addr = self.memory.allocate(s.len() + 1, AllocationKind::Static);
+        self.memory.write(addr, s.as_bytes())?;
+        self.strings.insert(s.into(), addr);
+        Ok(addr)
+    }
+
+    fn constant(&mut self, cst: &Constant) -> CompileResult<Value>
+    {
+        let value = match *cst
+        {
+            Constant::Int(v, _) => Value::Int(v),
+            Constant::UInt(v, _) => Value::UInt(v),
+            Constant::Float(v, _) => Value::Float(v),
+            Constant::Char(c) => Value::Char(c),
+            Constant::Bool(b) => Value::Bool(b),
+            Constant::NullPtr(_) => Value::Pointer(0),
+            Constant::String(ref s) => {
+                let data = self.string_data(s)?;
+                self.slice_value(data, s.len() as u64)
+            },
+            Constant::Array(ref elements) => {
+                let typ = cst.get_type();
+                let mut bytes = vec![0; self.layout.size_of(&typ)];
+                for (idx, element) in elements.iter().enumerate() {
+                    let offset = self.layout.offset_of(&typ, idx);
+                    let value = self.constant(element)?;
+                    self.layout.encode(&value, &element.get_type(), &mut bytes[offset..]);
+                }
+                Value::Bytes(bytes)
+            },
+        };
+        Ok(value)
+    }
+
+    fn operand(&mut self, op: &Operand) -> CompileResult<Value>
+    {
+        match *op
+        {
+            Operand::Var(ref v) => self.read_var(v),
+            Operand::AddressOf(ref v) => self.var_address(v).map(Value::Pointer),
+            Operand::Dereference(ref v) => {
+                let ptr = self.read_var(v)?.to_bits();
+                let typ = op.get_type(self.layout.int_size);
+                self.read(ptr, &typ)
+            },
+            Operand::Const(ref c) => self.constant(c),
+            Operand::Func(ref name) => Ok(Value::Pointer(self.function_address(name))),
+            Operand::SizeOf(ref typ) => Ok(Value::UInt(self.layout.size_of(typ) as u64)),
+            Operand::OffsetOf(ref typ, index) => Ok(Value::UInt(self.layout.offset_of(typ, index) as u64)),
+        }
+    }
+
+    // Address and type of a member of an array, slice, string, struct, union or sum type
+    fn member_address(&mut self, obj: &Var, index: &Operand) -> CompileResult<(u64, Type)>
+    {
+        let (addr, typ) = self.object_address(obj)?;
+        let idx = self.operand(index)?.to_bits() as usize;
+        let member = match typ
+        {
+            Type::Array(ref at) => {
+                if idx >= at.len {
+                    return runtime_error(format!("index {} out of bounds, the length of the array is {}", idx, at.len));
+                }
+                (addr + (idx * self.layout.stride_of(&at.element_type)) as u64, at.element_type.clone())
+            },
+
+            Type::Slice(_) | Type::String => {
+                let (data, len, element_type) = self.sequence_operand(&Operand::Var(obj.clone()))?;
+                if idx as u64 >= len {
+                    return runtime_error(format!("index {} out of bounds, the length is {}", idx, len));
+                }
+                (data + (idx * self.layout.stride_of(&element_type)) as u64, element_type)
+            },
+
+            Type::Struct(ref st) => (addr + self.layout.offset_of(&typ, idx) as u64, st.members[idx].typ.clone()),
+            Type::Union(ref st) => (addr, st.members[idx].typ.clone()),
+            Type::Sum(ref st) => (addr + self.layout.offset_of(&typ, 1) as u64, st.cases[idx].typ.clone()),
+            _ => return runtime_error(format!("type {} has no members", typ)),
+        };
+        Ok(member)
+    }
+
+    fn get_property(&mut self, obj: &Var, prop: ByteCodeProperty) -> CompileResult<Value>
+    {
+        let (addr, typ) = self.object_address(obj)?;
+        match (&typ, prop)
+        {
+            (&Type::Array(ref at), ByteCodeProperty::Len) => Ok(Value::UInt(at.len as u64)),
+            (&Type::Array(_), ByteCodeProperty::Data) => Ok(Value::Pointer(addr)),
+            (&Type::Slice(_), ByteCodeProperty::Len) |
+            (&Type::String, ByteCodeProperty::Len) => Ok(Value::UInt(self.slice_parts(&self.read(addr, &typ)?).1)),
+            (&Type::Slice(_), ByteCodeProperty::Data) |
+            (&Type::String, ByteCodeProperty::Data) => Ok(Value::Pointer(self.slice_parts(&self.read(addr, &typ)?).0)),
+            (&Type::Interface(_), ByteCodeProperty::Data) => self.read(addr, &ptr_type(Type::Void)),
+            (&Type::Sum(ref st), ByteCodeProperty::SumTypeIndex) => {
+                match self.sum_niche(addr, st) {
+                    Some((niche_addr, niche)) => {
+                        let idx = if self.read_pointer(niche_addr)? == 0 {niche.empty_case} else {niche.data_case};
+                        Ok(Value::UInt(idx as u64))
+                    },
+                    None => self.read(addr, &Type::UInt(self.layout.int_size)),
+                }
+            },
+            _ => runtime_error(format!("type {} has no property {}", typ, prop)),
+        }
+    }
+
+    // Address of the pointer which determines the case of a sum type with a niche
+    fn sum_niche(&self, addr: u64, st: &SumType) -> Option<(u64, SumTypeNiche)>
+    {
+        st.niche().map(|niche| {
+            let case_type = &st.cases[niche.data_case].typ;
+            (addr + self.layout.offset_of(case_type, niche.member) as u64, niche)
+        })
+    }
+
+    fn unary_op(&self, op: UnaryOperator, value: Value) -> CompileResult<Value>
+    {
+        match (op, value)
+        {
+            (UnaryOperator::Sub, Value::Int(v)) => Ok(Value::Int(v.wrapping_neg())),
+            (UnaryOperator::Sub, Value::UInt(v)) => Ok(Value::UInt(v.wrapping_neg())),
+            (UnaryOperator::Sub, Value::Float(v)) => Ok(Value::Float(-v)),
+            (UnaryOperator::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+            (op, value) => runtime_error(format!("operator {} not supported on {:?}", op, value)),
+        }
+    }
+
+    fn string_contents(&self, value: &Value) -> CompileResult<Vec<u8>>
+    {
+        let (data, len) = self.slice_parts(value);
+        self.memory.read(data, len as usize).map(|bytes| bytes.to_vec())
+    }
+
+    fn binary_op(&self, op: BinaryOperator, left_type: &Type, left: &Value, right: &Value) -> CompileResult<Value>
+    {
+        let is_division = op == BinaryOperator::Div || op == BinaryOperator::Mod;
+        let result = match (left, right)
+        {
+            (&Value::Int(a), _) => {
+                let b = right.to_bits() as i64;
+                if is_division && b == 0 {
+                    return runtime_error("division by zero".into());
+                }
+
+                match op
+                {
+                    BinaryOperator::Add => Some(Value::Int(a.wrapping_add(b))),
+                    BinaryOperator::Sub => Some(Value::Int(a.wrapping_sub(b))),
+                    BinaryOperator::Mul => Some(Value::Int(a.wrapping_mul(b))),
+                    BinaryOperator::Div => Some(Value::Int(a.wrapping_div(b))),
+                    BinaryOperator::Mod => Some(Value::Int(a.wrapping_rem(b))),
+                    _ => compare(op, a, b),
+                }
+            },
+
+            (&Value::UInt(a), _) => {
+                let b = right.to_bits();
+                if is_division && b == 0 {
+                    return runtime_error("division by zero".into());
+                }
+
+                match op
+                {
+                    BinaryOperator::Add => Some(Value::UInt(a.wrapping_add(b))),
+                    BinaryOperator::Sub => Some(Value::UInt(a.wrapping_sub(b))),
+                    BinaryOperator::Mul => Some(Value::UInt(a.wrapping_mul(b))),
+                    BinaryOperator::Div => Some(Value::UInt(a / b)),
+                    BinaryOperator::Mod => Some(Value::UInt(a % b)),
+                    _ => compare(op, a, b),
+                }
+            },
+
+            (&Value::Float(a), &Value::Float(b)) => match op
+            {
+                BinaryOperator::Add => Some(Value::Float(a + b)),
+                BinaryOperator::Sub => Some(Value::Float(a - b)),
+                BinaryOperator::Mul => Some(Value::Float(a * b)),
+                BinaryOperator::Div => Some(Value::Float(a / b)),
+                _ => compare(op, a, b),
+            },
+
+            (&Value::Char(a), &Value::Char(b)) => compare(op, a, b),
+            (&Value::Pointer(a), &Value::Pointer(b)) => compare(op, a, b),
+
+            (&Value::Bool(a), &Value::Bool(b)) => match op
+            {
+                BinaryOperator::And => Some(Value::Bool(a && b)),
+                BinaryOperator::Or => Some(Value::Bool(a || b)),
+                BinaryOperator::Equals | BinaryOperator::NotEquals => compare(op, a, b),
+                _ => None,
+            },
+
+            (&Value::Bytes(ref a), &Value::Bytes(ref b)) if op == BinaryOperator::Equals || op == BinaryOperator::NotEquals => {
+                if *left_type == Type::String {
+                    compare(op, self.string_contents(left)?, self.string_contents(right)?)
+                } else {
+                    compare(op, a, b)
+                }
+            },
+
+            _ => None,
+        };
+
+        match result {
+            Some(v) => Ok(v),
+            None => runtime_error(format!("operator {} not supported on type {}", op, left_type)),
+        }
+    }
+
+    fn cast(&mut self, dst_type: &Type, src: &Operand) -> CompileResult<Value>
+    {
+        let src_type = src.get_type(self.layout.int_size);
+        if let (&Type::Pointer(_), &Type::Array(_), &Operand::Var(ref v)) = (dst_type, &src_type, src) {
+            return self.var_address(v).map(Value::Pointer);
+        }
+
+        let value = self.operand(src)?;
+        let result = match (dst_type, &value)
+        {
+            (&Type::Int(_), &Value::Float(f)) => Value::Int(f as i64),
+            (&Type::Int(_), _) => Value::Int(value.to_bits() as i64),
+            (&Type::UInt(_), &Value::Float(f)) => Value::UInt(f as u64),
+            (&Type::UInt(_), _) => Value::UInt(value.to_bits()),
+            (&Type::Float(_), &Value::Int(v)) => Value::Float(v as f64),
+            (&Type::Float(_), &Value::UInt(v)) => Value::Float(v as f64),
+            (&Type::Float(_), &Value::Float(v)) => Value::Float(v),
+            (&Type::Char, &Value::Int(_)) |
+            (&Type::Char, &Value::UInt(_)) => Value::Char(::std::char::from_u32(value.to_bits() as u32).unwrap_or('\u{fffd}')),
+            (&Type::Pointer(_), &Value::Pointer(p)) => Value::Pointer(p),
+            _ => return runtime_error(format!("cast from type {} to type {} is not allowed", src_type, dst_type)),
+        };
+        Ok(result)
+    }
+
+    // Reinterpret the lower bits of v as a value of type typ
+    fn from_bits(&self, v: u64, typ: &Type) -> Value
+    {
+        let mut bytes = [0u8; 8];
+        self.layout.encode(&Value::UInt(v), &Type::UInt(IntSize::I64), &mut bytes);
+        self.layout.decode(&bytes, typ)
+    }
+
+    fn swap_bytes(&self, value: &Value, typ: &Type) -> Value
+    {
+        let size = self.layout.size_of(typ);
+        let mut bytes = vec![0; size];
+        self.layout.encode(value, typ, &mut bytes);
+        bytes.reverse();
+        self.layout.decode(&bytes, typ)
+    }
+
+    // Address in the bytes of a slice, for the unaligned loads and stores
+    fn byte_offset(&mut self, bytes: &Operand, offset: &Operand, size: usize) -> CompileResult<u64>
+    {
+        let (data, len, _) = self.sequence_operand(bytes)?;
+        let offset = self.operand(offset)?.to_bits();
+        if offset.saturating_add(size as u64) > len {
+            return runtime_error(format!("access of {} bytes at offset {} is out of bounds, the length is {}", size, offset, len));
+        }
+        Ok(data + offset)
+    }
+
+    // The splitmix64 generator, the same as the compiled runtime uses
+    fn random(&mut self) -> u64
+    {
+        let state = self.random_state.unwrap_or(self.random_seed).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        self.random_state = Some(state);
+        let z = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        let z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn intrinsic(&mut self, dst: Option<&Var>, intrinsic: Intrinsic, args: &[Operand]) -> CompileResult<Value>
+    {
+        let int_size = self.layout.int_size;
+        let result = match intrinsic
+        {
+            Intrinsic::Likely |
+            Intrinsic::Unlikely |
+            Intrinsic::ToLittleEndian |
+            Intrinsic::FromLittleEndian => self.operand(&args[0])?,
+
+            Intrinsic::Prefetch => Value::Void,
+
+            Intrinsic::Select |
+            Intrinsic::ConstantTimeSelect => {
+                let cond = self.operand(&args[0])?.to_bool();
+                let on_true = self.operand(&args[1])?;
+                let on_false = self.operand(&args[2])?;
+                if cond {on_true} else {on_false}
+            },
+
+            Intrinsic::ConstantTimeEq => {
+                let (left_data, left_len, element_type) = self.sequence_operand(&args[0])?;
+                let (right_data, right_len, _) = self.sequence_operand(&args[1])?;
+                if left_len != right_len {
+                    Value::Bool(false)
+                } else {
+                    let size = left_len as usize * self.layout.stride_of(&element_type);
+                    Value::Bool(self.memory.read(left_data, size)? == self.memory.read(right_data, size)?)
+                }
+            },
+
+            Intrinsic::ToBigEndian |
+            Intrinsic::FromBigEndian => {
+                let value = self.operand(&args[0])?;
+                self.swap_bytes(&value, &args[0].get_type(int_size))
+            },
+
+            Intrinsic::LoadLittleEndian |
+            Intrinsic::LoadBigEndian => {
+                let typ = dst.map(|d| d.typ.clone()).unwrap_or(Type::Void);
+                let size = self.layout.size_of(&typ);
+                let addr = self.byte_offset(&args[0], &args[1], size)?;
+                let value = self.read(addr, &typ)?;
+                if intrinsic == Intrinsic::LoadBigEndian {self.swap_bytes(&value, &typ)} else {value}
+            },
+
+            Intrinsic::StoreLittleEndian |
+            Intrinsic::StoreBigEndian => {
+                let typ = args[2].get_type(int_size);
+                let value = self.operand(&args[2])?;
+                let value = if intrinsic == Intrinsic::StoreBigEndian {self.swap_bytes(&value, &typ)} else {value};
+                let addr = self.byte_offset(&args[0], &args[1], self.layout.size_of(&typ))?;
+                self.write(addr, &typ, &value)?;
+                Value::Void
+            },
+
+            Intrinsic::CountOnes |
+            Intrinsic::LeadingZeros |
+            Intrinsic::TrailingZeros => {
+                let bits = self.layout.size_of(&args[0].get_type(int_size)) as u32 * 8;
+                let v = self.operand(&args[0])?.to_bits() & (u64::max_value() >> (64 - bits));
+                let count = match intrinsic {
+                    Intrinsic::CountOnes => v.count_ones(),
+                    Intrinsic::LeadingZeros => v.leading_zeros() - (64 - bits),
+                    _ => v.trailing_zeros().min(bits),
+                };
+                Value::UInt(count as u64)
+            },
+
+            Intrinsic::RotateLeft |
+            Intrinsic::RotateRight => {
+                let typ = args[0].get_type(int_size);
+                let bits = self.layout.size_of(&typ) as u64 * 8;
+                let mask = u64::max_value() >> (64 - bits);
+                let v = self.operand(&args[0])?.to_bits() & mask;
+                let amount = self.operand(&args[1])?.to_bits() & (bits - 1);
+                let other_amount = (bits - amount) & (bits - 1);
+                let (shl_amount, shr_amount) = if intrinsic == Intrinsic::RotateLeft {(amount, other_amount)} else {(other_amount, amount)};
+                self.from_bits(((v << shl_amount) | (v >> shr_amount)) & mask, &typ)
+            },
+
+            Intrinsic::Random => Value::UInt(self.random()),
+
+            // Void means nil
+            Intrinsic::ParseInt => {
+                let s = self.operand(&args[0])?;
+                let text = self.string_contents(&s)?;
+                let bits = self.layout.size_of(&Type::Int(int_size)) as u32 * 8;
+                match str::from_utf8(&text).ok().and_then(|s| s.parse::<i64>().ok()) {
+                    Some(v) if bits == 64 || (v >> (bits - 1)) == 0 || (v >> (bits - 1)) == -1 => Value::Int(v),
+                    _ => Value::Void,
+                }
+            },
+
+            Intrinsic::ParseFloat => {
+                let s = self.operand(&args[0])?;
+                let text = self.string_contents(&s)?;
+                match str::from_utf8(&text).ok().and_then(|s| s.parse::<f64>().ok()) {
+                    Some(v) => Value::Float(v),
+                    None => Value::Void,
+                }
+            },
+
+            Intrinsic::IntToString |
+            Intrinsic::FloatToString => {
+                let text = match self.operand(&args[0])? {
+                    Value::Int(v) => format_int(v.wrapping_abs() as u64, v < 0, self.operand(&args[1])?.to_bits()),
+                    Value::UInt(v) => format_int(v, false, self.operand(&args[1])?.to_bits()),
+                    Value::Float(v) => format_fixed(v, self.operand(&args[1])?.to_bits() as usize),
+                    value => return runtime_error(format!("cannot convert {:?} to a string", value)),
+                };
+                let data = self.memory.allocate(text.len(), AllocationKind::Heap);
+                self.memory.write(data, text.as_bytes())?;
+                self.slice_value(data, text.len() as u64)
+            },
+
+            Intrinsic::HexEncode => {
+                let bytes = self.operand(&args[0])?;
+                let text = hex_encode(&self.string_contents(&bytes)?);
+                let data = self.memory.allocate(text.len(), AllocationKind::Heap);
+                self.memory.write(data, text.as_bytes())?;
+                self.slice_value(data, text.len() as u64)
+            },
+
+            Intrinsic::HexDecode => {
+                let s = self.operand(&args[0])?;
+                match hex_decode(&self.string_contents(&s)?) {
+                    Some(bytes) => {
+                        let data = self.memory.allocate(bytes.len(), AllocationKind::Heap);
+                        self.memory.write(data, &bytes)?;
+                        self.slice_value(data, bytes.len() as u64)
+                    },
+                    None => Value::Void,
+                }
+            },
+
+            Intrinsic::FromUtf8 => {
+                let bytes = self.operand(&args[0])?;
+                if str::from_utf8(&self.string_contents(&bytes)?).is_ok() {bytes} else {Value::Void}
+            },
+        };
+        Ok(result)
+    }
+
+    fn execute(&mut self, instr: &'a Instruction) -> CompileResult<Flow<'a>>
+    {
+        let int_size = self.layout.int_size;
+        match *instr
+        {
+            Instruction::Store{ref dst, ref src} => {
+                let value = self.operand(src)?;
+                self.store(dst, &src.get_type(int_size), value)?;
+            },
+
+            Instruction::Load{ref dst, ref ptr} => {
+                let mut addr = self.var_address(ptr)?;
+                let mut typ = ptr.typ.clone();
+                while let Type::Pointer(inner) = typ.clone() {
+                    if typ == dst.typ {
+                        break;
+                    }
+                    addr = self.read_pointer(addr)?;
+                    typ = (*inner).clone();
+                }
+
+                let value = match typ {
+                    Type::Optional(ref inner) if dst.typ != typ => self.read(addr + self.layout.offset_of(&typ, 1) as u64, inner)?,
+                    _ => self.read(addr, &typ)?,
+                };
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &value)?;
+            },
+
+            Instruction::LoadMember{ref dst, ref obj, ref member_index} |
+            Instruction::AddressOfMember{ref dst, ref obj, ref member_index} => {
+                let (addr, typ) = self.member_address(obj, member_index)?;
+                let dst_addr = self.var_address(dst)?;
+                if dst.typ == ptr_type(typ.clone()) {
+                    self.write(dst_addr, &dst.typ, &Value::Pointer(addr))?;
+                } else {
+                    let value = self.read(addr, &typ)?;
+                    self.store_value(dst_addr, &dst.typ, &typ, value)?;
+                }
+            },
+
+            Instruction::StoreMember{ref obj, ref member_index, ref src} => {
+                let value = self.operand(src)?;
+                let (addr, typ) = self.member_address(obj, member_index)?;
+                self.store_value(addr, &typ, &src.get_type(int_size), value)?;
+            },
+
+            Instruction::AddressOf{ref dst, ref obj} => {
+                let value = if obj.typ == dst.typ {
+                    self.read_var(obj)?
+                } else {
+                    Value::Pointer(self.var_address(obj)?)
+                };
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &value)?;
+            },
+
+            Instruction::GetProperty{ref dst, ref obj, prop} => {
+                let value = self.get_property(obj, prop)?;
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &value)?;
+            },
+
+            Instruction::SetProperty{ref obj, prop, val} => {
+                let (addr, typ) = self.object_address(obj)?;
+                match (&typ, prop) {
+                    (&Type::Sum(ref st), ByteCodeProperty::SumTypeIndex) => {
+                        match self.sum_niche(addr, st) {
+                            // The data case gets a non null pointer when its members are initialized
+                            Some((niche_addr, niche)) => {
+                                if niche.empty_case == val {
+                                    self.write(niche_addr, &ptr_type(Type::Void), &Value::Pointer(0))?;
+                                }
+                            },
+                            None => self.write(addr, &Type::UInt(int_size), &Value::UInt(val as u64))?,
+                        }
+                    },
+                    _ => return runtime_error(format!("cannot set property {} of type {}", prop, typ)),
+                }
+            },
+
+            Instruction::UnaryOp{ref dst, op, ref src} => {
+                let value = self.operand(src)?;
+                let result = self.unary_op(op, value)?;
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &result)?;
+            },
+
+            Instruction::BinaryOp{ref dst, op, ref left, ref right} => {
+                let l = self.operand(left)?;
+                let r = self.operand(right)?;
+                let result = self.binary_op(op, &left.get_type(int_size), &l, &r)?;
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &result)?;
+            },
+
+            Instruction::Call{ref dst, ref func, ref args} => {
+                // Variables holding a function pointer, hide functions with the same name
+                let (callee, env) = if self.frame().vars.contains_key(func) || self.globals.contains_key(func) {
+                    let addr = self.var_address(&Var::named(func, ptr_type(Type::Void)))?;
+                    let ptr = self.read_pointer(addr)?;
+                    match function_index(ptr) {
+                        Some(idx) if idx < self.function_names.len() => {
+                            let name = self.function_names[idx].clone();
+                            (self.get_function(&name), self.closures.get(&idx).cloned())
+                        },
+                        _ => return runtime_error(format!("{} does not point to a function", func)),
+                    }
+                } else {
+                    (self.get_function(func), None)
+                };
+
+                let callee = match callee {
+                    Some(callee) => callee,
+                    None => return runtime_error(format!("unknown function {}", func)),
+                };
+
+                let mut values = Vec::with_capacity(args.len() + 1);
+                if let Some(env) = env {
+                    values.push((Value::Pointer(env), ptr_type(Type::Void)));
+                }
+                for arg in args {
+                    values.push((self.operand(arg)?, arg.get_type(int_size)));
+                }
+                return Ok(Flow::Call(callee, values, dst.clone()));
+            },
+
+            Instruction::InterfaceCall{ref dst, ref obj, index, ref args} => {
+                let value = self.read_var(obj)?;
+                let mut bytes = vec![0; self.layout.size_of(&obj.typ)];
+                self.layout.encode(&value, &obj.typ, &mut bytes);
+                let data = self.layout.decode(&bytes[..8], &ptr_type(Type::Void));
+                let table = self.layout.decode(&bytes[8..], &ptr_type(Type::Void)).to_bits();
+                let ptr = self.read_pointer(table + index as u64 * 8)?;
+                let callee = match function_index(ptr) {
+                    Some(idx) if idx < self.function_names.len() => {
+                        let name = self.function_names[idx].clone();
+                        self.get_function(&name)
+                    },
+                    _ => None,
+                };
+
+                let callee = match callee {
+                    Some(callee) => callee,
+                    None => return runtime_error(format!("function table of {} has no function at index {}", obj.name, index)),
+                };
+
+                let mut values = Vec::with_capacity(args.len() + 1);
+                values.push((data, ptr_type(Type::Void)));
+                for arg in args {
+                    values.push((self.operand(arg)?, arg.get_type(int_size)));
+                }
+                return Ok(Flow::Call(callee, values, dst.clone()));
+            },
+
+            Instruction::MakeInterface{ref dst, ref obj, ref functions} => {
+                let data = self.read_var(obj)?;
+                let table = Value::Pointer(self.vtable(functions)?);
+                let mut bytes = vec![0; self.layout.size_of(&dst.typ)];
+                self.layout.encode(&data, &ptr_type(Type::Void), &mut bytes[..8]);
+                self.layout.encode(&table, &ptr_type(Type::Void), &mut bytes[8..]);
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &Value::Bytes(bytes))?;
+            },
+
+            Instruction::MakeClosure{ref dst, ref func, ref env} => {
+                let env_addr = self.var_address(env)?;
+                let addr = self.closure_address(func, env_addr);
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &Value::Pointer(addr))?;
+            },
+
+            Instruction::Intrinsic{ref dst, intrinsic, ref args} => {
+                let result = self.intrinsic(dst.as_ref(), intrinsic, args)?;
+                if let Some(ref dst) = *dst {
+                    let dst_addr = self.var_address(dst)?;
+                    match dst.typ {
+                        // Parsing a number gives an optional
+                        Type::Optional(_) if result == Value::Void => self.write(dst_addr, &Type::Bool, &Value::Bool(false))?,
+                        Type::Optional(ref inner) => self.store_value(dst_addr, &dst.typ, inner, result)?,
+                        _ => self.write(dst_addr, &dst.typ, &result)?,
+                    }
+                }
+            },
+
+            Instruction::Slice{ref dst, ref src, ref start, ref len} => {
+                let (data, available, element_type) = self.sequence_operand(&Operand::Var(src.clone()))?;
+                let start = self.operand(start)?.to_bits();
+                let len = self.operand(len)?.to_bits();
+                if start.saturating_add(len) > available {
+                    return runtime_error(format!("slice of {} elements at {} is out of bounds, the length is {}", len, start, available));
+                }
+
+                let value = self.slice_value(data + start * self.layout.stride_of(&element_type) as u64, len);
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &value)?;
+            },
+
+            Instruction::MakeSlice{ref dst, ref data, ref len} => {
+                let data = self.read_var(data)?.to_bits();
+                let len = self.read_var(len)?.to_bits();
+                let value = self.slice_value(data, len);
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &value)?;
+            },
+
+            Instruction::Format{ref dst, ref parts} => {
+                let mut text = Vec::new();
+                for part in parts {
+                    match self.operand(part)? {
+                        Value::Int(v) => text.extend(v.to_string().bytes()),
+                        Value::UInt(v) => text.extend(v.to_string().bytes()),
+                        Value::Float(v) => text.extend(format_float(v).bytes()),
+                        Value::Char(c) => text.extend(c.to_string().bytes()),
+                        Value::Bool(b) => text.extend((if b {"true"} else {"false"}).bytes()),
+                        value @ Value::Bytes(_) => text.extend(self.string_contents(&value)?),
+                        value => return runtime_error(format!("cannot convert {:?} to a string", value)),
+                    }
+                }
+
+                let data = self.memory.allocate(text.len(), AllocationKind::Heap);
+                self.memory.write(data, &text)?;
+                let value = self.slice_value(data, text.len() as u64);
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &value)?;
+            },
+
+            Instruction::Cast{ref dst, ref src} => {
+                let value = self.cast(&dst.typ, src)?;
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &value)?;
+            },
+
+            Instruction::LoadOptionalFlag{ref dst, ref obj} => {
+                let (addr, typ) = self.object_address(obj)?;
+                let flag = match typ {
+                    Type::Optional(ref inner) if inner.has_null_niche() => Value::Bool(self.read_pointer(addr)? != 0),
+                    _ => self.read(addr, &Type::Bool)?,
+                };
+                let dst_addr = self.var_address(dst)?;
+                self.write(dst_addr, &dst.typ, &flag)?;
+            },
+
+            Instruction::StoreNil(ref var) => {
+                let (addr, typ) = self.object_address(var)?;
+                match typ {
+                    Type::Optional(ref inner) if inner.has_null_niche() => self.write(addr, inner, &Value::Pointer(0))?,
+                    _ => self.write(addr, &Type::Bool, &Value::Bool(false))?,
+                }
+            },
+
+            Instruction::StackAlloc(ref var) => {
+                let key = instr as *const Instruction;
+                let existing = self.frame().stack_allocs.get(&key).cloned();
+                match existing {
+                    Some(addr) => {
+                        let size = self.layout.size_of(&var.typ);
+                        self.frame().vars.insert(var.name.clone(), (addr, size));
+                    },
+                    None => {
+                        let addr = self.allocate_var(&var.name, &var.typ);
+                        self.frame().stack_allocs.insert(key, addr);
+                    },
+                }
+            },
+
+            Instruction::HeapAlloc(ref var) => {
+                let size = match var.typ {
+                    Type::Pointer(ref inner) => self.layout.size_of(inner),
+                    _ => self.layout.size_of(&var.typ),
+                };
+                let mem = self.memory.allocate(size, AllocationKind::Heap);
+                let addr = self.var_address(var)?;
+                self.write(addr, &ptr_type(Type::Void), &Value::Pointer(mem))?;
+            },
+
+            Instruction::Delete(ref var) => {
+                let ptr = self.read_var(var)?.to_bits();
+                if ptr != 0 {
+                    self.memory.free(ptr, AllocationKind::Heap)?;
+                }
+            },
+
+            Instruction::IncRef(ref var) => {
+                let ptr = self.read_var(var)?.to_bits();
+                self.memory.retain(ptr);
+            },
+
+            Instruction::DecRef(ref var) => {
+                let ptr = self.read_var(var)?.to_bits();
+                self.memory.release(ptr)?;
+                let addr = self.var_address(var)?;
+                self.write(addr, &var.typ, &Value::Pointer(0))?;
+            },
+
+            Instruction::BoundsCheck{ref index, ref len, inclusive, ref location} => {
+                let idx = self.operand(index)?;
+                let len = self.operand(len)?.to_bits();
+                if inclusive && idx.to_bits() > len {
+                    return runtime_error(format!("{}: slice bound {} is larger than {}", location, idx.to_bits(), len));
+                } else if !inclusive && idx.to_bits() >= len {
+                    let idx = match idx {
+                        Value::Int(v) => v.to_string(),
+                        _ => idx.to_bits().to_string(),
+                    };
+                    return runtime_error(format!("{}: index {} out of bounds, the length is {}", location, idx, len));
+                }
+            },
+
+            Instruction::Panic(ref msg) => {
+                let msg = self.operand(msg)?;
+                let contents = self.string_contents(&msg)?;
+                return runtime_error(String::from_utf8_lossy(&contents).trim_end().into());
+            },
+
+            Instruction::Log(ref msg) => {
+                let msg = self.operand(msg)?;
+                let contents = self.string_contents(&msg)?;
+                eprint!("{}", String::from_utf8_lossy(&contents));
+            },
+
+            Instruction::StartScope |
+            Instruction::EndScope |
+            Instruction::SourceLocation(_) |
+            Instruction::LoopHint(_) => (),
+
+            Instruction::Return(ref op) => {
+                let value = self.operand(op)?;
+                return Ok(Flow::Return(value, op.get_type(int_size)));
+            },
+
+            Instruction::ReturnVoid => return Ok(Flow::Return(Value::Void, Type::Void)),
+
+            Instruction::Branch(block) => return Ok(Flow::Branch(block)),
+
+            Instruction::BranchIf{ref cond, on_true, on_false} => {
+                let cond = self.operand(cond)?.to_bool();
+                return Ok(Flow::Branch(if cond {on_true} else {on_false}));
+            },
+
+            Instruction::Switch{ref cond, ref cases, default} => {
+                let value = self.operand(cond)?.to_bits();
+                for &(ref cst, bb) in cases {
+                    if self.constant(cst)?.to_bits() == value {
+                        return Ok(Flow::Branch(bb));
+                    }
+                }
+                return Ok(Flow::Branch(default));
+            },
+        }
+
+        Ok(Flow::Next)
+    }
+}
+
+// Interpret a module, returns the return value of main
+// Like the compiled runtime, @random is seeded from MENHIR_SEED, and a failure reports the seed if it was used
+pub fn run_module(module: &ByteCodeModule, int_size: IntSize) -> CompileResult<i64>
+{
+    let mut interpreter = Interpreter::new(module, int_size)?;
+    interpreter.random_seed = env::var(RANDOM_SEED_VARIABLE).ok().and_then(|seed| seed.parse().ok()).unwrap_or(0);
+    match (interpreter.run(), interpreter.random_state) {
+        (Err(CompileError::Other(msg)), Some(_)) => Err(CompileError::Other(format!("{}\nrandom seed: {}", msg, interpreter.random_seed))),
+        (result, _) => result,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use ast::IntSize;
+    use bytecode::{OptimizationLevel, optimize_module, DEFAULT_INLINE_THRESHOLD};
+    use bytecode::test::generate_byte_code;
+    use compileerror::CompileResult;
+    use super::run_module;
+
+    fn interpret(prog: &str) -> CompileResult<i64>
+    {
+        let mut bc_mod = generate_byte_code(prog, false)?;
+        optimize_module(&mut bc_mod, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+        run_module(&bc_mod, IntSize::I32)
+    }
+
+    #[test]
+    fn test_calls_and_structs()
+    {
+        let prog = r#"
+struct Vec2D:
+    x: int
+    y: int
+
+fn add(a: Vec2D, b: Vec2D) -> Vec2D:
+    Vec2D{a.x + b.x, a.y + b.y}
+
+fn fib(n: int) -> int:
+    if n < 2: n else fib(n - 1) + fib(n - 2)
+
+fn main() -> int:
+    let v = add(Vec2D{3, 4}, Vec2D{2, 2})
+    let f = fib
+    v.x * v.y + f(10)
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 85);
+    }
+
+    #[test]
+    fn test_member_arguments()
+    {
+        let prog = r#"
+struct Named:
+    name: string
+
+fn length(s: string) -> int: s.len as int
+
+fn Named.length(self) -> int: length(self.name)
+
+fn main() -> int:
+    let n = Named{"abc"}
+    n.length() + length("xy")
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 5);
+    }
+
+    #[test]
+    fn test_member_stores_and_rebinding()
+    {
+        let prog = r#"
+struct Small:
+    x: int
+
+struct Big:
+    x: int
+    name: string
+
+fn Big.rename(var self, other: Big):
+    self.name = other.name
+
+fn small(x: int) -> ?Small: Small{x}
+
+fn big(b: Big) -> ?Big: b
+
+fn main() -> int:
+    var a = Big{1, "a"}
+    a.rename(Big{2, "four"})
+    match small(3):
+        ?value => a.x += value.x
+        nil => a.x = 0
+    match big(a):
+        ?value => value.x + value.name.len as int
+        nil => 0
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 8);
+    }
+
+    #[test]
+    fn test_field_reflection()
+    {
+        let prog = r#"
+struct Point:
+    name: string
+    x: int
+
+fn describe(value: $T) -> string:
+    let names = @field_names($T)
+    let values = @field_values(value)
+    var out = ""
+    var i = 0u
+    while i < names.len:
+        out = @format(out, names[i], "=", values[i], ";")
+        i += 1u
+    out
+
+fn main() -> int:
+    let d = describe(Point{"abc", 42})
+    @assert_eq(d, "name=abc;x=42;")
+    @field_names(Point).len as int + d.len as int
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 16);
+    }
+
+    #[test]
+    fn test_sum_types_and_optionals()
+    {
+        let prog = r#"
+enum Option:
+    Some{v: int}
+    None
+
+fn unwrap_or(opt: Option, default: int) -> int:
+    match opt:
+        Option::Some{i} => i
+        Option::None => default
+
+fn optional_or(opt: ?int, default: int) -> int:
+    opt || default
+
+fn main() -> int:
+    unwrap_or(Option::Some{5}, 1) + unwrap_or(Option::None, 1) + optional_or(41, 1) + optional_or(nil, 1)
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 48);
+    }
+
+    #[test]
+    fn test_arrays_and_slices()
+    {
+        let prog = r#"
+fn main() -> int:
+    var data = [1, 2, 3, 4, 5]
+    data[0] = 10
+    let s = @slice(data as *int, data.len)
+    var sum = 0
+    for i in s:
+        sum += i
+    sum
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 24);
+    }
+
+    #[test]
+    fn test_runtime_errors()
+    {
+        let prog = r#"
+fn div(a: int, b: int) -> int: a / b
+
+fn main() -> int: div(4, 0)
+"#;
+        let msg = interpret(prog).expect_err("Division by zero must fail").to_string();
+        assert!(msg.contains("division by zero"));
+        assert!(msg.contains("in test::div"));
+    }
+
+    #[test]
+    fn test_bounds_checks()
+    {
+        let prog = r#"
+fn get(data: int[], idx: int) -> int: data[idx]
+
+fn main() -> int:
+    let s = "abc"
+    var data = [1, 2, 3]
+    get(@slice(data as *int, data.len), 2) + (s[1] as int)
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 101);
+
+        let prog = r#"
+fn get(data: int[], idx: int) -> int: data[idx]
+
+fn main() -> int:
+    var data = [1, 2, 3]
+    get(@slice(data as *int, data.len), -1)
+"#;
+        let msg = interpret(prog).expect_err("Negative index must fail").to_string();
+        assert!(msg.contains("index -1 out of bounds, the length is 3"));
+        assert!(msg.contains(":2:"));
+    }
+
+    #[test]
+    fn test_short_circuit()
+    {
+        let prog = r#"
+fn starts_with_a(s: string) -> bool: s.len > 0u && s[0u] == (97u as uint8)
+
+fn empty_or_a(s: string) -> bool: s.len == 0u || s[0u] == (97u as uint8)
+
+fn main() -> int:
+    if !starts_with_a("") && starts_with_a("abc") && empty_or_a("") && !empty_or_a("b"): 1 else 0
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 1);
+    }
+
+    #[test]
+    fn test_slicing()
+    {
+        let prog = r#"
+fn main() -> int:
+    let data = [1, 2, 3, 4]
+    let s = data[1..3]
+    s[0] * 10 + s[1] + (s.len as int) * 100
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 223);
+
+        let prog = r#"
+fn tail(data: int[], start: int) -> int[]: data[start..data.len]
+
+fn main() -> int:
+    let data = [1, 2, 3]
+    tail(data[0..3], 4).len as int
+"#;
+        let msg = interpret(prog).expect_err("Slice out of bounds must fail").to_string();
+        assert!(msg.contains("slice bound 4 is larger than 3"));
+
+        let prog = r#"
+fn main() -> int:
+    let s = "hello.mhr"
+    let ext = s[6u..s.len]
+    if ext == "mhr" && s[0u..5u] == "hello": ext.len as int else 0
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 3);
+    }
+
+    #[test]
+    fn test_random()
+    {
+        let prog = r#"
+fn main() -> int:
+    let a = @random()
+    let b = @random()
+    if a == b: 0 else (a % (1000u as uint64)) as int
+"#;
+        // The first value of splitmix64 with seed 0
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 535);
+
+        let prog = r#"
+fn div(a: int, b: int) -> int: a / b
+
+fn main() -> int:
+    let r = @random()
+    div(r as int, 0)
+"#;
+        let msg = interpret(prog).expect_err("Division by zero must fail").to_string();
+        assert!(msg.contains("random seed: 0"));
+    }
+
+    #[test]
+    fn test_assert_eq()
+    {
+        let prog = r#"
+fn main() -> int:
+    let a = [1, 2, 3]
+    @assert_eq(a[0..2], a[0..2])
+    @assert_eq("foo", "foo")
+    @assert_eq(a[2], 3)
+    7
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 7);
+
+        let prog = r#"
+fn main() -> int:
+    let a = [1, 2, 3]
+    let b = [1, 5, 3]
+    @assert_eq(a, b)
+    0
+"#;
+        let msg = interpret(prog).expect_err("Assertion must fail").to_string();
+        assert!(msg.contains("left: [1, 2, 3]"));
+        assert!(msg.contains("right: [1, 5, 3]"));
+        assert!(msg.contains("first difference at index 1"));
+
+        let prog = r#"
+fn main() -> int:
+    @assert_eq("foo", "foobar")
+    0
+"#;
+        let msg = interpret(prog).expect_err("Assertion must fail").to_string();
+        assert!(msg.contains("left: \"foo\""));
+        assert!(msg.contains("right: \"foobar\""));
+        assert!(msg.contains("first difference at byte 3"));
+
+        let prog = r#"
+fn main() -> int:
+    @assert_eq(4 * 2, 7)
+    0
+"#;
+        let msg = interpret(prog).expect_err("Assertion must fail").to_string();
+        assert!(msg.contains("assertion failed: left == right\n  left: 8\n right: 7"));
+    }
+
+    #[test]
+    fn test_assert_and_panic()
+    {
+        let prog = r#"
+fn check(n: int) -> int:
+    @assert(n > 0, "n must be positive, got ", n)
+    if n > 10:
+        @panic("too large: ", n)
+    n * 2
+
+fn main() -> int:
+    @assert(true)
+    check(4)
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 8);
+
+        let prog = r#"
+fn main() -> int:
+    let n = -3
+    @assert(n > 0, "n must be positive, got ", n)
+    0
+"#;
+        let msg = interpret(prog).expect_err("Assertion must fail").to_string();
+        assert!(msg.contains(":4:5: assertion failed: n must be positive, got -3"));
+
+        let prog = r#"
+fn main() -> int:
+    @panic("giving up")
+    0
+"#;
+        let msg = interpret(prog).expect_err("Panic must stop the program").to_string();
+        assert!(msg.contains(":3:5: panic: giving up"));
+    }
+
+    #[test]
+    fn test_string_interpolation()
+    {
+        let prog = r#"
+fn main() -> int:
+    let n = 42
+    let s = "n = ${n}, half = ${n as float / 4.0}, ${n > 40} ${'x'}"
+    if s == "n = 42, half = 10.5, true x": 1 else 0
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 1);
+    }
+
+    #[test]
+    fn test_ref_arguments()
+    {
+        let prog = r#"
+struct Point:
+    x: int
+    y: int
+
+fn swap(ref a: int, ref b: int):
+    let tmp = a
+    a = b
+    b = tmp
+
+fn shift(ref p: Point, ref steps: int):
+    p.x += 10
+    p = Point{p.x, p.y * 2}
+    var y = p.y
+    swap(steps, y)
+    p.y = y
+
+fn main() -> int:
+    var p = Point{1, 2}
+    var steps = 5
+    shift(p, steps)
+    p.x * 100 + p.y * 10 + steps
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 1154);
+    }
+
+    #[test]
+    fn test_interface_objects()
+    {
+        let prog = r#"
+interface Counter:
+    fn count(self) -> int
+
+struct Fixed:
+    n: int
+
+struct Doubled:
+    n: int
+
+fn Fixed.count(self) -> int: self.n
+
+fn Doubled.count(self) -> int: self.n * 2
+
+fn sum(a: Counter, b: Counter) -> int: a.count() + b.count()
+
+fn main() -> int:
+    let f = Fixed{5}
+    let d = Doubled{6}
+    sum(&f, &d) + sum(&d, &d)
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 41);
+    }
+
+    #[test]
+    fn test_closures()
+    {
+        let prog = r#"
+fn apply(x: int, f: fn(int) -> int) -> int:
+    f(x)
+
+fn scaled(factor: int, offset: int) -> int:
+    let add = fn(x: int) -> x + offset
+    apply(3, fn(x) -> x * factor) + apply(1, add) + apply(2, fn(x) -> apply(x, fn(y) -> y * factor + offset))
+
+fn main() -> int:
+    scaled(4, 5)
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 31);
+    }
+
+    #[test]
+    fn test_inlined_calls()
+    {
+        let prog = r#"
+fn clamp(x: int, lo: int, hi: int) -> int:
+    if x < lo:
+        return lo
+    if x > hi: hi else x
+
+fn main() -> int:
+    var total = 0
+    var i = 0
+    while i < 5:
+        total += clamp(i * 3, 2, 9)
+        i += 1
+    total
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 29);
+    }
+
+    #[test]
+    fn test_tail_calls()
+    {
+        let prog = r#"
+fn count(n: int, acc: int) -> int:
+    if n == 0:
+        return acc
+    count(n - 1, acc + 2)
+
+fn gcd(a: int, b: int) -> int: if b == 0: a else gcd(b, a % b)
+
+fn main() -> int:
+    count(1000000, 0) / 1000 + gcd(84, 36)
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 2012);
+    }
+
+    #[test]
+    fn test_reference_counting()
+    {
+        let prog = r#"
+struct Counter:
+    value: int
+
+struct Holder:
+    counter: *Counter
+
+fn make(v: int) -> *Counter:
+    new Counter{v}
+
+fn main() -> int:
+    var total = 0, i = 0
+    while i < 10:
+        let c = make(i)
+        total = total + c.value
+        i = i + 1
+    var p = new Counter{1}
+    let h = Holder{p}
+    p = new Counter{2}
+    let d = new Counter{3}
+    delete d
+    total + h.counter.value + p.value
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 48);
+    }
+
+    #[test]
+    fn test_number_conversions()
+    {
+        let prog = r#"
+fn unwrap_or(opt: ?int, default: int) -> int:
+    match opt:
+        ?value => value
+        nil => default
+
+fn main() -> int:
+    let big = unwrap_or(@parse_int("3000000000"), 1)
+    if @parse_float("x") == nil && @int_to_string(-255, 16) == "-ff" && @float_to_string(2.5, 1) == "2.5":
+        unwrap_or(@parse_int("-42"), 0) + big
+    else
+        0
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), -41);
+    }
+
+    #[test]
+    fn test_binary_data()
+    {
+        let prog = r#"
+fn main() -> int:
+    let magic = b"\x7fELF"
+    let header = @load_be(uint32, magic, 0u)
+    match @hex_decode("7F454c46"):
+        ?bytes =>
+            if bytes == magic && @hex_encode(magic) == "7f454c46" && @hex_decode("7f4") == nil && "ab" != "abc":
+                (header / 16777216u) as int
+            else
+                0
+        nil => 1
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 127);
+
+        let prog = r#"
+fn main() -> int:
+    match @from_utf8(b"caf\xc3\xa9"):
+        ?s => if @from_utf8(b"\xc3") == nil && @from_utf8(b"\xed\xa0\x80") == nil: s.len as int else 0
+        nil => 1
+"#;
+        assert_eq!(interpret(prog).expect("Interpreter failed"), 5);
+    }
+}
@@ -0,0 +1,415 @@
+use std::cmp::max;
+use ast::{Type, IntSize, FloatSize};
+
+// A value in the interpreter, aggregates are kept as the bytes of their memory representation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value
+{
+    Void,
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Char(char),
+    Bool(bool),
+    Pointer(u64),
+    Bytes(Vec<u8>),
+}
+
+impl Value
+{
+    // The bits of a scalar value, integers are zero or sign extended to 64 bits
+    pub fn to_bits(&self) -> u64
+    {
+        match *self
+        {
+            Value::Int(v) => v as u64,
+            Value::UInt(v) |
+            Value::Pointer(v) => v,
+            Value::Float(v) => v.to_bits(),
+            Value::Char(c) => c as u64,
+            Value::Bool(b) => b as u64,
+            Value::Void |
+            Value::Bytes(_) => panic!("Internal Compiler Error: {:?} is not a scalar value", self),
+        }
+    }
+
+    pub fn to_bool(&self) -> bool
+    {
+        match *self
+        {
+            Value::Bool(b) => b,
+            _ => self.to_bits() != 0,
+        }
+    }
+}
+
+fn round_up(offset: usize, alignment: usize) -> usize
+{
+    (offset + alignment - 1) / alignment * alignment
+}
+
+fn int_bytes(int_size: IntSize) -> usize
+{
+    int_size.size_in_bits() as usize / 8
+}
+
+fn read_uint(bytes: &[u8], size: usize) -> u64
+{
+    bytes[..size].iter().enumerate().fold(0, |v, (idx, b)| v | (*b as u64) << (8 * idx))
+}
+
+fn write_uint(bytes: &mut [u8], size: usize, v: u64)
+{
+    for (idx, b) in bytes[..size].iter_mut().enumerate() {
+        *b = (v >> (8 * idx)) as u8;
+    }
+}
+
+pub fn sign_extend(v: u64, bits: u32) -> i64
+{
+    if bits >= 64 {
+        v as i64
+    } else {
+        let shift = 64 - bits;
+        ((v << shift) as i64) >> shift
+    }
+}
+
+/*
+Memory layout of types, this follows the C rules of the LLVM backend: members are aligned to their natural
+alignment, slices and strings are a pointer followed by a length, optionals a flag followed by the value and
+sum types the case index followed by the largest case. Types with a niche have the layout of their inner type.
+Pointers are always 8 bytes and little endian.
+*/
+pub struct Layout
+{
+    pub int_size: IntSize,
+}
+
+impl Layout
+{
+    pub fn size_of(&self, typ: &Type) -> usize
+    {
+        match *typ
+        {
+            Type::Void |
+            Type::Opaque(_) => 0,
+            Type::Bool => 1,
+            Type::Char => 4,
+            Type::Int(int_size) |
+            Type::UInt(int_size) => int_bytes(int_size),
+            Type::Float(FloatSize::F32) => 4,
+            Type::Float(FloatSize::F64) => 8,
+            Type::Enum(_) => int_bytes(self.int_size),
+            Type::Pointer(_) |
+            Type::Func(_) => 8,
+            Type::String |
+            Type::Slice(_) => round_up(8 + int_bytes(self.int_size), 8),
+            // Data pointer and pointer to the function table
+            Type::Interface(_) => 16,
+            Type::Array(ref at) => self.stride_of(&at.element_type) * at.len,
+            Type::Struct(ref st) => {
+                let end = st.member_order(|t| self.align_of(t)).into_iter()
+                    .map(|idx| &st.members[idx].typ)
+                    .fold(0, |offset, t| round_up(offset, self.align_of(t)) + self.size_of(t));
+                round_up(end, self.align_of(typ))
+            },
+            Type::Union(ref st) => round_up(st.members.iter().map(|m| self.size_of(&m.typ)).max().unwrap_or(0), self.align_of(typ)),
+            Type::Sum(ref st) if st.niche().is_some() => {
+                let niche = st.niche().expect("Sum type has no niche");
+                self.size_of(&st.cases[niche.data_case].typ)
+            },
+            Type::Optional(ref inner) if inner.has_null_niche() => self.size_of(inner),
+            Type::Sum(ref st) => {
+                let data_size = st.cases.iter().map(|c| self.size_of(&c.typ)).max().unwrap_or(0);
+                round_up(self.offset_of(typ, 1) + data_size, self.align_of(typ))
+            },
+            Type::Optional(ref inner) => round_up(self.offset_of(typ, 1) + self.size_of(inner), self.align_of(typ)),
+            _ => panic!("Internal Compiler Error: size of type {} is not known", typ),
+        }
+    }
+
+    pub fn align_of(&self, typ: &Type) -> usize
+    {
+        match *typ
+        {
+            Type::Array(ref at) => self.align_of(&at.element_type),
+            Type::String |
+            Type::Slice(_) |
+            Type::Interface(_) => 8,
+            Type::Struct(ref st) |
+            Type::Union(ref st) => st.members.iter().map(|m| self.align_of(&m.typ)).max().unwrap_or(1),
+            Type::Sum(ref st) if st.niche().is_some() => {
+                let niche = st.niche().expect("Sum type has no niche");
+                self.align_of(&st.cases[niche.data_case].typ)
+            },
+            Type::Sum(ref st) => st.cases.iter().map(|c| self.align_of(&c.typ)).fold(int_bytes(self.int_size), max),
+            Type::Optional(ref inner) => self.align_of(inner),
+            _ => max(self.size_of(typ), 1),
+        }
+    }
+
+    // Distance between two elements of an array
+    pub fn stride_of(&self, typ: &Type) -> usize
+    {
+        round_up(self.size_of(typ), self.align_of(typ))
+    }
+
+    pub fn offset_of(&self, typ: &Type, index: usize) -> usize
+    {
+        match *typ
+        {
+            Type::Struct(ref st) => {
+                let mut offset = 0;
+                for idx in st.member_order(|t| self.align_of(t)) {
+                    let member_type = &st.members[idx].typ;
+                    offset = round_up(offset, self.align_of(member_type));
+                    if idx == index {
+                        break;
+                    }
+                    offset += self.size_of(member_type);
+                }
+                offset
+            },
+            Type::String |
+            Type::Slice(_) |
+            Type::Interface(_) => if index == 0 {0} else {8},
+            Type::Sum(ref st) if st.niche().is_some() => 0,
+            Type::Optional(ref inner) if inner.has_null_niche() => 0,
+            Type::Sum(ref st) => {
+                if index == 0 {
+                    0
+                } else {
+                    let data_align = st.cases.iter().map(|c| self.align_of(&c.typ)).max().unwrap_or(1);
+                    round_up(int_bytes(self.int_size), data_align)
+                }
+            },
+            Type::Optional(ref inner) => if index == 0 {0} else {self.align_of(inner)},
+            Type::Array(ref at) => self.stride_of(&at.element_type) * index,
+            Type::Union(_) => 0,
+            _ => panic!("Internal Compiler Error: type {} has no members", typ),
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8], typ: &Type) -> Value
+    {
+        match *typ
+        {
+            Type::Void => Value::Void,
+            Type::Int(int_size) => Value::Int(sign_extend(read_uint(bytes, int_bytes(int_size)), int_size.size_in_bits())),
+            Type::UInt(int_size) => Value::UInt(read_uint(bytes, int_bytes(int_size))),
+            Type::Enum(_) => Value::UInt(read_uint(bytes, int_bytes(self.int_size))),
+            Type::Float(FloatSize::F32) => Value::Float(f32::from_bits(read_uint(bytes, 4) as u32) as f64),
+            Type::Float(FloatSize::F64) => Value::Float(f64::from_bits(read_uint(bytes, 8))),
+            Type::Char => Value::Char(::std::char::from_u32(read_uint(bytes, 4) as u32).unwrap_or('\u{fffd}')),
+            Type::Bool => Value::Bool(bytes[0] != 0),
+            Type::Pointer(_) |
+            Type::Func(_) => Value::Pointer(read_uint(bytes, 8)),
+            _ => Value::Bytes(bytes[..self.size_of(typ)].to_vec()),
+        }
+    }
+
+    // Store value in bytes, integers are truncated to the size of typ
+    pub fn encode(&self, value: &Value, typ: &Type, bytes: &mut [u8])
+    {
+        let size = self.size_of(typ);
+        match (value, typ)
+        {
+            (&Value::Void, _) => (),
+            (&Value::Bytes(ref data), _) => {
+                let len = data.len().min(size);
+                bytes[..len].copy_from_slice(&data[..len]);
+            },
+            (&Value::Float(v), &Type::Float(FloatSize::F32)) => write_uint(bytes, 4, (v as f32).to_bits() as u64),
+            _ => write_uint(bytes, size, value.to_bits()),
+        }
+    }
+}
+
+fn strip_trailing_zeros(s: &str) -> &str
+{
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+// Formats a float like %g in printf, which is what the LLVM backend uses
+pub fn format_float(v: f64) -> String
+{
+    if v.is_nan() {
+        return "nan".into();
+    } else if v.is_infinite() {
+        return if v < 0.0 {"-inf".into()} else {"inf".into()};
+    } else if v == 0.0 {
+        return if v.is_sign_negative() {"-0".into()} else {"0".into()};
+    }
+
+    // 6 significant digits, the exponent after rounding determines the notation
+    let scientific = format!("{:.5e}", v);
+    let exp_pos = scientific.find('e').expect("Missing exponent");
+    let exp: i32 = scientific[exp_pos + 1..].parse().expect("Invalid exponent");
+    if exp < -4 || exp >= 6 {
+        let mantissa = strip_trailing_zeros(&scientific[..exp_pos]);
+        format!("{}e{}{:02}", mantissa, if exp < 0 {'-'} else {'+'}, exp.abs())
+    } else {
+        strip_trailing_zeros(&format!("{:.*}", (5 - exp) as usize, v)).into()
+    }
+}
+
+// Formats a float like %.*f in printf, for @float_to_string
+pub fn format_fixed(v: f64, precision: usize) -> String
+{
+    if v.is_nan() {
+        "nan".into()
+    } else {
+        format!("{:.*}", precision, v)
+    }
+}
+
+// Digits of an integer in a radix between 2 and 36, like menhir_format_int in the runtime
+pub fn format_int(magnitude: u64, negative: bool, radix: u64) -> String
+{
+    let mut digits = Vec::new();
+    let mut rest = magnitude;
+    loop {
+        digits.push(b"0123456789abcdefghijklmnopqrstuvwxyz"[(rest % radix) as usize]);
+        rest /= radix;
+        if rest == 0 {
+            break;
+        }
+    }
+
+    if negative {
+        digits.push(b'-');
+    }
+    digits.reverse();
+    String::from_utf8(digits).expect("Invalid digits")
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// None on an odd length or a character which is not a hex digit, like menhir_hex_decode
+pub fn hex_decode(text: &[u8]) -> Option<Vec<u8>>
+{
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    let nibble = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    text.chunks(2)
+        .map(|pair| Some((nibble(pair[0])? << 4) | nibble(pair[1])?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{Layout, Value, sign_extend, format_float, format_fixed, format_int, hex_encode, hex_decode};
+    use ast::{Type, IntSize, FloatSize, StructType, StructMember, array_type, optional_type, struct_type, struct_member,
+        ptr_type, sum_type, sum_type_case, interface_type};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_layout()
+    {
+        let layout = Layout{int_size: IntSize::I32};
+        let st = Type::Struct(Rc::new(StructType{
+            name: "Foo".into(),
+            members: vec![
+                StructMember{name: "a".into(), typ: Type::Bool},
+                StructMember{name: "b".into(), typ: Type::Int(IntSize::I32)},
+                StructMember{name: "c".into(), typ: Type::UInt(IntSize::I8)},
+            ],
+            repr_c: true,
+        }));
+
+        assert_eq!(layout.size_of(&st), 12);
+        assert_eq!(layout.offset_of(&st, 1), 4);
+        assert_eq!(layout.offset_of(&st, 2), 8);
+        assert_eq!(layout.size_of(&array_type(st.clone(), 3)), 36);
+        assert_eq!(layout.size_of(&Type::String), 16);
+        assert_eq!(layout.size_of(&interface_type("Shape", Vec::new(), Vec::new())), 16);
+        assert_eq!(layout.size_of(&optional_type(Type::Float(FloatSize::F64))), 16);
+
+        // Without @repr(C) the members are sorted by alignment
+        let reordered = struct_type("Bar", vec![
+            struct_member("a", Type::Bool),
+            struct_member("b", Type::Int(IntSize::I32)),
+            struct_member("c", Type::UInt(IntSize::I8)),
+        ]);
+        assert_eq!(layout.size_of(&reordered), 8);
+        assert_eq!(layout.offset_of(&reordered, 0), 4);
+        assert_eq!(layout.offset_of(&reordered, 1), 0);
+        assert_eq!(layout.offset_of(&reordered, 2), 5);
+
+        // Null pointers encode nil and the case without data
+        assert_eq!(layout.size_of(&optional_type(ptr_type(Type::Int(IntSize::I32)))), 8);
+        let link = struct_type("Link", vec![
+            struct_member("value", Type::Int(IntSize::I32)),
+            struct_member("next", ptr_type(Type::Int(IntSize::I32))),
+        ]);
+        let node = sum_type("Node", vec![sum_type_case("Link", link), sum_type_case("End", Type::UInt(IntSize::I32))]);
+        assert_eq!(layout.size_of(&node), 16);
+        assert_eq!(layout.offset_of(&node, 1), 0);
+        let pair = sum_type("Pair", vec![sum_type_case("A", reordered.clone()), sum_type_case("B", Type::UInt(IntSize::I32))]);
+        assert_eq!(layout.size_of(&pair), 12);
+    }
+
+    #[test]
+    fn test_encode_decode()
+    {
+        let layout = Layout{int_size: IntSize::I32};
+        let mut bytes = [0u8; 8];
+        layout.encode(&Value::Int(-2), &Type::Int(IntSize::I16), &mut bytes);
+        assert_eq!(bytes[..3], [0xFE, 0xFF, 0]);
+        assert_eq!(layout.decode(&bytes, &Type::Int(IntSize::I16)), Value::Int(-2));
+        assert_eq!(layout.decode(&bytes, &Type::UInt(IntSize::I16)), Value::UInt(0xFFFE));
+
+        layout.encode(&Value::UInt(0x1_0000_0005), &Type::UInt(IntSize::I32), &mut bytes);
+        assert_eq!(layout.decode(&bytes, &Type::UInt(IntSize::I32)), Value::UInt(5));
+
+        layout.encode(&Value::Float(1.5), &Type::Float(FloatSize::F32), &mut bytes);
+        assert_eq!(layout.decode(&bytes, &Type::Float(FloatSize::F32)), Value::Float(1.5));
+        assert_eq!(sign_extend(0x80, 8), -128);
+    }
+
+    #[test]
+    fn test_format_float()
+    {
+        assert_eq!(format_float(1.5), "1.5");
+        assert_eq!(format_float(-2.0), "-2");
+        assert_eq!(format_float(0.0), "0");
+        assert_eq!(format_float(100000.0), "100000");
+        assert_eq!(format_float(1234567.0), "1.23457e+06");
+        assert_eq!(format_float(0.00001234), "1.234e-05");
+        assert_eq!(format_float(999999.5), "1e+06");
+        assert_eq!(format_float(1.0 / 3.0), "0.333333");
+    }
+
+    #[test]
+    fn test_format_numbers()
+    {
+        assert_eq!(format_int(255, false, 16), "ff");
+        assert_eq!(format_int(5, true, 2), "-101");
+        assert_eq!(format_int(0, false, 36), "0");
+        assert_eq!(format_int(i64::min_value() as u64, true, 10), "-9223372036854775808");
+        assert_eq!(format_fixed(3.14159, 2), "3.14");
+        assert_eq!(format_fixed(-0.5, 0), "-0");
+        assert_eq!(format_fixed(::std::f64::NAN, 3), "nan");
+    }
+
+    #[test]
+    fn test_hex()
+    {
+        assert_eq!(hex_encode(&[0x00, 0xAB, 0x7f]), "00ab7f");
+        assert_eq!(hex_decode(b"00aB7F"), Some(vec![0x00, 0xAB, 0x7f]));
+        assert_eq!(hex_decode(b""), Some(vec![]));
+        assert_eq!(hex_decode(b"abc"), None);
+        assert_eq!(hex_decode(b"0g"), None);
+    }
+}
@@ -0,0 +1,25 @@
+use span::Span;
+
+// Helpers to write JSON output, for the tools which consume the compiler's output
+
+pub fn json_string(s: &str) -> String
+{
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn json_span(span: &Span) -> String
+{
+    format!(r#"{{"file": {}, "start": {{"line": {}, "offset": {}}}, "end": {{"line": {}, "offset": {}}}}}"#,
+        json_string(&span.file), span.start.line, span.start.offset, span.end.line, span.end.offset)
+}
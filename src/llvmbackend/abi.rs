@@ -0,0 +1,494 @@
+use std::cmp;
+use libc::{c_char, c_uint, size_t};
+use llvm::LLVMTypeKind;
+use llvm::core::*;
+use llvm::prelude::*;
+use ast::{Type, FunctionSignature};
+use super::compat::create_type_attribute;
+use super::context::Context;
+use super::instructions::copy;
+use super::symboltable::FunctionInstance;
+
+/*
+Passing of structs according to the C ABI of the target, so calls between menhir and C code agree
+on where a struct lives. Small structs are passed and returned in registers, by reinterpreting
+their memory as one or more integer or floating point values. Bigger structs go through memory:
+arguments as a pointer to a copy (byval), results through a hidden first argument (sret).
+
+Return value optimization turns a function returning a struct into one with an extra $ret argument,
+which is lifted out of the argument list again here when the target returns the struct differently.
+
+Targets without a classification below keep passing structs as a pointer, and results through $ret.
+*/
+
+// How a single argument is passed
+#[derive(Clone)]
+pub enum ArgPassing
+{
+    // The value itself, or a pointer to it for types which are not passed by value
+    Direct,
+    // The memory of the struct loaded as these values, each passed in a register
+    Coerced(Vec<LLVMTypeRef>),
+    // Pointer to a copy of the struct, made by the caller
+    ByVal(LLVMTypeRef),
+    // The environment of a closure, which a trampoline passes in a register of its own
+    Nest,
+}
+
+// How the struct pointed to by $ret is returned
+#[derive(Clone, Copy)]
+pub enum ReturnPassing
+{
+    // There is no $ret, or it is an ordinary argument
+    Direct,
+    // The memory of the struct returned as the coerced type
+    Coerced{struct_type: LLVMTypeRef, coerced_type: LLVMTypeRef},
+    // $ret is passed as the first argument
+    SRet{struct_type: LLVMTypeRef},
+}
+
+pub struct FunctionAbi
+{
+    // One entry for every argument, except $ret when the return passing is not direct
+    pub args: Vec<ArgPassing>,
+    pub ret: ReturnPassing,
+    pub function_type: LLVMTypeRef,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class
+{
+    None,
+    Integer,
+    Sse,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Convention
+{
+    Default,
+    SysV64,
+    AArch64,
+    Win64,
+}
+
+fn convention(triplet: &str) -> Convention
+{
+    let arch = triplet.split('-').next().unwrap_or("");
+    let windows = triplet.contains("windows") || triplet.contains("mingw") || triplet.contains("win32");
+    match arch
+    {
+        "x86_64" | "amd64" if windows => Convention::Win64,
+        "x86_64" | "amd64" => Convention::SysV64,
+        "aarch64" | "arm64" if !windows => Convention::AArch64,
+        _ => Convention::Default,
+    }
+}
+
+unsafe fn is_float(typ: LLVMTypeRef) -> bool
+{
+    match LLVMGetTypeKind(typ)
+    {
+        LLVMTypeKind::LLVMFloatTypeKind | LLVMTypeKind::LLVMDoubleTypeKind => true,
+        _ => false,
+    }
+}
+
+// The scalars making up a type, with their offset in bytes
+unsafe fn flatten(ctx: &Context, typ: LLVMTypeRef, offset: usize, scalars: &mut Vec<(usize, LLVMTypeRef)>)
+{
+    match LLVMGetTypeKind(typ)
+    {
+        LLVMTypeKind::LLVMStructTypeKind => {
+            for idx in 0..LLVMCountStructElementTypes(typ) {
+                let member_offset = ctx.target_machine.offset_of_element(typ, idx as usize);
+                flatten(ctx, LLVMStructGetTypeAtIndex(typ, idx), offset + member_offset, scalars);
+            }
+        },
+
+        LLVMTypeKind::LLVMArrayTypeKind => {
+            let element_type = LLVMGetElementType(typ);
+            let element_size = ctx.target_machine.alloc_size_of_type(element_type);
+            for idx in 0..LLVMGetArrayLength(typ) as usize {
+                flatten(ctx, element_type, offset + idx * element_size, scalars);
+            }
+        },
+
+        _ => scalars.push((offset, typ)),
+    }
+}
+
+unsafe fn int_type(ctx: &Context, bytes: usize) -> LLVMTypeRef
+{
+    LLVMIntTypeInContext(ctx.context, (bytes * 8) as c_uint)
+}
+
+// System V x86-64: each eightbyte of a struct of at most 16 bytes goes in a general purpose register,
+// unless it only contains floating point values, then it goes in an SSE register
+unsafe fn classify_sysv64(ctx: &Context, typ: LLVMTypeRef) -> Option<Vec<LLVMTypeRef>>
+{
+    let size = ctx.target_machine.alloc_size_of_type(typ);
+    if size == 0 || size > 16 {
+        return None;
+    }
+
+    let mut scalars = Vec::new();
+    flatten(ctx, typ, 0, &mut scalars);
+
+    let mut classes = vec![Class::None; (size + 7) / 8];
+    for &(offset, scalar) in &scalars {
+        if offset % ctx.target_machine.alignment_of_type(scalar) != 0 {
+            return None;
+        }
+
+        let class = &mut classes[offset / 8];
+        if !is_float(scalar) {
+            *class = Class::Integer;
+        } else if *class == Class::None {
+            *class = Class::Sse;
+        }
+    }
+
+    let parts = classes.iter().enumerate().map(|(idx, class)| {
+        let bytes = cmp::min(8, size - idx * 8);
+        let only_floats = scalars.iter()
+            .filter(|&&(offset, _)| offset / 8 == idx)
+            .all(|&(_, scalar)| LLVMGetTypeKind(scalar) == LLVMTypeKind::LLVMFloatTypeKind);
+        match *class
+        {
+            Class::Sse if bytes <= 4 => LLVMFloatTypeInContext(ctx.context),
+            Class::Sse if only_floats => LLVMVectorType(LLVMFloatTypeInContext(ctx.context), 2),
+            Class::Sse => LLVMDoubleTypeInContext(ctx.context),
+            _ => int_type(ctx, bytes),
+        }
+    }).collect();
+    Some(parts)
+}
+
+// AArch64: up to four floats or doubles go in floating point registers, other structs of at most 16 bytes
+// in one or two general purpose registers
+unsafe fn classify_aarch64(ctx: &Context, typ: LLVMTypeRef) -> Option<Vec<LLVMTypeRef>>
+{
+    let size = ctx.target_machine.alloc_size_of_type(typ);
+    if size == 0 || size > 32 {
+        return None;
+    }
+
+    let mut scalars = Vec::new();
+    flatten(ctx, typ, 0, &mut scalars);
+    let first = scalars[0].1;
+    if scalars.len() <= 4 && is_float(first) && scalars.iter().all(|&(_, scalar)| scalar == first) {
+        return Some(vec![LLVMArrayType(first, scalars.len() as c_uint)]);
+    }
+
+    if size > 16 {
+        None
+    } else if size <= 8 {
+        Some(vec![int_type(ctx, 8)])
+    } else {
+        Some(vec![LLVMArrayType(int_type(ctx, 8), 2)])
+    }
+}
+
+// Windows x64: only structs of 1, 2, 4 or 8 bytes are passed in a register
+unsafe fn classify_win64(ctx: &Context, typ: LLVMTypeRef) -> Option<Vec<LLVMTypeRef>>
+{
+    match ctx.target_machine.alloc_size_of_type(typ)
+    {
+        size @ 1 | size @ 2 | size @ 4 | size @ 8 => Some(vec![int_type(ctx, size)]),
+        _ => None,
+    }
+}
+
+unsafe fn classify(ctx: &Context, convention: Convention, typ: LLVMTypeRef) -> Option<Vec<LLVMTypeRef>>
+{
+    match convention
+    {
+        Convention::SysV64 => classify_sysv64(ctx, typ),
+        Convention::AArch64 => classify_aarch64(ctx, typ),
+        Convention::Win64 => classify_win64(ctx, typ),
+        Convention::Default => None,
+    }
+}
+
+unsafe fn coerced_type(ctx: &Context, parts: &[LLVMTypeRef]) -> LLVMTypeRef
+{
+    if parts.len() == 1 {
+        parts[0]
+    } else {
+        let mut parts = parts.to_vec();
+        LLVMStructTypeInContext(ctx.context, parts.as_mut_ptr(), parts.len() as c_uint, 0)
+    }
+}
+
+fn return_struct(sig: &FunctionSignature) -> Option<&Type>
+{
+    match sig.args.last()
+    {
+        Some(arg) if arg.name == "$ret" => match arg.typ.get_pointer_element_type()
+        {
+            Some(typ @ &Type::Struct(_)) => Some(typ),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+unsafe fn arg_llvm_type(ctx: &Context, typ: &Type) -> LLVMTypeRef
+{
+    let llvm_type = ctx.resolve_type(typ);
+    if typ.pass_by_value() {
+        llvm_type
+    } else {
+        LLVMPointerType(llvm_type, 0)
+    }
+}
+
+pub unsafe fn function_abi(ctx: &Context, sig: &FunctionSignature) -> FunctionAbi
+{
+    let convention = convention(&ctx.target_machine.target.triplet);
+    let ret = match return_struct(sig)
+    {
+        Some(typ) if convention != Convention::Default => {
+            let struct_type = ctx.resolve_type(typ);
+            match classify(ctx, convention, struct_type)
+            {
+                Some(parts) => ReturnPassing::Coerced{struct_type, coerced_type: coerced_type(ctx, &parts)},
+                None => ReturnPassing::SRet{struct_type},
+            }
+        },
+        _ => ReturnPassing::Direct,
+    };
+
+    let (ret_type, mut param_types, arg_count) = match ret
+    {
+        ReturnPassing::Direct => (ctx.resolve_type(&sig.return_type), Vec::new(), sig.args.len()),
+        ReturnPassing::Coerced{coerced_type, ..} => (coerced_type, Vec::new(), sig.args.len() - 1),
+        ReturnPassing::SRet{struct_type} => (LLVMVoidTypeInContext(ctx.context), vec![LLVMPointerType(struct_type, 0)], sig.args.len() - 1),
+    };
+
+    // On x86-64 a struct only goes in registers if there are enough left for the whole struct
+    let mut free_int_regs = if param_types.is_empty() {6} else {5};
+    let mut free_sse_regs = 8;
+    let mut args = Vec::new();
+    for arg in &sig.args[..arg_count] {
+        let passing = match arg.typ
+        {
+            _ if arg.name == "$env" => ArgPassing::Nest,
+
+            Type::Struct(_) => match classify(ctx, convention, ctx.resolve_type(&arg.typ))
+            {
+                Some(parts) => {
+                    let sse = parts.iter().filter(|&&part| LLVMGetTypeKind(part) != LLVMTypeKind::LLVMIntegerTypeKind).count();
+                    let int = parts.len() - sse;
+                    if convention != Convention::SysV64 || (int <= free_int_regs && sse <= free_sse_regs) {
+                        free_int_regs -= cmp::min(int, free_int_regs);
+                        free_sse_regs -= cmp::min(sse, free_sse_regs);
+                        ArgPassing::Coerced(parts)
+                    } else {
+                        ArgPassing::ByVal(ctx.resolve_type(&arg.typ))
+                    }
+                },
+                None if convention == Convention::SysV64 => ArgPassing::ByVal(ctx.resolve_type(&arg.typ)),
+                None => ArgPassing::Direct,
+            },
+
+            Type::Float(_) => {
+                free_sse_regs = cmp::max(free_sse_regs, 1) - 1;
+                ArgPassing::Direct
+            },
+
+            _ => {
+                free_int_regs = cmp::max(free_int_regs, 1) - 1;
+                ArgPassing::Direct
+            },
+        };
+
+        match passing
+        {
+            ArgPassing::Coerced(ref parts) => param_types.extend(parts.iter().cloned()),
+            _ => param_types.push(arg_llvm_type(ctx, &arg.typ)),
+        }
+        args.push(passing);
+    }
+
+    let function_type = LLVMFunctionType(ret_type, param_types.as_mut_ptr(), param_types.len() as c_uint, 0);
+    FunctionAbi{
+        args,
+        ret,
+        function_type,
+    }
+}
+
+impl FunctionAbi
+{
+    // The attributes of the parameters, with the index in the LLVM parameter list
+    pub unsafe fn param_attributes(&self, ctx: &Context) -> Vec<(usize, LLVMAttributeRef)>
+    {
+        let mut attributes = Vec::new();
+        let mut idx = 0;
+        if let ReturnPassing::SRet{struct_type} = self.ret {
+            attributes.push((idx, create_type_attribute(ctx.context, attribute_kind("sret"), struct_type)));
+            idx += 1;
+        }
+
+        for arg in &self.args {
+            match *arg
+            {
+                ArgPassing::Direct => idx += 1,
+                ArgPassing::Coerced(ref parts) => idx += parts.len(),
+                ArgPassing::ByVal(struct_type) => {
+                    attributes.push((idx, create_type_attribute(ctx.context, attribute_kind("byval"), struct_type)));
+                    idx += 1;
+                },
+                ArgPassing::Nest => {
+                    attributes.push((idx, LLVMCreateEnumAttribute(ctx.context, attribute_kind("nest"), 0)));
+                    idx += 1;
+                },
+            }
+        }
+        attributes
+    }
+}
+
+unsafe fn attribute_kind(name: &str) -> c_uint
+{
+    LLVMGetEnumAttributeKindForName(name.as_ptr() as *const c_char, name.len() as size_t)
+}
+
+// A pointer to memory with the coerced type, containing the struct at ptr
+unsafe fn coerced_ptr(ctx: &Context, ptr: LLVMValueRef, struct_type: LLVMTypeRef, coerced_type: LLVMTypeRef) -> LLVMValueRef
+{
+    if ctx.target_machine.alloc_size_of_type(coerced_type) <= ctx.target_machine.alloc_size_of_type(struct_type) {
+        return LLVMBuildBitCast(ctx.builder, ptr, LLVMPointerType(coerced_type, 0), cstr!("coerced"));
+    }
+
+    // Reading the coerced type directly would go past the end of the struct, so use a copy
+    let tmp = ctx.entry_alloca(coerced_type, "coerced");
+    copy(ctx, LLVMBuildBitCast(ctx.builder, tmp, LLVMPointerType(struct_type, 0), cstr!("coerced_struct")), ptr, struct_type);
+    tmp
+}
+
+// The value of every argument, as the function body expects them, a pointer for aggregates
+pub unsafe fn gen_arguments(ctx: &mut Context, fi: &FunctionInstance, sig: &FunctionSignature) -> Vec<LLVMValueRef>
+{
+    let abi = match fi.abi
+    {
+        Some(ref abi) => abi,
+        None => return (0..sig.args.len()).map(|idx| LLVMGetParam(fi.function, idx as c_uint)).collect(),
+    };
+
+    let mut param = 0;
+    let ret_arg = match abi.ret
+    {
+        ReturnPassing::Direct => None,
+        ReturnPassing::Coerced{struct_type, coerced_type} => {
+            let slot = ctx.entry_alloca(coerced_type, "ret_slot");
+            ctx.return_slot = Some(slot);
+            Some(LLVMBuildBitCast(ctx.builder, slot, LLVMPointerType(struct_type, 0), cstr!("ret")))
+        },
+        ReturnPassing::SRet{..} => {
+            param += 1;
+            Some(LLVMGetParam(fi.function, 0))
+        },
+    };
+
+    let mut values = Vec::new();
+    for (arg, passing) in sig.args.iter().zip(abi.args.iter()) {
+        match *passing
+        {
+            ArgPassing::Direct | ArgPassing::ByVal(_) | ArgPassing::Nest => {
+                values.push(LLVMGetParam(fi.function, param as c_uint));
+                param += 1;
+            },
+
+            ArgPassing::Coerced(ref parts) => {
+                let struct_type = ctx.resolve_type(&arg.typ);
+                let coerced_type = coerced_type(ctx, parts);
+                let mem = ctx.entry_alloca(coerced_type, "argcoerced");
+                if parts.len() == 1 {
+                    LLVMBuildStore(ctx.builder, LLVMGetParam(fi.function, param as c_uint), mem);
+                } else {
+                    for idx in 0..parts.len() {
+                        let part_ptr = LLVMBuildStructGEP(ctx.builder, mem, idx as c_uint, cstr!("part"));
+                        LLVMBuildStore(ctx.builder, LLVMGetParam(fi.function, (param + idx) as c_uint), part_ptr);
+                    }
+                }
+                param += parts.len();
+                values.push(LLVMBuildBitCast(ctx.builder, mem, LLVMPointerType(struct_type, 0), cstr!("arg")));
+            },
+        }
+    }
+
+    values.extend(ret_arg);
+    values
+}
+
+unsafe fn build_call(ctx: &Context, func: LLVMValueRef, args: &mut [LLVMValueRef]) -> LLVMValueRef
+{
+    let function_type = LLVMGetElementType(LLVMTypeOf(func));
+    let name = if LLVMGetTypeKind(LLVMGetReturnType(function_type)) == LLVMTypeKind::LLVMVoidTypeKind {
+        cstr!("")
+    } else {
+        cstr!("call")
+    };
+    LLVMBuildCall(ctx.builder, func, args.as_mut_ptr(), args.len() as c_uint, name)
+}
+
+// Call a function, the arguments are the same as for a function without an ABI, so a pointer for aggregates
+pub unsafe fn gen_call(ctx: &Context, fi: &FunctionInstance, mut args: Vec<LLVMValueRef>) -> LLVMValueRef
+{
+    let abi = match fi.abi
+    {
+        Some(ref abi) => abi,
+        None => return build_call(ctx, fi.function, &mut args),
+    };
+
+    // The address of the result is only there when the caller uses the result
+    let ret_ptr = match abi.ret
+    {
+        ReturnPassing::Direct => None,
+        _ => if args.len() > abi.args.len() {args.pop()} else {None},
+    };
+
+    let mut params = Vec::new();
+    if let ReturnPassing::SRet{struct_type} = abi.ret {
+        params.push(ret_ptr.unwrap_or_else(|| ctx.entry_alloca(struct_type, "unused_ret")));
+    }
+
+    for (arg, passing) in args.iter().zip(abi.args.iter()) {
+        match *passing
+        {
+            ArgPassing::Direct | ArgPassing::ByVal(_) | ArgPassing::Nest => params.push(*arg),
+            ArgPassing::Coerced(ref parts) => {
+                let struct_type = LLVMGetElementType(LLVMTypeOf(*arg));
+                let alignment = ctx.target_machine.alignment_of_type(struct_type) as c_uint;
+                let mem = coerced_ptr(ctx, *arg, struct_type, coerced_type(ctx, parts));
+                for idx in 0..parts.len() {
+                    let part_ptr = if parts.len() == 1 {mem} else {LLVMBuildStructGEP(ctx.builder, mem, idx as c_uint, cstr!("part"))};
+                    let part = LLVMBuildLoad(ctx.builder, part_ptr, cstr!("part"));
+                    LLVMSetAlignment(part, alignment);
+                    params.push(part);
+                }
+            },
+        }
+    }
+
+    let call = build_call(ctx, fi.function, &mut params);
+    for (idx, attribute) in abi.param_attributes(ctx) {
+        LLVMAddCallSiteAttribute(call, idx as c_uint + 1, attribute);
+    }
+
+    if let (ReturnPassing::Coerced{struct_type, coerced_type}, Some(ret_ptr)) = (abi.ret, ret_ptr) {
+        if ctx.target_machine.alloc_size_of_type(coerced_type) <= ctx.target_machine.alloc_size_of_type(struct_type) {
+            let mem = LLVMBuildBitCast(ctx.builder, ret_ptr, LLVMPointerType(coerced_type, 0), cstr!("coerced"));
+            let store = LLVMBuildStore(ctx.builder, call, mem);
+            LLVMSetAlignment(store, ctx.target_machine.alignment_of_type(struct_type) as c_uint);
+        } else {
+            let tmp = ctx.entry_alloca(coerced_type, "coerced");
+            LLVMBuildStore(ctx.builder, call, tmp);
+            copy(ctx, ret_ptr, LLVMBuildBitCast(ctx.builder, tmp, LLVMPointerType(struct_type, 0), cstr!("coerced_struct")), struct_type);
+        }
+    }
+    call
+}
@@ -0,0 +1,94 @@
+use std::ptr;
+use libc::c_uint;
+use llvm::core::*;
+use ast::Type;
+use bytecode::{ByteCodeFunction, Instruction, Var};
+use super::context::Context;
+use super::function::gen_function_ptr;
+use super::intrinsics::get_llvm_intrinsic;
+use super::runtime::runtime_function;
+use super::valueref::ValueRef;
+
+/*
+A closure is a function with a nest argument pointing to its environment. To keep function pointers
+a single pointer, a trampoline is written in memory of the function creating the closure. The
+trampoline loads the environment in the register reserved for the nest argument, and jumps to the
+function, so callers don't have to know they are calling a closure.
+
+The environment lives in the stack frame of the function creating the closure, so the closure can
+only be called as long as that function has not returned, which the type checker makes sure of.
+The trampoline has to be executable. A binary has an executable stack, so there it is on the stack
+as well. Code running in the JIT gets executable memory from the menhir_alloc_trampoline hook instead,
+which the function gives back with menhir_free_trampoline when it returns.
+*/
+
+// Big enough for the trampolines of all supported targets
+pub const TRAMPOLINE_SIZE: c_uint = 72;
+
+// A slot for the trampoline of every closure the current function creates, all null until a closure is created
+pub unsafe fn gen_trampoline_slots(ctx: &mut Context, func: &ByteCodeFunction)
+{
+    let i8_ptr_type = LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0);
+    let closures = func.blocks.values()
+        .flat_map(|b| b.instructions.iter())
+        .filter(|inst| matches!(**inst, Instruction::MakeClosure{..}))
+        .count();
+
+    ctx.trampoline_slots = (0..closures)
+        .map(|_| {
+            let slot = LLVMBuildAlloca(ctx.builder, i8_ptr_type, cstr!("trampoline_slot"));
+            LLVMBuildStore(ctx.builder, LLVMConstNull(i8_ptr_type), slot);
+            slot
+        })
+        .collect();
+    ctx.next_trampoline_slot = 0;
+}
+
+// Give back the trampolines of the current function, before it returns
+pub unsafe fn gen_free_trampolines(ctx: &Context)
+{
+    let free = runtime_function(ctx, "free_trampoline");
+    for slot in &ctx.trampoline_slots {
+        let mut args = [LLVMBuildLoad(ctx.builder, *slot, cstr!("trampoline"))];
+        LLVMBuildCall(ctx.builder, free, args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+    }
+}
+
+pub unsafe fn gen_make_closure(ctx: &mut Context, dst: &Var, func: &str, env: &Var)
+{
+    let fi = ctx.get_function(func).expect("Internal Compiler Error: unknown closure function");
+    let i8_type = LLVMInt8TypeInContext(ctx.context);
+    let i8_ptr_type = LLVMPointerType(i8_type, 0);
+
+    // Creating the closure again, in a loop, replaces the trampoline, like the one on the stack is overwritten
+    let slot = ctx.trampoline_slots[ctx.next_trampoline_slot];
+    ctx.next_trampoline_slot += 1;
+    let mut free_args = [LLVMBuildLoad(ctx.builder, slot, cstr!("old_trampoline"))];
+    LLVMBuildCall(ctx.builder, runtime_function(ctx, "free_trampoline"), free_args.as_mut_ptr(), free_args.len() as c_uint, cstr!(""));
+    let heap_trampoline = LLVMBuildCall(ctx.builder, runtime_function(ctx, "alloc_trampoline"), ptr::null_mut(), 0, cstr!("heap_trampoline"));
+    LLVMBuildStore(ctx.builder, heap_trampoline, slot);
+
+    let stack_trampoline = ctx.entry_alloca(LLVMArrayType(i8_type, TRAMPOLINE_SIZE), "trampoline");
+    LLVMSetAlignment(stack_trampoline, 16);
+    let stack_trampoline = LLVMBuildBitCast(ctx.builder, stack_trampoline, i8_ptr_type, cstr!("stack_trampoline"));
+    let on_stack = LLVMBuildIsNull(ctx.builder, heap_trampoline, cstr!("on_stack"));
+    let trampoline = LLVMBuildSelect(ctx.builder, on_stack, stack_trampoline, heap_trampoline, cstr!("trampoline"));
+    let function = LLVMBuildBitCast(ctx.builder, fi.function, i8_ptr_type, cstr!("function"));
+    let env_var = ctx.get_variable(&env.name, &env.typ);
+    let env_ptr = LLVMBuildBitCast(ctx.builder, env_var.value, i8_ptr_type, cstr!("env"));
+
+    let init = get_llvm_intrinsic(ctx, "llvm.init.trampoline", LLVMVoidTypeInContext(ctx.context), &mut [i8_ptr_type, i8_ptr_type, i8_ptr_type]);
+    let mut init_args = [trampoline, function, env_ptr];
+    LLVMBuildCall(ctx.builder, init, init_args.as_mut_ptr(), init_args.len() as c_uint, cstr!(""));
+
+    let adjust = get_llvm_intrinsic(ctx, "llvm.adjust.trampoline", i8_ptr_type, &mut [i8_ptr_type]);
+    let mut adjust_args = [trampoline];
+    let closure = LLVMBuildCall(ctx.builder, adjust, adjust_args.as_mut_ptr(), adjust_args.len() as c_uint, cstr!("closure"));
+    let closure = LLVMBuildBitCast(ctx.builder, closure, ctx.resolve_type(&dst.typ), cstr!("closure"));
+
+    let dst_var = ctx.get_variable(&dst.name, &dst.typ);
+    dst_var.store(ctx, &ValueRef::new(closure, dst.typ.clone()));
+    if let Type::Func(ref ft) = dst.typ {
+        gen_function_ptr(ctx, &dst.name, closure, ft.return_type.clone(), dst.typ.clone());
+    }
+}
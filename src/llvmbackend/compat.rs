@@ -0,0 +1,135 @@
+use std::ptr;
+use libc::{c_char, c_uint};
+#[cfg(llvm_at_least_8)]
+use libc::size_t;
+#[cfg(not(llvm_at_least_12))]
+use llvm::core::LLVMCreateEnumAttribute;
+use llvm::prelude::*;
+use llvm::debuginfo::*;
+
+/*
+Functions of the LLVM C API which changed after LLVM 7, the version llvm-sys 70 declares them for.
+build.rs tells which LLVM we are linked against, with a llvm_at_least_N cfg for every version which changed one
+of them, and they are declared here the way that version has them. The same goes for intrinsics which changed.
+*/
+
+// llvm.prefetch is overloaded on the type of the pointer since LLVM 10
+#[cfg(llvm_at_least_10)]
+pub const PREFETCH_INTRINSIC: &str = "llvm.prefetch.p0i8";
+#[cfg(not(llvm_at_least_10))]
+pub const PREFETCH_INTRINSIC: &str = "llvm.prefetch";
+
+#[cfg(llvm_at_least_8)]
+extern "C" {
+    #[link_name = "LLVMDIBuilderCreateBasicType"]
+    fn create_basic_type(
+        builder: LLVMDIBuilderRef,
+        name: *const c_char,
+        name_len: size_t,
+        size_in_bits: u64,
+        encoding: LLVMDWARFTypeEncoding,
+        flags: LLVMDIFlags) -> LLVMMetadataRef;
+}
+
+// The SysRoot and SDK arguments were added in LLVM 10 and 11, they are left empty
+#[cfg(llvm_at_least_10)]
+extern "C" {
+    #[link_name = "LLVMDIBuilderCreateCompileUnit"]
+    fn create_compile_unit(
+        builder: LLVMDIBuilderRef,
+        lang: LLVMDWARFSourceLanguage,
+        file: LLVMMetadataRef,
+        producer: *const c_char,
+        producer_len: size_t,
+        is_optimized: LLVMBool,
+        flags: *const c_char,
+        flags_len: size_t,
+        runtime_ver: c_uint,
+        split_name: *const c_char,
+        split_name_len: size_t,
+        kind: LLVMDWARFEmissionKind,
+        dwo_id: c_uint,
+        split_debug_inlining: LLVMBool,
+        debug_info_for_profiling: LLVMBool,
+        sys_root: *const c_char,
+        sys_root_len: size_t,
+        sdk: *const c_char,
+        sdk_len: size_t) -> LLVMMetadataRef;
+}
+
+// byval and sret carry the type they point to since LLVM 12
+#[cfg(llvm_at_least_12)]
+extern "C" {
+    fn LLVMCreateTypeAttribute(ctx: LLVMContextRef, kind: c_uint, type_ref: LLVMTypeRef) -> LLVMAttributeRef;
+}
+
+#[cfg(llvm_at_least_8)]
+pub unsafe fn di_create_basic_type(builder: LLVMDIBuilderRef, name: &str, size_in_bits: u64, encoding: LLVMDWARFTypeEncoding) -> LLVMMetadataRef
+{
+    create_basic_type(builder, name.as_ptr() as *const c_char, name.len(), size_in_bits, encoding, LLVMDIFlagZero)
+}
+
+#[cfg(not(llvm_at_least_8))]
+pub unsafe fn di_create_basic_type(builder: LLVMDIBuilderRef, name: &str, size_in_bits: u64, encoding: LLVMDWARFTypeEncoding) -> LLVMMetadataRef
+{
+    LLVMDIBuilderCreateBasicType(builder, name.as_ptr() as *const c_char, name.len(), size_in_bits, encoding)
+}
+
+#[cfg(llvm_at_least_10)]
+pub unsafe fn di_create_compile_unit(builder: LLVMDIBuilderRef, file: LLVMMetadataRef, producer: &str, optimized: bool) -> LLVMMetadataRef
+{
+    create_compile_unit(
+        builder,
+        LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+        file,
+        producer.as_ptr() as *const c_char,
+        producer.len(),
+        optimized as LLVMBool,
+        ptr::null(),
+        0,
+        0,
+        ptr::null(),
+        0,
+        LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+        0,
+        0,
+        0,
+        ptr::null(),
+        0,
+        ptr::null(),
+        0)
+}
+
+#[cfg(not(llvm_at_least_10))]
+pub unsafe fn di_create_compile_unit(builder: LLVMDIBuilderRef, file: LLVMMetadataRef, producer: &str, optimized: bool) -> LLVMMetadataRef
+{
+    LLVMDIBuilderCreateCompileUnit(
+        builder,
+        LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+        file,
+        producer.as_ptr() as *const c_char,
+        producer.len(),
+        optimized as LLVMBool,
+        ptr::null(),
+        0,
+        0,
+        ptr::null(),
+        0,
+        LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+        0,
+        0,
+        0)
+}
+
+// An attribute of a pointer argument, which refers to the type it points to
+#[cfg(llvm_at_least_12)]
+pub unsafe fn create_type_attribute(ctx: LLVMContextRef, kind: c_uint, pointee: LLVMTypeRef) -> LLVMAttributeRef
+{
+    LLVMCreateTypeAttribute(ctx, kind, pointee)
+}
+
+#[cfg(not(llvm_at_least_12))]
+pub unsafe fn create_type_attribute(ctx: LLVMContextRef, kind: c_uint, _pointee: LLVMTypeRef) -> LLVMAttributeRef
+{
+    LLVMCreateEnumAttribute(ctx, kind, 0)
+}
@@ -1,11 +1,17 @@
 use std::ffi::CString;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::fs::DirBuilder;
 use std::ptr;
+use libc::{c_char, c_uint};
+use llvm::{LLVMLinkage, LLVMUnnamedAddr};
 use llvm::prelude::*;
 use llvm::core::*;
-use ast::{Type, ptr_type};
+use ast::{Type, StructType, LoopHints, ptr_type};
 use super::CodeGenOptions;
+use super::debuginfo::{DebugInfo, declare_variable};
+use super::heapprofile::HeapProfile;
 use super::symboltable::{SymbolTable, FunctionInstance, VariableInstance};
 use super::target::TargetMachine;
 use super::valueref::ValueRef;
@@ -15,6 +21,9 @@ use super::valueref::ValueRef;
 struct StackFrame
 {
     pub symbols: SymbolTable,
+    // Variables of scopes which have ended, blocks are generated in order, so a block after the end
+    // of a scope can still belong to it, like the increment of a loop with an if in its body
+    pub ended_scopes: SymbolTable,
     pub current_function: LLVMValueRef,
 }
 
@@ -24,6 +33,7 @@ impl StackFrame
     {
         StackFrame{
             symbols: SymbolTable::new(),
+            ended_scopes: SymbolTable::new(),
             current_function: current_function,
         }
     }
@@ -38,6 +48,23 @@ pub struct Context<'a>
     pub target_machine: &'a TargetMachine,
     name: String,
     stack: Vec<StackFrame>,
+    // Guard and init function of every lazily initialized global
+    lazy_globals: HashMap<String, (LLVMValueRef, LLVMValueRef)>,
+    pub debug_info: Option<DebugInfo>,
+    // Share one global between identical string literals
+    pub intern_strings: bool,
+    // Let the linker merge identical string literals of different modules
+    pub merge_strings: bool,
+    string_constants: RefCell<HashMap<String, LLVMValueRef>>,
+    // Where the current function keeps its result, when a struct is returned in registers
+    pub return_slot: Option<LLVMValueRef>,
+    // Hints for the loop whose back edge is generated next
+    pub loop_hints: Option<LoopHints>,
+    // Allocation sites, with --instrument heap
+    pub heap_profile: Option<HeapProfile>,
+    // The trampolines of the closures the current function creates, freed when it returns
+    pub trampoline_slots: Vec<LLVMValueRef>,
+    pub next_trampoline_slot: usize,
 }
 
 impl<'a> Context<'a>
@@ -47,21 +74,61 @@ impl<'a> Context<'a>
         unsafe {
             let context_name = CString::new(module_name).expect("Invalid module name");
             let context = LLVMContextCreate();
+            let module = LLVMModuleCreateWithNameInContext(context_name.as_ptr(), context);
+            target_machine.set_module_target(module);
             Ok(Context::<'a> {
                 context: context,
-                module: LLVMModuleCreateWithNameInContext(context_name.as_ptr(), context),
+                module: module,
                 builder: LLVMCreateBuilderInContext(context),
                 target_machine: target_machine,
                 name: module_name.into(),
                 stack: vec![StackFrame::new(ptr::null_mut())],
+                lazy_globals: HashMap::new(),
+                debug_info: None,
+                intern_strings: false,
+                merge_strings: false,
+                string_constants: RefCell::new(HashMap::new()),
+                return_slot: None,
+                loop_hints: None,
+                heap_profile: None,
+                trampoline_slots: Vec::new(),
+                next_trampoline_slot: 0,
             })
         }
     }
 
 
+    // The global containing the characters of a string literal
+    pub unsafe fn string_constant(&self, s: &str) -> LLVMValueRef
+    {
+        if let Some(glob) = self.string_constants.borrow().get(s) {
+            return *glob;
+        }
+
+        let char_type = LLVMInt8TypeInContext(self.context);
+        let glob = LLVMAddGlobal(self.module, LLVMArrayType(char_type, (s.len() + 1) as c_uint), cstr!("str_constant"));
+        let const_string = LLVMConstStringInContext(self.context, s.as_bytes().as_ptr() as *const c_char, s.len() as c_uint, 0);
+        LLVMSetInitializer(glob, const_string);
+        if self.merge_strings {
+            // The address of a private unnamed_addr constant is not significant, so the linker can merge it with other strings
+            LLVMSetLinkage(glob, LLVMLinkage::LLVMPrivateLinkage);
+            LLVMSetUnnamedAddress(glob, LLVMUnnamedAddr::LLVMGlobalUnnamedAddr);
+            LLVMSetGlobalConstant(glob, 1);
+        } else {
+            LLVMSetLinkage(glob, LLVMLinkage::LLVMInternalLinkage);
+        }
+
+        if self.intern_strings {
+            LLVMSetGlobalConstant(glob, 1);
+            self.string_constants.borrow_mut().insert(s.into(), glob);
+        }
+        glob
+    }
+
     pub fn set_variable(&mut self, name: &str, vr: ValueRef)
     {
-        if let Some(vi) = self.get_variable_instance(name) {
+        let existing = self.get_variable_instance(name).or_else(|| self.get_ended_scope_variable(name, &vr.typ, true));
+        if let Some(vi) = existing {
             unsafe {
                 vi.value.store(self, &vr);
             }
@@ -82,21 +149,28 @@ impl<'a> Context<'a>
     pub fn stack_alloc(&mut self, name: &str, typ: &Type) -> LLVMValueRef
     {
         unsafe {
-            let typ = self.resolve_type(typ);
-            let func = self.get_current_function();
-            let entry_bb = LLVMGetEntryBasicBlock(func);
-            let current_bb = LLVMGetInsertBlock(self.builder);
-            // We allocate in the entry block
-            LLVMPositionBuilder(self.builder, entry_bb, LLVMGetFirstInstruction(entry_bb));
-
-            let name = CString::new(name).expect("Invalid string");
-            let alloc = LLVMBuildAlloca(self.builder, typ, name.as_ptr());
-            LLVMPositionBuilderAtEnd(self.builder, current_bb); // Position the builder where it was before
+            let llvm_type = self.resolve_type(typ);
+            let alloc = self.entry_alloca(llvm_type, name);
+            declare_variable(self, name, typ, alloc, true, 0);
             alloc
         }
 
     }
 
+    // Allocate in the entry block, so the allocation happens once per call, even in a loop
+    pub unsafe fn entry_alloca(&self, llvm_type: LLVMTypeRef, name: &str) -> LLVMValueRef
+    {
+        let func = self.get_current_function();
+        let entry_bb = LLVMGetEntryBasicBlock(func);
+        let current_bb = LLVMGetInsertBlock(self.builder);
+        LLVMPositionBuilder(self.builder, entry_bb, LLVMGetFirstInstruction(entry_bb));
+
+        let c_name = CString::new(name).expect("Invalid string");
+        let alloc = LLVMBuildAlloca(self.builder, llvm_type, c_name.as_ptr());
+        LLVMPositionBuilderAtEnd(self.builder, current_bb); // Position the builder where it was before
+        alloc
+    }
+
     fn get_variable_instance(&self, name: &str) -> Option<Rc<VariableInstance>>
     {
         for sf in self.stack.iter().rev()
@@ -109,9 +183,42 @@ impl<'a> Context<'a>
         None
     }
 
+    // A variable of the same type, in a scope of the current function which has ended. To assign it, it
+    // has to be on the stack, other values only exist in the blocks of their own scope, and a variable
+    // with the same name in another scope is a different one.
+    fn get_ended_scope_variable(&self, name: &str, typ: &Type, assign: bool) -> Option<Rc<VariableInstance>>
+    {
+        for sf in self.stack.iter().rev()
+        {
+            if let Some(vi) = sf.ended_scopes.get_variable(name) {
+                let on_stack = unsafe {!LLVMIsAAllocaInst(vi.value.value).is_null()};
+                if (on_stack || !assign) && (vi.value.typ == *typ || vi.value.typ == ptr_type(typ.clone())) {
+                    return Some(vi);
+                }
+            }
+
+            if !sf.current_function.is_null() {
+                break;
+            }
+        }
+        None
+    }
+
     pub fn get_variable(&mut self, name: &str, typ: &Type) -> ValueRef
     {
         if let Some(vi) = self.get_variable_instance(name) {
+            if let Some(&(guard, init_function)) = self.lazy_globals.get(name) {
+                // Make sure the global is initialized, unless we are initializing it
+                if self.get_current_function() != init_function {
+                    unsafe {
+                        LLVMBuildCall(self.builder, guard, ptr::null_mut(), 0, cstr!(""));
+                    }
+                }
+            }
+            return vi.value.clone();
+        }
+
+        if let Some(vi) = self.get_ended_scope_variable(name, typ, false) {
             return vi.value.clone();
         }
 
@@ -121,6 +228,11 @@ impl<'a> Context<'a>
         ret
     }
 
+    pub fn add_lazy_global(&mut self, name: &str, guard: LLVMValueRef, init_function: LLVMValueRef)
+    {
+        self.lazy_globals.insert(name.into(), (guard, init_function));
+    }
+
     pub fn add_function(&mut self, f: Rc<FunctionInstance>)
     {
         self.stack.last_mut().expect("Stack is empty").symbols.add_function(f)
@@ -146,7 +258,14 @@ impl<'a> Context<'a>
 
     pub fn pop_stack(&mut self)
     {
-        self.stack.pop();
+        if let Some(sf) = self.stack.pop() {
+            if sf.current_function.is_null() {
+                if let Some(parent) = self.stack.last_mut() {
+                    parent.ended_scopes.add_variables(sf.ended_scopes);
+                    parent.ended_scopes.add_variables(sf.symbols);
+                }
+            }
+        }
     }
 
     pub fn get_current_function(&self) -> LLVMValueRef
@@ -165,10 +284,26 @@ impl<'a> Context<'a>
     {
         unsafe{
             use llvmbackend::types::to_llvm_type;
-            to_llvm_type(self.context, self.target_machine, typ)
+            to_llvm_type(self.context, self.module, self.target_machine, typ)
+        }
+    }
+
+    pub fn struct_member_order(&self, st: &StructType) -> Vec<usize>
+    {
+        unsafe{
+            use llvmbackend::types::struct_member_order;
+            struct_member_order(self.context, self.module, self.target_machine, st)
         }
     }
 
+    // Index in the LLVM struct type of the member with index in the declaration of st
+    pub fn struct_member_position(&self, st: &StructType, index: usize) -> usize
+    {
+        self.struct_member_order(st).iter()
+            .position(|idx| *idx == index)
+            .unwrap_or_else(|| panic!("Internal Compiler Error: {} has no member {}", st.name, index))
+    }
+
     pub fn dump_module(&self)
     {
         println!("LLVM IR: {}", self.name);
@@ -238,7 +373,6 @@ impl<'a> Context<'a>
     pub fn verify(&self) -> Result<(), String>
     {
         use llvm::analysis::*;
-        use libc::c_char;
         use std::ffi::CStr;
         unsafe {
             let mut error_message: *mut c_char = ptr::null_mut();
@@ -263,12 +397,15 @@ impl<'a> Drop for Context<'a>
 {
     fn drop(&mut self)
     {
+        // The debug info builder refers to the module, so it has to go first
+        self.debug_info = None;
         unsafe {
             LLVMDisposeBuilder(self.builder);
             if !self.module.is_null() {
                 LLVMDisposeModule(self.module);
             }
             LLVMContextDispose(self.context);
+            self.target_machine.forget_type_layouts();
         }
     }
 }
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
+use libc::*;
+use llvm::LLVMModuleFlagBehavior;
+use llvm::core::*;
+use llvm::debuginfo::*;
+use llvm::prelude::*;
+
+use ast::*;
+use span::Pos;
+use super::compat::{di_create_basic_type, di_create_compile_unit};
+use super::context::Context;
+
+/*
+DWARF debug information, generated with LLVM's DIBuilder. Every function gets a subprogram, the
+SourceLocation instructions in the bytecode become line table entries, and named variables
+and arguments are declared at the location they are allocated.
+*/
+
+const DW_ATE_BOOLEAN: c_uint = 0x02;
+const DW_ATE_FLOAT: c_uint = 0x04;
+const DW_ATE_SIGNED: c_uint = 0x05;
+const DW_ATE_UNSIGNED: c_uint = 0x08;
+const DW_ATE_UTF: c_uint = 0x10;
+
+const DWARF_VERSION: u64 = 4;
+
+pub struct DebugInfo
+{
+    builder: LLVMDIBuilderRef,
+    compile_unit: LLVMMetadataRef,
+    files: HashMap<String, LLVMMetadataRef>,
+    types: HashMap<Type, LLVMMetadataRef>,
+    // Subprogram and file of the function being generated
+    subprogram: LLVMMetadataRef,
+    file: LLVMMetadataRef,
+    location: Pos,
+}
+
+impl Drop for DebugInfo
+{
+    fn drop(&mut self)
+    {
+        unsafe {
+            LLVMDisposeDIBuilder(self.builder);
+        }
+    }
+}
+
+unsafe fn create_file(builder: LLVMDIBuilderRef, path: &str) -> LLVMMetadataRef
+{
+    let path = Path::new(path);
+    let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let dir = path.parent().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default();
+    LLVMDIBuilderCreateFile(
+        builder,
+        file_name.as_ptr() as *const c_char,
+        file_name.len(),
+        dir.as_ptr() as *const c_char,
+        dir.len())
+}
+
+unsafe fn add_module_flag(ctx: &Context, name: &str, value: u64)
+{
+    let value = LLVMConstInt(LLVMInt32TypeInContext(ctx.context), value, 0);
+    LLVMAddModuleFlag(
+        ctx.module,
+        LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+        name.as_ptr() as *const c_char,
+        name.len(),
+        LLVMValueAsMetadata(value));
+}
+
+impl DebugInfo
+{
+    pub unsafe fn new(ctx: &Context, main_file: &str, optimized: bool) -> DebugInfo
+    {
+        let builder = LLVMCreateDIBuilder(ctx.module);
+        let file = create_file(builder, main_file);
+        let compile_unit = di_create_compile_unit(builder, file, "menhir", optimized);
+
+        add_module_flag(ctx, "Debug Info Version", LLVMDebugMetadataVersion() as u64);
+        add_module_flag(ctx, "Dwarf Version", DWARF_VERSION);
+
+        let mut files = HashMap::new();
+        files.insert(main_file.to_string(), file);
+        DebugInfo{
+            builder,
+            compile_unit,
+            files,
+            types: HashMap::new(),
+            subprogram: ptr::null_mut(),
+            file,
+            location: Pos::zero(),
+        }
+    }
+
+    unsafe fn get_file(&mut self, path: &str) -> LLVMMetadataRef
+    {
+        if let Some(file) = self.files.get(path) {
+            return *file;
+        }
+
+        let file = create_file(self.builder, path);
+        self.files.insert(path.into(), file);
+        file
+    }
+
+    pub unsafe fn finalize(&self)
+    {
+        LLVMDIBuilderFinalize(self.builder);
+    }
+}
+
+fn debug_info_builder(ctx: &Context) -> Option<LLVMDIBuilderRef>
+{
+    ctx.debug_info.as_ref().map(|di| di.builder)
+}
+
+unsafe fn basic_type(builder: LLVMDIBuilderRef, name: &str, size_in_bits: u64, encoding: c_uint) -> LLVMMetadataRef
+{
+    di_create_basic_type(builder, name, size_in_bits, encoding)
+}
+
+unsafe fn member_type(ctx: &mut Context, builder: LLVMDIBuilderRef, scope: LLVMMetadataRef, m: &StructMember, offset: usize) -> LLVMMetadataRef
+{
+    let member_llvm_type = ctx.resolve_type(&m.typ);
+    let member_type = to_debug_type(ctx, &m.typ);
+    LLVMDIBuilderCreateMemberType(
+        builder,
+        scope,
+        m.name.as_ptr() as *const c_char,
+        m.name.len(),
+        ptr::null_mut(),
+        0,
+        (ctx.target_machine.size_of_type(member_llvm_type) * 8) as u64,
+        (ctx.target_machine.alignment_of_type(member_llvm_type) * 8) as u32,
+        (offset * 8) as u64,
+        LLVMDIFlagZero,
+        member_type)
+}
+
+// Describe a type which is represented by an LLVM struct, with members in the same order as the LLVM struct
+unsafe fn struct_type(ctx: &mut Context, builder: LLVMDIBuilderRef, name: &str, members: &[StructMember], llvm_type: LLVMTypeRef) -> LLVMMetadataRef
+{
+    let scope = ctx.debug_info.as_ref().map(|di| di.compile_unit).expect("Debug info is not enabled");
+    let mut elements = Vec::with_capacity(members.len());
+    for (idx, m) in members.iter().enumerate() {
+        let offset = ctx.target_machine.offset_of_element(llvm_type, idx);
+        elements.push(member_type(ctx, builder, scope, m, offset));
+    }
+
+    LLVMDIBuilderCreateStructType(
+        builder,
+        scope,
+        name.as_ptr() as *const c_char,
+        name.len(),
+        ptr::null_mut(),
+        0,
+        (ctx.target_machine.alloc_size_of_type(llvm_type) * 8) as u64,
+        (ctx.target_machine.alignment_of_type(llvm_type) * 8) as u32,
+        LLVMDIFlagZero,
+        ptr::null_mut(),
+        elements.as_mut_ptr(),
+        elements.len() as c_uint,
+        0,
+        ptr::null_mut(),
+        ptr::null(),
+        0)
+}
+
+unsafe fn union_type(ctx: &mut Context, builder: LLVMDIBuilderRef, name: &str, members: &[StructMember], llvm_type: LLVMTypeRef) -> LLVMMetadataRef
+{
+    let scope = ctx.debug_info.as_ref().map(|di| di.compile_unit).expect("Debug info is not enabled");
+    let mut elements = Vec::with_capacity(members.len());
+    for m in members {
+        elements.push(member_type(ctx, builder, scope, m, 0));
+    }
+
+    LLVMDIBuilderCreateUnionType(
+        builder,
+        scope,
+        name.as_ptr() as *const c_char,
+        name.len(),
+        ptr::null_mut(),
+        0,
+        (ctx.target_machine.alloc_size_of_type(llvm_type) * 8) as u64,
+        (ctx.target_machine.alignment_of_type(llvm_type) * 8) as u32,
+        LLVMDIFlagZero,
+        elements.as_mut_ptr(),
+        elements.len() as c_uint,
+        0,
+        ptr::null(),
+        0)
+}
+
+unsafe fn subroutine_type(ctx: &mut Context, builder: LLVMDIBuilderRef, return_type: &Type, args: &[Type]) -> LLVMMetadataRef
+{
+    let file = ctx.debug_info.as_ref().map(|di| di.file).expect("Debug info is not enabled");
+    // The first element is the return type, null for void
+    let mut types = vec![to_debug_type(ctx, return_type)];
+    for arg in args {
+        types.push(to_debug_type(ctx, arg));
+    }
+    LLVMDIBuilderCreateSubroutineType(builder, file, types.as_mut_ptr(), types.len() as c_uint, LLVMDIFlagZero)
+}
+
+// Convert a type to its debug info description, void is represented by null
+pub unsafe fn to_debug_type(ctx: &mut Context, typ: &Type) -> LLVMMetadataRef
+{
+    let builder = debug_info_builder(ctx).expect("Debug info is not enabled");
+    if let Some(existing) = ctx.debug_info.as_ref().and_then(|di| di.types.get(typ)) {
+        return *existing;
+    }
+
+    let name = typ.to_string();
+    let di_type = match *typ
+    {
+        Type::Void => return ptr::null_mut(),
+        // Opaque types have no size, they are forward declarations
+        Type::Opaque(_) => LLVMDIBuilderCreateStructType(
+            builder,
+            ptr::null_mut(),
+            name.as_ptr() as *const c_char,
+            name.len(),
+            ptr::null_mut(),
+            0,
+            0,
+            0,
+            LLVMDIFlagFwdDecl,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            0,
+            0,
+            ptr::null_mut(),
+            ptr::null(),
+            0),
+        _ => sized_debug_type(ctx, builder, typ, &name),
+    };
+
+    if let Some(ref mut di) = ctx.debug_info {
+        di.types.insert(typ.clone(), di_type);
+    }
+    di_type
+}
+
+unsafe fn sized_debug_type(ctx: &mut Context, builder: LLVMDIBuilderRef, typ: &Type, name: &str) -> LLVMMetadataRef
+{
+    let llvm_type = ctx.resolve_type(typ);
+    let size_in_bits = (ctx.target_machine.size_of_type(llvm_type) * 8) as u64;
+    match *typ
+    {
+        Type::Int(_) => basic_type(builder, name, size_in_bits, DW_ATE_SIGNED),
+        Type::UInt(_) |
+        Type::Enum(_) => basic_type(builder, name, size_in_bits, DW_ATE_UNSIGNED),
+        Type::Float(_) => basic_type(builder, name, size_in_bits, DW_ATE_FLOAT),
+        Type::Char => basic_type(builder, name, size_in_bits, DW_ATE_UTF),
+        Type::Bool => basic_type(builder, name, 8, DW_ATE_BOOLEAN),
+        Type::Pointer(ref inner) => {
+            let inner = to_debug_type(ctx, inner);
+            LLVMDIBuilderCreatePointerType(builder, inner, size_in_bits, 0, 0, name.as_ptr() as *const c_char, name.len())
+        },
+        Type::Func(ref ft) => {
+            let func_type = subroutine_type(ctx, builder, &ft.return_type, &ft.args);
+            LLVMDIBuilderCreatePointerType(builder, func_type, size_in_bits, 0, 0, name.as_ptr() as *const c_char, name.len())
+        },
+        Type::Array(ref at) => {
+            let element_type = to_debug_type(ctx, &at.element_type);
+            let mut subscripts = vec![LLVMDIBuilderGetOrCreateSubrange(builder, 0, at.len as i64)];
+            LLVMDIBuilderCreateArrayType(
+                builder,
+                (ctx.target_machine.alloc_size_of_type(llvm_type) * 8) as u64,
+                (ctx.target_machine.alignment_of_type(llvm_type) * 8) as u32,
+                element_type,
+                subscripts.as_mut_ptr(),
+                subscripts.len() as c_uint)
+        },
+        Type::String => {
+            let st = string_type_representation(ctx.target_machine.target.int_size);
+            struct_type(ctx, builder, name, &st.members, llvm_type)
+        },
+        Type::Slice(ref st) => {
+            let members = vec![
+                struct_member("data", ptr_type(st.element_type.clone())),
+                struct_member("len", ctx.target_machine.target.native_uint_type.clone()),
+            ];
+            struct_type(ctx, builder, name, &members, llvm_type)
+        },
+        Type::Interface(_) => {
+            let members = vec![
+                struct_member("data", ptr_type(Type::UInt(IntSize::I8))),
+                struct_member("vtable", ptr_type(ptr_type(Type::UInt(IntSize::I8)))),
+            ];
+            struct_type(ctx, builder, name, &members, llvm_type)
+        },
+        // Types with a niche have the representation of their inner type
+        Type::Optional(ref inner) if inner.has_null_niche() => to_debug_type(ctx, inner),
+        Type::Sum(ref st) if st.niche().is_some() => {
+            let niche = st.niche().expect("Sum type has no niche");
+            to_debug_type(ctx, &st.cases[niche.data_case].typ)
+        },
+        Type::Optional(ref inner) => {
+            let members = vec![
+                struct_member("valid", Type::Bool),
+                struct_member("value", (**inner).clone()),
+            ];
+            struct_type(ctx, builder, name, &members, llvm_type)
+        },
+        Type::Sum(ref st) => {
+            // The tag, followed by the data of the case, which is shown as a union of all cases
+            let cases = Type::Union(Rc::new(StructType{
+                name: format!("{}.cases", st.name),
+                members: st.cases.iter().map(|c| struct_member(&c.name, c.typ.clone())).collect(),
+                repr_c: false,
+            }));
+            let members = vec![
+                struct_member("index", ctx.target_machine.target.native_uint_type.clone()),
+                struct_member("data", cases),
+            ];
+            struct_type(ctx, builder, name, &members, llvm_type)
+        },
+        Type::Struct(ref st) => {
+            let members: Vec<StructMember> = ctx.struct_member_order(st).into_iter().map(|idx| st.members[idx].clone()).collect();
+            struct_type(ctx, builder, name, &members, llvm_type)
+        },
+        Type::Union(ref ut) => union_type(ctx, builder, name, &ut.members, llvm_type),
+        _ => panic!("Internal Compiler Error: no debug info for type {}", typ),
+    }
+}
+
+// Create the subprogram of a function, and make it the scope of the instructions which follow
+pub unsafe fn start_function(ctx: &mut Context, func: LLVMValueRef, sig: &FunctionSignature)
+{
+    let builder = match debug_info_builder(ctx) {
+        Some(builder) => builder,
+        None => return,
+    };
+
+    let file = match ctx.debug_info {
+        Some(ref mut di) => if sig.span.file.is_empty() {di.file} else {di.get_file(&sig.span.file)},
+        None => return,
+    };
+
+    let arg_types: Vec<Type> = sig.args.iter().map(|a| a.typ.clone()).collect();
+    if let Some(ref mut di) = ctx.debug_info {
+        di.file = file;
+    }
+    let func_type = subroutine_type(ctx, builder, &sig.return_type, &arg_types);
+    let mut linkage_name_len = 0;
+    let linkage_name = CStr::from_ptr(LLVMGetValueName2(func, &mut linkage_name_len)).to_string_lossy().into_owned();
+    let line = sig.span.start.line as c_uint;
+    let subprogram = LLVMDIBuilderCreateFunction(
+        builder,
+        file,
+        sig.name.as_ptr() as *const c_char,
+        sig.name.len(),
+        linkage_name.as_ptr() as *const c_char,
+        linkage_name.len(),
+        file,
+        line,
+        func_type,
+        0,
+        1,
+        line,
+        LLVMDIFlagZero,
+        0);
+    LLVMSetSubprogram(func, subprogram);
+
+    if let Some(ref mut di) = ctx.debug_info {
+        di.subprogram = subprogram;
+    }
+    set_location(ctx, sig.span.start);
+}
+
+pub unsafe fn end_function(ctx: &mut Context)
+{
+    if let Some(ref mut di) = ctx.debug_info {
+        di.subprogram = ptr::null_mut();
+        LLVMSetCurrentDebugLocation(ctx.builder, ptr::null_mut());
+    }
+}
+
+// Attach pos to all instructions generated from now on
+pub unsafe fn set_location(ctx: &mut Context, pos: Pos)
+{
+    let (subprogram, context, builder) = (ctx.debug_info.as_ref().map(|di| di.subprogram), ctx.context, ctx.builder);
+    match subprogram {
+        Some(subprogram) if !subprogram.is_null() => {
+            let location = LLVMDIBuilderCreateDebugLocation(context, pos.line as c_uint, pos.offset as c_uint, subprogram, ptr::null_mut());
+            LLVMSetCurrentDebugLocation(builder, LLVMMetadataAsValue(context, location));
+            if let Some(ref mut di) = ctx.debug_info {
+                di.location = pos;
+            }
+        },
+        _ => (),
+    }
+}
+
+// Declare a variable, if is_address is true value is the address of the variable, otherwise it is
+// its value. The arg_no is the position of an argument starting from 1, or 0 for local variables.
+pub unsafe fn declare_variable(ctx: &mut Context, name: &str, typ: &Type, value: LLVMValueRef, is_address: bool, arg_no: usize)
+{
+    // Temporaries start with a $, they are not visible in the debugger
+    if name.starts_with('$') {
+        return;
+    }
+
+    let (builder, subprogram, file, pos) = match ctx.debug_info {
+        Some(ref di) if !di.subprogram.is_null() => (di.builder, di.subprogram, di.file, di.location),
+        _ => return,
+    };
+
+    let var_type = to_debug_type(ctx, typ);
+    let var = if arg_no > 0 {
+        LLVMDIBuilderCreateParameterVariable(
+            builder,
+            subprogram,
+            name.as_ptr() as *const c_char,
+            name.len(),
+            arg_no as c_uint,
+            file,
+            pos.line as c_uint,
+            var_type,
+            1,
+            LLVMDIFlagZero)
+    } else {
+        LLVMDIBuilderCreateAutoVariable(
+            builder,
+            subprogram,
+            name.as_ptr() as *const c_char,
+            name.len(),
+            file,
+            pos.line as c_uint,
+            var_type,
+            1,
+            LLVMDIFlagZero,
+            0)
+    };
+
+    let location = LLVMDIBuilderCreateDebugLocation(ctx.context, pos.line as c_uint, pos.offset as c_uint, subprogram, ptr::null_mut());
+    let expr = LLVMDIBuilderCreateExpression(builder, ptr::null_mut(), 0);
+    let bb = LLVMGetInsertBlock(ctx.builder);
+    if is_address {
+        LLVMDIBuilderInsertDeclareAtEnd(builder, value, var, expr, location, bb);
+    } else {
+        LLVMDIBuilderInsertDbgValueAtEnd(builder, value, var, expr, location, bb);
+    }
+}
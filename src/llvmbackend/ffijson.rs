@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use ast::{Type, IntSize, FloatSize, StructType, FunctionSignature, ptr_type};
+use bytecode::ByteCodeModule;
+use cheader::c_name;
+use json::json_string;
+use super::context::Context;
+
+/*
+Machine readable description of the @export functions of a library, and the layout of the types they use,
+so binding generators don't have to parse C headers.
+*/
+
+fn int_bits(size: IntSize) -> usize
+{
+    match size {
+        IntSize::I8 => 8,
+        IntSize::I16 => 16,
+        IntSize::I32 => 32,
+        IntSize::I64 => 64,
+    }
+}
+
+fn json_type(typ: &Type) -> String
+{
+    match *typ
+    {
+        Type::Void => r#"{"kind": "void"}"#.into(),
+        Type::Bool => r#"{"kind": "bool"}"#.into(),
+        Type::Char => r#"{"kind": "char"}"#.into(),
+        Type::Int(size) => format!(r#"{{"kind": "int", "bits": {}}}"#, int_bits(size)),
+        Type::UInt(size) => format!(r#"{{"kind": "uint", "bits": {}}}"#, int_bits(size)),
+        Type::Float(FloatSize::F32) => r#"{"kind": "float", "bits": 32}"#.into(),
+        Type::Float(FloatSize::F64) => r#"{"kind": "float", "bits": 64}"#.into(),
+        Type::Pointer(ref inner) => format!(r#"{{"kind": "pointer", "to": {}}}"#, json_type(inner)),
+        Type::Array(ref at) => format!(r#"{{"kind": "array", "element": {}, "len": {}}}"#, json_type(&at.element_type), at.len),
+        Type::Struct(ref st) => format!(r#"{{"kind": "struct", "name": {}}}"#, json_string(&st.name)),
+        Type::Union(ref st) => format!(r#"{{"kind": "union", "name": {}}}"#, json_string(&st.name)),
+        Type::Opaque(ref ot) => format!(r#"{{"kind": "opaque", "name": {}}}"#, json_string(&ot.name)),
+        _ => panic!("Internal Compiler Error: type {} cannot be used by exported functions", typ),
+    }
+}
+
+// Arguments which are not passed by value, are passed as a pointer
+fn abi_argument_type(typ: &Type) -> Type
+{
+    if typ.pass_by_value() {
+        typ.clone()
+    } else {
+        ptr_type(typ.clone())
+    }
+}
+
+fn collect_types(typ: &Type, types: &mut BTreeMap<String, Type>)
+{
+    match *typ
+    {
+        Type::Pointer(ref inner) => collect_types(inner, types),
+        Type::Array(ref at) => collect_types(&at.element_type, types),
+        Type::Struct(ref st) |
+        Type::Union(ref st) => {
+            if !types.contains_key(&st.name) {
+                types.insert(st.name.clone(), typ.clone());
+                for m in &st.members {
+                    collect_types(&m.typ, types);
+                }
+            }
+        },
+        _ => (),
+    }
+}
+
+fn json_function(sig: &FunctionSignature) -> String
+{
+    let args: Vec<String> = sig.args.iter()
+        .map(|arg| format!(r#"{{"name": {}, "type": {}}}"#, json_string(&arg.name), json_type(&abi_argument_type(&arg.typ))))
+        .collect();
+
+    format!(r#"    {{"name": {}, "symbol": {}, "return": {}, "args": [{}]}}"#,
+        json_string(&sig.name),
+        json_string(sig.exported_name()),
+        json_type(&sig.return_type),
+        args.join(", "))
+}
+
+unsafe fn json_struct(ctx: &Context, typ: &Type, st: &Rc<StructType>, kind: &str) -> String
+{
+    let llvm_type = ctx.resolve_type(typ);
+    let fields: Vec<String> = st.members.iter().enumerate()
+        .map(|(idx, m)| {
+            let offset = if kind == "union" {0} else {ctx.target_machine.offset_of_element(llvm_type, ctx.struct_member_position(st, idx))};
+            format!(r#"{{"name": {}, "type": {}, "offset": {}}}"#, json_string(&m.name), json_type(&m.typ), offset)
+        })
+        .collect();
+
+    format!(r#"    {{"name": {}, "c_name": {}, "kind": "{}", "size": {}, "alignment": {}, "fields": [{}]}}"#,
+        json_string(&st.name),
+        json_string(&c_name(&st.name)),
+        kind,
+        ctx.target_machine.alloc_size_of_type(llvm_type),
+        ctx.target_machine.alignment_of_type(llvm_type),
+        fields.join(", "))
+}
+
+pub fn ffi_json(ctx: &Context, bc_mod: &ByteCodeModule) -> String
+{
+    let mut functions: Vec<&FunctionSignature> = bc_mod.exported_functions.iter()
+        .filter_map(|name| bc_mod.get_function(name))
+        .map(|func| &func.sig)
+        .collect();
+    functions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut types = BTreeMap::new();
+    for sig in &functions {
+        for arg in &sig.args {
+            collect_types(&arg.typ, &mut types);
+        }
+        collect_types(&sig.return_type, &mut types);
+    }
+
+    let type_descriptions: Vec<String> = types.values()
+        .map(|typ| unsafe {
+            match *typ {
+                Type::Union(ref st) => json_struct(ctx, typ, st, "union"),
+                Type::Struct(ref st) => json_struct(ctx, typ, st, "struct"),
+                _ => unreachable!(),
+            }
+        })
+        .collect();
+
+    let function_descriptions: Vec<String> = functions.iter().map(|sig| json_function(sig)).collect();
+    format!("{{\n  \"package\": {},\n  \"target\": {},\n  \"functions\": [\n{}\n  ],\n  \"types\": [\n{}\n  ]\n}}\n",
+        json_string(&bc_mod.name),
+        json_string(&ctx.target_machine.target.triplet),
+        function_descriptions.join(",\n"),
+        type_descriptions.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::json_type;
+    use ast::{Type, IntSize, ptr_type, array_type};
+
+    #[test]
+    fn test_json_types()
+    {
+        assert_eq!(json_type(&Type::Int(IntSize::I32)), r#"{"kind": "int", "bits": 32}"#);
+        assert_eq!(json_type(&ptr_type(Type::Char)), r#"{"kind": "pointer", "to": {"kind": "char"}}"#);
+        assert_eq!(json_type(&array_type(Type::UInt(IntSize::I8), 4)),
+            r#"{"kind": "array", "element": {"kind": "uint", "bits": 8}, "len": 4}"#);
+    }
+}
@@ -0,0 +1,305 @@
+use std::ffi::CString;
+use libc::*;
+use llvm::LLVMIntPredicate;
+use llvm::core::*;
+use llvm::prelude::*;
+use ast::{Type, IntSize, Intrinsic, ptr_type};
+use bytecode::{ByteCodeProperty, Constant, Operand, Var};
+use super::context::Context;
+use super::instructions::{get_operand, get_pointer};
+use super::heapprofile::{gen_alloc, AllocationSource};
+use super::runtime::{runtime_function, FORMAT_INT_BUFFER_SIZE};
+use super::valueref::ValueRef;
+
+/*
+Formatting of interpolated strings. All parts are converted using one printf style format string,
+which is passed to snprintf twice: first to determine the length of the result, and then to fill
+a buffer of that length allocated with menhir_alloc (or menhir_heap_alloc when profiling the heap). Constant parts are put in the format string.
+
+@int_to_string and @float_to_string end up in snprintf in the same way, @parse_int and @parse_float call the runtime.
+*/
+
+// int snprintf(char* buf, size_t size, const char* format, ...)
+unsafe fn snprintf_function(ctx: &Context) -> LLVMValueRef
+{
+    let i8_ptr_type = LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut arg_types = vec![i8_ptr_type, size_type, i8_ptr_type];
+    let function_type = LLVMFunctionType(LLVMInt32TypeInContext(ctx.context), arg_types.as_mut_ptr(), arg_types.len() as c_uint, 1);
+    let name = CString::new("snprintf").expect("Invalid string");
+    let func = LLVMGetNamedFunction(ctx.module, name.as_ptr());
+    if func.is_null() {
+        LLVMAddFunction(ctx.module, name.as_ptr(), function_type)
+    } else if LLVMTypeOf(func) != LLVMPointerType(function_type, 0) {
+        LLVMConstBitCast(func, LLVMPointerType(function_type, 0))
+    } else {
+        func
+    }
+}
+
+// Encodes a char as UTF-8 in a 4 byte buffer, returns a pointer to the buffer and the number of bytes used
+unsafe fn gen_utf8_encode(ctx: &Context, c: LLVMValueRef) -> (LLVMValueRef, LLVMValueRef)
+{
+    let builder = ctx.builder;
+    let i8_type = LLVMInt8TypeInContext(ctx.context);
+    let i32_type = LLVMInt32TypeInContext(ctx.context);
+    let cst = |v: u64| LLVMConstInt(i32_type, v, 0);
+
+    let mut len = cst(1);
+    for limit in &[0x80, 0x800, 0x10000] {
+        let above = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntUGE, c, cst(*limit), cstr!("above"));
+        len = LLVMBuildAdd(builder, len, LLVMBuildZExt(builder, above, i32_type, cstr!("extra")), cstr!("len"));
+    }
+
+    // The first byte starts with the marker bits 0, 110, 1110 or 11110, depending on the length
+    let last = LLVMBuildSub(builder, len, cst(1), cstr!("last"));
+    let lead_shift = LLVMBuildMul(builder, last, cst(6), cstr!("lead_shift"));
+    let marker_shift = LLVMBuildMul(builder, last, cst(8), cstr!("marker_shift"));
+    let marker = LLVMBuildAnd(builder, LLVMBuildLShr(builder, cst(0xF0E0_C000), marker_shift, cstr!("markers")), cst(0xFF), cstr!("marker"));
+    let lead = LLVMBuildOr(builder, LLVMBuildLShr(builder, c, lead_shift, cstr!("lead_bits")), marker, cstr!("lead"));
+
+    let buf = ctx.entry_alloca(LLVMArrayType(i8_type, 4), "utf8_buf");
+    let buf_ptr = LLVMBuildBitCast(builder, buf, LLVMPointerType(i8_type, 0), cstr!("utf8_ptr"));
+    LLVMBuildStore(builder, LLVMBuildTrunc(builder, lead, i8_type, cstr!("byte")), buf_ptr);
+
+    // Continuation bytes hold 6 bits each, bytes past the length are not used
+    for idx in 1..4 {
+        let used = LLVMBuildICmp(builder, LLVMIntPredicate::LLVMIntUGT, len, cst(idx), cstr!("used"));
+        let remaining = LLVMBuildSub(builder, last, cst(idx), cstr!("remaining"));
+        let shift = LLVMBuildSelect(builder, used, LLVMBuildMul(builder, remaining, cst(6), cstr!("shift")), cst(0), cstr!("shift"));
+        let bits = LLVMBuildAnd(builder, LLVMBuildLShr(builder, c, shift, cstr!("bits")), cst(0x3F), cstr!("bits"));
+        let byte = LLVMBuildTrunc(builder, LLVMBuildOr(builder, bits, cst(0x80), cstr!("cont")), i8_type, cstr!("byte"));
+        let mut index = [cst(idx)];
+        let byte_ptr = LLVMBuildGEP(builder, buf_ptr, index.as_mut_ptr(), 1, cstr!("byte_ptr"));
+        LLVMBuildStore(builder, byte, byte_ptr);
+    }
+
+    (buf_ptr, len)
+}
+
+pub unsafe fn gen_format(ctx: &mut Context, dst: &Var, parts: &[Operand])
+{
+    let int_size = ctx.target_machine.target.int_size;
+    let i32_type = LLVMInt32TypeInContext(ctx.context);
+    let i64_type = LLVMInt64TypeInContext(ctx.context);
+
+    let mut format = String::new();
+    let mut args = Vec::new();
+    for part in parts {
+        if let Operand::Const(Constant::String(ref s)) = *part {
+            format.push_str(&s.replace('%', "%%"));
+            continue;
+        }
+
+        let (typ, value) = match *part {
+            // Members which are not passed by value are loaded as their address
+            Operand::Var(ref v @ Var{typ: Type::Pointer(_), ..}) => {
+                let ptr = get_pointer(ctx, v);
+                (v.typ.get_element_type().unwrap_or(Type::Unknown), ValueRef::new(ptr, v.typ.clone()))
+            },
+            _ => (part.get_type(int_size), get_operand(ctx, part)),
+        };
+        match typ {
+            Type::String => {
+                format.push_str("%.*s");
+                let len = value.get_property(ctx, ByteCodeProperty::Len).value;
+                args.push(LLVMBuildIntCast(ctx.builder, len, i32_type, cstr!("len")));
+                args.push(value.get_property(ctx, ByteCodeProperty::Data).value);
+            },
+
+            Type::Char => {
+                format.push_str("%.*s");
+                let (buf, len) = gen_utf8_encode(ctx, value.load(ctx));
+                args.push(len);
+                args.push(buf);
+            },
+
+            Type::Bool => {
+                format.push_str("%s");
+                let true_str = LLVMBuildGlobalStringPtr(ctx.builder, cstr!("true"), cstr!("true_str"));
+                let false_str = LLVMBuildGlobalStringPtr(ctx.builder, cstr!("false"), cstr!("false_str"));
+                args.push(LLVMBuildSelect(ctx.builder, value.load(ctx), true_str, false_str, cstr!("bool_str")));
+            },
+
+            Type::Int(_) => {
+                format.push_str("%lld");
+                args.push(LLVMBuildSExtOrBitCast(ctx.builder, value.load(ctx), i64_type, cstr!("int")));
+            },
+
+            Type::UInt(_) => {
+                format.push_str("%llu");
+                args.push(LLVMBuildZExtOrBitCast(ctx.builder, value.load(ctx), i64_type, cstr!("uint")));
+            },
+
+            Type::Float(_) => {
+                format.push_str("%g");
+                args.push(LLVMBuildFPCast(ctx.builder, value.load(ctx), LLVMDoubleTypeInContext(ctx.context), cstr!("double")));
+            },
+
+            _ => panic!("Internal Compiler Error: cannot convert {} to a string", typ),
+        }
+    }
+
+    gen_snprintf(ctx, dst, format, args);
+}
+
+// Format the arguments into a newly allocated string, which is stored in dst
+unsafe fn gen_snprintf(ctx: &mut Context, dst: &Var, format: String, args: Vec<LLVMValueRef>)
+{
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let snprintf = snprintf_function(ctx);
+    let format = CString::new(format).expect("Invalid format string");
+    let format_ptr = LLVMBuildGlobalStringPtr(ctx.builder, format.as_ptr(), cstr!("format"));
+
+    // First determine the length, then format into a buffer with room for the terminating zero
+    let mut call_args = vec![LLVMConstNull(LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0)), LLVMConstInt(size_type, 0, 0), format_ptr];
+    call_args.extend(args.iter().cloned());
+    let len = LLVMBuildCall(ctx.builder, snprintf, call_args.as_mut_ptr(), call_args.len() as c_uint, cstr!("format_len"));
+    let len = LLVMBuildZExtOrBitCast(ctx.builder, len, size_type, cstr!("len"));
+    let buf_size = LLVMBuildAdd(ctx.builder, len, LLVMConstInt(size_type, 1, 0), cstr!("buf_size"));
+
+    let buf = gen_alloc(ctx, buf_size, AllocationSource::Runtime, "buf");
+    call_args[0] = buf;
+    call_args[1] = buf_size;
+    LLVMBuildCall(ctx.builder, snprintf, call_args.as_mut_ptr(), call_args.len() as c_uint, cstr!(""));
+
+    let dst_var = ctx.get_variable(&dst.name, &dst.typ);
+    let data = ValueRef::new(buf, ptr_type(Type::UInt(IntSize::I8)));
+    dst_var.create_slice(ctx, &data, &ValueRef::new(len, ctx.target_machine.target.native_uint_type.clone()));
+}
+
+// @int_to_string(value, radix)
+pub unsafe fn gen_int_to_string(ctx: &mut Context, dst: &Var, value: &Operand, radix: &Operand)
+{
+    let int_size = ctx.target_machine.target.int_size;
+    let i32_type = LLVMInt32TypeInContext(ctx.context);
+    let i64_type = LLVMInt64TypeInContext(ctx.context);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+
+    let v = get_operand(ctx, value).load(ctx);
+    let (magnitude, negative) = if let Type::Int(_) = value.get_type(int_size) {
+        let v = LLVMBuildSExtOrBitCast(ctx.builder, v, i64_type, cstr!("int"));
+        let negative = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntSLT, v, LLVMConstInt(i64_type, 0, 0), cstr!("negative"));
+        (LLVMBuildSelect(ctx.builder, negative, LLVMBuildNeg(ctx.builder, v, cstr!("neg")), v, cstr!("magnitude")), negative)
+    } else {
+        (LLVMBuildZExtOrBitCast(ctx.builder, v, i64_type, cstr!("uint")), LLVMConstInt(LLVMInt1TypeInContext(ctx.context), 0, 0))
+    };
+
+    let i8_type = LLVMInt8TypeInContext(ctx.context);
+    let buf = ctx.entry_alloca(LLVMArrayType(i8_type, FORMAT_INT_BUFFER_SIZE as c_uint), "digits");
+    let buf_ptr = LLVMBuildBitCast(ctx.builder, buf, LLVMPointerType(i8_type, 0), cstr!("digits_ptr"));
+    let radix = LLVMBuildZExtOrBitCast(ctx.builder, get_operand(ctx, radix).load(ctx), i64_type, cstr!("radix"));
+    let mut args = vec![magnitude, negative, radix, buf_ptr];
+    let start = LLVMBuildCall(ctx.builder, runtime_function(ctx, "format_int"), args.as_mut_ptr(), args.len() as c_uint, cstr!("start"));
+
+    let len = LLVMBuildSub(ctx.builder, LLVMConstInt(size_type, FORMAT_INT_BUFFER_SIZE, 0), start, cstr!("len"));
+    let mut index = [start];
+    let digits = LLVMBuildGEP(ctx.builder, buf_ptr, index.as_mut_ptr(), 1, cstr!("digits"));
+    gen_snprintf(ctx, dst, "%.*s".into(), vec![LLVMBuildIntCast(ctx.builder, len, i32_type, cstr!("len")), digits]);
+}
+
+// @float_to_string(value, precision), with precision digits after the decimal point
+pub unsafe fn gen_float_to_string(ctx: &mut Context, dst: &Var, value: &Operand, precision: &Operand)
+{
+    let precision = get_operand(ctx, precision).load(ctx);
+    let v = get_operand(ctx, value).load(ctx);
+    let args = vec![
+        LLVMBuildIntCast(ctx.builder, precision, LLVMInt32TypeInContext(ctx.context), cstr!("precision")),
+        LLVMBuildFPCast(ctx.builder, v, LLVMDoubleTypeInContext(ctx.context), cstr!("double")),
+    ];
+    gen_snprintf(ctx, dst, "%.*f".into(), args);
+}
+
+// @parse_int and @parse_float, dst is an optional which is nil when the string is not a number
+pub unsafe fn gen_parse_number(ctx: &mut Context, dst: &Var, s: &Operand, intrinsic: Intrinsic)
+{
+    let s = get_operand(ctx, s);
+    let data = s.get_property(ctx, ByteCodeProperty::Data).value;
+    let len = s.get_property(ctx, ByteCodeProperty::Len).value;
+    let value_type = match dst.typ {
+        Type::Optional(ref inner) => ctx.resolve_type(inner),
+        _ => panic!("Internal Compiler Error: @{} must return an optional", intrinsic),
+    };
+
+    let (parsed_type, func) = if intrinsic == Intrinsic::ParseInt {
+        (LLVMInt64TypeInContext(ctx.context), "parse_int")
+    } else {
+        (LLVMDoubleTypeInContext(ctx.context), "parse_float")
+    };
+    let parsed = ctx.entry_alloca(parsed_type, "parsed");
+    let mut args = vec![LLVMBuildBitCast(ctx.builder, data, LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0), cstr!("data")), len, parsed];
+    let ok = LLVMBuildCall(ctx.builder, runtime_function(ctx, func), args.as_mut_ptr(), args.len() as c_uint, cstr!("ok"));
+    let value = LLVMBuildLoad(ctx.builder, parsed, cstr!("value"));
+
+    let (ok, value) = if intrinsic == Intrinsic::ParseInt && value_type != parsed_type {
+        // It must also fit in an int of the target
+        let truncated = LLVMBuildTrunc(ctx.builder, value, value_type, cstr!("truncated"));
+        let extended = LLVMBuildSExt(ctx.builder, truncated, parsed_type, cstr!("extended"));
+        let fits = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, extended, value, cstr!("fits"));
+        (LLVMBuildAnd(ctx.builder, ok, fits, cstr!("ok")), truncated)
+    } else {
+        (ok, value)
+    };
+
+    let dst_var = ctx.get_variable(&dst.name, &dst.typ);
+    LLVMBuildStore(ctx.builder, ok, LLVMBuildStructGEP(ctx.builder, dst_var.value, 0, cstr!("opt_flag_ptr")));
+    LLVMBuildStore(ctx.builder, value, LLVMBuildStructGEP(ctx.builder, dst_var.value, 1, cstr!("data_ptr")));
+}
+
+// @hex_encode(bytes), two lowercase digits per byte
+pub unsafe fn gen_hex_encode(ctx: &mut Context, dst: &Var, bytes: &Operand)
+{
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let bytes = get_operand(ctx, bytes);
+    let data = bytes.get_property(ctx, ByteCodeProperty::Data).value;
+    let len = bytes.get_property(ctx, ByteCodeProperty::Len).value;
+    let hex_len = LLVMBuildMul(ctx.builder, len, LLVMConstInt(size_type, 2, 0), cstr!("hex_len"));
+    let buf = gen_alloc(ctx, hex_len, AllocationSource::Runtime, "hex");
+
+    let mut args = vec![LLVMBuildBitCast(ctx.builder, data, LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0), cstr!("data")), len, buf];
+    LLVMBuildCall(ctx.builder, runtime_function(ctx, "hex_encode"), args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+
+    let dst_var = ctx.get_variable(&dst.name, &dst.typ);
+    let data = ValueRef::new(buf, ptr_type(Type::UInt(IntSize::I8)));
+    dst_var.create_slice(ctx, &data, &ValueRef::new(hex_len, ctx.target_machine.target.native_uint_type.clone()));
+}
+
+// @hex_decode(s), nil when s has an odd length or a character which is not a hex digit
+pub unsafe fn gen_hex_decode(ctx: &mut Context, dst: &Var, s: &Operand)
+{
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let s = get_operand(ctx, s);
+    let data = s.get_property(ctx, ByteCodeProperty::Data).value;
+    let len = s.get_property(ctx, ByteCodeProperty::Len).value;
+    let byte_len = LLVMBuildLShr(ctx.builder, len, LLVMConstInt(size_type, 1, 0), cstr!("byte_len"));
+    let buf = gen_alloc(ctx, byte_len, AllocationSource::Runtime, "bytes");
+
+    let mut args = vec![LLVMBuildBitCast(ctx.builder, data, LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0), cstr!("data")), len, buf];
+    let ok = LLVMBuildCall(ctx.builder, runtime_function(ctx, "hex_decode"), args.as_mut_ptr(), args.len() as c_uint, cstr!("ok"));
+
+    let slice_type = match dst.typ {
+        Type::Optional(ref inner) => (**inner).clone(),
+        _ => panic!("Internal Compiler Error: @hex_decode must return an optional"),
+    };
+    let dst_var = ctx.get_variable(&dst.name, &dst.typ);
+    LLVMBuildStore(ctx.builder, ok, LLVMBuildStructGEP(ctx.builder, dst_var.value, 0, cstr!("opt_flag_ptr")));
+    let bytes = ValueRef::new(LLVMBuildStructGEP(ctx.builder, dst_var.value, 1, cstr!("data_ptr")), slice_type);
+    let data = ValueRef::new(buf, ptr_type(Type::UInt(IntSize::I8)));
+    bytes.create_slice(ctx, &data, &ValueRef::new(byte_len, ctx.target_machine.target.native_uint_type.clone()));
+}
+
+// @from_utf8(bytes), the string shares the memory of the bytes, nil when they are not valid UTF-8
+pub unsafe fn gen_from_utf8(ctx: &mut Context, dst: &Var, bytes: &Operand)
+{
+    let bytes = get_operand(ctx, bytes);
+    let data = bytes.get_property(ctx, ByteCodeProperty::Data).value;
+    let data = LLVMBuildBitCast(ctx.builder, data, LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0), cstr!("data"));
+    let len = bytes.get_property(ctx, ByteCodeProperty::Len).value;
+    let mut args = vec![data, len];
+    let ok = LLVMBuildCall(ctx.builder, runtime_function(ctx, "utf8_valid"), args.as_mut_ptr(), args.len() as c_uint, cstr!("ok"));
+
+    let dst_var = ctx.get_variable(&dst.name, &dst.typ);
+    LLVMBuildStore(ctx.builder, ok, LLVMBuildStructGEP(ctx.builder, dst_var.value, 0, cstr!("opt_flag_ptr")));
+    let s = ValueRef::new(LLVMBuildStructGEP(ctx.builder, dst_var.value, 1, cstr!("string_ptr")), Type::String);
+    s.create_slice(ctx, &ValueRef::new(data, ptr_type(Type::UInt(IntSize::I8))), &ValueRef::new(len, ctx.target_machine.target.native_uint_type.clone()));
+}
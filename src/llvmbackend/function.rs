@@ -1,5 +1,5 @@
 use std::ffi::{CString};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 use libc;
 use llvm::core::*;
@@ -8,30 +8,28 @@ use llvm::prelude::*;
 use ast::*;
 use bytecode::*;
 use span::Span;
+use super::abi::{function_abi, gen_arguments};
+use super::closure::gen_trampoline_slots;
 use super::symboltable::FunctionInstance;
 use super::context::Context;
+use super::debuginfo::{start_function, end_function, declare_variable};
 use super::instructions::*;
 use super::valueref::ValueRef;
 
 
 pub unsafe fn gen_function_sig(ctx: &mut Context, sig: &FunctionSignature, name_override: Option<&str>)
 {
-    let ret_type = ctx.resolve_type(&sig.return_type);
-    let mut arg_types: Vec<_> = sig.args.iter().map(|arg|{
-        let llvm_type = ctx.resolve_type(&arg.typ);
-        if arg.typ.pass_by_value() {
-            llvm_type
-        } else {
-            LLVMPointerType(llvm_type, 0)
-        }
-    }).collect();
-
-    let function_type = LLVMFunctionType(ret_type, arg_types.as_mut_ptr(), arg_types.len() as libc::c_uint, 0);
+    let abi = function_abi(ctx, sig);
     let llvm_name = name_override.unwrap_or(&sig.name);
     let cstring = CString::new(llvm_name.as_bytes()).expect("Invalid string");
     let name = cstring.as_ptr();
-    let func = LLVMAddFunction(ctx.module, name, function_type);
-    let fi = FunctionInstance::new(&sig.name, func, sig.return_type.clone(), sig.get_type());
+    let func = LLVMAddFunction(ctx.module, name, abi.function_type);
+    for (idx, attribute) in abi.param_attributes(ctx) {
+        LLVMAddAttributeAtIndex(func, idx as libc::c_uint + 1, attribute);
+    }
+
+    let mut fi = FunctionInstance::new(&sig.name, func, sig.return_type.clone(), sig.get_type());
+    fi.abi = Some(abi);
     ctx.add_function(Rc::new(fi));
 }
 
@@ -41,6 +39,56 @@ pub unsafe fn gen_function_ptr(ctx: &mut Context, name: &str, func_ptr: LLVMValu
     ctx.add_function(Rc::new(fi));
 }
 
+// Variables passed by value which are the result of more than one instruction
+fn reassigned_values(func: &ByteCodeFunction) -> Vec<Var>
+{
+    let mut counts: BTreeMap<&str, (&Var, usize)> = BTreeMap::new();
+    for block in func.blocks.values() {
+        for inst in &block.instructions {
+            let dst = match *inst {
+                Instruction::Store{ref dst, ..} |
+                Instruction::Load{ref dst, ..} |
+                Instruction::LoadMember{ref dst, ..} |
+                Instruction::UnaryOp{ref dst, ..} |
+                Instruction::BinaryOp{ref dst, ..} |
+                Instruction::Cast{ref dst, ..} |
+                Instruction::GetProperty{ref dst, ..} |
+                Instruction::LoadOptionalFlag{ref dst, ..} |
+                Instruction::Call{dst: Some(ref dst), ..} |
+                Instruction::InterfaceCall{dst: Some(ref dst), ..} |
+                Instruction::Intrinsic{dst: Some(ref dst), ..} => dst,
+                _ => continue,
+            };
+
+            if dst.typ.pass_by_value() {
+                counts.entry(&dst.name).or_insert((dst, 0)).1 += 1;
+            }
+        }
+    }
+
+    counts.values()
+        .filter(|&&(_, count)| count > 1)
+        .map(|&(var, _)| var.clone())
+        .collect()
+}
+
+// Variables passed by value whose address is taken, so they have to be in memory
+fn addressed_values(func: &ByteCodeFunction) -> Vec<Var>
+{
+    let mut addressed: BTreeMap<&str, &Var> = BTreeMap::new();
+    for block in func.blocks.values() {
+        for inst in &block.instructions {
+            if let Instruction::AddressOf{ref obj, ..} = *inst {
+                if obj.typ.pass_by_value() && !obj.typ.is_pointer() {
+                    addressed.insert(&obj.name, obj);
+                }
+            }
+        }
+    }
+
+    addressed.values().map(|&var| var.clone()).collect()
+}
+
 pub unsafe fn gen_function(ctx: &mut Context, func: &ByteCodeFunction)
 {
     let fi = ctx.get_function(&func.sig.name).expect("Internal Compiler Error: Unknown function");
@@ -48,29 +96,41 @@ pub unsafe fn gen_function(ctx: &mut Context, func: &ByteCodeFunction)
     LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
 
     ctx.push_stack(fi.function);
+    start_function(ctx, fi.function, &func.sig);
+    if let Some(ref mut hp) = ctx.heap_profile {
+        hp.function = func.sig.name.clone();
+        hp.file = func.sig.span.file.clone();
+        hp.location = func.sig.span.start;
+    }
 
-    for (i, arg) in func.sig.args.iter().enumerate() {
-        let var = LLVMGetParam(fi.function, i as libc::c_uint);
+    let addressed = addressed_values(func);
+    let arg_values = gen_arguments(ctx, &fi, &func.sig);
+    for (i, (arg, &var)) in func.sig.args.iter().zip(arg_values.iter()).enumerate() {
         match arg.typ
         {
             Type::Func(ref ft) => {
                 gen_function_ptr(ctx, &arg.name, var, ft.return_type.clone(), arg.typ.clone());
                 ctx.set_variable(&arg.name, ValueRef::new(var, arg.typ.clone()));
+                declare_variable(ctx, &arg.name, &arg.typ, var, false, i + 1);
             },
 
             _ => {
                 if arg.typ.pass_by_value() {
-                    if arg.mutable && !arg.typ.is_pointer() {
-                        // To make it mutable, copy the argument into a local variable
+                    let is_addressed = addressed.iter().any(|v| v.name == arg.name);
+                    if (arg.mutable || is_addressed) && !arg.typ.is_pointer() {
+                        // To make it mutable, or to take its address, copy the argument into a local variable
                         // and use that instead
                         let argcopy = LLVMBuildAlloca(ctx.builder, ctx.resolve_type(&arg.typ), cstr!("argcopy"));
                         LLVMBuildStore(ctx.builder, var, argcopy);
                         ctx.set_variable(&arg.name, ValueRef::new(argcopy, ptr_type(arg.typ.clone())));
+                        declare_variable(ctx, &arg.name, &arg.typ, argcopy, true, i + 1);
                     } else {
                         ctx.set_variable(&arg.name, ValueRef::new(var, arg.typ.clone()));
+                        declare_variable(ctx, &arg.name, &arg.typ, var, false, i + 1);
                     }
                 } else {
                     ctx.set_variable(&arg.name, ValueRef::new(var, ptr_type(arg.typ.clone())));
+                    declare_variable(ctx, &arg.name, &arg.typ, var, true, i + 1);
                 }
             },
         }
@@ -87,6 +147,14 @@ pub unsafe fn gen_function(ctx: &mut Context, func: &ByteCodeFunction)
         }
     }
 
+    // A value which is assigned in more than one place, like the result of an if, needs to be on the
+    // stack, the first assignment would otherwise make it an SSA value which the others cannot store to.
+    // The same goes for a value whose address is taken.
+    for var in reassigned_values(func).into_iter().chain(addressed) {
+        ctx.get_variable(&var.name, &var.typ);
+    }
+    gen_trampoline_slots(ctx, func);
+
     for (bb_ref, block) in &func.blocks {
         let bb = blocks.get(bb_ref).expect("Unknown basic block");
         LLVMPositionBuilderAtEnd(ctx.builder, *bb);
@@ -95,6 +163,9 @@ pub unsafe fn gen_function(ctx: &mut Context, func: &ByteCodeFunction)
         }
     }
 
+    end_function(ctx);
+    ctx.return_slot = None;
+    ctx.trampoline_slots.clear();
     ctx.pop_stack();
 }
 
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::rc::Rc;
+use libc::c_uint;
+use llvm::{LLVMLinkage, LLVMIntPredicate};
+use llvm::core::*;
+use llvm::prelude::*;
+use ast::{Type, func_type};
+use span::Pos;
+use super::context::Context;
+use super::runtime::runtime_function;
+use super::symboltable::FunctionInstance;
+
+/*
+Heap profiling, enabled with --instrument heap. Allocations call menhir_heap_alloc instead of
+menhir_alloc, passing the index of the allocation site: the statement doing the allocation.
+menhir_heap_alloc puts a header in front of the memory with the size and the site, so that
+menhir_heap_free knows which site to account a free to.
+
+Per site the number of allocations, the live allocations, the live bytes and the peak of the live
+bytes are counted. When the program exits, menhir_heap_report prints them on stderr, for every
+site which allocated something.
+
+Leak checks (the leak_checks profile setting, on in debug builds) use the same counters, but only
+for allocations done with new. At exit only the sites with live allocations are printed.
+
+Memory which is not allocated by the program itself (for example by a C library) must not be
+deleted by an instrumented program, because it has no header.
+*/
+
+const HEADER_SIZE: u64 = 16;
+
+// Counters per site, in this order
+const ALLOCATIONS: u64 = 0;
+const LIVE_COUNT: u64 = 1;
+const LIVE_BYTES: u64 = 2;
+const PEAK_BYTES: u64 = 3;
+const NUM_COUNTERS: c_uint = 4;
+
+pub const HEAP_REPORT_FUNCTION: &str = "menhir_heap_report";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeapProfileMode
+{
+    // Count all allocations, and report every site
+    Profile,
+    // Count allocations with new, and report the sites with allocations that were never deleted
+    Leaks,
+}
+
+#[derive(Clone, Copy)]
+pub enum AllocationSource
+{
+    New,
+    // Allocations done by the generated code itself, like the buffer of an interpolated string
+    Runtime,
+}
+
+pub struct HeapProfile
+{
+    mode: HeapProfileMode,
+    // Description of every site, the index of a site is passed to menhir_heap_alloc
+    sites: Vec<String>,
+    site_indices: HashMap<String, usize>,
+    // Where the code which is being generated comes from
+    pub function: String,
+    pub file: String,
+    pub location: Pos,
+}
+
+impl HeapProfile
+{
+    pub fn new(mode: HeapProfileMode) -> HeapProfile
+    {
+        HeapProfile{
+            mode: mode,
+            sites: Vec::new(),
+            site_indices: HashMap::new(),
+            function: String::new(),
+            file: String::new(),
+            location: Pos::new(0, 0),
+        }
+    }
+
+    fn current_site(&mut self) -> usize
+    {
+        let site = format!("{}:{} ({})", self.file, self.location, self.function);
+        if let Some(idx) = self.site_indices.get(&site) {
+            return *idx;
+        }
+
+        let idx = self.sites.len();
+        self.sites.push(site.clone());
+        self.site_indices.insert(site, idx);
+        idx
+    }
+}
+
+unsafe fn i8_ptr_type(ctx: &Context) -> LLVMTypeRef
+{
+    LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0)
+}
+
+unsafe fn size_type(ctx: &Context) -> LLVMTypeRef
+{
+    ctx.resolve_type(&ctx.target_machine.target.native_uint_type)
+}
+
+// Get or declare one of the profiling functions, their bodies are generated by add_heap_profile_functions
+unsafe fn profile_function(ctx: &Context, name: &str, ret_type: LLVMTypeRef, arg_types: &mut [LLVMTypeRef]) -> LLVMValueRef
+{
+    let cname = CString::new(name).expect("Invalid string");
+    let func = LLVMGetNamedFunction(ctx.module, cname.as_ptr());
+    if !func.is_null() {
+        return func;
+    }
+
+    let function_type = LLVMFunctionType(ret_type, arg_types.as_mut_ptr(), arg_types.len() as c_uint, 0);
+    let func = LLVMAddFunction(ctx.module, cname.as_ptr(), function_type);
+    LLVMSetLinkage(func, LLVMLinkage::LLVMInternalLinkage);
+    func
+}
+
+unsafe fn heap_alloc_function(ctx: &Context) -> LLVMValueRef
+{
+    profile_function(ctx, "menhir_heap_alloc", i8_ptr_type(ctx), &mut [size_type(ctx), LLVMInt64TypeInContext(ctx.context)])
+}
+
+unsafe fn heap_free_function(ctx: &Context) -> LLVMValueRef
+{
+    profile_function(ctx, "menhir_heap_free", LLVMVoidTypeInContext(ctx.context), &mut [i8_ptr_type(ctx)])
+}
+
+// Allocate size bytes, through the heap profiler if it is enabled
+pub unsafe fn gen_alloc(ctx: &mut Context, size: LLVMValueRef, source: AllocationSource, name: &str) -> LLVMValueRef
+{
+    let name = CString::new(name).expect("Invalid string");
+    let site = match (&mut ctx.heap_profile, source) {
+        (&mut Some(ref hp), AllocationSource::Runtime) if hp.mode == HeapProfileMode::Leaks => None,
+        (&mut Some(ref mut hp), _) => Some(hp.current_site()),
+        (&mut None, _) => None,
+    };
+
+    let site = match site {
+        Some(site) => site,
+        None => {
+            let mut args = vec![size];
+            return LLVMBuildCall(ctx.builder, runtime_function(ctx, "alloc"), args.as_mut_ptr(), args.len() as c_uint, name.as_ptr());
+        },
+    };
+
+    let mut args = vec![size, LLVMConstInt(LLVMInt64TypeInContext(ctx.context), site as u64, 0)];
+    LLVMBuildCall(ctx.builder, heap_alloc_function(ctx), args.as_mut_ptr(), args.len() as c_uint, name.as_ptr())
+}
+
+// Free memory allocated with new
+pub unsafe fn gen_free(ctx: &mut Context, ptr: LLVMValueRef)
+{
+    let ptr = LLVMBuildBitCast(ctx.builder, ptr, i8_ptr_type(ctx), cstr!("ptr"));
+    let func = if ctx.heap_profile.is_some() {heap_free_function(ctx)} else {runtime_function(ctx, "free")};
+    let mut args = vec![ptr];
+    LLVMBuildCall(ctx.builder, func, args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+}
+
+unsafe fn add_global(ctx: &Context, name: &str, typ: LLVMTypeRef) -> LLVMValueRef
+{
+    let cname = CString::new(name).expect("Invalid string");
+    let glob = LLVMAddGlobal(ctx.module, typ, cname.as_ptr());
+    LLVMSetInitializer(glob, LLVMConstNull(typ));
+    LLVMSetLinkage(glob, LLVMLinkage::LLVMInternalLinkage);
+    glob
+}
+
+unsafe fn counter(ctx: &Context, counters: LLVMValueRef, site: LLVMValueRef, which: u64) -> LLVMValueRef
+{
+    let int32_type = LLVMInt32TypeInContext(ctx.context);
+    let mut indices = [LLVMConstInt(int32_type, 0, 0), site, LLVMConstInt(int32_type, which, 0)];
+    LLVMBuildGEP(ctx.builder, counters, indices.as_mut_ptr(), indices.len() as c_uint, cstr!("counter"))
+}
+
+unsafe fn add_to(ctx: &Context, ptr: LLVMValueRef, value: LLVMValueRef) -> LLVMValueRef
+{
+    let sum = LLVMBuildAdd(ctx.builder, LLVMBuildLoad(ctx.builder, ptr, cstr!("old")), value, cstr!("new"));
+    LLVMBuildStore(ctx.builder, sum, ptr);
+    sum
+}
+
+unsafe fn store_max(ctx: &Context, ptr: LLVMValueRef, value: LLVMValueRef)
+{
+    let old = LLVMBuildLoad(ctx.builder, ptr, cstr!("old"));
+    let bigger = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntUGT, value, old, cstr!("bigger"));
+    LLVMBuildStore(ctx.builder, LLVMBuildSelect(ctx.builder, bigger, value, old, cstr!("max")), ptr);
+}
+
+// fn menhir_heap_alloc(size: uint, site: i64) -> *void
+unsafe fn gen_heap_alloc(ctx: &Context, counters: LLVMValueRef, live_bytes: LLVMValueRef, peak_bytes: LLVMValueRef)
+{
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let func = heap_alloc_function(ctx);
+    let (size, site) = (LLVMGetParam(func, 0), LLVMGetParam(func, 1));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let failed_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("failed"));
+    let count_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("count"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let total = LLVMBuildAdd(ctx.builder, size, LLVMConstInt(size_type(ctx), HEADER_SIZE, 0), cstr!("total"));
+    let mut args = vec![total];
+    let mem = LLVMBuildCall(ctx.builder, runtime_function(ctx, "alloc"), args.as_mut_ptr(), args.len() as c_uint, cstr!("mem"));
+    LLVMBuildCondBr(ctx.builder, LLVMBuildIsNull(ctx.builder, mem, cstr!("failed")), failed_bb, count_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, failed_bb);
+    LLVMBuildRet(ctx.builder, mem);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, count_bb);
+    let size = LLVMBuildZExtOrBitCast(ctx.builder, size, int64_type, cstr!("size"));
+    let header = LLVMBuildBitCast(ctx.builder, mem, LLVMPointerType(int64_type, 0), cstr!("header"));
+    LLVMBuildStore(ctx.builder, size, header);
+    let mut site_index = [LLVMConstInt(int64_type, 1, 0)];
+    LLVMBuildStore(ctx.builder, site, LLVMBuildGEP(ctx.builder, header, site_index.as_mut_ptr(), 1, cstr!("site")));
+
+    let one = LLVMConstInt(int64_type, 1, 0);
+    add_to(ctx, counter(ctx, counters, site, ALLOCATIONS), one);
+    add_to(ctx, counter(ctx, counters, site, LIVE_COUNT), one);
+    let site_bytes = add_to(ctx, counter(ctx, counters, site, LIVE_BYTES), size);
+    store_max(ctx, counter(ctx, counters, site, PEAK_BYTES), site_bytes);
+    let total_bytes = add_to(ctx, live_bytes, size);
+    store_max(ctx, peak_bytes, total_bytes);
+
+    let mut data_index = [LLVMConstInt(int64_type, HEADER_SIZE, 0)];
+    LLVMBuildRet(ctx.builder, LLVMBuildGEP(ctx.builder, mem, data_index.as_mut_ptr(), 1, cstr!("data")));
+}
+
+// fn menhir_heap_free(ptr: *void)
+unsafe fn gen_heap_free(ctx: &Context, counters: LLVMValueRef, live_bytes: LLVMValueRef)
+{
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let func = heap_free_function(ctx);
+    let ptr = LLVMGetParam(func, 0);
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let free_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("free"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("done"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    LLVMBuildCondBr(ctx.builder, LLVMBuildIsNull(ctx.builder, ptr, cstr!("is_null")), done_bb, free_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, free_bb);
+    let mut mem_index = [LLVMConstInt(int64_type, (HEADER_SIZE as i64).wrapping_neg() as u64, 1)];
+    let mem = LLVMBuildGEP(ctx.builder, ptr, mem_index.as_mut_ptr(), 1, cstr!("mem"));
+    let header = LLVMBuildBitCast(ctx.builder, mem, LLVMPointerType(int64_type, 0), cstr!("header"));
+    let size = LLVMBuildLoad(ctx.builder, header, cstr!("size"));
+    let mut site_index = [LLVMConstInt(int64_type, 1, 0)];
+    let site = LLVMBuildLoad(ctx.builder, LLVMBuildGEP(ctx.builder, header, site_index.as_mut_ptr(), 1, cstr!("site_ptr")), cstr!("site"));
+
+    let size = LLVMBuildNeg(ctx.builder, size, cstr!("neg_size"));
+    add_to(ctx, counter(ctx, counters, site, LIVE_COUNT), LLVMConstInt(int64_type, (-1i64) as u64, 1));
+    add_to(ctx, counter(ctx, counters, site, LIVE_BYTES), size);
+    add_to(ctx, live_bytes, size);
+
+    let mut args = vec![mem];
+    LLVMBuildCall(ctx.builder, runtime_function(ctx, "free"), args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+    LLVMBuildBr(ctx.builder, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    LLVMBuildRetVoid(ctx.builder);
+}
+
+// int dprintf(int fd, const char* format, ...)
+unsafe fn dprintf_function(ctx: &Context) -> LLVMValueRef
+{
+    let int32_type = LLVMInt32TypeInContext(ctx.context);
+    let mut arg_types = vec![int32_type, i8_ptr_type(ctx)];
+    let function_type = LLVMFunctionType(int32_type, arg_types.as_mut_ptr(), arg_types.len() as c_uint, 1);
+    let name = CString::new("dprintf").expect("Invalid string");
+    let func = LLVMGetNamedFunction(ctx.module, name.as_ptr());
+    if func.is_null() {
+        LLVMAddFunction(ctx.module, name.as_ptr(), function_type)
+    } else if LLVMTypeOf(func) != LLVMPointerType(function_type, 0) {
+        LLVMConstBitCast(func, LLVMPointerType(function_type, 0))
+    } else {
+        func
+    }
+}
+
+unsafe fn gen_print(ctx: &Context, format: &str, args: &[LLVMValueRef])
+{
+    let format = CString::new(format).expect("Invalid format string");
+    let mut call_args = vec![
+        LLVMConstInt(LLVMInt32TypeInContext(ctx.context), 2, 0),
+        LLVMBuildGlobalStringPtr(ctx.builder, format.as_ptr(), cstr!("format")),
+    ];
+    call_args.extend(args.iter().cloned());
+    LLVMBuildCall(ctx.builder, dprintf_function(ctx), call_args.as_mut_ptr(), call_args.len() as c_uint, cstr!(""));
+}
+
+// Print the values of a site, with the format, unless the counter which is skip_if_zero is zero
+unsafe fn gen_print_site(ctx: &Context, func: LLVMValueRef, counters: LLVMValueRef, idx: usize, site: &str, skip_if_zero: u64, format: &str)
+{
+    let site_index = LLVMConstInt(LLVMInt32TypeInContext(ctx.context), idx as u64, 0);
+    let values: Vec<LLVMValueRef> = (0..NUM_COUNTERS as u64)
+        .map(|which| LLVMBuildLoad(ctx.builder, counter(ctx, counters, site_index, which), cstr!("value")))
+        .collect();
+
+    let print_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("print"));
+    let next_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("next"));
+    let zero = LLVMConstInt(LLVMInt64TypeInContext(ctx.context), 0, 0);
+    let skip = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, values[skip_if_zero as usize], zero, cstr!("skip"));
+    LLVMBuildCondBr(ctx.builder, skip, next_bb, print_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, print_bb);
+    let site = CString::new(site).expect("Invalid string");
+    let mut args = vec![LLVMBuildGlobalStringPtr(ctx.builder, site.as_ptr(), cstr!("site"))];
+    args.extend(values);
+    gen_print(ctx, format, &args);
+    LLVMBuildBr(ctx.builder, next_bb);
+    LLVMPositionBuilderAtEnd(ctx.builder, next_bb);
+}
+
+// fn menhir_heap_report(), prints the counters of every site which allocated something, or with leak checks the sites which leaked
+unsafe fn gen_heap_report(ctx: &Context, mode: HeapProfileMode, sites: &[String], counters: LLVMValueRef, live_bytes: LLVMValueRef, peak_bytes: LLVMValueRef) -> LLVMValueRef
+{
+    let func = profile_function(ctx, HEAP_REPORT_FUNCTION, LLVMVoidTypeInContext(ctx.context), &mut []);
+    LLVMPositionBuilderAtEnd(ctx.builder, LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry")));
+
+    if mode == HeapProfileMode::Leaks {
+        for (idx, site) in sites.iter().enumerate() {
+            gen_print_site(ctx, func, counters, idx, site, LIVE_COUNT, "leak: %4$llu bytes in %3$llu allocations done at %1$s, which were never deleted\n");
+        }
+        LLVMBuildRetVoid(ctx.builder);
+        return func;
+    }
+
+    let live = LLVMBuildLoad(ctx.builder, live_bytes, cstr!("live_bytes"));
+    let peak = LLVMBuildLoad(ctx.builder, peak_bytes, cstr!("peak_bytes"));
+    gen_print(ctx, "heap profile: peak usage %llu bytes, %llu bytes still live at exit\n", &[peak, live]);
+    gen_print(ctx, &format!("{:<50} {:>12} {:>12} {:>12} {:>12}\n", "site", "allocations", "live", "live bytes", "peak bytes"), &[]);
+
+    for (idx, site) in sites.iter().enumerate() {
+        gen_print_site(ctx, func, counters, idx, site, ALLOCATIONS, "%-50s %12llu %12llu %12llu %12llu\n");
+    }
+
+    LLVMBuildRetVoid(ctx.builder);
+    func
+}
+
+// Generate the profiling functions, once all allocation sites are known
+pub unsafe fn add_heap_profile_functions(ctx: &mut Context)
+{
+    let (mode, sites) = match ctx.heap_profile {
+        Some(ref hp) => (hp.mode, hp.sites.clone()),
+        None => return,
+    };
+
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let counters_type = LLVMArrayType(LLVMArrayType(int64_type, NUM_COUNTERS), sites.len() as c_uint);
+    let counters = add_global(ctx, "menhir_heap_sites", counters_type);
+    let live_bytes = add_global(ctx, "menhir_heap_live_bytes", int64_type);
+    let peak_bytes = add_global(ctx, "menhir_heap_peak_bytes", int64_type);
+
+    gen_heap_alloc(ctx, counters, live_bytes, peak_bytes);
+    gen_heap_free(ctx, counters, live_bytes);
+    let report = gen_heap_report(ctx, mode, &sites, counters, live_bytes, peak_bytes);
+    ctx.add_function(Rc::new(FunctionInstance::new(HEAP_REPORT_FUNCTION, report, Type::Void, func_type(Vec::new(), Type::Void))));
+}
@@ -6,10 +6,19 @@ use llvm::*;
 use llvm::core::*;
 use llvm::prelude::*;
 use bytecode::*;
-use ast::{Type, UnaryOperator, BinaryOperator, ptr_type};
+use ast::{Type, UnaryOperator, BinaryOperator, LoopHints, ptr_type};
+use super::abi::gen_call;
+use super::closure::{gen_make_closure, gen_free_trampolines};
+use super::format::gen_format;
 use super::function::gen_function_ptr;
+use super::interface::{gen_make_interface, gen_interface_call};
+use super::intrinsics::gen_intrinsic;
+use super::heapprofile::{gen_alloc, gen_free, AllocationSource};
+use super::runtime::{runtime_function, gen_ref_counted, STDERR_FILENO, REF_COUNT_HEADER_SIZE};
 use super::valueref::ValueRef;
 use super::context::Context;
+use llvm::debuginfo::{LLVMTemporaryMDNode, LLVMMetadataReplaceAllUsesWith};
+use super::debuginfo::set_location;
 use super::types::native_llvm_int_type;
 
 pub unsafe fn const_int(ctx: &Context, v: i64) -> LLVMValueRef
@@ -27,11 +36,6 @@ pub unsafe fn const_bool(ctx: &Context, v: bool) -> LLVMValueRef
     LLVMConstInt(LLVMInt1TypeInContext(ctx.context), if v {1} else {0}, 0)
 }
 
-pub unsafe fn const_float(ctx: &Context, v: f64) -> LLVMValueRef
-{
-    LLVMConstReal(LLVMDoubleTypeInContext(ctx.context), v)
-}
-
 pub unsafe fn const_char(ctx: &Context, c: char) -> LLVMValueRef
 {
     LLVMConstInt(LLVMInt32TypeInContext(ctx.context), c as c_ulonglong, 0)
@@ -84,6 +88,15 @@ pub unsafe fn get_operand(ctx: &mut Context, operand: &Operand) -> ValueRef
             let llvm_type = ctx.resolve_type(typ);
             ValueRef::new(LLVMSizeOf(llvm_type), ctx.target_machine.target.native_uint_type.clone())
         }
+
+        Operand::OffsetOf(ref typ, index) => {
+            let position = match *typ {
+                Type::Struct(ref st) => ctx.struct_member_position(st, index),
+                _ => index,
+            };
+            let offset = ctx.target_machine.offset_of_element(ctx.resolve_type(typ), position);
+            ValueRef::new(const_uint(ctx, offset as u64), ctx.target_machine.target.native_uint_type.clone())
+        }
     }
 }
 
@@ -99,21 +112,20 @@ pub unsafe fn copy(ctx: &Context, dst: LLVMValueRef, src: LLVMValueRef, typ: LLV
     LLVMBuildCall(ctx.builder, func.function, args.as_mut_ptr(), args.len() as c_uint, cstr!("ac"));
 }
 
-unsafe fn get_function_arg(ctx: &mut Context, operand: &Operand) -> LLVMValueRef
+pub unsafe fn get_function_arg(ctx: &mut Context, operand: &Operand) -> LLVMValueRef
 {
     match *operand
     {
         Operand::Var(ref v) => {
             let src = ctx.get_variable(&v.name, &v.typ);
-            if !src.typ.is_pointer() {
+            // A pointer which is the value of the variable, and not the memory it is stored in, is passed as is
+            if !src.typ.is_pointer() || v.typ == src.typ {
                 return src.value
             }
 
             let inner_type = src.typ.get_pointer_element_type().expect("Expecting pointer type here");
             if inner_type.pass_by_value() {
                 src.load(ctx)
-            } else if v.typ == src.typ {
-                src.value
             } else {
                 let dst = ctx.stack_alloc("argcopy", inner_type);
                 copy(ctx, dst, src.value, ctx.resolve_type(inner_type));
@@ -222,9 +234,20 @@ unsafe fn gen_cast(ctx: &mut Context, dst: &Var, src: &Operand)
     let src_type = src.get_type(ctx.target_machine.target.int_size);
     let casted = match (&dst.typ, &src_type)
     {
+        // Integers of a different size are truncated, or extended according to the sign of the source
+        (&Type::Int(ref to), &Type::Int(ref from)) |
+        (&Type::UInt(ref to), &Type::Int(ref from)) if to.size_in_bits() > from.size_in_bits() =>
+            LLVMBuildSExt(ctx.builder, operand.load(ctx), ctx.resolve_type(&dst.typ), cstr!("cast_to_int")),
+
+        (&Type::UInt(ref to), &Type::UInt(ref from)) |
+        (&Type::Int(ref to), &Type::UInt(ref from)) if to.size_in_bits() > from.size_in_bits() =>
+            LLVMBuildZExt(ctx.builder, operand.load(ctx), ctx.resolve_type(&dst.typ), cstr!("cast_to_int")),
+
+        (&Type::Int(_), &Type::Int(_)) |
+        (&Type::UInt(_), &Type::UInt(_)) |
         (&Type::UInt(_), &Type::Int(_)) |
         (&Type::Int(_), &Type::UInt(_)) =>
-            LLVMBuildIntCast(ctx.builder, operand.load(ctx), ctx.resolve_type(&dst.typ), cstr!("cast_to_int")),
+            LLVMBuildTruncOrBitCast(ctx.builder, operand.load(ctx), ctx.resolve_type(&dst.typ), cstr!("cast_to_int")),
 
         (&Type::Int(_), &Type::Float(_)) =>
             LLVMBuildFPToSI(ctx.builder, operand.load(ctx), ctx.resolve_type(&dst.typ), cstr!("cast_to_int")),
@@ -238,8 +261,10 @@ unsafe fn gen_cast(ctx: &mut Context, dst: &Var, src: &Operand)
         (&Type::Float(_), &Type::UInt(_)) =>
             LLVMBuildUIToFP(ctx.builder, operand.load(ctx), ctx.resolve_type(&dst.typ), cstr!("cast_to_int")),
 
-        (&Type::Pointer(_), &Type::Pointer(_)) =>
-            LLVMBuildBitCast(ctx.builder, operand.value, ctx.resolve_type(&dst.typ), cstr!("ptr_cast")),
+        (&Type::Pointer(_), &Type::Pointer(_)) => {
+            let ptr = if let Operand::Var(ref v) = *src {get_pointer(ctx, v)} else {operand.value};
+            LLVMBuildBitCast(ctx.builder, ptr, ctx.resolve_type(&dst.typ), cstr!("ptr_cast"))
+        },
 
         (&Type::Pointer(_), &Type::Array(_)) =>
             LLVMBuildBitCast(ctx.builder, operand.load(ctx), ctx.resolve_type(&dst.typ), cstr!("ptr_cast")),
@@ -250,6 +275,108 @@ unsafe fn gen_cast(ctx: &mut Context, dst: &Var, src: &Operand)
     ctx.set_variable(&dst.name, ValueRef::new(casted, dst.typ.clone()));
 }
 
+// Attach llvm.loop metadata to the branch back to the start of a loop
+unsafe fn set_loop_metadata(ctx: &Context, branch: LLVMValueRef, hints: &LoopHints)
+{
+    let i32_type = LLVMInt32TypeInContext(ctx.context);
+    let hint = |name: &str, value: LLVMValueRef| {
+        let mut ops = [LLVMMDStringInContext(ctx.context, name.as_ptr() as *const c_char, name.len() as c_uint), value];
+        LLVMMDNodeInContext(ctx.context, ops.as_mut_ptr(), ops.len() as c_uint)
+    };
+
+    // The first operand of a loop id is the node itself, a temporary node stands in for it until the node exists
+    let temp = LLVMTemporaryMDNode(ctx.context, ptr::null_mut(), 0);
+    let mut ops = vec![LLVMMetadataAsValue(ctx.context, temp)];
+    if hints.vectorize {
+        ops.push(hint("llvm.loop.vectorize.enable", const_bool(ctx, true)));
+    }
+    if let Some(width) = hints.vectorize_width {
+        ops.push(hint("llvm.loop.vectorize.width", LLVMConstInt(i32_type, width as c_ulonglong, 0)));
+    }
+    if let Some(count) = hints.unroll_count {
+        ops.push(hint("llvm.loop.unroll.count", LLVMConstInt(i32_type, count as c_ulonglong, 0)));
+    }
+
+    let loop_id = LLVMMDNodeInContext(ctx.context, ops.as_mut_ptr(), ops.len() as c_uint);
+    LLVMMetadataReplaceAllUsesWith(temp, LLVMValueAsMetadata(loop_id));
+    let kind = "llvm.loop";
+    LLVMSetMetadata(branch, LLVMGetMDKindIDInContext(ctx.context, kind.as_ptr() as *const c_char, kind.len() as c_uint), loop_id);
+}
+
+// Compare the index and the length in the widest of both types, a negative index becomes a huge unsigned value
+unsafe fn gen_bounds_check(ctx: &mut Context, index: &Operand, len: &Operand, inclusive: bool, location: &str)
+{
+    let signed = match index.get_type(ctx.target_machine.target.int_size) {
+        Type::Int(_) => true,
+        _ => false,
+    };
+    let index = get_operand(ctx, index).load(ctx);
+    let len = get_operand(ctx, len).load(ctx);
+    let (index, len) = if LLVMGetIntTypeWidth(LLVMTypeOf(index)) > LLVMGetIntTypeWidth(LLVMTypeOf(len)) {
+        (index, LLVMBuildZExt(ctx.builder, len, LLVMTypeOf(index), cstr!("len")))
+    } else if signed {
+        (LLVMBuildSExtOrBitCast(ctx.builder, index, LLVMTypeOf(len), cstr!("index")), len)
+    } else {
+        (LLVMBuildZExtOrBitCast(ctx.builder, index, LLVMTypeOf(len), cstr!("index")), len)
+    };
+
+    let func = ctx.get_current_function();
+    let fail_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("out_of_bounds"));
+    let ok_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("in_bounds"));
+    let predicate = if inclusive {LLVMIntPredicate::LLVMIntULE} else {LLVMIntPredicate::LLVMIntULT};
+    let in_bounds = LLVMBuildICmp(ctx.builder, predicate, index, len, cstr!("in_bounds"));
+    LLVMBuildCondBr(ctx.builder, in_bounds, ok_bb, fail_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, fail_bb);
+    let msg = format!("{}: {} out of bounds\n", location, if inclusive {"slice"} else {"index"});
+    let cmsg = CString::new(&msg[..]).expect("Invalid string");
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut args = vec![
+        LLVMBuildGlobalStringPtr(ctx.builder, cmsg.as_ptr(), cstr!("msg")),
+        LLVMConstInt(size_type, msg.len() as u64, 0),
+    ];
+    LLVMBuildCall(ctx.builder, runtime_function(ctx, "panic"), args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+    LLVMBuildUnreachable(ctx.builder);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, ok_bb);
+}
+
+// The value of a pointer variable, which is either stored in memory or, for the address of something, the pointer itself
+pub unsafe fn get_pointer(ctx: &mut Context, var: &Var) -> LLVMValueRef
+{
+    let vr = ctx.get_variable(&var.name, &var.typ);
+    if vr.typ == var.typ {
+        vr.value
+    } else {
+        vr.load(ctx)
+    }
+}
+
+// Indexing a pointer gives the address of the element at index, pointers to types with members or
+// to sequences are indexed like the types themselves instead
+unsafe fn pointer_element_ptr(ctx: &mut Context, ptr: &Var, index: &Operand) -> Option<ValueRef>
+{
+    match ptr.typ {
+        Type::Pointer(ref inner) if !inner.has_members() && !inner.is_sequence() => {
+            let base = get_pointer(ctx, ptr);
+            let mut indices = vec![get_operand(ctx, index).load(ctx)];
+            Some(ValueRef::new(
+                LLVMBuildGEP(ctx.builder, base, indices.as_mut_ptr(), 1, cstr!("element")),
+                ptr_type((**inner).clone())
+            ))
+        },
+        _ => None,
+    }
+}
+
+// Call menhir_retain, menhir_release or menhir_allocation
+unsafe fn gen_ref_count_call(ctx: &Context, func: &str, ptr: LLVMValueRef) -> LLVMValueRef
+{
+    let void_ptr = LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0);
+    let mut args = vec![LLVMBuildBitCast(ctx.builder, ptr, void_ptr, cstr!("ptr"))];
+    LLVMBuildCall(ctx.builder, runtime_function(ctx, func), args.as_mut_ptr(), args.len() as c_uint, cstr!(""))
+}
+
 pub unsafe fn gen_instruction(ctx: &mut Context, instr: &Instruction, blocks: &HashMap<BasicBlockRef, LLVMBasicBlockRef>)
 {
     //print!(">> {}", instr);
@@ -267,20 +394,39 @@ pub unsafe fn gen_instruction(ctx: &mut Context, instr: &Instruction, blocks: &H
         Instruction::Load{ref dst, ref ptr} => {
             let src_var = ctx.get_variable(&ptr.name, &ptr.typ);
             let val = src_var.load(ctx);
-            ctx.set_variable(&dst.name, ValueRef::new(val, dst.typ.clone()));
+            // Loading something which is not passed by value, gives its address
+            let typ = if dst.typ.pass_by_value() {dst.typ.clone()} else {ptr_type(dst.typ.clone())};
+            ctx.set_variable(&dst.name, ValueRef::new(val, typ));
         }
 
-        Instruction::LoadMember{ref dst, ref obj, ref member_index} |
-        Instruction::AddressOfMember{ref dst, ref obj, ref member_index} => { ;
-            let obj_var = ctx.get_variable(&obj.name, &obj.typ);
-            let member_ptr = obj_var.get_member_ptr(ctx, member_index);
+        Instruction::LoadMember{ref dst, ref obj, ref member_index} => {
+            let member_ptr = match pointer_element_ptr(ctx, obj, member_index) {
+                Some(element_ptr) => element_ptr,
+                None => ctx.get_variable(&obj.name, &obj.typ).get_member_ptr(ctx, member_index),
+            };
+            // Values are copied, so storing to the member afterwards doesn't change them
+            if dst.typ.pass_by_value() && member_ptr.typ == ptr_type(dst.typ.clone()) {
+                let val = member_ptr.load(ctx);
+                ctx.set_variable(&dst.name, ValueRef::new(val, dst.typ.clone()));
+            } else {
+                ctx.set_variable(&dst.name, member_ptr);
+            }
+        }
+
+        Instruction::AddressOfMember{ref dst, ref obj, ref member_index} => {
+            let member_ptr = match pointer_element_ptr(ctx, obj, member_index) {
+                Some(element_ptr) => element_ptr,
+                None => ctx.get_variable(&obj.name, &obj.typ).get_member_ptr(ctx, member_index),
+            };
             ctx.set_variable(&dst.name, member_ptr);
         }
 
         Instruction::StoreMember{ref obj, ref member_index, ref src} => {
             let src_val = get_operand(ctx, src);
-            let obj_var = ctx.get_variable(&obj.name, &obj.typ);
-            obj_var.store_member(ctx, member_index, &src_val);
+            match pointer_element_ptr(ctx, obj, member_index) {
+                Some(element_ptr) => element_ptr.store(ctx, &src_val),
+                None => ctx.get_variable(&obj.name, &obj.typ).store_member(ctx, member_index, &src_val),
+            }
         }
 
         Instruction::AddressOf{ref dst, ref obj} => {
@@ -309,21 +455,32 @@ pub unsafe fn gen_instruction(ctx: &mut Context, instr: &Instruction, blocks: &H
 
         Instruction::Call{ref dst, ref func, ref args} => {
             let func = ctx.get_function(func).expect("Unknown function");
-            let mut func_args = args.iter()
+            let func_args = args.iter()
                 .map(|a| get_function_arg(ctx, a))
                 .collect::<Vec<_>>();
 
+            let call = gen_call(ctx, &func, func_args);
             if let Some(ref dst) = *dst {
-                let ret = ValueRef::new(
-                    LLVMBuildCall(ctx.builder, func.function, func_args.as_mut_ptr(), args.len() as c_uint, cstr!("call")),
-                    func.return_type.clone()
-                );
-                ctx.set_variable(&dst.name, ret);
-            } else {
-                LLVMBuildCall(ctx.builder, func.function, func_args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+                ctx.set_variable(&dst.name, ValueRef::new(call, func.return_type.clone()));
             }
         }
 
+        Instruction::InterfaceCall{ref dst, ref obj, index, ref args} => {
+            gen_interface_call(ctx, dst, obj, index, args);
+        }
+
+        Instruction::MakeInterface{ref dst, ref obj, ref functions} => {
+            gen_make_interface(ctx, dst, obj, functions);
+        }
+
+        Instruction::MakeClosure{ref dst, ref func, ref env} => {
+            gen_make_closure(ctx, dst, func, env);
+        }
+
+        Instruction::Intrinsic{ref dst, intrinsic, ref args} => {
+            gen_intrinsic(ctx, dst, intrinsic, args);
+        }
+
         Instruction::Slice{ref dst, ref src, ref start, ref len} => {
             let dst_var = ctx.get_variable(&dst.name, &dst.typ);
             let src_var = ctx.get_variable(&src.name, &dst.typ);
@@ -332,11 +489,15 @@ pub unsafe fn gen_instruction(ctx: &mut Context, instr: &Instruction, blocks: &H
 
         Instruction::MakeSlice{ref dst, ref data, ref len} => {
             let dst_var = ctx.get_variable(&dst.name, &dst.typ);
-            let data_var = ctx.get_variable(&data.name, &data.typ);
+            let data_var = ValueRef::new(get_pointer(ctx, data), data.typ.clone());
             let len_var = ctx.get_variable(&len.name, &len.typ);
             dst_var.create_slice(ctx, &data_var, &len_var);
         }
 
+        Instruction::Format{ref dst, ref parts} => {
+            gen_format(ctx, dst, parts);
+        }
+
         Instruction::LoadOptionalFlag{ref dst, ref obj} => {
             let obj_var = ctx.get_variable(&obj.name, &obj.typ);
             let opt_flag = obj_var.load_optional_flag(ctx);
@@ -354,8 +515,14 @@ pub unsafe fn gen_instruction(ctx: &mut Context, instr: &Instruction, blocks: &H
 
         Instruction::HeapAlloc(ref var) => {
             let name = CString::new(&var.name[..]).expect("Invalid string");
-            let value = LLVMBuildMalloc(ctx.builder, ctx.resolve_type(&var.typ), name.as_ptr());
-            ctx.set_variable(&var.name, ValueRef::new(value, ptr_type(var.typ.clone())))
+            let typ = ctx.resolve_type(var.typ.get_pointer_element_type().expect("HeapAlloc of a non pointer"));
+            let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+            let size = LLVMConstInt(size_type, ctx.target_machine.alloc_size_of_type(typ) as u64 + REF_COUNT_HEADER_SIZE, 0);
+            let mem = gen_alloc(ctx, size, AllocationSource::New, "mem");
+            let data = gen_ref_counted(ctx, mem);
+            let value = LLVMBuildBitCast(ctx.builder, data, LLVMPointerType(typ, 0), name.as_ptr());
+            let dst = ctx.get_variable(&var.name, &var.typ);
+            LLVMBuildStore(ctx.builder, value, dst.value);
         }
 
         Instruction::StackAlloc(ref var) => {
@@ -371,17 +538,38 @@ pub unsafe fn gen_instruction(ctx: &mut Context, instr: &Instruction, blocks: &H
             ctx.pop_stack();
         }
 
+        Instruction::SourceLocation(pos) => {
+            set_location(ctx, pos);
+            if let Some(ref mut hp) = ctx.heap_profile {
+                hp.location = pos;
+            }
+        }
+
         Instruction::Return(ref operand) => {
-            LLVMBuildRet(ctx.builder, get_operand(ctx, operand).load(ctx));
+            let ret = get_operand(ctx, operand).load(ctx);
+            gen_free_trampolines(ctx);
+            LLVMBuildRet(ctx.builder, ret);
         }
 
         Instruction::ReturnVoid => {
-            LLVMBuildRetVoid(ctx.builder);
+            gen_free_trampolines(ctx);
+            if let Some(slot) = ctx.return_slot {
+                LLVMBuildRet(ctx.builder, LLVMBuildLoad(ctx.builder, slot, cstr!("ret")));
+            } else {
+                LLVMBuildRetVoid(ctx.builder);
+            }
+        }
+
+        Instruction::LoopHint(ref hints) => {
+            ctx.loop_hints = Some(hints.clone());
         }
 
         Instruction::Branch(ref bbref) => {
             let llvm_bb = blocks.get(bbref).expect("Unknown basic block");
-            LLVMBuildBr(ctx.builder, *llvm_bb);
+            let branch = LLVMBuildBr(ctx.builder, *llvm_bb);
+            if let Some(hints) = ctx.loop_hints.take() {
+                set_loop_metadata(ctx, branch, &hints);
+            }
         }
 
         Instruction::BranchIf{ref cond, ref on_true, ref on_false} => {
@@ -390,8 +578,63 @@ pub unsafe fn gen_instruction(ctx: &mut Context, instr: &Instruction, blocks: &H
             LLVMBuildCondBr(ctx.builder, get_operand(ctx, cond).load(ctx), *on_true_bb, *on_false_bb);
         }
 
+        Instruction::Switch{ref cond, ref cases, ref default} => {
+            let default_bb = blocks.get(default).expect("Unknown basic block");
+            let value = get_operand(ctx, cond).load(ctx);
+            let switch = LLVMBuildSwitch(ctx.builder, value, *default_bb, cases.len() as c_uint);
+            for &(ref cst, ref bb) in cases {
+                let case_bb = blocks.get(bb).expect("Unknown basic block");
+                let (bits, signed) = match *cst {
+                    Constant::Int(v, _) => (v as u64, 1),
+                    Constant::UInt(v, _) => (v, 0),
+                    Constant::Char(c) => (c as u64, 0),
+                    _ => panic!("Internal Compiler Error: {} cannot be used in a switch", cst),
+                };
+                LLVMAddCase(switch, LLVMConstInt(LLVMTypeOf(value), bits as c_ulonglong, signed), *case_bb);
+            }
+        }
+
         Instruction::Delete(ref var) => {
-            LLVMBuildFree(ctx.builder, ctx.get_variable(&var.name, &var.typ).value);
+            let ptr = get_pointer(ctx, var);
+            let mem = gen_ref_count_call(ctx, "allocation", ptr);
+            gen_free(ctx, mem);
+        }
+
+        Instruction::IncRef(ref var) => {
+            let ptr = get_pointer(ctx, var);
+            gen_ref_count_call(ctx, "retain", ptr);
+        }
+
+        Instruction::DecRef(ref var) => {
+            let dst = ctx.get_variable(&var.name, &var.typ);
+            let mem = gen_ref_count_call(ctx, "release", dst.load(ctx));
+            // Freeing null does nothing
+            gen_free(ctx, mem);
+            LLVMBuildStore(ctx.builder, LLVMConstNull(ctx.resolve_type(&var.typ)), dst.value);
+        }
+
+        Instruction::BoundsCheck{ref index, ref len, inclusive, ref location} => {
+            gen_bounds_check(ctx, index, len, inclusive, location);
+        }
+
+        Instruction::Panic(ref msg) => {
+            let msg = get_operand(ctx, msg);
+            let mut args = vec![
+                msg.get_property(ctx, ByteCodeProperty::Data).value,
+                msg.get_property(ctx, ByteCodeProperty::Len).value,
+            ];
+            LLVMBuildCall(ctx.builder, runtime_function(ctx, "panic"), args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+            LLVMBuildUnreachable(ctx.builder);
+        }
+
+        Instruction::Log(ref msg) => {
+            let msg = get_operand(ctx, msg);
+            let mut args = vec![
+                LLVMConstInt(LLVMInt32TypeInContext(ctx.context), STDERR_FILENO, 0),
+                msg.get_property(ctx, ByteCodeProperty::Data).value,
+                msg.get_property(ctx, ByteCodeProperty::Len).value,
+            ];
+            LLVMBuildCall(ctx.builder, runtime_function(ctx, "write"), args.as_mut_ptr(), args.len() as c_uint, cstr!("written"));
         }
     }
 }
@@ -0,0 +1,107 @@
+use std::ffi::CString;
+use libc::c_uint;
+use llvm::LLVMLinkage;
+use llvm::core::*;
+use llvm::prelude::*;
+use ast::{Type, IntSize, Argument, ptr_type, sig};
+use bytecode::{Operand, Var};
+use span::Span;
+use super::abi::{function_abi, gen_call};
+use super::context::Context;
+use super::instructions::get_function_arg;
+use super::symboltable::FunctionInstance;
+use super::valueref::ValueRef;
+
+/*
+Interface objects are a pair of pointers: one to the object, and one to the function table of the
+interface for the type of the object. There is one table per (type, interface) pair, with the functions
+in the order they are declared in the interface. A call through an interface object loads the function
+from the table, and passes the object pointer as the self argument.
+*/
+
+unsafe fn i8_ptr_type(ctx: &Context) -> LLVMTypeRef
+{
+    LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0)
+}
+
+unsafe fn vtable(ctx: &Context, name: &str, functions: &[String]) -> LLVMValueRef
+{
+    let name = CString::new(name).expect("Invalid string");
+    let existing = LLVMGetNamedGlobal(ctx.module, name.as_ptr());
+    if !existing.is_null() {
+        return existing;
+    }
+
+    let i8_ptr_type = i8_ptr_type(ctx);
+    let mut entries: Vec<LLVMValueRef> = functions.iter()
+        .map(|f| {
+            let fi = ctx.get_function(f).expect("Internal Compiler Error: unknown function in interface table");
+            LLVMConstBitCast(fi.function, i8_ptr_type)
+        })
+        .collect();
+
+    let table_type = LLVMArrayType(i8_ptr_type, entries.len() as c_uint);
+    let glob = LLVMAddGlobal(ctx.module, table_type, name.as_ptr());
+    LLVMSetInitializer(glob, LLVMConstArray(i8_ptr_type, entries.as_mut_ptr(), entries.len() as c_uint));
+    LLVMSetLinkage(glob, LLVMLinkage::LLVMPrivateLinkage);
+    LLVMSetGlobalConstant(glob, 1);
+    glob
+}
+
+pub unsafe fn gen_make_interface(ctx: &mut Context, dst: &Var, obj: &Var, functions: &[String])
+{
+    let interface_name = match dst.typ {
+        Type::Interface(ref it) => it.name.clone(),
+        _ => panic!("Internal Compiler Error: {} is not an interface type", dst.typ),
+    };
+    let type_name = obj.typ.get_pointer_element_type().map(|t| t.name()).unwrap_or_default();
+    let table = vtable(ctx, &format!("vtable.{}.{}", type_name, interface_name), functions);
+
+    let i8_ptr_type = i8_ptr_type(ctx);
+    let obj_ptr = ctx.get_variable(&obj.name, &obj.typ).load(ctx);
+    let dst_var = ctx.get_variable(&dst.name, &dst.typ);
+    let data = LLVMBuildBitCast(ctx.builder, obj_ptr, i8_ptr_type, cstr!("data"));
+    LLVMBuildStore(ctx.builder, data, LLVMBuildStructGEP(ctx.builder, dst_var.value, 0, cstr!("data_ptr")));
+    let table = LLVMBuildBitCast(ctx.builder, table, i8_ptr_type, cstr!("vtable"));
+    LLVMBuildStore(ctx.builder, table, LLVMBuildStructGEP(ctx.builder, dst_var.value, 1, cstr!("vtable_ptr")));
+}
+
+pub unsafe fn gen_interface_call(ctx: &mut Context, dst: &Option<Var>, obj: &Var, index: usize, args: &[Operand])
+{
+    let func = match obj.typ {
+        Type::Interface(ref it) => it.functions[index].clone(),
+        _ => panic!("Internal Compiler Error: {} is not an interface type", obj.typ),
+    };
+
+    // The functions in the table only know self is a pointer, when the result does not fit in a register
+    // the caller passes the address of the destination, like it does for a direct call
+    let mut sig_args: Vec<Argument> = func.args.clone();
+    sig_args[0].typ = ptr_type(Type::UInt(IntSize::I8));
+    let mut return_type = func.return_type.clone();
+    if args.len() + 1 > sig_args.len() {
+        sig_args.push(Argument::new("$ret", ptr_type(return_type), true, Span::default()));
+        return_type = Type::Void;
+    }
+    let call_sig = sig(&func.name, return_type, sig_args, Span::default());
+    let abi = function_abi(ctx, &call_sig);
+
+    let i8_ptr_type = i8_ptr_type(ctx);
+    let obj_var = ctx.get_variable(&obj.name, &obj.typ);
+    let data = LLVMBuildLoad(ctx.builder, LLVMBuildStructGEP(ctx.builder, obj_var.value, 0, cstr!("data_ptr")), cstr!("data"));
+    let table = LLVMBuildLoad(ctx.builder, LLVMBuildStructGEP(ctx.builder, obj_var.value, 1, cstr!("vtable_ptr")), cstr!("vtable"));
+    let table = LLVMBuildBitCast(ctx.builder, table, LLVMPointerType(i8_ptr_type, 0), cstr!("vtable"));
+    let mut entry_index = [LLVMConstInt(LLVMInt32TypeInContext(ctx.context), index as u64, 0)];
+    let entry = LLVMBuildGEP(ctx.builder, table, entry_index.as_mut_ptr(), 1, cstr!("entry"));
+    let func_ptr = LLVMBuildLoad(ctx.builder, entry, cstr!("func"));
+    let func_ptr = LLVMBuildBitCast(ctx.builder, func_ptr, LLVMPointerType(abi.function_type, 0), cstr!("func"));
+
+    let mut call_args = vec![data];
+    call_args.extend(args.iter().map(|a| get_function_arg(ctx, a)));
+
+    let mut fi = FunctionInstance::new(&func.name, func_ptr, call_sig.return_type.clone(), call_sig.get_type());
+    fi.abi = Some(abi);
+    let call = gen_call(ctx, &fi, call_args);
+    if let Some(ref dst) = *dst {
+        ctx.set_variable(&dst.name, ValueRef::new(call, fi.return_type.clone()));
+    }
+}
@@ -0,0 +1,287 @@
+use std::ffi::CString;
+use std::ptr;
+use libc::*;
+use llvm::LLVMIntPredicate;
+use llvm::prelude::*;
+use llvm::core::*;
+use ast::{Intrinsic, Type, ptr_type};
+use bytecode::{ByteCodeProperty, Operand, Var};
+use super::compat::PREFETCH_INTRINSIC;
+use super::context::Context;
+use super::format::{gen_int_to_string, gen_float_to_string, gen_parse_number, gen_hex_encode, gen_hex_decode, gen_from_utf8};
+use super::instructions::{get_operand, get_pointer, const_bool};
+use super::runtime::runtime_function;
+use super::valueref::ValueRef;
+
+// Looks up an LLVM intrinsic in the module, and declares it if it is not there yet
+pub unsafe fn get_llvm_intrinsic(ctx: &Context, name: &str, ret_type: LLVMTypeRef, arg_types: &mut [LLVMTypeRef]) -> LLVMValueRef
+{
+    let name = CString::new(name).expect("Invalid string");
+    let func = LLVMGetNamedFunction(ctx.module, name.as_ptr());
+    if !func.is_null() {
+        return func;
+    }
+
+    let func_type = LLVMFunctionType(ret_type, arg_types.as_mut_ptr(), arg_types.len() as c_uint, 0);
+    LLVMAddFunction(ctx.module, name.as_ptr(), func_type)
+}
+
+unsafe fn gen_expect(ctx: &mut Context, dst: &Var, cond: &Operand, expected: bool)
+{
+    let bool_type = LLVMInt1TypeInContext(ctx.context);
+    let func = get_llvm_intrinsic(ctx, "llvm.expect.i1", bool_type, &mut [bool_type, bool_type]);
+    let mut args = vec![get_operand(ctx, cond).load(ctx), const_bool(ctx, expected)];
+    let result = LLVMBuildCall(ctx.builder, func, args.as_mut_ptr(), args.len() as c_uint, cstr!("expect"));
+    ctx.set_variable(&dst.name, ValueRef::new(result, Type::Bool));
+}
+
+unsafe fn gen_prefetch(ctx: &mut Context, ptr: &Operand)
+{
+    let i8_ptr_type = LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0);
+    let i32_type = LLVMInt32TypeInContext(ctx.context);
+    let func = get_llvm_intrinsic(ctx, PREFETCH_INTRINSIC, LLVMVoidTypeInContext(ctx.context), &mut [i8_ptr_type, i32_type, i32_type, i32_type]);
+    let ptr = match *ptr {
+        Operand::Var(ref v) => get_pointer(ctx, v),
+        _ => get_operand(ctx, ptr).value,
+    };
+    let ptr = LLVMBuildBitCast(ctx.builder, ptr, i8_ptr_type, cstr!("prefetch_ptr"));
+
+    // Read access, keep it in all cache levels, data cache
+    let mut args = vec![
+        ptr,
+        LLVMConstInt(i32_type, 0, 0),
+        LLVMConstInt(i32_type, 3, 0),
+        LLVMConstInt(i32_type, 1, 0),
+    ];
+    LLVMBuildCall(ctx.builder, func, args.as_mut_ptr(), args.len() as c_uint, cstr!(""));
+}
+
+// Both sides are already evaluated, so this never introduces a branch
+unsafe fn gen_select(ctx: &mut Context, dst: &Var, cond: &Operand, on_true: &Operand, on_false: &Operand)
+{
+    let cond = get_operand(ctx, cond).load(ctx);
+    let on_true = get_operand(ctx, on_true).load(ctx);
+    let on_false = get_operand(ctx, on_false).load(ctx);
+    let result = LLVMBuildSelect(ctx.builder, cond, on_true, on_false, cstr!("select"));
+    // Values which are not passed by value are selected by their address
+    let typ = if dst.typ.pass_by_value() {dst.typ.clone()} else {ptr_type(dst.typ.clone())};
+    ctx.set_variable(&dst.name, ValueRef::new(result, typ));
+}
+
+// Compares all elements without an early exit, so the time taken only depends on the length
+unsafe fn gen_constant_time_eq(ctx: &mut Context, dst: &Var, left: &Operand, right: &Operand)
+{
+    let left = get_operand(ctx, left);
+    let right = get_operand(ctx, right);
+    let element_type = match left.typ.get_pointer_element_type().and_then(|t| t.get_element_type()) {
+        Some(t) => ctx.resolve_type(&t),
+        None => panic!("Internal Compiler Error: @ct_eq expects slices"),
+    };
+
+    let left_len = left.get_property(ctx, ByteCodeProperty::Len).value;
+    let right_len = right.get_property(ctx, ByteCodeProperty::Len).value;
+    let left_data = left.get_property(ctx, ByteCodeProperty::Data).value;
+    let right_data = right.get_property(ctx, ByteCodeProperty::Data).value;
+
+    // Lengths are public, if they differ start with a difference and don't look at the data
+    let same_len = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, left_len, right_len, cstr!("same_len"));
+    let len = LLVMBuildSelect(ctx.builder, same_len, left_len, LLVMConstNull(LLVMTypeOf(left_len)), cstr!("len"));
+    let init = LLVMBuildSelect(ctx.builder, same_len, LLVMConstNull(element_type), LLVMConstInt(element_type, 1, 0), cstr!("init"));
+
+    let func = ctx.get_current_function();
+    let pre_bb = LLVMGetInsertBlock(ctx.builder);
+    let cond_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("ct_eq_cond"));
+    let body_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("ct_eq_body"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("ct_eq_done"));
+    LLVMBuildBr(ctx.builder, cond_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, cond_bb);
+    let idx = LLVMBuildPhi(ctx.builder, LLVMTypeOf(len), cstr!("idx"));
+    let acc = LLVMBuildPhi(ctx.builder, element_type, cstr!("acc"));
+    let in_range = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntULT, idx, len, cstr!("in_range"));
+    LLVMBuildCondBr(ctx.builder, in_range, body_bb, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, body_bb);
+    let mut index = vec![idx];
+    let left_ptr = LLVMBuildGEP(ctx.builder, left_data, index.as_mut_ptr(), 1, cstr!("left_ptr"));
+    let right_ptr = LLVMBuildGEP(ctx.builder, right_data, index.as_mut_ptr(), 1, cstr!("right_ptr"));
+    let diff = LLVMBuildXor(
+        ctx.builder,
+        LLVMBuildLoad(ctx.builder, left_ptr, cstr!("left")),
+        LLVMBuildLoad(ctx.builder, right_ptr, cstr!("right")),
+        cstr!("diff"));
+    let next_acc = LLVMBuildOr(ctx.builder, acc, diff, cstr!("next_acc"));
+    let next_idx = LLVMBuildAdd(ctx.builder, idx, LLVMConstInt(LLVMTypeOf(len), 1, 0), cstr!("next_idx"));
+    LLVMBuildBr(ctx.builder, cond_bb);
+
+    let mut idx_values = vec![LLVMConstNull(LLVMTypeOf(len)), next_idx];
+    let mut acc_values = vec![init, next_acc];
+    let mut incoming_blocks = vec![pre_bb, body_bb];
+    LLVMAddIncoming(idx, idx_values.as_mut_ptr(), incoming_blocks.as_mut_ptr(), 2);
+    LLVMAddIncoming(acc, acc_values.as_mut_ptr(), incoming_blocks.as_mut_ptr(), 2);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    let result = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, acc, LLVMConstNull(element_type), cstr!("ct_eq"));
+    ctx.set_variable(&dst.name, ValueRef::new(result, Type::Bool));
+}
+
+// Uses a mask instead of a select, so the optimizer cannot turn it into a branch
+unsafe fn gen_constant_time_select(ctx: &mut Context, dst: &Var, cond: &Operand, on_true: &Operand, on_false: &Operand)
+{
+    let int_type = ctx.resolve_type(&dst.typ);
+    let cond = get_operand(ctx, cond).load(ctx);
+    let on_true = get_operand(ctx, on_true).load(ctx);
+    let on_false = get_operand(ctx, on_false).load(ctx);
+    let mask = LLVMBuildSExt(ctx.builder, cond, int_type, cstr!("mask"));
+    let inverted_mask = LLVMBuildNot(ctx.builder, mask, cstr!("inverted_mask"));
+    let result = LLVMBuildOr(
+        ctx.builder,
+        LLVMBuildAnd(ctx.builder, on_true, mask, cstr!("masked_true")),
+        LLVMBuildAnd(ctx.builder, on_false, inverted_mask, cstr!("masked_false")),
+        cstr!("ct_select"));
+    ctx.set_variable(&dst.name, ValueRef::new(result, dst.typ.clone()));
+}
+
+unsafe fn gen_bswap(ctx: &Context, value: LLVMValueRef) -> LLVMValueRef
+{
+    let int_type = LLVMTypeOf(value);
+    let bits = LLVMGetIntTypeWidth(int_type);
+    if bits == 8 {
+        return value;
+    }
+
+    let func = get_llvm_intrinsic(ctx, &format!("llvm.bswap.i{}", bits), int_type, &mut [int_type]);
+    let mut args = vec![value];
+    LLVMBuildCall(ctx.builder, func, args.as_mut_ptr(), args.len() as c_uint, cstr!("bswap"))
+}
+
+// Converting to and from a byte order is the same operation, a swap when it differs from the native one
+unsafe fn convert_byte_order(ctx: &Context, value: LLVMValueRef, little_endian: bool) -> LLVMValueRef
+{
+    if ctx.target_machine.is_little_endian() == little_endian {
+        value
+    } else {
+        gen_bswap(ctx, value)
+    }
+}
+
+unsafe fn gen_byte_order(ctx: &mut Context, dst: &Var, src: &Operand, little_endian: bool)
+{
+    let value = get_operand(ctx, src).load(ctx);
+    let result = convert_byte_order(ctx, value, little_endian);
+    ctx.set_variable(&dst.name, ValueRef::new(result, dst.typ.clone()));
+}
+
+unsafe fn byte_offset_ptr(ctx: &mut Context, bytes: &Operand, offset: &Operand, int_type: LLVMTypeRef) -> LLVMValueRef
+{
+    let bytes = get_operand(ctx, bytes);
+    let data = bytes.get_property(ctx, ByteCodeProperty::Data).value;
+    let mut index = vec![get_operand(ctx, offset).load(ctx)];
+    let ptr = LLVMBuildGEP(ctx.builder, data, index.as_mut_ptr(), 1, cstr!("byte_ptr"));
+    LLVMBuildBitCast(ctx.builder, ptr, LLVMPointerType(int_type, 0), cstr!("int_ptr"))
+}
+
+unsafe fn gen_unaligned_load(ctx: &mut Context, dst: &Var, bytes: &Operand, offset: &Operand, little_endian: bool)
+{
+    let int_type = ctx.resolve_type(&dst.typ);
+    let ptr = byte_offset_ptr(ctx, bytes, offset, int_type);
+    let value = LLVMBuildLoad(ctx.builder, ptr, cstr!("unaligned_load"));
+    LLVMSetAlignment(value, 1);
+    let result = convert_byte_order(ctx, value, little_endian);
+    ctx.set_variable(&dst.name, ValueRef::new(result, dst.typ.clone()));
+}
+
+unsafe fn gen_unaligned_store(ctx: &mut Context, bytes: &Operand, offset: &Operand, value: &Operand, little_endian: bool)
+{
+    let value = get_operand(ctx, value).load(ctx);
+    let value = convert_byte_order(ctx, value, little_endian);
+    let ptr = byte_offset_ptr(ctx, bytes, offset, LLVMTypeOf(value));
+    let store = LLVMBuildStore(ctx.builder, value, ptr);
+    LLVMSetAlignment(store, 1);
+}
+
+unsafe fn gen_bit_count(ctx: &mut Context, dst: &Var, src: &Operand, intrinsic: Intrinsic)
+{
+    let value = get_operand(ctx, src).load(ctx);
+    let int_type = LLVMTypeOf(value);
+    let bits = LLVMGetIntTypeWidth(int_type);
+    let count = match intrinsic {
+        Intrinsic::CountOnes => {
+            let func = get_llvm_intrinsic(ctx, &format!("llvm.ctpop.i{}", bits), int_type, &mut [int_type]);
+            let mut args = vec![value];
+            LLVMBuildCall(ctx.builder, func, args.as_mut_ptr(), args.len() as c_uint, cstr!("count_ones"))
+        },
+        _ => {
+            let name = if intrinsic == Intrinsic::LeadingZeros {"ctlz"} else {"cttz"};
+            let bool_type = LLVMInt1TypeInContext(ctx.context);
+            let func = get_llvm_intrinsic(ctx, &format!("llvm.{}.i{}", name, bits), int_type, &mut [int_type, bool_type]);
+            // Zero is a valid input, which returns the number of bits
+            let mut args = vec![value, const_bool(ctx, false)];
+            LLVMBuildCall(ctx.builder, func, args.as_mut_ptr(), args.len() as c_uint, cstr!("count_zeros"))
+        },
+    };
+
+    let result = LLVMBuildIntCast(ctx.builder, count, ctx.resolve_type(&dst.typ), cstr!("count"));
+    ctx.set_variable(&dst.name, ValueRef::new(result, dst.typ.clone()));
+}
+
+// The funnel shift intrinsics are not available in the LLVM version we use,
+// so emit the shift pattern which LLVM recognizes as a rotate
+unsafe fn gen_rotate(ctx: &mut Context, dst: &Var, src: &Operand, amount: &Operand, left: bool)
+{
+    let value = get_operand(ctx, src).load(ctx);
+    let amount = get_operand(ctx, amount).load(ctx);
+    let int_type = LLVMTypeOf(value);
+    let bits = LLVMGetIntTypeWidth(int_type);
+    let mask = LLVMConstInt(int_type, (bits - 1) as c_ulonglong, 0);
+    let amount = LLVMBuildAnd(ctx.builder, amount, mask, cstr!("amount"));
+    let other_amount = LLVMBuildAnd(ctx.builder, LLVMBuildNeg(ctx.builder, amount, cstr!("neg_amount")), mask, cstr!("other_amount"));
+
+    let (shl_amount, shr_amount) = if left {(amount, other_amount)} else {(other_amount, amount)};
+    let result = LLVMBuildOr(
+        ctx.builder,
+        LLVMBuildShl(ctx.builder, value, shl_amount, cstr!("shl")),
+        LLVMBuildLShr(ctx.builder, value, shr_amount, cstr!("shr")),
+        cstr!("rotate"));
+    ctx.set_variable(&dst.name, ValueRef::new(result, dst.typ.clone()));
+}
+
+unsafe fn gen_random(ctx: &mut Context, dst: &Var)
+{
+    let result = LLVMBuildCall(ctx.builder, runtime_function(ctx, "random"), ptr::null_mut(), 0, cstr!("random"));
+    ctx.set_variable(&dst.name, ValueRef::new(result, dst.typ.clone()));
+}
+
+pub unsafe fn gen_intrinsic(ctx: &mut Context, dst: &Option<Var>, intrinsic: Intrinsic, args: &[Operand])
+{
+    match (intrinsic, dst) {
+        (Intrinsic::Likely, &Some(ref dst)) => gen_expect(ctx, dst, &args[0], true),
+        (Intrinsic::Unlikely, &Some(ref dst)) => gen_expect(ctx, dst, &args[0], false),
+        (Intrinsic::Prefetch, &None) => gen_prefetch(ctx, &args[0]),
+        (Intrinsic::Select, &Some(ref dst)) => gen_select(ctx, dst, &args[0], &args[1], &args[2]),
+        (Intrinsic::ConstantTimeEq, &Some(ref dst)) => gen_constant_time_eq(ctx, dst, &args[0], &args[1]),
+        (Intrinsic::ConstantTimeSelect, &Some(ref dst)) => gen_constant_time_select(ctx, dst, &args[0], &args[1], &args[2]),
+        (Intrinsic::ToLittleEndian, &Some(ref dst)) |
+        (Intrinsic::FromLittleEndian, &Some(ref dst)) => gen_byte_order(ctx, dst, &args[0], true),
+        (Intrinsic::ToBigEndian, &Some(ref dst)) |
+        (Intrinsic::FromBigEndian, &Some(ref dst)) => gen_byte_order(ctx, dst, &args[0], false),
+        (Intrinsic::LoadLittleEndian, &Some(ref dst)) => gen_unaligned_load(ctx, dst, &args[0], &args[1], true),
+        (Intrinsic::LoadBigEndian, &Some(ref dst)) => gen_unaligned_load(ctx, dst, &args[0], &args[1], false),
+        (Intrinsic::StoreLittleEndian, &None) => gen_unaligned_store(ctx, &args[0], &args[1], &args[2], true),
+        (Intrinsic::StoreBigEndian, &None) => gen_unaligned_store(ctx, &args[0], &args[1], &args[2], false),
+        (Intrinsic::CountOnes, &Some(ref dst)) |
+        (Intrinsic::LeadingZeros, &Some(ref dst)) |
+        (Intrinsic::TrailingZeros, &Some(ref dst)) => gen_bit_count(ctx, dst, &args[0], intrinsic),
+        (Intrinsic::RotateLeft, &Some(ref dst)) => gen_rotate(ctx, dst, &args[0], &args[1], true),
+        (Intrinsic::RotateRight, &Some(ref dst)) => gen_rotate(ctx, dst, &args[0], &args[1], false),
+        (Intrinsic::Random, &Some(ref dst)) => gen_random(ctx, dst),
+        (Intrinsic::ParseInt, &Some(ref dst)) |
+        (Intrinsic::ParseFloat, &Some(ref dst)) => gen_parse_number(ctx, dst, &args[0], intrinsic),
+        (Intrinsic::IntToString, &Some(ref dst)) => gen_int_to_string(ctx, dst, &args[0], &args[1]),
+        (Intrinsic::FloatToString, &Some(ref dst)) => gen_float_to_string(ctx, dst, &args[0], &args[1]),
+        (Intrinsic::HexEncode, &Some(ref dst)) => gen_hex_encode(ctx, dst, &args[0]),
+        (Intrinsic::HexDecode, &Some(ref dst)) => gen_hex_decode(ctx, dst, &args[0]),
+        (Intrinsic::FromUtf8, &Some(ref dst)) => gen_from_utf8(ctx, dst, &args[0]),
+        _ => panic!("Internal Compiler Error: invalid destination for intrinsic {}", intrinsic),
+    }
+}
@@ -1,83 +1,48 @@
 use std::ptr;
-use std::mem;
-use std::ops::Drop;
-use std::ffi::CStr;
-use libc;
-use llvm::support::LLVMSearchForAddressOfSymbol;
-use llvm::prelude::LLVMModuleRef;
-use llvm::orc::{
-    LLVMOrcCreateInstance,
-    LLVMOrcAddEagerlyCompiledIR,
-    LLVMOrcJITStackRef,
-    LLVMOrcDisposeInstance,
-    LLVMOrcGetSymbolAddress,
-};
-use llvmbackend::target::TargetMachine;
+use std::ffi::CString;
+use llvm::support::LLVMLoadLibraryPermanently;
+use llvmbackend::context::Context;
+use llvmbackend::mcjit::ExecutionEngine;
 
+/*
+Runs a generated module in the compiler process, instead of linking it into a binary. Symbols which
+the module doesn't define are looked up in the compiler process, which has the C library, and in the
+shared libraries loaded with load_library.
 
-extern "C" fn resolve_symbol(name: *const libc::c_char, jit_stack: *mut libc::c_void) -> u64
-{
-    unsafe {
-        let js = jit_stack as LLVMOrcJITStackRef;
-        let symbol = LLVMOrcGetSymbolAddress(js, name);
-        if symbol != 0 {
-            return symbol;
-        }
-
-        let addr = LLVMSearchForAddressOfSymbol(name);
-        if addr != ptr::null_mut() {
-            return addr as u64;
-        }
-
-        match CStr::from_ptr(name).to_str().unwrap() {
-            "memcpy" => libc::memcpy as u64,
-            _ => 0,
-        }
-    }
-}
-
+This uses MCJIT, which every LLVM version since 7 has. The C API of ORC which LLVM 7 offers was removed
+in LLVM 12, and its replacement doesn't exist in LLVM 7.
+*/
 
 pub struct JIT
 {
-    jit_stack: LLVMOrcJITStackRef,
-    target_machine: TargetMachine,
+    // Libraries are loaded into the process, so there is no state of our own
+    _private: (),
 }
 
 impl JIT
 {
     pub unsafe fn new() -> Result<JIT, String>
     {
-        let target_machine = TargetMachine::new()?;
-        let jit_stack = LLVMOrcCreateInstance(target_machine.target_machine);
-        if jit_stack == ptr::null_mut() {
-            return Err(format!("Failed to create ORC JIT instance"));
-        }
-
-        Ok(JIT{jit_stack, target_machine})
+        // A null file name makes the symbols of the compiler process available
+        LLVMLoadLibraryPermanently(ptr::null());
+        Ok(JIT{_private: ()})
     }
 
-    pub unsafe fn run(&self, module: LLVMModuleRef) -> Result<i64, String>
+    // Make the symbols of a shared library available to the generated code
+    pub unsafe fn load_library(&self, path: &str) -> Result<(), String>
     {
-        LLVMOrcAddEagerlyCompiledIR(self.jit_stack, module, resolve_symbol, self.jit_stack as *mut libc::c_void);
-        let main_func = LLVMOrcGetSymbolAddress(self.jit_stack, cstr!("main"));
-        println!("main_func {:x}", main_func);
-        if main_func == 0 {
-            return Err(format!("Cannot execute module, no main function found"));
+        let c_path = CString::new(path).expect("Invalid string");
+        if LLVMLoadLibraryPermanently(c_path.as_ptr()) != 0 {
+            return Err(format!("Unable to load shared library {}", path));
         }
-
-        let func: extern "C" fn() -> i64 = mem::transmute(main_func);
-        let ret = func();
-        Ok(ret)
+        Ok(())
     }
-}
 
-impl Drop for JIT
-{
-    fn drop(&mut self)
+    // Compile the module of ctx and run its main function, the module is moved out of ctx.
+    // Global constructors run before main, and destructors after it, when the engine is dropped.
+    pub unsafe fn run(&self, ctx: &mut Context) -> Result<i64, String>
     {
-        unsafe {
-            LLVMOrcDisposeInstance(self.jit_stack);
-        }
+        let mut engine = ExecutionEngine::new(ctx)?;
+        engine.run_main()
     }
 }
-
@@ -0,0 +1,193 @@
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+use libc;
+use llvm::core::LLVMDisposeMessage;
+use llvm::execution_engine::*;
+use llvm::support::LLVMLoadLibraryPermanently;
+use super::closure::TRAMPOLINE_SIZE;
+use super::context::Context;
+
+/*
+Runs generated code in the compiler process with MCJIT, instead of linking a binary. The module is
+moved out of the context, so the engine owns it, but it still belongs to the LLVM context, so the
+engine must be dropped before the context. External functions are resolved from the symbols of the
+compiler process, which has the C library.
+*/
+pub struct ExecutionEngine
+{
+    engine: LLVMExecutionEngineRef,
+    // Destructors only run when the constructors did
+    constructed: bool,
+    trampolines: TrampolinePool,
+}
+
+impl ExecutionEngine
+{
+    pub unsafe fn new(ctx: &mut Context) -> Result<ExecutionEngine, String>
+    {
+        LLVMLinkInMCJIT();
+        // A null file name makes the symbols of the process itself available
+        LLVMLoadLibraryPermanently(ptr::null());
+
+        let mut options: LLVMMCJITCompilerOptions = mem::zeroed();
+        let options_size = mem::size_of::<LLVMMCJITCompilerOptions>();
+        LLVMInitializeMCJITCompilerOptions(&mut options, options_size);
+
+        let module = mem::replace(&mut ctx.module, ptr::null_mut());
+        let mut engine = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        if LLVMCreateMCJITCompilerForModule(&mut engine, module, &mut options, options_size, &mut error) != 0 {
+            let msg = CStr::from_ptr(error).to_string_lossy().into_owned();
+            LLVMDisposeMessage(error);
+            return Err(format!("Unable to create the JIT execution engine: {}", msg));
+        }
+
+        Ok(ExecutionEngine{engine, constructed: false, trampolines: TrampolinePool::new()})
+    }
+
+    // Run the global constructors and main, and return the result of main
+    pub unsafe fn run_main(&mut self) -> Result<i64, String>
+    {
+        let main_func = LLVMGetFunctionAddress(self.engine, cstr!("main"));
+        if main_func == 0 {
+            return Err("Cannot execute module, no main function found".into());
+        }
+
+        self.set_hook("alloc_trampoline", alloc_trampoline as *const u8);
+        self.set_hook("free_trampoline", free_trampoline as *const u8);
+        let engine = self.engine;
+        self.trampolines.activate(|| LLVMRunStaticConstructors(engine));
+        self.constructed = true;
+        let func: extern "C" fn() -> i64 = mem::transmute(main_func);
+        let ret = self.trampolines.activate(|| func());
+        // Output of the program goes through the C library, make sure it comes before ours
+        libc::fflush(ptr::null_mut());
+        Ok(ret)
+    }
+
+    // Register a function with menhir_set_<hook>_hook, when the module has the hook
+    unsafe fn set_hook(&self, hook: &str, func: *const u8)
+    {
+        if let Some(hook_ptr) = self.global_address(&format!("menhir_{}_hook", hook)) {
+            *(hook_ptr as *mut *const u8) = func;
+        }
+    }
+
+    // Address of a global in the generated code, which stays valid until the engine is dropped
+    pub unsafe fn global_address(&self, name: &str) -> Option<*const u8>
+    {
+        let name = CString::new(name).expect("Invalid string");
+        match LLVMGetGlobalValueAddress(self.engine, name.as_ptr()) {
+            0 => None,
+            addr => Some(addr as *const u8),
+        }
+    }
+}
+
+/*
+The trampolines of closures have to be executable. A binary has an executable stack for them, the
+compiler doesn't, so the engine hands out executable memory for them through the trampoline hooks.
+Pages are mapped as they are needed, and unmapped when the engine is dropped.
+*/
+struct TrampolinePool
+{
+    pages: Vec<*mut libc::c_void>,
+    free: Vec<*mut u8>,
+}
+
+// Trampolines keep the alignment of the stack allocated ones
+const TRAMPOLINE_SLOT_SIZE: usize = (TRAMPOLINE_SIZE as usize + 15) & !15;
+
+thread_local! {
+    // The pool of the engine whose code is running on this thread
+    static ACTIVE_POOL: Cell<*mut TrampolinePool> = const {Cell::new(ptr::null_mut())};
+}
+
+impl TrampolinePool
+{
+    fn new() -> TrampolinePool
+    {
+        TrampolinePool{pages: Vec::new(), free: Vec::new()}
+    }
+
+    unsafe fn alloc(&mut self) -> *mut u8
+    {
+        if self.free.is_empty() {
+            let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            let page = libc::mmap(ptr::null_mut(), page_size, libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+            if page == libc::MAP_FAILED {
+                return ptr::null_mut();
+            }
+
+            self.pages.push(page);
+            let start = page as *mut u8;
+            self.free.extend((0..page_size / TRAMPOLINE_SLOT_SIZE).rev().map(|idx| start.add(idx * TRAMPOLINE_SLOT_SIZE)));
+        }
+        self.free.pop().unwrap_or(ptr::null_mut())
+    }
+
+    fn free(&mut self, trampoline: *mut u8)
+    {
+        if !trampoline.is_null() {
+            self.free.push(trampoline);
+        }
+    }
+
+    // Run f with the pool serving the trampoline hooks of this thread
+    fn activate<T, F: FnOnce() -> T>(&mut self, f: F) -> T
+    {
+        let previous = ACTIVE_POOL.with(|pool| pool.replace(self));
+        let ret = f();
+        ACTIVE_POOL.with(|pool| pool.set(previous));
+        ret
+    }
+}
+
+impl Drop for TrampolinePool
+{
+    fn drop(&mut self)
+    {
+        let page_size = unsafe {libc::sysconf(libc::_SC_PAGESIZE) as usize};
+        for page in &self.pages {
+            unsafe {
+                libc::munmap(*page, page_size);
+            }
+        }
+    }
+}
+
+extern "C" fn alloc_trampoline() -> *mut u8
+{
+    ACTIVE_POOL.with(|pool| match pool.get() {
+        p if p.is_null() => ptr::null_mut(),
+        p => unsafe {(*p).alloc()},
+    })
+}
+
+extern "C" fn free_trampoline(trampoline: *mut u8)
+{
+    ACTIVE_POOL.with(|pool| {
+        let p = pool.get();
+        if !p.is_null() {
+            unsafe {(*p).free(trampoline)};
+        }
+    })
+}
+
+impl Drop for ExecutionEngine
+{
+    fn drop(&mut self)
+    {
+        unsafe {
+            if self.constructed {
+                let engine = self.engine;
+                self.trampolines.activate(|| LLVMRunStaticDestructors(engine));
+                libc::fflush(ptr::null_mut());
+            }
+            LLVMDisposeExecutionEngine(self.engine);
+        }
+    }
+}
@@ -9,35 +9,57 @@ macro_rules! cstr {
 }
 
 
+mod abi;
+mod closure;
+mod compat;
 mod context;
+mod debuginfo;
+mod ffijson;
+mod format;
 mod function;
+mod heapprofile;
 mod instructions;
+mod interface;
+mod intrinsics;
+mod jit;
+mod mcjit;
+mod runtime;
+mod stackusage;
 mod symboltable;
 mod target;
 mod types;
 mod valueref;
+mod wasm;
 
-/*
-Disabled for now, the ORC jit stuff is crashing
 #[cfg(test)]
 mod tests;
-#[cfg(test)]
-mod jit;
-*/
 
 use std::ffi::CString;
+use std::ptr;
+use std::path::Path;
 use std::process::{Output, Command};
 use std::fmt;
-use llvm::LLVMLinkage;
+use llvm::{LLVMLinkage, LLVMIntPredicate, LLVMAtomicOrdering};
 use llvm::core::*;
+use llvm::prelude::*;
+use libc::c_uint;
 
-use bytecode::{ByteCodeModule, Constant};
+use ast::{Type, ptr_type};
+use bytecode::{ByteCodeModule, Constant, RuntimeGlobal};
 pub use self::target::TargetMachine;
+pub use self::context::Context;
+pub use self::jit::JIT;
+pub use self::mcjit::ExecutionEngine;
+pub use self::stackusage::estimate_stack_usage;
+pub use self::ffijson::ffi_json;
+pub use self::wasm::WASM_TARGET_TRIPLET;
 use self::valueref::ValueRef;
 use self::function::{gen_function, gen_function_sig, add_libc_functions};
-use self::context::Context;
+use self::debuginfo::DebugInfo;
+use self::runtime::add_runtime_functions;
+use self::heapprofile::{HeapProfile, HeapProfileMode, HEAP_REPORT_FUNCTION, add_heap_profile_functions};
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OutputType
 {
     #[serde(rename = "binary")]
@@ -46,6 +68,8 @@ pub enum OutputType
     StaticLib,
     #[serde(rename = "sharedlib")]
     SharedLib,
+    #[serde(rename = "wasm")]
+    Wasm,
 }
 
 impl Default for OutputType
@@ -64,10 +88,20 @@ impl fmt::Display for OutputType
             OutputType::Binary => write!(f, "binary"),
             OutputType::SharedLib => write!(f, "sharedlib"),
             OutputType::StaticLib => write!(f, "staticlib"),
+            OutputType::Wasm => write!(f, "wasm"),
         }
     }
 }
 
+// Priorities up to 100 are reserved for the implementation
+const FIRST_STRUCTOR_PRIORITY: usize = 1000;
+
+// States of the once flag of a lazy global
+const ONCE_UNINITIALIZED: u64 = 0;
+const ONCE_RUNNING: u64 = 1;
+const ONCE_DONE: u64 = 2;
+
+#[derive(Default)]
 pub struct CodeGenOptions
 {
     pub build_dir: String,
@@ -75,10 +109,21 @@ pub struct CodeGenOptions
     pub output_type: OutputType,
     pub dump_ir: bool,
     pub optimize: bool,
+    // Generate DWARF debug information
+    pub debug_info: bool,
+    // Identical string literals share one constant
+    pub intern_strings: bool,
+    // Identical string literals of different modules are merged by the linker
+    pub merge_strings: bool,
+    // Count allocations per site, and print them when the program exits
+    pub instrument_heap: bool,
+    // Print the allocations done with new which were never deleted, when the program exits
+    pub leak_checks: bool,
 }
 
 
-pub fn llvm_init() -> Result<TargetMachine, String>
+// Initialize LLVM and create a target machine for target_triplet, if None the host is targeted
+pub fn llvm_init(target_triplet: Option<&str>) -> Result<TargetMachine, String>
 {
     unsafe {
         use llvm::initialization::*;
@@ -102,7 +147,7 @@ pub fn llvm_init() -> Result<TargetMachine, String>
         LLVMInitializeIPA(pass_registry);
         LLVMInitializeCodeGen(pass_registry);
         LLVMInitializeTarget(pass_registry);
-        TargetMachine::new()
+        TargetMachine::new(target_triplet)
     }
 }
 
@@ -113,21 +158,164 @@ pub fn llvm_shutdown()
     }
 }
 
-unsafe fn gen_global(ctx: &mut Context, glob_name: &str, glob_value: &Constant)
+unsafe fn gen_global(ctx: &mut Context, glob_name: &str, glob_value: &Constant, thread_local: bool)
 {
-    let v = ValueRef::from_const(ctx, glob_value);
+    let v = match *glob_value {
+        Constant::String(ref s) => ValueRef::new(ValueRef::const_string_slice(ctx, s), Type::String),
+        _ => ValueRef::from_const(ctx, glob_value),
+    };
     let name = CString::new(glob_name.as_bytes()).expect("Invalid string");
     let glob = LLVMAddGlobal(ctx.module, ctx.resolve_type(&v.typ), name.as_ptr());
     LLVMSetLinkage(glob, LLVMLinkage::LLVMExternalLinkage);
     LLVMSetInitializer(glob, v.value);
-    ctx.set_variable(glob_name, v);
+    LLVMSetThreadLocal(glob, thread_local as LLVMBool);
+    if let Constant::String(_) = *glob_value {
+        // Like a string on the stack, the variable is the memory the slice is in
+        ctx.set_variable(glob_name, ValueRef::new(glob, Type::String));
+    } else if thread_local || v.typ.pass_by_value() {
+        // Refer to the global itself, so that mutable globals can be assigned
+        let typ = ptr_type(v.typ.clone());
+        ctx.set_variable(glob_name, ValueRef::new(glob, typ));
+    } else {
+        ctx.set_variable(glob_name, v);
+    }
+}
+
+unsafe fn gen_runtime_global(ctx: &mut Context, glob_name: &str, rg: &RuntimeGlobal, thread_local: bool)
+{
+    let name = CString::new(glob_name.as_bytes()).expect("Invalid string");
+    let typ = ctx.resolve_type(&rg.typ);
+    let glob = LLVMAddGlobal(ctx.module, typ, name.as_ptr());
+    LLVMSetLinkage(glob, LLVMLinkage::LLVMExternalLinkage);
+    LLVMSetInitializer(glob, LLVMConstNull(typ));
+    LLVMSetThreadLocal(glob, thread_local as LLVMBool);
+    ctx.set_variable(glob_name, ValueRef::new(glob, ptr_type(rg.typ.clone())));
+}
+
+unsafe fn load_once_flag(ctx: &Context, flag: LLVMValueRef) -> LLVMValueRef
+{
+    let state = LLVMBuildLoad(ctx.builder, flag, cstr!("state"));
+    LLVMSetOrdering(state, LLVMAtomicOrdering::LLVMAtomicOrderingAcquire);
+    LLVMSetAlignment(state, 4);
+    let int32_type = LLVMInt32TypeInContext(ctx.context);
+    LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, state, LLVMConstInt(int32_type, ONCE_DONE, 0), cstr!("done"))
+}
+
+// Generate the function which initializes a lazy global on first use. The once flag of the global goes
+// from ONCE_UNINITIALIZED to ONCE_RUNNING to ONCE_DONE, only the thread which sets it to ONCE_RUNNING calls
+// the init function, other threads wait until it is done. Thread local globals have a once flag per thread.
+unsafe fn gen_lazy_global_guard(ctx: &mut Context, glob_name: &str, init_function: LLVMValueRef, thread_local: bool) -> LLVMValueRef
+{
+    let int32_type = LLVMInt32TypeInContext(ctx.context);
+    let flag_name = CString::new(format!("{}.once", glob_name)).expect("Invalid string");
+    let flag = LLVMAddGlobal(ctx.module, int32_type, flag_name.as_ptr());
+    LLVMSetLinkage(flag, LLVMLinkage::LLVMInternalLinkage);
+    LLVMSetInitializer(flag, LLVMConstInt(int32_type, ONCE_UNINITIALIZED, 0));
+    LLVMSetThreadLocal(flag, thread_local as LLVMBool);
+
+    let guard_name = CString::new(format!("{}.get", glob_name)).expect("Invalid string");
+    let guard_type = LLVMFunctionType(LLVMVoidTypeInContext(ctx.context), ptr::null_mut(), 0, 0);
+    let guard = LLVMAddFunction(ctx.module, guard_name.as_ptr(), guard_type);
+    LLVMSetLinkage(guard, LLVMLinkage::LLVMInternalLinkage);
+
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, guard, cstr!("entry"));
+    let claim_bb = LLVMAppendBasicBlockInContext(ctx.context, guard, cstr!("claim"));
+    let init_bb = LLVMAppendBasicBlockInContext(ctx.context, guard, cstr!("init"));
+    let wait_bb = LLVMAppendBasicBlockInContext(ctx.context, guard, cstr!("wait"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, guard, cstr!("done"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let done = load_once_flag(ctx, flag);
+    LLVMBuildCondBr(ctx.builder, done, done_bb, claim_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, claim_bb);
+    let result = LLVMBuildAtomicCmpXchg(
+        ctx.builder,
+        flag,
+        LLVMConstInt(int32_type, ONCE_UNINITIALIZED, 0),
+        LLVMConstInt(int32_type, ONCE_RUNNING, 0),
+        LLVMAtomicOrdering::LLVMAtomicOrderingAcquireRelease,
+        LLVMAtomicOrdering::LLVMAtomicOrderingAcquire,
+        0);
+    let claimed = LLVMBuildExtractValue(ctx.builder, result, 1, cstr!("claimed"));
+    LLVMBuildCondBr(ctx.builder, claimed, init_bb, wait_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, init_bb);
+    LLVMBuildCall(ctx.builder, init_function, ptr::null_mut(), 0, cstr!(""));
+    let store = LLVMBuildStore(ctx.builder, LLVMConstInt(int32_type, ONCE_DONE, 0), flag);
+    LLVMSetOrdering(store, LLVMAtomicOrdering::LLVMAtomicOrderingRelease);
+    LLVMSetAlignment(store, 4);
+    LLVMBuildBr(ctx.builder, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, wait_bb);
+    let done = load_once_flag(ctx, flag);
+    LLVMBuildCondBr(ctx.builder, done, done_bb, wait_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    LLVMBuildRetVoid(ctx.builder);
+    guard
 }
 
-pub fn llvm_code_generation<'a>(bc_mod: &ByteCodeModule, target_machine: &'a TargetMachine) -> Result<Context<'a>, String>
+// Register functions in llvm.global_ctors or llvm.global_dtors, so that they run in the order they are passed.
+// Constructors are run lowest priority first, destructors highest priority first.
+unsafe fn gen_global_structors(ctx: &mut Context, array_name: &str, functions: &[String], highest_first: bool)
+{
+    if functions.is_empty() {
+        return;
+    }
+
+    let int32_type = LLVMInt32TypeInContext(ctx.context);
+    let func_ptr_type = LLVMPointerType(LLVMFunctionType(LLVMVoidTypeInContext(ctx.context), ptr::null_mut(), 0, 0), 0);
+    let data_ptr_type = LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0);
+    let mut member_types = vec![int32_type, func_ptr_type, data_ptr_type];
+    let entry_type = LLVMStructTypeInContext(ctx.context, member_types.as_mut_ptr(), member_types.len() as c_uint, 0);
+
+    let mut entries = Vec::with_capacity(functions.len());
+    for (idx, name) in functions.iter().enumerate() {
+        let func = ctx.get_function(name).unwrap_or_else(|| panic!("Internal Compiler Error: unknown function {}", name));
+        let priority = if highest_first {FIRST_STRUCTOR_PRIORITY + functions.len() - idx} else {FIRST_STRUCTOR_PRIORITY + idx};
+        let mut values = vec![
+            LLVMConstInt(int32_type, priority as u64, 0),
+            func.function,
+            LLVMConstNull(data_ptr_type),
+        ];
+        entries.push(LLVMConstNamedStruct(entry_type, values.as_mut_ptr(), values.len() as c_uint));
+    }
+
+    let name = CString::new(array_name).expect("Invalid string");
+    let glob = LLVMAddGlobal(ctx.module, LLVMArrayType(entry_type, entries.len() as c_uint), name.as_ptr());
+    LLVMSetLinkage(glob, LLVMLinkage::LLVMAppendingLinkage);
+    LLVMSetInitializer(glob, LLVMConstArray(entry_type, entries.as_mut_ptr(), entries.len() as c_uint));
+}
+
+// The file the compile unit is named after, the file of main if there is one
+fn main_file(bc_mod: &ByteCodeModule) -> String
+{
+    bc_mod.get_function(&bc_mod.main_function_name())
+        .into_iter()
+        .chain(bc_mod.functions.values())
+        .map(|func| &func.sig.span.file)
+        .find(|file| !file.is_empty())
+        .cloned()
+        .unwrap_or_else(|| format!("{}.mhr", bc_mod.name))
+}
+
+pub fn llvm_code_generation<'a>(bc_mod: &ByteCodeModule, target_machine: &'a TargetMachine, opts: &CodeGenOptions) -> Result<Context<'a>, String>
 {
     let mut ctx = Context::new(&bc_mod.name, target_machine)?;
+    ctx.intern_strings = opts.intern_strings;
+    ctx.merge_strings = opts.merge_strings;
+    if opts.instrument_heap {
+        ctx.heap_profile = Some(HeapProfile::new(HeapProfileMode::Profile));
+    } else if opts.leak_checks {
+        ctx.heap_profile = Some(HeapProfile::new(HeapProfileMode::Leaks));
+    }
 
     unsafe {
+        if opts.debug_info {
+            ctx.debug_info = Some(DebugInfo::new(&ctx, &main_file(bc_mod), opts.optimize));
+        }
+
         add_libc_functions(&mut ctx);
 
         for func in &bc_mod.imported_functions {
@@ -135,23 +323,60 @@ pub fn llvm_code_generation<'a>(bc_mod: &ByteCodeModule, target_machine: &'a Tar
         }
 
         for (glob_name, glob_val) in &bc_mod.globals {
-           gen_global(&mut ctx, glob_name, glob_val);
+           gen_global(&mut ctx, glob_name, glob_val, bc_mod.thread_local_globals.contains(glob_name));
+        }
+
+        for (glob_name, rg) in &bc_mod.runtime_globals {
+            gen_runtime_global(&mut ctx, glob_name, rg, bc_mod.thread_local_globals.contains(glob_name));
         }
 
         for func in bc_mod.functions.values() {
             if func.sig.name == bc_mod.main_function_name() {
                 gen_function_sig(&mut ctx, &func.sig, Some("main"));
+            } else if bc_mod.exported_functions.contains(&func.sig.name) {
+                gen_function_sig(&mut ctx, &func.sig, Some(func.sig.exported_name()));
             } else {
                 gen_function_sig(&mut ctx, &func.sig, None);
             }
         }
 
+        add_runtime_functions(&mut ctx);
+
+        for (glob_name, rg) in bc_mod.runtime_globals.iter().filter(|&(_, rg)| rg.lazy) {
+            let init_function = ctx.get_function(&rg.init_function)
+                .unwrap_or_else(|| panic!("Internal Compiler Error: unknown function {}", rg.init_function))
+                .function;
+            let guard = gen_lazy_global_guard(&mut ctx, glob_name, init_function, bc_mod.thread_local_globals.contains(glob_name));
+            ctx.add_lazy_global(glob_name, guard, init_function);
+        }
+
         for func in bc_mod.functions.values() {
             if !func.external {
                 gen_function(&mut ctx, func);
             }
         }
 
+        // Copies of functions from other packages are only there for inlining, the package itself defines them
+        for func in bc_mod.imported_functions.iter().filter(|f| !f.external) {
+            gen_function(&mut ctx, func);
+            let fi = ctx.get_function(&func.sig.name).expect("Internal Compiler Error: Unknown function");
+            LLVMSetLinkage(fi.function, LLVMLinkage::LLVMAvailableExternallyLinkage);
+        }
+
+        gen_global_structors(&mut ctx, "llvm.global_ctors", &bc_mod.init_functions, false);
+        if ctx.heap_profile.is_some() {
+            add_heap_profile_functions(&mut ctx);
+            // Last, so memory freed by the other destructors is not reported as live
+            let mut fini_functions = bc_mod.fini_functions.clone();
+            fini_functions.push(HEAP_REPORT_FUNCTION.into());
+            gen_global_structors(&mut ctx, "llvm.global_dtors", &fini_functions, true);
+        } else {
+            gen_global_structors(&mut ctx, "llvm.global_dtors", &bc_mod.fini_functions, true);
+        }
+
+        if let Some(ref di) = ctx.debug_info {
+            di.finalize();
+        }
         ctx.verify()?;
     }
 
@@ -192,26 +417,35 @@ pub fn link(ctx: &Context, opts: &CodeGenOptions, linker_flags: &LinkerFlags) ->
 
     let output_file_path = format!("{}/{}", opts.build_dir, opts.output_file_name);
 
+    // When cross compiling, use the GNU toolchain for the target (e.g. aarch64-linux-gnu-gcc)
+    let tool_prefix = if ctx.target_machine.is_cross_compiling() {
+        format!("{}-", ctx.target_machine.target.triplet)
+    } else {
+        String::new()
+    };
+
     let mut cmd = match opts.output_type {
         OutputType::Binary => {
-            let mut cmd = Command::new("gcc");
+            let mut cmd = Command::new(format!("{}gcc", tool_prefix));
             cmd.arg("-o").arg(&output_file_path).arg(obj_file);
             linker_flags.add_flags(&mut cmd);
             cmd
         },
 
         OutputType::StaticLib => {
-            let mut cmd = Command::new("ar");
+            let mut cmd = Command::new(format!("{}ar", tool_prefix));
             cmd.arg("rcs").arg(&output_file_path).arg(obj_file);
             cmd
         }
 
         OutputType::SharedLib => {
-            let mut cmd = Command::new("gcc");
+            let mut cmd = Command::new(format!("{}gcc", tool_prefix));
             cmd.arg("-shared").arg("-o").arg(&output_file_path).arg(obj_file);
             linker_flags.add_flags(&mut cmd);
             cmd
         }
+
+        OutputType::Wasm => self::wasm::wasm_link_command(&output_file_path, &obj_file),
     };
 
     println!("  Linking {}", output_file_path);
@@ -226,5 +460,9 @@ pub fn link(ctx: &Context, opts: &CodeGenOptions, linker_flags: &LinkerFlags) ->
         return Err(msg);
     }
 
+    if let OutputType::Wasm = opts.output_type {
+        self::wasm::write_js_loader(Path::new(&output_file_path))?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,779 @@
+use std::ffi::CString;
+use std::ptr;
+use libc::c_uint;
+use llvm::{LLVMLinkage, LLVMTypeKind, LLVMIntPredicate};
+use llvm::core::*;
+use llvm::prelude::*;
+use super::context::Context;
+
+/*
+Runtime hooks, so that programs embedding a menhir library can override how memory is allocated,
+how output is written, what happens on a panic and where the trampolines of closures are.
+
+For every hook, menhir_<hook> is generated with weak linkage, so the host can replace it at link time.
+It calls the function registered with menhir_set_<hook>_hook, or when nothing is registered,
+falls back to the libc implementation.
+
+The random number generator behind @random is seeded on first use from the MENHIR_SEED environment
+variable, so runs are reproducible. A panic prints the seed, when the program used the generator.
+
+Memory allocated with new is reference counted. It starts with a header of a magic number and the count,
+so menhir_retain and menhir_release can tell it apart from other pointers (to the stack, a global, or
+memory of a C library), which they leave alone.
+
+Parsing and formatting numbers, for @parse_int, @parse_float and @int_to_string, and hexadecimal
+encoding, is done by runtime functions as well, so the code is not repeated at every call.
+*/
+
+pub const STDERR_FILENO: u64 = 2;
+
+// Constants of the splitmix64 generator, the interpreter uses the same ones
+const RANDOM_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+const RANDOM_MIX1: u64 = 0xBF58_476D_1CE4_E5B9;
+const RANDOM_MIX2: u64 = 0x94D0_49BB_1331_11EB;
+
+// "MENHIRRC", followed by the reference count, keeps the data 16 byte aligned
+const REF_COUNT_MAGIC: u64 = 0x4D45_4E48_4952_5243;
+pub const REF_COUNT_HEADER_SIZE: u64 = 16;
+
+// Room for the digits of any 64 bit integer in base 2, and a sign
+pub const FORMAT_INT_BUFFER_SIZE: u64 = 65;
+
+unsafe fn void_ptr_type(ctx: &Context) -> LLVMTypeRef
+{
+    LLVMPointerType(LLVMInt8TypeInContext(ctx.context), 0)
+}
+
+// Get a libc function, it might already be declared by the program with a different signature
+unsafe fn libc_function(ctx: &Context, name: &str, ret_type: LLVMTypeRef, arg_types: &mut [LLVMTypeRef]) -> LLVMValueRef
+{
+    let function_type = LLVMFunctionType(ret_type, arg_types.as_mut_ptr(), arg_types.len() as c_uint, 0);
+    let cname = CString::new(name).expect("Invalid string");
+    let func = LLVMGetNamedFunction(ctx.module, cname.as_ptr());
+    if func.is_null() {
+        LLVMAddFunction(ctx.module, cname.as_ptr(), function_type)
+    } else if LLVMTypeOf(func) != LLVMPointerType(function_type, 0) {
+        LLVMConstBitCast(func, LLVMPointerType(function_type, 0))
+    } else {
+        func
+    }
+}
+
+// The program can declare runtime functions as external functions, in which case we reuse the declaration
+unsafe fn add_weak_function(ctx: &Context, name: &str, function_type: LLVMTypeRef) -> LLVMValueRef
+{
+    let cname = CString::new(name).expect("Invalid string");
+    let mut func = LLVMGetNamedFunction(ctx.module, cname.as_ptr());
+    if func.is_null() || LLVMTypeOf(func) != LLVMPointerType(function_type, 0) {
+        func = LLVMAddFunction(ctx.module, cname.as_ptr(), function_type);
+    }
+    LLVMSetLinkage(func, LLVMLinkage::LLVMWeakAnyLinkage);
+    func
+}
+
+unsafe fn gen_hook<F>(ctx: &Context, hook: &str, function_type: LLVMTypeRef, noreturn: bool, gen_default: F)
+    where F: Fn(&Context, &mut Vec<LLVMValueRef>) -> LLVMValueRef
+{
+    let hook_ptr_type = LLVMPointerType(function_type, 0);
+    let hook_name = CString::new(format!("menhir_{}_hook", hook)).expect("Invalid string");
+    let hook_ptr = LLVMAddGlobal(ctx.module, hook_ptr_type, hook_name.as_ptr());
+    LLVMSetLinkage(hook_ptr, LLVMLinkage::LLVMWeakAnyLinkage);
+    LLVMSetInitializer(hook_ptr, LLVMConstNull(hook_ptr_type));
+
+    // menhir_set_<hook>_hook
+    let mut setter_args = vec![hook_ptr_type];
+    let setter_type = LLVMFunctionType(LLVMVoidTypeInContext(ctx.context), setter_args.as_mut_ptr(), 1, 0);
+    let setter = add_weak_function(ctx, &format!("menhir_set_{}_hook", hook), setter_type);
+    LLVMPositionBuilderAtEnd(ctx.builder, LLVMAppendBasicBlockInContext(ctx.context, setter, cstr!("entry")));
+    LLVMBuildStore(ctx.builder, LLVMGetParam(setter, 0), hook_ptr);
+    LLVMBuildRetVoid(ctx.builder);
+
+    // menhir_<hook>
+    let func = add_weak_function(ctx, &format!("menhir_{}", hook), function_type);
+    let mut params: Vec<LLVMValueRef> = (0..LLVMCountParams(func)).map(|idx| LLVMGetParam(func, idx)).collect();
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let custom_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("custom"));
+    let default_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("default"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let hook_func = LLVMBuildLoad(ctx.builder, hook_ptr, cstr!("hook"));
+    let no_hook = LLVMBuildIsNull(ctx.builder, hook_func, cstr!("no_hook"));
+    LLVMBuildCondBr(ctx.builder, no_hook, default_bb, custom_bb);
+
+    let returns_void = LLVMGetTypeKind(LLVMGetReturnType(function_type)) == LLVMTypeKind::LLVMVoidTypeKind;
+    let finish = |ret: LLVMValueRef| {
+        if noreturn {
+            let abort = libc_function(ctx, "abort", LLVMVoidTypeInContext(ctx.context), &mut []);
+            LLVMBuildCall(ctx.builder, abort, ptr::null_mut(), 0, cstr!(""));
+            LLVMBuildUnreachable(ctx.builder);
+        } else if returns_void {
+            LLVMBuildRetVoid(ctx.builder);
+        } else {
+            LLVMBuildRet(ctx.builder, ret);
+        }
+    };
+
+    LLVMPositionBuilderAtEnd(ctx.builder, custom_bb);
+    let ret = LLVMBuildCall(ctx.builder, hook_func, params.as_mut_ptr(), params.len() as c_uint, cstr!(""));
+    finish(ret);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, default_bb);
+    let ret = gen_default(ctx, &mut params);
+    finish(ret);
+}
+
+unsafe fn add_internal_global(ctx: &Context, name: &str, typ: LLVMTypeRef) -> LLVMValueRef
+{
+    let cname = CString::new(name).expect("Invalid string");
+    let glob = LLVMAddGlobal(ctx.module, typ, cname.as_ptr());
+    LLVMSetLinkage(glob, LLVMLinkage::LLVMInternalLinkage);
+    LLVMSetInitializer(glob, LLVMConstNull(typ));
+    glob
+}
+
+// The seed as a string, and its length, null until the generator is used
+unsafe fn random_seed_globals(ctx: &Context) -> (LLVMValueRef, LLVMValueRef)
+{
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    (add_internal_global(ctx, "menhir_random_seed", void_ptr_type(ctx)), add_internal_global(ctx, "menhir_random_seed_len", size_type))
+}
+
+// fn menhir_random() -> u64, the splitmix64 generator
+unsafe fn add_random_function(ctx: &Context, seed: LLVMValueRef, seed_len: LLVMValueRef)
+{
+    let int32_type = LLVMInt32TypeInContext(ctx.context);
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let void_ptr = void_ptr_type(ctx);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let state = add_internal_global(ctx, "menhir_random_state", int64_type);
+
+    let function_type = LLVMFunctionType(int64_type, ptr::null_mut(), 0, 0);
+    let func = add_weak_function(ctx, "menhir_random", function_type);
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let seed_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("seed"));
+    let next_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("next"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let unseeded = LLVMBuildIsNull(ctx.builder, LLVMBuildLoad(ctx.builder, seed, cstr!("seed")), cstr!("unseeded"));
+    LLVMBuildCondBr(ctx.builder, unseeded, seed_bb, next_bb);
+
+    // Without MENHIR_SEED, the seed is 0
+    LLVMPositionBuilderAtEnd(ctx.builder, seed_bb);
+    let getenv = libc_function(ctx, "getenv", void_ptr, &mut [void_ptr]);
+    let mut getenv_args = vec![LLVMBuildGlobalStringPtr(ctx.builder, cstr!("MENHIR_SEED"), cstr!("name"))];
+    let value = LLVMBuildCall(ctx.builder, getenv, getenv_args.as_mut_ptr(), getenv_args.len() as c_uint, cstr!("value"));
+    let default_seed = LLVMBuildGlobalStringPtr(ctx.builder, cstr!("0"), cstr!("default_seed"));
+    let no_value = LLVMBuildIsNull(ctx.builder, value, cstr!("no_value"));
+    let seed_str = LLVMBuildSelect(ctx.builder, no_value, default_seed, value, cstr!("seed_str"));
+    LLVMBuildStore(ctx.builder, seed_str, seed);
+
+    let strlen = libc_function(ctx, "strlen", size_type, &mut [void_ptr]);
+    let mut strlen_args = vec![seed_str];
+    LLVMBuildStore(ctx.builder, LLVMBuildCall(ctx.builder, strlen, strlen_args.as_mut_ptr(), 1, cstr!("len")), seed_len);
+
+    let strtoull = libc_function(ctx, "strtoull", int64_type, &mut [void_ptr, LLVMPointerType(void_ptr, 0), int32_type]);
+    let mut strtoull_args = vec![seed_str, LLVMConstNull(LLVMPointerType(void_ptr, 0)), LLVMConstInt(int32_type, 10, 0)];
+    let initial = LLVMBuildCall(ctx.builder, strtoull, strtoull_args.as_mut_ptr(), strtoull_args.len() as c_uint, cstr!("initial"));
+    LLVMBuildStore(ctx.builder, initial, state);
+    LLVMBuildBr(ctx.builder, next_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, next_bb);
+    let current = LLVMBuildLoad(ctx.builder, state, cstr!("state"));
+    let next = LLVMBuildAdd(ctx.builder, current, LLVMConstInt(int64_type, RANDOM_GAMMA, 0), cstr!("next"));
+    LLVMBuildStore(ctx.builder, next, state);
+
+    let mix = |z: LLVMValueRef, shift: u64, factor: Option<u64>| {
+        let shifted = LLVMBuildLShr(ctx.builder, z, LLVMConstInt(int64_type, shift, 0), cstr!("shifted"));
+        let z = LLVMBuildXor(ctx.builder, z, shifted, cstr!("z"));
+        match factor {
+            Some(factor) => LLVMBuildMul(ctx.builder, z, LLVMConstInt(int64_type, factor, 0), cstr!("z")),
+            None => z,
+        }
+    };
+    let z = mix(next, 30, Some(RANDOM_MIX1));
+    let z = mix(z, 27, Some(RANDOM_MIX2));
+    LLVMBuildRet(ctx.builder, mix(z, 31, None));
+}
+
+// The header in front of reference counted data, as a pointer to the magic number, the count follows it
+unsafe fn ref_count_header(ctx: &Context, data: LLVMValueRef) -> LLVMValueRef
+{
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let mut index = [LLVMConstInt(int64_type, (REF_COUNT_HEADER_SIZE as i64).wrapping_neg() as u64, 1)];
+    let mem = LLVMBuildGEP(ctx.builder, data, index.as_mut_ptr(), 1, cstr!("mem"));
+    LLVMBuildBitCast(ctx.builder, mem, LLVMPointerType(int64_type, 0), cstr!("header"))
+}
+
+unsafe fn ref_count_ptr(ctx: &Context, header: LLVMValueRef) -> LLVMValueRef
+{
+    let mut index = [LLVMConstInt(LLVMInt64TypeInContext(ctx.context), 1, 0)];
+    LLVMBuildGEP(ctx.builder, header, index.as_mut_ptr(), 1, cstr!("count_ptr"))
+}
+
+// Branch to counted_bb if ptr points to reference counted memory, and to other_bb if not, returns the header
+unsafe fn gen_is_counted(ctx: &Context, func: LLVMValueRef, ptr: LLVMValueRef, counted_bb: LLVMBasicBlockRef, other_bb: LLVMBasicBlockRef) -> LLVMValueRef
+{
+    let check_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("check"));
+    LLVMBuildCondBr(ctx.builder, LLVMBuildIsNull(ctx.builder, ptr, cstr!("is_null")), other_bb, check_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, check_bb);
+    let header = ref_count_header(ctx, ptr);
+    let magic = LLVMBuildLoad(ctx.builder, header, cstr!("magic"));
+    let expected = LLVMConstInt(LLVMInt64TypeInContext(ctx.context), REF_COUNT_MAGIC, 0);
+    let counted = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, magic, expected, cstr!("counted"));
+    LLVMBuildCondBr(ctx.builder, counted, counted_bb, other_bb);
+    header
+}
+
+// Fill in the header of memory allocated with new, returns the pointer to the data after it
+pub unsafe fn gen_ref_counted(ctx: &Context, mem: LLVMValueRef) -> LLVMValueRef
+{
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let header = LLVMBuildBitCast(ctx.builder, mem, LLVMPointerType(int64_type, 0), cstr!("header"));
+    LLVMBuildStore(ctx.builder, LLVMConstInt(int64_type, REF_COUNT_MAGIC, 0), header);
+    LLVMBuildStore(ctx.builder, LLVMConstInt(int64_type, 1, 0), ref_count_ptr(ctx, header));
+    let mut data_index = [LLVMConstInt(int64_type, REF_COUNT_HEADER_SIZE, 0)];
+    LLVMBuildGEP(ctx.builder, mem, data_index.as_mut_ptr(), 1, cstr!("data"))
+}
+
+/*
+fn menhir_retain(ptr: *void)
+fn menhir_release(ptr: *void) -> *void, returns the memory to free when the last reference is gone, and null otherwise
+fn menhir_allocation(ptr: *void) -> *void, the memory which delete has to free, the count of it is no longer used
+*/
+unsafe fn add_ref_count_functions(ctx: &Context)
+{
+    let void_type = LLVMVoidTypeInContext(ctx.context);
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let void_ptr = void_ptr_type(ctx);
+    let mut args = vec![void_ptr];
+    let zero = LLVMConstInt(int64_type, 0, 0);
+    let one = LLVMConstInt(int64_type, 1, 0);
+
+    let retain = add_weak_function(ctx, "menhir_retain", LLVMFunctionType(void_type, args.as_mut_ptr(), 1, 0));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, retain, cstr!("entry"));
+    let increment_bb = LLVMAppendBasicBlockInContext(ctx.context, retain, cstr!("increment"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, retain, cstr!("done"));
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let header = gen_is_counted(ctx, retain, LLVMGetParam(retain, 0), increment_bb, done_bb);
+    LLVMPositionBuilderAtEnd(ctx.builder, increment_bb);
+    let count_ptr = ref_count_ptr(ctx, header);
+    let count = LLVMBuildAdd(ctx.builder, LLVMBuildLoad(ctx.builder, count_ptr, cstr!("count")), one, cstr!("count"));
+    LLVMBuildStore(ctx.builder, count, count_ptr);
+    LLVMBuildBr(ctx.builder, done_bb);
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    LLVMBuildRetVoid(ctx.builder);
+
+    let release = add_weak_function(ctx, "menhir_release", LLVMFunctionType(void_ptr, args.as_mut_ptr(), 1, 0));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, release, cstr!("entry"));
+    let decrement_bb = LLVMAppendBasicBlockInContext(ctx.context, release, cstr!("decrement"));
+    let last_bb = LLVMAppendBasicBlockInContext(ctx.context, release, cstr!("last"));
+    let keep_bb = LLVMAppendBasicBlockInContext(ctx.context, release, cstr!("keep"));
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let header = gen_is_counted(ctx, release, LLVMGetParam(release, 0), decrement_bb, keep_bb);
+    LLVMPositionBuilderAtEnd(ctx.builder, decrement_bb);
+    let count_ptr = ref_count_ptr(ctx, header);
+    let count = LLVMBuildSub(ctx.builder, LLVMBuildLoad(ctx.builder, count_ptr, cstr!("count")), one, cstr!("count"));
+    LLVMBuildStore(ctx.builder, count, count_ptr);
+    let is_last = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, count, zero, cstr!("is_last"));
+    LLVMBuildCondBr(ctx.builder, is_last, last_bb, keep_bb);
+    LLVMPositionBuilderAtEnd(ctx.builder, last_bb);
+    // So a dangling pointer to it is not mistaken for reference counted memory
+    LLVMBuildStore(ctx.builder, zero, header);
+    LLVMBuildRet(ctx.builder, LLVMBuildBitCast(ctx.builder, header, void_ptr, cstr!("mem")));
+    LLVMPositionBuilderAtEnd(ctx.builder, keep_bb);
+    LLVMBuildRet(ctx.builder, LLVMConstNull(void_ptr));
+
+    let allocation = add_weak_function(ctx, "menhir_allocation", LLVMFunctionType(void_ptr, args.as_mut_ptr(), 1, 0));
+    let ptr = LLVMGetParam(allocation, 0);
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, allocation, cstr!("entry"));
+    let counted_bb = LLVMAppendBasicBlockInContext(ctx.context, allocation, cstr!("counted"));
+    let other_bb = LLVMAppendBasicBlockInContext(ctx.context, allocation, cstr!("other"));
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let header = gen_is_counted(ctx, allocation, ptr, counted_bb, other_bb);
+    LLVMPositionBuilderAtEnd(ctx.builder, counted_bb);
+    LLVMBuildStore(ctx.builder, zero, header);
+    LLVMBuildRet(ctx.builder, LLVMBuildBitCast(ctx.builder, header, void_ptr, cstr!("mem")));
+    LLVMPositionBuilderAtEnd(ctx.builder, other_bb);
+    LLVMBuildRet(ctx.builder, ptr);
+}
+
+// fn menhir_parse_int(data: *u8, len: uint, result: *i64) -> bool, decimal digits with an optional sign
+unsafe fn add_parse_int_function(ctx: &Context)
+{
+    let bool_type = LLVMInt1TypeInContext(ctx.context);
+    let int8_type = LLVMInt8TypeInContext(ctx.context);
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut args = vec![void_ptr_type(ctx), size_type, LLVMPointerType(int64_type, 0)];
+    let func = add_weak_function(ctx, "menhir_parse_int", LLVMFunctionType(bool_type, args.as_mut_ptr(), args.len() as c_uint, 0));
+    let (data, len, result) = (LLVMGetParam(func, 0), LLVMGetParam(func, 1), LLVMGetParam(func, 2));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let sign_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("sign"));
+    let digit_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("digit"));
+    let accumulate_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("accumulate"));
+    let next_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("next"));
+    let finish_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("finish"));
+    let fail_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("fail"));
+    let min = LLVMConstInt(int64_type, i64::min_value() as u64, 1);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let idx = LLVMBuildAlloca(ctx.builder, size_type, cstr!("idx"));
+    let acc = LLVMBuildAlloca(ctx.builder, int64_type, cstr!("acc"));
+    LLVMBuildStore(ctx.builder, LLVMConstInt(int64_type, 0, 0), acc);
+    let empty = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, len, LLVMConstInt(size_type, 0, 0), cstr!("empty"));
+    LLVMBuildCondBr(ctx.builder, empty, fail_bb, sign_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, sign_bb);
+    let first = LLVMBuildLoad(ctx.builder, data, cstr!("first"));
+    let negative = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, first, LLVMConstInt(int8_type, b'-' as u64, 0), cstr!("negative"));
+    let positive = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, first, LLVMConstInt(int8_type, b'+' as u64, 0), cstr!("positive"));
+    let start = LLVMBuildZExt(ctx.builder, LLVMBuildOr(ctx.builder, negative, positive, cstr!("has_sign")), size_type, cstr!("start"));
+    LLVMBuildStore(ctx.builder, start, idx);
+    let only_sign = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, start, len, cstr!("only_sign"));
+    LLVMBuildCondBr(ctx.builder, only_sign, fail_bb, digit_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, digit_bb);
+    let i = LLVMBuildLoad(ctx.builder, idx, cstr!("i"));
+    let mut index = [i];
+    let c = LLVMBuildLoad(ctx.builder, LLVMBuildGEP(ctx.builder, data, index.as_mut_ptr(), 1, cstr!("c_ptr")), cstr!("c"));
+    let d = LLVMBuildSub(ctx.builder, LLVMBuildZExt(ctx.builder, c, int64_type, cstr!("c")), LLVMConstInt(int64_type, b'0' as u64, 0), cstr!("d"));
+    let is_digit = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntULT, d, LLVMConstInt(int64_type, 10, 0), cstr!("is_digit"));
+    LLVMBuildCondBr(ctx.builder, is_digit, accumulate_bb, fail_bb);
+
+    // The digits are accumulated as a negative number, which also has room for the smallest one
+    LLVMPositionBuilderAtEnd(ctx.builder, accumulate_bb);
+    let a = LLVMBuildLoad(ctx.builder, acc, cstr!("a"));
+    let cutoff = LLVMConstInt(int64_type, (i64::min_value() / 10) as u64, 1);
+    let too_small = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntSLT, a, cutoff, cstr!("too_small"));
+    let m = LLVMBuildMul(ctx.builder, a, LLVMConstInt(int64_type, 10, 0), cstr!("m"));
+    let below = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntSLT, m, LLVMBuildAdd(ctx.builder, min, d, cstr!("limit")), cstr!("below"));
+    LLVMBuildStore(ctx.builder, LLVMBuildSub(ctx.builder, m, d, cstr!("a")), acc);
+    LLVMBuildCondBr(ctx.builder, LLVMBuildOr(ctx.builder, too_small, below, cstr!("overflow")), fail_bb, next_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, next_bb);
+    let next = LLVMBuildAdd(ctx.builder, i, LLVMConstInt(size_type, 1, 0), cstr!("next"));
+    LLVMBuildStore(ctx.builder, next, idx);
+    let done = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, next, len, cstr!("done"));
+    LLVMBuildCondBr(ctx.builder, done, finish_bb, digit_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, finish_bb);
+    let a = LLVMBuildLoad(ctx.builder, acc, cstr!("a"));
+    LLVMBuildStore(ctx.builder, LLVMBuildSelect(ctx.builder, negative, a, LLVMBuildNeg(ctx.builder, a, cstr!("neg")), cstr!("value")), result);
+    let is_min = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, a, min, cstr!("is_min"));
+    let fits = LLVMBuildOr(ctx.builder, negative, LLVMBuildNot(ctx.builder, is_min, cstr!("not_min")), cstr!("fits"));
+    LLVMBuildRet(ctx.builder, fits);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, fail_bb);
+    LLVMBuildRet(ctx.builder, LLVMConstInt(bool_type, 0, 0));
+}
+
+// fn menhir_parse_float(data: *u8, len: uint, result: *double) -> bool, strtod on a zero terminated copy, which has to use all of it
+unsafe fn add_parse_float_function(ctx: &Context)
+{
+    let bool_type = LLVMInt1TypeInContext(ctx.context);
+    let int8_type = LLVMInt8TypeInContext(ctx.context);
+    let double_type = LLVMDoubleTypeInContext(ctx.context);
+    let void_ptr = void_ptr_type(ctx);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut args = vec![void_ptr, size_type, LLVMPointerType(double_type, 0)];
+    let func = add_weak_function(ctx, "menhir_parse_float", LLVMFunctionType(bool_type, args.as_mut_ptr(), args.len() as c_uint, 0));
+    let (data, len, result) = (LLVMGetParam(func, 0), LLVMGetParam(func, 1), LLVMGetParam(func, 2));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let check_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("check"));
+    let parse_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("parse"));
+    let fail_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("fail"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let end = LLVMBuildAlloca(ctx.builder, void_ptr, cstr!("end"));
+    let empty = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, len, LLVMConstInt(size_type, 0, 0), cstr!("empty"));
+    LLVMBuildCondBr(ctx.builder, empty, fail_bb, check_bb);
+
+    // strtod skips leading whitespace, the interpreter doesn't accept it
+    LLVMPositionBuilderAtEnd(ctx.builder, check_bb);
+    let first = LLVMBuildLoad(ctx.builder, data, cstr!("first"));
+    let space = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntULE, first, LLVMConstInt(int8_type, b' ' as u64, 0), cstr!("space"));
+    LLVMBuildCondBr(ctx.builder, space, fail_bb, parse_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, parse_bb);
+    let mut alloc_args = vec![LLVMBuildAdd(ctx.builder, len, LLVMConstInt(size_type, 1, 0), cstr!("size"))];
+    let buf = LLVMBuildCall(ctx.builder, runtime_function(ctx, "alloc"), alloc_args.as_mut_ptr(), 1, cstr!("buf"));
+    let memcpy = libc_function(ctx, "memcpy", void_ptr, &mut [void_ptr, void_ptr, size_type]);
+    let mut memcpy_args = vec![buf, data, len];
+    LLVMBuildCall(ctx.builder, memcpy, memcpy_args.as_mut_ptr(), memcpy_args.len() as c_uint, cstr!(""));
+    let mut index = [len];
+    let buf_end = LLVMBuildGEP(ctx.builder, buf, index.as_mut_ptr(), 1, cstr!("buf_end"));
+    LLVMBuildStore(ctx.builder, LLVMConstInt(int8_type, 0, 0), buf_end);
+
+    let strtod = libc_function(ctx, "strtod", double_type, &mut [void_ptr, LLVMPointerType(void_ptr, 0)]);
+    let mut strtod_args = vec![buf, end];
+    let value = LLVMBuildCall(ctx.builder, strtod, strtod_args.as_mut_ptr(), strtod_args.len() as c_uint, cstr!("value"));
+    let used_all = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, LLVMBuildLoad(ctx.builder, end, cstr!("end")), buf_end, cstr!("used_all"));
+    let mut free_args = vec![buf];
+    LLVMBuildCall(ctx.builder, runtime_function(ctx, "free"), free_args.as_mut_ptr(), 1, cstr!(""));
+    LLVMBuildStore(ctx.builder, value, result);
+    LLVMBuildRet(ctx.builder, used_all);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, fail_bb);
+    LLVMBuildRet(ctx.builder, LLVMConstInt(bool_type, 0, 0));
+}
+
+/*
+fn menhir_format_int(magnitude: u64, negative: bool, radix: u64, buf: *u8) -> uint
+The digits are written at the end of the buffer of FORMAT_INT_BUFFER_SIZE bytes, it returns where they start
+*/
+unsafe fn add_format_int_function(ctx: &Context)
+{
+    let bool_type = LLVMInt1TypeInContext(ctx.context);
+    let int8_type = LLVMInt8TypeInContext(ctx.context);
+    let int64_type = LLVMInt64TypeInContext(ctx.context);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut args = vec![int64_type, bool_type, int64_type, void_ptr_type(ctx)];
+    let func = add_weak_function(ctx, "menhir_format_int", LLVMFunctionType(size_type, args.as_mut_ptr(), args.len() as c_uint, 0));
+    let (magnitude, negative, radix, buf) = (LLVMGetParam(func, 0), LLVMGetParam(func, 1), LLVMGetParam(func, 2), LLVMGetParam(func, 3));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let digit_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("digit"));
+    let minus_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("minus"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("done"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let pos = LLVMBuildAlloca(ctx.builder, size_type, cstr!("pos"));
+    let rest = LLVMBuildAlloca(ctx.builder, int64_type, cstr!("rest"));
+    LLVMBuildStore(ctx.builder, LLVMConstInt(size_type, FORMAT_INT_BUFFER_SIZE, 0), pos);
+    LLVMBuildStore(ctx.builder, magnitude, rest);
+    let digits = LLVMBuildGlobalStringPtr(ctx.builder, cstr!("0123456789abcdefghijklmnopqrstuvwxyz"), cstr!("digits"));
+    LLVMBuildBr(ctx.builder, digit_bb);
+
+    // Put a character in front of what is already in the buffer
+    let prepend = |c: LLVMValueRef| {
+        let p = LLVMBuildSub(ctx.builder, LLVMBuildLoad(ctx.builder, pos, cstr!("pos")), LLVMConstInt(size_type, 1, 0), cstr!("p"));
+        LLVMBuildStore(ctx.builder, p, pos);
+        let mut index = [p];
+        LLVMBuildStore(ctx.builder, c, LLVMBuildGEP(ctx.builder, buf, index.as_mut_ptr(), 1, cstr!("c_ptr")));
+    };
+
+    // At least one digit, so 0 becomes "0"
+    LLVMPositionBuilderAtEnd(ctx.builder, digit_bb);
+    let r = LLVMBuildLoad(ctx.builder, rest, cstr!("r"));
+    let mut index = [LLVMBuildURem(ctx.builder, r, radix, cstr!("d"))];
+    prepend(LLVMBuildLoad(ctx.builder, LLVMBuildGEP(ctx.builder, digits, index.as_mut_ptr(), 1, cstr!("digit_ptr")), cstr!("digit")));
+    let r = LLVMBuildUDiv(ctx.builder, r, radix, cstr!("r"));
+    LLVMBuildStore(ctx.builder, r, rest);
+    let finished = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntEQ, r, LLVMConstInt(int64_type, 0, 0), cstr!("finished"));
+    let sign_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("sign"));
+    LLVMBuildCondBr(ctx.builder, finished, sign_bb, digit_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, sign_bb);
+    LLVMBuildCondBr(ctx.builder, negative, minus_bb, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, minus_bb);
+    prepend(LLVMConstInt(int8_type, b'-' as u64, 0));
+    LLVMBuildBr(ctx.builder, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    LLVMBuildRet(ctx.builder, LLVMBuildLoad(ctx.builder, pos, cstr!("pos")));
+}
+
+// A loop over idx from 0 up to len, body_bb has to branch back to the returned block, which increments idx
+unsafe fn gen_counting_loop(ctx: &Context, func: LLVMValueRef, len: LLVMValueRef, body_bb: LLVMBasicBlockRef, done_bb: LLVMBasicBlockRef) -> (LLVMValueRef, LLVMBasicBlockRef)
+{
+    let size_type = LLVMTypeOf(len);
+    let idx = LLVMBuildAlloca(ctx.builder, size_type, cstr!("idx"));
+    LLVMBuildStore(ctx.builder, LLVMConstInt(size_type, 0, 0), idx);
+    let cond_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("cond"));
+    let next_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("next"));
+    LLVMBuildBr(ctx.builder, cond_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, cond_bb);
+    let in_range = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntULT, LLVMBuildLoad(ctx.builder, idx, cstr!("i")), len, cstr!("in_range"));
+    LLVMBuildCondBr(ctx.builder, in_range, body_bb, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, next_bb);
+    let next = LLVMBuildAdd(ctx.builder, LLVMBuildLoad(ctx.builder, idx, cstr!("i")), LLVMConstInt(size_type, 1, 0), cstr!("next"));
+    LLVMBuildStore(ctx.builder, next, idx);
+    LLVMBuildBr(ctx.builder, cond_bb);
+    (idx, next_bb)
+}
+
+unsafe fn byte_ptr(ctx: &Context, data: LLVMValueRef, index: LLVMValueRef) -> LLVMValueRef
+{
+    let mut indices = [index];
+    LLVMBuildGEP(ctx.builder, data, indices.as_mut_ptr(), 1, cstr!("byte_ptr"))
+}
+
+// fn menhir_hex_encode(data: *u8, len: uint, out: *u8), out has room for 2 * len lowercase digits
+unsafe fn add_hex_encode_function(ctx: &Context)
+{
+    let void_ptr = void_ptr_type(ctx);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut args = vec![void_ptr, size_type, void_ptr];
+    let func = add_weak_function(ctx, "menhir_hex_encode", LLVMFunctionType(LLVMVoidTypeInContext(ctx.context), args.as_mut_ptr(), args.len() as c_uint, 0));
+    let (data, len, out) = (LLVMGetParam(func, 0), LLVMGetParam(func, 1), LLVMGetParam(func, 2));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let body_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("body"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("done"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let digits = LLVMBuildGlobalStringPtr(ctx.builder, cstr!("0123456789abcdef"), cstr!("digits"));
+    let (idx, next_bb) = gen_counting_loop(ctx, func, len, body_bb, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, body_bb);
+    let i = LLVMBuildLoad(ctx.builder, idx, cstr!("i"));
+    let b = LLVMBuildZExt(ctx.builder, LLVMBuildLoad(ctx.builder, byte_ptr(ctx, data, i), cstr!("b")), size_type, cstr!("b"));
+    let high = LLVMBuildLShr(ctx.builder, b, LLVMConstInt(size_type, 4, 0), cstr!("high"));
+    let low = LLVMBuildAnd(ctx.builder, b, LLVMConstInt(size_type, 15, 0), cstr!("low"));
+    let pos = LLVMBuildMul(ctx.builder, i, LLVMConstInt(size_type, 2, 0), cstr!("pos"));
+    LLVMBuildStore(ctx.builder, LLVMBuildLoad(ctx.builder, byte_ptr(ctx, digits, high), cstr!("c")), byte_ptr(ctx, out, pos));
+    let pos = LLVMBuildAdd(ctx.builder, pos, LLVMConstInt(size_type, 1, 0), cstr!("pos"));
+    LLVMBuildStore(ctx.builder, LLVMBuildLoad(ctx.builder, byte_ptr(ctx, digits, low), cstr!("c")), byte_ptr(ctx, out, pos));
+    LLVMBuildBr(ctx.builder, next_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    LLVMBuildRetVoid(ctx.builder);
+}
+
+/*
+fn menhir_hex_decode(data: *u8, len: uint, out: *u8) -> bool
+Writes len / 2 bytes to out, upper and lower case digits are accepted. Returns false on an odd length or an invalid digit.
+*/
+unsafe fn add_hex_decode_function(ctx: &Context)
+{
+    let bool_type = LLVMInt1TypeInContext(ctx.context);
+    let int8_type = LLVMInt8TypeInContext(ctx.context);
+    let void_ptr = void_ptr_type(ctx);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut args = vec![void_ptr, size_type, void_ptr];
+    let func = add_weak_function(ctx, "menhir_hex_decode", LLVMFunctionType(bool_type, args.as_mut_ptr(), args.len() as c_uint, 0));
+    let (data, len, out) = (LLVMGetParam(func, 0), LLVMGetParam(func, 1), LLVMGetParam(func, 2));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let start_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("start"));
+    let body_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("body"));
+    let store_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("store"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("done"));
+    let fail_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("fail"));
+    let one = LLVMConstInt(size_type, 1, 0);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let odd = LLVMBuildTrunc(ctx.builder, len, bool_type, cstr!("odd"));
+    LLVMBuildCondBr(ctx.builder, odd, fail_bb, start_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, start_bb);
+    let (idx, next_bb) = gen_counting_loop(ctx, func, LLVMBuildLShr(ctx.builder, len, one, cstr!("n")), body_bb, done_bb);
+
+    // Digits map to 0 up to 15, everything else to a value above it
+    let nibble = |c: LLVMValueRef| {
+        let digit = LLVMBuildSub(ctx.builder, c, LLVMConstInt(int8_type, b'0' as u64, 0), cstr!("digit"));
+        let lower = LLVMBuildOr(ctx.builder, c, LLVMConstInt(int8_type, 0x20, 0), cstr!("lower"));
+        let letter = LLVMBuildSub(ctx.builder, lower, LLVMConstInt(int8_type, b'a' as u64, 0), cstr!("letter"));
+        let is_digit = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntULT, digit, LLVMConstInt(int8_type, 10, 0), cstr!("is_digit"));
+        let is_letter = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntULT, letter, LLVMConstInt(int8_type, 6, 0), cstr!("is_letter"));
+        let letter_value = LLVMBuildSelect(ctx.builder, is_letter, LLVMBuildAdd(ctx.builder, letter, LLVMConstInt(int8_type, 10, 0), cstr!("v")), LLVMConstInt(int8_type, 16, 0), cstr!("v"));
+        LLVMBuildSelect(ctx.builder, is_digit, digit, letter_value, cstr!("nibble"))
+    };
+
+    LLVMPositionBuilderAtEnd(ctx.builder, body_bb);
+    let i = LLVMBuildLoad(ctx.builder, idx, cstr!("i"));
+    let pos = LLVMBuildShl(ctx.builder, i, one, cstr!("pos"));
+    let high = nibble(LLVMBuildLoad(ctx.builder, byte_ptr(ctx, data, pos), cstr!("c")));
+    let pos = LLVMBuildAdd(ctx.builder, pos, one, cstr!("pos"));
+    let low = nibble(LLVMBuildLoad(ctx.builder, byte_ptr(ctx, data, pos), cstr!("c")));
+    let invalid = LLVMBuildICmp(ctx.builder, LLVMIntPredicate::LLVMIntUGT, LLVMBuildOr(ctx.builder, high, low, cstr!("both")), LLVMConstInt(int8_type, 15, 0), cstr!("invalid"));
+    LLVMBuildCondBr(ctx.builder, invalid, fail_bb, store_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, store_bb);
+    let b = LLVMBuildOr(ctx.builder, LLVMBuildShl(ctx.builder, high, LLVMConstInt(int8_type, 4, 0), cstr!("high")), low, cstr!("b"));
+    LLVMBuildStore(ctx.builder, b, byte_ptr(ctx, out, i));
+    LLVMBuildBr(ctx.builder, next_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    LLVMBuildRet(ctx.builder, LLVMConstInt(bool_type, 1, 0));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, fail_bb);
+    LLVMBuildRet(ctx.builder, LLVMConstInt(bool_type, 0, 0));
+}
+
+/*
+fn menhir_utf8_valid(data: *u8, len: uint) -> bool
+Every character has to be the shortest encoding of a code point up to U+10FFFF, which is not a surrogate.
+The first byte determines the number of continuation bytes, and the range of the second byte.
+*/
+unsafe fn add_utf8_valid_function(ctx: &Context)
+{
+    let bool_type = LLVMInt1TypeInContext(ctx.context);
+    let int8_type = LLVMInt8TypeInContext(ctx.context);
+    let void_ptr = void_ptr_type(ctx);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let mut args = vec![void_ptr, size_type];
+    let func = add_weak_function(ctx, "menhir_utf8_valid", LLVMFunctionType(bool_type, args.as_mut_ptr(), args.len() as c_uint, 0));
+    let (data, len) = (LLVMGetParam(func, 0), LLVMGetParam(func, 1));
+    let entry_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("entry"));
+    let cond_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("cond"));
+    let lead_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("lead"));
+    let multi_byte_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("multi_byte"));
+    let second_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("second"));
+    let rest_cond_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("rest_cond"));
+    let rest_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("rest"));
+    let advance_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("advance"));
+    let done_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("done"));
+    let fail_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("fail"));
+    let byte = |v: u64| LLVMConstInt(int8_type, v, 0);
+    let size = |v: u64| LLVMConstInt(size_type, v, 0);
+    let cmp = |pred: LLVMIntPredicate, a: LLVMValueRef, b: LLVMValueRef| LLVMBuildICmp(ctx.builder, pred, a, b, cstr!("cmp"));
+    let in_range = |b: LLVMValueRef, low: LLVMValueRef, high: LLVMValueRef| LLVMBuildAnd(ctx.builder,
+        cmp(LLVMIntPredicate::LLVMIntUGE, b, low),
+        cmp(LLVMIntPredicate::LLVMIntULE, b, high),
+        cstr!("in_range"));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, entry_bb);
+    let pos = LLVMBuildAlloca(ctx.builder, size_type, cstr!("pos"));
+    let end = LLVMBuildAlloca(ctx.builder, size_type, cstr!("end"));
+    LLVMBuildStore(ctx.builder, size(0), pos);
+    LLVMBuildBr(ctx.builder, cond_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, cond_bb);
+    let more = cmp(LLVMIntPredicate::LLVMIntULT, LLVMBuildLoad(ctx.builder, pos, cstr!("p")), len);
+    LLVMBuildCondBr(ctx.builder, more, lead_bb, done_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, lead_bb);
+    let p = LLVMBuildLoad(ctx.builder, pos, cstr!("p"));
+    let b = LLVMBuildLoad(ctx.builder, byte_ptr(ctx, data, p), cstr!("b"));
+    let ascii = cmp(LLVMIntPredicate::LLVMIntULT, b, byte(0x80));
+    let two = in_range(b, byte(0xC2), byte(0xDF));
+    let three = in_range(b, byte(0xE0), byte(0xEF));
+    let four = in_range(b, byte(0xF0), byte(0xF4));
+    let extra = LLVMBuildSelect(ctx.builder, two, size(1), LLVMBuildSelect(ctx.builder, three, size(2), LLVMBuildSelect(ctx.builder, four, size(3), size(0), cstr!("extra")), cstr!("extra")), cstr!("extra"));
+    let is_byte = |v: u64| cmp(LLVMIntPredicate::LLVMIntEQ, b, byte(v));
+    let low = LLVMBuildSelect(ctx.builder, is_byte(0xE0), byte(0xA0), LLVMBuildSelect(ctx.builder, is_byte(0xF0), byte(0x90), byte(0x80), cstr!("low")), cstr!("low"));
+    let high = LLVMBuildSelect(ctx.builder, is_byte(0xED), byte(0x9F), LLVMBuildSelect(ctx.builder, is_byte(0xF4), byte(0x8F), byte(0xBF), cstr!("high")), cstr!("high"));
+    let char_end = LLVMBuildAdd(ctx.builder, LLVMBuildAdd(ctx.builder, p, size(1), cstr!("next")), extra, cstr!("char_end"));
+    LLVMBuildStore(ctx.builder, char_end, end);
+    let valid_lead = LLVMBuildOr(ctx.builder, LLVMBuildOr(ctx.builder, ascii, two, cstr!("valid")), LLVMBuildOr(ctx.builder, three, four, cstr!("valid")), cstr!("valid"));
+    let fits = cmp(LLVMIntPredicate::LLVMIntULE, char_end, len);
+    LLVMBuildCondBr(ctx.builder, LLVMBuildAnd(ctx.builder, valid_lead, fits, cstr!("ok")), multi_byte_bb, fail_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, multi_byte_bb);
+    LLVMBuildCondBr(ctx.builder, ascii, advance_bb, second_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, second_bb);
+    let second_pos = LLVMBuildAdd(ctx.builder, p, size(1), cstr!("second_pos"));
+    let second = LLVMBuildLoad(ctx.builder, byte_ptr(ctx, data, second_pos), cstr!("second"));
+    LLVMBuildStore(ctx.builder, LLVMBuildAdd(ctx.builder, second_pos, size(1), cstr!("next")), pos);
+    LLVMBuildCondBr(ctx.builder, in_range(second, low, high), rest_cond_bb, fail_bb);
+
+    // The remaining bytes only have to be continuation bytes
+    LLVMPositionBuilderAtEnd(ctx.builder, rest_cond_bb);
+    let q = LLVMBuildLoad(ctx.builder, pos, cstr!("q"));
+    let more = cmp(LLVMIntPredicate::LLVMIntULT, q, LLVMBuildLoad(ctx.builder, end, cstr!("end")));
+    LLVMBuildCondBr(ctx.builder, more, rest_bb, cond_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, rest_bb);
+    let q = LLVMBuildLoad(ctx.builder, pos, cstr!("q"));
+    let c = LLVMBuildLoad(ctx.builder, byte_ptr(ctx, data, q), cstr!("c"));
+    LLVMBuildStore(ctx.builder, LLVMBuildAdd(ctx.builder, q, size(1), cstr!("next")), pos);
+    let continuation = cmp(LLVMIntPredicate::LLVMIntEQ, LLVMBuildAnd(ctx.builder, c, byte(0xC0), cstr!("top")), byte(0x80));
+    LLVMBuildCondBr(ctx.builder, continuation, rest_cond_bb, fail_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, advance_bb);
+    LLVMBuildStore(ctx.builder, LLVMBuildLoad(ctx.builder, end, cstr!("end")), pos);
+    LLVMBuildBr(ctx.builder, cond_bb);
+
+    LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+    LLVMBuildRet(ctx.builder, LLVMConstInt(bool_type, 1, 0));
+
+    LLVMPositionBuilderAtEnd(ctx.builder, fail_bb);
+    LLVMBuildRet(ctx.builder, LLVMConstInt(bool_type, 0, 0));
+}
+
+// Add the runtime functions with their default implementations
+pub unsafe fn add_runtime_functions(ctx: &mut Context)
+{
+    let void_type = LLVMVoidTypeInContext(ctx.context);
+    let int32_type = LLVMInt32TypeInContext(ctx.context);
+    let void_ptr = void_ptr_type(ctx);
+    let size_type = ctx.resolve_type(&ctx.target_machine.target.native_uint_type);
+    let ssize_type = ctx.resolve_type(&ctx.target_machine.target.native_int_type);
+
+    // fn menhir_alloc(size: uint) -> *void
+    let mut args = vec![size_type];
+    let alloc_type = LLVMFunctionType(void_ptr, args.as_mut_ptr(), args.len() as c_uint, 0);
+    gen_hook(ctx, "alloc", alloc_type, false, |ctx, params| {
+        let malloc = libc_function(ctx, "malloc", void_ptr, &mut [size_type]);
+        LLVMBuildCall(ctx.builder, malloc, params.as_mut_ptr(), params.len() as c_uint, cstr!("mem"))
+    });
+
+    // fn menhir_free(ptr: *void)
+    let mut args = vec![void_ptr];
+    let free_type = LLVMFunctionType(void_type, args.as_mut_ptr(), args.len() as c_uint, 0);
+    gen_hook(ctx, "free", free_type, false, |ctx, params| {
+        let free = libc_function(ctx, "free", void_type, &mut [void_ptr]);
+        LLVMBuildCall(ctx.builder, free, params.as_mut_ptr(), params.len() as c_uint, cstr!(""))
+    });
+
+    // fn menhir_write(fd: i32, data: *void, len: uint) -> int
+    let mut args = vec![int32_type, void_ptr, size_type];
+    let write_type = LLVMFunctionType(ssize_type, args.as_mut_ptr(), args.len() as c_uint, 0);
+    gen_hook(ctx, "write", write_type, false, |ctx, params| {
+        let write = libc_function(ctx, "write", ssize_type, &mut [int32_type, void_ptr, size_type]);
+        LLVMBuildCall(ctx.builder, write, params.as_mut_ptr(), params.len() as c_uint, cstr!("written"))
+    });
+
+    // fn menhir_alloc_trampoline() -> *void, executable memory for the trampoline of a closure,
+    // by default null, which puts the trampoline on the stack
+    let alloc_trampoline_type = LLVMFunctionType(void_ptr, ptr::null_mut(), 0, 0);
+    gen_hook(ctx, "alloc_trampoline", alloc_trampoline_type, false, |_, _| LLVMConstNull(void_ptr));
+
+    // fn menhir_free_trampoline(trampoline: *void), called with null as well
+    let mut args = vec![void_ptr];
+    let free_trampoline_type = LLVMFunctionType(void_type, args.as_mut_ptr(), args.len() as c_uint, 0);
+    gen_hook(ctx, "free_trampoline", free_trampoline_type, false, |_, _| ptr::null_mut());
+
+    let (seed, seed_len) = random_seed_globals(ctx);
+    add_random_function(ctx, seed, seed_len);
+    add_ref_count_functions(ctx);
+
+    // fn menhir_panic(msg: *void, len: uint), always aborts afterwards.
+    // Only write and abort are used, so it is safe to call from a signal handler.
+    let mut args = vec![void_ptr, size_type];
+    let panic_type = LLVMFunctionType(void_type, args.as_mut_ptr(), args.len() as c_uint, 0);
+    gen_hook(ctx, "panic", panic_type, true, |ctx, params| {
+        let write = libc_function(ctx, "write", ssize_type, &mut [int32_type, void_ptr, size_type]);
+        let write_stderr = |data: LLVMValueRef, len: LLVMValueRef| {
+            let mut write_args = vec![LLVMConstInt(int32_type, STDERR_FILENO, 0), data, len];
+            LLVMBuildCall(ctx.builder, write, write_args.as_mut_ptr(), write_args.len() as c_uint, cstr!("written"))
+        };
+        let written = write_stderr(params[0], params[1]);
+
+        let func = LLVMGetBasicBlockParent(LLVMGetInsertBlock(ctx.builder));
+        let print_seed_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("print_seed"));
+        let done_bb = LLVMAppendBasicBlockInContext(ctx.context, func, cstr!("done"));
+        let seed_str = LLVMBuildLoad(ctx.builder, seed, cstr!("seed"));
+        LLVMBuildCondBr(ctx.builder, LLVMBuildIsNull(ctx.builder, seed_str, cstr!("unseeded")), done_bb, print_seed_bb);
+
+        LLVMPositionBuilderAtEnd(ctx.builder, print_seed_bb);
+        write_stderr(LLVMBuildGlobalStringPtr(ctx.builder, cstr!("random seed: "), cstr!("prefix")), LLVMConstInt(size_type, 13, 0));
+        write_stderr(seed_str, LLVMBuildLoad(ctx.builder, seed_len, cstr!("seed_len")));
+        write_stderr(LLVMBuildGlobalStringPtr(ctx.builder, cstr!("\n"), cstr!("newline")), LLVMConstInt(size_type, 1, 0));
+        LLVMBuildBr(ctx.builder, done_bb);
+
+        LLVMPositionBuilderAtEnd(ctx.builder, done_bb);
+        written
+    });
+
+    add_parse_int_function(ctx);
+    add_parse_float_function(ctx);
+    add_format_int_function(ctx);
+    add_hex_encode_function(ctx);
+    add_hex_decode_function(ctx);
+    add_utf8_valid_function(ctx);
+}
+
+// Get one of the runtime functions
+pub unsafe fn runtime_function(ctx: &Context, hook: &str) -> LLVMValueRef
+{
+    let name = CString::new(format!("menhir_{}", hook)).expect("Invalid string");
+    let func = LLVMGetNamedFunction(ctx.module, name.as_ptr());
+    if func.is_null() {
+        panic!("Internal Compiler Error: unknown runtime function {}", hook);
+    }
+    func
+}
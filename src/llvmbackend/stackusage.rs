@@ -0,0 +1,118 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use bytecode::{ByteCodeModule, ByteCodeFunction, Instruction};
+use super::context::Context;
+
+pub struct FunctionStackUsage
+{
+    pub frame_size: usize,
+    // None if the function can end up calling itself
+    pub max_path_size: Option<usize>,
+}
+
+pub struct StackUsage
+{
+    pub functions: BTreeMap<String, FunctionStackUsage>,
+}
+
+fn frame_size(ctx: &Context, func: &ByteCodeFunction) -> usize
+{
+    let mut size = 0;
+    func.for_each_instruction(|instr| {
+        if let Instruction::StackAlloc(ref var) = *instr {
+            size += unsafe{ctx.target_machine.size_of_type(ctx.resolve_type(&var.typ))};
+        }
+        true
+    });
+    size
+}
+
+fn callees(bc_mod: &ByteCodeModule, func: &ByteCodeFunction) -> BTreeSet<String>
+{
+    let mut callees = BTreeSet::new();
+    func.for_each_instruction(|instr| {
+        if let Instruction::Call{func: ref callee, ..} = *instr {
+            if bc_mod.functions.contains_key(callee) {
+                callees.insert(callee.clone());
+            }
+        }
+        true
+    });
+    callees
+}
+
+fn max_path_size(
+    name: &str,
+    frames: &BTreeMap<String, usize>,
+    calls: &BTreeMap<String, BTreeSet<String>>,
+    visiting: &mut BTreeSet<String>,
+    cache: &mut BTreeMap<String, Option<usize>>) -> Option<usize>
+{
+    if let Some(size) = cache.get(name) {
+        return *size;
+    }
+
+    if visiting.contains(name) {
+        return None;
+    }
+
+    visiting.insert(name.into());
+    let mut deepest_callee = Some(0);
+    for callee in &calls[name] {
+        deepest_callee = match (deepest_callee, max_path_size(callee, frames, calls, visiting, cache)) {
+            (Some(a), Some(b)) => Some(cmp::max(a, b)),
+            _ => None,
+        };
+    }
+    visiting.remove(name);
+
+    let size = deepest_callee.map(|s| s + frames[name]);
+    cache.insert(name.into(), size);
+    size
+}
+
+// Estimate the stack usage of every function, based upon the size of the stack allocations done in the function
+pub fn estimate_stack_usage(ctx: &Context, bc_mod: &ByteCodeModule) -> StackUsage
+{
+    let mut frames = BTreeMap::new();
+    let mut calls = BTreeMap::new();
+    for (name, func) in &bc_mod.functions {
+        if func.external {
+            continue;
+        }
+        frames.insert(name.clone(), frame_size(ctx, func));
+        calls.insert(name.clone(), callees(bc_mod, func));
+    }
+
+    for callees in calls.values_mut() {
+        *callees = callees.iter().filter(|c| frames.contains_key(*c)).cloned().collect();
+    }
+
+    let mut cache = BTreeMap::new();
+    let mut functions = BTreeMap::new();
+    for (name, frame_size) in &frames {
+        let mut visiting = BTreeSet::new();
+        functions.insert(name.clone(), FunctionStackUsage{
+            frame_size: *frame_size,
+            max_path_size: max_path_size(name, &frames, &calls, &mut visiting, &mut cache),
+        });
+    }
+
+    StackUsage{functions}
+}
+
+impl fmt::Display for StackUsage
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "{:<40} {:>10} {:>12}", "function", "frame", "max path")?;
+        for (name, usage) in &self.functions {
+            match usage.max_path_size {
+                Some(s) => writeln!(f, "{:<40} {:>10} {:>12}", name, usage.frame_size, s)?,
+                None => writeln!(f, "{:<40} {:>10} {:>12}", name, usage.frame_size, "unbounded")?,
+            }
+        }
+        Ok(())
+    }
+}
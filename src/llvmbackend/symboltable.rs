@@ -3,6 +3,7 @@ use std::collections::HashMap;
 use llvm::prelude::*;
 
 use ast::{Type};
+use llvmbackend::abi::FunctionAbi;
 use llvmbackend::valueref::ValueRef;
 
 pub struct FunctionInstance
@@ -11,6 +12,8 @@ pub struct FunctionInstance
     pub name: String,
     pub return_type: Type,
     pub typ: Type,
+    // How struct arguments and results are passed, None for function pointers
+    pub abi: Option<FunctionAbi>,
 }
 
 impl FunctionInstance
@@ -22,6 +25,7 @@ impl FunctionInstance
             function: func,
             return_type: return_type,
             typ: typ,
+            abi: None,
         }
     }
 }
@@ -60,6 +64,12 @@ impl SymbolTable
         self.vars.get(name).cloned()
     }
 
+    // Move the variables of other into this table, replacing those with the same name
+    pub fn add_variables(&mut self, other: SymbolTable)
+    {
+        self.vars.extend(other.vars);
+    }
+
     pub fn add_function(&mut self, f: Rc<FunctionInstance>)
     {
         let name = f.name.clone();
@@ -1,6 +1,7 @@
+use std::cell::Cell;
 use std::ptr;
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_uint};
 
 use llvm::prelude::*;
 use llvm::core::*;
@@ -9,24 +10,36 @@ use llvm::target::*;
 use ast::IntSize;
 use target::Target;
 
-unsafe fn create_target_machine() -> Result<(String, LLVMTargetMachineRef), String>
+pub fn host_triplet() -> String
 {
-    let target_triple = LLVMGetDefaultTargetTriple();
-    let target_triple_str = CStr::from_ptr(target_triple).to_str().expect("Invalid target triple").to_owned();
+    unsafe {
+        let target_triple = LLVMGetDefaultTargetTriple();
+        let target_triple_str = CStr::from_ptr(target_triple).to_str().expect("Invalid target triple").to_owned();
+        LLVMDisposeMessage(target_triple);
+        target_triple_str
+    }
+}
+
+pub unsafe fn create_target_machine(target_triplet: Option<&str>) -> Result<(String, LLVMTargetMachineRef), String>
+{
+    let target_triple_str = match target_triplet {
+        Some(triplet) => triplet.to_owned(),
+        None => host_triplet(),
+    };
+    let target_triple = CString::new(&target_triple_str[..]).map_err(|_| format!("Invalid target triple {}", target_triple_str))?;
 
     let mut target: LLVMTargetRef = ptr::null_mut();
     let mut error_message: *mut c_char = ptr::null_mut();
-    if LLVMGetTargetFromTriple(target_triple, &mut target, &mut error_message) != 0 {
+    if LLVMGetTargetFromTriple(target_triple.as_ptr(), &mut target, &mut error_message) != 0 {
         let msg = CStr::from_ptr(error_message).to_str().expect("Invalid C string");
         let e = format!("Unable to get an LLVM target reference for {}: {}", target_triple_str, msg);
         LLVMDisposeMessage(error_message);
-        LLVMDisposeMessage(target_triple);
         return Err(e);
     }
 
     let target_machine = LLVMCreateTargetMachine(
         target,
-        target_triple,
+        target_triple.as_ptr(),
         cstr!(""),
         cstr!(""),
         LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
@@ -34,7 +47,6 @@ unsafe fn create_target_machine() -> Result<(String, LLVMTargetMachineRef), Stri
         LLVMCodeModel::LLVMCodeModelDefault,
     );
 
-    LLVMDisposeMessage(target_triple);
     if target_machine.is_null() {
         let e = format!("Unable to get a LLVM target machine for {}", target_triple_str);
         return Err(e);
@@ -46,15 +58,17 @@ unsafe fn create_target_machine() -> Result<(String, LLVMTargetMachineRef), Stri
 pub struct TargetMachine
 {
     pub target_machine: LLVMTargetMachineRef,
-    pub target_data: LLVMTargetDataRef,
+    // Caches the layout of struct types by their address, see forget_type_layouts
+    target_data: Cell<LLVMTargetDataRef>,
     pub target: Target
 }
 
 impl TargetMachine
 {
-    pub unsafe fn new() -> Result<TargetMachine, String>
+    // Create a target machine for target_triplet, or for the host if it is None
+    pub unsafe fn new(target_triplet: Option<&str>) -> Result<TargetMachine, String>
     {
-        let (target_triplet, target_machine) = create_target_machine()?;
+        let (target_triplet, target_machine) = create_target_machine(target_triplet)?;
         let target_data = LLVMCreateTargetDataLayout(target_machine);
         let int_size = match LLVMPointerSize(target_data) {
             1 => IntSize::I8,
@@ -66,14 +80,58 @@ impl TargetMachine
 
         Ok(TargetMachine{
             target_machine,
-            target_data,
+            target_data: Cell::new(target_data),
             target: Target::new(int_size, target_triplet)
         })
     }
 
+    // The triple and data layout of a module have to match the target machine
+    pub unsafe fn set_module_target(&self, module: LLVMModuleRef)
+    {
+        let triplet = CString::new(&self.target.triplet[..]).expect("Invalid target triple");
+        LLVMSetTarget(module, triplet.as_ptr());
+        LLVMSetModuleDataLayout(module, self.target_data.get());
+    }
+
+    pub fn is_cross_compiling(&self) -> bool
+    {
+        self.target.triplet != host_triplet()
+    }
+
+    // A struct type of a context which is disposed can have the address of a type of a later context,
+    // so the layouts cached for the types of a context must be forgotten with it
+    pub unsafe fn forget_type_layouts(&self)
+    {
+        let target_data = self.target_data.replace(LLVMCreateTargetDataLayout(self.target_machine));
+        LLVMDisposeTargetData(target_data);
+    }
+
     pub unsafe fn size_of_type(&self, typ: LLVMTypeRef) -> usize
     {
-        LLVMStoreSizeOfType(self.target_data, typ) as usize
+        LLVMStoreSizeOfType(self.target_data.get(), typ) as usize
+    }
+
+    pub unsafe fn alloc_size_of_type(&self, typ: LLVMTypeRef) -> usize
+    {
+        LLVMABISizeOfType(self.target_data.get(), typ) as usize
+    }
+
+    pub unsafe fn alignment_of_type(&self, typ: LLVMTypeRef) -> usize
+    {
+        LLVMABIAlignmentOfType(self.target_data.get(), typ) as usize
+    }
+
+    pub unsafe fn offset_of_element(&self, struct_type: LLVMTypeRef, index: usize) -> usize
+    {
+        LLVMOffsetOfElement(self.target_data.get(), struct_type, index as c_uint) as usize
+    }
+
+    pub unsafe fn is_little_endian(&self) -> bool
+    {
+        match LLVMByteOrder(self.target_data.get()) {
+            LLVMByteOrdering::LLVMLittleEndian => true,
+            LLVMByteOrdering::LLVMBigEndian => false,
+        }
     }
 
     pub unsafe fn emit_to_file(&self, module: LLVMModuleRef, obj_file_name: &str) -> Result<(), String>
@@ -96,7 +154,7 @@ impl Drop for TargetMachine
     {
         unsafe {
             LLVMDisposeTargetMachine(self.target_machine);
-            LLVMDisposeTargetData(self.target_data);
+            LLVMDisposeTargetData(self.target_data.get());
         }
     }
 }
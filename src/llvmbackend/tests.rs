@@ -1,20 +1,18 @@
 use std::fs;
-use std::mem;
-use std::ptr;
 use std::io::Read;
 use std::path::{PathBuf, Path};
-use bytecode::{OptimizationLevel, optimize_module};
-use bytecode::test::generate_byte_code;
-use target::register_target;
+use std::collections::HashMap;
+use ast::LogLevel;
+use bytecode::{OptimizationLevel, compile_to_byte_code, optimize_module, DEFAULT_INLINE_THRESHOLD};
+use ide::check_package;
 use llvmbackend::target::TargetMachine;
-use llvmbackend::{llvm_init, llvm_code_generation};
+use llvmbackend::{llvm_init, llvm_code_generation, CodeGenOptions};
 use llvmbackend::jit::JIT;
 
 pub struct Test
 {
-    pub name: String,
     pub ret: i64,
-    pub code: String,
+    pub path: PathBuf,
 }
 
 impl Test
@@ -28,35 +26,36 @@ impl Test
         assert!(data.starts_with("#ret:"));
         let ret: String = data.chars().skip(5).take_while(|c| c.is_numeric()).collect();
         Test{
-            name: path.file_stem().unwrap().to_str().unwrap().into(),
             ret: i64::from_str_radix(&ret, 10).unwrap(),
-            code: data,
+            path: path.to_owned(),
         }
     }
 
-    pub fn run(&self, dump: bool, target_machine: &TargetMachine) -> Result<i64, String>
+    pub fn run(&self, target_machine: &TargetMachine) -> Result<i64, String>
     {
-        let mut bc_mod = match generate_byte_code(&self.code, dump)
-        {
-            Ok(bc_mod) => bc_mod,
-            Err(e) => return Err(format!("Compile error: {}", e)),
-        };
+        let pkg = check_package(&self.path, &target_machine.target, &HashMap::new())
+            .map_err(|e| format!("Compile error: {}", e))?;
+        let mut bc_mod = compile_to_byte_code(&pkg, &target_machine.target, false, LogLevel::Debug)
+            .map_err(|e| format!("Compile error: {}", e))?;
 
-        optimize_module(&mut bc_mod, OptimizationLevel::Normal);
-        let mut ctx = llvm_code_generation(&bc_mod, target_machine)?;
+        optimize_module(&mut bc_mod, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+        let opts = CodeGenOptions{
+            intern_strings: true,
+            ..CodeGenOptions::default()
+        };
+        let mut ctx = llvm_code_generation(&bc_mod, target_machine, &opts)?;
         unsafe {
             let jit = JIT::new()?;
-            let llvm_module = mem::replace(&mut ctx.module, ptr::null_mut());
-            jit.run(llvm_module)
+            jit.run(&mut ctx)
         }
     }
 }
 
 
-fn run_test(prog: &Path, dump: bool, target_machine: &TargetMachine) -> Result<i64, String>
+fn run_test(prog: &Path, target_machine: &TargetMachine) -> Result<i64, String>
 {
     let test = Test::load(prog);
-    let ret = test.run(dump, target_machine)?;
+    let ret = test.run(target_machine)?;
     if ret != test.ret {
         Err(format!("Return value doesn't match: {}, expecting {}", ret, test.ret))
     } else {
@@ -75,7 +74,7 @@ fn run_tests_in_directory(dir: fs::ReadDir, target_machine: &TargetMachine) -> u
 
     for path in &paths {
         if path.extension().unwrap_or_default() == "mhr" {
-            let r = run_test(&path, false, target_machine);
+            let r = run_test(&path, target_machine);
             println!("{}: {:?}", path.file_stem().unwrap().to_str().unwrap(), r);
             if !r.is_ok()  {
                 failures += 1;
@@ -89,8 +88,7 @@ fn run_tests_in_directory(dir: fs::ReadDir, target_machine: &TargetMachine) -> u
 #[test]
 fn test_all()
 {
-    let target_machine = llvm_init().expect("Cannot create llvm target machine");
-    register_target(&target_machine);
+    let target_machine = llvm_init(None).expect("Cannot create llvm target machine");
 
     let mut testcode_found = false;
     for path in &["testcode", "../testcode"] {
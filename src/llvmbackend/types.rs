@@ -1,4 +1,6 @@
+use std::cmp;
 use std::ptr;
+use std::ffi::CString;
 use libc::*;
 use llvm::core::*;
 use llvm::prelude::*;
@@ -6,14 +8,14 @@ use llvm::prelude::*;
 use super::target::TargetMachine;
 use ast::*;
 
-unsafe fn string_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine) -> LLVMTypeRef
+unsafe fn string_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine) -> LLVMTypeRef
 {
-    struct_to_llvm_type(context, target_machine, &string_type_representation(target_machine.target.int_size))
+    struct_to_llvm_type(context, module, target_machine, &string_type_representation(target_machine.target.int_size))
 }
 
-unsafe fn slice_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine, slice_type: &SliceType) -> LLVMTypeRef
+unsafe fn slice_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, slice_type: &SliceType) -> LLVMTypeRef
 {
-    let element_type = to_llvm_type(context, target_machine, &slice_type.element_type);
+    let element_type = to_llvm_type(context, module, target_machine, &slice_type.element_type);
     let mut member_types = vec![
         LLVMPointerType(element_type, 0),      // Pointer to data
         native_llvm_int_type(context, target_machine),  // Length of string
@@ -21,20 +23,25 @@ unsafe fn slice_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMac
     LLVMStructTypeInContext(context, member_types.as_mut_ptr(), member_types.len() as c_uint, 0)
 }
 
-unsafe fn array_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine, at: &ArrayType) -> LLVMTypeRef
+unsafe fn array_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, at: &ArrayType) -> LLVMTypeRef
 {
-    let element_type = to_llvm_type(context, target_machine, &at.element_type);
+    let element_type = to_llvm_type(context, module, target_machine, &at.element_type);
     LLVMArrayType(element_type, at.len as c_uint)
 }
 
-unsafe fn sum_type_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine, st: &SumType) -> LLVMTypeRef
+unsafe fn sum_type_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, st: &SumType) -> LLVMTypeRef
 {
+    // The case is determined by a pointer being null or not
+    if let Some(niche) = st.niche() {
+        return to_llvm_type(context, module, target_machine, &st.cases[niche.data_case].typ);
+    }
+
     let mut member_types = vec![native_llvm_int_type(context, target_machine)]; // first entry is the tag
 
     // Calculate the biggest type
     let mut largest_type = ptr::null_mut();
     for c in &st.cases {
-        let case_typ = to_llvm_type(context, target_machine, &c.typ);
+        let case_typ = to_llvm_type(context, module, target_machine, &c.typ);
         if largest_type.is_null() || target_machine.size_of_type(case_typ) > target_machine.size_of_type(largest_type) {
             largest_type = case_typ;
         }
@@ -45,16 +52,22 @@ unsafe fn sum_type_to_llvm_type(context: LLVMContextRef, target_machine: &Target
     LLVMStructTypeInContext(context, member_types.as_mut_ptr(), member_types.len() as c_uint, 0)
 }
 
-unsafe fn func_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine, ft: &FuncType) -> LLVMTypeRef
+unsafe fn func_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, ft: &FuncType) -> LLVMTypeRef
 {
+    // Like the functions themselves, arguments which are not passed by value are passed as a pointer
     let mut llvm_arg_types = Vec::with_capacity(ft.args.len());
     for arg in &ft.args {
-        llvm_arg_types.push(to_llvm_type(context, target_machine, arg));
+        let llvm_type = to_llvm_type(context, module, target_machine, arg);
+        if arg.pass_by_value() {
+            llvm_arg_types.push(llvm_type);
+        } else {
+            llvm_arg_types.push(LLVMPointerType(llvm_type, 0));
+        }
     }
 
     LLVMPointerType(
         LLVMFunctionType(
-            to_llvm_type(context, target_machine, &ft.return_type),
+            to_llvm_type(context, module, target_machine, &ft.return_type),
             llvm_arg_types.as_mut_ptr(),
             ft.args.len() as c_uint,
             0
@@ -63,18 +76,64 @@ unsafe fn func_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMach
     )
 }
 
-unsafe fn struct_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine, st: &StructType) -> LLVMTypeRef
+// Order of the members in the LLVM type, the alignment of members comes from the data layout of the target
+pub unsafe fn struct_member_order(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, st: &StructType) -> Vec<usize>
+{
+    st.member_order(|typ| target_machine.alignment_of_type(to_llvm_type(context, module, target_machine, typ)))
+}
+
+unsafe fn struct_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, st: &StructType) -> LLVMTypeRef
 {
     let mut llvm_member_types = Vec::with_capacity(st.members.len());
-    for m in &st.members {
-        llvm_member_types.push(to_llvm_type(context, target_machine, &m.typ));
+    for idx in struct_member_order(context, module, target_machine, st) {
+        llvm_member_types.push(to_llvm_type(context, module, target_machine, &st.members[idx].typ));
     }
     LLVMStructTypeInContext(context, llvm_member_types.as_mut_ptr(), llvm_member_types.len() as c_uint, 0)
 }
 
-unsafe fn optional_to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine, inner: &Type) -> LLVMTypeRef
+unsafe fn union_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, ut: &StructType) -> LLVMTypeRef
+{
+    let mut size = 0;
+    let mut alignment = 1;
+    for m in &ut.members {
+        let member_type = to_llvm_type(context, module, target_machine, &m.typ);
+        size = cmp::max(size, target_machine.alloc_size_of_type(member_type));
+        alignment = cmp::max(alignment, target_machine.alignment_of_type(member_type));
+    }
+
+    // A blob of bytes, made out of integers of the biggest alignment, so the union gets the same alignment as its members
+    let num_elements = (size + alignment - 1) / alignment;
+    let element_type = LLVMIntTypeInContext(context, (alignment * 8) as c_uint);
+    LLVMArrayType(element_type, num_elements as c_uint)
+}
+
+unsafe fn opaque_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, ot: &OpaqueType) -> LLVMTypeRef
 {
-    let inner = to_llvm_type(context, target_machine, inner);
+    // Named struct without a body, reuse it if it was already created, so all pointers to it have the same type
+    let name = CString::new(&ot.name[..]).expect("Invalid type name");
+    let existing = LLVMGetTypeByName(module, name.as_ptr());
+    if existing.is_null() {
+        LLVMStructCreateNamed(context, name.as_ptr())
+    } else {
+        existing
+    }
+}
+
+unsafe fn interface_to_llvm_type(context: LLVMContextRef) -> LLVMTypeRef
+{
+    // Pointer to the object and pointer to the function table
+    let i8_ptr_type = LLVMPointerType(LLVMInt8TypeInContext(context), 0);
+    let mut member_types = vec![i8_ptr_type, i8_ptr_type];
+    LLVMStructTypeInContext(context, member_types.as_mut_ptr(), member_types.len() as c_uint, 0)
+}
+
+unsafe fn optional_to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, inner: &Type) -> LLVMTypeRef
+{
+    if inner.has_null_niche() {
+        return to_llvm_type(context, module, target_machine, inner);
+    }
+
+    let inner = to_llvm_type(context, module, target_machine, inner);
     let mut member_types = vec![
         LLVMInt1TypeInContext(context),  // nil or not
         inner,
@@ -93,7 +152,7 @@ pub unsafe fn native_llvm_int_type(context: LLVMContextRef, target_machine: &Tar
     }
 }
 
-pub unsafe fn to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachine, typ: &Type) -> LLVMTypeRef
+pub unsafe fn to_llvm_type(context: LLVMContextRef, module: LLVMModuleRef, target_machine: &TargetMachine, typ: &Type) -> LLVMTypeRef
 {
     match *typ
     {
@@ -106,18 +165,20 @@ pub unsafe fn to_llvm_type(context: LLVMContextRef, target_machine: &TargetMachi
         Type::Bool => LLVMInt1TypeInContext(context),
         Type::Float(FloatSize::F32) => LLVMFloatTypeInContext(context),
         Type::Float(FloatSize::F64) => LLVMDoubleTypeInContext(context),
-        Type::Pointer(ref inner) => LLVMPointerType(to_llvm_type(context, target_machine, inner), 0),
-        Type::Array(ref at) => array_to_llvm_type(context, target_machine, at),
-        Type::Slice(ref st) => slice_to_llvm_type(context, target_machine, st),
-        Type::String => string_to_llvm_type(context, target_machine),
-        Type::Func(ref ft) => func_to_llvm_type(context, target_machine, ft),
-        Type::Struct(ref st) => struct_to_llvm_type(context, target_machine, st),
-        Type::Sum(ref st) => sum_type_to_llvm_type(context, target_machine, st),
-        Type::Optional(ref ot) => optional_to_llvm_type(context, target_machine, ot),
+        Type::Pointer(ref inner) => LLVMPointerType(to_llvm_type(context, module, target_machine, inner), 0),
+        Type::Array(ref at) => array_to_llvm_type(context, module, target_machine, at),
+        Type::Slice(ref st) => slice_to_llvm_type(context, module, target_machine, st),
+        Type::String => string_to_llvm_type(context, module, target_machine),
+        Type::Func(ref ft) => func_to_llvm_type(context, module, target_machine, ft),
+        Type::Struct(ref st) => struct_to_llvm_type(context, module, target_machine, st),
+        Type::Union(ref ut) => union_to_llvm_type(context, module, target_machine, ut),
+        Type::Opaque(ref ot) => opaque_to_llvm_type(context, module, ot),
+        Type::Sum(ref st) => sum_type_to_llvm_type(context, module, target_machine, st),
+        Type::Optional(ref ot) => optional_to_llvm_type(context, module, target_machine, ot),
         Type::Generic(_) => panic!("Internal Compiler Error: All generic types must have been resolved before code generation"),
         Type::Unresolved(_) => panic!("Internal Compiler Error: All types must be resolved before code generation"),
         Type::Unknown => panic!("Internal Compiler Error: all types must be known before code generation"),
         Type::SelfType => panic!("Internal Compiler Error: self type must be known at this point"),
-        Type::Interface(_) => panic!("Internal Compiler Error: interface type must be known at this point")
+        Type::Interface(_) => interface_to_llvm_type(context),
     }
 }
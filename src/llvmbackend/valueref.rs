@@ -1,4 +1,4 @@
-use libc::{c_char, c_uint};
+use libc::{c_uint, c_ulonglong};
 use llvm::LLVMLinkage;
 use llvm::core::*;
 use llvm::prelude::*;
@@ -6,7 +6,7 @@ use llvm::prelude::*;
 use ast::*;
 use bytecode::{ByteCodeProperty, Operand, Constant};
 use super::context::Context;
-use super::instructions::{const_uint, const_int, const_bool, const_float, const_char, copy, get_operand};
+use super::instructions::{const_uint, const_int, const_bool, const_char, copy, get_operand};
 
 
 #[derive(Clone)]
@@ -31,13 +31,26 @@ impl ValueRef
     {
         match *cst {
             Constant::String(ref s) => ValueRef::const_string(ctx, s),
-            Constant::Int(v, int_size) => ValueRef::new(const_int(ctx, v), Type::Int(int_size)),
-            Constant::UInt(v, int_size) => ValueRef::new(const_uint(ctx, v), Type::UInt(int_size)),
-            Constant::Float(v, float_size) => ValueRef::new(const_float(ctx, v), Type::Float(float_size)),
+            // Constants of other sizes than the native ones get the LLVM type of their size
+            Constant::Int(v, int_size) => {
+                let typ = Type::Int(int_size);
+                ValueRef::new(LLVMConstInt(ctx.resolve_type(&typ), v as c_ulonglong, 1), typ)
+            },
+            Constant::UInt(v, int_size) => {
+                let typ = Type::UInt(int_size);
+                ValueRef::new(LLVMConstInt(ctx.resolve_type(&typ), v as c_ulonglong, 0), typ)
+            },
+            Constant::Float(v, float_size) => {
+                let typ = Type::Float(float_size);
+                ValueRef::new(LLVMConstReal(ctx.resolve_type(&typ), v), typ)
+            },
             Constant::Char(v) => ValueRef::new(const_char(ctx, v), Type::Char),
             Constant::Bool(v) => ValueRef::new(const_bool(ctx, v), Type::Bool),
             Constant::Array(ref elements) => ValueRef::const_array(ctx, elements),
-            Constant::NullPtr(ref typ) => ValueRef::new(LLVMConstNull(ctx.resolve_type(typ)), ptr_type(typ.clone())),
+            Constant::NullPtr(ref typ) => {
+                let typ = ptr_type(typ.clone());
+                ValueRef::new(LLVMConstNull(ctx.resolve_type(&typ)), typ)
+            },
         }
     }
 
@@ -45,11 +58,7 @@ impl ValueRef
     unsafe fn const_string(ctx: &Context, s: &str) -> ValueRef
     {
         let char_type = LLVMInt8TypeInContext(ctx.context);
-        let glob = LLVMAddGlobal(ctx.module, LLVMArrayType(char_type, (s.len() + 1) as c_uint), cstr!("str_constant"));
-        LLVMSetLinkage(glob, LLVMLinkage::LLVMInternalLinkage);
-        let const_string = LLVMConstStringInContext(ctx.context, s.as_bytes().as_ptr() as *const c_char, s.len() as c_uint, 0);
-        LLVMSetInitializer(glob, const_string);
-
+        let glob = ctx.string_constant(s);
 
         let ret = ValueRef::new(
             LLVMBuildAlloca(ctx.builder, ctx.resolve_type(&Type::String), cstr!("str")),
@@ -65,6 +74,18 @@ impl ValueRef
         ret
     }
 
+    // A string constant outside of a function, where there is no stack to put the slice on, so it is a constant too
+    pub unsafe fn const_string_slice(ctx: &Context, s: &str) -> LLVMValueRef
+    {
+        let char_type = LLVMInt8TypeInContext(ctx.context);
+        let glob = ctx.string_constant(s);
+        let mut parts = [
+            LLVMConstBitCast(glob, LLVMPointerType(char_type, 0)),
+            const_uint(ctx, s.len() as u64),
+        ];
+        LLVMConstNamedStruct(ctx.resolve_type(&Type::String), parts.as_mut_ptr(), parts.len() as c_uint)
+    }
+
     unsafe fn const_array(ctx: &Context, elements: &[Constant]) -> ValueRef
     {
         let (element_type, array_type) = if let Some(el) = elements.first() {
@@ -94,6 +115,17 @@ impl ValueRef
         let element_type = self.typ.get_pointer_element_type().unwrap_or_else(|| panic!("Store not allowed on type {}", self.typ));
         match *element_type
         {
+            Type::Optional(ref inner) if inner.has_null_niche() => {
+                let value = if **inner == val.typ {
+                    val.value
+                } else if val.typ.is_pointer_to_optional() {
+                    LLVMBuildLoad(ctx.builder, val.value, cstr!("src"))
+                } else {
+                    val.load(ctx)
+                };
+                LLVMBuildStore(ctx.builder, value, self.value);
+            },
+
             Type::Optional(ref inner) => {
                 let dst_opt_flag_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("dst_opt_flag_ptr"));
                 let dst_data_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 1, cstr!("dst_data_ptr"));
@@ -141,6 +173,10 @@ impl ValueRef
         if let Some(element_type) = self.typ.get_pointer_element_type() {
             match *element_type
             {
+                Type::Optional(ref inner_type) if inner_type.has_null_niche() => unsafe {
+                    LLVMBuildLoad(ctx.builder, self.value, cstr!("inner"))
+                },
+
                 Type::Optional(ref inner_type) => unsafe {
                     let inner_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 1, cstr!("inner_ptr"));
                     if inner_type.pass_by_value() {
@@ -170,6 +206,7 @@ impl ValueRef
             Type::Array(_) |
             Type::Slice(_) |
             Type::Struct(_) |
+            Type::Union(_) |
             Type::Sum(_) |
             Type::Func(_) |
             Type::Optional(_) |
@@ -185,6 +222,14 @@ impl ValueRef
         let typ = self.typ.get_pointer_element_type().unwrap_or(&self.typ);
         match *typ
         {
+            Type::Optional(ref inner) if inner.has_null_niche() => unsafe {
+                let ptr = LLVMBuildLoad(ctx.builder, self.value, cstr!("ptr"));
+                ValueRef::new(
+                    LLVMBuildIsNotNull(ctx.builder, ptr, cstr!("is_nil")),
+                    Type::Bool
+                )
+            },
+
             Type::Optional(_) => unsafe {
                 let opt_flag_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("opt_flag_ptr"));
                 ValueRef::new(
@@ -202,6 +247,10 @@ impl ValueRef
         let element_type = self.typ.get_pointer_element_type().unwrap_or_else(|| panic!("storenil not allowed on type {}", self.typ));
         match *element_type
         {
+            Type::Optional(ref inner) if inner.has_null_niche() => unsafe {
+                LLVMBuildStore(ctx.builder, LLVMConstNull(ctx.resolve_type(inner)), self.value);
+            },
+
             Type::Optional(_) => unsafe {
                 let opt_flag_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("opt_flag_ptr"));
                 LLVMBuildStore(ctx.builder, const_bool(ctx, false), opt_flag_ptr);
@@ -238,6 +287,16 @@ impl ValueRef
                 )
             },
 
+            Type::String => unsafe {
+                let index = get_operand(ctx, index).load(ctx);
+                let data_ptr = LLVMBuildLoad(ctx.builder, self.slice_data_ptr(ctx), cstr!("data_ptr"));
+                let mut indices = vec![index];
+                ValueRef::new(
+                    LLVMBuildGEP(ctx.builder, data_ptr, indices.as_mut_ptr(), 1, cstr!("member")),
+                    ptr_type(Type::UInt(IntSize::I8))
+                )
+            },
+
             Type::Struct(ref st) => unsafe {
                 let index = match *index {
                     Operand::Const(Constant::Int(v, _)) => v as usize,
@@ -245,12 +304,29 @@ impl ValueRef
                     _ => panic!("Struct member access has to be through an integer"),
                 };
 
+                let position = ctx.struct_member_position(st, index);
                 ValueRef::new(
-                    LLVMBuildStructGEP(ctx.builder, self.value, index as c_uint, cstr!("member")),
+                    LLVMBuildStructGEP(ctx.builder, self.value, position as c_uint, cstr!("member")),
                     ptr_type(st.members[index].typ.clone())
                 )
             },
 
+            Type::Union(ref ut) => unsafe {
+                let index = match *index {
+                    Operand::Const(Constant::Int(v, _)) => v as usize,
+                    Operand::Const(Constant::UInt(v, _)) => v as usize,
+                    _ => panic!("Union member access has to be through an integer"),
+                };
+
+                // All members start at the beginning of the union, so just cast the pointer
+                let member_type = &ut.members[index].typ;
+                let type_to_cast_to = LLVMPointerType(ctx.resolve_type(member_type), 0);
+                ValueRef::new(
+                    LLVMBuildBitCast(ctx.builder, self.value, type_to_cast_to, cstr!("union_member_ptr")),
+                    ptr_type(member_type.clone())
+                )
+            },
+
             Type::Sum(ref st) => unsafe {
                 let index = match *index {
                     Operand::Const(Constant::Int(v, _)) => v as usize,
@@ -258,7 +334,11 @@ impl ValueRef
                     _ => panic!("Sum type member access has to be through an integer"),
                 };
 
-                let st_data_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 1, cstr!("st_data_ptr"));
+                let st_data_ptr = if st.niche().is_some() {
+                    self.value
+                } else {
+                    LLVMBuildStructGEP(ctx.builder, self.value, 1, cstr!("st_data_ptr"))
+                };
                 let case_type = &st.cases[index].typ;
                 let type_to_cast_to = LLVMPointerType(ctx.resolve_type(case_type), 0);
                 ValueRef::new(
@@ -286,11 +366,26 @@ impl ValueRef
             .unwrap_or_else(|| panic!("Store member not allowed on type {}", self.typ));
         match *element_type
         {
-            Type::Array(_) | Type::Struct(_) | Type::Slice(_) | Type::Pointer(_)  => unsafe {
+            Type::Array(_) | Type::Struct(_) | Type::Union(_) | Type::Slice(_) | Type::Pointer(_)  => unsafe {
                 let member_ptr = self.get_member_ptr(ctx, index);
                 member_ptr.store(ctx, value);
             },
 
+            // A string built from its data pointer and length
+            Type::String => unsafe {
+                let index = match *index {
+                    Operand::Const(Constant::UInt(v, _)) => v as usize,
+                    _ => panic!("String member access has to be through an integer"),
+                };
+
+                let string_rep = string_type_representation(ctx.target_machine.target.int_size);
+                let member_ptr = ValueRef::new(
+                    LLVMBuildStructGEP(ctx.builder, self.value, index as c_uint, cstr!("member")),
+                    ptr_type(string_rep.members[index].typ.clone())
+                );
+                member_ptr.store(ctx, value);
+            },
+
             _ => panic!("Store member not allowed on type {}", self.typ),
         }
 
@@ -329,6 +424,14 @@ impl ValueRef
                 )
             },
 
+            (&Type::Interface(_), ByteCodeProperty::Data) => unsafe {
+                let data_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("data_ptr"));
+                ValueRef::new(
+                    LLVMBuildLoad(ctx.builder, data_ptr, cstr!("data")),
+                    ptr_type(Type::UInt(IntSize::I8)),
+                )
+            },
+
             (&Type::String, ByteCodeProperty::Data) => unsafe {
                 let data_ptr = self.slice_data_ptr(ctx);
                 ValueRef::new(
@@ -337,7 +440,16 @@ impl ValueRef
                 )
             },
 
-            (&Type::Sum(_), ByteCodeProperty::SumTypeIndex) => unsafe {
+            (&Type::Sum(ref st), ByteCodeProperty::SumTypeIndex) => unsafe {
+                if let Some(niche) = st.niche() {
+                    let ptr = LLVMBuildLoad(ctx.builder, self.sum_niche_ptr(ctx, st), cstr!("niche"));
+                    let is_null = LLVMBuildIsNull(ctx.builder, ptr, cstr!("is_null"));
+                    return ValueRef::new(
+                        LLVMBuildSelect(ctx.builder, is_null, const_uint(ctx, niche.empty_case as u64), const_uint(ctx, niche.data_case as u64), cstr!("sti")),
+                        native_uint_type,
+                    );
+                }
+
                 let sti_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("sti_ptr"));
                 ValueRef::new(
                     LLVMBuildLoad(ctx.builder, sti_ptr, cstr!("sti")),
@@ -356,15 +468,38 @@ impl ValueRef
 
         match (element_type, prop)
         {
-            (&Type::Sum(_), ByteCodeProperty::SumTypeIndex) => unsafe {
-                let sti_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("sti_ptr"));
-                LLVMBuildStore(ctx.builder, const_uint(ctx, value as u64), sti_ptr);
+            (&Type::Sum(ref st), ByteCodeProperty::SumTypeIndex) => unsafe {
+                match st.niche() {
+                    // The data case gets a non null pointer, when its members are initialized
+                    Some(niche) if niche.empty_case == value => {
+                        let niche_ptr = self.sum_niche_ptr(ctx, st);
+                        LLVMBuildStore(ctx.builder, LLVMConstNull(LLVMGetElementType(LLVMTypeOf(niche_ptr))), niche_ptr);
+                    },
+                    Some(_) => (),
+                    None => {
+                        let sti_ptr = LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("sti_ptr"));
+                        LLVMBuildStore(ctx.builder, const_uint(ctx, value as u64), sti_ptr);
+                    },
+                }
             },
 
             _ => panic!("Set property not allowed")
         }
     }
 
+    // Pointer to the member which determines the case of a sum type with a niche
+    unsafe fn sum_niche_ptr(&self, ctx: &Context, st: &SumType) -> LLVMValueRef
+    {
+        let niche = st.niche().expect("Internal Compiler Error: sum type has no niche");
+        match st.cases[niche.data_case].typ {
+            Type::Struct(ref case_type) => {
+                let position = ctx.struct_member_position(case_type, niche.member);
+                LLVMBuildStructGEP(ctx.builder, self.value, position as c_uint, cstr!("niche_ptr"))
+            },
+            _ => panic!("Internal Compiler Error: the data case of {} is not a struct", st.name),
+        }
+    }
+
     unsafe fn slice_data_ptr(&self, ctx: &Context) -> LLVMValueRef
     {
         LLVMBuildStructGEP(ctx.builder, self.value, 0, cstr!("slice_data_ptr"))
@@ -382,7 +517,7 @@ impl ValueRef
 
         match *inner_type
         {
-            Type::Array(_) | Type::Slice(_) => {
+            Type::Array(_) | Type::Slice(_) | Type::String => {
                 let data_ptr = self.slice_data_ptr(ctx);
                 let len_ptr = self.slice_len_ptr(ctx);
                 let member_ptr = array.get_member_ptr(ctx, start);
@@ -398,7 +533,10 @@ impl ValueRef
     {
         let data_ptr = self.slice_data_ptr(ctx);
         let len_ptr = self.slice_len_ptr(ctx);
-        LLVMBuildStore(ctx.builder, data.value, data_ptr);
+        // A data pointer loaded from a member or variable comes as the address of that pointer
+        let data_is_address = data.typ.get_pointer_element_type().map(|t| t.is_pointer()).unwrap_or(false);
+        let data_value = if data_is_address {data.load(ctx)} else {data.value};
+        LLVMBuildStore(ctx.builder, data_value, data_ptr);
         LLVMBuildStore(ctx.builder, len.load(ctx), len_ptr);
     }
 }
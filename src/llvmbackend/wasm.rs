@@ -0,0 +1,83 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+pub const WASM_TARGET_TRIPLET: &str = "wasm32-unknown-unknown";
+
+/*
+A WebAssembly module is linked with wasm-ld instead of the native linker. There is no libc, so every
+undefined function becomes an import from the env module, which the JS loader has to provide.
+The loader implements puts, anything else can be passed in by the embedder.
+*/
+const JS_LOADER: &str = r#"'use strict';
+
+// Loader for {module}, call run() to execute main
+const WASM_FILE = '{module}';
+
+function readString(memory, ptr) {
+    const bytes = new Uint8Array(memory.buffer, ptr);
+    let end = 0;
+    while (bytes[end] !== 0) {
+        end++;
+    }
+    return new TextDecoder('utf-8').decode(bytes.subarray(0, end));
+}
+
+async function instantiate(imports) {
+    if (typeof process !== 'undefined' && process.versions && process.versions.node) {
+        const fs = require('fs');
+        const path = require('path');
+        const bytes = fs.readFileSync(path.join(__dirname, WASM_FILE));
+        return (await WebAssembly.instantiate(bytes, imports)).instance;
+    }
+    return (await WebAssembly.instantiateStreaming(fetch(WASM_FILE), imports)).instance;
+}
+
+async function run(env = {}) {
+    let instance = null;
+    const imports = {
+        env: Object.assign({
+            puts: (ptr) => {
+                console.log(readString(instance.exports.memory, ptr));
+                return 0;
+            },
+        }, env),
+    };
+
+    instance = await instantiate(imports);
+    if (instance.exports.__wasm_call_ctors) {
+        instance.exports.__wasm_call_ctors();
+    }
+    return instance.exports.main();
+}
+
+if (typeof module !== 'undefined') {
+    module.exports = { run };
+    if (require.main === module) {
+        run().then((code) => process.exit(code));
+    }
+}
+"#;
+
+pub fn wasm_link_command(output_file_path: &str, obj_file: &str) -> Command
+{
+    let mut cmd = Command::new("wasm-ld");
+    cmd.arg("--no-entry")
+        .arg("--export-all")
+        .arg("--allow-undefined")
+        .arg("-o").arg(output_file_path)
+        .arg(obj_file);
+    cmd
+}
+
+// Write the JS loader next to the module
+pub fn write_js_loader(wasm_file_path: &Path) -> Result<(), String>
+{
+    let module = wasm_file_path.file_name().expect("Wasm module must have a file name").to_string_lossy();
+    let path = wasm_file_path.with_extension("js");
+    println!("  Generating {}", path.to_string_lossy());
+    File::create(&path)
+        .and_then(|mut file| file.write_all(JS_LOADER.replace("{module}", &module).as_bytes()))
+        .map_err(|e| format!("Unable to write {}: {}", path.to_string_lossy(), e))
+}
@@ -22,10 +22,20 @@ macro_rules! try_opt {
 }
 
 
+mod artifact;
 mod ast;
+mod callgraph;
+mod checkcache;
+mod cheader;
 mod compileerror;
 mod bytecode;
+mod buildinfo;
+mod examples;
 mod exportlibrary;
+mod ide;
+mod importindex;
+mod interpreter;
+mod json;
 mod parser;
 mod typechecker;
 mod span;
@@ -33,46 +43,333 @@ mod llvmbackend;
 mod target;
 mod timer;
 mod package;
+mod query;
 mod packagebuild;
+mod project;
+mod reduce;
+mod repl;
+mod unittest;
+mod selftest;
+mod stdlib;
 
-use std::fs::File;
-use std::process::exit;
-use std::path::PathBuf;
-use clap::ArgMatches;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{self, exit, Command};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{Arg, ArgMatches, SubCommand};
 
-use compileerror::{CompileResult};
-use llvmbackend::{OutputType, llvm_init, llvm_shutdown};
-use packagebuild::{PackageData, BuildOptions};
+use ast::LogLevel;
+use buildinfo::parse_define;
+use bytecode::{compile_to_byte_code, optimize_module, print_match_lowering, OptimizationLevel, DEFAULT_INLINE_THRESHOLD};
+use compileerror::{CompileResult, CompileError, ColorMode, DEFAULT_ERROR_LIMIT, set_color_mode};
+use llvmbackend::{OutputType, TargetMachine, WASM_TARGET_TRIPLET, llvm_init, llvm_shutdown};
+use packagebuild::{PackageData, BuildOptions, BuildProfile, import_search_path, check_cache_directory};
 use exportlibrary::ExportLibrary;
+use callgraph::CallGraph;
+use package::Package;
+use ide::{Workspace, check_package, completions_to_json, parse_position};
+use span::Pos;
+use target::Target;
+use interpreter::run_module;
+use examples::run_examples;
+use unittest::run_unit_tests;
+use project::{new_project, init_project};
+use selftest::{selftest, SelfTestResult};
+use repl::run_repl;
+use reduce::reduce;
+use json::{json_string, json_span};
+use parser::{grammar_to_ebnf, syntax_definition, SyntaxFormat};
 
 
-fn build_command(matches: &ArgMatches, dump_flags: &str) -> CompileResult<i32>
+fn build_profile(matches: &ArgMatches) -> CompileResult<BuildProfile>
 {
-    let input_file = matches.value_of("INPUT_FILE").expect("No input file given");
-    let build_options = BuildOptions{
-        optimize: matches.is_present("OPTIMIZE"),
+    let mut profile = if matches.is_present("RELEASE") {
+        BuildProfile::release()
+    } else {
+        BuildProfile::from_name(matches.value_of("PROFILE").unwrap_or("debug"))?
+    };
+
+    if matches.is_present("OPTIMIZE") {
+        profile.optimize = true;
+    }
+
+    if matches.is_present("DEBUG_INFO") {
+        profile.debug_info = true;
+    }
+
+    if matches.is_present("NO_INTERN_STRINGS") {
+        profile.intern_strings = false;
+        profile.merge_strings = false;
+    }
+
+    if let Some(level) = log_level(matches) {
+        profile.log_level = level;
+    }
+
+    if let Some(threshold) = matches.value_of("INLINE_THRESHOLD") {
+        profile.inline_threshold = threshold.parse::<usize>()
+            .map_err(|_| CompileError::Other(format!("Invalid --inline-threshold {}, expecting a number of instructions", threshold)))?;
+    }
+    Ok(profile)
+}
+
+// The level given with --log-level
+fn log_level(matches: &ArgMatches) -> Option<LogLevel>
+{
+    matches.value_of("LOG_LEVEL").and_then(LogLevel::from_name)
+}
+
+// The values given with --define key=value, for @build_info
+fn defines(matches: &ArgMatches) -> CompileResult<HashMap<String, String>>
+{
+    let mut defines = HashMap::new();
+    for define in matches.values_of("DEFINE").into_iter().flat_map(|values| values) {
+        let (key, value) = parse_define(define)?;
+        defines.insert(key, value);
+    }
+    Ok(defines)
+}
+
+const EMIT_VALUES: [&str; 5] = ["stack-usage", "c-header", "ffi-json", "package", "wasm"];
+
+// Whether --emit contains value, the argument is a comma separated list
+fn emit(matches: &ArgMatches, value: &str) -> CompileResult<bool>
+{
+    let mut found = false;
+    for e in matches.value_of("EMIT").unwrap_or("").split(',').filter(|e| !e.is_empty()) {
+        if !EMIT_VALUES.contains(&e) {
+            return Err(CompileError::Other(format!("Unknown --emit value {}", e)));
+        }
+        found |= e == value;
+    }
+    Ok(found)
+}
+
+// --emit wasm implies the wasm32 target, unless another target is given
+fn target_machine(matches: &ArgMatches) -> CompileResult<TargetMachine>
+{
+    let target = match matches.value_of("TARGET") {
+        Some(target) => Some(target),
+        None if emit(matches, "wasm")? => Some(WASM_TARGET_TRIPLET),
+        None => None,
+    };
+    Ok(llvm_init(target)?)
+}
+
+// Whether the program is instrumented with value, for --instrument
+fn instrument(matches: &ArgMatches, value: &str) -> bool
+{
+    matches.value_of("INSTRUMENT") == Some(value)
+}
+
+fn import_directories(matches: &ArgMatches) -> Vec<PathBuf>
+{
+    matches.value_of("IMPORTS")
+        .map(|dirs| dirs.split(',').map(PathBuf::from).collect())
+        .unwrap_or_else(Vec::new)
+}
+
+// Handle --print, returns false if there is nothing to print
+fn print_info(matches: &ArgMatches) -> bool
+{
+    match matches.value_of("PRINT") {
+        Some("import-paths") => {
+            for import_path in import_search_path(&import_directories(matches)) {
+                let exists = if import_path.path.is_dir() {""} else {" (does not exist)"};
+                println!("{} [{}]{}", import_path.path.to_string_lossy(), import_path.origin, exists);
+            }
+            true
+        },
+        _ => false,
+    }
+}
+
+// The build options given on the command line, shared by the subcommands which build a program
+fn build_options_from_matches(matches: &ArgMatches, dump_flags: &str) -> CompileResult<BuildOptions>
+{
+    Ok(BuildOptions{
+        profile: build_profile(matches)?,
         dump_flags: dump_flags.into(),
-        target_machine: llvm_init()?,
+        target_machine: target_machine(matches)?,
         sources_directory: String::new(),
-        import_directories: matches.value_of("IMPORTS")
-            .map(|dirs| dirs.split(',').map(PathBuf::from).collect())
-            .unwrap_or_else(Vec::new),
-    };
+        build_directory: "build".into(),
+        import_directories: import_directories(matches),
+        emit_stack_usage: emit(matches, "stack-usage")?,
+        // Libraries built with --crate-type always get a C header for their @export functions
+        emit_c_header: emit(matches, "c-header")? || matches.value_of("CRATE_TYPE").map(|t| t != "bin").unwrap_or(false),
+        emit_ffi_json: emit(matches, "ffi-json")?,
+        emit_package: emit(matches, "package")?,
+        emit_wasm: emit(matches, "wasm")?,
+        eager_globals: matches.is_present("EAGER_GLOBALS"),
+        instrument_heap: instrument(matches, "heap"),
+        defines: defines(matches)?,
+    })
+}
+
+fn build_command(matches: &ArgMatches, dump_flags: &str) -> CompileResult<i32>
+{
+    if print_info(matches) {
+        return Ok(0);
+    }
+
+    let input_file = matches.value_of("INPUT_FILE").expect("No input file given");
+    let mut build_options = build_options_from_matches(matches, dump_flags)?;
 
-    let output_type = match matches.value_of("LIB") {
-        Some("static") => OutputType::StaticLib,
-        Some("shared") => OutputType::SharedLib,
+    let output_type = match (matches.value_of("CRATE_TYPE"), matches.value_of("LIB")) {
+        (Some("lib"), _) | (Some("staticlib"), _) | (None, Some("static")) => OutputType::StaticLib,
+        (Some("dylib"), _) | (None, Some("shared")) => OutputType::SharedLib,
         _ => OutputType::Binary,
     };
 
     let pkg = PackageData::single_file(&input_file, output_type)?;
-    pkg.build(&build_options)?;
+    pkg.build(&mut build_options)?;
     Ok(0)
 }
 
+// Build a single file in a temporary directory and run it, the exit code of the program is passed on
+fn run_command(matches: &ArgMatches, dump_flags: &str) -> CompileResult<i32>
+{
+    let input_file = matches.value_of("INPUT_FILE").expect("No input file given");
+    let build_directory = env::temp_dir().join(format!("menhir-run-{}", process::id()));
+    let mut build_options = build_options_from_matches(matches, dump_flags)?;
+    build_options.build_directory = build_directory.to_string_lossy().into_owned();
+
+    let pkg = PackageData::single_file(&input_file, OutputType::Binary)?;
+    if matches.is_present("JIT") {
+        if matches.is_present("ARGS") {
+            return Err(CompileError::Other("Arguments cannot be passed to a program run with --jit".into()));
+        }
+        // Like an exit status, only the lowest byte of the result of main is kept
+        let ret = pkg.run_jit(&mut build_options)?;
+        return Ok((ret & 0xFF) as i32);
+    }
+
+    let outputs = pkg.build(&mut build_options);
+    let status = outputs.and_then(|outputs| {
+        Command::new(&outputs[0])
+            .args(matches.values_of("ARGS").map(|args| args.collect::<Vec<_>>()).unwrap_or_else(Vec::new))
+            .status()
+            .map_err(|e| CompileError::Other(format!("Unable to run {}: {}", outputs[0].to_string_lossy(), e)))
+    });
+
+    let _ = fs::remove_dir_all(&build_directory);
+    let status = status?;
+    // Killed by a signal, use the same exit code as the shell would
+    Ok(status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(1))
+}
+
+// Run the @test functions of a file, or build and run every program in a directory, and compare their output with the expected output
+fn test_command(matches: &ArgMatches, dump_flags: &str) -> CompileResult<i32>
+{
+    let build_directory = env::temp_dir().join(format!("menhir-test-{}", process::id()));
+    let mut build_options = build_options_from_matches(matches, dump_flags)?;
+    build_options.build_directory = build_directory.to_string_lossy().into_owned();
+
+    let seed = match matches.value_of("SEED") {
+        Some(seed) => seed.parse().map_err(|_| CompileError::Other(format!("Invalid seed {}, expecting an unsigned integer", seed)))?,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() ^ d.subsec_nanos() as u64).unwrap_or(0),
+    };
+
+    let failed = match matches.value_of("INPUT_FILE") {
+        Some(input_file) => run_unit_tests(Path::new(input_file), &build_options, seed)?,
+        None => {
+            let examples_dir = matches.value_of("RUN_EXAMPLES").expect("No examples directory given");
+            run_examples(Path::new(examples_dir), &mut build_options, seed)?
+        },
+    };
+    Ok(if failed > 0 {1} else {0})
+}
+
+// Run a single file with the interpreter and as a compiled binary, and check that they agree
+fn selftest_command(matches: &ArgMatches, dump_flags: &str) -> CompileResult<i32>
+{
+    let input_file = matches.value_of("INPUT_FILE").expect("No input file given");
+    let build_directory = env::temp_dir().join(format!("menhir-selftest-{}", process::id()));
+    let mut build_options = build_options_from_matches(matches, dump_flags)?;
+    build_options.build_directory = build_directory.to_string_lossy().into_owned();
+
+    let result = selftest(Path::new(input_file), &mut build_options);
+    let _ = fs::remove_dir_all(&build_directory);
+    let result = result?;
+    println!("{}", result);
+    Ok(match result {
+        SelfTestResult::Disagree{..} => 1,
+        _ => 0,
+    })
+}
+
+// Reduce a program which fails to compile, and print the reduced program or write it to the output file
+fn reduce_command(matches: &ArgMatches) -> CompileResult<i32>
+{
+    let input_file = matches.value_of("INPUT_FILE").expect("No input file given");
+    let check = matches.value_of("CHECK").expect("No check given");
+    let mut code = String::new();
+    File::open(input_file)?.read_to_string(&mut code)?;
+
+    let target = Target::host();
+    let reduced = reduce(Path::new(input_file), &code, check, &target)?;
+    match matches.value_of("OUTPUT_FILE") {
+        Some(output_file) => {
+            File::create(output_file)?.write_all(reduced.as_bytes())?;
+            println!("Reduced {} from {} to {} lines", input_file, code.lines().count(), reduced.lines().count());
+        },
+        None => print!("{}", reduced),
+    }
+    Ok(0)
+}
+
+// Parse and type check a file or package, without generating bytecode or using LLVM
+fn check_command(matches: &ArgMatches) -> CompileResult<i32>
+{
+    let target = matches.value_of("TARGET").map(Target::from_triplet).unwrap_or_else(Target::host);
+    let (pkg, sources_directory) = match matches.value_of("INPUT_FILE") {
+        Some(input_file) => (PackageData::single_file(input_file, OutputType::Binary)?, ""),
+        None => (PackageData::load(matches.value_of("PACKAGE_TOML").unwrap_or("./package.toml"))?, "src"),
+    };
+    pkg.check(sources_directory, &import_directories(matches), &target)?;
+    Ok(0)
+}
+
+// Run a single file with the bytecode interpreter, so no LLVM toolchain is needed
+fn interp_command(matches: &ArgMatches, dump_flags: &str) -> CompileResult<i32>
+{
+    let input_file = matches.value_of("INPUT_FILE").expect("No input file given");
+    let target = Target::host();
+    let pkg = check_package(Path::new(input_file), &target, &HashMap::new())?;
+    if dump_flags.contains("match-lowering") {
+        print_match_lowering(&pkg, &target);
+    }
+
+    let mut bc_mod = compile_to_byte_code(&pkg, &target, matches.is_present("EAGER_GLOBALS"), log_level(matches).unwrap_or(LogLevel::Debug))?;
+    if dump_flags.contains("bytecode") || dump_flags.contains("all") {
+        println!("bytecode:");
+        println!("------\n");
+        println!("{}", bc_mod);
+        println!("------\n");
+    }
+
+    optimize_module(&mut bc_mod, OptimizationLevel::Minimal, DEFAULT_INLINE_THRESHOLD);
+    let ret = run_module(&bc_mod, target.int_size)?;
+    Ok(ret as i32)
+}
+
+// Evaluate declarations and expressions typed on stdin, and print their values
+fn repl_command() -> CompileResult<i32>
+{
+    run_repl(llvm_init(None)?)?;
+    Ok(0)
+}
 
 fn build_package_command(matches: &ArgMatches, dump_flags: &str) -> CompileResult<i32>
 {
+    if print_info(matches) {
+        return Ok(0);
+    }
+
     let package_toml = if let Some(toml) = matches.value_of("PACKAGE_TOML") {
         toml
     } else {
@@ -80,16 +377,50 @@ fn build_package_command(matches: &ArgMatches, dump_flags: &str) -> CompileResul
     };
 
     let pkg = PackageData::load(package_toml)?;
-    let build_options = BuildOptions{
-        optimize: matches.is_present("OPTIMIZE"),
-        dump_flags: dump_flags.into(),
-        target_machine: llvm_init()?,
-        sources_directory: "src".into(),
-        import_directories: matches.value_of("IMPORTS")
-            .map(|dirs| dirs.split(',').map(PathBuf::from).collect())
-            .unwrap_or_else(Vec::new),
+    let mut build_options = build_options_from_matches(matches, dump_flags)?;
+    build_options.sources_directory = "src".into();
+    pkg.build(&mut build_options)?;
+    Ok(0)
+}
+
+fn new_command(matches: &ArgMatches) -> CompileResult<i32>
+{
+    let dir = Path::new(matches.value_of("NAME").expect("No name given"));
+    new_project(dir)?;
+    println!("Created package {}", dir.to_string_lossy());
+    Ok(0)
+}
+
+// Create a package in the current directory, named after the directory unless a name is given
+fn init_command(matches: &ArgMatches) -> CompileResult<i32>
+{
+    let dir = env::current_dir()?;
+    let name = match matches.value_of("NAME") {
+        Some(name) => name.to_string(),
+        None => dir.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| CompileError::Other("Cannot determine the package name of the current directory".into()))?,
     };
-    pkg.build(&build_options)?;
+    init_project(&dir, &name)?;
+    println!("Created package {}", name);
+    Ok(0)
+}
+
+fn dump_command(matches: &ArgMatches) -> CompileResult<i32>
+{
+    if matches.is_present("GRAMMAR") {
+        print!("{}", grammar_to_ebnf());
+    }
+    Ok(0)
+}
+
+fn emit_syntax_command(matches: &ArgMatches) -> CompileResult<i32>
+{
+    let format = match matches.value_of("FORMAT") {
+        Some("vim") => SyntaxFormat::Vim,
+        _ => SyntaxFormat::TextMate,
+    };
+    print!("{}", syntax_definition(format));
     Ok(0)
 }
 
@@ -102,6 +433,105 @@ fn exports_command(matches: &ArgMatches) -> CompileResult<i32>
     Ok(0)
 }
 
+fn check_input_file(matches: &ArgMatches) -> CompileResult<Package>
+{
+    let input_file = matches.value_of("INPUT_FILE").expect("No input file given");
+    let target_machine = llvm_init(None)?;
+    check_package(Path::new(input_file), &target_machine.target, &HashMap::new())
+}
+
+fn analyze_command(matches: &ArgMatches) -> CompileResult<i32>
+{
+    let pkg = check_input_file(matches)?;
+    if matches.is_present("RECURSION") {
+        let call_graph = CallGraph::new(&pkg);
+        call_graph.print_recursion_report(&format!("{}::main", pkg.name));
+    }
+    Ok(0)
+}
+
+fn ide_position(matches: &ArgMatches) -> CompileResult<Pos>
+{
+    parse_position(matches.value_of("POSITION").expect("No position given"))
+}
+
+fn ide_command(ide_matches: &ArgMatches) -> CompileResult<i32>
+{
+    let target_machine = llvm_init(None)?;
+    let mut workspace = Workspace::new(target_machine.target.clone());
+    if !ide_matches.is_present("NO_CHECK_CACHE") {
+        workspace.set_check_cache_dir(check_cache_directory());
+    }
+    let (subcommand, matches) = match ide_matches.subcommand() {
+        (subcommand, Some(matches)) => (subcommand, matches),
+        _ => {
+            println!("{}", ide_matches.usage());
+            return Ok(1);
+        }
+    };
+
+    let input_file = Path::new(matches.value_of("INPUT_FILE").expect("No input file given"));
+    if ide_matches.is_present("STDIN") {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        workspace.set_buffer(input_file, contents);
+    }
+
+    match subcommand
+    {
+        "check" => {
+            let diagnostics: Vec<String> = workspace.diagnostics(input_file).iter()
+                .map(|e| match *e {
+                    CompileError::Parse(ref ed) |
+                    CompileError::Type(ref ed) |
+                    CompileError::UnknownName(ref ed, _) => format!(r#"  {{"span": {}, "message": {}}}"#, json_span(&ed.span), json_string(&ed.msg)),
+                    _ => format!(r#"  {{"span": null, "message": {}}}"#, json_string(e.to_string().trim_end())),
+                })
+                .collect();
+            print!("[\n{}\n]\n", diagnostics.join(",\n"));
+        },
+
+        "symbols" => {
+            print!("{}", workspace.symbol_index(input_file)?.to_json());
+        },
+
+        "references" => {
+            let pos = ide_position(matches)?;
+            let file = input_file.to_string_lossy();
+            let index = workspace.symbol_index(input_file)?;
+            let occ = index.occurrence_at(&file, pos)
+                .ok_or_else(|| CompileError::Other(format!("No symbol found at {}:{}", file, pos)))?;
+            let spans: Vec<String> = index.references(occ).iter().map(|o| format!("  {}", json_span(&o.span))).collect();
+            print!("[\n{}\n]\n", spans.join(",\n"));
+        },
+
+        "rename" => {
+            let new_name = matches.value_of("NEW_NAME").expect("No new name given");
+            let edits: Vec<String> = workspace.rename(input_file, ide_position(matches)?, new_name)?
+                .iter()
+                .map(|e| format!(r#"  {{"span": {}, "new_text": {}}}"#, json_span(&e.span), json_string(&e.new_text)))
+                .collect();
+            print!("[\n{}\n]\n", edits.join(",\n"));
+        },
+
+        "complete" => {
+            let completions = workspace.complete(input_file, ide_position(matches)?)?;
+            print!("{}", completions_to_json(&completions));
+        },
+
+        "hover" => {
+            match workspace.hover(input_file, ide_position(matches)?)? {
+                Some(h) => print!("{}", h.to_json()),
+                None => println!("null"),
+            }
+        },
+
+        _ => unreachable!(),
+    }
+
+    Ok(0)
+}
+
 fn run() -> CompileResult<i32>
 {
     let app = clap_app!(cobrac =>
@@ -109,45 +539,231 @@ fn run() -> CompileResult<i32>
         (author: "Joris Guisson <joris.guisson@gmail.com>")
         (about: "Nomad language compiler")
         (@arg DUMP: -d --dump +takes_value "Dump internal compiler state for debug purposes. Argument can be all, ast, bytecode or ir. A comma separated list of these values is also supported.")
+        (@arg PRINT_MATCH_LOWERING: --("print-match-lowering") "Print for every match expression whether it is lowered to a jump table or to a chain of comparisons, and why")
         (@arg TARGET_TRIPLET: -t --triplet "Print the default target triplet of the current system, and exit")
+        (@arg ERROR_LIMIT: --("error-limit") +takes_value "Maximum number of errors to print, 20 by default, 0 prints all errors")
+        (@arg COLOR: --color +takes_value possible_value[auto always never] "Use colors in error messages, auto (the default) only uses them when printing to a terminal")
         (@subcommand build =>
             (about: "Build a menhir file")
-            (@arg INPUT_FILE: +required "File to build")
+            (@arg INPUT_FILE: required_unless("PRINT") "File to build")
             (@arg OUTPUT_FILE: -o --output +takes_value "Name of binary to create (by default input file without the extensions)")
             (@arg OPTIMIZE: -O --optimize "Optimize the code")
+            (@arg DEBUG_INFO: -g --("debug-info") "Generate DWARF debug information, so the program can be debugged with gdb or lldb")
+            (@arg NO_INTERN_STRINGS: --("no-intern-strings") "Emit a separate constant for every string literal, instead of sharing one constant between identical literals")
+            (@arg RELEASE: --release "Build using the release profile")
+            (@arg PROFILE: --profile +takes_value "Build profile to use, debug (the default) or release")
+            (@arg LOG_LEVEL: --("log-level") +takes_value possible_value[debug info warn error] "Leave out calls to @debug, @info, @warn and @error below this level, by default debug for the debug profile and warn for the release profile")
+            (@arg INLINE_THRESHOLD: --("inline-threshold") +takes_value "Inline functions of at most this many bytecode instructions when optimizing, 20 by default")
+            (@arg DEFINE: -D --define +takes_value +multiple number_of_values(1) "Define a value for @build_info as key=value, or override one of the defaults: version, git_hash, build_date, profile and target")
             (@arg IMPORTS: -I --imports +takes_value "Directory to look for imports, use a comma separated list for more then one.")
+            (@arg PRINT: --print +takes_value possible_value("import-paths") "Print information about the build and exit. Argument can be import-paths, which lists the directories searched for libraries in order.")
+            (@arg EMIT: --emit +takes_value "Emit extra information about the build in the build directory. Argument can be stack-usage, c-header, ffi-json, package (a precompiled library package, for libraries) or wasm (a WebAssembly module and a JS loader, instead of a binary). A comma separated list of these values is also supported.")
+            (@arg EAGER_GLOBALS: --("eager-globals") "Initialize globals which are not constant before main, instead of on first use")
             (@arg LIB: -l --lib +takes_value possible_value[static shared] "Create a library, type of library must be pass")
+            (@arg CRATE_TYPE: --("crate-type") +takes_value possible_value[bin lib staticlib dylib] conflicts_with[LIB] "Type of output: bin (an executable, the default), staticlib (a .a archive), dylib (a .so shared object) or lib (the default library type, a static library). Libraries don't need a main function and get a C header for their @export functions.")
+            (@arg TARGET: --target +takes_value "Target triplet to cross compile for, for example aarch64-linux-gnu. Linking uses the <triplet>-gcc toolchain.")
+            (@arg INSTRUMENT: --instrument +takes_value possible_value[heap] "Instrument the program. Argument can be heap, which counts allocations and frees per allocation site, and prints the live allocations and peak usage of every site when the program exits.")
+        )
+        (@subcommand check =>
+            (about: "Parse and type check a menhir file or package, without generating any code")
+            (@arg INPUT_FILE: conflicts_with[PACKAGE_TOML] "File to check, by default the package in the current directory is checked")
+            (@arg PACKAGE_TOML: -p --package +takes_value "Specify the package.toml file to check")
+            (@arg IMPORTS: -I --imports +takes_value "Directory to look for imports, use a comma separated list for more then one.")
+            (@arg TARGET: --target +takes_value "Target triplet to check for, for example aarch64-linux-gnu. This determines the size of int and uint.")
+        )
+        (@subcommand run =>
+            (about: "Build a menhir file and run it")
+            (@arg INPUT_FILE: +required "File to run")
+            (@arg OPTIMIZE: -O --optimize "Optimize the code")
+            (@arg DEBUG_INFO: -g --("debug-info") "Generate DWARF debug information, so the program can be debugged with gdb or lldb")
+            (@arg NO_INTERN_STRINGS: --("no-intern-strings") "Emit a separate constant for every string literal, instead of sharing one constant between identical literals")
+            (@arg RELEASE: --release "Build using the release profile")
+            (@arg PROFILE: --profile +takes_value "Build profile to use, debug (the default) or release")
+            (@arg LOG_LEVEL: --("log-level") +takes_value possible_value[debug info warn error] "Leave out calls to @debug, @info, @warn and @error below this level, by default debug for the debug profile and warn for the release profile")
+            (@arg INLINE_THRESHOLD: --("inline-threshold") +takes_value "Inline functions of at most this many bytecode instructions when optimizing, 20 by default")
+            (@arg DEFINE: -D --define +takes_value +multiple number_of_values(1) "Define a value for @build_info as key=value, or override one of the defaults: version, git_hash, build_date, profile and target")
+            (@arg IMPORTS: -I --imports +takes_value "Directory to look for imports, use a comma separated list for more then one.")
+            (@arg EAGER_GLOBALS: --("eager-globals") "Initialize globals which are not constant before main, instead of on first use")
+            (@arg INSTRUMENT: --instrument +takes_value possible_value[heap] "Instrument the program. Argument can be heap, which counts allocations and frees per allocation site, and prints the live allocations and peak usage of every site when the program exits.")
+            (@arg JIT: --jit "Run the program in the compiler process with the LLVM JIT, instead of linking a binary, so no linker is needed")
+            (@arg ARGS: +multiple +last "Arguments passed to the program, after --")
+        )
+        (@subcommand test =>
+            (about: "Run the tests of menhir programs")
+            (@arg INPUT_FILE: required_unless("RUN_EXAMPLES") conflicts_with[RUN_EXAMPLES] "File with @test functions to run, every test is built into its own binary and runs in its own process")
+            (@arg RUN_EXAMPLES: --("run-examples") +takes_value "Build and run every program in a directory, and compare its output with the .expected file next to it")
+            (@arg SEED: --seed +takes_value "Seed for @random in the programs, by default a new seed is picked for every run")
+            (@arg OPTIMIZE: -O --optimize "Optimize the code")
+            (@arg RELEASE: --release "Build using the release profile")
+            (@arg PROFILE: --profile +takes_value "Build profile to use, debug (the default) or release")
+            (@arg IMPORTS: -I --imports +takes_value "Directory to look for imports, use a comma separated list for more then one.")
+        )
+        (@subcommand selftest =>
+            (about: "Run a program with the interpreter and as a compiled binary, and check that the results are the same")
+            (@arg INPUT_FILE: +required "File to test")
+            (@arg OPTIMIZE: -O --optimize "Optimize the code")
+            (@arg RELEASE: --release "Build using the release profile")
+            (@arg PROFILE: --profile +takes_value "Build profile to use, debug (the default) or release")
+            (@arg IMPORTS: -I --imports +takes_value "Directory to look for imports, use a comma separated list for more then one.")
+            (@arg EAGER_GLOBALS: --("eager-globals") "Initialize globals which are not constant before main, instead of on first use")
+        )
+        (@subcommand reduce =>
+            (about: "Remove declarations and statements from a program which fails to compile, for as long as it keeps failing with the same error")
+            (@arg INPUT_FILE: +required "File to reduce")
+            (@arg CHECK: --check +takes_value +required "Text the compile error must contain, use ICE to match any internal compiler error")
+            (@arg OUTPUT_FILE: -o --output +takes_value "File to write the reduced program to (by default it is printed)")
+        )
+        (@subcommand interp =>
+            (about: "Run a menhir file with the bytecode interpreter, without generating native code")
+            (@arg INPUT_FILE: +required "File to run")
+            (@arg EAGER_GLOBALS: --("eager-globals") "Initialize globals which are not constant before main, instead of on first use")
+            (@arg LOG_LEVEL: --("log-level") +takes_value possible_value[debug info warn error] "Leave out calls to @debug, @info, @warn and @error below this level, debug by default")
+        )
+        (@subcommand repl =>
+            (about: "Evaluate menhir expressions interactively. Declarations are kept for the rest of the session, the value of an expression is printed with its type. A line ending with a colon continues until an empty line.")
         )
         (@subcommand buildpkg =>
             (about: "Build a menhir package.")
             (@arg PACKAGE_TOML: -p --package +takes_value "Specify the package.toml file. If not specified, menhir will look in the current directory for one.")
             (@arg OPTIMIZE: -O --optimize "Optimize the code")
+            (@arg DEBUG_INFO: -g --("debug-info") "Generate DWARF debug information, so the program can be debugged with gdb or lldb")
+            (@arg NO_INTERN_STRINGS: --("no-intern-strings") "Emit a separate constant for every string literal, instead of sharing one constant between identical literals")
+            (@arg RELEASE: --release "Build using the release profile")
+            (@arg PROFILE: --profile +takes_value "Build profile to use, debug (the default) or release")
+            (@arg LOG_LEVEL: --("log-level") +takes_value possible_value[debug info warn error] "Leave out calls to @debug, @info, @warn and @error below this level, by default debug for the debug profile and warn for the release profile")
+            (@arg INLINE_THRESHOLD: --("inline-threshold") +takes_value "Inline functions of at most this many bytecode instructions when optimizing, 20 by default")
+            (@arg DEFINE: -D --define +takes_value +multiple number_of_values(1) "Define a value for @build_info as key=value, or override one of the defaults: version, git_hash, build_date, profile and target")
             (@arg IMPORTS: -I --imports +takes_value "Directory to look for imports, use a comma separated list for more then one.")
+            (@arg PRINT: --print +takes_value possible_value("import-paths") "Print information about the build and exit. Argument can be import-paths, which lists the directories searched for libraries in order.")
+            (@arg EMIT: --emit +takes_value "Emit extra information about the build in the build directory. Argument can be stack-usage, c-header, ffi-json, package (a precompiled library package, for libraries) or wasm (a WebAssembly module and a JS loader, instead of a binary). A comma separated list of these values is also supported.")
+            (@arg EAGER_GLOBALS: --("eager-globals") "Initialize globals which are not constant before main, instead of on first use")
+            (@arg TARGET: --target +takes_value "Target triplet to cross compile for, for example aarch64-linux-gnu. Linking uses the <triplet>-gcc toolchain.")
+            (@arg INSTRUMENT: --instrument +takes_value possible_value[heap] "Instrument the program. Argument can be heap, which counts allocations and frees per allocation site, and prints the live allocations and peak usage of every site when the program exits.")
+        )
+        (@subcommand new =>
+            (about: "Create a new package, with a manifest, a hello world program and a .gitignore")
+            (@arg NAME: +required "Name of the package, a directory with this name is created")
+        )
+        (@subcommand init =>
+            (about: "Create a new package in the current directory")
+            (@arg NAME: --name +takes_value "Name of the package, by default the name of the directory")
+        )
+        (@subcommand analyze =>
+            (about: "Analyze a menhir file without building it")
+            (@arg INPUT_FILE: +required "File to analyze")
+            (@arg RECURSION: --recursion "Print the recursive call cycles and the maximum static call depth")
+        )
+        (@subcommand dump =>
+            (about: "Print information about the language")
+            (@arg GRAMMAR: --grammar +required "Print the grammar in EBNF")
         )
         (@subcommand exports =>
             (about: "List the exported symbols in an exports file")
             (@arg EXPORTS_FILE: +required "Exports file")
         )
+        (@subcommand ide =>
+            (about: "Answer queries about the source code, for editor integration")
+            (@arg STDIN: --stdin "Read the contents of the input file from stdin, for files which are not saved yet")
+            (@arg NO_CHECK_CACHE: --("no-check-cache") "Type check every module, instead of using the modules checked by a previous query")
+            (@subcommand check =>
+                (about: "Print the errors in a file as JSON")
+                (@arg INPUT_FILE: +required "File to check")
+            )
+            (@subcommand symbols =>
+                (about: "Print every identifier with its kind, type and definition as JSON")
+                (@arg INPUT_FILE: +required "File to index")
+            )
+            (@subcommand references =>
+                (about: "Print all references to the symbol at a position as JSON")
+                (@arg INPUT_FILE: +required "File containing the symbol")
+                (@arg POSITION: +required "Position of the symbol, in the line:offset format")
+            )
+            (@subcommand complete =>
+                (about: "Print the possible completions of the identifier at a position as JSON")
+                (@arg INPUT_FILE: +required "File containing the identifier")
+                (@arg POSITION: +required "Position of the cursor, in the line:offset format")
+            )
+            (@subcommand hover =>
+                (about: "Print the type of the expression at a position as JSON")
+                (@arg INPUT_FILE: +required "File containing the expression")
+                (@arg POSITION: +required "Position of the expression, in the line:offset format")
+            )
+            (@subcommand rename =>
+                (about: "Print the edits needed to rename the symbol at a position as JSON")
+                (@arg INPUT_FILE: +required "File containing the symbol")
+                (@arg POSITION: +required "Position of the symbol, in the line:offset format")
+                (@arg NEW_NAME: +required "New name of the symbol")
+            )
+        )
     );
 
+    // clap_app! only accepts identifiers as subcommand names
+    let app = app.subcommand(SubCommand::with_name("emit-syntax")
+        .about("Print syntax highlighting definitions for editors")
+        .arg(Arg::with_name("FORMAT")
+            .long("format")
+            .takes_value(true)
+            .required(true)
+            .possible_values(&["tmlanguage", "vim"])
+            .help("Format of the definitions, a TextMate grammar or a vim syntax file")));
+
     let matches = app.get_matches();
-    let dump_flags = matches.value_of("DUMP").unwrap_or("");
+    let mut dump_flags = matches.value_of("DUMP").unwrap_or("").to_string();
+    if matches.is_present("PRINT_MATCH_LOWERING") {
+        dump_flags.push_str(",match-lowering");
+    }
+    let dump_flags = &dump_flags[..];
+    set_color_mode(matches.value_of("COLOR").and_then(ColorMode::from_name).unwrap_or(ColorMode::Auto));
+    let error_limit = match matches.value_of("ERROR_LIMIT") {
+        Some(limit) => limit.parse::<usize>().map_err(|_| CompileError::Other(format!("Invalid error limit {}", limit)))?,
+        None => DEFAULT_ERROR_LIMIT,
+    };
 
-    if matches.is_present("TARGET_TRIPLET") {
-        let target_machine = llvm_init()?;
+    let result = if matches.is_present("TARGET_TRIPLET") {
+        let target_machine = llvm_init(None)?;
         print!("{}", target_machine.target.triplet);
         Ok(0)
     } else if let Some(matches) = matches.subcommand_matches("build") {
         build_command(matches, dump_flags)
+    } else if let Some(matches) = matches.subcommand_matches("check") {
+        check_command(matches)
+    } else if let Some(matches) = matches.subcommand_matches("run") {
+        run_command(matches, dump_flags)
+    } else if let Some(matches) = matches.subcommand_matches("test") {
+        test_command(matches, dump_flags)
+    } else if let Some(matches) = matches.subcommand_matches("selftest") {
+        selftest_command(matches, dump_flags)
+    } else if let Some(matches) = matches.subcommand_matches("reduce") {
+        reduce_command(matches)
+    } else if let Some(matches) = matches.subcommand_matches("interp") {
+        interp_command(matches, dump_flags)
+    } else if matches.subcommand_matches("repl").is_some() {
+        repl_command()
     } else if let Some(matches) = matches.subcommand_matches("buildpkg") {
         build_package_command(matches, dump_flags)
+    } else if let Some(matches) = matches.subcommand_matches("new") {
+        new_command(matches)
+    } else if let Some(matches) = matches.subcommand_matches("init") {
+        init_command(matches)
+    } else if let Some(matches) = matches.subcommand_matches("analyze") {
+        analyze_command(matches)
+    } else if let Some(matches) = matches.subcommand_matches("dump") {
+        dump_command(matches)
+    } else if let Some(matches) = matches.subcommand_matches("emit-syntax") {
+        emit_syntax_command(matches)
     } else if let Some(matches) = matches.subcommand_matches("exports") {
         exports_command(matches)
+    } else if let Some(matches) = matches.subcommand_matches("ide") {
+        ide_command(matches)
     } else {
         println!("{}", matches.usage());
         Ok(1)
-    }
+    };
 
+    result.or_else(|e| {
+        e.print_limited(error_limit);
+        Ok(-1)
+    })
 }
 
 fn main()
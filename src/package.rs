@@ -1,21 +1,44 @@
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::io::Read;
-use std::collections::HashMap;
-use std::path::Path;
+use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
 use either::Either;
 
-use ast::{Module, Import, ImportMap, TreePrinter, prefix};
+use ast::{Module, Import, ImportMap, ImportName, Expression, AssignTarget, Literal, TreePrinter, global_binding, prefix};
 use llvmbackend::{LinkerFlags, OutputType};
-use compileerror::{CompileResult, CompileError, type_error};
+use compileerror::{CompileResult, CompileError, Diagnostics, type_error_result, unknown_name, register_source};
 use exportlibrary::ExportLibrary;
-use parser::parse_file;
+use parser::{parse_file, parse_buffer};
 use target::Target;
 use typechecker::type_check_module;
 use span::Span;
+use stdlib::{parse_builtin_module, builtin_module_source};
+use query::{Queries, CheckedModule, hash_input, type_check_input_hash, INITIAL_HASH};
 
 type MissingImportsMap = HashMap<String, Span>;
 
+// Globals of a module which get assigned by one of its @init functions
+fn globals_assigned_by_init(module: &Module) -> Vec<String>
+{
+    let mut assigned = Vec::new();
+    for func in module.functions.values().filter(|f| f.init) {
+        let _ = func.expression.visit(&mut |e: &Expression| -> CompileResult<()> {
+            if let Expression::Assign(ref a) = *e {
+                if let AssignTarget::Var(ref nr) = a.left {
+                    if module.globals.contains_key(&nr.name) {
+                        assigned.push(nr.name.clone());
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+    assigned
+}
+
 pub struct ImportData
 {
     pub imports: HashMap<String, Rc<Import>>,
@@ -39,7 +62,8 @@ impl ImportData
         None
     }
 
-    fn resolve_module_imports(&self, module: &Module) -> Either<ImportMap, MissingImportsMap>
+    // Modules of a package can import each other without the package name in front
+    fn resolve_module_imports(&self, module: &Module, package: &str) -> Either<ImportMap, MissingImportsMap>
     {
         let mut missing = MissingImportsMap::new();
         let mut imports = ImportMap::new();
@@ -50,7 +74,9 @@ impl ImportData
                 continue;
             }
 
-            if let Some(i) = self.find_import(&import) {
+            let found = self.find_import(&import)
+                .or_else(|| self.find_import(&format!("{}::{}", package, import)));
+            if let Some(i) = found {
                 imports.insert(import, i.clone());
             } else {
                 missing.insert(import, import_name.span.clone());
@@ -71,6 +97,12 @@ pub struct Package
     pub modules: HashMap<String, Module>,
     pub import_data: ImportData,
     pub linker_flags: LinkerFlags,
+    // Unsaved editor buffers, which override the contents of files on disk
+    pub source_buffers: HashMap<PathBuf, String>,
+    // Results of earlier parses and type checks, which are reused for the files and modules which didn't change.
+    // The hashes of the sources are only kept when it is set.
+    pub queries: Option<Rc<RefCell<Queries>>>,
+    pub source_hashes: HashMap<String, u64>,
 }
 
 impl Package
@@ -85,25 +117,34 @@ impl Package
                 libraries: Vec::new(),
             },
             linker_flags: LinkerFlags::default(),
+            source_buffers: HashMap::new(),
+            queries: None,
+            source_hashes: HashMap::new(),
         }
     }
 
-    pub fn add_library<R: Read>(&mut self, input: &mut R, dep: &str, deps_dir: &str, target_triplet: &str) -> Result<(), String>
+    // Add a library, lib_dir is the directory containing the library file
+    pub fn add_library<R: Read>(&mut self, input: &mut R, dep: &str, lib_dir: &str) -> Result<(), String>
     {
         let export_library = ExportLibrary::load(input)?;
+        for lib in &self.import_data.libraries {
+            export_library.check_compatibility(lib)?;
+            lib.check_compatibility(&export_library)?;
+        }
+
         match export_library.output_type {
             OutputType::StaticLib => {
-                let lib_path = format!("{}/{}/{}/lib{}.a", deps_dir, target_triplet, dep, dep);
+                let lib_path = format!("{}/lib{}.a", lib_dir, dep);
                 self.linker_flags.linker_static_libs.push(lib_path);
             }
 
             OutputType::SharedLib => {
-                let lib_path = format!("{}/{}/{}/", deps_dir, target_triplet, dep);
+                let lib_path = format!("{}/", lib_dir);
                 self.linker_flags.linker_paths.push(lib_path);
                 self.linker_flags.linker_shared_libs.push(dep.into());
             }
 
-            OutputType::Binary => {
+            OutputType::Binary | OutputType::Wasm => {
                 return Err(format!("Cannot add a binary as a library"));
             }
         }
@@ -114,27 +155,98 @@ impl Package
 
     fn parse_file_tree(&mut self, dir: &Path, namespace: &str, target: &Target) -> CompileResult<()>
     {
+        // Parse errors in one file don't stop the others from being parsed
+        let mut diagnostics = Diagnostics::new();
         for entry in dir.read_dir()? {
             if let Ok(entry) = entry {
                 let path = entry.path();
                 if path.is_dir() {
                     let sub_ns = format!("{}::{}", namespace, path.file_stem().expect("Path must have a stem").to_string_lossy());
-                    self.parse_file_tree(&path, &sub_ns, target)?;
+                    diagnostics.check(self.parse_file_tree(&path, &sub_ns, target));
                 } else if path.extension() == Some(OsStr::new("mhr")) {
                     let sub_ns = format!("{}::{}", namespace, path.file_stem().expect("Path must have a stem").to_string_lossy());
-                    let module = parse_file(&path, &sub_ns, target)?;
-                    self.modules.insert(sub_ns, module);
+                    if let Some(module) = diagnostics.check(self.parse_source_file(&path, &sub_ns, target)) {
+                        self.modules.insert(sub_ns, module);
+                    }
                 }
             }
         }
 
-        Ok(())
+        diagnostics.into_result()
+    }
+
+    fn parse_source_file(&mut self, path: &Path, namespace: &str, target: &Target) -> CompileResult<Module>
+    {
+        if let Some(queries) = self.queries.clone() {
+            let code = match self.source_buffers.get(path) {
+                Some(code) => code.clone(),
+                None => {
+                    let mut code = String::new();
+                    File::open(path)?.read_to_string(&mut code)?;
+                    code
+                },
+            };
+
+            let hash = hash_input(INITIAL_HASH, &code);
+            self.source_hashes.insert(namespace.into(), hash);
+            let key = format!("{}:{}", path.to_string_lossy(), namespace);
+            let parsed = queries.borrow_mut().parse.get(&key, hash);
+            if let Some(module) = parsed {
+                // Errors found later on still need to show the source
+                register_source(&path.to_string_lossy(), &code);
+                return Ok(module);
+            }
+
+            let module = parse_buffer(&code, path, namespace, target)?;
+            queries.borrow_mut().parse.insert(&key, hash, module.clone());
+            return Ok(module);
+        }
+
+        match self.source_buffers.get(path) {
+            Some(code) => parse_buffer(code, path, namespace, target),
+            None => parse_file(path, namespace, target),
+        }
+    }
+
+    fn is_source_file(&self, path: &Path) -> bool
+    {
+        self.source_buffers.contains_key(path) || (path.exists() && path.is_file())
+    }
+
+    // The modules a single file imports are in the same directory, collections::map in collections/map.mhr.
+    // Their imports are found in the same way, modules which don't exist there can come from libraries or are built-in.
+    fn parse_imported_files(&mut self, dir: &Path, target: &Target) -> CompileResult<()>
+    {
+        let mut diagnostics = Diagnostics::new();
+        let mut seen = HashSet::new();
+        let mut todo: Vec<ImportName> = self.modules.values()
+            .flat_map(|m| m.import_names.iter().cloned())
+            .collect();
+
+        while let Some(import_name) = todo.pop() {
+            let name = import_name.to_namespace_string();
+            let path = dir.join(import_name.to_path());
+            if !seen.insert(name.clone()) || self.modules.contains_key(&name) || self.import_data.find_import(&name).is_some() || !self.is_source_file(&path) {
+                continue;
+            }
+
+            if let Some(module) = diagnostics.check(self.parse_source_file(&path, &name, target)) {
+                todo.extend(module.import_names.iter().cloned());
+                self.modules.insert(name, module);
+            }
+        }
+
+        diagnostics.into_result()
     }
 
     pub fn parse_files(&mut self, path: &Path, target: &Target) -> CompileResult<()>
     {
-        if path.exists() && path.is_file() {
-            self.modules.insert(self.name.clone(), parse_file(path, &self.name, target)?);
+        if self.is_source_file(path) {
+            let name = self.name.clone();
+            let module = self.parse_source_file(path, &name, target)?;
+            self.modules.insert(name, module);
+            let dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            self.parse_imported_files(&dir, target)?;
         } else {
             if !path.exists() || !path.is_dir() {
                 return Err(CompileError::Other(format!("Cannot find {}.mhr or the directory {}", self.name, self.name)))
@@ -146,23 +258,90 @@ impl Package
         Ok(())
     }
 
+    // Add constant string globals to every module, they have to be added after parsing and before type checking
+    pub fn add_constant_globals(&mut self, constants: &HashMap<String, String>)
+    {
+        let mut names: Vec<&String> = constants.keys().collect();
+        names.sort();
+        for module in self.modules.values_mut() {
+            for (name, value) in constants {
+                let init = Expression::Literal(Literal::String(Span::default(), value.clone()));
+                module.globals.insert(name.clone(), global_binding(name.clone(), init, false, Span::default()));
+            }
+
+            // A module checked with other values of the constants cannot come from an earlier type check
+            if let Some(hash) = self.source_hashes.get_mut(&module.name) {
+                for name in &names {
+                    *hash = hash_input(hash_input(*hash, name), &constants[*name]);
+                }
+            }
+        }
+    }
+
+    // Built-in modules which get imported, when the package and its libraries don't have a module with that name
+    fn add_builtin_modules(&mut self, target: &Target) -> CompileResult<()>
+    {
+        let mut todo: Vec<String> = self.modules.values()
+            .flat_map(|m| m.import_names.iter().map(|i| i.to_namespace_string()))
+            .collect();
+
+        while let Some(name) = todo.pop() {
+            let in_package = format!("{}::{}", self.name, name);
+            if self.modules.contains_key(&name) || self.modules.contains_key(&in_package) || self.import_data.find_import(&name).is_some() {
+                continue;
+            }
+
+            if let Some(module) = parse_builtin_module(&name, target) {
+                let module = module?;
+                match builtin_module_source(&name, target) {
+                    Some(code) if self.queries.is_some() => {
+                        self.source_hashes.insert(name.clone(), hash_input(INITIAL_HASH, code));
+                    },
+                    _ => (),
+                }
+                todo.extend(module.import_names.iter().map(|i| i.to_namespace_string()));
+                self.modules.insert(name, module);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn type_check(&mut self, target: &Target) -> CompileResult<()>
     {
+        self.add_builtin_modules(target)?;
         let mut count = 0;
         while count < self.modules.len() {
             let count_at_start = count;
             let mut all_missing_imports = MissingImportsMap::new();
+            let mut diagnostics = Diagnostics::new();
 
             for module in self.modules.values_mut() {
                 if module.type_checked {
                     continue;
                 }
 
-                match self.import_data.resolve_module_imports(module) {
+                match self.import_data.resolve_module_imports(module, &self.name) {
                     Either::Left(imports) => {
-                        type_check_module(module, target, &imports)?;
-                        self.import_data.imports.insert(module.name.clone(), Rc::new(module.get_exported_symbols(target)));
-                        count += 1;
+                        let input_hash = self.source_hashes.get(&module.name).map(|hash| type_check_input_hash(*hash, &imports));
+                        let checked = match (self.queries.as_ref(), input_hash) {
+                            (Some(queries), Some(hash)) => queries.borrow_mut().type_check.get(&module.name, hash),
+                            _ => None,
+                        };
+
+                        if let Some(checked) = checked {
+                            *module = checked.module;
+                            self.import_data.imports.insert(module.name.clone(), checked.import);
+                            count += 1;
+                        } else if diagnostics.check(type_check_module(module, target, &imports)).is_some() {
+                            let import = Rc::new(module.get_exported_symbols(target));
+                            if let (Some(queries), Some(hash)) = (self.queries.as_ref(), input_hash) {
+                                let checked = CheckedModule{module: module.clone(), import: import.clone()};
+                                queries.borrow_mut().type_check.insert(&module.name, hash, checked);
+                            }
+                            self.import_data.imports.insert(module.name.clone(), import);
+                            count += 1;
+                        }
                     }
 
                     Either::Right(mut missing) => {
@@ -171,15 +350,52 @@ impl Package
                 }
             }
 
+            // Modules importing a module with errors cannot be checked, so report the errors found so far
+            if !diagnostics.is_empty() {
+                return diagnostics.into_result();
+            }
+
             if count_at_start == count {
                 let errors = all_missing_imports
                     .iter()
-                    .map(|(name, span)| type_error(span, format!("Unknown import {}", name)))
+                    .map(|(name, span)| unknown_name(span, name, format!("Unknown import {}", name)))
                     .collect();
                 return Err(CompileError::Many(errors))
             }
         }
 
+        self.check_init_dependencies()
+    }
+
+    // The order in which the @init functions of different modules run is unspecified,
+    // so an @init function cannot depend on a global another module initializes in its @init functions
+    fn check_init_dependencies(&self) -> CompileResult<()>
+    {
+        let mut initialized_by = HashMap::new();
+        for module in self.modules.values() {
+            for global in globals_assigned_by_init(module) {
+                initialized_by.insert(global, module.name.clone());
+            }
+        }
+
+        for module in self.modules.values() {
+            for func in module.functions.values().filter(|f| f.init) {
+                func.expression.visit(&mut |e: &Expression| -> CompileResult<()> {
+                    if let Expression::NameRef(ref nr) = *e {
+                        match initialized_by.get(&nr.name) {
+                            Some(owner) if *owner != module.name => {
+                                return type_error_result(&nr.span,
+                                    format!("@init function {} uses global {}, which is initialized by an @init function of module {}, and the initialization order between modules is unspecified",
+                                        func.sig.name, nr.name, owner));
+                            },
+                            _ => (),
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -200,3 +416,56 @@ impl TreePrinter for Package
 
 
 
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use ast::IntSize;
+
+    #[test]
+    fn test_hierarchical_imports()
+    {
+        let target = Target::new(IntSize::I32, "");
+        let main = Path::new("app/main.mhr");
+        let mut pkg = Package::new("main");
+        pkg.source_buffers.insert(main.into(), r#"
+import collections::map
+
+fn main() -> int:
+    let m = new_map(3)
+    collections::map::insert(m, 4) + size(m)
+"#.into());
+
+        pkg.source_buffers.insert(PathBuf::from("app/collections/map.mhr"), r#"
+import collections::list
+
+pub struct Map:
+    keys: List
+
+pub fn new_map(n: int) -> Map: Map{List{n}}
+
+pub fn insert(m: Map, key: int) -> int: push(m.keys, key)
+
+pub fn size(m: Map) -> int: m.keys.len
+"#.into());
+
+        pkg.source_buffers.insert(PathBuf::from("app/collections/list.mhr"), r#"
+pub struct List:
+    len: int
+
+pub fn push(l: List, v: int) -> int: l.len + v
+"#.into());
+
+        pkg.parse_files(main, &target).expect("Parsing failed");
+        assert!(pkg.modules.contains_key("collections::map"));
+        assert!(pkg.modules.contains_key("collections::list"));
+        assert!(pkg.modules["collections::map"].functions.contains_key("collections::map::insert"));
+        assert!(pkg.type_check(&target).is_ok());
+
+        let mut missing = Package::new("main");
+        missing.source_buffers.insert(main.into(), "import collections::set\n\nfn main() -> int: 0\n".into());
+        missing.parse_files(main, &target).expect("Parsing failed");
+        assert!(missing.type_check(&target).is_err());
+    }
+}
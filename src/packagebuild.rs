@@ -1,26 +1,145 @@
 use std::fs::{File};
-use std::io::{Read};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use toml;
 
-use ast::{TreePrinter};
+use ast::{TreePrinter, LogLevel};
 use timer::{time_operation, time_operation_mut};
 use llvmbackend::TargetMachine;
-use bytecode::{compile_to_byte_code, optimize_module, OptimizationLevel};
-use llvmbackend::{CodeGenOptions, OutputType, llvm_code_generation, link};
+use bytecode::{ByteCodeModule, compile_to_byte_code, optimize_module, devirtualize_interface_calls, remove_bounds_checks, print_match_lowering, OptimizationLevel, DEFAULT_INLINE_THRESHOLD};
+use llvmbackend::{CodeGenOptions, Context, JIT, OutputType, llvm_code_generation, link, estimate_stack_usage, ffi_json};
 use compileerror::{CompileResult, CompileError};
 use exportlibrary::ExportLibrary;
 use package::Package;
+use target::Target;
+use importindex::suggest_imports;
+use cheader::generate_c_header;
+use artifact::{ArtifactMetadata, Dependency, parse_dependency, find_artifact, create_artifact};
+use buildinfo::{build_info, build_info_globals};
 
 
-pub struct BuildOptions
+#[derive(Debug, Clone)]
+pub struct BuildProfile
 {
+    pub name: String,
     pub optimize: bool,
+    pub overflow_checks: bool,
+    pub bounds_checks: bool,
+    pub leak_checks: bool,
+    pub log_level: LogLevel,
+    pub inline_threshold: usize,
+    pub debug_info: bool,
+    pub lto: bool,
+    pub intern_strings: bool,
+    pub merge_strings: bool,
+}
+
+impl BuildProfile
+{
+    pub fn debug() -> BuildProfile
+    {
+        BuildProfile{
+            name: "debug".into(),
+            optimize: false,
+            overflow_checks: true,
+            bounds_checks: true,
+            leak_checks: true,
+            log_level: LogLevel::Debug,
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+            debug_info: true,
+            lto: false,
+            intern_strings: true,
+            merge_strings: false,
+        }
+    }
+
+    pub fn release() -> BuildProfile
+    {
+        BuildProfile{
+            name: "release".into(),
+            optimize: true,
+            overflow_checks: false,
+            bounds_checks: true,
+            leak_checks: false,
+            log_level: LogLevel::Warn,
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+            debug_info: false,
+            lto: true,
+            intern_strings: true,
+            merge_strings: true,
+        }
+    }
+
+    pub fn from_name(name: &str) -> CompileResult<BuildProfile>
+    {
+        match name {
+            "debug" => Ok(BuildProfile::debug()),
+            "release" => Ok(BuildProfile::release()),
+            _ => Err(CompileError::Other(format!("Unknown build profile {}, expecting debug or release", name))),
+        }
+    }
+
+    fn apply(&mut self, settings: &ProfileSettings)
+    {
+        self.optimize = settings.optimize.unwrap_or(self.optimize);
+        self.overflow_checks = settings.overflow_checks.unwrap_or(self.overflow_checks);
+        self.bounds_checks = settings.bounds_checks.unwrap_or(self.bounds_checks);
+        self.leak_checks = settings.leak_checks.unwrap_or(self.leak_checks);
+        self.log_level = settings.log_level.unwrap_or(self.log_level);
+        self.inline_threshold = settings.inline_threshold.unwrap_or(self.inline_threshold);
+        self.debug_info = settings.debug_info.unwrap_or(self.debug_info);
+        self.lto = settings.lto.unwrap_or(self.lto);
+        self.intern_strings = settings.intern_strings.unwrap_or(self.intern_strings);
+        self.merge_strings = settings.merge_strings.unwrap_or(self.merge_strings);
+    }
+}
+
+impl fmt::Display for BuildProfile
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{} (optimize: {}, overflow checks: {}, bounds checks: {}, leak checks: {}, log level: {}, inline threshold: {}, debug info: {}, lto: {}, intern strings: {}, merge strings: {})",
+            self.name, self.optimize, self.overflow_checks, self.bounds_checks, self.leak_checks, self.log_level, self.inline_threshold, self.debug_info, self.lto, self.intern_strings, self.merge_strings)
+    }
+}
+
+// Profile settings in the package.toml, which override the defaults of the profile
+#[derive(Debug, Deserialize, Default)]
+pub struct ProfileSettings
+{
+    optimize: Option<bool>,
+    overflow_checks: Option<bool>,
+    bounds_checks: Option<bool>,
+    leak_checks: Option<bool>,
+    log_level: Option<LogLevel>,
+    inline_threshold: Option<usize>,
+    debug_info: Option<bool>,
+    lto: Option<bool>,
+    intern_strings: Option<bool>,
+    merge_strings: Option<bool>,
+}
+
+pub struct BuildOptions
+{
+    pub profile: BuildProfile,
     pub dump_flags: String,
     pub target_machine: TargetMachine,
     pub sources_directory: String,
+    pub build_directory: String,
     pub import_directories: Vec<PathBuf>,
+    pub emit_stack_usage: bool,
+    pub emit_c_header: bool,
+    pub emit_ffi_json: bool,
+    pub emit_package: bool,
+    pub emit_wasm: bool,
+    pub eager_globals: bool,
+    // Count heap allocations per site and report them when the program exits
+    pub instrument_heap: bool,
+    // Build info given with --define, which adds to or overrides the defaults
+    pub defines: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -33,6 +152,8 @@ pub struct PackageTarget
     depends: Option<Vec<String>>,
 }
 
+// Only the version is used to build, the other fields describe the package
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Default)]
 pub struct PackageDescription
 {
@@ -48,6 +169,8 @@ pub struct PackageData
 {
     package: PackageDescription,
     target: Vec<PackageTarget>,
+    #[serde(default)]
+    profile: HashMap<String, ProfileSettings>,
 }
 
 impl PackageData
@@ -85,114 +208,245 @@ impl PackageData
         Ok(package)
     }
 
-    pub fn build(&self, build_options: &BuildOptions) -> CompileResult<()>
+    // Build all targets, returns the paths of the created binaries and libraries
+    pub fn build(&self, build_options: &mut BuildOptions) -> CompileResult<Vec<PathBuf>>
     {
+        if let Some(settings) = self.profile.get(&build_options.profile.name) {
+            build_options.profile.apply(settings);
+        }
+
         println!("Compiling for {}", build_options.target_machine.target.triplet);
+        println!("Profile {}", build_options.profile);
+        let mut outputs = Vec::new();
         for t in &self.target {
-            time_operation(2, "Total build time", ||{
-                t.build(build_options)
+            let output = time_operation(2, "Total build time", ||{
+                t.build(&self.package.version, build_options)
             })?;
+            outputs.push(output);
         }
 
+        Ok(outputs)
+    }
+
+    // Run the first target with the JIT, returns the result of its main function
+    pub fn run_jit(&self, build_options: &mut BuildOptions) -> CompileResult<i64>
+    {
+        if let Some(settings) = self.profile.get(&build_options.profile.name) {
+            build_options.profile.apply(settings);
+        }
+
+        match self.target.first() {
+            Some(t) => t.run_jit(&self.package.version, build_options),
+            None => Err(CompileError::Other("Package has no targets".into())),
+        }
+    }
+
+    // Parse and type check all targets, without generating any code
+    pub fn check(&self, sources_directory: &str, import_directories: &[PathBuf], target: &Target) -> CompileResult<()>
+    {
+        let info = build_info(&self.package.version, "debug", &target.triplet, &HashMap::new());
+        for t in &self.target {
+            t.check(sources_directory, import_directories, target, &info)?;
+        }
         Ok(())
     }
 }
 
-fn output_file_name(name: &str, output_type: OutputType) -> String
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ImportPath
+{
+    pub path: PathBuf,
+    pub origin: &'static str,
+}
+
+fn env_paths(var: &str, origin: &'static str, paths: &mut Vec<ImportPath>)
+{
+    if let Some(value) = env::var_os(var) {
+        for path in env::split_paths(&value).filter(|p| !p.as_os_str().is_empty()) {
+            paths.push(ImportPath{path: path, origin: origin});
+        }
+    }
+}
+
+// The per user directory with the module cache and the check cache
+fn menhir_home() -> Option<PathBuf>
+{
+    if let Some(home) = env::var_os("MENHIR_HOME") {
+        Some(PathBuf::from(home))
+    } else if let Some(cache) = env::var_os("XDG_CACHE_HOME") {
+        Some(PathBuf::from(cache).join("menhir"))
+    } else {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join(".menhir"))
+    }
+}
+
+// The per user module cache, where installed libraries live
+pub fn module_cache_directory() -> Option<PathBuf>
+{
+    menhir_home().map(|home| home.join("lib"))
+}
+
+// Where interactive tools keep the type checked modules of the packages they check
+pub fn check_cache_directory() -> Option<PathBuf>
+{
+    menhir_home().map(|home| home.join("check"))
+}
+
+/*
+Directories searched for libraries, in order: the build directory, the directories passed with -I,
+the directories in MENHIR_PATH (and the older MENHIR_IMPORT_DIRS), and finally the module cache.
+*/
+pub fn import_search_path(import_directories: &[PathBuf]) -> Vec<ImportPath>
+{
+    let mut paths = vec![ImportPath{path: PathBuf::from("build"), origin: "build directory"}];
+    for dir in import_directories {
+        paths.push(ImportPath{path: dir.clone(), origin: "-I"});
+    }
+
+    env_paths("MENHIR_PATH", "MENHIR_PATH", &mut paths);
+    env_paths("MENHIR_IMPORT_DIRS", "MENHIR_IMPORT_DIRS", &mut paths);
+    if let Some(cache) = module_cache_directory() {
+        paths.push(ImportPath{path: cache, origin: "module cache"});
+    }
+    paths
+}
+
+pub fn output_file_name(name: &str, output_type: OutputType) -> String
 {
     match output_type {
         OutputType::Binary => name.into(),
         OutputType::StaticLib => format!("lib{}.a", name),
         OutputType::SharedLib => format!("lib{}.so", name),
+        OutputType::Wasm => format!("{}.wasm", name),
     }
 }
 
 impl PackageTarget
 {
-    fn find_dependency_in_path(&self, dep: &str, deps_dir: &str, target_triplet: &str, pkg: &mut Package) -> CompileResult<bool>
+    fn find_dependency_in_path(&self, dep: &Dependency, deps_dir: &Path, target_triplet: &str, pkg: &mut Package) -> CompileResult<bool>
     {
-        let path = format!("{}/{}/{}/{}.mhr.exports", deps_dir, target_triplet, dep, dep);
-        if let Ok(mut file) = File::open(&path) {
-            if let Ok(_) = pkg.add_library(&mut file, dep, deps_dir, target_triplet) {
-                Ok(true)
-            } else {
-                Ok(false)
+        // The output of a library build, only used when no specific version is requested
+        let lib_dir = deps_dir.join(target_triplet).join(&dep.name);
+        if dep.version.is_none() {
+            if let Ok(mut file) = File::open(lib_dir.join(format!("{}.mhr.exports", dep.name))) {
+                pkg.add_library(&mut file, &dep.name, &lib_dir.to_string_lossy())?;
+                return Ok(true);
             }
-        } else {
-            Ok(false)
         }
-    }
 
-    fn find_dependency(&self, dep: &str, build_options: &BuildOptions, pkg: &mut Package) -> CompileResult<()>
-    {
-        // Always try the build directory first
-        if self.find_dependency_in_path(dep, "build", &build_options.target_machine.target.triplet, pkg)? {
-            return Ok(())
+        if let Some((dir, meta)) = find_artifact(deps_dir, target_triplet, dep)? {
+            let mut file = File::open(dir.join(meta.exports_file()))?;
+            pkg.add_library(&mut file, &dep.name, &dir.to_string_lossy())?;
+            return Ok(true);
         }
 
-        for import_dir in &build_options.import_directories {
-            if self.find_dependency_in_path(dep, &import_dir.to_string_lossy(), &build_options.target_machine.target.triplet, pkg)? {
-                return Ok(())
-            }
-        }
+        Ok(false)
+    }
 
-        if let Ok(import_paths) = env::var("MENHIR_IMPORT_DIRS") {
-            for path in import_paths.split(':') {
-                if self.find_dependency_in_path(dep, path, &build_options.target_machine.target.triplet, pkg)? {
-                    return Ok(())
-                }
+    fn find_dependency(&self, dep: &str, import_directories: &[PathBuf], target: &Target, pkg: &mut Package) -> CompileResult<()>
+    {
+        let dependency = parse_dependency(dep);
+        for import_path in import_search_path(import_directories) {
+            if self.find_dependency_in_path(&dependency, &import_path.path, &target.triplet, pkg)? {
+                return Ok(())
             }
         }
 
-        Err(CompileError::Other(format!("Unable to find dependency {}", dep)))
+        Err(CompileError::Other(format!("Unable to find dependency {}, use --print import-paths to see where it was searched", dep)))
     }
 
-    fn find_dependencies(&self, build_options: &BuildOptions, pkg: &mut Package) -> CompileResult<()>
+    fn find_dependencies(&self, import_directories: &[PathBuf], target: &Target, pkg: &mut Package) -> CompileResult<()>
     {
         if let Some(ref deps) = self.depends {
             for dep in deps {
-                self.find_dependency(dep, build_options, pkg)?;
+                self.find_dependency(dep, import_directories, target, pkg)?;
             }
         }
 
         Ok(())
     }
 
-
-    fn build(&self, build_options: &BuildOptions) -> CompileResult<()>
+    // The file or directory containing the sources of the target
+    fn source_path(&self, sources_directory: &str) -> PathBuf
     {
-        println!("Building target {}", self.name);
-        let single_file = format!("{}/{}.mhr", build_options.sources_directory, self.name);
-        let dir_name = format!("{}/{}", build_options.sources_directory, self.name);
+        if let Some(ref path) = self.path {
+            return path.clone();
+        }
 
-        let path = if let Some(ref path) = self.path {
-            path.as_path()
+        let single_file = PathBuf::from(format!("{}/{}.mhr", sources_directory, self.name));
+        if single_file.exists() {
+            single_file
         } else {
-            let path = Path::new(&single_file);
-            if path.exists() {
-                path
-            } else {
-                Path::new(&dir_name)
-            }
-        };
+            PathBuf::from(format!("{}/{}", sources_directory, self.name))
+        }
+    }
 
+    // Parse and type check the target, this needs no LLVM target machine
+    fn check(&self, sources_directory: &str, import_directories: &[PathBuf], target: &Target, info: &HashMap<String, String>) -> CompileResult<Package>
+    {
         let mut pkg = Package::new(&self.name);
-        self.find_dependencies(build_options, &mut pkg)?;
-        pkg.parse_files(path, &build_options.target_machine.target)?;
+        self.find_dependencies(import_directories, target, &mut pkg)?;
+        pkg.parse_files(&self.source_path(sources_directory), target)?;
+        pkg.add_constant_globals(&build_info_globals(info));
 
-        time_operation_mut(2, "Type checking", ||{
-            pkg.type_check(&build_options.target_machine.target)
-        })?;
+        let result = time_operation_mut(2, "Type checking", ||{
+            pkg.type_check(target)
+        });
+
+        match result {
+            Ok(()) => Ok(pkg),
+            Err(e) => Err(suggest_imports(e, &pkg, import_directories, &target.triplet)),
+        }
+    }
+
+    // Check the target, compile it to bytecode, optimize it and generate the LLVM module
+    fn generate<'a>(&self, version: &str, build_options: &'a BuildOptions) -> CompileResult<(Package, ByteCodeModule, CodeGenOptions, Context<'a>)>
+    {
+        let target = &build_options.target_machine.target;
+        let info = build_info(version, &build_options.profile.name, &target.triplet, &build_options.defines);
+        let pkg = self.check(&build_options.sources_directory, &build_options.import_directories, target, &info)?;
 
         if build_options.dump_flags.contains("ast") || build_options.dump_flags.contains("all") {
             println!("AST: {}", pkg.name);
             pkg.print(0);
         }
 
+        if build_options.dump_flags.contains("match-lowering") {
+            print_match_lowering(&pkg, &build_options.target_machine.target);
+        }
+
         let mut bc_mod = time_operation(2, "Compile to bytecode", ||{
-            compile_to_byte_code(&pkg, &build_options.target_machine.target)
+            compile_to_byte_code(&pkg, &build_options.target_machine.target, build_options.eager_globals, build_options.profile.log_level)
         })?;
 
+        // With --emit wasm, binaries become WebAssembly modules
+        let output_type = match self.output_type {
+            OutputType::Binary if build_options.emit_wasm => OutputType::Wasm,
+            output_type => output_type,
+        };
+
+        time_operation_mut(2, "Optimization", ||{
+            // Only an executable is the whole program, users of a library can implement its interfaces with their own types
+            let whole_program = match output_type {
+                OutputType::Binary | OutputType::Wasm => true,
+                _ => false,
+            };
+            if build_options.profile.lto && whole_program {
+                devirtualize_interface_calls(&mut bc_mod, &pkg);
+            }
+
+            if !build_options.profile.bounds_checks {
+                remove_bounds_checks(&mut bc_mod);
+            }
+
+            if build_options.profile.optimize {
+                optimize_module(&mut bc_mod, OptimizationLevel::Normal, build_options.profile.inline_threshold);
+            } else {
+                optimize_module(&mut bc_mod, OptimizationLevel::Minimal, build_options.profile.inline_threshold);
+            }
+        });
+
+        // After optimization, so the dump shows the bytecode LLVM gets
         if build_options.dump_flags.contains("bytecode") || build_options.dump_flags.contains("all") {
             println!("bytecode:");
             println!("------\n");
@@ -200,32 +454,65 @@ impl PackageTarget
             println!("------\n");
         }
 
-        time_operation_mut(2, "Optimization", ||{
-            if build_options.optimize {
-                optimize_module(&mut bc_mod, OptimizationLevel::Normal);
-            } else {
-                optimize_module(&mut bc_mod, OptimizationLevel::Minimal);
+        if let OutputType::Binary = output_type {
+            if bc_mod.get_function(&bc_mod.main_function_name()).is_none() {
+                return Err(CompileError::Other(format!("{} has no main function, only libraries can be built without one", self.name)));
             }
-        });
+        } else if build_options.instrument_heap {
+            return Err(CompileError::Other(format!("{} is a {}, only binaries can be built with --instrument heap", self.name, output_type)));
+        }
 
         let opts = CodeGenOptions{
             dump_ir: build_options.dump_flags.contains("ir") ||  build_options.dump_flags.contains("all"),
-            build_dir: format!("build/{}/{}", build_options.target_machine.target.triplet, self.name),
-            output_file_name: output_file_name(&self.name, self.output_type),
-            output_type: self.output_type,
-            optimize: build_options.optimize,
+            build_dir: format!("{}/{}/{}", build_options.build_directory, build_options.target_machine.target.triplet, self.name),
+            output_file_name: output_file_name(&self.name, output_type),
+            output_type: output_type,
+            optimize: build_options.profile.optimize,
+            debug_info: build_options.profile.debug_info,
+            intern_strings: build_options.profile.intern_strings,
+            merge_strings: build_options.profile.merge_strings,
+            instrument_heap: build_options.instrument_heap,
+            // Libraries don't know when the program exits
+            leak_checks: build_options.profile.leak_checks && output_type == OutputType::Binary,
         };
 
-
-
         let ctx = time_operation(2, "Code generation", ||{
-            llvm_code_generation(&bc_mod, &build_options.target_machine).map_err(CompileError::Other)
+            llvm_code_generation(&bc_mod, &build_options.target_machine, &opts).map_err(CompileError::Other)
         })?;
 
+        Ok((pkg, bc_mod, opts, ctx))
+    }
+
+    fn build(&self, version: &str, build_options: &BuildOptions) -> CompileResult<PathBuf>
+    {
+        println!("Building target {}", self.name);
+        let (pkg, bc_mod, opts, ctx) = self.generate(version, build_options)?;
+
         time_operation(2, "Linking", ||{
             link(&ctx, &opts, &pkg.linker_flags)
         })?;
 
+        if build_options.emit_stack_usage {
+            let path = format!("{}/{}.stack_usage", opts.build_dir, self.name);
+            let mut file = File::create(&path)?;
+            println!("  Generating {}", path);
+            write!(file, "{}", estimate_stack_usage(&ctx, &bc_mod))?;
+        }
+
+        if build_options.emit_c_header {
+            let path = format!("{}/{}.h", opts.build_dir, self.name);
+            let mut file = File::create(&path)?;
+            println!("  Generating {}", path);
+            write!(file, "{}", generate_c_header(&pkg))?;
+        }
+
+        if build_options.emit_ffi_json {
+            let path = format!("{}/{}.ffi.json", opts.build_dir, self.name);
+            let mut file = File::create(&path)?;
+            println!("  Generating {}", path);
+            write!(file, "{}", ffi_json(&ctx, &bc_mod))?;
+        }
+
         match opts.output_type
         {
             OutputType::SharedLib | OutputType::StaticLib => {
@@ -234,10 +521,51 @@ impl PackageTarget
                 println!("  Generating {}", path);
                 let export_lib = ExportLibrary::new(&pkg, opts.output_type);
                 export_lib.save(&mut file)?;
+
+                if build_options.emit_package {
+                    let meta = ArtifactMetadata{
+                        name: self.name.clone(),
+                        version: version.into(),
+                        target: build_options.target_machine.target.triplet.clone(),
+                        output_type: opts.output_type,
+                    };
+                    let dir = create_artifact(Path::new(&opts.build_dir), &meta)?;
+                    println!("  Generating package {}", dir.to_string_lossy());
+                }
             }
 
             _ => (),
         }
-        Ok(())
+        Ok(Path::new(&opts.build_dir).join(&opts.output_file_name))
     }
-}
\ No newline at end of file
+
+    // Generate the code of a binary, and run its main function with the JIT instead of linking it
+    fn run_jit(&self, version: &str, build_options: &BuildOptions) -> CompileResult<i64>
+    {
+        if self.output_type != OutputType::Binary || build_options.emit_wasm {
+            return Err(CompileError::Other(format!("{} is not a binary, only binaries can be run with the JIT", self.name)));
+        }
+
+        let (pkg, _, _, mut ctx) = self.generate(version, build_options)?;
+        let flags = &pkg.linker_flags;
+        if let Some(lib) = flags.linker_static_libs.first() {
+            return Err(CompileError::Other(format!("Static library {} cannot be used with the JIT, only shared libraries can", lib)));
+        }
+
+        unsafe {
+            let jit = JIT::new()?;
+            for lib in &flags.linker_shared_libs {
+                let file_name = output_file_name(lib, OutputType::SharedLib);
+                match flags.linker_paths.iter().map(|dir| Path::new(dir).join(&file_name)).find(|path| path.exists()) {
+                    Some(path) => jit.load_library(&path.to_string_lossy())?,
+                    None => return Err(CompileError::Other(format!("Cannot find shared library {}", file_name))),
+                }
+            }
+
+            let ret = time_operation_mut(2, "Running", ||{
+                jit.run(&mut ctx)
+            })?;
+            Ok(ret)
+        }
+    }
+}
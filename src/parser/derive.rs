@@ -0,0 +1,271 @@
+use ast::*;
+use compileerror::{CompileResult, parse_error_result};
+use target::Target;
+use super::namespaced;
+
+// The support code of the derived functions, JsonWriter and JsonReader
+const JSON_SUPPORT: &str = include_str!("json.mhr");
+
+// The name of a type or case without its namespace
+fn short_name(name: &str) -> &str
+{
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn derives_json(derives: &[String]) -> bool
+{
+    derives.iter().any(|d| d == "Json")
+}
+
+// Members which are native integers, doubles, bools, strings or types of the same module declared with
+// @derive(Json) can be converted. Every module gets its own JsonWriter and JsonReader, so types from
+// other modules cannot be used.
+fn check_json_member(module: &Module, namespace: &str, owner: &str, member: &StructMemberDeclaration, target: &Target) -> CompileResult<()>
+{
+    match member.typ
+    {
+        Type::Float(FloatSize::F64) | Type::Bool | Type::String => Ok(()),
+        ref t if *t == target.native_int_type || *t == target.native_uint_type => Ok(()),
+        Type::Unresolved(ref ut) if ut.generic_args.is_empty() => {
+            let derived = match module.types.get(&namespaced(namespace, &ut.name)) {
+                Some(&TypeDeclaration::Struct(ref sd)) => derives_json(&sd.derives),
+                Some(&TypeDeclaration::Sum(ref st)) => derives_json(&st.derives),
+                _ => false,
+            };
+
+            if derived {
+                Ok(())
+            } else {
+                parse_error_result(&member.span, format!("Cannot derive Json for {}, the type {} of member {} doesn't derive Json in this module", owner, ut.name, member.name))
+            }
+        },
+        _ => parse_error_result(&member.span, format!("Cannot derive Json for {}, member {} has type {} which has no JSON representation", owner, member.name, member.typ)),
+    }
+}
+
+fn check_json_members(module: &Module, namespace: &str, owner: &str, sd: &StructDeclaration, target: &Target) -> CompileResult<()>
+{
+    if sd.members.is_empty() {
+        return parse_error_result(&sd.span, format!("Cannot derive Json for {}, it has no members", owner));
+    }
+
+    for m in &sd.members {
+        check_json_member(module, namespace, owner, m, target)?;
+    }
+    Ok(())
+}
+
+// Statement adding value of type typ to the JsonWriter self
+fn write_member(value: &str, typ: &Type, target: &Target) -> String
+{
+    match *typ
+    {
+        Type::Float(_) => format!("self.add_float({})", value),
+        Type::Bool => format!("self.add_bool({})", value),
+        Type::String => format!("self.add_string({})", value),
+        Type::Unresolved(ref ut) => format!("self.write_{}({})", ut.name, value),
+        ref t if *t == target.native_uint_type => format!("self.add_uint({})", value),
+        _ => format!("self.add_int({})", value),
+    }
+}
+
+// Expression reading a value of type typ with the JsonReader self
+fn read_member(typ: &Type, target: &Target) -> String
+{
+    match *typ
+    {
+        Type::Float(_) => "self.read_float()".into(),
+        Type::Bool => "self.read_bool()".into(),
+        Type::String => "self.read_string()".into(),
+        Type::Unresolved(ref ut) => format!("self.read_{}()", ut.name),
+        ref t if *t == target.native_uint_type => "self.read_uint()".into(),
+        _ => "self.read_int()".into(),
+    }
+}
+
+// A value of type typ, returned by the readers after an error
+fn default_value(module: &Module, namespace: &str, typ: &Type, target: &Target) -> String
+{
+    match *typ
+    {
+        Type::Float(_) => "0.0".into(),
+        Type::Bool => "false".into(),
+        Type::String => "\"\"".into(),
+        Type::Unresolved(ref ut) => match module.types.get(&namespaced(namespace, &ut.name)) {
+            Some(&TypeDeclaration::Struct(ref sd)) => default_object(module, namespace, &ut.name, sd, target),
+            Some(&TypeDeclaration::Sum(ref st)) => default_case(module, namespace, st, target),
+            _ => panic!("Internal Compiler Error: {} was checked to derive Json", ut.name),
+        },
+        ref t if *t == target.native_uint_type => "0u".into(),
+        _ => "0".into(),
+    }
+}
+
+fn default_object(module: &Module, namespace: &str, constructor: &str, sd: &StructDeclaration, target: &Target) -> String
+{
+    let values: Vec<String> = sd.members.iter().map(|m| default_value(module, namespace, &m.typ, target)).collect();
+    format!("{}{{{}}}", constructor, values.join(", "))
+}
+
+fn default_case(module: &Module, namespace: &str, st: &SumTypeDeclaration, target: &Target) -> String
+{
+    let case = &st.cases[0];
+    match case.data {
+        Some(ref sd) => default_object(module, namespace, short_name(&case.name), sd, target),
+        None => short_name(&case.name).into(),
+    }
+}
+
+// Statements writing the members of sd as a JSON object, value gives the expression of a member
+fn write_object<F: Fn(&str) -> String>(sd: &StructDeclaration, indent: &str, prefix: &str, suffix: &str, value: F, target: &Target) -> String
+{
+    let mut code = String::new();
+    for (idx, m) in sd.members.iter().enumerate() {
+        let separator = if idx == 0 {"{"} else {","};
+        code.push_str(&format!("{}self.add(\"{}{}\\\"{}\\\":\")\n", indent, if idx == 0 {prefix} else {""}, separator, m.name));
+        code.push_str(&format!("{}{}\n", indent, write_member(&value(&m.name), &m.typ, target)));
+    }
+    code.push_str(&format!("{}self.add(\"}}{}\")\n", indent, suffix));
+    code
+}
+
+// Statements reading the members of sd from a JSON object into value_<member>
+fn read_object(sd: &StructDeclaration, indent: &str, target: &Target) -> String
+{
+    let names: Vec<String> = sd.members.iter().map(|m| format!("\"{}\"", m.name)).collect();
+    let mut code = format!("{}let object = self.read_object([{}])\n", indent, names.join(", "));
+    for m in &sd.members {
+        code.push_str(&format!("{}self.seek_member(object, \"{}\")\n", indent, m.name));
+        code.push_str(&format!("{}let value_{} = {}\n", indent, m.name, read_member(&m.typ, target)));
+    }
+    code.push_str(&format!("{}self.end_object(object)\n", indent));
+    code
+}
+
+// constructor{value_<member>, ...}
+fn construct(sd: &StructDeclaration, constructor: &str) -> String
+{
+    let values: Vec<String> = sd.members.iter().map(|m| format!("value_{}", m.name)).collect();
+    format!("{}{{{}}}", constructor, values.join(", "))
+}
+
+// to_json and from_json, on top of the write and read functions of the type. There are no generic sum
+// types to return, so every type gets its own result type.
+fn conversions(name: &str) -> String
+{
+    format!(r#"
+enum {0}JsonResult:
+    {0}JsonOk{{value: {0}}}
+    {0}JsonError{{message: string}}
+
+fn {0}.to_json(self) -> string:
+    var writer = json_writer()
+    writer.write_{0}(*self)
+    writer.text()
+
+fn {0}.from_json(text: string) -> {0}JsonResult:
+    var reader = json_reader(text)
+    let value = reader.read_{0}()
+    reader.finish()
+    if reader.ok(): {0}JsonOk{{value}} else {0}JsonError{{reader.error}}
+"#, name)
+}
+
+fn struct_json(sd: &StructDeclaration, target: &Target) -> String
+{
+    let name = short_name(&sd.name);
+    let mut code = format!("\nfn JsonWriter.write_{0}(var self, v: {0}):\n", name);
+    code.push_str(&write_object(sd, "    ", "", "", |m| format!("v.{}", m), target));
+    code.push_str(&format!("\nfn JsonReader.read_{0}(var self) -> {0}:\n", name));
+    code.push_str(&read_object(sd, "    ", target));
+    code.push_str(&format!("    {}\n", construct(sd, name)));
+    code.push_str(&conversions(name));
+    code
+}
+
+fn sum_type_json(module: &Module, namespace: &str, st: &SumTypeDeclaration, target: &Target) -> String
+{
+    let name = short_name(&st.name);
+    let mut write = format!("\nfn JsonWriter.write_{0}(var self, v: {0}):\n    match v:\n", name);
+    let mut read = format!("\nfn JsonReader.read_{0}(var self) -> {0}:\n", name);
+    read.push_str("    let data = self.begin_case()\n    let name = self.read_case_name(data)\n");
+    for (idx, case) in st.cases.iter().enumerate() {
+        let case_name = short_name(&case.name);
+        let keyword = if idx == 0 {"if"} else {"else if"};
+        read.push_str(&format!("    {} json_equal(name, \"{}\"):\n", keyword, case_name));
+        match case.data {
+            Some(ref sd) => {
+                write.push_str(&format!("        {} =>\n", construct(sd, case_name)));
+                write.push_str(&write_object(sd, "            ", &format!("{{\\\"{}\\\":", case_name), "}", |m| format!("value_{}", m), target));
+                read.push_str("        self.check_case_data(name, data, true)\n");
+                read.push_str(&read_object(sd, "        ", target));
+                read.push_str("        self.end_case(data)\n");
+                read.push_str(&format!("        {}\n", construct(sd, case_name)));
+            },
+            None => {
+                write.push_str(&format!("        {} => self.add(\"\\\"{}\\\"\")\n", case_name, case_name));
+                read.push_str("        self.check_case_data(name, data, false)\n");
+                read.push_str("        self.end_case(data)\n");
+                read.push_str(&format!("        {}\n", case_name));
+            },
+        }
+    }
+
+    // After an error every read gives a default value, the first case will do as well
+    read.push_str("    else\n        self.unknown_case(name)\n");
+    read.push_str(&format!("        {}\n", default_case(module, namespace, st, target)));
+
+    let mut code = write;
+    code.push_str(&read);
+    code.push_str(&conversions(name));
+    code
+}
+
+// The support code, without the external functions the module already declares
+fn json_support(module: &Module) -> String
+{
+    JSON_SUPPORT.lines()
+        .filter(|line| {
+            let declared = line.trim_start_matches("extern fn ");
+            declared.len() == line.len() || !module.externals.keys().any(|name| declared.starts_with(&format!("{}(", name)))
+        })
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+// The source of the functions @derive(Json) adds to the types declared in file_name: JsonWriter.write_<type>,
+// JsonReader.read_<type>, to_json and from_json, together with the support code they need.
+pub fn derived_functions(module: &Module, namespace: &str, file_name: &str, target: &Target) -> CompileResult<Option<String>>
+{
+    let mut types: Vec<&TypeDeclaration> = module.types.values()
+        .filter(|td| td.span().file == file_name)
+        .collect();
+    types.sort_by_key(|td| {let start = td.span().start; (start.line, start.offset)});
+
+    let mut code = String::new();
+    for td in types {
+        match *td {
+            TypeDeclaration::Struct(ref sd) if derives_json(&sd.derives) => {
+                check_json_members(module, namespace, &sd.name, sd, target)?;
+                code.push_str(&struct_json(sd, target));
+            },
+
+            TypeDeclaration::Sum(ref st) if derives_json(&st.derives) => {
+                for case in &st.cases {
+                    if let Some(ref sd) = case.data {
+                        check_json_members(module, namespace, &case.name, sd, target)?;
+                    }
+                }
+                code.push_str(&sum_type_json(module, namespace, st, target));
+            },
+
+            _ => (),
+        }
+    }
+
+    if code.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(format!("{}{}", json_support(module), code)))
+    }
+}
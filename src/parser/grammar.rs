@@ -0,0 +1,395 @@
+/*
+The grammar of the language in EBNF, as implemented by the parser. Every production names the
+parse function implementing it, so when the syntax changes the two can be updated together.
+
+Lexical elements are written in lower case (identifier, number, string, char, token). A string
+containing "${" is split into string_start, string_middle and string_end around the interpolated
+expressions. The layout is
+written as NEWLINE (a line at the same indentation as the current block), INDENT (a line which is
+indented deeper, starting a block) and DEDENT (the end of a block). Comma separated lists may be
+continued on lines which are indented deeper than the current block.
+*/
+
+pub struct Production
+{
+    pub name: &'static str,
+    pub rule: &'static str,
+    pub parser: &'static str,
+}
+
+const LEXICAL_ELEMENTS: [&str; 11] = [
+    "identifier", "number", "string", "string_start", "string_middle", "string_end", "char", "token",
+    "NEWLINE", "INDENT", "DEDENT",
+];
+
+pub const GRAMMAR: &[Production] = &[
+    Production{
+        name: "module",
+        rule: r#"{ NEWLINE | annotation | import | public_declaration | function | external_function | opaque_type | global | struct | union | enum | type_declaration | interface | interface_impl | macro }"#,
+        parser: "parse_module",
+    },
+    Production{
+        name: "public_declaration",
+        rule: r#""pub" ( function | external_function | opaque_type | struct | union | enum | type_declaration | interface )"#,
+        parser: "parse_declaration",
+    },
+    Production{
+        name: "annotation",
+        rule: r#""@" ( "derive" "(" identifier { "," identifier } [ "," ] ")" | "deprecated" [ "(" string ")" ] | "repr" "(" "C" ")" | "must_use" | "constant_time" | "unsafe" | "init" | "fini" | "thread_local" | "export" | "test" )"#,
+        parser: "parse_annotation",
+    },
+    Production{
+        name: "import",
+        rule: r#""import" import_name { "," import_name }"#,
+        parser: "parse_module",
+    },
+    Production{
+        name: "import_name",
+        rule: r#"identifier { "::" identifier }"#,
+        parser: "parse_import_name",
+    },
+    Production{
+        name: "function",
+        rule: r#""fn" ( "~" identifier | identifier [ "." identifier ] ) arguments [ "->" type ] ":" block"#,
+        parser: "parse_function_declaration",
+    },
+    Production{
+        name: "external_function",
+        rule: r#""extern" "fn" function_signature"#,
+        parser: "parse_external_function",
+    },
+    Production{
+        name: "opaque_type",
+        rule: r#""extern" "type" identifier"#,
+        parser: "parse_module",
+    },
+    Production{
+        name: "function_signature",
+        rule: r#"identifier arguments [ "->" type ]"#,
+        parser: "parse_function_signature",
+    },
+    Production{
+        name: "arguments",
+        rule: r#""(" [ argument { "," argument } [ "," ] ] ")""#,
+        parser: "parse_function_arguments",
+    },
+    Production{
+        name: "argument",
+        rule: r#"[ "var" | "ref" ] ( identifier struct_pattern | ( struct_pattern | "(" binding_pattern "," binding_pattern { "," binding_pattern } [ "," ] ")" ) ":" type | identifier [ ":" type [ ":" type { "+" type } ] ] )"#,
+        parser: "parse_function_argument",
+    },
+    Production{
+        name: "global",
+        rule: r#"( "let" | "var" ) binding_pattern "=" expression { [ "," ] binding_pattern "=" expression }"#,
+        parser: "parse_global_bindings",
+    },
+    Production{
+        name: "struct",
+        rule: r#""struct" struct_body"#,
+        parser: "parse_struct_type",
+    },
+    Production{
+        name: "union",
+        rule: r#""union" struct_body"#,
+        parser: "parse_struct_type",
+    },
+    Production{
+        name: "struct_body",
+        rule: r#"identifier ( "{" [ member { "," member } [ "," ] ] "}" | ":" INDENT member { NEWLINE member } DEDENT )"#,
+        parser: "parse_struct_type",
+    },
+    Production{
+        name: "member",
+        rule: r#"identifier ":" type"#,
+        parser: "parse_struct_type",
+    },
+    Production{
+        name: "enum",
+        rule: r#""enum" identifier [ generic_params ] ":" INDENT enum_case { NEWLINE enum_case } DEDENT"#,
+        parser: "parse_sum_type",
+    },
+    Production{
+        name: "type_declaration",
+        rule: r#""type" identifier [ generic_params ] "=" enum_case { "|" enum_case }"#,
+        parser: "parse_type_declaration",
+    },
+    Production{
+        name: "generic_params",
+        rule: r#""<" "$" identifier { "," "$" identifier } [ "," ] ">""#,
+        parser: "parse_generic_params",
+    },
+    Production{
+        name: "enum_case",
+        rule: r#"identifier [ "{" [ member { "," member } [ "," ] ] "}" ]"#,
+        parser: "parse_sum_type",
+    },
+    Production{
+        name: "interface",
+        rule: r#""interface" identifier ":" INDENT "fn" function_signature { NEWLINE "fn" function_signature } DEDENT"#,
+        parser: "parse_interface",
+    },
+    Production{
+        name: "interface_impl",
+        rule: r#""impl" identifier "for" identifier ":" INDENT "fn" identifier arguments [ "->" type ] ":" block { NEWLINE "fn" identifier arguments [ "->" type ] ":" block } DEDENT"#,
+        parser: "parse_interface_impl",
+    },
+    Production{
+        name: "macro",
+        rule: r#""macro" identifier ( "(" [ identifier { "," identifier } ] ")" ":" token { token } | ":" INDENT macro_rule { NEWLINE macro_rule } DEDENT )"#,
+        parser: "parse_macro_declaration",
+    },
+    Production{
+        name: "macro_rule",
+        rule: r#""(" { macro_pattern } ")" "=>" token { token }"#,
+        parser: "parse_macro_rule",
+    },
+    Production{
+        name: "macro_pattern",
+        rule: r#"token | "$" identifier | "$" "(" { macro_pattern } ")" [ token ] "*""#,
+        parser: "parse_macro_pattern",
+    },
+    Production{
+        name: "macro_invocation",
+        rule: r#"identifier "!" "(" { token } ")""#,
+        parser: "expand_macros",
+    },
+    Production{
+        name: "type",
+        rule: r#"type_start { "[" [ number | "$" identifier ] "]" }"#,
+        parser: "parse_type",
+    },
+    Production{
+        name: "type_start",
+        rule: r#""*" type | "?" type | "$" identifier | "$" "(" type { "+" type } ")" | "fn" "(" [ type { "," type } [ "," ] ] ")" "->" type | "{" [ type { "," type } [ "," ] ] "}" | primitive_type | identifier [ "<" type { "," type } [ "," ] ">" ]"#,
+        parser: "parse_start_of_type",
+    },
+    Production{
+        name: "primitive_type",
+        rule: r#""int" | "int8" | "int16" | "int32" | "int64" | "uint" | "uint8" | "uint16" | "uint32" | "uint64" | "float" | "float32" | "double" | "float64" | "string" | "bool" | "char" | "Self" | "void""#,
+        parser: "to_primitive",
+    },
+    Production{
+        name: "block",
+        rule: r#"statement { ";" } | INDENT statement { ";" } { NEWLINE statement { ";" } } DEDENT"#,
+        parser: "parse_block",
+    },
+    Production{
+        name: "statement",
+        rule: r#"expression [ assign_operator expression ] | guard_let"#,
+        parser: "parse_block",
+    },
+    Production{
+        name: "guard_let",
+        rule: r#""let" identifier "=" expression "else" "return" [ expression ]"#,
+        parser: "parse_guard_let",
+    },
+    Production{
+        name: "assign_operator",
+        rule: r#""=" | "+=" | "-=" | "*=" | "/=" | "&&=" | "||=""#,
+        parser: "parse_block",
+    },
+    Production{
+        name: "expression",
+        rule: r#"postfix_expression { binary_operator postfix_expression | "as" type }"#,
+        parser: "parse_expression",
+    },
+    Production{
+        name: "binary_operator",
+        rule: r#""*" | "/" | "%" | "+" | "-" | "<" | "<=" | ">" | ">=" | "==" | "!=" | "&&" | "||""#,
+        parser: "parse_binary_op_rhs",
+    },
+    Production{
+        name: "unary_operand",
+        rule: r#"postfix_expression { "as" type }"#,
+        parser: "parse_unary_expression",
+    },
+    Production{
+        name: "postfix_expression",
+        rule: r#"primary_expression { "[" expression [ ".." expression ] "]" | ( "." | "?." ) identifier [ call_arguments ] }"#,
+        parser: "parse_expression_start",
+    },
+    Production{
+        name: "primary_expression",
+        rule: r#"literal | name [ call_arguments | struct_initializer ] | struct_initializer | "(" block ")" | lambda | shorthand_lambda | match | binding | if | while | for | "return" [ expression ] | "new" expression | "delete" expression | ( "!" | "-" ) unary_operand | "&" expression | "*" postfix_expression | compiler_call | macro_invocation"#,
+        parser: "parse_expression_start",
+    },
+    Production{
+        name: "literal",
+        rule: r#""nil" | "null" | "true" | "false" | number | string | interpolated_string | char | array_literal"#,
+        parser: "parse_expression_start",
+    },
+    Production{
+        name: "interpolated_string",
+        rule: r#"string_start expression { string_middle expression } string_end"#,
+        parser: "parse_interpolated_string",
+    },
+    Production{
+        name: "array_literal",
+        rule: r#""[" ( expression ";" number | [ expression { [ "," ] expression } [ "," ] ] ) "]""#,
+        parser: "parse_array_literal",
+    },
+    Production{
+        name: "name",
+        rule: r#"identifier { "::" identifier }"#,
+        parser: "parse_name",
+    },
+    Production{
+        name: "call_arguments",
+        rule: r#""(" [ expression { "," expression } [ "," ] ] ")" [ trailing_lambda | shorthand_lambda ]"#,
+        parser: "parse_function_call",
+    },
+    Production{
+        name: "shorthand_lambda",
+        rule: r#""\" "(" block ")""#,
+        parser: "parse_shorthand_lambda",
+    },
+    Production{
+        name: "trailing_lambda",
+        rule: r#""fn" arguments ( "->" expression | ":" block )"#,
+        parser: "parse_trailing_lambda",
+    },
+    Production{
+        name: "struct_initializer",
+        rule: r#""{" [ expression { "," expression } [ "," ] ] "}""#,
+        parser: "parse_struct_initializer",
+    },
+    Production{
+        name: "lambda",
+        rule: r#""fn" arguments "->" expression"#,
+        parser: "parse_lambda",
+    },
+    Production{
+        name: "match",
+        rule: r#""match" expression ":" INDENT match_case { NEWLINE match_case } DEDENT"#,
+        parser: "parse_match",
+    },
+    Production{
+        name: "match_case",
+        rule: r#"pattern "=>" block"#,
+        parser: "parse_match",
+    },
+    Production{
+        name: "pattern",
+        rule: r#"number | string | char | "true" | "false" | "nil" | "_" | "[" "]" | "[" identifier "|" identifier "]" | array_literal | name [ struct_pattern ] | "?" identifier"#,
+        parser: "parse_pattern",
+    },
+    Production{
+        name: "struct_pattern",
+        rule: r#""{" [ [ "*" ] identifier { "," [ "*" ] identifier } [ "," ] ] "}""#,
+        parser: "parse_struct_pattern",
+    },
+    Production{
+        name: "binding",
+        rule: r#"( "let" | "var" ) binding_pattern "=" expression { [ "," ] binding_pattern "=" expression }"#,
+        parser: "parse_bindings",
+    },
+    Production{
+        name: "binding_pattern",
+        rule: r#"identifier | struct_pattern | "(" binding_pattern "," binding_pattern { "," binding_pattern } [ "," ] ")""#,
+        parser: "parse_binding_type",
+    },
+    Production{
+        name: "if",
+        rule: r#""if" expression ":" block [ [ NEWLINE ] "else" ( if | block ) ]"#,
+        parser: "parse_if",
+    },
+    Production{
+        name: "while",
+        rule: r#""while" expression ":" block"#,
+        parser: "parse_while",
+    },
+    Production{
+        name: "for",
+        rule: r#""for" binding_pattern "in" expression ":" block"#,
+        parser: "parse_for",
+    },
+    Production{
+        name: "compiler_call",
+        rule: r#""@" ( ( "size" | "field_names" | "field_types" | "field_count" ) "(" type ")" | "field_values" "(" expression ")" | ( "slice" | "assert_eq" ) "(" expression "," expression ")" | "assert" "(" expression { "," expression } ")" | ( "format" | "panic" | "debug" | "info" | "warn" | "error" ) "(" [ expression { "," expression } ] ")" | ( "include_str" | "include_bytes" | "build_info" ) "(" string ")" | "offsetof" "(" type "," identifier ")" | intrinsic "(" [ [ type "," ] expression { "," expression } ] ")" )"#,
+        parser: "parse_compiler_call",
+    },
+    Production{
+        name: "intrinsic",
+        rule: r#""likely" | "unlikely" | "prefetch" | "select" | "ct_eq" | "ct_select" | "to_le" | "to_be" | "from_le" | "from_be" | "load_le" | "load_be" | "store_le" | "store_be" | "count_ones" | "leading_zeros" | "trailing_zeros" | "rotate_left" | "rotate_right" | "random" | "parse_int" | "parse_float" | "int_to_string" | "float_to_string" | "hex_encode" | "hex_decode" | "from_utf8""#,
+        parser: "parse_compiler_call",
+    },
+];
+
+// The quoted terminals of a production
+pub fn terminals(name: &str) -> Vec<&'static str>
+{
+    GRAMMAR.iter()
+        .filter(|p| p.name == name)
+        .flat_map(|p| p.rule.split('"').skip(1).step_by(2))
+        .collect()
+}
+
+pub fn grammar_to_ebnf() -> String
+{
+    let width = GRAMMAR.iter().map(|p| p.name.len()).max().unwrap_or(0);
+    let mut ebnf = String::from("(* Grammar of the menhir language, lexical elements: ");
+    ebnf.push_str(&LEXICAL_ELEMENTS.join(", "));
+    ebnf.push_str(" *)\n\n");
+    for p in GRAMMAR {
+        ebnf.push_str(&format!("(* {} *)\n{:width$} = {} ;\n\n", p.parser, p.name, p.rule, width = width));
+    }
+    ebnf
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashSet;
+    use ast::Intrinsic;
+    use super::{GRAMMAR, LEXICAL_ELEMENTS, terminals};
+
+    // The names of the non terminals used in a rule
+    fn non_terminals(rule: &str) -> Vec<&str>
+    {
+        let mut names = Vec::new();
+        for (idx, part) in rule.split('"').enumerate() {
+            // Odd parts are quoted terminals
+            if idx % 2 == 1 {
+                continue;
+            }
+
+            names.extend(part
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .filter(|name| !name.is_empty()));
+        }
+        names
+    }
+
+    #[test]
+    fn test_grammar_is_complete()
+    {
+        let defined: HashSet<&str> = GRAMMAR.iter().map(|p| p.name).collect();
+        assert_eq!(defined.len(), GRAMMAR.len(), "Productions must be defined once");
+
+        let mut reachable = HashSet::new();
+        let mut todo = vec!["module"];
+        while let Some(name) = todo.pop() {
+            if !reachable.insert(name) {
+                continue;
+            }
+
+            let production = GRAMMAR.iter().find(|p| p.name == name).expect("Production must exist");
+            for nt in non_terminals(production.rule) {
+                if !LEXICAL_ELEMENTS.contains(&nt) {
+                    assert!(defined.contains(nt), "{} used in {} is not defined", nt, name);
+                    todo.push(nt);
+                }
+            }
+        }
+
+        assert_eq!(reachable, defined, "All productions must be reachable from module");
+    }
+
+    #[test]
+    fn test_intrinsics()
+    {
+        for name in terminals("intrinsic") {
+            assert!(Intrinsic::from_name(name).is_some(), "{} is not an intrinsic", name);
+        }
+    }
+}
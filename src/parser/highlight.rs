@@ -0,0 +1,196 @@
+use json::json_string;
+use super::grammar::terminals;
+use super::lexer::{KEYWORDS, OPERATORS};
+use super::tokens::TokenKind;
+
+/*
+Syntax highlighting definitions for editors. Keywords and operators come from the tables of the
+lexer, and types, annotations and compiler calls from the grammar, so new syntax is picked up
+by regenerating the definitions.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyntaxFormat
+{
+    TextMate,
+    Vim,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeywordGroup
+{
+    Conditional,
+    Repeat,
+    Operator,
+    Constant,
+    Declaration,
+}
+
+fn keyword_group(kind: &TokenKind) -> KeywordGroup
+{
+    match *kind
+    {
+        TokenKind::If | TokenKind::Else | TokenKind::Match | TokenKind::Return => KeywordGroup::Conditional,
+        TokenKind::While | TokenKind::For | TokenKind::In => KeywordGroup::Repeat,
+        TokenKind::BinaryOperator(_) | TokenKind::New | TokenKind::Delete => KeywordGroup::Operator,
+        TokenKind::True | TokenKind::False | TokenKind::Nil | TokenKind::Null => KeywordGroup::Constant,
+        _ => KeywordGroup::Declaration,
+    }
+}
+
+fn keywords(group: KeywordGroup) -> Vec<&'static str>
+{
+    KEYWORDS.iter()
+        .filter(|&&(_, ref kind)| keyword_group(kind) == group)
+        .map(|&(keyword, _)| keyword)
+        .collect()
+}
+
+fn is_name(s: &str) -> bool
+{
+    s.chars().next().map(|c| c.is_lowercase()).unwrap_or(false) && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn annotations() -> Vec<&'static str>
+{
+    terminals("annotation").into_iter().filter(|t| is_name(t)).collect()
+}
+
+fn compiler_calls() -> Vec<&'static str>
+{
+    let mut calls: Vec<&str> = terminals("compiler_call").into_iter().filter(|t| is_name(t)).collect();
+    calls.extend(terminals("intrinsic"));
+    calls
+}
+
+// Longest first, so a regex alternation matches the longest operator
+fn operators() -> Vec<&'static str>
+{
+    let mut ops: Vec<&str> = OPERATORS.iter().map(|&(op, _)| op).collect();
+    ops.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    ops
+}
+
+fn regex_escape(s: &str) -> String
+{
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn tm_pattern(scope: &str, regex: &str) -> String
+{
+    format!("        {{\"name\": {}, \"match\": {}}}", json_string(&format!("{}.menhir", scope)), json_string(regex))
+}
+
+fn tm_words(scope: &str, prefix: &str, words: &[&str]) -> String
+{
+    tm_pattern(scope, &format!("{}\\b({})\\b", prefix, words.join("|")))
+}
+
+fn tmlanguage() -> String
+{
+    let ops: Vec<String> = operators().iter().map(|op| regex_escape(op)).collect();
+    let patterns = vec![
+        tm_pattern("comment.line.number-sign", "#.*$"),
+        format!(
+            "        {{\"name\": \"string.quoted.double.menhir\", \"begin\": \"\\\"\", \"end\": \"\\\"\", \"patterns\": [{{\"name\": \"constant.character.escape.menhir\", \"match\": {}}}]}}",
+            json_string("\\\\.")),
+        tm_pattern("constant.character", "'(\\\\.|[^'])'"),
+        tm_pattern("constant.numeric", "\\b[0-9][0-9.e]*u?\\b"),
+        tm_words("keyword.control.conditional", "", &keywords(KeywordGroup::Conditional)),
+        tm_words("keyword.control.loop", "", &keywords(KeywordGroup::Repeat)),
+        tm_words("keyword.operator.word", "", &keywords(KeywordGroup::Operator)),
+        tm_words("constant.language", "", &keywords(KeywordGroup::Constant)),
+        tm_words("storage.type", "", &keywords(KeywordGroup::Declaration)),
+        tm_words("support.type.primitive", "", &terminals("primitive_type")),
+        tm_words("storage.modifier.annotation", "@", &annotations()),
+        tm_words("support.function.builtin", "@", &compiler_calls()),
+        tm_pattern("keyword.operator", &ops.join("|")),
+    ];
+
+    format!(r#"{{
+    "name": "Menhir",
+    "scopeName": "source.menhir",
+    "fileTypes": ["mhr"],
+    "patterns": [
+{}
+    ]
+}}
+"#, patterns.join(",\n"))
+}
+
+fn vim_keywords(group: &str, words: &[&str]) -> String
+{
+    format!("syn keyword {} {}\n", group, words.join(" "))
+}
+
+fn vim() -> String
+{
+    let mut syntax = String::from("\" Vim syntax file for menhir, generated by cobrac emit-syntax\n");
+    syntax.push_str("if exists(\"b:current_syntax\")\n  finish\nendif\n\n");
+    syntax.push_str(&vim_keywords("menhirConditional", &keywords(KeywordGroup::Conditional)));
+    syntax.push_str(&vim_keywords("menhirRepeat", &keywords(KeywordGroup::Repeat)));
+    syntax.push_str(&vim_keywords("menhirOperator", &keywords(KeywordGroup::Operator)));
+    syntax.push_str(&vim_keywords("menhirConstant", &keywords(KeywordGroup::Constant)));
+    syntax.push_str(&vim_keywords("menhirKeyword", &keywords(KeywordGroup::Declaration)));
+    syntax.push_str(&vim_keywords("menhirType", &terminals("primitive_type")));
+    syntax.push_str(&format!("syn match menhirAnnotation \"@\\({}\\)\\>\"\n", annotations().join("\\|")));
+    syntax.push_str(&format!("syn match menhirBuiltin \"@\\({}\\)\\>\"\n", compiler_calls().join("\\|")));
+    syntax.push_str("syn match menhirNumber \"\\<[0-9][0-9.e]*u\\=\\>\"\n");
+    syntax.push_str("syn match menhirChar \"'\\\\\\=.'\"\n");
+    syntax.push_str("syn region menhirString start=+\"+ skip=+\\\\\\\\\\|\\\\\"+ end=+\"+\n");
+    syntax.push_str("syn match menhirComment \"#.*$\"\n\n");
+
+    for &(group, link) in &[
+        ("menhirConditional", "Conditional"),
+        ("menhirRepeat", "Repeat"),
+        ("menhirOperator", "Operator"),
+        ("menhirConstant", "Constant"),
+        ("menhirKeyword", "Keyword"),
+        ("menhirType", "Type"),
+        ("menhirAnnotation", "PreProc"),
+        ("menhirBuiltin", "Function"),
+        ("menhirNumber", "Number"),
+        ("menhirChar", "Character"),
+        ("menhirString", "String"),
+        ("menhirComment", "Comment"),
+    ] {
+        syntax.push_str(&format!("hi def link {} {}\n", group, link));
+    }
+
+    syntax.push_str("\nlet b:current_syntax = \"menhir\"\n");
+    syntax
+}
+
+pub fn syntax_definition(format: SyntaxFormat) -> String
+{
+    match format
+    {
+        SyntaxFormat::TextMate => tmlanguage(),
+        SyntaxFormat::Vim => vim(),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{syntax_definition, keywords, KeywordGroup, SyntaxFormat};
+
+    #[test]
+    fn test_syntax_definitions()
+    {
+        assert_eq!(keywords(KeywordGroup::Repeat), vec!["in", "while", "for"]);
+        let tm = syntax_definition(SyntaxFormat::TextMate);
+        assert!(tm.contains(r#""match": "\\b(match|if|else|return)\\b""#));
+        assert!(tm.contains("ct_select"));
+        let vim = syntax_definition(SyntaxFormat::Vim);
+        assert!(vim.contains("syn keyword menhirRepeat in while for\n"));
+        assert!(vim.contains("syn keyword menhirType int int8"));
+    }
+}
@@ -18,6 +18,7 @@ enum LexState
     Number,
     Operator,
     InString,
+    InByteString,
     InChar,
 }
 
@@ -31,8 +32,79 @@ pub struct Lexer
     escape_code: bool,
     file_name: String,
     indent_level: usize,
+    // Unescaped '$' seen in a string, a following '{' starts an interpolation
+    dollar_pending: bool,
+    // Set when a string resumes after an interpolated expression
+    string_continued: bool,
+    // Nesting depth of curly braces for each open interpolation
+    interpolations: Vec<usize>,
 }
 
+pub static KEYWORDS: [(&str, TokenKind); 28] = [
+    ("import", TokenKind::Import),
+    ("match", TokenKind::Match),
+    ("let", TokenKind::Let),
+    ("in", TokenKind::In),
+    ("true", TokenKind::True),
+    ("false", TokenKind::False),
+    ("type", TokenKind::Type),
+    ("struct", TokenKind::Struct),
+    ("union", TokenKind::Union),
+    ("enum", TokenKind::Enum),
+    ("if", TokenKind::If),
+    ("else", TokenKind::Else),
+    ("extern", TokenKind::Extern),
+    ("new", TokenKind::New),
+    ("delete", TokenKind::Delete),
+    ("while", TokenKind::While),
+    ("for", TokenKind::For),
+    ("nil", TokenKind::Nil),
+    ("null", TokenKind::Null),
+    ("var", TokenKind::Var),
+    ("ref", TokenKind::Ref),
+    ("as", TokenKind::BinaryOperator(BinaryOperator::As)),
+    ("interface", TokenKind::Interface),
+    ("impl", TokenKind::Impl),
+    ("fn", TokenKind::Func),
+    ("pub", TokenKind::Pub),
+    ("return", TokenKind::Return),
+    ("macro", TokenKind::Macro),
+];
+
+pub static OPERATORS: [(&str, TokenKind); 31] = [
+    ("+", TokenKind::BinaryOperator(BinaryOperator::Add)),
+    ("-", TokenKind::BinaryOperator(BinaryOperator::Sub)),
+    ("*", TokenKind::BinaryOperator(BinaryOperator::Mul)),
+    ("/", TokenKind::BinaryOperator(BinaryOperator::Div)),
+    ("%", TokenKind::BinaryOperator(BinaryOperator::Mod)),
+    (">", TokenKind::BinaryOperator(BinaryOperator::GreaterThan)),
+    (">=", TokenKind::BinaryOperator(BinaryOperator::GreaterThanEquals)),
+    ("<", TokenKind::BinaryOperator(BinaryOperator::LessThan)),
+    ("<=", TokenKind::BinaryOperator(BinaryOperator::LessThanEquals)),
+    ("=", TokenKind::Assign(AssignOperator::Assign)),
+    ("+=", TokenKind::Assign(AssignOperator::Add)),
+    ("-=", TokenKind::Assign(AssignOperator::Sub)),
+    ("*=", TokenKind::Assign(AssignOperator::Mul)),
+    ("/=", TokenKind::Assign(AssignOperator::Div)),
+    ("&&=", TokenKind::Assign(AssignOperator::And)),
+    ("||=", TokenKind::Assign(AssignOperator::Or)),
+    ("==", TokenKind::BinaryOperator(BinaryOperator::Equals)),
+    ("!", TokenKind::UnaryOperator(UnaryOperator::Not)),
+    ("!=", TokenKind::BinaryOperator(BinaryOperator::NotEquals)),
+    ("&&", TokenKind::BinaryOperator(BinaryOperator::And)),
+    ("||", TokenKind::BinaryOperator(BinaryOperator::Or)),
+    ("->", TokenKind::Arrow),
+    ("=>", TokenKind::FatArrow),
+    (":", TokenKind::Colon),
+    ("::", TokenKind::DoubleColon),
+    ("|", TokenKind::Pipe),
+    (".", TokenKind::BinaryOperator(BinaryOperator::Dot)),
+    ("..", TokenKind::DotDot),
+    ("?", TokenKind::QuestionMark),
+    ("?.", TokenKind::OptionalDot),
+    ("&", TokenKind::Ampersand),
+];
+
 fn is_operator_start(c: char) -> bool
 {
     for op in &['+', '-', '*', '/', '%', '>', '<', '=', '!', '.', '|', '&', ':']
@@ -48,6 +120,40 @@ fn is_identifier_start(c: char) -> bool
     c.is_alphanumeric() || c == '_'
 }
 
+// Byte strings only contain ASCII, other bytes are written as \xNN
+fn unescape_bytes(s: &str) -> Result<Vec<u8>, String>
+{
+    let mut bytes = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if !c.is_ascii() {
+            return Err(format!("Byte strings can only contain ASCII characters, not '{}', use \\xNN escapes for other bytes", c));
+        }
+
+        if c != '\\' {
+            bytes.push(c as u8);
+            continue;
+        }
+
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) if hex.len() == 2 => bytes.push(b),
+                    _ => return Err(format!("Invalid escape \\x{} in byte string, expecting two hexadecimal digits", hex)),
+                }
+            },
+            Some(c) if c.is_ascii() => bytes.push(c as u8),
+            _ => return Err("Invalid escape in byte string".into()),
+        }
+    }
+    Ok(bytes)
+}
+
 impl Lexer
 {
     pub fn new(file_name: &str) -> Lexer
@@ -61,6 +167,9 @@ impl Lexer
             escape_code: false,
             file_name: file_name.into(),
             indent_level: 0,
+            dollar_pending: false,
+            string_continued: false,
+            interpolations: Vec::new(),
         }
     }
 
@@ -94,14 +203,36 @@ impl Lexer
             ',' => {self.add(TokenKind::Comma, span); Ok(())},
             '(' => {self.add(TokenKind::OpenParen, span); Ok(())},
             ')' => {self.add(TokenKind::CloseParen, span); Ok(())},
-            '{' => {self.add(TokenKind::OpenCurly, span); Ok(())},
-            '}' => {self.add(TokenKind::CloseCurly, span); Ok(())},
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add(TokenKind::OpenCurly, span);
+                Ok(())
+            },
+            '}' => {
+                match self.interpolations.last().cloned() {
+                    Some(0) => {
+                        // End of an interpolated expression, continue with the rest of the string
+                        self.interpolations.pop();
+                        self.start(c, LexState::InString);
+                        self.string_continued = true;
+                    }
+                    Some(depth) => {
+                        *self.interpolations.last_mut().expect("Interpolation stack is empty") = depth - 1;
+                        self.add(TokenKind::CloseCurly, span);
+                    }
+                    None => self.add(TokenKind::CloseCurly, span),
+                }
+                Ok(())
+            },
             '[' => {self.add(TokenKind::OpenBracket, span); Ok(())},
             ']' => {self.add(TokenKind::CloseBracket, span); Ok(())},
             '$' => {self.add(TokenKind::Dollar, span); Ok(())},
             ';' => {self.add(TokenKind::SemiColon, span); Ok(())},
             '~' => {self.add(TokenKind::Tilde, span); Ok(())},
-            '?' => {self.add(TokenKind::QuestionMark, span); Ok(())},
+            '\\' => {self.add(TokenKind::Backslash, span); Ok(())},
+            '?' => {self.start(c, LexState::Operator); Ok(())},
             '@' => {self.add(TokenKind::At, span); Ok(())},
             '0'...'9' => {self.start(c, LexState::Number); Ok(())},
             '\"' => {self.start(c, LexState::InString); Ok(())},
@@ -124,32 +255,9 @@ impl Lexer
 
     fn add_identifier(&mut self) -> TokenKind
     {
-        let tok = match &self.data[..]
-        {
-            "import" => TokenKind::Import,
-            "match" => TokenKind::Match,
-            "let" => TokenKind::Let,
-            "in" => TokenKind::In,
-            "true" => TokenKind::True,
-            "false" => TokenKind::False,
-            "type" => TokenKind::Type,
-            "struct" => TokenKind::Struct,
-            "enum" => TokenKind::Enum,
-            "if" => TokenKind::If,
-            "else" => TokenKind::Else,
-            "extern" => TokenKind::Extern,
-            "new" => TokenKind::New,
-            "delete" => TokenKind::Delete,
-            "while" => TokenKind::While,
-            "for" => TokenKind::For,
-            "nil" => TokenKind::Nil,
-            "null" => TokenKind::Null,
-            "var" => TokenKind::Var,
-            "as" => TokenKind::BinaryOperator(BinaryOperator::As),
-            "interface" => TokenKind::Interface,
-            "fn" => TokenKind::Func,
-            "return" => TokenKind::Return,
-            _ => TokenKind::Identifier(mem::replace(&mut self.data, String::new())),
+        let tok = match KEYWORDS.iter().find(|&&(keyword, _)| keyword == self.data) {
+            Some(&(_, ref kind)) => kind.clone(),
+            None => TokenKind::Identifier(mem::replace(&mut self.data, String::new())),
         };
 
         self.data.clear();
@@ -173,6 +281,13 @@ impl Lexer
             self.data.push(c);
             Ok(())
         }
+        else if c == '"' && self.data == "b"
+        {
+            // b"..." is a byte string, the token starts at the b
+            self.data.clear();
+            self.state = LexState::InByteString;
+            Ok(())
+        }
         else
         {
             self.state = LexState::Idle;
@@ -185,7 +300,19 @@ impl Lexer
 
     fn number(&mut self, c: char) -> CompileResult<()>
     {
-        if c.is_numeric() || c == '.' || c == 'e'
+        if c == '.' && self.data.ends_with('.')
+        {
+            // The number is the start of a range like 0..n, the first dot belongs to the ..
+            self.data.pop();
+            let span = Span::new(&self.file_name, self.token_start_pos, Pos::new(self.pos.line, self.pos.offset - 2));
+            let num = mem::replace(&mut self.data, String::new());
+            self.add(TokenKind::Number(num), span);
+            self.token_start_pos = Pos::new(self.pos.line, self.pos.offset - 1);
+            self.state = LexState::Operator;
+            self.data.push_str("..");
+            Ok(())
+        }
+        else if c.is_numeric() || c == '.' || c == 'e'
         {
             self.data.push(c);
             Ok(())
@@ -200,40 +327,32 @@ impl Lexer
         }
     }
 
-    fn data_to_token_kind(&self) -> CompileResult<TokenKind>
+    fn add_operators(&mut self) -> CompileResult<()>
     {
-        match &self.data[..]
+        // A run of operator characters can contain several operators (for example the >: in enum Option<$a>:),
+        // so split it up into the longest operators which match
+        let data = self.data.clone();
+        let mut start = self.token_start_pos;
+        let mut remaining = &data[..];
+        while !remaining.is_empty()
         {
-            "+" => Ok(TokenKind::BinaryOperator(BinaryOperator::Add)),
-            "-" => Ok(TokenKind::BinaryOperator(BinaryOperator::Sub)),
-            "*" => Ok(TokenKind::BinaryOperator(BinaryOperator::Mul)),
-            "/" => Ok(TokenKind::BinaryOperator(BinaryOperator::Div)),
-            "%" => Ok(TokenKind::BinaryOperator(BinaryOperator::Mod)),
-            ">" => Ok(TokenKind::BinaryOperator(BinaryOperator::GreaterThan)),
-            ">=" => Ok(TokenKind::BinaryOperator(BinaryOperator::GreaterThanEquals)),
-            "<" => Ok(TokenKind::BinaryOperator(BinaryOperator::LessThan)),
-            "<=" => Ok(TokenKind::BinaryOperator(BinaryOperator::LessThanEquals)),
-            "=" => Ok(TokenKind::Assign(AssignOperator::Assign)),
-            "+=" => Ok(TokenKind::Assign(AssignOperator::Add)),
-            "-=" => Ok(TokenKind::Assign(AssignOperator::Sub)),
-            "*=" => Ok(TokenKind::Assign(AssignOperator::Mul)),
-            "/=" => Ok(TokenKind::Assign(AssignOperator::Div)),
-            "&&=" => Ok(TokenKind::Assign(AssignOperator::And)),
-            "||=" => Ok(TokenKind::Assign(AssignOperator::Or)),
-            "==" => Ok(TokenKind::BinaryOperator(BinaryOperator::Equals)),
-            "!" => Ok(TokenKind::UnaryOperator(UnaryOperator::Not)),
-            "!=" => Ok(TokenKind::BinaryOperator(BinaryOperator::NotEquals)),
-            "&&" => Ok(TokenKind::BinaryOperator(BinaryOperator::And)),
-            "||" => Ok(TokenKind::BinaryOperator(BinaryOperator::Or)),
-            "->" => Ok(TokenKind::Arrow),
-            "=>" => Ok(TokenKind::FatArrow),
-            ":" => Ok(TokenKind::Colon),
-            "::" => Ok(TokenKind::DoubleColon),
-            "|" => Ok(TokenKind::Pipe),
-            "." => Ok(TokenKind::BinaryOperator(BinaryOperator::Dot)),
-            "&" => Ok(TokenKind::Ampersand),
-            _ => parse_error_result(&self.current_single_span(), format!("Invalid operator {}", self.data)),
+            let longest = OPERATORS.iter()
+                .filter(|&&(op, _)| remaining.starts_with(op))
+                .max_by_key(|&&(op, _)| op.len());
+
+            match longest {
+                Some(&(op, ref kind)) => {
+                    let len = op.chars().count();
+                    let span = Span::new(&self.file_name, start, Pos::new(start.line, start.offset + len - 1));
+                    self.add(kind.clone(), span);
+                    start.offset += len;
+                    remaining = &remaining[op.len()..];
+                },
+                None => return parse_error_result(&Span::single(&self.file_name, start), format!("Invalid operator {}", remaining)),
+            }
         }
+
+        Ok(())
     }
 
     fn operator(&mut self, c: char) -> CompileResult<()>
@@ -241,12 +360,11 @@ impl Lexer
         if c.is_whitespace() || c.is_alphanumeric() ||
             c == '{' || c == '(' || c == '[' ||
             c == '}' || c == ')' || c == ']' ||
-            c == '$' || c == ',' || c == '_'
+            c == '$' || c == ',' || c == '_' || c == '\\' ||
+            (self.data == "?" && c != '.') // Only ?. continues after a ?, so ?*int is still an optional pointer
         {
-            let kind = self.data_to_token_kind()?;
+            self.add_operators()?;
             self.state = LexState::Idle;
-            let span = self.current_span();
-            self.add(kind, span);
             self.idle(c)
         }
         else
@@ -256,7 +374,8 @@ impl Lexer
         }
     }
 
-    fn in_string_or_char_literal(&mut self, c: char, end: char)
+    // Returns whether c ends the literal, an escaped end character doesn't
+    fn in_string_or_char_literal(&mut self, c: char, end: char) -> bool
     {
         if self.escape_code
         {
@@ -268,41 +387,96 @@ impl Lexer
                 't' => self.data.push('\t'),
                 _   => self.data.push(c),
             }
+            false
         }
         else if c == '\\'
         {
             self.escape_code = true;
+            false
         }
         else if c != end
         {
             self.data.push(c);
+            false
+        }
+        else
+        {
+            true
         }
     }
 
     fn in_string(&mut self, c: char) -> CompileResult<()>
     {
-        self.in_string_or_char_literal(c, '"');
-        if c == '"'
+        if c == '{' && self.dollar_pending
+        {
+            // "${" starts an interpolated expression
+            self.data.pop();
+            self.dollar_pending = false;
+            let s = mem::replace(&mut self.data, String::new());
+            let mut span = self.current_span();
+            span.end.offset += 1;
+            let kind = if self.string_continued {
+                TokenKind::StringInterpolationMiddle(s)
+            } else {
+                TokenKind::StringInterpolationStart(s)
+            };
+            self.add(kind, span);
+            self.interpolations.push(0);
+            self.string_continued = false;
+            self.state = LexState::Idle;
+            return Ok(());
+        }
+
+        self.dollar_pending = c == '$' && !self.escape_code;
+        if self.in_string_or_char_literal(c, '"')
         {
             let s = mem::replace(&mut self.data, String::new());
             let mut span = self.current_span();
             span.end.offset += 1; // Need to include the quote
-            self.add(TokenKind::StringLiteral(s), span);
+            let kind = if self.string_continued {
+                TokenKind::StringInterpolationEnd(s)
+            } else {
+                TokenKind::StringLiteral(s)
+            };
+            self.add(kind, span);
             self.escape_code = false;
+            self.string_continued = false;
             self.state = LexState::Idle;
 
         }
         Ok(())
     }
 
+    // Escapes are only decoded at the end, so \xNN can produce any byte
+    fn in_byte_string(&mut self, c: char) -> CompileResult<()>
+    {
+        if c == '"' && !self.escape_code
+        {
+            let mut span = self.current_span();
+            span.end.offset += 1;
+            let bytes = match unescape_bytes(&self.data) {
+                Ok(bytes) => bytes,
+                Err(msg) => return parse_error_result(&span, msg),
+            };
+            self.data.clear();
+            self.add(TokenKind::ByteStringLiteral(bytes), span);
+            self.state = LexState::Idle;
+        }
+        else
+        {
+            self.escape_code = c == '\\' && !self.escape_code;
+            self.data.push(c);
+        }
+        Ok(())
+    }
+
     fn in_char(&mut self, c: char) -> CompileResult<()>
     {
-        self.in_string_or_char_literal(c, '\'');
-        if c == '\''
+        if self.in_string_or_char_literal(c, '\'')
         {
             let mut span = self.current_span();
             span.end.offset += 1; // Need to include the single quote
-            if self.data.len() != 1 {
+            if self.data.chars().count() != 1 {
                 return parse_error_result(&span, "Invalid char literal");
             }
 
@@ -352,6 +526,7 @@ impl Lexer
             LexState::Number =>  self.number(c),
             LexState::Operator => self.operator(c),
             LexState::InString => self.in_string(c),
+            LexState::InByteString => self.in_byte_string(c),
             LexState::InChar => self.in_char(c),
         }
     }
@@ -471,10 +646,55 @@ mod tests
         ]);
     }
 
+    #[test]
+    fn test_optional_operators()
+    {
+        let mut cursor = Cursor::new("a?.b ?*c ?d");
+        let tokens: Vec<Token> = Lexer::new("")
+            .read(&mut cursor)
+            .expect("Lexing failed")
+            .collect();
+
+        assert_eq!(tokens, vec![
+            tok(TokenKind::Indent(0), 1, 1, 1, 1),
+            tok(TokenKind::Identifier("a".into()), 1, 1, 1, 1),
+            tok(TokenKind::OptionalDot, 1, 2, 1, 3),
+            tok(TokenKind::Identifier("b".into()), 1, 4, 1, 4),
+            tok(TokenKind::QuestionMark, 1, 6, 1, 6),
+            tok(TokenKind::BinaryOperator(BinaryOperator::Mul), 1, 7, 1, 7),
+            tok(TokenKind::Identifier("c".into()), 1, 8, 1, 8),
+            tok(TokenKind::QuestionMark, 1, 10, 1, 10),
+            tok(TokenKind::Identifier("d".into()), 1, 11, 1, 11),
+            tok(TokenKind::EOF, 2, 1, 2, 1),
+        ]);
+    }
+
+    #[test]
+    fn test_ranges()
+    {
+        let mut cursor = Cursor::new("0..10 x..y 1.5");
+        let tokens: Vec<Token> = Lexer::new("")
+            .read(&mut cursor)
+            .expect("Lexing failed")
+            .collect();
+
+        assert_eq!(tokens, vec![
+            tok(TokenKind::Indent(0), 1, 1, 1, 1),
+            tok(TokenKind::Number("0".into()), 1, 1, 1, 1),
+            tok(TokenKind::DotDot, 1, 2, 1, 3),
+            tok(TokenKind::Number("10".into()), 1, 4, 1, 5),
+            tok(TokenKind::Identifier("x".into()), 1, 7, 1, 7),
+            tok(TokenKind::DotDot, 1, 8, 1, 9),
+            tok(TokenKind::Identifier("y".into()), 1, 10, 1, 10),
+            tok(TokenKind::Number("1.5".into()), 1, 12, 1, 14),
+            tok(TokenKind::EOF, 2, 1, 2, 1),
+        ]);
+    }
+
     #[test]
     fn test_string()
     {
-        let mut cursor = Cursor::new(r#""This is a string" "Blaat\n" "$a""#);
+        let mut cursor = Cursor::new(r#""This is a string" "Blaat\n" "$a" "\"q\" \\""#);
         let tokens: Vec<Token> = Lexer::new("")
             .read(&mut cursor)
             .expect("Lexing failed")
@@ -485,7 +705,101 @@ mod tests
             tok(TokenKind::StringLiteral("This is a string".into()), 1, 1, 1, 18),
             tok(TokenKind::StringLiteral("Blaat\n".into()), 1, 20, 1, 28),
             tok(TokenKind::StringLiteral("$a".into()), 1, 30, 1, 33),
+            tok(TokenKind::StringLiteral("\"q\" \\".into()), 1, 35, 1, 44),
             tok(TokenKind::EOF, 2, 1, 2, 1),
         ]);
     }
+
+    #[test]
+    fn test_adjacent_operators()
+    {
+        let mut cursor = Cursor::new("Option<$a>: >> ->=");
+        let tokens: Vec<Token> = Lexer::new("")
+            .read(&mut cursor)
+            .expect("Lexing failed")
+            .collect();
+
+        assert_eq!(tokens, vec![
+            tok(TokenKind::Indent(0), 1, 1, 1, 1),
+            tok(TokenKind::Identifier("Option".into()), 1, 1, 1, 6),
+            tok(TokenKind::BinaryOperator(BinaryOperator::LessThan), 1, 7, 1, 7),
+            tok(TokenKind::Dollar, 1, 8, 1, 8),
+            tok(TokenKind::Identifier("a".into()), 1, 9, 1, 9),
+            tok(TokenKind::BinaryOperator(BinaryOperator::GreaterThan), 1, 10, 1, 10),
+            tok(TokenKind::Colon, 1, 11, 1, 11),
+            tok(TokenKind::BinaryOperator(BinaryOperator::GreaterThan), 1, 13, 1, 13),
+            tok(TokenKind::BinaryOperator(BinaryOperator::GreaterThan), 1, 14, 1, 14),
+            tok(TokenKind::Arrow, 1, 16, 1, 17),
+            tok(TokenKind::Assign(AssignOperator::Assign), 1, 18, 1, 18),
+            tok(TokenKind::EOF, 2, 1, 2, 1),
+        ]);
+    }
+
+    #[test]
+    fn test_byte_string()
+    {
+        let mut cursor = Cursor::new(r#"b"PK\x03\x04\"" b"" ab"#);
+        let tokens: Vec<Token> = Lexer::new("")
+            .read(&mut cursor)
+            .expect("Lexing failed")
+            .collect();
+
+        assert_eq!(tokens, vec![
+            tok(TokenKind::Indent(0), 1, 1, 1, 1),
+            tok(TokenKind::ByteStringLiteral(vec![b'P', b'K', 3, 4, b'"']), 1, 1, 1, 15),
+            tok(TokenKind::ByteStringLiteral(Vec::new()), 1, 17, 1, 19),
+            tok(TokenKind::Identifier("ab".into()), 1, 21, 1, 22),
+            tok(TokenKind::EOF, 2, 1, 2, 1),
+        ]);
+
+        let mut cursor = Cursor::new(r#"b"\x0g""#);
+        assert!(Lexer::new("").read(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_string_interpolation()
+    {
+        let mut cursor = Cursor::new(r#""a ${x} b ${f({y})}" "\${z}""#);
+        let tokens: Vec<Token> = Lexer::new("")
+            .read(&mut cursor)
+            .expect("Lexing failed")
+            .collect();
+
+        assert_eq!(tokens, vec![
+            tok(TokenKind::Indent(0), 1, 1, 1, 1),
+            tok(TokenKind::StringInterpolationStart("a ".into()), 1, 1, 1, 5),
+            tok(TokenKind::Identifier("x".into()), 1, 6, 1, 6),
+            tok(TokenKind::StringInterpolationMiddle(" b ".into()), 1, 7, 1, 12),
+            tok(TokenKind::Identifier("f".into()), 1, 13, 1, 13),
+            tok(TokenKind::OpenParen, 1, 14, 1, 14),
+            tok(TokenKind::OpenCurly, 1, 15, 1, 15),
+            tok(TokenKind::Identifier("y".into()), 1, 16, 1, 16),
+            tok(TokenKind::CloseCurly, 1, 17, 1, 17),
+            tok(TokenKind::CloseParen, 1, 18, 1, 18),
+            tok(TokenKind::StringInterpolationEnd("".into()), 1, 19, 1, 20),
+            tok(TokenKind::StringLiteral("${z}".into()), 1, 22, 1, 28),
+            tok(TokenKind::EOF, 2, 1, 2, 1),
+        ]);
+    }
+
+    #[test]
+    fn test_char()
+    {
+        let mut cursor = Cursor::new("'a' 'é' '\\n'");
+        let tokens: Vec<Token> = Lexer::new("")
+            .read(&mut cursor)
+            .expect("Lexing failed")
+            .collect();
+
+        assert_eq!(tokens, vec![
+            tok(TokenKind::Indent(0), 1, 1, 1, 1),
+            tok(TokenKind::CharLiteral('a'), 1, 1, 1, 3),
+            tok(TokenKind::CharLiteral('é'), 1, 5, 1, 7),
+            tok(TokenKind::CharLiteral('\n'), 1, 9, 1, 12),
+            tok(TokenKind::EOF, 2, 1, 2, 1),
+        ]);
+
+        let mut cursor = Cursor::new("'ab'");
+        assert!(Lexer::new("").read(&mut cursor).is_err());
+    }
 }
@@ -0,0 +1,633 @@
+use std::collections::{HashMap, HashSet};
+use ast::{BinaryOperator, UnaryOperator};
+use compileerror::{CompileResult, parse_error_result};
+use span::Span;
+use super::tokenqueue::TokenQueue;
+use super::tokens::{Token, TokenKind};
+
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/*
+    Macros are expanded on the tokens, before parsing. A macro has rules, which are tried in order,
+    the first one whose pattern matches the tokens between the parentheses of an invocation is expanded:
+
+    macro max:
+        ($a) => $a
+        ($a, $($rest),*) => if $a > max!($($rest),*): $a else max!($($rest),*)
+
+    In a pattern, $name matches the tokens up to the token which follows it in the pattern or a comma, or
+    one token tree (a token or a group in parentheses, brackets or braces) when a fragment follows it.
+    $( pattern ) sep * matches the pattern zero or more times, separated by the optional token sep.
+    In the body, $name is replaced by what it matched, in parentheses when that is more than one token,
+    and $( body ) sep * is repeated for every match of the fragments it uses.
+
+    macro name(a, b): body is a macro with one rule, in which a and b are written without $.
+*/
+
+#[derive(Debug)]
+enum PatternElement
+{
+    Token(TokenKind),
+    Fragment(String),
+    Repetition(Vec<PatternElement>, Option<TokenKind>),
+}
+
+#[derive(Debug)]
+enum BodyElement
+{
+    Token(TokenKind),
+    Fragment(String),
+    Repetition(Vec<BodyElement>, Option<TokenKind>),
+}
+
+struct Rule
+{
+    pattern: Vec<PatternElement>,
+    body: Vec<BodyElement>,
+    // Bindings introduced by the body, which are renamed on expansion
+    introduced: HashSet<String>,
+}
+
+struct Macro
+{
+    rules: Vec<Rule>,
+}
+
+// What a fragment matched, for a fragment in a repetition one match per repetition
+#[derive(Clone)]
+enum Match
+{
+    Tokens(Vec<Token>),
+    Repeated(Vec<Match>),
+}
+
+type Bindings = HashMap<String, Match>;
+
+fn is_kind(tok: Option<&Token>, kind: &TokenKind) -> bool
+{
+    tok.map(|t| t.kind == *kind).unwrap_or(false)
+}
+
+fn is_end_of_line(tok: &Token) -> bool
+{
+    match tok.kind
+    {
+        TokenKind::Indent(_) | TokenKind::EOF => true,
+        _ => false,
+    }
+}
+
+fn is_open(kind: &TokenKind) -> bool
+{
+    match *kind
+    {
+        TokenKind::OpenParen | TokenKind::OpenBracket | TokenKind::OpenCurly => true,
+        _ => false,
+    }
+}
+
+fn is_close(kind: &TokenKind) -> bool
+{
+    match *kind
+    {
+        TokenKind::CloseParen | TokenKind::CloseBracket | TokenKind::CloseCurly => true,
+        _ => false,
+    }
+}
+
+// Index after the token tree starting at start, a group ends at its closing token
+fn token_tree_end(tokens: &[Token], start: usize) -> usize
+{
+    let mut depth = 0;
+    let mut idx = start;
+    while idx < tokens.len()
+    {
+        let kind = &tokens[idx].kind;
+        idx += 1;
+        if is_open(kind) {
+            depth += 1;
+        } else if is_close(kind) {
+            depth -= 1;
+        }
+
+        if depth <= 0 {
+            break;
+        }
+    }
+    idx
+}
+
+// The tokens of a group, start is the index of its opening token. Returns them and the index after the closing token.
+fn group(tokens: &[Token], start: usize, span: &Span, what: &str) -> CompileResult<(Vec<Token>, usize)>
+{
+    let mut depth = 0;
+    let mut idx = start;
+    while idx < tokens.len() && !is_end_of_line(&tokens[idx])
+    {
+        let kind = &tokens[idx].kind;
+        if is_open(kind) {
+            depth += 1;
+        } else if is_close(kind) {
+            depth -= 1;
+            if depth == 0 {
+                return Ok((tokens[start + 1..idx].to_vec(), idx + 1));
+            }
+        }
+        idx += 1;
+    }
+
+    parse_error_result(span, format!("Unterminated {}", what))
+}
+
+fn is_repetition_end(tok: Option<&Token>) -> bool
+{
+    is_kind(tok, &TokenKind::BinaryOperator(BinaryOperator::Mul))
+}
+
+// After the closing paren of $( ... ), an optional separator and the *, returns the separator and the index after the *
+fn parse_repetition_end(tokens: &[Token], idx: usize, span: &Span) -> CompileResult<(Option<TokenKind>, usize)>
+{
+    if is_repetition_end(tokens.get(idx)) {
+        Ok((None, idx + 1))
+    } else if idx < tokens.len() && is_repetition_end(tokens.get(idx + 1)) {
+        Ok((Some(tokens[idx].kind.clone()), idx + 2))
+    } else {
+        parse_error_result(span, "Expected * or a separator and * after $( ) in a macro")
+    }
+}
+
+fn parse_macro_pattern(tokens: &[Token], names: &mut HashSet<String>) -> CompileResult<Vec<PatternElement>>
+{
+    let mut pattern = Vec::new();
+    let mut idx = 0;
+    while idx < tokens.len()
+    {
+        let tok = &tokens[idx];
+        if tok.kind != TokenKind::Dollar {
+            pattern.push(PatternElement::Token(tok.kind.clone()));
+            idx += 1;
+            continue;
+        }
+
+        match tokens.get(idx + 1).map(|t| &t.kind) {
+            Some(&TokenKind::Identifier(ref name)) => {
+                if !names.insert(name.clone()) {
+                    return parse_error_result(&tok.span, format!("Fragment ${} appears twice in a macro pattern", name));
+                }
+                pattern.push(PatternElement::Fragment(name.clone()));
+                idx += 2;
+            },
+
+            Some(&TokenKind::OpenParen) => {
+                let (sub, next_idx) = group(tokens, idx + 1, &tok.span, "repetition in macro pattern")?;
+                let (separator, next_idx) = parse_repetition_end(tokens, next_idx, &tok.span)?;
+                pattern.push(PatternElement::Repetition(parse_macro_pattern(&sub, names)?, separator));
+                idx = next_idx;
+            },
+
+            _ => return parse_error_result(&tok.span, "Expected a fragment name or ( after $ in a macro pattern"),
+        }
+    }
+    Ok(pattern)
+}
+
+// In the body, $name is a fragment when the pattern has it, otherwise it is left alone, so generic types can be used
+fn parse_macro_body(tokens: &[Token], fragments: &HashSet<String>) -> CompileResult<Vec<BodyElement>>
+{
+    let mut body = Vec::new();
+    let mut idx = 0;
+    while idx < tokens.len()
+    {
+        let tok = &tokens[idx];
+        match (&tok.kind, tokens.get(idx + 1).map(|t| &t.kind)) {
+            (&TokenKind::Dollar, Some(&TokenKind::Identifier(ref name))) if fragments.contains(name) => {
+                body.push(BodyElement::Fragment(name.clone()));
+                idx += 2;
+            },
+
+            (&TokenKind::Dollar, Some(&TokenKind::OpenParen)) => {
+                let (sub, next_idx) = group(tokens, idx + 1, &tok.span, "repetition in macro body")?;
+                let (separator, next_idx) = parse_repetition_end(tokens, next_idx, &tok.span)?;
+                body.push(BodyElement::Repetition(parse_macro_body(&sub, fragments)?, separator));
+                idx = next_idx;
+            },
+
+            _ => {
+                body.push(BodyElement::Token(tok.kind.clone()));
+                idx += 1;
+            },
+        }
+    }
+    Ok(body)
+}
+
+fn introduced_bindings(body: &[BodyElement], introduced: &mut HashSet<String>)
+{
+    for (idx, element) in body.iter().enumerate() {
+        match *element {
+            BodyElement::Token(TokenKind::Let) | BodyElement::Token(TokenKind::Var) => {
+                if let Some(&BodyElement::Token(TokenKind::Identifier(ref name))) = body.get(idx + 1) {
+                    introduced.insert(name.clone());
+                }
+            },
+            BodyElement::Repetition(ref sub, _) => introduced_bindings(sub, introduced),
+            _ => (),
+        }
+    }
+}
+
+fn new_rule(pattern: Vec<PatternElement>, body: Vec<BodyElement>) -> Rule
+{
+    let mut introduced = HashSet::new();
+    introduced_bindings(&body, &mut introduced);
+    Rule{pattern, body, introduced}
+}
+
+// The tokens up to the end of the line, which must not be empty
+fn rule_body(tokens: &[Token], start: usize, span: &Span, name: &str) -> CompileResult<(Vec<Token>, usize)>
+{
+    let mut idx = start;
+    while idx < tokens.len() && !is_end_of_line(&tokens[idx]) {
+        idx += 1;
+    }
+
+    if idx == start {
+        return parse_error_result(span, format!("Macro {} has an empty body", name));
+    }
+    Ok((tokens[start..idx].to_vec(), idx))
+}
+
+// A rule of a macro: ( pattern ) => body, starting at the opening paren
+fn parse_macro_rule(tokens: &[Token], start: usize, name: &str) -> CompileResult<(Rule, usize)>
+{
+    let rule_span = tokens[start].span.clone();
+    if tokens[start].kind != TokenKind::OpenParen {
+        return parse_error_result(&rule_span, format!("Unexpected token {}, expecting ( to start a rule of macro {}", tokens[start].kind, name));
+    }
+
+    let (pattern_tokens, idx) = group(tokens, start, &rule_span, "macro pattern")?;
+    let mut fragments = HashSet::new();
+    let pattern = parse_macro_pattern(&pattern_tokens, &mut fragments)?;
+    if !is_kind(tokens.get(idx), &TokenKind::FatArrow) {
+        return parse_error_result(&rule_span, format!("Expected => after the pattern of a rule of macro {}", name));
+    }
+
+    let (body_tokens, idx) = rule_body(tokens, idx + 1, &rule_span, name)?;
+    Ok((new_rule(pattern, parse_macro_body(&body_tokens, &fragments)?), idx))
+}
+
+fn parse_macro_declaration(tokens: &[Token], start: usize, macros: &mut HashMap<String, Macro>) -> CompileResult<usize>
+{
+    let macro_span = tokens[start].span.clone();
+    let mut idx = start + 1;
+    let next = |idx: &mut usize| -> CompileResult<Token> {
+        match tokens.get(*idx) {
+            Some(tok) => {
+                *idx += 1;
+                Ok(tok.clone())
+            },
+            None => parse_error_result(&macro_span, "Unexpected end of file in macro declaration"),
+        }
+    };
+
+    let name_tok = next(&mut idx)?;
+    let name = match name_tok.kind {
+        TokenKind::Identifier(ref name) => name.clone(),
+        _ => return parse_error_result(&name_tok.span, format!("Expected macro name, found {}", name_tok)),
+    };
+
+    let mut rules = Vec::new();
+    let tok = next(&mut idx)?;
+    match tok.kind
+    {
+        // One rule, with parameters instead of a pattern
+        TokenKind::OpenParen => {
+            let mut params = Vec::new();
+            let mut pattern = Vec::new();
+            loop
+            {
+                let tok = next(&mut idx)?;
+                match tok.kind
+                {
+                    TokenKind::CloseParen => break,
+                    TokenKind::Comma if !params.is_empty() => pattern.push(PatternElement::Token(TokenKind::Comma)),
+                    TokenKind::Identifier(ref p) => {
+                        if params.contains(p) {
+                            return parse_error_result(&tok.span, format!("Macro parameter {} declared twice", p));
+                        }
+                        params.push(p.clone());
+                        pattern.push(PatternElement::Fragment(p.clone()));
+                    },
+                    _ => return parse_error_result(&tok.span, format!("Expected macro parameter, found {}", tok)),
+                }
+            }
+
+            let tok = next(&mut idx)?;
+            if tok.kind != TokenKind::Colon {
+                return parse_error_result(&tok.span, format!("Unexpected token {}, expecting :", tok.kind));
+            }
+
+            let (body_tokens, next_idx) = rule_body(tokens, idx, &macro_span, &name)?;
+            let body = body_tokens.into_iter()
+                .map(|tok| match tok.kind {
+                    TokenKind::Identifier(ref p) if params.contains(p) => BodyElement::Fragment(p.clone()),
+                    kind => BodyElement::Token(kind),
+                })
+                .collect();
+            rules.push(new_rule(pattern, body));
+            idx = next_idx;
+        },
+
+        // Rules on the indented lines which follow
+        TokenKind::Colon => {
+            while let Some(&Token{kind: TokenKind::Indent(level), ..}) = tokens.get(idx) {
+                if level == 0 || idx + 1 >= tokens.len() {
+                    break;
+                }
+                let (rule, next_idx) = parse_macro_rule(tokens, idx + 1, &name)?;
+                rules.push(rule);
+                idx = next_idx;
+            }
+
+            if rules.is_empty() {
+                return parse_error_result(&macro_span, format!("Macro {} has no rules", name));
+            }
+        },
+
+        _ => return parse_error_result(&tok.span, format!("Unexpected token {}, expecting ( or :", tok.kind)),
+    }
+
+    if macros.contains_key(&name) {
+        return parse_error_result(&name_tok.span, format!("Macro {} redefined", name));
+    }
+
+    macros.insert(name, Macro{rules});
+    Ok(idx)
+}
+
+fn collect_macros(tokens: Vec<Token>, macros: &mut HashMap<String, Macro>) -> CompileResult<Vec<Token>>
+{
+    let mut remaining = Vec::with_capacity(tokens.len());
+    let mut idx = 0;
+    while idx < tokens.len()
+    {
+        if tokens[idx].kind == TokenKind::Macro {
+            if idx == 0 || tokens[idx - 1].kind != TokenKind::Indent(0) {
+                return parse_error_result(&tokens[idx].span, "Macros can only be declared at the top level of a module");
+            }
+            idx = parse_macro_declaration(&tokens, idx, macros)?;
+        } else {
+            remaining.push(tokens[idx].clone());
+            idx += 1;
+        }
+    }
+    Ok(remaining)
+}
+
+// The tokens at which a fragment or repetition in front of next stops, when next is a fragment it gets one token tree
+fn stops_before(next: Option<&PatternElement>, outer_stops: &[TokenKind]) -> Option<Vec<TokenKind>>
+{
+    match next {
+        Some(&PatternElement::Token(ref kind)) => Some(vec![kind.clone()]),
+        Some(&PatternElement::Fragment(_)) => None,
+        Some(&PatternElement::Repetition(ref sub, _)) => match sub.first() {
+            Some(&PatternElement::Token(ref kind)) => Some(vec![kind.clone()]),
+            _ => None,
+        },
+        None => Some(outer_stops.to_vec()),
+    }
+}
+
+// Commas outside a group separate arguments, so a fragment never contains one
+fn is_stop(tok: Option<&Token>, stops: &[TokenKind]) -> bool
+{
+    tok.map(|t| t.kind == TokenKind::Comma || stops.contains(&t.kind)).unwrap_or(true)
+}
+
+fn add_fragment_names(pattern: &[PatternElement], names: &mut Vec<String>)
+{
+    for element in pattern {
+        match *element {
+            PatternElement::Fragment(ref name) => names.push(name.clone()),
+            PatternElement::Repetition(ref sub, _) => add_fragment_names(sub, names),
+            PatternElement::Token(_) => (),
+        }
+    }
+}
+
+// Matches pattern against the start of tokens, returns what the fragments matched and the number of tokens matched
+fn match_pattern(pattern: &[PatternElement], tokens: &[Token], outer_stops: &[TokenKind]) -> Option<(Bindings, usize)>
+{
+    let mut bindings = Bindings::new();
+    let mut pos = 0;
+    for (idx, element) in pattern.iter().enumerate()
+    {
+        match *element
+        {
+            PatternElement::Token(ref kind) => {
+                if !is_kind(tokens.get(pos), kind) {
+                    return None;
+                }
+                pos += 1;
+            },
+
+            PatternElement::Fragment(ref name) => {
+                let start = pos;
+                match stops_before(pattern.get(idx + 1), outer_stops) {
+                    Some(stops) => {
+                        while !is_stop(tokens.get(pos), &stops) {
+                            pos = token_tree_end(tokens, pos);
+                        }
+                    },
+                    None if !is_stop(tokens.get(pos), &[]) => pos = token_tree_end(tokens, pos),
+                    None => (),
+                }
+
+                if pos == start || pos > tokens.len() {
+                    return None;
+                }
+                bindings.insert(name.clone(), Match::Tokens(tokens[start..pos].to_vec()));
+            },
+
+            PatternElement::Repetition(ref sub, ref separator) => {
+                let stops = stops_before(pattern.get(idx + 1), outer_stops).unwrap_or_default();
+                let mut inner_stops = stops.clone();
+                inner_stops.extend(separator.iter().cloned());
+
+                let mut repetitions = Vec::new();
+                while pos < tokens.len() && !is_stop(tokens.get(pos), &stops) {
+                    let (sub_bindings, len) = match match_pattern(sub, &tokens[pos..], &inner_stops) {
+                        Some((ref b, len)) if len > 0 => (b.clone(), len),
+                        _ => break,
+                    };
+                    repetitions.push(sub_bindings);
+                    pos += len;
+
+                    match *separator {
+                        Some(ref sep) if is_kind(tokens.get(pos), sep) => pos += 1,
+                        Some(_) => break,
+                        None => (),
+                    }
+                }
+
+                let mut names = Vec::new();
+                add_fragment_names(sub, &mut names);
+                for name in names {
+                    let matches = repetitions.iter().map(|r| r[&name].clone()).collect();
+                    bindings.insert(name, Match::Repeated(matches));
+                }
+            },
+        }
+    }
+    Some((bindings, pos))
+}
+
+fn add_body_fragment_names(body: &[BodyElement], names: &mut Vec<String>)
+{
+    for element in body {
+        match *element {
+            BodyElement::Fragment(ref name) => names.push(name.clone()),
+            BodyElement::Repetition(ref sub, _) => add_body_fragment_names(sub, names),
+            BodyElement::Token(_) => (),
+        }
+    }
+}
+
+fn transcribe(rule: &Rule, body: &[BodyElement], bindings: &Bindings, call_span: &Span, expansion: usize, result: &mut Vec<Token>) -> CompileResult<()>
+{
+    for element in body
+    {
+        match *element
+        {
+            // Bindings introduced by the macro body get a name which cannot be written in source code,
+            // so they never clash with names at the call site
+            BodyElement::Token(TokenKind::Identifier(ref name)) if rule.introduced.contains(name) => {
+                result.push(Token::new(TokenKind::Identifier(format!("{}#{}", name, expansion)), call_span.clone()));
+            },
+
+            BodyElement::Token(ref kind) => result.push(Token::new(kind.clone(), call_span.clone())),
+
+            BodyElement::Fragment(ref name) => match bindings[name] {
+                Match::Tokens(ref tokens) if tokens.len() == 1 => result.push(tokens[0].clone()),
+                Match::Tokens(ref tokens) => {
+                    result.push(Token::new(TokenKind::OpenParen, call_span.clone()));
+                    result.extend(tokens.iter().cloned());
+                    result.push(Token::new(TokenKind::CloseParen, call_span.clone()));
+                },
+                Match::Repeated(_) => {
+                    return parse_error_result(call_span, format!("Fragment ${} is repeated, so it can only be used in $( )*", name));
+                },
+            },
+
+            BodyElement::Repetition(ref sub, ref separator) => {
+                let mut names = Vec::new();
+                add_body_fragment_names(sub, &mut names);
+                let repeated: Vec<(&String, &Vec<Match>)> = names.iter()
+                    .filter_map(|name| match bindings[name] {
+                        Match::Repeated(ref matches) => Some((name, matches)),
+                        Match::Tokens(_) => None,
+                    })
+                    .collect();
+
+                let count = match repeated.first() {
+                    Some(&(_, matches)) => matches.len(),
+                    None => return parse_error_result(call_span, "$( )* in a macro body needs a repeated fragment"),
+                };
+
+                if let Some(&(name, _)) = repeated.iter().find(|&&(_, matches)| matches.len() != count) {
+                    return parse_error_result(call_span, format!("Fragments ${} and ${} are repeated a different number of times", repeated[0].0, name));
+                }
+
+                for idx in 0..count {
+                    if idx > 0 {
+                        if let Some(ref sep) = *separator {
+                            result.push(Token::new(sep.clone(), call_span.clone()));
+                        }
+                    }
+
+                    let mut iteration = bindings.clone();
+                    for &(name, matches) in &repeated {
+                        iteration.insert(name.clone(), matches[idx].clone());
+                    }
+                    transcribe(rule, sub, &iteration, call_span, expansion, result)?;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn instantiate_macro(rule: &Rule, bindings: &Bindings, call_span: &Span, expansion: usize) -> CompileResult<Vec<Token>>
+{
+    let mut result = Vec::new();
+    transcribe(rule, &rule.body, bindings, call_span, expansion, &mut result)?;
+
+    // Bodies which introduce bindings are statements, the rest are expressions
+    let is_statement = result.iter().any(|tok| match tok.kind {
+        TokenKind::Let | TokenKind::Var | TokenKind::SemiColon => true,
+        _ => false,
+    });
+
+    if !is_statement {
+        result.insert(0, Token::new(TokenKind::OpenParen, call_span.clone()));
+        result.push(Token::new(TokenKind::CloseParen, call_span.clone()));
+    }
+    Ok(result)
+}
+
+fn expand(tokens: Vec<Token>, macros: &HashMap<String, Macro>, expansion_count: &mut usize, depth: usize) -> CompileResult<Vec<Token>>
+{
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut idx = 0;
+    while idx < tokens.len()
+    {
+        let macro_name = match tokens[idx].kind {
+            TokenKind::Identifier(ref name) if macros.contains_key(name) &&
+                is_kind(tokens.get(idx + 1), &TokenKind::UnaryOperator(UnaryOperator::Not)) &&
+                is_kind(tokens.get(idx + 2), &TokenKind::OpenParen) => name.clone(),
+            _ => {
+                result.push(tokens[idx].clone());
+                idx += 1;
+                continue;
+            }
+        };
+
+        let call_span = tokens[idx].span.clone();
+        if depth >= MAX_EXPANSION_DEPTH {
+            return parse_error_result(&call_span, format!("Expansion of macro {} is nested too deeply", macro_name));
+        }
+
+        let (args, next_idx) = group(&tokens, idx + 2, &call_span, "macro invocation")?;
+        let m = &macros[&macro_name];
+        let matched = m.rules.iter()
+            .filter_map(|rule| match match_pattern(&rule.pattern, &args, &[]) {
+                Some((bindings, len)) if len == args.len() => Some((rule, bindings)),
+                _ => None,
+            })
+            .next();
+
+        let (rule, bindings) = match matched {
+            Some(m) => m,
+            None => return parse_error_result(&call_span, format!("No rule of macro {} matches the arguments of this invocation", macro_name)),
+        };
+
+        *expansion_count += 1;
+        let body = instantiate_macro(rule, &bindings, &call_span, *expansion_count)?;
+        result.extend(expand(body, macros, expansion_count, depth + 1)?);
+        idx = next_idx;
+    }
+
+    Ok(result)
+}
+
+pub fn expand_macros(tq: TokenQueue) -> CompileResult<TokenQueue>
+{
+    let mut macros = HashMap::new();
+    let tokens = collect_macros(tq.collect(), &mut macros)?;
+    let mut expansion_count = 0;
+    let mut result = TokenQueue::new();
+    for tok in expand(tokens, &macros, &mut expansion_count, 0)? {
+        result.add(tok);
+    }
+    Ok(result)
+}
@@ -1,4 +1,8 @@
+mod derive;
+mod grammar;
+mod highlight;
 mod lexer;
+mod macros;
 mod tokens;
 mod tokenqueue;
 
@@ -7,21 +11,28 @@ mod tests;
 
 #[cfg(test)]
 pub use self::tests::{th_expr, th_mod};
+pub use self::grammar::grammar_to_ebnf;
+pub use self::highlight::{syntax_definition, SyntaxFormat};
 
+use std::collections::HashMap;
 use std::path::{Path};
 use std::fs;
 use std::io::{Read};
 use std::rc::Rc;
+use std::mem;
 use std::ops::Deref;
 
 use ast::*;
 use timer::time_operation;
-use compileerror::{CompileResult, parse_error_result};
+use compileerror::{CompileResult, Diagnostics, parse_error_result, register_source};
 use span::{Span};
 use target::Target;
+use buildinfo::build_info_global_name;
 
 use self::tokenqueue::{TokenQueue};
 use self::lexer::{Lexer};
+use self::derive::derived_functions;
+use self::macros::expand_macros;
 use self::tokens::{Token, TokenKind};
 
 fn is_end_of_expression(tok: &Token) -> bool
@@ -33,7 +44,10 @@ fn is_end_of_expression(tok: &Token) -> bool
         TokenKind::Number(_) |
         TokenKind::Identifier(_) |
         TokenKind::StringLiteral(_) |
+        TokenKind::ByteStringLiteral(_) |
+        TokenKind::StringInterpolationStart(_) |
         TokenKind::Assign(_) |
+        TokenKind::OptionalDot |
         TokenKind::OpenParen |
         TokenKind::OpenBracket |
         TokenKind::OpenCurly => false,
@@ -143,69 +157,56 @@ fn parse_name(tq: &mut TokenQueue, id: String, span: &Span) -> CompileResult<Nam
     Ok(NameRef::new(name, span.expanded(tq.pos())))
 }
 
+// The operand of a unary operator only includes the binary operators which bind tighter, so -a * b is (-a) * b
 fn parse_unary_expression(tq: &mut TokenQueue, op: UnaryOperator, op_span: &Span, indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
-    let se = parse_expression(tq, indent_level, target)?;
+    let se = parse_operand(tq, UNARY_PRECEDENCE + 1, indent_level, target)?;
     Ok(unary_op(op, se, op_span.expanded(tq.pos())))
 }
 
-fn combine_binary_op(op: BinaryOperator, lhs: Expression, rhs: Expression) -> Expression
+fn parse_operand(tq: &mut TokenQueue, min_precedence: usize, indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
-    if lhs.is_binary_op() && lhs.precedence() < op.precedence()
-    {
-        let bop = lhs.extract_binary_op().expect("Not a binary op");
-        let nrhs = combine_binary_op(op, bop.right.clone(), rhs);
-        let span = Span::merge(&bop.left.span(), &nrhs.span());
-        bin_op(bop.operator, bop.left.clone(), nrhs, span)
-    }
-    else
-    {
-        let span = Span::merge(&lhs.span(), &rhs.span());
-        bin_op(op, lhs, rhs, span)
-    }
-}
-
-fn combine_type_cast(lhs: Expression, destination_type: Type, span: Span) -> Expression
-{
-    if lhs.is_binary_op()
-    {
-        let bop = lhs.extract_binary_op().expect("Not a binary op");
-        let nrhs = combine_type_cast(bop.right.clone(), destination_type, span);
-        let span = Span::merge(&bop.left.span(), &nrhs.span());
-        bin_op(bop.operator, bop.left, nrhs, span)
-    }
-    else
-    {
-        type_cast(lhs, destination_type, span)
-    }
+    let tok = tq.pop()?;
+    let start = parse_expression_start(tq, tok, indent_level, target)?;
+    parse_binary_op_rhs(tq, start, min_precedence, indent_level, target)
 }
 
-fn parse_binary_op_rhs(tq: &mut TokenQueue, mut lhs: Expression, indent_level: usize, target: &Target) -> CompileResult<Expression>
+// Precedence climbing, adds the binary operators with at least min_precedence to lhs, following the operator table
+fn parse_binary_op_rhs(tq: &mut TokenQueue, mut lhs: Expression, min_precedence: usize, indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
-    //use ast::TreePrinter;
-
     loop
     {
         if tq.peek().map(|tok| is_end_of_expression(tok)).unwrap_or(false) {
             return Ok(lhs);
         }
 
-        if !tq.is_next_binary_operator() {
-            return Ok(lhs);
-        }
+        let op = match tq.is_next_binary_operator() {
+            Some(op) if op.precedence() >= min_precedence => op,
+            _ => return Ok(lhs),
+        };
 
-        let op = tq.expect_binary_operator()?;
+        tq.pop()?;
         if op == BinaryOperator::As {
             let typ = parse_type(tq, indent_level, target)?;
             let span = lhs.span().expanded(tq.pos());
-            lhs = combine_type_cast(lhs, typ, span);
+            lhs = type_cast(lhs, typ, span);
             continue;
         }
 
-
-        let next_tok = tq.pop()?;
-        let rhs = parse_expression_start(tq, next_tok, indent_level, target)?;
-        lhs = combine_binary_op(op, lhs, rhs);
+        // All operators are left associative or cannot be chained, so the rhs only takes tighter binding operators
+        let rhs = parse_operand(tq, op.precedence() + 1, indent_level, target)?;
+        let span = Span::merge(&lhs.span(), &rhs.span());
+        lhs = bin_op(op, lhs, rhs, span);
+
+        if op.associativity() == Associativity::None {
+            match tq.is_next_binary_operator() {
+                Some(next) if next.precedence() == op.precedence() => {
+                    let span = tq.pop()?.span;
+                    return parse_error_result(&span, format!("Operator {} cannot be chained with {}, use parentheses or &&", next, op));
+                },
+                _ => (),
+            }
+        }
     }
 }
 
@@ -243,7 +244,14 @@ fn parse_comma_separated_list<T, P>(tq: &mut TokenQueue, end_token: &TokenKind,
 fn parse_function_call(tq: &mut TokenQueue, name: NameRef, indent_level: usize, target: &Target) -> CompileResult<Call>
 {
     tq.expect(&TokenKind::OpenParen)?;
-    let args = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+    let mut args = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+    if tq.is_next(&TokenKind::Func) {
+        let tok = tq.pop()?;
+        args.push(parse_trailing_lambda(tq, &tok.span, indent_level, target)?);
+    } else if tq.is_next(&TokenKind::Backslash) {
+        let tok = tq.pop()?;
+        args.push(parse_shorthand_lambda(tq, &tok.span, indent_level, target)?);
+    }
     let span = name.span.expanded(tq.pos());
     Ok(Call::new(name, args, span))
 }
@@ -305,7 +313,7 @@ fn parse_start_of_type(tq: &mut TokenQueue, indent_level: usize, target: &Target
                 parse_type, indent_level,
                 target
             )?;
-            Ok(generic_type_with_constraints(constraints))
+            Ok(generic_type_with_constraints("", constraints))
         }
         else
         {
@@ -364,6 +372,12 @@ fn parse_type(tq: &mut TokenQueue, indent_level: usize, target: &Target) -> Comp
         if tq.is_next(&TokenKind::CloseBracket) {
             tq.pop()?;
             typ = slice_type(typ);
+        } else if tq.is_next(&TokenKind::Dollar) {
+            // int[$N], the length becomes known when a function using it is instantiated
+            tq.pop()?;
+            let (name, _span) = tq.expect_identifier()?;
+            typ = generic_array_type(typ, &name);
+            tq.expect(&TokenKind::CloseBracket)?;
         } else {
             let (len, _span) = tq.expect_int()?;
             typ = array_type(typ, len as usize);
@@ -374,7 +388,36 @@ fn parse_type(tq: &mut TokenQueue, indent_level: usize, target: &Target) -> Comp
     Ok(typ)
 }
 
-fn parse_function_argument(tq: &mut TokenQueue, self_type: &Type, indent_level: usize, target: &Target) -> CompileResult<Argument>
+// Point{x, y} or (a, b): Pair, an argument which is destructured at the start of the body
+fn is_argument_pattern(tq: &TokenQueue) -> bool
+{
+    let struct_name = match tq.peek() {
+        Some(tok) => if let TokenKind::Identifier(_) = tok.kind {true} else {false},
+        None => false,
+    };
+    tq.is_next(&TokenKind::OpenParen) || tq.is_next(&TokenKind::OpenCurly) || (struct_name && tq.is_next_at(1, &TokenKind::OpenCurly))
+}
+
+// The argument gets the pattern as name, which can't clash with other names
+fn parse_argument_pattern(tq: &mut TokenQueue, mutable: bool, indent_level: usize, target: &Target) -> CompileResult<(Argument, Option<BindingType>)>
+{
+    let (pattern, typ, span) = if tq.is_next(&TokenKind::OpenParen) || tq.is_next(&TokenKind::OpenCurly) {
+        let (pattern, span) = parse_binding_type(tq, indent_level, target)?;
+        tq.expect(&TokenKind::Colon)?;
+        let typ = parse_type(tq, indent_level, target)?;
+        (pattern, typ, span)
+    } else {
+        let (name, span) = tq.expect_identifier()?;
+        let pattern = parse_struct_pattern(tq, &name, &span, indent_level, target)?;
+        let span = pattern.span.clone();
+        (BindingType::Struct(pattern), unresolved_type(&name, Vec::new()), span)
+    };
+
+    let arg = Argument::new(pattern.to_string(), typ, mutable, span.expanded(tq.pos()));
+    Ok((arg, Some(pattern)))
+}
+
+fn parse_function_argument(tq: &mut TokenQueue, self_type: &Type, indent_level: usize, target: &Target) -> CompileResult<(Argument, Option<BindingType>)>
 {
     let mutable = if tq.is_next(&TokenKind::Var) {
         tq.pop()?;
@@ -383,10 +426,38 @@ fn parse_function_argument(tq: &mut TokenQueue, self_type: &Type, indent_level:
         false
     };
 
+    let by_ref = if tq.is_next(&TokenKind::Ref) {
+        tq.pop()?;
+        true
+    } else {
+        false
+    };
+
+    if is_argument_pattern(tq) {
+        if by_ref {
+            let span = tq.peek().expect("Unexpected EOF").span.clone();
+            return parse_error_result(&span, "A ref argument cannot be a pattern");
+        }
+        return parse_argument_pattern(tq, mutable, indent_level, target);
+    }
+
     let (name, span) = tq.expect_identifier()?;
+    if by_ref && mutable {
+        return parse_error_result(&span, "An argument cannot be both var and ref");
+    }
+
+    if by_ref && name == "self" {
+        return parse_error_result(&span, "self is already passed by pointer, it cannot be a ref argument");
+    }
+
     let typ = if tq.is_next(&TokenKind::Colon) {
         tq.expect(&TokenKind::Colon)?;
-        parse_type(tq, indent_level, target)?
+        let typ = parse_type(tq, indent_level, target)?;
+        if tq.is_next(&TokenKind::Colon) {
+            parse_generic_constraints(tq, typ, &span, indent_level, target)?
+        } else {
+            typ
+        }
     } else if name == "self" {
         if *self_type != Type::Unknown {
             self_type.clone()
@@ -397,29 +468,127 @@ fn parse_function_argument(tq: &mut TokenQueue, self_type: &Type, indent_level:
         generic_type(&name) // If the type is not known threat it as generic arg
     };
 
-    Ok(Argument::new(name, typ, mutable, span.expanded(tq.pos())))
+    if by_ref {
+        let mut arg = Argument::new(name, ptr_type(typ), false, span.expanded(tq.pos()));
+        arg.by_ref = true;
+        return Ok((arg, None));
+    }
+    Ok((Argument::new(name, typ, mutable, span.expanded(tq.pos())), None))
 }
 
-fn parse_function_arguments(tq: &mut TokenQueue, self_type: &Type, indent_level: usize, target: &Target) -> CompileResult<Vec<Argument>>
+// $T: A + B, the generic has to implement the interfaces A and B
+fn parse_generic_constraints(tq: &mut TokenQueue, typ: Type, span: &Span, indent_level: usize, target: &Target) -> CompileResult<Type>
+{
+    let name = match typ {
+        Type::Generic(ref gt) => match **gt {
+            GenericType::Any(ref name) => name.clone(),
+            GenericType::Restricted(..) => return parse_error_result(span, format!("Generic type {} already has constraints", typ)),
+            GenericType::Length(_) => return parse_error_result(span, format!("Only generic types can have interface constraints, not {}", typ)),
+        },
+        _ => return parse_error_result(span, format!("Only generic types can have interface constraints, not {}", typ)),
+    };
+
+    tq.expect(&TokenKind::Colon)?;
+    let mut constraints = vec![parse_type(tq, indent_level, target)?];
+    while tq.is_next(&TokenKind::BinaryOperator(BinaryOperator::Add)) {
+        tq.pop()?;
+        constraints.push(parse_type(tq, indent_level, target)?);
+    }
+    Ok(generic_type_with_constraints(&name, constraints))
+}
+
+fn constrain_generics(typ: &Type, constrained: &HashMap<String, Type>) -> Type
+{
+    match *typ {
+        Type::Generic(ref gt) => match **gt {
+            GenericType::Any(ref name) => constrained.get(name).cloned().unwrap_or_else(|| typ.clone()),
+            GenericType::Restricted(..) | GenericType::Length(_) => typ.clone(),
+        },
+        Type::Pointer(ref inner) => ptr_type(constrain_generics(inner, constrained)),
+        Type::Optional(ref inner) => optional_type(constrain_generics(inner, constrained)),
+        Type::Array(ref at) => match at.generic_len {
+            Some(ref name) => generic_array_type(constrain_generics(&at.element_type, constrained), name),
+            None => array_type(constrain_generics(&at.element_type, constrained), at.len),
+        },
+        Type::Slice(ref st) => slice_type(constrain_generics(&st.element_type, constrained)),
+        Type::Func(ref ft) => func_type(
+            ft.args.iter().map(|a| constrain_generics(a, constrained)).collect(),
+            constrain_generics(&ft.return_type, constrained)
+        ),
+        Type::Unresolved(ref ut) => unresolved_type(&ut.name, ut.generic_args.iter().map(|a| constrain_generics(a, constrained)).collect()),
+        _ => typ.clone(),
+    }
+}
+
+// The constraints of a generic argument apply to every other use of the generic in the signature
+fn apply_generic_constraints(args: &mut Vec<Argument>, ret_type: Type) -> CompileResult<Type>
+{
+    let mut constrained: HashMap<String, Type> = HashMap::new();
+    for arg in args.iter() {
+        // The type of a ref argument is a pointer to the declared type
+        let declared = match arg.typ {
+            Type::Pointer(ref inner) if arg.by_ref => &**inner,
+            ref typ => typ,
+        };
+
+        if let Type::Generic(ref gt) = *declared {
+            if let GenericType::Restricted(ref name, _) = **gt {
+                if name.is_empty() {
+                    continue;
+                }
+
+                if constrained.insert(name.clone(), declared.clone()).is_some() {
+                    return parse_error_result(&arg.span, format!("Generic type ${} has constraints more than once", name));
+                }
+            }
+        }
+    }
+
+    for arg in args.iter_mut() {
+        arg.typ = constrain_generics(&arg.typ, &constrained);
+    }
+    Ok(constrain_generics(&ret_type, &constrained))
+}
+
+// The arguments of a function definition, with the patterns of the arguments declared as one
+fn parse_function_definition_arguments(tq: &mut TokenQueue, self_type: &Type, indent_level: usize, target: &Target) -> CompileResult<(Vec<Argument>, Vec<(String, BindingType)>)>
 {
     tq.expect(&TokenKind::OpenParen)?;
     let parse_arg = |tq: &mut TokenQueue, indent_level: usize, target: &Target| {
         parse_function_argument(tq, self_type, indent_level, target)
     };
     let args = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_arg, indent_level, target)?;
+    let patterns = args.iter()
+        .filter_map(|&(ref arg, ref pattern)| pattern.as_ref().map(|p| (arg.name.clone(), p.clone())))
+        .collect();
+    Ok((args.into_iter().map(|(arg, _)| arg).collect(), patterns))
+}
+
+fn parse_function_arguments(tq: &mut TokenQueue, self_type: &Type, indent_level: usize, target: &Target) -> CompileResult<Vec<Argument>>
+{
+    let start = tq.peek().map(|tok| tok.span.clone()).unwrap_or_default();
+    let (args, patterns) = parse_function_definition_arguments(tq, self_type, indent_level, target)?;
+    if !patterns.is_empty() {
+        return parse_error_result(&start.expanded(tq.pos()), "Patterns are only allowed as arguments of a function definition");
+    }
+
+    if args.iter().any(|a| a.by_ref) {
+        return parse_error_result(&start.expanded(tq.pos()), "Ref arguments are only allowed in a function definition");
+    }
     Ok(args)
 }
 
 fn parse_function_signature(tq: &mut TokenQueue, self_type: &Type, indent_level: usize, target: &Target) -> CompileResult<FunctionSignature>
 {
     let (name, name_span) = tq.expect_identifier()?;
-    let args = parse_function_arguments(tq, self_type, indent_level, target)?;
+    let mut args = parse_function_arguments(tq, self_type, indent_level, target)?;
     let ret_type = if tq.is_next(&TokenKind::Arrow) {
         tq.pop()?;
         parse_type(tq, indent_level, target)?
     } else {
         Type::Void
     };
+    let ret_type = apply_generic_constraints(&mut args, ret_type)?;
 
     let sig_span_end = tq.pos();
     Ok(sig(&name, ret_type, args, name_span.expanded(sig_span_end)))
@@ -463,20 +632,29 @@ fn parse_function_declaration(tq: &mut TokenQueue, namespace: &str, span: &Span,
         },
     };
 
-    let args = parse_function_arguments(tq, &self_type, indent_level, target)?;
+    parse_function_definition(tq, &full_name, &self_type, span, indent_level, target)
+}
+
+// Parse the arguments, return type and body of a function
+fn parse_function_definition(tq: &mut TokenQueue, full_name: &str, self_type: &Type, span: &Span, indent_level: usize, target: &Target) -> CompileResult<Function>
+{
+    let (mut args, arg_patterns) = parse_function_definition_arguments(tq, self_type, indent_level, target)?;
     let ret_type = if tq.is_next(&TokenKind::Arrow) {
         tq.pop()?;
         parse_type(tq, indent_level, target)?
     } else {
         Type::Void
     };
+    let ret_type = apply_generic_constraints(&mut args, ret_type)?;
 
-    let signature = sig(&full_name, ret_type, args, span.expanded(tq.pos()));
+    let signature = sig(full_name, ret_type, args, span.expanded(tq.pos()));
     tq.expect(&TokenKind::Colon)?;
 
     let expr = parse_block(tq, &span.file, indent_level, target)?;
     let func_span = span.expanded(expr.span().end);
-    Ok(Function::new(signature, true, expr, func_span))
+    let mut func = Function::new(signature, false, expr, func_span);
+    func.arg_patterns = arg_patterns;
+    Ok(func)
 }
 
 fn parse_struct_pattern(tq: &mut TokenQueue, name: &str, span: &Span, indent_level: usize, target: &Target) -> CompileResult<StructPattern>
@@ -594,9 +772,35 @@ fn parse_lambda(tq: &mut TokenQueue, span: &Span, indent_level: usize, target: &
     Ok(lambda(args, expr, span.expanded(tq.pos())))
 }
 
+// A lambda after the arguments of a call is its last argument: each(xs) fn(x) -> print(x).
+// The body can also be an indented block after a colon.
+fn parse_trailing_lambda(tq: &mut TokenQueue, span: &Span, indent_level: usize, target: &Target) -> CompileResult<Expression>
+{
+    let args = parse_function_arguments(tq, &Type::Unknown, indent_level, target)?;
+    let expr = if tq.is_next(&TokenKind::Colon) {
+        tq.pop()?;
+        parse_block(tq, &span.file, indent_level, target)?
+    } else {
+        tq.expect(&TokenKind::Arrow)?;
+        parse_expression(tq, indent_level, target)?
+    };
+    Ok(lambda(args, expr, span.expanded(tq.pos())))
+}
+
+// \(it * 2) is a lambda with a single argument named it: fn(it) -> it * 2
+fn parse_shorthand_lambda(tq: &mut TokenQueue, span: &Span, indent_level: usize, target: &Target) -> CompileResult<Expression>
+{
+    let open = tq.expect(&TokenKind::OpenParen)?;
+    let expr = parse_block(tq, &span.file, indent_level, target)?;
+    tq.expect(&TokenKind::CloseParen)?;
+    let arg = Argument::new("it", generic_type("it"), false, open.span);
+    Ok(lambda(vec![arg], expr, span.expanded(tq.pos())))
+}
+
 fn is_end_of_bindings(tq: &mut TokenQueue, indent_level: usize) -> bool
 {
     tq.is_next(&TokenKind::In) ||
+    tq.is_next(&TokenKind::Else) ||
     tq.is_next(&TokenKind::SemiColon) ||
     tq.is_next(&TokenKind::CloseParen) ||
     tq.is_next(&TokenKind::CloseCurly) ||
@@ -604,6 +808,31 @@ fn is_end_of_bindings(tq: &mut TokenQueue, indent_level: usize) -> bool
     !tq.is_in_same_block(indent_level + 1)
 }
 
+// A name, a struct pattern {a, b} or a tuple pattern (a, b), whose elements can be patterns again
+fn parse_binding_type(tq: &mut TokenQueue, indent_level: usize, target: &Target) -> CompileResult<(BindingType, Span)>
+{
+    if tq.is_next(&TokenKind::OpenCurly) {
+        let span = tq.peek().expect("Unexpected EOF").span.clone();
+        let pattern = parse_struct_pattern(tq, "", &span, indent_level, target)?;
+        let span = pattern.span.clone();
+        Ok((BindingType::Struct(pattern), span))
+    } else if tq.is_next(&TokenKind::OpenParen) {
+        let open = tq.pop()?;
+        let parse_element = |tq: &mut TokenQueue, indent_level: usize, target: &Target| {
+            parse_binding_type(tq, indent_level, target).map(|(bt, _)| bt)
+        };
+        let elements = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_element, indent_level, target)?;
+        let span = open.span.expanded(tq.pos());
+        if elements.len() < 2 {
+            return parse_error_result(&span, "A tuple binding needs at least two elements");
+        }
+        Ok((BindingType::Tuple(tuple_pattern(elements, span.clone())), span))
+    } else {
+        let (name, span) = tq.expect_identifier()?;
+        Ok((BindingType::Name(name), span))
+    }
+}
+
 fn parse_bindings(tq: &mut TokenQueue, mutable: bool, indent_level: usize, target: &Target) -> CompileResult<Vec<Binding>>
 {
     let mut bindings = Vec::new();
@@ -611,15 +840,7 @@ fn parse_bindings(tq: &mut TokenQueue, mutable: bool, indent_level: usize, targe
     {
         tq.pop_indent()?;
 
-        let (binding_type, span) = if tq.is_next(&TokenKind::OpenCurly) {
-            let span = tq.peek().expect("Unexpected EOF").span.clone();
-            let pattern = parse_struct_pattern(tq, "", &span, indent_level, target)?;
-            let span = pattern.span.clone();
-            (BindingType::Struct(pattern), span)
-        } else {
-            let (name, span) = tq.expect_identifier()?;
-            (BindingType::Name(name), span)
-        };
+        let (binding_type, span) = parse_binding_type(tq, indent_level, target)?;
 
         tq.expect(&TokenKind::Assign(AssignOperator::Assign))?;
         let init = parse_expression(tq, indent_level, target)?;
@@ -663,29 +884,78 @@ fn parse_if(tq: &mut TokenQueue, span: &Span, indent_level: usize, target: &Targ
     }
 }
 
-fn parse_sum_type(tq: &mut TokenQueue, namespace: &str, span: &Span, indent_level: usize, target: &Target) -> CompileResult<SumTypeDeclaration>
+fn parse_sum_type_case(tq: &mut TokenQueue, namespace: &str, sum_type_name: &str, indent_level: usize, target: &Target) -> CompileResult<SumTypeCaseDeclaration>
 {
-    let (sum_type_name, _) = tq.expect_identifier()?;
-    tq.expect(&TokenKind::Colon)?;
+    if tq.is_next_at(1, &TokenKind::OpenCurly)
+    {
+        let sd = parse_struct_type(tq, sum_type_name, indent_level, target)?;
+        let span = sd.span.clone();
+        let name = namespaced(namespace, &sd.name);
+        Ok(sum_type_case_decl(&name, Some(sd), span))
+    }
+    else
+    {
+        let (case_name, case_name_span) = tq.expect_identifier()?;
+        let name = format!("{}::{}::{}", namespace, sum_type_name, case_name);
+        Ok(sum_type_case_decl(&name, None, case_name_span))
+    }
+}
 
-    let parse_sum_type_case = |tq: &mut TokenQueue, indent_level: usize, target: &Target| {
-        if tq.is_next_at(1, &TokenKind::OpenCurly)
-        {
-            let sd = parse_struct_type(tq, &sum_type_name, indent_level, target)?;
-            let span = sd.span.clone();
-            let name = namespaced(namespace, &sd.name);
-            Ok(sum_type_case_decl(&name, Some(sd), span))
+// Type parameters of a type declaration, like the $a in Option<$a>
+fn parse_generic_params(tq: &mut TokenQueue, type_name: &str, span: &Span, indent_level: usize, target: &Target) -> CompileResult<Vec<Type>>
+{
+    let params = parse_generic_arg_list(tq, indent_level, target)?;
+    for (idx, p) in params.iter().enumerate() {
+        match *p {
+            Type::Generic(_) => (),
+            _ => return parse_error_result(span, format!("Type parameter {} of {} must be a generic type like $a", p, type_name)),
         }
-        else
-        {
-            let (case_name, case_name_span) = tq.expect_identifier()?;
-            let name = format!("{}::{}::{}", namespace, sum_type_name, case_name);
-            Ok(sum_type_case_decl(&name, None, case_name_span))
+
+        if params[..idx].contains(p) {
+            return parse_error_result(span, format!("Type parameter {} of {} is declared twice", p, type_name));
         }
+    }
+
+    Ok(params)
+}
+
+fn parse_sum_type(tq: &mut TokenQueue, namespace: &str, span: &Span, indent_level: usize, target: &Target) -> CompileResult<SumTypeDeclaration>
+{
+    let (sum_type_name, name_span) = tq.expect_identifier()?;
+    let generic_params = parse_generic_params(tq, &sum_type_name, &name_span, indent_level, target)?;
+    tq.expect(&TokenKind::Colon)?;
+
+    let parse_case = |tq: &mut TokenQueue, indent_level: usize, target: &Target| {
+        parse_sum_type_case(tq, namespace, &sum_type_name, indent_level, target)
     };
-    let cases = parse_indented_block(tq, indent_level, parse_sum_type_case, target)?;
+    let cases = parse_indented_block(tq, indent_level, parse_case, target)?;
+
+    let mut st = sum_type_decl(&namespaced(namespace, &sum_type_name), cases, span.expanded(tq.pos()));
+    st.generic_params = generic_params;
+    Ok(st)
+}
+
+// type Name<$a> = CaseA{member: $a} | CaseB
+fn parse_type_declaration(tq: &mut TokenQueue, namespace: &str, span: &Span, indent_level: usize, target: &Target) -> CompileResult<SumTypeDeclaration>
+{
+    let (sum_type_name, name_span) = tq.expect_identifier()?;
+    let generic_params = parse_generic_params(tq, &sum_type_name, &name_span, indent_level, target)?;
+    tq.expect(&TokenKind::Assign(AssignOperator::Assign))?;
+
+    let mut cases = Vec::new();
+    loop
+    {
+        cases.push(parse_sum_type_case(tq, namespace, &sum_type_name, indent_level, target)?);
+        if tq.is_next(&TokenKind::Pipe) {
+            tq.pop()?;
+        } else {
+            break;
+        }
+    }
 
-    Ok(sum_type_decl(&namespaced(namespace, &sum_type_name), cases, span.expanded(tq.pos())))
+    let mut st = sum_type_decl(&namespaced(namespace, &sum_type_name), cases, span.expanded(tq.pos()));
+    st.generic_params = generic_params;
+    Ok(st)
 }
 
 fn namespaced(namespace: &str, name: &str) -> String
@@ -732,20 +1002,33 @@ fn parse_struct_initializer(tq: &mut TokenQueue, name: &NameRef, indent_level: u
 fn parse_member_access(tq: &mut TokenQueue, left_expr: Expression, indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
     let mut left = left_expr;
-    while tq.is_next(&TokenKind::BinaryOperator(BinaryOperator::Dot))
+    loop
     {
+        let optional = if tq.is_next(&TokenKind::BinaryOperator(BinaryOperator::Dot)) {
+            false
+        } else if tq.is_next(&TokenKind::OptionalDot) {
+            true
+        } else {
+            break;
+        };
+
         tq.pop()?;
         let (name, name_span) = tq.expect_identifier()?;
 
-        if tq.is_next(&TokenKind::OpenParen) {
+        let (ma, span) = if tq.is_next(&TokenKind::OpenParen) {
             let call = Box::new(parse_function_call(tq, NameRef::new(name, name_span), indent_level, target)?);
             let span = left.span().expanded(call.span.end);
-            left = member_access(left, MemberAccessType::Call(call), span);
+            (MemberAccessType::Call(call), span)
         } else {
-            let ma = MemberAccessType::Name(field(&name, 0));
             let span = left.span().expanded(name_span.end);
-            left = member_access(left, ma, span);
-        }
+            (MemberAccessType::Name(field(&name, 0)), span)
+        };
+
+        left = if optional {
+            optional_member_access(left, ma, span)
+        } else {
+            member_access(left, ma, span)
+        };
     }
 
     Ok(left)
@@ -777,10 +1060,57 @@ fn is_end_of_block(tq: &mut TokenQueue) -> bool
 
 
 fn parse_block(tq: &mut TokenQueue, current_file: &str, indent_level: usize, target: &Target) -> CompileResult<Expression>
+{
+    let block_indent_level = check_indent_level(tq, indent_level)?;
+    parse_block_expressions(tq, current_file, block_indent_level, target)
+}
+
+// let x = maybe else return default, binds x to the value of an optional, and runs the else part, which must
+// return, when it is nil. The rest of the block becomes the case of a match, where x is bound:
+//   match maybe:
+//       ?x => rest of the block
+//       nil => return default
+fn parse_guard_let(tq: &mut TokenQueue, binding: Expression, current_file: &str, block_indent_level: usize, target: &Target) -> CompileResult<Expression>
+{
+    let span = binding.span();
+    let (name, init, binding_span) = match binding {
+        Expression::Bindings(mut bl) => match (bl.bindings.len(), bl.bindings.pop()) {
+            (1, Some(Binding{mutable: false, binding_type: BindingType::Name(name), init, span, ..})) => (name, init, span),
+            _ => return parse_error_result(&span, "Only a let with a single name can have an else part"),
+        },
+        _ => return parse_error_result(&span, "Only a let can have an else part"),
+    };
+
+    let else_tok = tq.expect(&TokenKind::Else)?;
+    let on_nil = parse_expression(tq, block_indent_level, target)?;
+    match on_nil {
+        Expression::Return(_) => (),
+        _ => return parse_error_result(&on_nil.span(), "The else part of a let must return"),
+    }
+
+    let rest = parse_block_expressions(tq, current_file, block_indent_level, target)?;
+    let rest_span = binding_span.expanded(tq.pos());
+    let nil_span = else_tok.span.expanded(on_nil.span().end);
+    let cases = vec![
+        match_case(optional_pattern(name, binding_span), rest, rest_span),
+        match_case(Pattern::Nil(else_tok.span), on_nil, nil_span),
+    ];
+    Ok(match_expression(init, cases, span.expanded(tq.pos())))
+}
+
+// let x = foo() else return 0, the else has to be on the same line, otherwise it belongs to an if
+fn is_guard_let(tq: &TokenQueue, e: &Expression) -> bool
+{
+    match (e, tq.peek()) {
+        (&Expression::Bindings(_), Some(tok)) => tok.kind == TokenKind::Else && tok.span.start.line == e.span().end.line,
+        _ => false,
+    }
+}
+
+fn parse_block_expressions(tq: &mut TokenQueue, current_file: &str, block_indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
     let mut ends_with_semicolon = false;
     let mut expressions = Vec::new();
-    let block_indent_level = check_indent_level(tq, indent_level)?;
 
     while tq.is_in_same_block(block_indent_level)
     {
@@ -794,7 +1124,11 @@ fn parse_block(tq: &mut TokenQueue, current_file: &str, indent_level: usize, tar
         }
 
         let e = parse_expression(tq, block_indent_level, target)?;
-        if let Some(op) = tq.is_next_assign_operator() {
+        if is_guard_let(tq, &e) {
+            expressions.push(parse_guard_let(tq, e, current_file, block_indent_level, target)?);
+            ends_with_semicolon = false;
+            break;
+        } else if let Some(op) = tq.is_next_assign_operator() {
             tq.pop()?;
 
             let rhs = parse_expression(tq, block_indent_level, target)?;
@@ -841,20 +1175,65 @@ fn parse_while(tq: &mut TokenQueue, start: &Span, indent_level: usize, target: &
     let cond = parse_expression(tq, indent_level, target)?;
     tq.expect(&TokenKind::Colon)?;
     let body = parse_block(tq, &start.file, indent_level, target)?;
-    Ok(while_loop(cond, body, start.expanded(tq.pos())))
+    Ok(while_loop(cond, body, LoopHints::default(), start.expanded(tq.pos())))
 }
 
 
 fn parse_for(tq: &mut TokenQueue, start: &Span, indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
-    let (loop_variable, _) = tq.expect_identifier()?;
+    let (loop_variable, pattern) = match parse_binding_type(tq, indent_level, target)? {
+        (BindingType::Name(name), _) => (name, None),
+        (pattern, span) => (pattern.to_string(), Some((pattern, span))),
+    };
     tq.expect(&TokenKind::In)?;
 
     let iterable = parse_expression(tq, indent_level, target)?;
     tq.expect(&TokenKind::Colon)?;
 
     let body = parse_block(tq, &start.file, indent_level, target)?;
-    Ok(for_loop(&loop_variable, iterable, body, start.expanded(tq.pos())))
+    // for (k, v) in pairs: the loop variable is named after the pattern, and destructured at the start of the body
+    let body = match pattern {
+        Some((pattern, span)) => {
+            let element = binding(pattern, Expression::NameRef(NameRef::new(loop_variable.clone(), span.clone())), false, span.clone());
+            let body_span = body.span();
+            block(vec![bindings(vec![element], span), body], body_span)
+        },
+        None => body,
+    };
+    Ok(for_loop(&loop_variable, iterable, body, LoopHints::default(), start.expanded(tq.pos())))
+}
+
+// "a ${x} b" is turned into @format("a ", x, " b")
+fn parse_interpolated_string(tq: &mut TokenQueue, first: String, start: &Span, indent_level: usize, target: &Target) -> CompileResult<Expression>
+{
+    let mut parts = Vec::new();
+    if !first.is_empty() {
+        parts.push(Expression::Literal(Literal::String(start.clone(), first)));
+    }
+
+    loop {
+        parts.push(parse_expression(tq, indent_level, target)?);
+        let tok = tq.pop()?;
+        match tok.kind {
+            TokenKind::StringInterpolationMiddle(s) => {
+                if !s.is_empty() {
+                    parts.push(Expression::Literal(Literal::String(tok.span, s)));
+                }
+            }
+            TokenKind::StringInterpolationEnd(s) => {
+                if !s.is_empty() {
+                    parts.push(Expression::Literal(Literal::String(tok.span, s)));
+                }
+                break;
+            }
+            _ => return parse_error_result(&tok.span, format!("Expected end of interpolated expression, found {}", tok)),
+        }
+    }
+
+    Ok(Expression::CompilerCall(CompilerCall::Format{
+        parts,
+        span: start.expanded(tq.pos()),
+    }))
 }
 
 fn parse_compiler_call(tq: &mut TokenQueue, start: &Span, indent_level: usize, target: &Target) -> CompileResult<Expression>
@@ -885,13 +1264,237 @@ fn parse_compiler_call(tq: &mut TokenQueue, start: &Span, indent_level: usize, t
             }))
         }
 
-        _ => parse_error_result(&name_span, format!("Unknown compiler call {}", name))
+        "assert_eq" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let arguments = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+            let span = start.expanded(tq.pos());
+            if arguments.len() != 2 {
+                return parse_error_result(&span, "@assert_eq expects two arguments");
+            }
+
+            Ok(Expression::CompilerCall(CompilerCall::AssertEq{
+                left: Box::new(arguments[0].clone()),
+                right: Box::new(arguments[1].clone()),
+                span
+            }))
+        }
+
+        "assert" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let mut arguments = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+            let span = start.expanded(tq.pos());
+            if arguments.is_empty() {
+                return parse_error_result(&span, "@assert expects a condition");
+            }
+
+            let cond = arguments.remove(0);
+            Ok(Expression::CompilerCall(CompilerCall::Assert{
+                cond: Box::new(cond),
+                parts: arguments,
+                span
+            }))
+        }
+
+        "panic" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let parts = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+            Ok(Expression::CompilerCall(CompilerCall::Panic{
+                parts,
+                span: start.expanded(tq.pos()),
+            }))
+        }
+
+        "format" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let parts = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+            Ok(Expression::CompilerCall(CompilerCall::Format{
+                parts,
+                span: start.expanded(tq.pos()),
+            }))
+        }
+
+        "debug" | "info" | "warn" | "error" => {
+            let level = LogLevel::from_name(&name).expect("Unknown log level");
+            tq.expect(&TokenKind::OpenParen)?;
+            let parts = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+            Ok(Expression::CompilerCall(CompilerCall::Log{
+                level,
+                parts,
+                span: start.expanded(tq.pos()),
+            }))
+        }
+
+        "field_names" | "field_types" | "field_count" => {
+            let query = match &name[..] {
+                "field_names" => FieldQuery::Names,
+                "field_types" => FieldQuery::Types,
+                _ => FieldQuery::Count,
+            };
+
+            tq.expect(&TokenKind::OpenParen)?;
+            let typ = parse_type(tq, indent_level, target)?;
+            tq.expect(&TokenKind::CloseParen)?;
+            Ok(Expression::CompilerCall(CompilerCall::Fields(query, typ, start.expanded(tq.pos()))))
+        }
+
+        "field_values" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let value = parse_expression(tq, indent_level, target)?;
+            tq.expect(&TokenKind::CloseParen)?;
+            Ok(Expression::CompilerCall(CompilerCall::FieldValues(Box::new(value), start.expanded(tq.pos()))))
+        }
+
+        "include_str" | "include_bytes" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let (path, path_span) = tq.expect_string()?;
+            tq.expect(&TokenKind::CloseParen)?;
+            let span = start.expanded(tq.pos());
+            let data = read_included_file(&path, &path_span)?;
+            if name == "include_bytes" {
+                Ok(byte_array_literal(data, span))
+            } else {
+                match String::from_utf8(data) {
+                    Ok(s) => Ok(Expression::Literal(Literal::String(span, s))),
+                    Err(_) => parse_error_result(&path_span, format!("{} is not valid UTF-8, use @include_bytes instead", path)),
+                }
+            }
+        }
+
+        "build_info" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let (key, _) = tq.expect_string()?;
+            tq.expect(&TokenKind::CloseParen)?;
+            Ok(Expression::NameRef(NameRef::new(build_info_global_name(&key), start.expanded(tq.pos()))))
+        }
+
+        "offsetof" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let typ = parse_type(tq, indent_level, target)?;
+            tq.expect(&TokenKind::Comma)?;
+            let (field, _) = tq.expect_identifier()?;
+            tq.expect(&TokenKind::CloseParen)?;
+            Ok(Expression::CompilerCall(CompilerCall::OffsetOf{
+                typ,
+                field,
+                index: 0,
+                span: start.expanded(tq.pos()),
+            }))
+        }
+
+        "vectorize" | "unroll" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let (count, count_span) = tq.expect_int()?;
+            tq.expect(&TokenKind::CloseParen)?;
+            if name == "vectorize" && !count.is_power_of_two() {
+                return parse_error_result(&count_span, "@vectorize expects a power of two as width");
+            } else if count == 0 {
+                return parse_error_result(&count_span, "@unroll expects a count larger then zero");
+            }
+
+            let tok = tq.pop()?;
+            let mut lp = match tok.kind {
+                TokenKind::At => parse_compiler_call(tq, &tok.span, indent_level, target)?,
+                TokenKind::For => parse_for(tq, start, indent_level, target)?,
+                TokenKind::While => parse_while(tq, start, indent_level, target)?,
+                _ => return parse_error_result(&tok.span, format!("@{} must be followed by a for or while loop", name)),
+            };
+
+            {
+                let hints = match lp {
+                    Expression::For(ref mut f) => &mut f.hints,
+                    Expression::While(ref mut w) => &mut w.hints,
+                    _ => return parse_error_result(&name_span, format!("@{} must be followed by a for or while loop", name)),
+                };
+
+                if name == "vectorize" {
+                    hints.vectorize = true;
+                    hints.vectorize_width = Some(count as usize);
+                } else {
+                    hints.unroll_count = Some(count as usize);
+                }
+            }
+            Ok(lp)
+        }
+
+        _ => {
+            let intrinsic = match Intrinsic::from_name(&name) {
+                Some(intrinsic) => intrinsic,
+                None => return parse_error_result(&name_span, format!("Unknown compiler call {}", name)),
+            };
+
+            tq.expect(&TokenKind::OpenParen)?;
+            let typ = if intrinsic.has_type_argument() {
+                let typ = parse_type(tq, indent_level, target)?;
+                tq.expect(&TokenKind::Comma)?;
+                typ
+            } else {
+                Type::Unknown
+            };
+
+            let args = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_expression, indent_level, target)?;
+            let span = start.expanded(tq.pos());
+            if args.len() != intrinsic.num_args() {
+                return parse_error_result(&span, format!("@{} expects {} arguments", intrinsic, intrinsic.num_args()));
+            }
+
+            Ok(Expression::CompilerCall(CompilerCall::Intrinsic{
+                intrinsic,
+                args,
+                typ,
+                span
+            }))
+        }
+    }
+}
+
+// An uint8 array with the bytes of @include_bytes or a b"..." literal
+fn byte_array_literal(data: Vec<u8>, span: Span) -> Expression
+{
+    let len = data.len();
+    let bytes = data.into_iter()
+        .map(|b| Expression::Literal(Literal::UInt(span.clone(), u64::from(b), IntSize::I8)))
+        .collect();
+    Expression::Literal(Literal::Array(ArrayLiteral{
+        elements: bytes,
+        array_type: array_type(Type::UInt(IntSize::I8), len),
+        span,
+    }))
+}
+
+// Included files are looked up relative to the directory of the source file which includes them
+fn read_included_file(path: &str, span: &Span) -> CompileResult<Vec<u8>>
+{
+    let full_path = Path::new(&span.file)
+        .parent()
+        .map(|dir| dir.join(path))
+        .unwrap_or_else(|| Path::new(path).to_path_buf());
+
+    let mut data = Vec::new();
+    match fs::File::open(&full_path).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => Ok(data),
+        Err(e) => parse_error_result(span, format!("Unable to read {}: {}", full_path.to_string_lossy(), e)),
+    }
+}
+
+// A return without a value is followed by the end of the line, or by something closing the expression it is in
+fn is_end_of_return(tok: &Token) -> bool
+{
+    match tok.kind
+    {
+        TokenKind::Indent(_) |
+        TokenKind::EOF |
+        TokenKind::Else |
+        TokenKind::Comma |
+        TokenKind::CloseParen |
+        TokenKind::CloseBracket |
+        TokenKind::CloseCurly => true,
+        _ => false,
     }
 }
 
 fn parse_return(tq: &mut TokenQueue, start: &Span, indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
-    if tq.peek().map(|tok| is_end_of_expression(tok)).unwrap_or(true) {
+    if tq.peek().map(|tok| is_end_of_return(tok)).unwrap_or(true) {
         Ok(return_expr(Expression::Void, start.clone()))
     } else {
         let expr = parse_expression(tq, indent_level, target)?;
@@ -927,6 +1530,10 @@ fn parse_expression_start(tq: &mut TokenQueue, tok: Token, indent_level: usize,
             parse_lambda(tq, &tok.span, indent_level, target)?
         },
 
+        TokenKind::Backslash => {
+            parse_shorthand_lambda(tq, &tok.span, indent_level, target)?
+        },
+
         TokenKind::Match => {
             parse_match(tq, &tok.span, indent_level, target)?
         },
@@ -968,7 +1575,7 @@ fn parse_expression_start(tq: &mut TokenQueue, tok: Token, indent_level: usize,
 
         TokenKind::Identifier(id) => {
             let nr = parse_name(tq, id, &tok.span)?;
-            if tq.is_next(&TokenKind::BinaryOperator(BinaryOperator::Dot))
+            if tq.is_next(&TokenKind::BinaryOperator(BinaryOperator::Dot)) || tq.is_next(&TokenKind::OptionalDot)
             {
                 parse_member_access(tq, Expression::NameRef(nr), indent_level, target)?
             }
@@ -990,6 +1597,14 @@ fn parse_expression_start(tq: &mut TokenQueue, tok: Token, indent_level: usize,
             Expression::Literal(Literal::String(tok.span, s))
         },
 
+        TokenKind::ByteStringLiteral(b) => {
+            byte_array_literal(b, tok.span)
+        },
+
+        TokenKind::StringInterpolationStart(s) => {
+            parse_interpolated_string(tq, s, &tok.span, indent_level, target)?
+        },
+
         TokenKind::Number(n) => {
             parse_number(tq, &n, &tok.span, target).map(Expression::Literal)?
         },
@@ -1040,12 +1655,21 @@ fn parse_expression_start(tq: &mut TokenQueue, tok: Token, indent_level: usize,
         {
             TokenKind::OpenBracket => {
                 let index_expr = parse_expression(tq, indent_level, target)?;
-                tq.expect(&TokenKind::CloseBracket)?;
-                let span = lhs.span().expanded(tq.pos());
-                lhs = index_op(lhs, index_expr, span);
+                if tq.is_next(&TokenKind::DotDot) {
+                    tq.pop()?;
+                    let end = parse_expression(tq, indent_level, target)?;
+                    tq.expect(&TokenKind::CloseBracket)?;
+                    let span = lhs.span().expanded(tq.pos());
+                    lhs = slice_op(lhs, index_expr, end, span);
+                } else {
+                    tq.expect(&TokenKind::CloseBracket)?;
+                    let span = lhs.span().expanded(tq.pos());
+                    lhs = index_op(lhs, index_expr, span);
+                }
             },
 
-            TokenKind::BinaryOperator(BinaryOperator::Dot) => {
+            TokenKind::BinaryOperator(BinaryOperator::Dot) |
+            TokenKind::OptionalDot => {
                 tq.push_front(next);
                 lhs = parse_member_access(tq, lhs, indent_level, target)?;
             },
@@ -1062,20 +1686,30 @@ fn parse_expression_start(tq: &mut TokenQueue, tok: Token, indent_level: usize,
 
 pub fn parse_expression(tq: &mut TokenQueue, indent_level: usize, target: &Target) -> CompileResult<Expression>
 {
-    let tok = tq.pop()?;
-    let e_start = parse_expression_start(tq, tok, indent_level, target)?;
-    if tq.is_next_binary_operator() {
-        parse_binary_op_rhs(tq, e_start, indent_level, target)
-    } else {
-        Ok(e_start)
-    }
+    parse_operand(tq, 0, indent_level, target)
 }
 
-fn parse_global_bindings(module: &mut Module, tq: &mut TokenQueue, mutable: bool, indent_level: usize, namespace: &str, target: &Target) -> CompileResult<()>
+fn parse_global_bindings(
+    module: &mut Module,
+    tq: &mut TokenQueue,
+    mutable: bool,
+    thread_local: bool,
+    indent_level: usize,
+    namespace: &str,
+    target: &Target) -> CompileResult<()>
 {
     while !is_end_of_bindings(tq, indent_level)
     {
-        let (name, span) = tq.expect_identifier()?;
+        let (name, span) = match parse_binding_type(tq, indent_level, target)? {
+            (BindingType::Name(name), span) => (name, span),
+            (pattern, span) => {
+                tq.expect(&TokenKind::Assign(AssignOperator::Assign))?;
+                let init = parse_expression(tq, indent_level, target)?;
+                add_destructured_globals(module, pattern, init, mutable, thread_local, namespace, &span, span.expanded(tq.pos()))?;
+                eat_comma(tq)?;
+                continue;
+            },
+        };
         tq.expect(&TokenKind::Assign(AssignOperator::Assign))?;
         let init = parse_expression(tq, indent_level, target)?;
 
@@ -1084,14 +1718,65 @@ fn parse_global_bindings(module: &mut Module, tq: &mut TokenQueue, mutable: bool
         }
 
         let full_name = namespaced(namespace, &name);
-        module.globals.insert(full_name.clone(), global_binding(full_name, init, mutable, span.expanded(tq.pos())));
+        let mut global = global_binding(full_name.clone(), init, mutable, span.expanded(tq.pos()));
+        global.thread_local = thread_local;
+        module.globals.insert(full_name, global);
         eat_comma(tq)?;
     }
 
     Ok(())
 }
 
-fn parse_interface(module: &mut Module, tq: &mut TokenQueue, namespace: &str, span: &Span, indent_level: usize, target: &Target) -> CompileResult<()>
+/*
+let (quot, rem) = divmod(7, 2) at the top level becomes a global named after the pattern, (quot, rem), holding
+the value, and a global per name, which takes its member out of it:
+
+let (quot, rem) = divmod(7, 2)
+let quot = {let (quot, _) = (quot, rem); quot}
+let rem = {let (_, rem) = (quot, rem); rem}
+*/
+fn add_destructured_globals(
+    module: &mut Module,
+    pattern: BindingType,
+    init: Expression,
+    mutable: bool,
+    thread_local: bool,
+    namespace: &str,
+    pattern_span: &Span,
+    span: Span) -> CompileResult<()>
+{
+    if let BindingType::Struct(ref s) = pattern {
+        if s.bindings.iter().any(|b| b.mode == StructPatternBindingMode::Pointer) {
+            return parse_error_result(&span, "Global struct bindings cannot bind pointers to members");
+        }
+    }
+
+    let value_name = namespaced(namespace, &pattern.to_string());
+    // The value gets the shorter span, so it is type checked before the globals using it
+    let mut value = global_binding(value_name.clone(), init, false, pattern_span.clone());
+    value.thread_local = thread_local;
+
+    for name in pattern.names() {
+        let full_name = namespaced(namespace, name);
+        if module.globals.contains_key(&full_name) {
+            return parse_error_result(&span, format!("Global {} already defined in this module", name));
+        }
+
+        let member = binding(pattern.only_binding(name), Expression::NameRef(NameRef::new(value_name.clone(), span.clone())), false, span.clone());
+        let init = block(vec![
+            bindings(vec![member], span.clone()),
+            Expression::NameRef(NameRef::new(name.into(), span.clone())),
+        ], span.clone());
+        let mut global = global_binding(full_name.clone(), init, mutable, span.clone());
+        global.thread_local = thread_local;
+        module.globals.insert(full_name, global);
+    }
+
+    module.globals.insert(value_name, value);
+    Ok(())
+}
+
+fn parse_interface(module: &mut Module, tq: &mut TokenQueue, namespace: &str, span: &Span, public: bool, indent_level: usize, target: &Target) -> CompileResult<()>
 {
     let (name, _) = tq.expect_identifier()?;
     if module.types.contains_key(&name) {
@@ -1109,7 +1794,37 @@ fn parse_interface(module: &mut Module, tq: &mut TokenQueue, namespace: &str, sp
     let functions = parse_indented_block(tq, indent_level, parse_interface_function, target)?;
 
     let name = namespaced(namespace, &name);
-    module.types.insert(name.clone(), TypeDeclaration::Interface(interface(name, functions, span.expanded(tq.pos()))));
+    let mut it = interface(name.clone(), functions, span.expanded(tq.pos()));
+    it.public = public;
+    module.types.insert(name, TypeDeclaration::Interface(it));
+    Ok(())
+}
+
+fn parse_interface_impl(module: &mut Module, tq: &mut TokenQueue, namespace: &str, span: &Span, indent_level: usize, target: &Target) -> CompileResult<()>
+{
+    let (interface_name, _) = tq.expect_identifier()?;
+    tq.expect(&TokenKind::For)?;
+    let (type_name, _) = tq.expect_identifier()?;
+    tq.expect(&TokenKind::Colon)?;
+    let self_type = ptr_type(unresolved_type(&type_name, Vec::new()));
+
+    let parse_impl_function = |tq: &mut TokenQueue, indent_level: usize, target: &Target| {
+        let tok = tq.expect(&TokenKind::Func)?;
+        let (name, _) = tq.expect_identifier()?;
+        let full_name = namespaced(namespace, &format!("{}.{}", type_name, name));
+        let mut func = parse_function_definition(tq, &full_name, &self_type, &tok.span, indent_level, target)?;
+        // Everything which can use the interface, can call the functions implementing it
+        func.public = true;
+        Ok(func)
+    };
+
+    let functions = parse_indented_block(tq, indent_level, parse_impl_function, target)?;
+    let names = functions.iter().map(|f| f.sig.name.clone()).collect();
+    for func in functions {
+        add_function(module, func)?;
+    }
+
+    module.impls.push(interface_impl(interface_name, type_name, names, span.expanded(tq.pos())));
     Ok(())
 }
 
@@ -1136,99 +1851,345 @@ fn parse_import_name(tq: &mut TokenQueue) -> CompileResult<ImportName>
     Ok(ImportName::new(namespace, span))
 }
 
-fn parse_module<Input: Read>(
+enum Annotation
+{
+    Derive(Vec<String>),
+    Deprecated(String),
+    MustUse,
+    ConstantTime,
+    Unsafe,
+    Init,
+    Fini,
+    ReprC,
+    ThreadLocal,
+    Export,
+    Test,
+}
+
+fn parse_annotation(tq: &mut TokenQueue, indent_level: usize, target: &Target) -> CompileResult<(Annotation, Span)>
+{
+    let (name, name_span) = tq.expect_identifier()?;
+    match &name[..] {
+        "derive" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let parse_derive_name = |tq: &mut TokenQueue, _indent_level: usize, _target: &Target| {
+                tq.expect_identifier().map(|(name, _)| name)
+            };
+            let derives = parse_comma_separated_list(tq, &TokenKind::CloseParen, parse_derive_name, indent_level, target)?;
+            if derives.is_empty() {
+                return parse_error_result(&name_span, "@derive expects at least one trait name");
+            }
+            Ok((Annotation::Derive(derives), name_span.expanded(tq.pos())))
+        },
+
+        "deprecated" => {
+            let msg = if tq.is_next(&TokenKind::OpenParen) {
+                tq.pop()?;
+                let tok = tq.pop()?;
+                let msg = match tok.kind {
+                    TokenKind::StringLiteral(msg) => msg,
+                    _ => return parse_error_result(&tok.span, format!("Expected string literal, found {}", tok)),
+                };
+                tq.expect(&TokenKind::CloseParen)?;
+                msg
+            } else {
+                String::new()
+            };
+            Ok((Annotation::Deprecated(msg), name_span.expanded(tq.pos())))
+        },
+
+        "must_use" => Ok((Annotation::MustUse, name_span)),
+        "constant_time" => Ok((Annotation::ConstantTime, name_span)),
+        "unsafe" => Ok((Annotation::Unsafe, name_span)),
+        "init" => Ok((Annotation::Init, name_span)),
+        "fini" => Ok((Annotation::Fini, name_span)),
+        "thread_local" => Ok((Annotation::ThreadLocal, name_span)),
+        "export" => Ok((Annotation::Export, name_span)),
+        "test" => Ok((Annotation::Test, name_span)),
+
+        "repr" => {
+            tq.expect(&TokenKind::OpenParen)?;
+            let (repr, repr_span) = tq.expect_identifier()?;
+            tq.expect(&TokenKind::CloseParen)?;
+            if repr != "C" {
+                return parse_error_result(&repr_span, format!("Unsupported representation {}, only C is supported", repr));
+            }
+            Ok((Annotation::ReprC, name_span.expanded(tq.pos())))
+        },
+
+        _ => parse_error_result(&name_span, format!("Unknown annotation {}", name)),
+    }
+}
+
+fn apply_type_annotations(
+    annotations: Vec<(Annotation, Span)>,
+    derives: &mut Vec<String>,
+    deprecated: &mut Option<String>,
+    mut repr_c: Option<&mut bool>) -> CompileResult<()>
+{
+    for (annotation, span) in annotations {
+        match annotation {
+            Annotation::Derive(d) => derives.extend(d),
+            Annotation::Deprecated(msg) => *deprecated = Some(msg),
+            Annotation::MustUse => return parse_error_result(&span, "@must_use is only allowed on functions"),
+            Annotation::ConstantTime => return parse_error_result(&span, "@constant_time is only allowed on functions"),
+            Annotation::Unsafe => return parse_error_result(&span, "@unsafe is only allowed on functions"),
+            Annotation::Init => return parse_error_result(&span, "@init is only allowed on functions"),
+            Annotation::Fini => return parse_error_result(&span, "@fini is only allowed on functions"),
+            Annotation::ReprC => {
+                match repr_c {
+                    Some(ref mut repr_c) => **repr_c = true,
+                    None => return parse_error_result(&span, "@repr is only allowed on struct declarations"),
+                }
+            },
+            Annotation::ThreadLocal => return parse_error_result(&span, "@thread_local is only allowed on globals"),
+            Annotation::Export => return parse_error_result(&span, "@export is only allowed on functions"),
+            Annotation::Test => return parse_error_result(&span, "@test is only allowed on functions"),
+        }
+    }
+    Ok(())
+}
+
+fn apply_function_annotations(annotations: Vec<(Annotation, Span)>, func: &mut Function) -> CompileResult<()>
+{
+    for (annotation, span) in annotations {
+        match annotation {
+            Annotation::Derive(_) => return parse_error_result(&span, "@derive is only allowed on struct and enum declarations"),
+            Annotation::Deprecated(msg) => func.deprecated = Some(msg),
+            Annotation::MustUse => func.must_use = true,
+            Annotation::ConstantTime => func.constant_time = true,
+            Annotation::Unsafe => func.unsafe_code = true,
+            Annotation::Init => func.init = true,
+            Annotation::Fini => func.fini = true,
+            Annotation::Export => func.export = true,
+            Annotation::Test => func.test = true,
+            Annotation::ReprC => return parse_error_result(&span, "@repr is only allowed on struct declarations"),
+            Annotation::ThreadLocal => return parse_error_result(&span, "@thread_local is only allowed on globals"),
+        }
+    }
+    Ok(())
+}
+
+// Returns whether the globals are thread local
+fn apply_global_annotations(annotations: Vec<(Annotation, Span)>) -> CompileResult<bool>
+{
+    let mut thread_local = false;
+    for (annotation, span) in annotations {
+        match annotation {
+            Annotation::ThreadLocal => thread_local = true,
+            _ => return parse_error_result(&span, "Only @thread_local is allowed on globals"),
+        }
+    }
+    Ok(thread_local)
+}
+
+fn add_function(module: &mut Module, func: Function) -> CompileResult<()>
+{
+    if module.functions.contains_key(&func.sig.name) {
+        return parse_error_result(&func.span, format!("Function {} redefined", func.sig.name));
+    }
+    module.functions.insert(func.sig.name.clone(), func);
+    Ok(())
+}
+
+// Parse a top level declaration, starting with tok
+fn parse_declaration(
     module: &mut Module,
-    input: &mut Input,
+    tq: &mut TokenQueue,
+    tok: Token,
+    indent_level: &mut usize,
+    annotations: &mut Vec<(Annotation, Span)>,
     namespace: &str,
-    file_name: &str,
+    public: bool,
     target: &Target) -> CompileResult<()>
 {
-    let mut tq = Lexer::new(file_name).read(input)?;
-
-    let add_function = |module: &mut Module, func: Function| -> CompileResult<()> {
-        if module.functions.contains_key(&func.sig.name) {
-            return parse_error_result(&func.span, format!("Function {} redefined", func.sig.name));
+    if !annotations.is_empty() {
+        match tok.kind {
+            TokenKind::Indent(_) | TokenKind::At | TokenKind::Struct | TokenKind::Union | TokenKind::Enum | TokenKind::Func |
+            TokenKind::Let | TokenKind::Var | TokenKind::Pub => (),
+            _ => return parse_error_result(&tok.span, "Annotations are only allowed on functions, globals, struct, union and enum declarations"),
         }
-        module.functions.insert(func.sig.name.clone(), func);
-        Ok(())
-    };
+    }
 
-    let mut indent_level = 0;
-    while !tq.is_next(&TokenKind::EOF)
+    match tok.kind
     {
-        let tok = tq.pop()?;
-        match tok.kind
-        {
-            TokenKind::Indent(level) => {
-                indent_level = level;
-            }
+        TokenKind::Indent(level) => {
+            *indent_level = level;
+        }
 
-            TokenKind::Interface => {
-                parse_interface(module, &mut tq, namespace, &tok.span, indent_level, target)?;
+        TokenKind::At => {
+            annotations.push(parse_annotation(tq, *indent_level, target)?);
+        }
+
+        // Declarations without pub can only be used in their own module
+        TokenKind::Pub => {
+            let next = tq.pop()?;
+            match next.kind {
+                TokenKind::Func | TokenKind::Extern | TokenKind::Struct | TokenKind::Union | TokenKind::Enum | TokenKind::Type | TokenKind::Interface => (),
+                _ => return parse_error_result(&next.span, format!("Expected fn, extern, struct, union, enum, type or interface after pub, found token {}", next)),
             }
+            parse_declaration(module, tq, next, indent_level, annotations, namespace, true, target)?;
+        }
+
+        TokenKind::Interface => {
+            parse_interface(module, tq, namespace, &tok.span, public, *indent_level, target)?;
+        }
+
+        TokenKind::Impl => {
+            parse_interface_impl(module, tq, namespace, &tok.span, *indent_level, target)?;
+        }
+
+        TokenKind::Let => {
+            let thread_local = apply_global_annotations(mem::replace(annotations, Vec::new()))?;
+            parse_global_bindings(module, tq, false, thread_local, *indent_level, namespace, target)?;
+        }
+
+        TokenKind::Var => {
+            let thread_local = apply_global_annotations(mem::replace(annotations, Vec::new()))?;
+            parse_global_bindings(module, tq, true, thread_local, *indent_level, namespace, target)?;
+        }
 
-            TokenKind::Let => {
-                parse_global_bindings(module, &mut tq, false, indent_level, namespace, target)?;
+        TokenKind::Struct => {
+            let mut sd = parse_struct_type(tq, namespace, *indent_level, target)?;
+            sd.span = tok.span.expanded(sd.span.end);
+            sd.public = public;
+            apply_type_annotations(mem::replace(annotations, Vec::new()), &mut sd.derives, &mut sd.deprecated, Some(&mut sd.repr_c))?;
+            if module.types.contains_key(&sd.name) {
+                return parse_error_result(&sd.span, format!("Type {} redefined", sd.name));
             }
+            module.types.insert(sd.name.clone(), TypeDeclaration::Struct(sd));
+        }
 
-            TokenKind::Var => {
-                parse_global_bindings(module, &mut tq, true, indent_level, namespace, target)?;
+        TokenKind::Union => {
+            let mut ud = parse_struct_type(tq, namespace, *indent_level, target)?;
+            ud.span = tok.span.expanded(ud.span.end);
+            ud.public = public;
+            apply_type_annotations(mem::replace(annotations, Vec::new()), &mut ud.derives, &mut ud.deprecated, None)?;
+            if module.types.contains_key(&ud.name) {
+                return parse_error_result(&ud.span, format!("Type {} redefined", ud.name));
             }
+            module.types.insert(ud.name.clone(), TypeDeclaration::Union(ud));
+        }
 
-            TokenKind::Struct => {
-                let mut sd = parse_struct_type(&mut tq, namespace, indent_level, target)?;
-                sd.span = tok.span.expanded(sd.span.end);
-                if module.types.contains_key(&sd.name) {
-                    return parse_error_result(&sd.span, format!("Type {} redefined", sd.name));
-                }
-                module.types.insert(sd.name.clone(), TypeDeclaration::Struct(sd));
+        TokenKind::Enum => {
+            let mut st = parse_sum_type(tq, namespace, &tok.span, *indent_level, target)?;
+            st.public = public;
+            apply_type_annotations(mem::replace(annotations, Vec::new()), &mut st.derives, &mut st.deprecated, None)?;
+            if module.types.contains_key(&st.name) {
+                return parse_error_result(&st.span, format!("Type {} redefined", st.name));
             }
+            module.types.insert(st.name.clone(), TypeDeclaration::Sum(st));
+        }
 
-            TokenKind::Enum => {
-                let st = parse_sum_type(&mut tq, namespace, &tok.span, indent_level, target)?;
-                if module.types.contains_key(&st.name) {
-                    return parse_error_result(&st.span, format!("Type {} redefined", st.name));
-                }
-                module.types.insert(st.name.clone(), TypeDeclaration::Sum(st));
+        TokenKind::Type => {
+            let mut st = parse_type_declaration(tq, namespace, &tok.span, *indent_level, target)?;
+            st.public = public;
+            apply_type_annotations(mem::replace(annotations, Vec::new()), &mut st.derives, &mut st.deprecated, None)?;
+            if module.types.contains_key(&st.name) {
+                return parse_error_result(&st.span, format!("Type {} redefined", st.name));
             }
+            module.types.insert(st.name.clone(), TypeDeclaration::Sum(st));
+        }
 
-            TokenKind::Type => {
-                panic!("NYI");
+        TokenKind::Extern if tq.is_next(&TokenKind::Type) => {
+            tq.pop()?;
+            let (name, name_span) = tq.expect_identifier()?;
+            let mut od = opaque_type_declaration(&namespaced(namespace, &name), tok.span.expanded(name_span.end));
+            od.public = public;
+            if module.types.contains_key(&od.name) {
+                return parse_error_result(&od.span, format!("Type {} redefined", od.name));
             }
+            module.types.insert(od.name.clone(), TypeDeclaration::Opaque(od));
+        }
 
-            TokenKind::Extern => {
-                let ext_func = parse_external_function(&mut tq, &tok.span, indent_level, target)?;
-                if module.externals.contains_key(&ext_func.sig.name) {
-                    return parse_error_result(&ext_func.span, format!("External function {} redefined", ext_func.sig.name));
-                }
-                module.externals.insert(ext_func.sig.name.clone(), ext_func);
+        TokenKind::Extern => {
+            let mut ext_func = parse_external_function(tq, &tok.span, *indent_level, target)?;
+            ext_func.public = public;
+            if module.externals.contains_key(&ext_func.sig.name) {
+                return parse_error_result(&ext_func.span, format!("External function {} redefined", ext_func.sig.name));
             }
+            module.externals.insert(ext_func.sig.name.clone(), ext_func);
+        }
 
-            TokenKind::Import => {
-                loop
-                {
-                    let import = parse_import_name(&mut tq)?;
-                    module.import_names.insert(import);
-                    if tq.is_next(&TokenKind::Comma) {
-                        tq.pop()?;
-                    } else {
-                        break;
-                    }
+        TokenKind::Import => {
+            loop
+            {
+                let import = parse_import_name(tq)?;
+                module.import_names.insert(import);
+                if tq.is_next(&TokenKind::Comma) {
+                    tq.pop()?;
+                } else {
+                    break;
                 }
             }
+        }
 
-            TokenKind::Func => {
-                let func = parse_function_declaration(&mut tq, namespace, &tok.span, indent_level, target)?;
-                add_function(module, func)?;
-            }
+        TokenKind::Func => {
+            let mut func = parse_function_declaration(tq, namespace, &tok.span, *indent_level, target)?;
+            func.public = public;
+            apply_function_annotations(mem::replace(annotations, Vec::new()), &mut func)?;
+            add_function(module, func)?;
+        }
 
-            _ => {
-                return parse_error_result(&tok.span,
-                    format!("Expected import, fn, let, var, extern, type, struct, enum or interface found token {}", tok));
-            }
+        _ => {
+            return parse_error_result(&tok.span,
+                format!("Expected import, pub, fn, let, var, extern, type, struct, union, enum, interface or impl found token {}", tok));
+        }
+    }
+
+    Ok(())
+}
+
+// After an error, continue with the next line which is not indented
+fn skip_to_next_declaration(tq: &mut TokenQueue)
+{
+    loop {
+        let at_declaration = match tq.peek() {
+            Some(tok) => tok.kind == TokenKind::Indent(0) || tok.kind == TokenKind::EOF,
+            None => true,
+        };
+
+        if at_declaration {
+            break;
+        }
+        let _ = tq.pop();
+    }
+}
+
+// A parse error in a declaration doesn't stop the parser, so the errors in all declarations are reported
+fn parse_module<Input: Read>(
+    module: &mut Module,
+    input: &mut Input,
+    namespace: &str,
+    file_name: &str,
+    target: &Target) -> CompileResult<()>
+{
+    let mut source = String::new();
+    input.read_to_string(&mut source)?;
+    register_source(file_name, &source);
+
+    let mut tq = expand_macros(Lexer::new(file_name).read(&mut source.as_bytes())?)?;
+    let mut diagnostics = Diagnostics::new();
+    let mut indent_level = 0;
+    let mut annotations = Vec::new();
+    while !tq.is_next(&TokenKind::EOF)
+    {
+        let tok = tq.pop()?;
+        if let Err(e) = parse_declaration(module, &mut tq, tok, &mut indent_level, &mut annotations, namespace, false, target) {
+            diagnostics.add(e);
+            annotations.clear();
+            skip_to_next_declaration(&mut tq);
         }
     }
 
+    diagnostics.into_result()?;
+
+    // The functions @derive adds are generated as source, and parsed like the rest of the file
+    if let Some(code) = derived_functions(module, namespace, file_name, target)? {
+        let derived_file_name = format!("<derive>/{}", file_name);
+        parse_module(module, &mut code.as_bytes(), namespace, &derived_file_name, target)?;
+    }
+
     Ok(())
 }
 
@@ -1243,6 +2204,17 @@ pub fn parse_file(file_path: &Path, namespace: &str, target: &Target) -> Compile
     })
 }
 
+// Parse the contents of a file, which are not saved yet
+pub fn parse_buffer(code: &str, file_path: &Path, namespace: &str, target: &Target) -> CompileResult<Module>
+{
+    use std::io::Cursor;
+
+    let mut module = Module::new(namespace);
+    let mut cursor = Cursor::new(code);
+    parse_module(&mut module, &mut cursor, namespace, file_path.to_string_lossy().deref(), target)?;
+    Ok(module)
+}
+
 
 #[cfg(test)]
 use package::Package;
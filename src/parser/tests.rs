@@ -4,6 +4,7 @@ use parser::*;
 use super::lexer::Lexer;
 use target::Target;
 use span::{Pos, Span};
+use compileerror::CompileResult;
 
 fn span(sl: usize, so: usize, el: usize, eo: usize) -> Span
 {
@@ -278,6 +279,35 @@ fn test_precedence_10()
     assert!(e == s3);
 }
 
+#[test]
+fn test_precedence_11()
+{
+    let target = Target::new(IntSize::I32, "");
+    let e = th_expr("(a % b) as int", &target);
+    assert!(e == type_cast(
+        bin_op_with_precedence(
+            BinaryOperator::Mod,
+            name_ref("a", span(1, 2, 1, 2)),
+            name_ref("b", span(1, 6, 1, 6)),
+            span(1, 2, 1, 6),
+            TOP_PRECEDENCE
+        ),
+        Type::Int(IntSize::I32),
+        span(1, 2, 1, 14),
+    ));
+}
+
+#[test]
+fn test_return()
+{
+    let target = Target::new(IntSize::I32, "");
+    assert!(th_expr("return", &target) == return_expr(Expression::Void, span(1, 1, 1, 6)));
+    assert!(th_expr("return false", &target) == return_expr(
+        Expression::Literal(Literal::Bool(span(1, 8, 1, 12), false)),
+        span(1, 1, 1, 12)
+    ));
+}
+
 #[test]
 fn test_namespaced_call()
 {
@@ -374,7 +404,7 @@ fn test_function_with_args()
             ],
             span(1, 1, 1, 29)
         ),
-        true,
+        false,
         number(7, span(1, 32, 1, 32), &target),
         span(1, 1, 1, 32))
     )
@@ -392,7 +422,7 @@ fn test_function_with_no_args()
             Vec::new(),
             span(1, 1, 1, 15)
         ),
-        true,
+        false,
         number(7, span(1, 18, 1, 18), &target),
         span(1, 1, 1, 18))
     )
@@ -410,7 +440,7 @@ fn test_function_with_no_return_type()
             Vec::new(),
             span(1, 1, 1, 8)
         ),
-        true,
+        false,
         number(7, span(1, 11, 1, 11), &target),
         span(1, 1, 1, 11))
     )
@@ -441,7 +471,7 @@ fn test_function_with_func_type()
             ],
             span(1, 1, 1, 37)
         ),
-        true,
+        false,
         number(7, span(1, 40, 1, 40), &target),
         span(1, 1, 1, 40))
     )
@@ -463,6 +493,71 @@ fn test_external_function()
     )
 }
 
+#[test]
+fn test_external_type()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod("extern type Window", &target);
+    assert!(*md.types.get("test::Window").unwrap() == TypeDeclaration::Opaque(
+        opaque_type_declaration("test::Window", span(1, 1, 1, 18))
+    ))
+}
+
+#[test]
+fn test_public_declarations()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+pub extern type Window
+pub extern fn open() -> *Window
+extern fn close(w: *Window)
+
+pub interface Shape:
+    fn area(self) -> int
+
+@derive(Json)
+pub struct Square:
+    side: int
+
+enum Color:
+    Red
+    Green
+
+impl Shape for Square:
+    fn area(self) -> int: self.side * self.side
+
+pub fn Square.double(self) -> Square: Square{self.side * 2}
+
+fn helper() -> int: 7
+"#, &target);
+
+    assert!(md.types.get("test::Window").unwrap().is_public());
+    assert!(md.types.get("test::Shape").unwrap().is_public());
+    assert!(md.types.get("test::Square").unwrap().is_public());
+    assert!(!md.types.get("test::Color").unwrap().is_public());
+    assert!(md.externals.get("open").unwrap().public);
+    assert!(!md.externals.get("close").unwrap().public);
+    assert!(md.functions.get("test::Square.area").unwrap().public);
+    assert!(md.functions.get("test::Square.double").unwrap().public);
+    assert!(!md.functions.get("test::helper").unwrap().public);
+
+    assert!(parse_str("pub let x = 5", "test", &target).is_err());
+    assert!(parse_str("pub import net", "test", &target).is_err());
+}
+
+#[test]
+fn test_thread_local_global()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+@thread_local
+var counter = 0
+var total = 0
+"#, &target);
+    assert!(md.globals["test::counter"].thread_local);
+    assert!(!md.globals["test::total"].thread_local);
+}
+
 #[test]
 fn test_lambda()
 {
@@ -483,6 +578,235 @@ fn test_lambda()
     ))
 }
 
+#[test]
+fn test_trailing_lambda()
+{
+    let target = Target::new(IntSize::I32, "");
+    let e = th_expr("each(xs) fn(x) -> x", &target);
+    assert!(e == Expression::Call(Box::new(Call::new(
+        name_ref2("each", span(1, 1, 1, 4)),
+        vec![
+            name_ref("xs", span(1, 6, 1, 7)),
+            lambda(
+                vec![Argument::new("x", generic_type("x"), false, span(1, 13, 1, 13))],
+                name_ref("x", span(1, 19, 1, 19)),
+                span(1, 10, 1, 19)
+            ),
+        ],
+        span(1, 1, 1, 19)
+    ))));
+
+    let md = th_mod(r#"
+fn main() -> int:
+    let n = fold(xs, 0) fn(acc, x):
+        let y = x * 2
+        acc + y
+    xs.each() fn(x) -> print(x)
+    n
+"#, &target);
+    let f = md.functions.get("test::main").unwrap();
+    let block = match f.expression {
+        Expression::Block(ref b) => b,
+        _ => panic!("Expected block"),
+    };
+    assert_eq!(block.expressions.len(), 3);
+    match block.expressions[1] {
+        Expression::MemberAccess(ref ma) => match ma.right {
+            MemberAccessType::Call(ref c) => assert_eq!(c.args.len(), 1),
+            _ => panic!("Expected call"),
+        },
+        _ => panic!("Expected member access"),
+    }
+}
+
+#[test]
+fn test_shorthand_lambda()
+{
+    let target = Target::new(IntSize::I32, "");
+    let e = th_expr("\\(it * 2)", &target);
+    assert!(e == lambda(
+        vec![Argument::new("it", generic_type("it"), false, span(1, 2, 1, 2))],
+        bin_op_with_precedence(
+            BinaryOperator::Mul,
+            name_ref("it", span(1, 3, 1, 4)),
+            number(2, span(1, 8, 1, 8), &target),
+            span(1, 3, 1, 8),
+            TOP_PRECEDENCE
+        ),
+        span(1, 1, 1, 9)
+    ));
+
+    let e = th_expr("map(xs) \\(it + 1)", &target);
+    match e {
+        Expression::Call(ref c) => {
+            assert_eq!(c.args.len(), 2);
+            assert!(match c.args[1] {Expression::Lambda(_) => true, _ => false});
+        },
+        _ => panic!("Expected call"),
+    }
+}
+
+#[test]
+fn test_guard_let()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+fn foo(a: ?int) -> int:
+    let x = a else return 0
+    let y = x + 1
+    y
+"#, &target);
+    let body = &md.functions.get("test::foo").unwrap().expression;
+    match *body {
+        Expression::Match(ref m) => {
+            assert!(match m.target {Expression::NameRef(ref nr) => nr.name == "a", _ => false});
+            assert_eq!(m.cases.len(), 2);
+            assert!(match m.cases[0].pattern {Pattern::Optional(ref o) => o.binding == "x", _ => false});
+            assert!(match m.cases[0].to_execute {Expression::Block(ref b) => b.expressions.len() == 2, _ => false});
+            assert!(match m.cases[1].pattern {Pattern::Nil(_) => true, _ => false});
+            assert!(match m.cases[1].to_execute {Expression::Return(_) => true, _ => false});
+        },
+        _ => panic!("Expected match"),
+    }
+
+    assert!(parse_str(r#"
+fn foo(a: ?int) -> int:
+    let x = a else 0
+    x
+"#, "test", &target).is_err());
+    assert!(parse_str(r#"
+fn foo(a: ?int) -> int:
+    var x = a else return 0
+    x
+"#, "test", &target).is_err());
+}
+
+#[test]
+fn test_if_else_in_block()
+{
+    // An else which is not after a let belongs to the if, also when it is on the next line
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+fn foo(a: int) -> int:
+    let b = if a > 0: a else -a
+    if b > 10: 10 else b
+    if b > 5:
+        b - 5
+    else
+        b
+"#, &target);
+    let body = &md.functions.get("test::foo").unwrap().expression;
+    match *body {
+        Expression::Block(ref b) => {
+            assert_eq!(b.expressions.len(), 3);
+            assert!(match b.expressions[0] {Expression::Bindings(_) => true, _ => false});
+            assert!(match b.expressions[1] {Expression::If(ref i) => i.on_false.is_some(), _ => false});
+            assert!(match b.expressions[2] {Expression::If(ref i) => i.on_false.is_some(), _ => false});
+        },
+        _ => panic!("Expected block"),
+    }
+}
+
+#[test]
+fn test_tuple_binding()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+let (quot, rem) = divmod(7, 2)
+
+fn foo(pairs: {int, {int, int}}[]) -> int:
+    var sum = 0
+    for (a, (b, _)) in pairs:
+        sum += a + b
+    sum
+"#, &target);
+
+    assert!(md.globals.contains_key("test::(quot, rem)"));
+    match md.globals.get("test::quot").unwrap().init {
+        Expression::Block(ref b) => match b.expressions[0] {
+            Expression::Bindings(ref bl) => assert_eq!(bl.bindings[0].binding_type.to_string(), "(quot, _)"),
+            _ => panic!("Expected bindings"),
+        },
+        _ => panic!("Expected block"),
+    }
+    assert!(md.globals.contains_key("test::rem"));
+
+    let body = &md.functions.get("test::foo").unwrap().expression;
+    let for_loop = match *body {
+        Expression::Block(ref b) => &b.expressions[1],
+        _ => panic!("Expected block"),
+    };
+    match *for_loop {
+        Expression::For(ref f) => {
+            assert_eq!(f.loop_variable, "(a, (b, _))");
+            assert!(match f.body {Expression::Block(ref b) => b.expressions.len() == 2, _ => false});
+        },
+        _ => panic!("Expected for loop"),
+    }
+
+    assert!(parse_str(r#"
+fn foo() -> int:
+    let (a) = bar()
+    a
+"#, "test", &target).is_err());
+}
+
+#[test]
+fn test_argument_patterns()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+fn dist(Point{x, y}, var (a, b): {int, int}) -> int:
+    x * y + a - b
+"#, &target);
+
+    let f = md.functions.get("test::dist").unwrap();
+    let names: Vec<&str> = f.sig.args.iter().map(|a| &a.name[..]).collect();
+    assert_eq!(names, vec!["Point{x, y}", "(a, b)"]);
+    assert_eq!(f.sig.args[0].typ, unresolved_type("Point", Vec::new()));
+    assert!(f.sig.args[1].mutable);
+    assert_eq!(f.arg_patterns.len(), 2);
+    assert_eq!(f.arg_patterns[1].0, "(a, b)");
+
+    assert!(parse_str(r#"
+fn foo() -> int:
+    let f = fn((a, b): {int, int}) -> a + b
+    f({1, 2})
+"#, "test", &target).is_err());
+
+    assert!(parse_str(r#"
+fn foo((a, b)) -> int: a + b
+"#, "test", &target).is_err());
+}
+
+#[test]
+fn test_ref_arguments()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+fn swap(ref a: int, ref b: int, c: int):
+    let tmp = a
+    a = b
+    b = tmp + c
+"#, &target);
+
+    let f = md.functions.get("test::swap").unwrap();
+    assert!(f.sig.args[0].by_ref && f.sig.args[1].by_ref && !f.sig.args[2].by_ref);
+    assert_eq!(f.sig.args[0].typ, ptr_type(target.native_int_type.clone()));
+    assert_eq!(f.sig.ref_args(), vec![0, 1]);
+
+    assert!(parse_str(r#"
+fn foo(var ref a: int):
+    a = 1
+"#, "test", &target).is_err());
+
+    assert!(parse_str(r#"
+fn foo() -> int:
+    let f = fn(ref a: int) -> a
+    0
+"#, "test", &target).is_err());
+}
+
 #[test]
 fn test_match()
 {
@@ -523,6 +847,25 @@ struct Point:
     ))
 }
 
+#[test]
+fn test_union()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+union Value:
+    a: int
+    b: uint
+"#, &target);
+    assert!(*md.types.get("test::Value").unwrap() == TypeDeclaration::Union(struct_declaration(
+        "test::Value",
+        vec![
+            struct_member_declaration("a", target.native_int_type.clone(), span(3, 5, 3, 10)),
+            struct_member_declaration("b", target.native_uint_type.clone(), span(4, 5, 4, 11)),
+        ],
+        span(2, 1, 4, 11))
+    ))
+}
+
 #[test]
 fn test_generic_struct()
 {
@@ -622,6 +965,43 @@ a.b()
     )
 }
 
+#[test]
+fn test_optional_member_access()
+{
+    let target = Target::new(IntSize::I32, "");
+    let e = th_expr(r#"
+a?.b.c
+"#, &target);
+    assert!(e ==
+        member_access(
+            optional_member_access(
+                name_ref("a", span(2, 1, 2, 1)),
+                MemberAccessType::Name(field("b", 0)),
+                span(2, 1, 2, 4)
+            ),
+            MemberAccessType::Name(field("c", 0)),
+            span(2, 1, 2, 6)
+        )
+    )
+}
+
+#[test]
+fn test_slice_operation()
+{
+    let target = Target::new(IntSize::I32, "");
+    let e = th_expr(r#"
+a[1..n]
+"#, &target);
+    assert!(e ==
+        slice_op(
+            name_ref("a", span(2, 1, 2, 1)),
+            number(1, span(2, 3, 2, 3), &target),
+            name_ref("n", span(2, 6, 2, 6)),
+            span(2, 1, 2, 7)
+        )
+    )
+}
+
 #[test]
 fn test_sum_types()
 {
@@ -689,6 +1069,42 @@ enum Foo:
     ))
 }
 
+#[test]
+fn test_generic_sum_types()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+type Result<$v, $e> = Ok{value: $v} | Error{error: $e} | Unknown
+
+enum Option<$a>:
+    Some{value: $a}
+    None
+"#, &target);
+
+    let check = |name: &str, params: Vec<Type>, cases: Vec<&str>| {
+        match *md.types.get(name).unwrap() {
+            TypeDeclaration::Sum(ref st) => {
+                assert_eq!(st.generic_params, params);
+                let case_names: Vec<&str> = st.cases.iter().map(|c| &c.name[..]).collect();
+                assert_eq!(case_names, cases);
+            },
+            _ => panic!("{} is not a sum type", name),
+        }
+    };
+
+    check("test::Result", vec![generic_type("v"), generic_type("e")], vec!["test::Result::Ok", "test::Result::Error", "test::Result::Unknown"]);
+    check("test::Option", vec![generic_type("a")], vec!["test::Option::Some", "test::Option::None"]);
+}
+
+#[test]
+fn test_sum_type_params_must_be_generic()
+{
+    let target = Target::new(IntSize::I32, "");
+    assert!(parse_str(r#"
+type Option<int> = Some{value: int} | None
+"#, "test", &target).is_err());
+}
+
 #[test]
 fn test_generic_type_declaration()
 {
@@ -718,7 +1134,7 @@ fn foo(p: Point<int>) -> int: 7
             ],
             span(6, 1, 6, 28)
         ),
-        true,
+        false,
         number(7, span(6, 31, 6, 31), &target),
         span(6, 1, 6, 31))
     )
@@ -781,3 +1197,358 @@ interface Foo:
         span(2, 1, 3, 31)
     )))
 }
+
+#[test]
+fn test_derive()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+@derive(Json)
+struct Point:
+    x: int
+    y: int
+"#, &target);
+    if let TypeDeclaration::Struct(ref sd) = *md.types.get("test::Point").unwrap() {
+        assert!(sd.derives == vec![String::from("Json")]);
+    } else {
+        panic!("Expected a struct declaration");
+    }
+
+    for name in &["test::JsonWriter.write_Point", "test::JsonReader.read_Point", "test::Point.to_json", "test::Point.from_json"] {
+        let func = md.functions.get(*name).expect("Missing derived function");
+        assert!(func.span.file == "<derive>/");
+    }
+    assert!(md.types.contains_key("test::PointJsonResult"));
+
+    assert!(parse_str(r#"
+@derive(Json)
+struct Letter:
+    c: char
+"#, "test", &target).is_err());
+
+    assert!(parse_str(r#"
+struct Point:
+    x: int
+
+@derive(Json)
+enum Shape:
+    Dot{at: Point}
+"#, "test", &target).is_err());
+}
+
+#[test]
+fn test_macros()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+macro twice(x): x + x
+
+fn foo(a: int) -> int: twice!(a)
+"#, &target);
+    assert!(md.functions.contains_key("test::foo"));
+
+    assert!(parse_str(r#"
+macro twice(x): x + x
+
+fn foo(a: int) -> int: twice!(a, a)
+"#, "test", &target).is_err());
+
+    let md = th_mod(r#"
+macro max:
+    ($a) => $a
+    ($a, $($rest),*) => if $a > max!($($rest),*): $a else max!($($rest),*)
+
+fn foo(a: int, b: int) -> int: max!(a, b + 1, 3)
+"#, &target);
+    assert!(md.functions.contains_key("test::foo"));
+
+    // No rule matches no arguments
+    assert!(parse_str(r#"
+macro max:
+    ($a) => $a
+    ($a, $($rest),*) => if $a > max!($($rest),*): $a else max!($($rest),*)
+
+fn foo() -> int: max!()
+"#, "test", &target).is_err());
+
+    // A repeated fragment can only be used in a repetition
+    assert!(parse_str(r#"
+macro first:
+    ($($x),*) => $x
+
+fn foo() -> int: first!(1, 2)
+"#, "test", &target).is_err());
+
+    assert!(parse_str(r#"
+macro pair:
+    ($a, $a) => $a
+
+fn foo() -> int: pair!(1, 2)
+"#, "test", &target).is_err());
+
+    // The binding introduced by the body gets a name of its own, so it doesn't hide the t of the caller
+    let md = th_mod(r#"
+macro sum_squares(a, b): let t = a; t * t + b * b
+
+fn foo(t: int) -> int: sum_squares!(t + 1, t)
+"#, &target);
+    match md.functions.get("test::foo").unwrap().expression {
+        Expression::Block(ref b) => match b.expressions[0] {
+            Expression::Bindings(ref bl) => match bl.bindings[0].binding_type {
+                BindingType::Name(ref name) => assert!(name.starts_with("t#")),
+                _ => panic!("Expected a name binding"),
+            },
+            _ => panic!("Expected bindings"),
+        },
+        _ => panic!("Expected block"),
+    }
+}
+
+#[test]
+fn test_function_annotations()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+@deprecated("use bar")
+@must_use
+fn foo(a: int) -> int: a
+"#, &target);
+    let f = md.functions.get("test::foo").unwrap();
+    assert!(f.deprecated == Some(String::from("use bar")));
+    assert!(f.must_use);
+
+    assert!(parse_str(r#"
+@must_use
+struct Foo:
+    a: int
+"#, "test", &target).is_err());
+}
+
+#[test]
+fn test_loop_hints()
+{
+    let target = Target::new(IntSize::I32, "");
+    let e = th_expr(r#"
+@unroll(2) @vectorize(4) for x in data:
+    x
+"#, &target);
+    match e {
+        Expression::For(ref f) => {
+            assert_eq!(f.hints, LoopHints{vectorize: true, vectorize_width: Some(4), unroll_count: Some(2)});
+        },
+        _ => panic!("Expecting a for loop"),
+    }
+
+    assert!(parse_str(r#"
+fn foo(a: int) -> int:
+    @vectorize(3) while a > 0:
+        a
+"#, "test", &target).is_err());
+
+    assert!(parse_str(r#"
+fn foo(a: int) -> int:
+    @unroll(2) a + 1
+"#, "test", &target).is_err());
+}
+
+#[test]
+fn test_visitor()
+{
+    struct NameCounter
+    {
+        names: Vec<String>,
+        types: usize,
+    }
+
+    impl Visitor for NameCounter
+    {
+        type Error = ();
+
+        fn visit_name_ref(&mut self, nr: &NameRef) -> Result<(), ()>
+        {
+            self.names.push(nr.name.clone());
+            Ok(())
+        }
+
+        fn visit_type(&mut self, t: &Type) -> Result<(), ()>
+        {
+            self.types += 1;
+            walk_type(self, t)
+        }
+    }
+
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+fn foo(a: *int, b: int) -> int:
+    match b:
+        0 => *a
+        x => bar(x) + b
+"#, &target);
+
+    let mut counter = NameCounter{names: Vec::new(), types: 0};
+    counter.visit_module(&md).expect("Visit failed");
+    assert_eq!(counter.names, vec!["b", "a", "x", "bar", "x", "b"]);
+    assert_eq!(counter.types, 4);
+}
+
+#[test]
+fn test_multiple_parse_errors()
+{
+    let target = Target::new(IntSize::I32, "");
+    let errors = parse_str(r#"
+fn foo(a: int) -> int:
+    a +
+
+fn bar() -> int: 5
+
+fn baz(a int) -> int: a
+"#, "test", &target).err().expect("Parsing must fail").into_errors();
+    assert_eq!(errors.len(), 2);
+}
+
+#[test]
+fn test_include()
+{
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join("menhir_test_include.txt");
+    fs::write(&path, "ab\n").expect("Cannot write test file");
+
+    let target = Target::new(IntSize::I32, "");
+    let e = th_expr(&format!("@include_str(\"{}\")", path.to_string_lossy()), &target);
+    match e {
+        Expression::Literal(Literal::String(_, ref s)) => assert_eq!(s, "ab\n"),
+        _ => panic!("Expected string literal"),
+    }
+
+    let e = th_expr(&format!("@include_bytes(\"{}\")", path.to_string_lossy()), &target);
+    match e {
+        Expression::Literal(Literal::Array(ref a)) => {
+            assert_eq!(a.array_type, array_type(Type::UInt(IntSize::I8), 3));
+            let bytes: Vec<u64> = a.elements.iter()
+                .map(|e| match *e {
+                    Expression::Literal(Literal::UInt(_, v, IntSize::I8)) => v,
+                    _ => panic!("Expected uint8 literal"),
+                })
+                .collect();
+            assert_eq!(bytes, vec![97, 98, 10]);
+        },
+        _ => panic!("Expected array literal"),
+    }
+
+    let _ = fs::remove_file(&path);
+    assert!(parse_str("fn foo() -> string: @include_str(\"does_not_exist.txt\")", "test", &target).is_err());
+}
+
+#[test]
+fn test_generic_constraints()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+fn max(a: $T: Comparable + Printable, b: *$T, c: $U) -> $T[]: c
+"#, &target);
+    let f = md.functions.get("test::max").unwrap();
+    let t = generic_type_with_constraints("T", vec![unresolved_type("Comparable", Vec::new()), unresolved_type("Printable", Vec::new())]);
+    assert_eq!(f.sig.args[0].typ, t);
+    assert_eq!(f.sig.args[1].typ, ptr_type(t.clone()));
+    assert_eq!(f.sig.args[2].typ, generic_type("U"));
+    assert_eq!(f.sig.return_type, slice_type(t));
+
+    assert!(parse_str("fn foo(a: int: Comparable) -> int: a", "test", &target).is_err());
+    assert!(parse_str("fn foo(a: $T: Comparable, b: $T: Printable) -> int: 0", "test", &target).is_err());
+}
+
+#[test]
+fn test_array_length_generics()
+{
+    let target = Target::new(IntSize::I32, "");
+    let md = th_mod(r#"
+fn first(xs: $T[$N], n: int[4]) -> $T: xs[0]
+"#, &target);
+    let f = md.functions.get("test::first").unwrap();
+    assert_eq!(f.sig.args[0].typ, generic_array_type(generic_type("T"), "N"));
+    assert_eq!(f.sig.args[1].typ, array_type(target.native_int_type.clone(), 4));
+    assert!(f.sig.args[0].typ.is_generic());
+    assert_eq!(format!("{}", f.sig.args[0].typ), "$T[$N]");
+
+    assert!(parse_str("fn foo(a: int[$]) -> int: 0", "test", &target).is_err());
+}
+
+// Fully parenthesized form of an expression, to compare the way operators are grouped
+fn grouping(e: &Expression) -> String
+{
+    match *e {
+        Expression::BinaryOp(ref b) => format!("({} {} {})", grouping(&b.left), b.operator, grouping(&b.right)),
+        Expression::UnaryOp(ref u) => format!("({}{})", u.operator, grouping(&u.expression)),
+        Expression::Cast(ref t) => format!("({} as {})", grouping(&t.inner), t.destination_type),
+        Expression::NameRef(ref nr) => nr.name.clone(),
+        Expression::MemberAccess(ref ma) => match ma.right {
+            MemberAccessType::Name(ref f) => format!("{}.{}", grouping(&ma.left), f.name),
+            _ => panic!("Unexpected member access"),
+        },
+        _ => panic!("Unexpected expression"),
+    }
+}
+
+fn parse_grouping(code: &str) -> CompileResult<String>
+{
+    let target = Target::new(IntSize::I32, "");
+    let mut cursor = Cursor::new(code);
+    let mut tq = Lexer::new("").read(&mut cursor).expect("Lexing failed");
+    let (level, _) = tq.pop_indent().unwrap().unwrap();
+    parse_expression(&mut tq, level, &target).map(|e| grouping(&e))
+}
+
+#[test]
+fn test_operator_grouping()
+{
+    let cases = [
+        ("a - b - c", "((a - b) - c)"),
+        ("a / b / c", "((a / b) / c)"),
+        ("a - b + c * d % e", "((a - b) + ((c * d) % e))"),
+        ("a || b && c || d", "((a || (b && c)) || d)"),
+        ("a + b < c * d", "((a + b) < (c * d))"),
+        ("a == b && c != d", "((a == b) && (c != d))"),
+        ("-a * b", "((-a) * b)"),
+        ("-a + -b", "((-a) + (-b))"),
+        ("a - -b - c", "((a - (-b)) - c)"),
+        ("!a && b", "((!a) && b)"),
+        ("!a == b", "((!a) == b)"),
+        ("!(a && b)", "(!(a && b))"),
+        ("-a.b * c", "((-a.b) * c)"),
+        ("-a as uint8", "(-(a as uint8))"),
+        ("a + b as uint8", "(a + (b as uint8))"),
+        ("(a + b) as uint8 * c", "(((a + b) as uint8) * c)"),
+        ("a * (b - c) - d", "((a * (b - c)) - d)"),
+    ];
+
+    for &(code, expected) in &cases {
+        assert_eq!(parse_grouping(code).expect("Parsing failed"), expected, "{}", code);
+    }
+}
+
+#[test]
+fn test_operator_precedence_table()
+{
+    use ast::BinaryOperator::*;
+    let operators = [Mul, Div, Mod, Add, Sub, LessThan, GreaterThan, LessThanEquals, GreaterThanEquals, Equals, NotEquals, And, Or];
+
+    // Every pair of operators is grouped the way the operator table says
+    for first in &operators {
+        for second in &operators {
+            let code = format!("a {} b {} c", first, second);
+            let result = parse_grouping(&code);
+            if first.precedence() == second.precedence() && first.associativity() == Associativity::None {
+                assert!(result.is_err(), "{} should not parse", code);
+            } else if first.precedence() >= second.precedence() {
+                assert_eq!(result.expect("Parsing failed"), format!("((a {} b) {} c)", first, second));
+            } else {
+                assert_eq!(result.expect("Parsing failed"), format!("(a {} (b {} c))", first, second));
+            }
+        }
+    }
+
+    assert_eq!(parse_grouping("(a < b) < c").expect("Parsing failed"), "((a < b) < c)");
+    assert_eq!(parse_grouping("a < b && b < c").expect("Parsing failed"), "((a < b) && (b < c))");
+}
@@ -103,16 +103,16 @@ impl TokenQueue
         }
     }
 
-    pub fn expect_binary_operator(&mut self) -> CompileResult<BinaryOperator>
+    pub fn expect_string(&mut self) -> CompileResult<(String, Span)>
     {
         let tok = self.pop()?;
-        if let TokenKind::BinaryOperator(op) = tok.kind
+        if let TokenKind::StringLiteral(s) = tok.kind
         {
-            Ok(op)
+            Ok((s, tok.span))
         }
         else
         {
-            parse_error_result(&tok.span, format!("Expected operator, found {}", tok))
+            parse_error_result(&tok.span, format!("Expected string literal, found {}", tok))
         }
     }
 
@@ -135,12 +135,15 @@ impl TokenQueue
     }
 
 
-    pub fn is_next_binary_operator(&self) -> bool
+    pub fn is_next_binary_operator(&self) -> Option<BinaryOperator>
     {
-        match self.tokens.front()
-        {
-            Some(tok) => if let TokenKind::BinaryOperator(_) = tok.kind {true} else {false},
-            None => false,
+        match self.tokens.front() {
+            Some(ref tok) => if let TokenKind::BinaryOperator(op) = tok.kind {
+                Some(op)
+            } else {
+                None
+            },
+            _ => None,
         }
     }
 
@@ -8,6 +8,10 @@ pub enum TokenKind
     Identifier(String),
     Number(String),
     StringLiteral(String),
+    ByteStringLiteral(Vec<u8>),
+    StringInterpolationStart(String),
+    StringInterpolationMiddle(String),
+    StringInterpolationEnd(String),
     CharLiteral(char),
     BinaryOperator(BinaryOperator),
     UnaryOperator(UnaryOperator),
@@ -38,21 +42,29 @@ pub enum TokenKind
     False,
     Type,
     Struct,
+    Union,
     Enum,
     Tilde,
+    Backslash,
     New,
     Delete,
     QuestionMark,
+    OptionalDot,
+    DotDot,
     Nil,
     Null,
     Var,
+    Ref,
     For,
     Interface,
+    Impl,
     Func,
+    Pub,
     Indent(usize),
     Ampersand,
     At,
     Return,
+    Macro,
     EOF,
 }
 
@@ -65,6 +77,10 @@ impl Display for TokenKind
             TokenKind::Identifier(ref s) => write!(fmt, "identifier '{}'", s),
             TokenKind::Number(ref n) => write!(fmt, "number '{}'", n),
             TokenKind::StringLiteral(ref s) => write!(fmt, "string litteral '{}'", s),
+            TokenKind::ByteStringLiteral(ref b) => write!(fmt, "byte string literal '{}'", String::from_utf8_lossy(b)),
+            TokenKind::StringInterpolationStart(ref s) => write!(fmt, "start of interpolated string '{}'", s),
+            TokenKind::StringInterpolationMiddle(ref s) => write!(fmt, "interpolated string part '{}'", s),
+            TokenKind::StringInterpolationEnd(ref s) => write!(fmt, "end of interpolated string '{}'", s),
             TokenKind::CharLiteral(c) => write!(fmt, "char literal '{}'", c),
             TokenKind::BinaryOperator(ref op) => write!(fmt, "operator {}", op),
             TokenKind::UnaryOperator(ref op) => write!(fmt, "operator {}", op),
@@ -95,21 +111,29 @@ impl Display for TokenKind
             TokenKind::False => write!(fmt, "false"),
             TokenKind::Type => write!(fmt, "type"),
             TokenKind::Struct => write!(fmt, "struct"),
+            TokenKind::Union => write!(fmt, "union"),
             TokenKind::Enum => write!(fmt, "enum"),
             TokenKind::Tilde => write!(fmt, "~"),
+            TokenKind::Backslash => write!(fmt, "\\"),
             TokenKind::New => write!(fmt, "new"),
             TokenKind::Delete => write!(fmt, "delete"),
             TokenKind::QuestionMark => write!(fmt, "?"),
+            TokenKind::OptionalDot => write!(fmt, "?."),
+            TokenKind::DotDot => write!(fmt, ".."),
             TokenKind::Nil => write!(fmt, "nil"),
             TokenKind::Null => write!(fmt, "null"),
             TokenKind::Var => write!(fmt, "var"),
+            TokenKind::Ref => write!(fmt, "ref"),
             TokenKind::For => write!(fmt, "for"),
             TokenKind::Interface => write!(fmt, "interface"),
+            TokenKind::Impl => write!(fmt, "impl"),
             TokenKind::Func => write!(fmt, "fn"),
+            TokenKind::Pub => write!(fmt, "pub"),
             TokenKind::Indent(lvl) => write!(fmt, "indent {}", lvl),
             TokenKind::Ampersand => write!(fmt, "&"),
             TokenKind::At => write!(fmt, "@"),
             TokenKind::Return => write!(fmt, "return"),
+            TokenKind::Macro => write!(fmt, "macro"),
             TokenKind::EOF => write!(fmt, "EOF"),
         }
     }
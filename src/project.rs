@@ -0,0 +1,146 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use compileerror::{CompileResult, CompileError};
+use json::json_string;
+
+/*
+Scaffolding for new packages, used by the new and init subcommands. A project consists of:
+- package.toml: the manifest, with one binary target
+- src/main.mhr: a hello world program
+- .gitignore: ignoring the build directory
+*/
+
+const HELLO_WORLD: &str = r#"extern fn puts(s: *uint8) -> int32
+
+fn main() -> int:
+    puts("Hello, world!".data)
+    0
+"#;
+
+const GITIGNORE: &str = "/build/\n";
+
+// Package names end up in module and symbol names, so they must be valid identifiers
+fn check_package_name(name: &str) -> CompileResult<()>
+{
+    let valid = name.chars().next().map(|c| c.is_alphabetic() || c == '_').unwrap_or(false) &&
+        name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(CompileError::Other(format!("Invalid package name {}, it must start with a letter or _, followed by letters, digits or _", name)))
+    }
+}
+
+fn git_config(key: &str) -> Option<String>
+{
+    let output = try_opt!(Command::new("git").args(&["config", "--get", key]).output().ok());
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !value.is_empty() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn author() -> (String, String)
+{
+    let name = git_config("user.name")
+        .or_else(|| env::var("USER").ok())
+        .unwrap_or_default();
+    let email = git_config("user.email").unwrap_or_default();
+    (name, email)
+}
+
+// JSON string escapes are also valid in TOML basic strings
+fn manifest(name: &str, author: &str, email: &str) -> String
+{
+    format!(r#"[package]
+name = {name}
+author = {author}
+email = {email}
+license = ""
+version = "0.1.0"
+
+[[target]]
+name = {name}
+type = "binary"
+path = "src/main.mhr"
+"#, name = json_string(name), author = json_string(author), email = json_string(email))
+}
+
+fn write_new_file(path: &Path, contents: &str) -> CompileResult<()>
+{
+    let mut file = File::create(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+// Create a project in an existing directory, existing sources and .gitignore files are left alone
+pub fn init_project(dir: &Path, name: &str) -> CompileResult<()>
+{
+    check_package_name(name)?;
+    let manifest_path = dir.join("package.toml");
+    if manifest_path.exists() {
+        return Err(CompileError::Other(format!("{} already exists", manifest_path.to_string_lossy())));
+    }
+
+    let (author, email) = author();
+    fs::create_dir_all(dir.join("src"))?;
+    write_new_file(&manifest_path, &manifest(name, &author, &email))?;
+
+    let main_path = dir.join("src").join("main.mhr");
+    if !main_path.exists() {
+        write_new_file(&main_path, HELLO_WORLD)?;
+    }
+
+    let gitignore_path = dir.join(".gitignore");
+    if !gitignore_path.exists() {
+        write_new_file(&gitignore_path, GITIGNORE)?;
+    }
+    Ok(())
+}
+
+// Create a project in a new directory named after the package
+pub fn new_project(dir: &Path) -> CompileResult<()>
+{
+    if dir.exists() {
+        return Err(CompileError::Other(format!("{} already exists, use init to create a package in an existing directory", dir.to_string_lossy())));
+    }
+
+    let name = dir.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| CompileError::Other(format!("Cannot determine the package name of {}", dir.to_string_lossy())))?;
+    check_package_name(&name)?;
+    init_project(dir, &name)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{new_project, init_project, check_package_name};
+    use packagebuild::PackageData;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_new_project()
+    {
+        assert!(check_package_name("hello_world2").is_ok());
+        assert!(check_package_name("2hello").is_err());
+        assert!(check_package_name("hello-world").is_err());
+
+        let dir = env::temp_dir().join(format!("menhir-new-{}", process::id())).join("hello");
+        new_project(&dir).expect("Creating project failed");
+        assert!(dir.join("src").join("main.mhr").exists());
+        assert!(dir.join(".gitignore").exists());
+        assert!(PackageData::load(dir.join("package.toml")).is_ok());
+        assert!(new_project(&dir).is_err());
+        assert!(init_project(&dir, "hello").is_err());
+        let _ = fs::remove_dir_all(dir.parent().expect("No parent directory"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use ast::{Module, Import, ImportMap};
+
+/*
+    The front-end as memoized queries. A query stores its result per item, a source file or a module,
+    together with a hash of everything the result depends on:
+
+    - parse: the source of the file
+    - type check: the source of the module, and the ABI hashes of the modules it imports
+
+    Asking a query again with the same input hash gives the stored result, so a tool which keeps the
+    queries around between checks, like the workspace of the ide command, only redoes the items which changed.
+    A module whose exported symbols don't change, doesn't make the modules importing it to be checked again.
+*/
+
+// FNV-1a, so hashes stay the same between compiler builds
+pub fn hash_input(hash: u64, data: &str) -> u64
+{
+    let mut hash = hash;
+    for b in data.bytes().chain(Some(0)) {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub const INITIAL_HASH: u64 = 0xcbf29ce484222325;
+
+// The input of the type check query of a module
+pub fn type_check_input_hash(source_hash: u64, imports: &ImportMap) -> u64
+{
+    let mut names: Vec<&String> = imports.keys().collect();
+    names.sort();
+    names.iter().fold(source_hash, |hash, name| hash_input(hash, &format!("{}={:016x}", name, imports[*name].abi_hash)))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueryTable<V>
+{
+    entries: HashMap<String, (u64, V)>,
+    #[serde(skip)]
+    pub hits: usize,
+    #[serde(skip)]
+    pub misses: usize,
+}
+
+impl<V: Clone> QueryTable<V>
+{
+    pub fn new() -> QueryTable<V>
+    {
+        QueryTable{
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // The stored result for the item, if it was computed from the same inputs
+    pub fn get(&mut self, key: &str, input_hash: u64) -> Option<V>
+    {
+        match self.entries.get(key) {
+            Some(&(hash, ref value)) if hash == input_hash => {
+                self.hits += 1;
+                Some(value.clone())
+            },
+            _ => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, input_hash: u64, value: V)
+    {
+        self.entries.insert(key.into(), (input_hash, value));
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool
+    {
+        self.entries.is_empty()
+    }
+}
+
+impl<V: Clone> Default for QueryTable<V>
+{
+    fn default() -> QueryTable<V>
+    {
+        QueryTable::new()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CheckedModule
+{
+    pub module: Module,
+    pub import: Rc<Import>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Queries
+{
+    // Keyed by file and namespace, parsing is cheap enough to not be saved in the check cache
+    #[serde(skip)]
+    pub parse: QueryTable<Module>,
+    // Keyed by module name
+    pub type_check: QueryTable<CheckedModule>,
+}
+
+impl Queries
+{
+    pub fn new() -> Queries
+    {
+        Queries::default()
+    }
+}
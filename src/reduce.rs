@@ -0,0 +1,161 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use ast::LogLevel;
+use bytecode::{compile_to_byte_code, optimize_module, OptimizationLevel, DEFAULT_INLINE_THRESHOLD};
+use compileerror::{CompileResult, CompileError};
+use ide::check_package;
+use target::Target;
+
+/*
+Test case reduction for compiler bugs: lines are removed from a program for as long as compiling it
+still fails with an error containing the check string. A line is removed together with the lines
+indented below it, so whole declarations, blocks and statements are removed at once.
+
+Compilation runs in-process up to and including the bytecode optimizer. Panics are caught and
+reported as errors starting with ICE, so --check ICE reduces any internal compiler error.
+*/
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String
+{
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else {
+        "unknown panic".into()
+    }
+}
+
+fn compile(path: &Path, code: &str, target: &Target) -> CompileResult<()>
+{
+    let mut source_buffers = HashMap::new();
+    source_buffers.insert(path.to_path_buf(), code.to_string());
+    let pkg = check_package(path, target, &source_buffers)?;
+    let mut bc_mod = compile_to_byte_code(&pkg, target, false, LogLevel::Debug)?;
+    optimize_module(&mut bc_mod, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+    Ok(())
+}
+
+// The error message when compiling code fails, or None if it compiles
+fn failure(path: &Path, code: &str, target: &Target) -> Option<String>
+{
+    match panic::catch_unwind(AssertUnwindSafe(|| compile(path, code, target))) {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(payload) => Some(format!("ICE: {}", panic_message(payload))),
+    }
+}
+
+fn indentation(line: &str) -> usize
+{
+    line.len() - line.trim_start().len()
+}
+
+// End of the line at start and all lines indented below it
+fn chunk_end(lines: &[String], start: usize) -> usize
+{
+    if lines[start].trim().is_empty() {
+        return start + 1;
+    }
+
+    let indent = indentation(&lines[start]);
+    lines[start + 1..].iter()
+        .position(|l| !l.trim().is_empty() && indentation(l) <= indent)
+        .map(|idx| start + 1 + idx)
+        .unwrap_or(lines.len())
+}
+
+fn join_lines(lines: &[String]) -> String
+{
+    lines.iter().fold(String::new(), |mut code, l| {
+        code.push_str(l);
+        code.push('\n');
+        code
+    })
+}
+
+// Reduce code to a smaller program which still fails with an error containing check
+pub fn reduce(path: &Path, code: &str, check: &str, target: &Target) -> CompileResult<String>
+{
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| ()));
+    let still_fails = |code: &str| failure(path, code, target).map(|msg| msg.contains(check)).unwrap_or(false);
+
+    if !still_fails(code) {
+        panic::set_hook(hook);
+        return Err(CompileError::Other(format!("Compiling {} does not fail with an error containing \"{}\"", path.to_string_lossy(), check)));
+    }
+
+    let mut lines: Vec<String> = code.lines().map(|l| l.to_string()).collect();
+    loop {
+        let mut changed = false;
+        let mut idx = 0;
+        while idx < lines.len() {
+            let end = chunk_end(&lines, idx);
+            let mut candidate = lines[..idx].to_vec();
+            candidate.extend_from_slice(&lines[end..]);
+            if still_fails(&join_lines(&candidate)) {
+                lines = candidate;
+                changed = true;
+            } else {
+                idx += 1;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    panic::set_hook(hook);
+    Ok(join_lines(&lines))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{reduce, chunk_end};
+    use ast::IntSize;
+    use target::Target;
+    use std::path::Path;
+
+    #[test]
+    fn test_chunk_end()
+    {
+        let lines: Vec<String> = vec!["fn foo():", "    if x:", "        bar()", "", "    baz()", "fn main():"]
+            .into_iter()
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(chunk_end(&lines, 0), 5);
+        assert_eq!(chunk_end(&lines, 1), 4);
+        assert_eq!(chunk_end(&lines, 3), 4);
+        assert_eq!(chunk_end(&lines, 5), 6);
+    }
+
+    #[test]
+    fn test_reduce()
+    {
+        let prog = r#"
+struct Vec2D:
+    x: int
+    y: int
+
+fn add(a: Vec2D, b: Vec2D) -> Vec2D:
+    Vec2D{a.x + b.x, a.y + b.y}
+
+fn main() -> int:
+    let v = add(Vec2D{3, 4}, Vec2D{2, 2})
+    let y = bla + 1
+    v.x * v.y
+"#;
+        let target = Target::new(IntSize::I32, "");
+        let reduced = reduce(Path::new("test.mhr"), prog, "Unknown name bla", &target).expect("Reduce failed");
+        assert!(reduced.contains("bla"));
+        assert!(!reduced.contains("Vec2D"));
+        assert!(reduced.lines().count() < 4);
+        assert!(reduce(Path::new("test.mhr"), prog, "Unknown name foo", &target).is_err());
+    }
+}
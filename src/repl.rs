@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::slice;
+
+use ast::{LogLevel, Type};
+use bytecode::{compile_to_byte_code, optimize_module, OptimizationLevel, DEFAULT_INLINE_THRESHOLD};
+use compileerror::{CompileResult, CompileError};
+use ide::check_package;
+use llvmbackend::{CodeGenOptions, ExecutionEngine, TargetMachine, llvm_code_generation};
+use package::Package;
+
+/*
+Interactive evaluation. Every declaration typed in the REPL is added to a session module, and every
+expression is evaluated in the main function of a copy of that module, which is type checked, compiled
+and run with the JIT. The value of the expression is formatted by code generated from its type, into
+a string global, which is read back after main returns.
+
+Only declarations are kept between inputs, so the initializers of globals run again for every expression.
+*/
+
+const SESSION_NAME: &str = "repl";
+const VALUE_NAME: &str = "__repl_value";
+const OUTPUT_NAME: &str = "__repl_output";
+
+const DECLARATION_KEYWORDS: [&str; 13] = [
+    "import", "pub", "fn", "let", "var", "extern", "type", "struct", "union", "enum", "interface", "impl", "@thread_local",
+];
+
+// Whether input is a declaration, instead of an expression. A lambda starts with fn as well, but is followed by its arguments.
+pub fn is_declaration(input: &str) -> bool
+{
+    let input = input.trim_start();
+    let keyword = input.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '@')).next().unwrap_or("");
+    DECLARATION_KEYWORDS.contains(&keyword) && !input[keyword.len()..].trim_start().starts_with('(')
+}
+
+// Quote s as a string literal
+fn string_literal(s: &str) -> String
+{
+    let mut lit = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                lit.push('\\');
+                lit.push(c);
+            },
+            '\n' => lit.push_str("\\n"),
+            _ => lit.push(c),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+// The last part of a namespaced name, Square for geometry::Square
+fn short_name(name: &str) -> &str
+{
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/*
+Generates the statements which append the representation of a value to the output string, driven by
+the type of the value: numbers and booleans as with @format, strings and characters quoted, arrays
+and slices as [1, 2, 3], structs as Square{side: 2}, optionals as their value or nil, and sum types and
+enums as their case. Values of other types, like pointers and functions, are shown as their type.
+*/
+pub struct ValuePrinter
+{
+    output: String,
+    lines: Vec<String>,
+    next_var: usize,
+}
+
+impl ValuePrinter
+{
+    pub fn new(output: &str) -> ValuePrinter
+    {
+        ValuePrinter{
+            output: output.into(),
+            lines: Vec::new(),
+            next_var: 0,
+        }
+    }
+
+    // The generated statements, indented for the body of a function
+    pub fn into_code(self) -> String
+    {
+        self.lines.iter().map(|line| format!("    {}\n", line)).collect()
+    }
+
+    fn line(&mut self, indent: usize, line: String)
+    {
+        self.lines.push(format!("{}{}", "    ".repeat(indent), line));
+    }
+
+    fn append(&mut self, indent: usize, parts: &str)
+    {
+        let line = format!("{} = @format({}, {})", self.output, self.output, parts);
+        self.line(indent, line);
+    }
+
+    fn append_str(&mut self, indent: usize, s: &str)
+    {
+        let lit = string_literal(s);
+        self.append(indent, &lit);
+    }
+
+    fn new_var(&mut self, name: &str) -> String
+    {
+        self.next_var += 1;
+        format!("__repl_{}_{}", name, self.next_var)
+    }
+
+    pub fn print(&mut self, value: &str, typ: &Type, indent: usize)
+    {
+        match *typ
+        {
+            Type::Int(_) | Type::UInt(_) | Type::Float(_) | Type::Bool => {
+                self.append(indent, value);
+            },
+
+            Type::Char => {
+                self.append(indent, &format!("\"'\", {}, \"'\"", value));
+            },
+
+            Type::String => {
+                self.append(indent, &format!("\"\\\"\", {}, \"\\\"\"", value));
+            },
+
+            Type::Array(ref at) => self.print_sequence(value, &at.element_type, indent),
+            Type::Slice(ref st) => self.print_sequence(value, &st.element_type, indent),
+
+            Type::Struct(ref st) if st.members.iter().all(|m| !m.name.is_empty()) => {
+                self.append_str(indent, &format!("{}{{", short_name(&st.name)));
+                for (idx, m) in st.members.iter().enumerate() {
+                    let sep = if idx == 0 {""} else {", "};
+                    self.append_str(indent, &format!("{}{}: ", sep, m.name));
+                    self.print(&format!("{}.{}", value, m.name), &m.typ, indent);
+                }
+                self.append_str(indent, "}");
+            },
+
+            Type::Optional(ref inner) => {
+                let var = self.new_var("inner");
+                self.line(indent, format!("match {}:", value));
+                self.line(indent + 1, format!("?{} =>", var));
+                self.print(&var, inner, indent + 2);
+                self.line(indent + 1, "nil =>".into());
+                self.append_str(indent + 2, "nil");
+            },
+
+            Type::Enum(ref et) => {
+                self.line(indent, format!("match {}:", value));
+                for case in &et.cases {
+                    self.line(indent + 1, format!("{} =>", case));
+                    self.append_str(indent + 2, short_name(case));
+                }
+            },
+
+            Type::Sum(ref st) => {
+                self.line(indent, format!("match {}:", value));
+                for case in &st.cases {
+                    match case.typ {
+                        Type::Struct(ref cs) if !cs.members.is_empty() && cs.members.iter().all(|m| !m.name.is_empty()) => {
+                            let vars: Vec<String> = cs.members.iter().map(|_| self.new_var("member")).collect();
+                            self.line(indent + 1, format!("{}{{{}}} =>", case.name, vars.join(", ")));
+                            self.append_str(indent + 2, &format!("{}{{", short_name(&case.name)));
+                            for (idx, (m, var)) in cs.members.iter().zip(vars.iter()).enumerate() {
+                                let sep = if idx == 0 {""} else {", "};
+                                self.append_str(indent + 2, &format!("{}{}: ", sep, m.name));
+                                self.print(var, &m.typ, indent + 2);
+                            }
+                            self.append_str(indent + 2, "}");
+                        },
+                        _ => {
+                            self.line(indent + 1, format!("{} =>", case.name));
+                            self.append_str(indent + 2, short_name(&case.name));
+                        },
+                    }
+                }
+            },
+
+            _ => {
+                self.append_str(indent, &format!("<{}>", typ));
+            },
+        }
+    }
+
+    fn print_sequence(&mut self, value: &str, element_type: &Type, indent: usize)
+    {
+        let first = self.new_var("first");
+        let element = self.new_var("element");
+        self.append_str(indent, "[");
+        self.line(indent, format!("var {} = true", first));
+        self.line(indent, format!("for {} in {}:", element, value));
+        self.line(indent + 1, format!("if !{}:", first));
+        self.append_str(indent + 2, ", ");
+        self.line(indent + 1, format!("{} = false", first));
+        self.print(&element, element_type, indent + 1);
+        self.append_str(indent, "]");
+    }
+}
+
+pub struct Repl
+{
+    target_machine: TargetMachine,
+    declarations: Vec<String>,
+}
+
+impl Repl
+{
+    pub fn new(target_machine: TargetMachine) -> Repl
+    {
+        Repl{
+            target_machine,
+            declarations: Vec::new(),
+        }
+    }
+
+    fn session_source(&self, extra: &str) -> String
+    {
+        let mut source = String::new();
+        for decl in &self.declarations {
+            source.push_str(decl);
+            source.push_str("\n\n");
+        }
+        source.push_str(extra);
+        source.push('\n');
+        source
+    }
+
+    fn check(&self, source: String) -> CompileResult<Package>
+    {
+        let path = PathBuf::from(format!("{}.mhr", SESSION_NAME));
+        let mut buffers = HashMap::new();
+        buffers.insert(path.clone(), source);
+        check_package(&path, &self.target_machine.target, &buffers)
+    }
+
+    // The type of an expression, in the context of the session
+    fn expression_type(&self, expr: &str) -> CompileResult<Type>
+    {
+        let pkg = self.check(self.session_source(&format!("let {} = {}", VALUE_NAME, expr)))?;
+        let name = format!("{}::{}", SESSION_NAME, VALUE_NAME);
+        pkg.modules.values()
+            .filter_map(|m| m.globals.get(&name))
+            .map(|g| g.typ.clone())
+            .next()
+            .ok_or_else(|| CompileError::Other(format!("Internal Compiler Error: cannot find the type of {}", expr)))
+    }
+
+    // Evaluate a declaration or an expression, returns the value of an expression which has one
+    pub fn eval(&mut self, input: &str) -> CompileResult<Option<String>>
+    {
+        if is_declaration(input) {
+            self.check(self.session_source(input))?;
+            self.declarations.push(input.into());
+            return Ok(None);
+        }
+
+        let typ = self.expression_type(input)?;
+        let mut main = format!("var {} = \"\"\n\nfn main() -> int:\n", OUTPUT_NAME);
+        if typ == Type::Void {
+            main.push_str(&format!("    {}\n", input));
+        } else {
+            main.push_str(&format!("    let {} = {}\n", VALUE_NAME, input));
+            let mut printer = ValuePrinter::new(OUTPUT_NAME);
+            printer.print(VALUE_NAME, &typ, 0);
+            main.push_str(&printer.into_code());
+        }
+        main.push_str("    0");
+
+        let output = self.run(self.session_source(&main))?;
+        Ok(if typ == Type::Void {None} else {Some(format!("{}: {}", output, typ))})
+    }
+
+    // Compile and run a program, and return the contents of the output string after main returns
+    fn run(&self, source: String) -> CompileResult<String>
+    {
+        let target = &self.target_machine.target;
+        let pkg = self.check(source)?;
+        let mut bc_mod = compile_to_byte_code(&pkg, target, false, LogLevel::Debug)?;
+        optimize_module(&mut bc_mod, OptimizationLevel::Minimal, DEFAULT_INLINE_THRESHOLD);
+
+        let opts = CodeGenOptions{
+            intern_strings: true,
+            ..CodeGenOptions::default()
+        };
+        let mut ctx = llvm_code_generation(&bc_mod, &self.target_machine, &opts)?;
+        unsafe {
+            let mut engine = ExecutionEngine::new(&mut ctx)?;
+            engine.run_main()?;
+            let output_name = format!("{}::{}", SESSION_NAME, OUTPUT_NAME);
+            let output = engine.global_address(&output_name)
+                .ok_or_else(|| CompileError::Other(format!("Internal Compiler Error: cannot find global {}", output_name)))?;
+            // A string is a pointer to the data followed by the length
+            let string = &*(output as *const (*const u8, usize));
+            let bytes = slice::from_raw_parts(string.0, string.1);
+            Ok(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
+
+/*
+Read the next input. A line ending with a colon starts an indented block, like the body of a function,
+which continues until an empty line. Returns None at the end of the input.
+*/
+pub fn read_input(prompt: &str, continuation_prompt: &str) -> io::Result<Option<String>>
+{
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut input = match lines.next() {
+        Some(line) => line?,
+        None => return Ok(None),
+    };
+
+    if input.trim_end().ends_with(':') {
+        loop {
+            print!("{}", continuation_prompt);
+            io::stdout().flush()?;
+            match lines.next() {
+                Some(line) => {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    input.push('\n');
+                    input.push_str(&line);
+                },
+                None => break,
+            }
+        }
+    }
+
+    Ok(Some(input))
+}
+
+// Read, evaluate and print until the end of the input, or :quit
+pub fn run_repl(target_machine: TargetMachine) -> CompileResult<()>
+{
+    let mut repl = Repl::new(target_machine);
+    while let Some(input) = read_input("> ", "... ")? {
+        let input = input.trim_end();
+        if input == ":quit" {
+            break;
+        } else if input.trim().is_empty() {
+            continue;
+        }
+
+        match repl.eval(input) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => (),
+            Err(e) => e.print(),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use ast::{array_type, optional_type, struct_member, struct_type, Type, IntSize};
+    use super::{is_declaration, string_literal, ValuePrinter};
+
+    #[test]
+    fn test_is_declaration()
+    {
+        assert!(is_declaration("fn double(x: int) -> int: x * 2"));
+        assert!(is_declaration("let answer = 42"));
+        assert!(is_declaration("import path"));
+        assert!(!is_declaration("fn(x) -> x * 2"));
+        assert!(!is_declaration("double(21)"));
+        assert!(!is_declaration("letter + 1"));
+        assert!(!is_declaration("@format(1, 2)"));
+    }
+
+    #[test]
+    fn test_string_literal()
+    {
+        assert_eq!(string_literal("a\"b"), r#""a\"b""#);
+        assert_eq!(string_literal("$T"), r#""\$T""#);
+    }
+
+    #[test]
+    fn test_print_struct()
+    {
+        let typ = struct_type("repl::Point", vec![struct_member("x", Type::Int(IntSize::I64)), struct_member("y", Type::Int(IntSize::I64))]);
+        let mut printer = ValuePrinter::new("out");
+        printer.print("p", &typ, 0);
+        assert_eq!(printer.into_code(), concat!(
+            "    out = @format(out, \"Point{\")\n",
+            "    out = @format(out, \"x: \")\n",
+            "    out = @format(out, p.x)\n",
+            "    out = @format(out, \", y: \")\n",
+            "    out = @format(out, p.y)\n",
+            "    out = @format(out, \"}\")\n",
+        ));
+    }
+
+    #[test]
+    fn test_print_array_of_optionals()
+    {
+        let typ = array_type(optional_type(Type::Bool), 2);
+        let mut printer = ValuePrinter::new("out");
+        printer.print("a", &typ, 0);
+        assert_eq!(printer.into_code(), concat!(
+            "    out = @format(out, \"[\")\n",
+            "    var __repl_first_1 = true\n",
+            "    for __repl_element_2 in a:\n",
+            "        if !__repl_first_1:\n",
+            "            out = @format(out, \", \")\n",
+            "        __repl_first_1 = false\n",
+            "        match __repl_element_2:\n",
+            "            ?__repl_inner_3 =>\n",
+            "                out = @format(out, __repl_inner_3)\n",
+            "            nil =>\n",
+            "                out = @format(out, \"nil\")\n",
+            "    out = @format(out, \"]\")\n",
+        ));
+    }
+}
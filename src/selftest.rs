@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::Command;
+
+use bytecode::{compile_to_byte_code, optimize_module, OptimizationLevel};
+use compileerror::{CompileResult, CompileError};
+use ide::check_package;
+use interpreter::run_module;
+use llvmbackend::OutputType;
+use packagebuild::{PackageData, BuildOptions};
+
+/*
+Differential testing: a program is run by the bytecode interpreter and compiled by the LLVM backend,
+and the results are compared. The interpreter is the reference, so a difference points at a bug in
+code generation (or the interpreter). Only the exit code and whether the program failed at runtime are
+compared, because the interpreter cannot call external functions and thus produces no output.
+*/
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome
+{
+    Exit(i32),
+    Failed(String),
+}
+
+impl fmt::Display for Outcome
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            Outcome::Exit(code) => write!(f, "exit code {}", code),
+            Outcome::Failed(ref msg) => write!(f, "failure ({})", msg),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SelfTestResult
+{
+    Agree(Outcome),
+    Disagree{interpreted: Outcome, compiled: Outcome},
+    // The interpreter cannot run the program, for example because it calls an external function
+    Unsupported(String),
+}
+
+impl fmt::Display for SelfTestResult
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            SelfTestResult::Agree(ref outcome) => write!(f, "interpreter and compiled program agree: {}", outcome),
+            SelfTestResult::Disagree{ref interpreted, ref compiled} =>
+                write!(f, "interpreter and compiled program disagree:\n  interpreted: {}\n  compiled: {}", interpreted, compiled),
+            SelfTestResult::Unsupported(ref msg) => write!(f, "program cannot be interpreted: {}", msg),
+        }
+    }
+}
+
+// A runtime failure of the interpreter matches any crash of the compiled program, exit codes have to be equal
+fn compare(interpreted: Outcome, compiled: Outcome) -> SelfTestResult
+{
+    let agree = match (&interpreted, &compiled)
+    {
+        (&Outcome::Exit(a), &Outcome::Exit(b)) => a == b,
+        (&Outcome::Failed(_), &Outcome::Failed(_)) => true,
+        _ => false,
+    };
+
+    if agree {
+        SelfTestResult::Agree(interpreted)
+    } else {
+        SelfTestResult::Disagree{interpreted, compiled}
+    }
+}
+
+fn interpret(path: &Path, build_options: &BuildOptions) -> CompileResult<Result<Outcome, String>>
+{
+    let target = &build_options.target_machine.target;
+    let pkg = check_package(path, target, &HashMap::new())?;
+    let mut bc_mod = compile_to_byte_code(&pkg, target, build_options.eager_globals, build_options.profile.log_level)?;
+    optimize_module(&mut bc_mod, OptimizationLevel::Minimal, build_options.profile.inline_threshold);
+    match run_module(&bc_mod, target.int_size) {
+        // Like the exit status of a process, only the lowest byte of the return value of main is kept
+        Ok(ret) => Ok(Ok(Outcome::Exit((ret & 0xFF) as i32))),
+        Err(e) => {
+            let msg = e.to_string();
+            if msg.contains("cannot call external function") {
+                Ok(Err(msg))
+            } else {
+                Ok(Ok(Outcome::Failed(msg)))
+            }
+        },
+    }
+}
+
+fn run_compiled(path: &Path, build_options: &mut BuildOptions) -> CompileResult<Outcome>
+{
+    let pkg = PackageData::single_file(path, OutputType::Binary)?;
+    let outputs = pkg.build(build_options)?;
+    let status = Command::new(&outputs[0])
+        .status()
+        .map_err(|e| CompileError::Other(format!("Unable to run {}: {}", outputs[0].to_string_lossy(), e)))?;
+
+    Ok(match (status.code(), status.signal()) {
+        (Some(code), _) => Outcome::Exit(code),
+        (None, Some(signal)) => Outcome::Failed(format!("killed by signal {}", signal)),
+        (None, None) => Outcome::Failed(status.to_string()),
+    })
+}
+
+// Run the program in path with the interpreter and as a compiled binary, and compare the results
+pub fn selftest(path: &Path, build_options: &mut BuildOptions) -> CompileResult<SelfTestResult>
+{
+    let interpreted = match interpret(path, build_options)? {
+        Ok(outcome) => outcome,
+        Err(msg) => return Ok(SelfTestResult::Unsupported(msg)),
+    };
+
+    let compiled = run_compiled(path, build_options)?;
+    Ok(compare(interpreted, compiled))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{compare, Outcome, SelfTestResult};
+
+    #[test]
+    fn test_compare()
+    {
+        assert_eq!(compare(Outcome::Exit(3), Outcome::Exit(3)), SelfTestResult::Agree(Outcome::Exit(3)));
+        assert_eq!(
+            compare(Outcome::Exit(3), Outcome::Exit(4)),
+            SelfTestResult::Disagree{interpreted: Outcome::Exit(3), compiled: Outcome::Exit(4)}
+        );
+        assert_eq!(
+            compare(Outcome::Failed("division by zero".into()), Outcome::Failed("killed by signal 8".into())),
+            SelfTestResult::Agree(Outcome::Failed("division by zero".into()))
+        );
+        assert_eq!(
+            compare(Outcome::Failed("division by zero".into()), Outcome::Exit(0)),
+            SelfTestResult::Disagree{interpreted: Outcome::Failed("division by zero".into()), compiled: Outcome::Exit(0)}
+        );
+    }
+}
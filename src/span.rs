@@ -100,6 +100,11 @@ impl Span
     {
         Span::new(&self.file, self.start, new_end)
     }
+
+    pub fn contains(&self, pos: Pos) -> bool
+    {
+        self.start <= pos && pos <= self.end
+    }
 }
 
 impl Default for Span
@@ -0,0 +1,183 @@
+use std::path::Path;
+use ast::Module;
+use compileerror::CompileResult;
+use parser::parse_buffer;
+use target::Target;
+
+/*
+    Modules which come with the compiler, written in menhir. They live in the lib directory of the
+    repository, and are added to a package when one of its modules imports them, and nothing else
+    provides a module with that name.
+
+    Modules can have a different version for Windows in lib/windows, the other modules only use their API.
+*/
+const MODULES: &[(&str, &str, Option<&str>)] = &[
+    ("sys", include_str!("../lib/sys.mhr"), Some(include_str!("../lib/windows/sys.mhr"))),
+    ("path", include_str!("../lib/path.mhr"), None),
+    ("fs", include_str!("../lib/fs.mhr"), None),
+    ("net", include_str!("../lib/net.mhr"), None),
+    ("string", include_str!("../lib/string.mhr"), None),
+    ("vector", include_str!("../lib/vector.mhr"), None),
+];
+
+// The code of a built-in module for the target, and the directory it appears to be in
+fn builtin_module(name: &str, target: &Target) -> Option<(&'static str, &'static str)>
+{
+    MODULES.iter()
+        .find(|&&(n, _, _)| n == name)
+        .map(|&(_, code, windows_code)| match windows_code {
+            Some(windows_code) if target.is_windows() => (windows_code, "<builtin>/windows"),
+            _ => (code, "<builtin>"),
+        })
+}
+
+pub fn builtin_module_source(name: &str, target: &Target) -> Option<&'static str>
+{
+    builtin_module(name, target).map(|(code, _)| code)
+}
+
+pub fn parse_builtin_module(name: &str, target: &Target) -> Option<CompileResult<Module>>
+{
+    builtin_module(name, target)
+        .map(|(code, dir)| parse_buffer(code, Path::new(&format!("{}/{}.mhr", dir, name)), name, target))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use ast::{IntSize, LogLevel};
+    use bytecode::{OptimizationLevel, compile_to_byte_code, optimize_module, DEFAULT_INLINE_THRESHOLD};
+    use llvmbackend::{llvm_init, llvm_code_generation, CodeGenOptions, JIT};
+    use parser::parse_str;
+
+    #[test]
+    fn test_net_module()
+    {
+        let target = Target::new(IntSize::I64, "");
+        let mut pkg = parse_str(r#"
+import net
+
+fn serve(l: Listener) -> int:
+    match l.accept():
+        Ok{s} =>
+            let zero = 0u as uint8
+            var buffer = [zero; 64]
+            match s.read(@slice(buffer as *uint8, buffer.len)):
+                Ok{n} => n as int
+                Error{code} => -code
+        Error{code} => -code
+
+fn main() -> int:
+    match tcp_connect("127.0.0.1", 8080u as uint16):
+        Ok{s} =>
+            s.write_all(b"ping")
+            s.close()
+        Error{code} => code
+"#, "test", &target).expect("Parsing failed");
+
+        assert!(pkg.type_check(&target).is_ok());
+        assert!(pkg.modules.contains_key("net"));
+        assert!(parse_builtin_module("net", &target).expect("net is missing").is_ok());
+        assert!(parse_builtin_module("test", &target).is_none());
+    }
+
+    #[test]
+    fn test_fs_and_path_modules()
+    {
+        let code = r#"
+import fs, path
+
+fn count_sources(dir: string) -> int:
+    match list_dir(dir, fn(name: string) -> extension(name) == "mhr"):
+        Ok{n} => n as int
+        Error{code} => -code
+
+fn prepare(dir: string) -> bool:
+    if exists(dir):
+        return true
+
+    match mkdir(dir):
+        Ok{created} => created == dir
+        Error{code} => code == 0
+
+fn main() -> int:
+    let dir = join("build", "tmp")
+    if !prepare(dir):
+        return -1
+    let n = count_sources(dir)
+    remove(dir)
+    n + (basename(dir).len as int)
+"#;
+
+        for triplet in &["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc"] {
+            let target = Target::from_triplet(triplet);
+            let mut pkg = parse_str(code, "test", &target).expect("Parsing failed");
+            assert!(pkg.type_check(&target).is_ok());
+            assert!(pkg.modules.contains_key("sys"));
+        }
+    }
+
+    #[test]
+    fn test_string_module()
+    {
+        let target = Target::new(IntSize::I64, "");
+        let mut pkg = parse_str(r#"
+import string
+
+# Counts the parts of a comma separated list, up to and including the first one which is not a number
+fn numbers(csv: string) -> int:
+    split(csv, ",", fn(part: string) -> to_int(part) != nil) as int
+
+fn main() -> int:
+    let s = concat("key=", "value")
+    match find(s, "="):
+        ?i => numbers(substr(s, i + 1u, len(s))) + (i as int)
+        nil => -1
+"#, "test", &target).expect("Parsing failed");
+
+        assert!(pkg.type_check(&target).is_ok());
+        assert!(pkg.modules.contains_key("string"));
+    }
+
+    #[test]
+    fn test_vector_module()
+    {
+        // The vector gets its memory from the C library, which only the JIT can call
+        let target_machine = llvm_init(None).expect("Cannot create llvm target machine");
+        let target = &target_machine.target;
+        let mut pkg = parse_str(r#"
+import vector
+
+fn main() -> int:
+    var v = vector_of([1, 2, 3])
+    var i = 0
+    while i < 10:
+        v.push(i)
+        i += 1
+    v.set(0u, v.get(1u) * 10)
+    var total = 0
+    for x in v.items():
+        total += x
+    let last = match v.pop():
+        ?x => x
+        nil => -100
+    let result = total + last + (v.len() as int)
+    v.free()
+    result + (v.len() as int)
+"#, "test", target).expect("Parsing failed");
+
+        assert!(pkg.type_check(target).is_ok());
+        assert!(pkg.modules.contains_key("vector"));
+
+        let mut bc_mod = compile_to_byte_code(&pkg, target, false, LogLevel::Debug).expect("Compile to bytecode failed");
+        optimize_module(&mut bc_mod, OptimizationLevel::Normal, DEFAULT_INLINE_THRESHOLD);
+        let mut ctx = llvm_code_generation(&bc_mod, &target_machine, &CodeGenOptions::default()).expect("Code generation failed");
+        let ret = unsafe {
+            let jit = JIT::new().expect("Cannot create JIT");
+            jit.run(&mut ctx)
+        };
+        // 20 + 2 + 3 + 0 + ... + 9, the popped 9 and the 12 elements left, none after the free
+        assert_eq!(ret, Ok(91));
+    }
+}
@@ -1,6 +1,6 @@
 use ast::{IntSize, Type};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Target
 {
     pub int_size: IntSize,
@@ -20,4 +20,57 @@ impl Target
             triplet: triplet.into(),
         }
     }
+    // The target of the machine running the compiler, for the interpreter
+    pub fn host() -> Target
+    {
+        let int_size = if cfg!(target_pointer_width = "32") {IntSize::I32} else {IntSize::I64};
+        Target::new(int_size, "")
+    }
+
+    // An empty triplet is the machine running the compiler
+    pub fn is_windows(&self) -> bool
+    {
+        if self.triplet.is_empty() {
+            cfg!(windows)
+        } else {
+            self.triplet.contains("windows")
+        }
+    }
+
+    // A target for type checking, without initializing LLVM, so the integer size is derived from the architecture
+    pub fn from_triplet(triplet: &str) -> Target
+    {
+        let arch = triplet.split('-').next().unwrap_or("");
+        let int_size = if arch == "avr" || arch == "msp430" {
+            IntSize::I16
+        } else if arch.ends_with("64") || arch == "sparcv9" || arch == "s390x" {
+            IntSize::I64
+        } else if (arch.starts_with('i') && arch.ends_with("86")) || arch.starts_with("arm") || arch.starts_with("thumb") ||
+            ["x86", "mips", "mipsel", "powerpc", "riscv32", "wasm32", "sparc", "hexagon"].contains(&arch) {
+            IntSize::I32
+        } else {
+            IntSize::I64
+        };
+        Target::new(int_size, triplet)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use ast::IntSize;
+    use super::Target;
+
+    #[test]
+    fn test_from_triplet()
+    {
+        assert_eq!(Target::from_triplet("x86_64-unknown-linux-gnu").int_size, IntSize::I64);
+        assert_eq!(Target::from_triplet("aarch64-linux-gnu").int_size, IntSize::I64);
+        assert_eq!(Target::from_triplet("i686-pc-linux-gnu").int_size, IntSize::I32);
+        assert_eq!(Target::from_triplet("armv7-unknown-linux-gnueabihf").int_size, IntSize::I32);
+        assert_eq!(Target::from_triplet("wasm32-unknown-unknown").int_size, IntSize::I32);
+        assert_eq!(Target::from_triplet("avr-unknown-unknown").int_size, IntSize::I16);
+        assert!(Target::from_triplet("x86_64-pc-windows-msvc").is_windows());
+        assert!(!Target::from_triplet("x86_64-unknown-linux-gnu").is_windows());
+    }
 }
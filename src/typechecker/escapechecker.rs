@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use ast::*;
+use compileerror::{CompileResult, CompileError, type_error_result};
+use span::Span;
+use super::typecheckercontext::TypeCheckerContext;
+
+/*
+The environment of a closure lives in the stack frame of the function creating it, so a lambda which
+captures variables cannot be used after that function returns. Returning it, or storing it anywhere
+it outlives the function (a struct, an array, the heap, a global or through a pointer), is an error.
+Passing it to a call is fine, the call is finished before the function creating it returns.
+*/
+struct EscapeChecker<'a, 'b: 'a>
+{
+    ctx: &'a TypeCheckerContext<'b>,
+    locals: HashSet<String>,
+    // Local variables holding a closure
+    closures: HashSet<String>,
+}
+
+impl<'a, 'b> EscapeChecker<'a, 'b>
+{
+    // The closure an expression evaluates to, if it evaluates to one
+    fn closure_span(&self, e: &Expression) -> Option<Span>
+    {
+        match *e
+        {
+            Expression::Lambda(ref l) if !l.captures.is_empty() => Some(l.span.clone()),
+            Expression::NameRef(ref nr) if self.closures.contains(&nr.name) => Some(nr.span.clone()),
+            Expression::Block(ref b) => b.expressions.last().and_then(|e| self.closure_span(e)),
+            Expression::If(ref i) => {
+                self.closure_span(&i.on_true).or_else(|| i.on_false.as_ref().and_then(|e| self.closure_span(e)))
+            },
+            Expression::Match(ref m) => m.cases.iter().filter_map(|c| self.closure_span(&c.to_execute)).next(),
+            Expression::ToOptional(ref t) => self.closure_span(&t.inner),
+            Expression::Cast(ref c) => self.closure_span(&c.inner),
+            _ => None,
+        }
+    }
+
+    fn check_does_not_escape(&self, e: &Expression) -> CompileResult<()>
+    {
+        match self.closure_span(e) {
+            Some(span) => type_error_result(&span, "A lambda capturing local variables cannot be used after the function creating it returns"),
+            None => Ok(()),
+        }
+    }
+
+    fn is_global(&self, name: &str) -> bool
+    {
+        if self.locals.contains(name) {
+            return false;
+        }
+
+        matches!(self.ctx.resolve(name), Some(Symbol{symbol_type: SymbolType::Global, ..}))
+    }
+}
+
+impl<'a, 'b> Visitor for EscapeChecker<'a, 'b>
+{
+    type Error = CompileError;
+
+    fn visit_expression(&mut self, e: &Expression) -> CompileResult<()>
+    {
+        match *e
+        {
+            Expression::Return(ref r) => self.check_does_not_escape(&r.expression)?,
+            Expression::New(ref n) => self.check_does_not_escape(&n.inner)?,
+            Expression::StructInitializer(ref si) => {
+                for m in &si.member_initializers {
+                    self.check_does_not_escape(m)?;
+                }
+            },
+            Expression::Literal(Literal::Array(ref a)) => {
+                for element in &a.elements {
+                    self.check_does_not_escape(element)?;
+                }
+            },
+            Expression::Bindings(ref bl) => {
+                for b in &bl.bindings {
+                    if let BindingType::Name(ref name) = b.binding_type {
+                        self.locals.insert(name.clone());
+                        if self.closure_span(&b.init).is_some() {
+                            self.closures.insert(name.clone());
+                        } else {
+                            self.closures.remove(name);
+                        }
+                    }
+                }
+            },
+            Expression::Assign(ref a) => {
+                match a.left {
+                    AssignTarget::Var(ref nr) if !self.is_global(&nr.name) => {
+                        if self.closure_span(&a.right).is_some() {
+                            self.closures.insert(nr.name.clone());
+                        }
+                    },
+                    _ => self.check_does_not_escape(&a.right)?,
+                }
+            },
+            Expression::Lambda(ref l) => {
+                // The body of a lambda is a function of its own, which cannot return a closure either
+                for arg in &l.sig.args {
+                    self.locals.insert(arg.name.clone());
+                }
+                self.check_does_not_escape(&l.expr)?;
+            },
+            _ => (),
+        }
+
+        walk_expression(self, e)
+    }
+}
+
+pub fn check_escaping_closures(ctx: &TypeCheckerContext, fun: &Function) -> CompileResult<()>
+{
+    let mut checker = EscapeChecker{
+        ctx,
+        locals: fun.sig.args.iter().map(|arg| arg.name.clone()).collect(),
+        closures: HashSet::new(),
+    };
+    checker.visit_expression(&fun.expression)?;
+    checker.check_does_not_escape(&fun.expression)
+}
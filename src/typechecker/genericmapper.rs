@@ -48,6 +48,11 @@ pub fn fill_in_generics(ctx: &TypeCheckerContext, actual: &Type, generic: &Type,
         },
 
         (&Type::Array(ref generic_at), &Type::Array(ref actual_at)) => {
+            match generic_at.generic_len {
+                Some(ref name) => add(known_types, &generic_length(name), &array_length(actual_at.len), span)?,
+                None if generic_at.len != actual_at.len => return map_err(),
+                None => (),
+            }
             add(known_types, &generic_at.element_type, &actual_at.element_type, span)?;
             let new_el_type = fill_in_generics(ctx, &actual_at.element_type, &generic_at.element_type, known_types, span)?;
             Ok(array_type(new_el_type, actual_at.len))
@@ -90,7 +95,7 @@ pub fn fill_in_generics(ctx: &TypeCheckerContext, actual: &Type, generic: &Type,
                 new_members.push(struct_member(&aa.name, nt));
             }
 
-            Ok(struct_type(&actual_st.name, new_members))
+            Ok(struct_type_with_layout(&actual_st.name, new_members, actual_st.repr_c))
         },
 
         (&Type::Sum(ref generic_st), &Type::Sum(ref actual_st)) => {
@@ -129,7 +134,7 @@ pub fn fill_in_generics(ctx: &TypeCheckerContext, actual: &Type, generic: &Type,
 mod tests
 {
     use super::*;
-    use ast::{Type, GenericMapping, array_type, slice_type, func_type, string_type, ptr_type, generic_type, IntSize};
+    use ast::{Type, GenericMapping, array_type, generic_array_type, slice_type, func_type, string_type, ptr_type, generic_type, IntSize};
     use typechecker::instantiate::make_concrete;
     use typechecker::typecheckercontext::ImportSymbolResolver;
     use span::Span;
@@ -173,6 +178,22 @@ mod tests
         assert!(make_concrete(&ctx, &tm, &generic_type("a"), &Span::default()).unwrap() == Type::Int(IntSize::I32));
     }
 
+    #[test]
+    fn test_array_length()
+    {
+        let imports = ImportMap::new();
+        let ctx = TypeCheckerContext::new(ImportSymbolResolver::ImportMap(&imports));
+        let mut tm = GenericMapping::new();
+        let ga = generic_array_type(generic_type("a"), "n");
+        let r = fill_in_generics(&ctx, &array_type(Type::Int(IntSize::I32), 5), &ga, &mut tm, &Span::default());
+        assert!(r == Ok(array_type(Type::Int(IntSize::I32), 5)));
+        assert!(make_concrete(&ctx, &tm, &ga, &Span::default()).unwrap() == array_type(Type::Int(IntSize::I32), 5));
+
+        // Only an array of the same length fits an array with a fixed length
+        let r = fill_in_generics(&ctx, &array_type(Type::Int(IntSize::I32), 6), &array_type(generic_type("a"), 5), &mut GenericMapping::new(), &Span::default());
+        assert!(r.is_err());
+    }
+
     #[test]
     fn test_pointer()
     {
@@ -4,7 +4,7 @@ use span::Span;
 use compileerror::{CompileResult, type_error};
 use super::typecheckercontext::TypeCheckerContext;
 
-fn matches_function_signature(expected: &Type, actual: &Type, concrete_type: &Type, interface: &Type, method_name: &str) -> Result<(), String>
+pub fn matches_function_signature(expected: &Type, actual: &Type, concrete_type: &Type, interface: &Type, method_name: &str) -> Result<(), String>
 {
     fn type_matches(expected: &Type, actual: &Type, concrete_type: &Type, interface: &Type) -> bool {
         match (expected, actual)
@@ -41,7 +41,7 @@ fn matches_function_signature(expected: &Type, actual: &Type, concrete_type: &Ty
     }
 }
 
-fn satisfies_interface(ctx: &TypeCheckerContext, concrete_type: &Type, interface: &Type) -> Result<(), String>
+pub fn satisfies_interface(ctx: &TypeCheckerContext, concrete_type: &Type, interface: &Type) -> Result<(), String>
 {
     let it = if let Type::Interface(ref it) = *interface {
         it
@@ -67,13 +67,14 @@ fn check_interface_constraints(ctx: &TypeCheckerContext, generic: &Type, concret
         Type::Generic(ref gt) => {
             match *gt.deref()
             {
-                GenericType::Any(_) => Ok(concrete.clone()),
+                GenericType::Any(_) | GenericType::Length(_) => Ok(concrete.clone()),
 
-                GenericType::Restricted(ref interfaces) => {
+                GenericType::Restricted(ref name, ref interfaces) => {
+                    let required_by = if name.is_empty() {String::new()} else {format!(", which ${} requires", name)};
                     for interface in interfaces {
                         satisfies_interface(ctx, concrete, interface)
                             .map_err(|msg|
-                                format!("Type {} does not implement the interface {}: {}", concrete.name(), interface.name(), msg)
+                                format!("Type {} does not implement the interface {}{}: {}", concrete.name(), interface.name(), required_by, msg)
                             )?;
                     }
 
@@ -87,7 +88,7 @@ fn check_interface_constraints(ctx: &TypeCheckerContext, generic: &Type, concret
 }
 
 
-fn make_concrete_type(ctx: &TypeCheckerContext, mapping: &GenericMapping, generic: &Type) -> Result<Type, String>
+pub fn make_concrete_type(ctx: &TypeCheckerContext, mapping: &GenericMapping, generic: &Type) -> Result<Type, String>
 {
     if !generic.is_generic() {
         return Ok(generic.clone());
@@ -100,7 +101,14 @@ fn make_concrete_type(ctx: &TypeCheckerContext, mapping: &GenericMapping, generi
     let typ = match *generic
     {
         Type::Array(ref at) => {
-            array_type(make_concrete_type(ctx, mapping, &at.element_type)?, at.len)
+            let element_type = make_concrete_type(ctx, mapping, &at.element_type)?;
+            match at.generic_len {
+                Some(ref name) => match mapping.get(&generic_length(name)) {
+                    Some(&Type::Array(ref len)) => array_type(element_type, len.len),
+                    _ => generic_array_type(element_type, name),
+                },
+                None => array_type(element_type, at.len),
+            }
         },
 
         Type::Slice(ref st) => {
@@ -122,7 +130,7 @@ fn make_concrete_type(ctx: &TypeCheckerContext, mapping: &GenericMapping, generi
                 members.push(struct_member(&m.name, make_concrete_type(ctx, mapping, &m.typ)?));
             }
 
-            struct_type(&st.name, members)
+            struct_type_with_layout(&st.name, members, st.repr_c)
         },
 
         Type::Sum(ref st) => {
@@ -173,12 +181,37 @@ fn substitute_bindings(ctx: &TypeCheckerContext, generic_args: &GenericMapping,
                     b.span.clone()
                 )
             },
+
+            BindingType::Tuple(ref t) => {
+                binding(
+                    BindingType::Tuple(substitute_tuple_pattern(ctx, generic_args, t)?),
+                    binding_expr,
+                    b.mutable,
+                    b.span.clone()
+                )
+            },
         };
         bindings.push(new_binding);
     }
     Ok(bindings)
 }
 
+fn substitute_tuple_pattern(ctx: &TypeCheckerContext, generic_args: &GenericMapping, p: &TuplePattern) -> CompileResult<TuplePattern>
+{
+    let mut elements = Vec::with_capacity(p.elements.len());
+    for e in &p.elements {
+        elements.push(match *e {
+            BindingType::Name(ref name) => BindingType::Name(name.clone()),
+            BindingType::Struct(ref s) => BindingType::Struct(substitute_struct_pattern(ctx, generic_args, s)?),
+            BindingType::Tuple(ref t) => BindingType::Tuple(substitute_tuple_pattern(ctx, generic_args, t)?),
+        });
+    }
+
+    let mut new_pattern = tuple_pattern(elements, p.span.clone());
+    new_pattern.typ = make_concrete(ctx, generic_args, &p.typ, &p.span)?;
+    Ok(new_pattern)
+}
+
 
 
 fn substitute_struct_pattern(ctx: &TypeCheckerContext, generic_args: &GenericMapping, p: &StructPattern) -> CompileResult<StructPattern>
@@ -273,6 +306,7 @@ fn substitute_member_access(ctx: &TypeCheckerContext, generic_args: &GenericMapp
         right,
         span: sma.span.clone(),
         typ: sma.typ.clone(),
+        optional: sma.optional,
     })
 }
 
@@ -334,12 +368,15 @@ fn substitute_expr(ctx: &TypeCheckerContext, generic_args: &GenericMapping, e: &
         },
 
         Expression::If(ref i) => {
-            Ok(if_expression(
-                substitute_expr(ctx, generic_args, &i.condition)?,
-                substitute_expr(ctx, generic_args, &i.on_true)?,
-                substitute_expr(ctx, generic_args, &i.on_true)?,
-                i.span.clone(),
-            ))
+            let condition = substitute_expr(ctx, generic_args, &i.condition)?;
+            let on_true = substitute_expr(ctx, generic_args, &i.on_true)?;
+            match i.on_false {
+                Some(ref on_false) => {
+                    let on_false = substitute_expr(ctx, generic_args, on_false)?;
+                    Ok(if_expression(condition, on_true, on_false, i.span.clone()))
+                },
+                None => Ok(single_if_expression(condition, on_true, i.span.clone())),
+            }
         },
 
         Expression::Block(ref b) => {
@@ -431,13 +468,13 @@ fn substitute_expr(ctx: &TypeCheckerContext, generic_args: &GenericMapping, e: &
         Expression::While(ref w) => {
             let c = substitute_expr(ctx, generic_args, &w.cond)?;
             let b = substitute_expr(ctx, generic_args, &w.body)?;
-            Ok(while_loop(c, b, w.span.clone()))
+            Ok(while_loop(c, b, w.hints.clone(), w.span.clone()))
         },
 
         Expression::For(ref f) => {
             let i = substitute_expr(ctx, generic_args, &f.iterable)?;
             let b = substitute_expr(ctx, generic_args, &f.body)?;
-            Ok(for_loop(&f.loop_variable, i, b, f.span.clone()))
+            Ok(for_loop(&f.loop_variable, i, b, f.hints.clone(), f.span.clone()))
         },
 
         Expression::Nil(ref span) => {
@@ -450,7 +487,8 @@ fn substitute_expr(ctx: &TypeCheckerContext, generic_args: &GenericMapping, e: &
 
         Expression::ToOptional(ref t) => {
             let inner = substitute_expr(ctx, generic_args, &t.inner)?;
-            Ok(to_optional(inner, t.optional_type.clone()))
+            let optional_type = make_concrete(ctx, generic_args, &t.optional_type, &t.inner.span())?;
+            Ok(to_optional(inner, optional_type))
         },
 
         Expression::Cast(ref t) => {
@@ -465,6 +503,25 @@ fn substitute_expr(ctx: &TypeCheckerContext, generic_args: &GenericMapping, e: &
             Ok(Expression::CompilerCall(CompilerCall::SizeOf(new_t, span.clone())))
         }
 
+        Expression::CompilerCall(CompilerCall::Fields(query, ref t, ref span)) => {
+            let new_t = make_concrete(ctx, generic_args, t, span)?;
+            Ok(Expression::CompilerCall(CompilerCall::Fields(query, new_t, span.clone())))
+        }
+
+        Expression::CompilerCall(CompilerCall::FieldValues(ref value, ref span)) => {
+            let new_value = substitute_expr(ctx, generic_args, value)?;
+            Ok(Expression::CompilerCall(CompilerCall::FieldValues(Box::new(new_value), span.clone())))
+        }
+
+        Expression::CompilerCall(CompilerCall::OffsetOf{ref typ, ref field, index, ref span}) => {
+            Ok(Expression::CompilerCall(CompilerCall::OffsetOf{
+                typ: make_concrete(ctx, generic_args, typ, span)?,
+                field: field.clone(),
+                index,
+                span: span.clone(),
+            }))
+        }
+
         Expression::CompilerCall(CompilerCall::Slice{ref data, ref len, ref typ, ref span}) => {
             let new_data = substitute_expr(ctx, generic_args, data)?;
             let new_len = substitute_expr(ctx, generic_args, len)?;
@@ -477,12 +534,86 @@ fn substitute_expr(ctx: &TypeCheckerContext, generic_args: &GenericMapping, e: &
             }))
         },
 
+        Expression::CompilerCall(CompilerCall::Intrinsic{intrinsic, ref args, ref typ, ref span}) => {
+            let mut new_args = Vec::with_capacity(args.len());
+            for a in args {
+                new_args.push(substitute_expr(ctx, generic_args, a)?);
+            }
+            Ok(Expression::CompilerCall(CompilerCall::Intrinsic{
+                intrinsic,
+                args: new_args,
+                typ: make_concrete(ctx, generic_args, typ, span)?,
+                span: span.clone(),
+            }))
+        },
+
+        Expression::CompilerCall(CompilerCall::Format{ref parts, ref span}) => {
+            let mut new_parts = Vec::with_capacity(parts.len());
+            for p in parts {
+                new_parts.push(substitute_expr(ctx, generic_args, p)?);
+            }
+            Ok(Expression::CompilerCall(CompilerCall::Format{
+                parts: new_parts,
+                span: span.clone(),
+            }))
+        },
+
+        Expression::CompilerCall(CompilerCall::Log{level, ref parts, ref span}) => {
+            let mut new_parts = Vec::with_capacity(parts.len());
+            for p in parts {
+                new_parts.push(substitute_expr(ctx, generic_args, p)?);
+            }
+            Ok(Expression::CompilerCall(CompilerCall::Log{
+                level,
+                parts: new_parts,
+                span: span.clone(),
+            }))
+        },
+
+        Expression::CompilerCall(CompilerCall::AssertEq{ref left, ref right, ref span}) => {
+            Ok(Expression::CompilerCall(CompilerCall::AssertEq{
+                left: Box::new(substitute_expr(ctx, generic_args, left)?),
+                right: Box::new(substitute_expr(ctx, generic_args, right)?),
+                span: span.clone(),
+            }))
+        },
+
+        Expression::CompilerCall(CompilerCall::Assert{ref cond, ref parts, ref span}) => {
+            let mut new_parts = Vec::with_capacity(parts.len());
+            for p in parts {
+                new_parts.push(substitute_expr(ctx, generic_args, p)?);
+            }
+            Ok(Expression::CompilerCall(CompilerCall::Assert{
+                cond: Box::new(substitute_expr(ctx, generic_args, cond)?),
+                parts: new_parts,
+                span: span.clone(),
+            }))
+        },
+
+        Expression::CompilerCall(CompilerCall::Panic{ref parts, ref span}) => {
+            let mut new_parts = Vec::with_capacity(parts.len());
+            for p in parts {
+                new_parts.push(substitute_expr(ctx, generic_args, p)?);
+            }
+            Ok(Expression::CompilerCall(CompilerCall::Panic{
+                parts: new_parts,
+                span: span.clone(),
+            }))
+        },
+
         Expression::IndexOperation(ref iop) => {
             let target = substitute_expr(ctx, generic_args, &iop.target)?;
             let index_expr = substitute_expr(ctx, generic_args, &iop.index_expr)?;
             Ok(index_op(target, index_expr, iop.span.clone()))
         }
 
+        Expression::SliceOperation(ref sop) => {
+            let target = substitute_expr(ctx, generic_args, &sop.target)?;
+            let start = substitute_expr(ctx, generic_args, &sop.start)?;
+            let end = substitute_expr(ctx, generic_args, &sop.end)?;
+            Ok(slice_op(target, start, end, sop.span.clone()))
+        }
+
         Expression::Return(ref r) => {
             let e = substitute_expr(ctx, generic_args, &r.expression)?;
             Ok(return_expr(e, r.span.clone()))
@@ -496,7 +627,9 @@ pub fn instantiate(ctx: &TypeCheckerContext, func: &Function, generic_args: &Gen
     let mut args = Vec::with_capacity(func.sig.args.len());
     for arg in &func.sig.args {
         let arg_typ = make_concrete(ctx, generic_args, &arg.typ, &arg.span)?;
-        args.push(Argument::new(arg.name.clone(), arg_typ.clone(), arg.mutable, arg.span.clone()));
+        let mut new_arg = Argument::new(arg.name.clone(), arg_typ.clone(), arg.mutable, arg.span.clone());
+        new_arg.by_ref = arg.by_ref;
+        args.push(new_arg);
         arg_types.push(arg_typ);
     }
 
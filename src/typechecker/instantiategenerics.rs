@@ -4,7 +4,7 @@ use target::Target;
 use super::instantiate::instantiate;
 use super::typecheck::type_check_function;
 use super::typecheckercontext::{TypeCheckerContext, ImportSymbolResolver};
-use compileerror::{CompileResult, unknown_name_result};
+use compileerror::{CompileResult, CompileError, unknown_name_result};
 
 
 type FunctionMap = HashMap<String, Function>;
@@ -40,34 +40,49 @@ fn resolve_generic_call(ctx: &mut TypeCheckerContext, new_functions: &mut Functi
         }
     }
 
-    unknown_name_result(&call.span, format!("Unknown function {}", call.callee.name))
+    unknown_name_result(&call.span, &call.callee.name, format!("Unknown function {}", call.callee.name))
 }
 
-fn resolve_generics(ctx: &mut TypeCheckerContext, new_functions: &mut FunctionMap, imports: &ImportMap, module: &Module, e: &Expression, target: &Target) -> CompileResult<()>
+struct GenericResolver<'a, 'b: 'a>
 {
-    let mut rg = |e: &Expression| {
+    ctx: &'a mut TypeCheckerContext<'b>,
+    new_functions: &'a mut FunctionMap,
+    imports: &'a ImportMap,
+    module: &'a Module,
+    target: &'a Target,
+}
+
+impl<'a, 'b> Visitor for GenericResolver<'a, 'b>
+{
+    type Error = CompileError;
+
+    fn visit_expression(&mut self, e: &Expression) -> CompileResult<()>
+    {
         if let Expression::Call(ref c) = *e {
             if !c.generic_args.is_empty() {
-                resolve_generic_call(ctx, new_functions, imports, module, c, target)?;
+                resolve_generic_call(self.ctx, self.new_functions, self.imports, self.module, c, self.target)?;
             }
         }
-        Ok(())
-    };
-    e.visit(&mut rg)
+        walk_expression(self, e)
+    }
 }
 
-fn replace_generic_calls(e: &mut Expression) -> CompileResult<()>
+// Renames calls to generic functions, to the name of their instantiation
+struct GenericCallRenamer;
+
+impl MutVisitor for GenericCallRenamer
 {
-    let mut replace_calls = |e: &mut Expression| {
-        if let Expression::Call(ref mut call) =  *e {
+    type Error = CompileError;
+
+    fn visit_expression(&mut self, e: &mut Expression) -> CompileResult<()>
+    {
+        if let Expression::Call(ref mut call) = *e {
             if !call.generic_args.is_empty() {
                 call.callee.name = new_func_name(&call.callee.name, &call.generic_args);
             }
         }
-        Ok(())
-    };
-
-    e.visit_mut(&mut replace_calls)
+        walk_expression_mut(self, e)
+    }
 }
 
 /*
@@ -76,17 +91,27 @@ fn replace_generic_calls(e: &mut Expression) -> CompileResult<()>
 pub fn instantiate_generics(module: &mut Module, ctx: &mut TypeCheckerContext, imports: &ImportMap, target: &Target) -> CompileResult<()>
 {
     let mut new_functions = FunctionMap::new();
-    for f in module.functions.values()
     {
-        if !f.generics_resolved && !f.is_generic() {
-            resolve_generics(ctx, &mut new_functions, imports, module, &f.expression, target)?;
+        let mut resolver = GenericResolver{
+            ctx: ctx,
+            new_functions: &mut new_functions,
+            imports: imports,
+            module: module,
+            target: target,
+        };
+
+        for f in module.functions.values()
+        {
+            if !f.generics_resolved && !f.is_generic() {
+                resolver.visit_function(f)?;
+            }
         }
     }
 
     for f in module.functions.values_mut()
     {
         if !f.generics_resolved && !f.is_generic() {
-            replace_generic_calls(&mut f.expression)?;
+            GenericCallRenamer.visit_function(f)?;
             f.generics_resolved = true;
         }
     }
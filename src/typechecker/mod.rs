@@ -5,6 +5,8 @@ mod instantiategenerics;
 mod genericmapper;
 mod matchchecker;
 mod typeresolver;
+mod unusedchecker;
+mod escapechecker;
 #[cfg(test)]
 mod tests;
 
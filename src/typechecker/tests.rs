@@ -1,7 +1,8 @@
-use parser::{th_expr, th_mod};
+use std::rc::Rc;
+use parser::{parse_str, th_expr, th_mod};
 use super::typecheck::{type_check_expression, type_check_module};
 use super::typecheckercontext::{TypeCheckerContext, ImportSymbolResolver};
-use ast::{IntSize, Type, ImportMap};
+use ast::{IntSize, FloatSize, Type, ImportMap, optional_type, array_type, slice_type};
 use compileerror::{CompileResult};
 use target::Target;
 
@@ -157,3 +158,1216 @@ fn test_mutability()
         "#).is_ok()
 	);
 }
+
+#[test]
+fn test_field_reflection()
+{
+	assert!(type_check_mod(r#"
+struct Foo:
+    a: int
+    b: double
+
+fn main() -> int:
+    let n = @field_names(Foo)
+    @field_count(Foo) as int
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn main() -> int:
+    @field_count(int) as int
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+struct Foo:
+    a: int
+    b: string
+
+fn main() -> int:
+    let values = @field_values(Foo{1, "b"})
+    values[0].len as int
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn main() -> int:
+    @field_values(3).len as int
+        "#).is_err()
+	);
+
+	// Members must be something @format can convert
+	assert!(type_check_mod(r#"
+struct Inner:
+    a: int
+
+struct Outer:
+    inner: Inner
+
+fn main() -> int:
+    @field_values(Outer{Inner{1}}).len as int
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_source_location()
+{
+	assert!(type_check("__line__").is_ok());
+	assert!(type_check("__file__").is_ok());
+	assert!(type_check_mod(r#"
+fn foo() -> string:
+    __function__
+        "#).is_ok()
+	);
+}
+
+#[test]
+fn test_hint_intrinsics()
+{
+	assert!(type_check("if @likely(4 > 3): 1 else 0").is_ok());
+	assert!(type_check("if @unlikely(true): 1 else 0").is_ok());
+	assert!(type_check("@likely(5)").is_err());
+	assert!(type_check("@prefetch(5)").is_err());
+	assert!(type_check_mod(r#"
+fn foo(p: *int) -> int:
+    @prefetch(p)
+    *p
+        "#).is_ok()
+	);
+}
+
+#[test]
+fn test_select()
+{
+	assert!(type_check("@select(4 > 3, 1, 2)").is_ok());
+	assert!(type_check("@select(1, 1, 2)").is_err());
+	assert!(type_check("@select(true, 1, \"a\")").is_err());
+}
+
+#[test]
+fn test_constant_time_intrinsics()
+{
+	assert!(type_check("@ct_select(true, 4, 5)").is_ok());
+	assert!(type_check("@ct_select(true, 4.0, 5.0)").is_err());
+	assert!(type_check_mod(r#"
+fn foo(a: int[], b: int[]) -> bool:
+    @ct_eq(a, b)
+        "#).is_ok()
+	);
+	assert!(type_check_mod(r#"
+fn foo(a: double[], b: double[]) -> bool:
+    @ct_eq(a, b)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_byte_order_intrinsics()
+{
+	assert!(type_check("@to_le(5)").is_ok());
+	assert!(type_check("@from_be(5u)").is_ok());
+	assert!(type_check("@to_be(5.0)").is_err());
+	assert!(type_check_mod(r#"
+fn foo(data: uint8[]) -> uint32:
+    @store_le(data, 4u, 7u as uint16)
+    @load_be(uint32, data, 0u)
+        "#).is_ok()
+	);
+	assert!(type_check_mod(r#"
+fn foo(data: uint8[]) -> double:
+    @load_be(double, data, 0u)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_integer_casts()
+{
+	assert_eq!(type_check("7u as uint16").unwrap(), Type::UInt(IntSize::I16));
+	assert_eq!(type_check("7 as int64").unwrap(), Type::Int(IntSize::I64));
+	assert!(type_check("true as uint8").is_err());
+}
+
+#[test]
+fn test_bit_intrinsics()
+{
+	assert!(type_check("@count_ones(5)").is_ok());
+	assert!(type_check("@rotate_left(5u, 3)").is_ok());
+	assert!(type_check("@leading_zeros(true)").is_err());
+	assert!(type_check_mod(r#"
+fn foo(a: uint32) -> uint:
+    a.trailing_zeros() + a.rotate_right(4).count_ones()
+        "#).is_ok()
+	);
+	assert!(type_check_mod(r#"
+fn foo(a: uint32) -> uint:
+    a.bogus()
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_number_intrinsics()
+{
+	assert_eq!(type_check("@parse_int(\"42\")").unwrap(), optional_type(Type::Int(IntSize::I32)));
+	assert_eq!(type_check("@parse_float(\"4.2\")").unwrap(), optional_type(Type::Float(FloatSize::F64)));
+	assert_eq!(type_check("@int_to_string(255, 16)").unwrap(), Type::String);
+	assert_eq!(type_check("@float_to_string(2.5, 3)").unwrap(), Type::String);
+	assert!(type_check("@parse_int(42)").is_err());
+	assert!(type_check("@int_to_string(255, 37)").is_err());
+	assert!(type_check("@float_to_string(2, 3)").is_err());
+	assert!(type_check_mod(r#"
+fn foo(a: int, radix: uint) -> string:
+    @int_to_string(a, radix)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_binary_data()
+{
+	assert_eq!(type_check("b\"ab\"").unwrap(), array_type(Type::UInt(IntSize::I8), 2));
+	assert_eq!(type_check("b\"ab\" == b\"ac\"").unwrap(), Type::Bool);
+	assert_eq!(type_check("\"ab\" != \"ac\"").unwrap(), Type::Bool);
+	assert_eq!(type_check("@hex_encode(b\"ab\")").unwrap(), Type::String);
+	assert_eq!(type_check("@hex_decode(\"6162\")").unwrap(), optional_type(slice_type(Type::UInt(IntSize::I8))));
+	assert!(type_check("[1.5, 2.5] == [1.5, 2.5]").is_err());
+	assert!(type_check("@hex_encode(\"ab\")").is_err());
+	assert_eq!(type_check("@from_utf8(b\"ab\")").unwrap(), optional_type(Type::String));
+	assert!(type_check("@from_utf8(\"ab\")").is_err());
+}
+
+#[test]
+fn test_offsetof()
+{
+	assert!(type_check_mod(r#"
+@repr(C)
+struct Foo:
+    a: int
+    b: double
+
+fn main() -> uint:
+    @offsetof(Foo, b)
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+struct Foo:
+    a: int
+    b: double
+
+fn main() -> uint:
+    @offsetof(Foo, b)
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+@repr(C)
+struct Foo:
+    a: int
+
+fn main() -> uint:
+    @offsetof(Foo, c)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_unions()
+{
+	assert!(type_check_mod(r#"
+union Value:
+    i: int64
+    d: double
+
+@unsafe
+fn main() -> int64:
+    var v = Value{7}
+    v.d = 1.5
+    v.i
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+union Value:
+    i: int64
+    d: double
+
+fn main() -> int64:
+    let v = Value{7}
+    v.i
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+union Value:
+    i: int64
+    d: double
+
+fn main() -> int:
+    let v = Value{7, 1.5}
+    0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_opaque_types()
+{
+	assert!(type_check_mod(r#"
+extern type Window
+extern fn open_window() -> *Window
+extern fn close_window(w: *Window)
+
+fn Window.close(self):
+    close_window(self)
+
+fn main() -> int:
+    let w = open_window()
+    w.close()
+    0
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+extern type Window
+extern fn open_window() -> Window
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+extern type Window
+
+struct Wrapper:
+    w: Window
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+extern type Window
+extern fn open_window() -> *Window
+
+fn main() -> int:
+    let w = *open_window()
+    0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_init_functions()
+{
+	assert!(type_check_mod(r#"
+var counter = 0
+
+@init
+fn setup():
+    counter = 42
+
+fn main() -> int:
+    counter
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+@init
+fn setup(x: int):
+    x
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+@fini
+fn teardown() -> int:
+    0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_tuple_bindings()
+{
+	assert!(type_check_mod(r#"
+fn divmod(a: int, b: int) -> {int, {int, bool}}:
+    {a / b, {a % b, false}}
+
+fn main() -> int:
+    let (quot, (rem, _)) = divmod(7, 2)
+    quot + rem
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn divmod(a: int, b: int) -> {int, int}:
+    {a / b, a % b}
+
+fn main() -> int:
+    let (quot, rem, extra) = divmod(7, 2)
+    quot
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn main() -> int:
+    let (a, b) = 7
+    a
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_test_functions()
+{
+	assert!(type_check_mod(r#"
+@test
+fn addition():
+    @assert_eq(1 + 1, 2)
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+@test
+fn addition() -> bool:
+    1 + 1 == 2
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+@test
+@init
+fn setup():
+    @assert_eq(1 + 1, 2)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_exported_functions()
+{
+	assert!(type_check_mod(r#"
+@repr(C)
+struct Point:
+    x: int32
+    y: int32
+
+@export
+fn sum(p: Point) -> int32: p.x + p.y
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+struct Point:
+    x: int32
+    y: int32
+
+@export
+fn sum(p: Point) -> int32: p.x + p.y
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+@repr(C)
+struct Point:
+    x: int32
+    y: int32
+
+@export
+fn origin() -> Point: Point{0, 0}
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+@export
+fn first(s: int[]) -> int: s[0]
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_thread_local_globals()
+{
+	assert!(type_check_mod(r#"
+@thread_local
+var counter = 0
+
+fn main() -> int:
+    counter += 1
+    counter
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+@thread_local
+var counter = 0
+
+fn main() -> int:
+    let p = &counter
+    *p
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+@thread_local
+var counter = 0
+
+@unsafe
+fn main() -> int:
+    let p = &counter
+    *p
+        "#).is_ok()
+	);
+}
+
+#[test]
+fn test_unused_values_are_not_errors()
+{
+	assert!(type_check_mod(r#"
+fn main() -> int:
+    let x = 5
+    let y = 6
+    x + 1
+    x
+        "#).is_ok()
+	);
+}
+
+#[test]
+fn test_inline_functions()
+{
+	let target = Target::new(IntSize::I32, "");
+	let mut md = th_mod(r#"
+var total = 0
+
+fn max(a: int, b: int) -> int: if a > b: a else b
+
+fn add_to_total(a: int) -> int:
+    total += a
+    total
+
+fn main() -> int: max(3, 4)
+        "#, &target);
+	type_check_module(&mut md, &target, &ImportMap::new()).expect("Type check failed");
+
+	let import = md.get_exported_symbols(&target);
+	assert!(import.inline_functions.contains_key("test::max"));
+	assert!(!import.inline_functions.contains_key("test::add_to_total"));
+	assert!(!import.inline_functions.contains_key("test::main"));
+}
+
+#[test]
+fn test_multiple_type_errors()
+{
+	let target = Target::new(IntSize::I32, "");
+	let mut md = th_mod(r#"
+fn foo() -> int: x
+
+fn bar() -> int: true
+
+fn main() -> int: 5
+        "#, &target);
+	let errors = type_check_module(&mut md, &target, &ImportMap::new()).err().expect("Type check must fail").into_errors();
+	assert_eq!(errors.len(), 2);
+}
+
+#[test]
+fn test_string_interpolation()
+{
+	assert!(type_check_mod(r#"
+fn main() -> string:
+    let x = 5
+    "x = ${x}, ${x as float / 2.0} ${x > 4} ${"nested ${x}"}"
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+struct Foo:
+    a: int
+
+fn main() -> string:
+    let f = Foo{4}
+    "f = ${f}"
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_interface_impl()
+{
+	let interface = r#"
+interface Shape:
+    fn area(self) -> int
+    fn sides(self) -> int
+
+struct Square:
+    size: int
+"#;
+
+	assert!(type_check_mod(&format!(r#"{}
+impl Shape for Square:
+    fn area(self) -> int: self.size * self.size
+    fn sides(self) -> int: 4
+
+fn main() -> int:
+    let s = Square{{3}}
+    s.area()
+        "#, interface)).is_ok()
+	);
+
+	// Missing function
+	assert!(type_check_mod(&format!(r#"{}
+impl Shape for Square:
+    fn area(self) -> int: self.size * self.size
+
+fn main() -> int: 0
+        "#, interface)).is_err()
+	);
+
+	// Wrong signature
+	assert!(type_check_mod(&format!(r#"{}
+impl Shape for Square:
+    fn area(self) -> bool: true
+    fn sides(self) -> int: 4
+
+fn main() -> int: 0
+        "#, interface)).is_err()
+	);
+
+	// Not part of the interface
+	assert!(type_check_mod(&format!(r#"{}
+impl Shape for Square:
+    fn area(self) -> int: self.size * self.size
+    fn sides(self) -> int: 4
+    fn corners(self) -> int: 4
+
+fn main() -> int: 0
+        "#, interface)).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+struct Square:
+    size: int
+
+impl Square for Square:
+    fn area(self) -> int: self.size
+
+fn main() -> int: 0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_interface_objects()
+{
+	let shapes = r#"
+interface Shape:
+    fn area(self) -> int
+
+struct Square:
+    size: int
+
+fn Square.area(self) -> int: self.size * self.size
+
+fn total(a: Shape, b: Shape) -> int: a.area() + b.area()
+"#;
+
+	assert!(type_check_mod(&format!(r#"{}
+fn main() -> int:
+    let s = Square{{3}}
+    total(&s, &s)
+        "#, shapes)).is_ok()
+	);
+
+	// Type without the member functions of the interface
+	assert!(type_check_mod(&format!(r#"{}
+struct Circle:
+    radius: int
+
+fn main() -> int:
+    let c = Circle{{3}}
+    total(&c, &c)
+        "#, shapes)).is_err()
+	);
+
+	// Unknown function on the interface
+	assert!(type_check_mod(&format!(r#"{}
+fn perimeter(s: Shape) -> int: s.perimeter()
+
+fn main() -> int: 0
+        "#, shapes)).is_err()
+	);
+
+	// Self outside of the self argument cannot be used through an interface object
+	assert!(type_check_mod(r#"
+interface Comparable:
+    fn less(self, other: *Self) -> bool
+
+struct Number:
+    value: int
+
+fn Number.less(self, other: *Number) -> bool: self.value < other.value
+
+fn main() -> int:
+    let n = Number{3}
+    let c = &n as Comparable
+    0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_generic_constraints()
+{
+	let shapes = r#"
+interface Shape:
+    fn area(self) -> int
+
+struct Square:
+    size: int
+
+struct Circle:
+    radius: int
+
+fn Square.area(self) -> int: self.size * self.size
+
+fn larger(a: $T: Shape, b: $T) -> int: if a.area() > b.area(): a.area() else b.area()
+"#;
+
+	assert!(type_check_mod(&format!(r#"{}
+fn main() -> int: larger(Square{{2}}, Square{{3}})
+        "#, shapes)).is_ok()
+	);
+
+	// Circle does not implement Shape
+	let r = type_check_mod(&format!(r#"{}
+fn main() -> int: larger(Circle{{2}}, Circle{{3}})
+        "#, shapes));
+	assert!(format!("{}", r.unwrap_err()).contains("which $T requires"));
+
+	// Both arguments have to be the same type
+	assert!(type_check_mod(&format!(r#"{}
+fn main() -> int: larger(Square{{2}}, Circle{{3}})
+        "#, shapes)).is_err()
+	);
+
+	// Only interfaces can be constraints
+	assert!(type_check_mod(r#"
+fn foo(a: $T: int) -> int: 0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_array_length_generics()
+{
+	let sum = r#"
+fn sum(xs: int[$N]) -> int:
+    var total = 0
+    for x in xs:
+        total += x
+    total
+
+fn pick(a: $T[$N], b: $T[$N], i: uint) -> $T: if i < a.len: a[i] else b[0u]
+"#;
+
+	assert!(type_check_mod(&format!(r#"{}
+fn main() -> int:
+    let a = [1, 2, 3]
+    let b = [4, 5, 6, 7]
+    sum(a) + sum(b) + pick(a, a, 1u)
+        "#, sum)).is_ok()
+	);
+
+	// $N has to be the same length for both arguments
+	assert!(type_check_mod(&format!(r#"{}
+fn main() -> int: pick([1, 2], [3, 4, 5], 0u)
+        "#, sum)).is_err()
+	);
+
+	// A fixed length has to match exactly
+	assert!(type_check_mod(r#"
+fn first(xs: $T[3]) -> $T: xs[0u]
+
+fn main() -> int: first([1, 2, 3, 4])
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_generic_sum_types()
+{
+	let result = r#"
+type Result<$v, $e> = Ok{value: $v} | Error{error: $e}
+
+fn get(r: Result<int, bool>) -> int:
+    match r:
+        Ok{v} => v
+        Error{_} => 0
+"#;
+
+	assert!(type_check_mod(&format!(r#"{}
+fn main() -> int: get(Ok{{3}}) + get(Error{{true}})
+        "#, result)).is_ok()
+	);
+
+	// Wrong type for an instantiated parameter
+	assert!(type_check_mod(&format!(r#"{}
+fn main() -> int: get(Ok{{true}})
+        "#, result)).is_err()
+	);
+
+	// Matches on an instantiated sum type must still handle every case
+	assert!(type_check_mod(r#"
+type Option<$a> = Some{value: $a} | None
+
+fn get(o: Option<int>) -> int:
+    match o:
+        Some{v} => v
+
+fn main() -> int: get(None)
+        "#).is_err()
+	);
+
+	// Generic not declared as a parameter
+	assert!(type_check_mod(r#"
+type Option<$a> = Some{value: $b} | None
+
+fn main() -> int: 0
+        "#).is_err()
+	);
+
+	// Wrong number of type arguments
+	assert!(type_check_mod(r#"
+type Option<$a> = Some{value: $a} | None
+
+fn get(o: Option<int, int>) -> int: 0
+
+fn main() -> int: 0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_optional_member_access()
+{
+	let node = r#"
+struct Leaf:
+    value: int
+
+struct Node:
+    value: int
+    leaf: ?*Leaf
+
+fn Node.get(self) -> int: self.value
+"#;
+
+	assert!(type_check_mod(&format!(r#"{}
+fn leaf_value(n: ?*Node) -> ?int:
+    let leaf = n?.leaf
+    leaf?.value
+
+fn get(n: ?*Node) -> ?int: n?.get()
+        "#, node)).is_ok()
+	);
+
+	// The result is optional
+	assert!(type_check_mod(&format!(r#"{}
+fn value(n: ?*Node) -> int: n?.value
+        "#, node)).is_err()
+	);
+
+	// Only on optionals
+	assert!(type_check_mod(&format!(r#"{}
+fn value(n: *Node) -> ?int: n?.value
+        "#, node)).is_err()
+	);
+}
+
+#[test]
+fn test_index_operation()
+{
+	assert!(type_check_mod(r#"
+fn first(s: string, data: int[], array: int[3]) -> int:
+    let c = s[0]
+    data[0] + array[1] + c as int
+        "#).is_ok()
+	);
+
+	// Strings cannot be changed
+	assert!(type_check_mod(r#"
+fn clear(s: string) -> int:
+    s[0] = 0u as uint8
+    0
+        "#).is_err()
+	);
+
+	// Only integers can be used as index
+	assert!(type_check_mod(r#"
+fn first(data: int[]) -> int: data[true]
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_slice_operation()
+{
+	assert!(type_check_mod(r#"
+fn tail(data: int[], array: int[3], n: int) -> int[]:
+    let a = array[1..n]
+    data[1u..data.len]
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn tail(data: int[]) -> int[]: data[1..true]
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn tail(s: string) -> int[]: s[1..2]
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn tail(s: string) -> string: s[1u..s.len]
+        "#).is_ok()
+	);
+}
+
+#[test]
+fn test_assert_eq()
+{
+	assert!(type_check_mod(r#"
+fn check(data: int[], s: string, c: char):
+    @assert_eq(data, data[0..1])
+    @assert_eq(s, "foo")
+    @assert_eq(c, 'c')
+    @assert_eq(data.len, 3u)
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn check(s: string):
+    @assert_eq(s, 1)
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn check(data: string[]):
+    @assert_eq(data, data)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_argument_patterns()
+{
+	assert!(type_check_mod(r#"
+struct Point:
+    x: int
+    y: int
+
+fn dist(Point{x, y}, (a, (b, _)): {int, {int, bool}}) -> int:
+    x * y + a * b
+
+fn main() -> int:
+    dist(Point{1, 2}, {3, {4, true}})
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+struct Point:
+    x: int
+    y: int
+
+fn sum(Point{x, y, z}) -> int:
+    x + y + z
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn first((a, b): int) -> int:
+    a
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_ref_arguments()
+{
+	assert!(type_check_mod(r#"
+struct Counter:
+    count: int
+
+fn bump(ref c: Counter, ref total: int, n: int):
+    c.count += n
+    total += n
+
+fn pass_on(ref total: int):
+    var c = Counter{0}
+    bump(c, total, 2)
+
+fn main() -> int:
+    var total = 0
+    pass_on(total)
+    total
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn inc(ref n: int):
+    n += 1
+
+fn main() -> int:
+    let n = 0
+    inc(n)
+    n
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn inc(ref n: int):
+    n += 1
+
+fn main() -> int:
+    var n = 0
+    inc(n + 1)
+    n
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn clear(ref a: int[4]):
+    a[0] = 0
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_assert_and_panic()
+{
+	assert!(type_check_mod(r#"
+fn check(n: int, p: ?*int):
+    @assert(n > 0)
+    @assert(p, "p is nil")
+    @assert(n < 10, "n = ", n)
+    if n == 5:
+        @panic("n is ", n)
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn check(n: int):
+    @assert(n, "n is not a bool")
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+struct Foo:
+    x: int
+
+fn check(foo: Foo):
+    @panic("bad foo: ", foo)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_log()
+{
+	assert!(type_check_mod(r#"
+fn check(x: int, name: string):
+    @debug("x = ", x)
+    @error(name, " failed")
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+struct Foo:
+    x: int
+
+fn check(foo: Foo):
+    @info("foo = ", foo)
+        "#).is_err()
+	);
+}
+
+#[test]
+fn test_build_info()
+{
+	use std::collections::HashMap;
+	use parser::parse_str;
+	use buildinfo::{build_info, build_info_globals};
+
+	let target = Target::new(IntSize::I32, "");
+	let mut defines = HashMap::new();
+	defines.insert("channel".to_string(), "beta".to_string());
+	let globals = build_info_globals(&build_info("1.0", "debug", "", &defines));
+
+	let check = |code: &str| {
+		let mut pkg = parse_str(code, "test", &target).expect("Parsing failed");
+		pkg.add_constant_globals(&globals);
+		pkg.type_check(&target)
+	};
+
+	assert!(check(r#"
+fn version() -> string: @build_info("version")
+fn channel() -> string: @build_info("channel")
+        "#).is_ok()
+	);
+
+	assert!(check(r#"
+fn version() -> int: @build_info("version")
+        "#).is_err()
+	);
+
+	assert!(check(r#"
+fn commit() -> string: @build_info("commit")
+        "#).is_err()
+	);
+}
+
+fn type_check_with_import(lib: &str, code: &str) -> CompileResult<()>
+{
+	let target = Target::new(IntSize::I32, "");
+	let mut lib_md = parse_str(lib, "shapes", &target).expect("Parsing failed").modules.remove("shapes").expect("No module named shapes");
+	type_check_module(&mut lib_md, &target, &ImportMap::new()).expect("Type check failed");
+
+	let mut imports = ImportMap::new();
+	imports.insert("shapes".into(), Rc::new(lib_md.get_exported_symbols(&target)));
+	let mut md = th_mod(code, &target);
+	let r = type_check_module(&mut md, &target, &imports);
+	println!("result: {:?}", r);
+	r
+}
+
+#[test]
+fn test_private_declarations()
+{
+	let lib = r#"
+pub struct Square:
+    side: int
+
+struct Circle:
+    radius: int
+
+fn area(s: Square) -> int: s.side * s.side
+
+pub fn Square.area(self) -> int: area(*self)
+
+pub fn square(side: int) -> Square: Square{side}
+        "#;
+
+	assert!(type_check_with_import(lib, r#"
+fn main() -> int: square(4).area()
+        "#).is_ok()
+	);
+
+	assert!(type_check_with_import(lib, r#"
+fn main() -> int: Square{4}.side
+        "#).is_ok()
+	);
+
+	let r = type_check_with_import(lib, r#"
+fn main() -> int: area(square(4))
+        "#);
+	assert!(format!("{}", r.unwrap_err()).contains("area is private to module shapes"));
+
+	let r = type_check_with_import(lib, r#"
+fn main() -> int: Circle{4}.radius
+        "#);
+	assert!(format!("{}", r.unwrap_err()).contains("Circle is private to module shapes"));
+
+	let r = type_check_with_import(lib, r#"
+fn grow(c: Circle) -> int: c.radius + 1
+        "#);
+	assert!(format!("{}", r.unwrap_err()).contains("private to module shapes"));
+}
+
+#[test]
+fn test_arrays_of_declared_types()
+{
+	assert!(type_check_mod(r#"
+enum Op:
+	Push
+	Halt
+
+struct Point:
+	x: int
+	y: int
+
+fn count(code: Op[]) -> int:
+	var n = 0
+	for op in code:
+		match op:
+			Push => n += 1
+			Halt => n += 0
+	n
+
+fn first(points: Point[2]) -> int: points[0].x
+
+fn main() -> int: count([Push, Halt, Push]) + first([Point{1, 2}, Point{3, 4}])
+"#).is_ok());
+}
+
+#[test]
+fn test_escaping_closures()
+{
+	assert!(type_check_mod(r#"
+fn apply(x: int, f: fn(int) -> int) -> int: f(x)
+
+fn main() -> int:
+    let base = 10
+    let add_base = fn(x: int) -> x + base
+    apply(1, add_base) + apply(2, fn(x: int) -> x * base)
+        "#).is_ok()
+	);
+
+	assert!(type_check_mod(r#"
+fn adder(base: int) -> fn(int) -> int:
+    fn(x: int) -> x + base
+
+fn main() -> int: 0
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+fn adder(base: int) -> fn(int) -> int:
+    let f = fn(x: int) -> x + base
+    f
+
+fn main() -> int: 0
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+var handler = fn(x: int) -> x
+
+fn install(base: int) -> int:
+    handler = fn(x: int) -> x + base
+    0
+
+fn main() -> int: install(1)
+        "#).is_err()
+	);
+
+	assert!(type_check_mod(r#"
+struct Handler:
+    f: fn(int) -> int
+
+fn handler(base: int) -> Handler:
+    Handler{fn(x: int) -> x + base}
+
+fn main() -> int: 0
+        "#).is_err()
+	);
+
+	// Without captures, a lambda is a plain function, which can be returned
+	assert!(type_check_mod(r#"
+fn doubler() -> fn(int) -> int:
+    fn(x: int) -> x * 2
+
+fn main() -> int: doubler()(2)
+        "#).is_ok()
+	);
+}
@@ -1,13 +1,18 @@
 use std::ops::Deref;
+use std::mem;
+use std::collections::HashSet;
 use ast::*;
-use compileerror::{CompileResult, CompileError, type_error, unknown_type_result, unknown_name, type_error_result};
+use compileerror::{CompileResult, CompileError, Diagnostics, type_error, unknown_type_result, unknown_name, type_error_result, print_warning};
 use super::typecheckercontext::{TypeCheckerContext, ImportSymbolResolver};
 use super::instantiategenerics::instantiate_generics;
 use super::typeresolver::{resolve_type, resolve_types, TypeResolved};
 use super::matchchecker::check_match_is_exhaustive;
+use super::unusedchecker::check_unused_values;
+use super::escapechecker::check_escaping_closures;
 use super::genericmapper::fill_in_generics;
-use super::instantiate::make_concrete;
+use super::instantiate::{make_concrete, matches_function_signature, satisfies_interface};
 use target::Target;
+use buildinfo::build_info_global_name;
 use span::Span;
 
 #[derive(Debug)]
@@ -199,7 +204,9 @@ fn type_check_binary_op(ctx: &mut TypeCheckerContext, b: &mut BinaryOp, target:
 fn type_check_array_literal(ctx: &mut TypeCheckerContext, a: &mut ArrayLiteral, target: &Target) -> TypeCheckResult
 {
     if a.elements.is_empty() {
-        a.array_type = array_type(target.native_uint_type.clone(), 0);
+        if a.array_type == Type::Unknown {
+            a.array_type = array_type(target.native_uint_type.clone(), 0);
+        }
         return valid(a.array_type.clone());
     }
 
@@ -252,12 +259,24 @@ fn resolve_generic_args_in_call(ctx: &mut TypeCheckerContext, ft: &FuncType, c:
 }
 
 
+fn warn_if_deprecated(ctx: &TypeCheckerContext, name: &str, span: &Span)
+{
+    if let Some(msg) = ctx.get_deprecated(name) {
+        if msg.is_empty() {
+            print_warning(&format!("{} is deprecated", name), span);
+        } else {
+            print_warning(&format!("{} is deprecated: {}", name, msg), span);
+        }
+    }
+}
+
 fn type_check_call(ctx: &mut TypeCheckerContext, c: &mut Call, target: &Target) -> TypeCheckResult
 {
     let resolved = ctx.resolve(&c.callee.name)
-        .ok_or_else(|| unknown_name(&c.callee.span, format!("Unknown call {}", c.callee.name)))?;
+        .ok_or_else(|| ctx.unknown_name(&c.callee.span, &c.callee.name, format!("Unknown call {}", c.callee.name)))?;
 
     c.callee.name = resolved.name;
+    warn_if_deprecated(ctx, &c.callee.name, &c.span);
     if let Type::Func(ref ft) = resolved.typ
     {
         if ft.args.len() != c.args.len() {
@@ -265,6 +284,10 @@ fn type_check_call(ctx: &mut TypeCheckerContext, c: &mut Call, target: &Target)
                 format!("Attempting to call {} with {} arguments, but it needs {}", c.callee.name, c.args.len(), ft.args.len()));
         }
 
+        for &idx in &resolved.ref_args {
+            pass_by_reference(ctx, &mut c.args[idx])?;
+        }
+
         let arg_types = resolve_generic_args_in_call(ctx, ft, c, target)?;
         for (idx, arg) in c.args.iter_mut().enumerate()
         {
@@ -286,12 +309,79 @@ fn type_check_call(ctx: &mut TypeCheckerContext, c: &mut Call, target: &Target)
     }
 }
 
+// A ref argument is a pointer to a mutable variable of the caller, a ref argument of the caller itself is passed on
+fn pass_by_reference(ctx: &mut TypeCheckerContext, arg: &mut Expression) -> CompileResult<()>
+{
+    match *arg {
+        Expression::NameRef(ref mut nr) => {
+            if reference_as_pointer(ctx, nr) {
+                return Ok(());
+            }
+
+            if !ctx.resolve(&nr.name).map(|s| s.mutable).unwrap_or(false) {
+                return type_error_result(&nr.span, format!("Only mutable variables can be passed as ref argument, {} is not mutable", nr.name));
+            }
+        },
+        // Already checked, or the caller passes the address itself
+        Expression::AddressOf(_) => return Ok(()),
+        _ => return type_error_result(&arg.span(), "Only mutable variables can be passed as ref argument"),
+    }
+
+    let inner = mem::replace(arg, Expression::Void);
+    let span = inner.span();
+    *arg = address_of(inner, span);
+    Ok(())
+}
+
+// Use the pointer of a ref argument, instead of the value it points to
+fn reference_as_pointer(ctx: &TypeCheckerContext, nr: &mut NameRef) -> bool
+{
+    match ctx.resolve(&nr.name) {
+        Some(Symbol{symbol_type: SymbolType::Reference, name, typ, ..}) => {
+            nr.name = name;
+            nr.typ = typ;
+            true
+        },
+        _ => false,
+    }
+}
+
+// The argument of fn dist(Point{x, y}) -> float is named after its pattern, the body starts with binding the pattern to it
+fn destructure_arguments(fun: &mut Function)
+{
+    let span = fun.expression.span();
+    let patterns = mem::replace(&mut fun.arg_patterns, Vec::new());
+    let mut destructured = Vec::with_capacity(patterns.len());
+    for (name, pattern) in patterns {
+        let arg = fun.sig.args.iter().find(|a| a.name == name).expect("Internal Compiler Error: pattern of an unknown argument");
+        let init = Expression::NameRef(NameRef::new(name.clone(), arg.span.clone()));
+        destructured.push(binding(pattern, init, arg.mutable, arg.span.clone()));
+    }
+
+    let body = mem::replace(&mut fun.expression, Expression::Void);
+    fun.expression = block(vec![bindings(destructured, span.clone()), body], span);
+}
+
 pub fn type_check_function(ctx: &mut TypeCheckerContext, fun: &mut Function, target: &Target) -> CompileResult<()>
 {
+    if !fun.arg_patterns.is_empty() {
+        destructure_arguments(fun);
+    }
+
+    ctx.set_function_name(Some(fun.sig.name.clone()));
     ctx.enter_scope(Some(fun.sig.return_type.clone()));
     for arg in &mut fun.sig.args
     {
-        ctx.add(Symbol::new(&arg.name, &arg.typ, arg.mutable, &arg.span, SymbolType::Normal))?;
+        if arg.by_ref {
+            if let Type::Pointer(ref inner) = arg.typ {
+                if let Type::Array(_) = **inner {
+                    return type_error_result(&arg.span, format!("Ref argument {} cannot be an array, pass a slice to modify its elements", arg.name));
+                }
+            }
+            ctx.add(Symbol::new(&arg.name, &arg.typ, true, &arg.span, SymbolType::Reference))?;
+        } else {
+            ctx.add(Symbol::new(&arg.name, &arg.typ, arg.mutable, &arg.span, SymbolType::Normal))?;
+        }
     }
 
     let et = match type_check_expression(ctx, &mut fun.expression, Some(&fun.sig.return_type), target)
@@ -305,6 +395,7 @@ pub fn type_check_function(ctx: &mut TypeCheckerContext, fun: &mut Function, tar
     };
 
     ctx.exit_scope();
+    ctx.set_function_name(None);
     if et != fun.sig.return_type {
         if let Some(expression) = fun.sig.return_type.convert(&et, &fun.expression) {
             fun.expression = expression;
@@ -314,6 +405,7 @@ pub fn type_check_function(ctx: &mut TypeCheckerContext, fun: &mut Function, tar
         }
     }
 
+    check_escaping_closures(ctx, fun)?;
     fun.type_checked = true;
     Ok(())
 }
@@ -334,6 +426,16 @@ fn is_result_mutable(ctx: &TypeCheckerContext, e: &Expression) -> bool
     }
 }
 
+// Whether control never reaches the end of an expression, because it returns
+fn diverges(e: &Expression) -> bool
+{
+    match *e {
+        Expression::Return(_) => true,
+        Expression::Block(ref b) => b.expressions.last().map(diverges).unwrap_or(false),
+        _ => false,
+    }
+}
+
 fn type_check_match(ctx: &mut TypeCheckerContext, m: &mut MatchExpression, target: &Target) -> TypeCheckResult
 {
     let target_type = type_check_expression(ctx, &mut m.target, None, target)?;
@@ -343,8 +445,13 @@ fn type_check_match(ctx: &mut TypeCheckerContext, m: &mut MatchExpression, targe
     for c in &mut m.cases
     {
         let infer_case_type = |ctx: &mut TypeCheckerContext, e: &mut Expression, return_type: &Type| {
-            let tt = type_check_expression(ctx, e, None, target)?;
-            if *return_type != Type::Unknown && *return_type != tt {
+            // The type of the previous cases is the hint, so a case of a generic sum type gets the same type parameters
+            let hint = if *return_type != Type::Unknown {Some(return_type)} else {None};
+            let tt = type_check_expression(ctx, e, hint, target)?;
+            if diverges(e) {
+                // A case which returns has no value, so the other cases determine the type
+                Ok(return_type.clone())
+            } else if *return_type != Type::Unknown && *return_type != tt {
                 type_error_result(&e.span(), "Expressions in match statements must return the same type")
             } else {
                 Ok(tt)
@@ -426,7 +533,7 @@ fn type_check_match(ctx: &mut TypeCheckerContext, m: &mut MatchExpression, targe
 
             Pattern::Struct(ref mut p) => {
                 ctx.enter_scope(None);
-                type_check_struct_pattern(ctx, p, target_is_mutable)?;
+                type_check_struct_pattern(ctx, p, &target_type, target_is_mutable)?;
                 if p.typ != target_type {
                     return type_error_result(&match_span,
                         format!("Cannot pattern match an expression of type {} with an expression of type {}",
@@ -478,14 +585,43 @@ fn type_check_match(ctx: &mut TypeCheckerContext, m: &mut MatchExpression, targe
     valid(return_type)
 }
 
+// Collects the local variables of the enclosing function which are used in a lambda body
+struct CaptureFinder<'a, 'b: 'a>
+{
+    ctx: &'a TypeCheckerContext<'b>,
+    captures: Vec<Argument>,
+}
+
+impl<'a, 'b> Visitor for CaptureFinder<'a, 'b>
+{
+    type Error = CompileError;
+
+    fn visit_name_ref(&mut self, nr: &NameRef) -> CompileResult<()>
+    {
+        if self.captures.iter().any(|c| c.name == nr.name) {
+            return Ok(());
+        }
+
+        if let Some(s) = self.ctx.resolve_capture(&nr.name) {
+            self.captures.push(Argument::new(nr.name.clone(), s.typ, false, nr.span.clone()));
+        }
+        Ok(())
+    }
+}
+
 fn type_check_lambda_body(ctx: &mut TypeCheckerContext, m: &mut Lambda, target: &Target) -> TypeCheckResult
 {
-    ctx.enter_scope(None);
+    ctx.enter_lambda_scope();
     for arg in &mut m.sig.args {
         ctx.add(Symbol::new(&arg.name, &arg.typ, false, &arg.span, SymbolType::Normal))?;
     }
 
     let return_type = type_check_expression(ctx, &mut m.expr, None, target)?;
+    m.captures = {
+        let mut finder = CaptureFinder{ctx: &*ctx, captures: Vec::new()};
+        finder.visit_expression(&m.expr)?;
+        finder.captures
+    };
     ctx.exit_scope();
     m.set_return_type(return_type);
     valid(m.sig.typ.clone())
@@ -523,7 +659,9 @@ fn is_instantiation_of(concrete_type: &Type, generic_type: &Type) -> bool
 
     match (concrete_type, generic_type)
     {
-        (&Type::Array(ref a), &Type::Array(ref b)) => is_instantiation_of(&a.element_type, &b.element_type),
+        (&Type::Array(ref a), &Type::Array(ref b)) => {
+            (b.generic_len.is_some() || a.len == b.len) && is_instantiation_of(&a.element_type, &b.element_type)
+        },
         (_, &Type::Generic(_)) => true,
         (&Type::Struct(ref a), &Type::Struct(ref b)) => {
             a.members.len() == b.members.len() &&
@@ -538,6 +676,8 @@ fn is_instantiation_of(concrete_type: &Type, generic_type: &Type) -> bool
                 .all(|(ma, mb)| is_instantiation_of(ma, mb))
         }
         (&Type::Sum(ref a), &Type::Sum(ref b)) => {
+            a.name == b.name &&
+            a.cases.len() == b.cases.len() &&
             a.cases.iter()
                 .zip(b.cases.iter())
                 .all(|(ma, mb)| is_instantiation_of(&ma.typ, &mb.typ))
@@ -546,6 +686,18 @@ fn is_instantiation_of(concrete_type: &Type, generic_type: &Type) -> bool
     }
 }
 
+fn type_check_source_location(ctx: &TypeCheckerContext, nr: &NameRef, target: &Target) -> Option<Expression>
+{
+    let lit = match &nr.name[..] {
+        "__file__" => Literal::String(nr.span.clone(), nr.span.file.clone()),
+        "__line__" => Literal::UInt(nr.span.clone(), nr.span.start.line as u64, target.int_size),
+        "__function__" => Literal::String(nr.span.clone(), ctx.get_function_name().unwrap_or("").into()),
+        _ => return None,
+    };
+
+    Some(Expression::Literal(lit))
+}
+
 fn type_check_name(ctx: &mut TypeCheckerContext, nr: &mut NameRef, type_hint: Option<&Type>) -> TypeCheckResult
 {
     if nr.name == "_" {
@@ -556,10 +708,24 @@ fn type_check_name(ctx: &mut TypeCheckerContext, nr: &mut NameRef, type_hint: Op
         return valid(nr.typ.clone()); // We have already determined the type
     }
 
-    let resolved = ctx.resolve(&nr.name)
-        .ok_or_else(|| unknown_name(&nr.span, format!("Unknown name {}", nr.name)))?;
+    let build_info_prefix = build_info_global_name("");
+    let resolved = match ctx.resolve(&nr.name) {
+        Some(resolved) => resolved,
+        None if nr.name.starts_with(&build_info_prefix) => {
+            let key = &nr.name[build_info_prefix.len()..];
+            return type_error_result(&nr.span, format!("Unknown build info {}, use --define {}=value to define it", key, key));
+        },
+        None => return Err(ctx.unknown_name(&nr.span, &nr.name, format!("Unknown name {}", nr.name))),
+    };
     nr.name = resolved.name;
 
+    if let SymbolType::Reference = resolved.symbol_type {
+        if nr.typ.is_unknown() {
+            nr.typ = resolved.typ;
+            return replace_by(dereference(Expression::NameRef(nr.clone()), nr.span.clone()));
+        }
+    }
+
     if let Some(typ) = type_hint {
         if resolved.typ == Type::Unknown {
             return unknown_type_result(&nr.name, typ);
@@ -615,6 +781,48 @@ fn add_struct_bindings(ctx: &mut TypeCheckerContext, b: &mut StructPattern, stru
     Ok(())
 }
 
+// The elements of a tuple pattern are bound to the members of the struct in order, and can be patterns themselves
+fn add_tuple_bindings(ctx: &mut TypeCheckerContext, t: &mut TuplePattern, typ: &Type, mutable: bool) -> CompileResult<()>
+{
+    t.typ = typ.clone();
+    let st = match *typ {
+        Type::Struct(ref st) => st,
+        _ => return type_error_result(&t.span, format!("Cannot bind {} to a tuple pattern, it is not a struct type", typ)),
+    };
+
+    if st.members.len() != t.elements.len() {
+        return type_error_result(&t.span,
+            format!("Wrong number of elements in tuple binding (expecting {}, found {})",
+                st.members.len(), t.elements.len()));
+    }
+
+    for (element, member) in t.elements.iter_mut().zip(st.members.iter()) {
+        match *element {
+            BindingType::Name(ref name) => {
+                if name != "_" {
+                    ctx.add(Symbol::new(name, &member.typ, mutable, &t.span, SymbolType::Normal))?;
+                }
+            },
+
+            BindingType::Struct(ref mut s) => {
+                s.typ = member.typ.clone();
+                match member.typ {
+                    Type::Struct(ref inner) if inner.members.len() == s.bindings.len() => add_struct_bindings(ctx, s, inner, false)?,
+                    Type::Struct(ref inner) => {
+                        return type_error_result(&s.span,
+                            format!("Wrong number of members in struct binding (expecting {}, found {})",
+                                inner.members.len(), s.bindings.len()));
+                    },
+                    _ => return type_error_result(&s.span, format!("Cannot bind {} to a struct pattern, it is not a struct type", member.typ)),
+                }
+            },
+
+            BindingType::Tuple(ref mut inner) => add_tuple_bindings(ctx, inner, &member.typ, mutable)?,
+        }
+    }
+    Ok(())
+}
+
 fn type_check_binding(ctx: &mut TypeCheckerContext, b: &mut Binding, target: &Target) -> TypeCheckResult
 {
     b.typ = type_check_expression(ctx, &mut b.init, None, target)?;
@@ -643,6 +851,10 @@ fn type_check_binding(ctx: &mut TypeCheckerContext, b: &mut Binding, target: &Ta
                 return type_error_result(&b.init.span(), "Expression does not return a struct type");
             }
         },
+
+        BindingType::Tuple(ref mut t) => {
+            add_tuple_bindings(ctx, t, &b.typ, b.mutable)?;
+        },
     }
 
     valid(b.typ.clone())
@@ -754,7 +966,7 @@ fn type_check_struct_members_in_initializer(ctx: &mut TypeCheckerContext, st: &S
         new_members.push(struct_member(&member.name, expected_type));
     }
 
-    Ok(struct_type(&st.name, new_members))
+    Ok(struct_type_with_layout(&st.name, new_members, st.repr_c))
 }
 
 fn type_check_anonymous_struct_initializer(ctx: &mut TypeCheckerContext, si: &mut StructInitializer, target: &Target) -> TypeCheckResult
@@ -769,14 +981,23 @@ fn type_check_anonymous_struct_initializer(ctx: &mut TypeCheckerContext, si: &mu
     valid(si.typ.clone())
 }
 
-fn type_check_struct_initializer(ctx: &mut TypeCheckerContext, si: &mut StructInitializer, target: &Target) -> TypeCheckResult
+fn type_check_struct_initializer(ctx: &mut TypeCheckerContext, si: &mut StructInitializer, type_hint: Option<&Type>, target: &Target) -> TypeCheckResult
 {
     if si.struct_name.is_empty() {
         return type_check_anonymous_struct_initializer(ctx, si, target);
     }
 
-    let resolved = ctx.resolve(&si.struct_name).ok_or_else(|| unknown_name(&si.span, format!("Unknown struct {}", si.struct_name)))?;
+    if si.struct_name == "string" {
+        // string{data, len} builds a string from a pointer to the bytes and their length
+        let string_rep = string_type_representation(target.int_size);
+        type_check_struct_members_in_initializer(ctx, &string_rep, si, target)?;
+        si.typ = Type::String;
+        return valid(Type::String);
+    }
+
+    let resolved = ctx.resolve(&si.struct_name).ok_or_else(|| ctx.unknown_name(&si.span, &si.struct_name, format!("Unknown struct {}", si.struct_name)))?;
     si.struct_name = resolved.name;
+    warn_if_deprecated(ctx, &si.struct_name, &si.span);
     match resolved.typ
     {
         Type::Struct(ref st) => {
@@ -803,14 +1024,24 @@ fn type_check_struct_initializer(ctx: &mut TypeCheckerContext, si: &mut StructIn
             }
 
             si.typ = sum_type(&st.name, sum_type_cases);
+            if let Some(hint) = type_hint {
+                // The other cases of a generic sum type take their types from the expected instantiation
+                if si.typ.is_generic() && !hint.is_generic() && is_instantiation_of(hint, &si.typ) {
+                    si.typ = hint.clone();
+                }
+            }
             valid(si.typ.clone())
         },
 
-        Type::String => {
-            let string_rep = string_type_representation(target.int_size);
-            type_check_struct_members_in_initializer(ctx, &string_rep, si, target)?;
-            si.typ = Type::String;
-            valid(Type::String)
+        Type::Union(ref ut) => {
+            // Like in C, a union initializer initializes the first member
+            if si.member_initializers.len() != 1 || ut.members.is_empty() {
+                return type_error_result(&si.span, format!("Union {} can only be initialized with a value for its first member", si.struct_name));
+            }
+
+            type_check_with_conversion(ctx, &mut si.member_initializers[0], &ut.members[0].typ, target)?;
+            si.typ = resolved.typ.clone();
+            valid(si.typ.clone())
         },
 
         _ => type_error_result(&si.span, format!("{} is not a struct", si.struct_name)),
@@ -825,7 +1056,7 @@ fn find_member_type(members: &[StructMember], member_name: &str, span: &Span) ->
         .enumerate()
         .find(|&(_, m)| m.name == *member_name)
         .map(|(idx, m)| (idx, m.typ.clone()))
-        .ok_or_else(|| unknown_name(span, format!("Unknown struct member {}", member_name)))
+        .ok_or_else(|| unknown_name(span, member_name, format!("Unknown struct member {}", member_name)))
 }
 
 fn member_call_to_call(left: &Expression, call: &Call, int_size: IntSize) -> Expression
@@ -874,7 +1105,7 @@ fn type_check_generic_member_call(ctx: &mut TypeCheckerContext, call: &mut Call,
             Ok(call.return_type.clone())
         },
 
-        GenericType::Restricted(ref interfaces) => {
+        GenericType::Restricted(_, ref interfaces) => {
             for interface in interfaces {
                 if let Some(typ) = check_interface(interface, call) {
                     call.return_type = typ.clone();
@@ -883,8 +1114,53 @@ fn type_check_generic_member_call(ctx: &mut TypeCheckerContext, call: &mut Call,
             }
 
             type_error_result(&call.span, format!("No member function named {}", call.callee.name))
+        },
+
+        GenericType::Length(ref name) => type_error_result(&call.span, format!("${} is an array length, it has no member functions", name)),
+    }
+}
+
+fn uses_self_type(typ: &Type) -> bool
+{
+    match *typ
+    {
+        Type::SelfType => true,
+        Type::Func(ref ft) => ft.args.iter().any(uses_self_type) || uses_self_type(&ft.return_type),
+        _ => typ.get_element_type().map(|et| uses_self_type(&et)).unwrap_or(false),
+    }
+}
+
+// Interface objects erase the concrete type, so apart from the self argument, Self cannot appear in the signatures
+fn check_object_safe(it: &InterfaceType, span: &Span) -> CompileResult<()>
+{
+    for func in &it.functions {
+        if func.args.iter().skip(1).any(|arg| uses_self_type(&arg.typ)) || uses_self_type(&func.return_type) {
+            return type_error_result(span,
+                format!("Interface {} cannot be used as an object type, function {} uses Self outside of the self argument", it.name, func.name));
         }
     }
+    Ok(())
+}
+
+// Member call on an interface object, which is dispatched at runtime
+fn type_check_interface_call(ctx: &mut TypeCheckerContext, call: &mut Call, it: &InterfaceType, target: &Target) -> CompileResult<Type>
+{
+    check_object_safe(it, &call.span)?;
+    let func = it.functions.iter()
+        .find(|f| f.name == call.callee.name)
+        .ok_or_else(|| type_error(&call.span, format!("Interface {} has no member function named {}", it.name, call.callee.name)))?;
+
+    if func.args.len() != call.args.len() + 1 {
+        return type_error_result(&call.span,
+            format!("Attempting to call {} with {} arguments, but it needs {}", call.callee.name, call.args.len(), func.args.len() - 1));
+    }
+
+    for (arg, expected) in call.args.iter_mut().zip(func.args.iter().skip(1)) {
+        type_check_with_conversion(ctx, arg, &expected.typ, target)?;
+    }
+
+    call.return_type = func.return_type.clone();
+    Ok(call.return_type.clone())
 }
 
 fn to_static_function_call(ctx: &mut TypeCheckerContext, sma: &MemberAccess) -> Option<Call> 
@@ -910,8 +1186,59 @@ fn to_static_function_call(ctx: &mut TypeCheckerContext, sma: &MemberAccess) ->
     None
 }
 
+// a?.b becomes: match a: ?v => v.b, nil => nil. So the result is an optional, unless b is void.
+fn type_check_optional_member_access(ctx: &mut TypeCheckerContext, sma: &mut MemberAccess, target: &Target) -> TypeCheckResult
+{
+    let left_type = type_check_expression(ctx, &mut sma.left, None, target)?;
+    if !left_type.is_optional() {
+        return type_error_result(&sma.span, format!("?. can only be used on optionals, not on an expression of type {}", left_type));
+    }
+    let inner_type = left_type.get_element_type().expect("Optional type expected");
+
+    let left_span = sma.left.span();
+    let binding = format!("$optional_{}_{}_{}", left_span.start.line, left_span.start.offset, sma.span.end.offset);
+    let mut on_value = Expression::MemberAccess(Box::new(MemberAccess{
+        left: Expression::NameRef(NameRef::new(binding.clone(), left_span.clone())),
+        right: sma.right.clone(),
+        span: sma.span.clone(),
+        typ: Type::Unknown,
+        optional: false,
+    }));
+
+    ctx.enter_scope(None);
+    ctx.add(Symbol::new(&binding, &inner_type, false, &sma.span, SymbolType::Normal))?;
+    let member_type = type_check_expression(ctx, &mut on_value, None, target);
+    ctx.exit_scope();
+    let member_type = member_type?;
+
+    let (on_value, on_nil) = if member_type == Type::Void {
+        (on_value, Expression::Void)
+    } else if member_type.is_optional() {
+        let inner = member_type.get_element_type().expect("Optional type expected");
+        (on_value, nil_expr_with_type(sma.span.clone(), inner))
+    } else {
+        let typ = optional_type(member_type.clone());
+        (to_optional(on_value, typ), nil_expr_with_type(sma.span.clone(), member_type))
+    };
+
+    let cases = vec![
+        match_case(optional_pattern(binding, left_span.clone()), on_value, sma.span.clone()),
+        match_case(Pattern::Nil(sma.span.clone()), on_nil, sma.span.clone()),
+    ];
+    replace_by(match_expression(sma.left.clone(), cases, sma.span.clone()))
+}
+
 fn type_check_member_access(ctx: &mut TypeCheckerContext, sma: &mut MemberAccess, target: &Target) -> TypeCheckResult
 {
+    if sma.optional {
+        return type_check_optional_member_access(ctx, sma, target);
+    }
+
+    // Members of a ref argument are accessed through its pointer, so they can be modified
+    if let Expression::NameRef(ref mut nr) = sma.left {
+        reference_as_pointer(ctx, nr);
+    }
+
     let left_type = type_check_expression(ctx, &mut sma.left, None, target)?;
     // member access through pointer is the same as a normal member access
     let left_type_ref = if let Type::Pointer(ref inner) = left_type {
@@ -944,6 +1271,17 @@ fn type_check_member_access(ctx: &mut TypeCheckerContext, sma: &mut MemberAccess
             (member_type, None)
         },
 
+        (&mut MemberAccessType::Name(ref mut field), &Type::Union(ref ut)) => {
+            if !ctx.in_unsafe_function() {
+                return type_error_result(&sma.span,
+                    format!("Accessing member {} of union {} is unsafe, and is only allowed in functions marked @unsafe", field.name, ut.name));
+            }
+
+            let (member_idx, member_type) = find_member_type(&ut.members, &field.name, &sma.span)?;
+            field.index = member_idx;
+            (member_type, None)
+        },
+
         (&mut MemberAccessType::Name(ref mut field), &Type::Array(_)) |
         (&mut MemberAccessType::Name(ref mut field), &Type::Slice(_)) |
         (&mut MemberAccessType::Name(ref mut field), &Type::String) => {
@@ -963,16 +1301,42 @@ fn type_check_member_access(ctx: &mut TypeCheckerContext, sma: &mut MemberAccess
             return replace_by(member_call_to_call(&sma.left, call, target.int_size));
         },
 
+        (&mut MemberAccessType::Call(ref mut call), &Type::Opaque(ref ot)) => {
+            let call_name = format!("{}.{}", ot.name, call.callee.name);
+            call.callee.name = call_name;
+            return replace_by(member_call_to_call(&sma.left, call, target.int_size));
+        },
+
         (&mut MemberAccessType::Call(ref mut call), &Type::Sum(ref st)) => {
             let call_name = format!("{}.{}", st.name, call.callee.name);
             call.callee.name = call_name;
             return replace_by(member_call_to_call(&sma.left, call, target.int_size));
         },
 
+        (&mut MemberAccessType::Call(ref mut call), &Type::Int(_)) |
+        (&mut MemberAccessType::Call(ref mut call), &Type::UInt(_)) => {
+            if let Some(intrinsic) = Intrinsic::from_integer_method(&call.callee.name) {
+                let mut args = vec![sma.left.clone()];
+                args.extend(call.args.drain(..));
+                return replace_by(Expression::CompilerCall(CompilerCall::Intrinsic{
+                    intrinsic,
+                    args,
+                    typ: Type::Unknown,
+                    span: sma.span.clone(),
+                }));
+            }
+
+            return type_error_result(&sma.span, format!("Type '{}' has no method named '{}'", left_type_ref, call.callee.name));
+        },
+
         (&mut MemberAccessType::Call(ref mut call), &Type::Generic(ref gt)) => {
             (type_check_generic_member_call(ctx, call, gt)?, None)
         },
 
+        (&mut MemberAccessType::Call(ref mut call), &Type::Interface(ref it)) => {
+            (type_check_interface_call(ctx, call, it, target)?, None)
+        },
+
         _ => {
             return type_error_result(
                 &sma.span,
@@ -988,15 +1352,35 @@ fn type_check_member_access(ctx: &mut TypeCheckerContext, sma: &mut MemberAccess
     valid(sma.typ.clone())
 }
 
-fn type_check_struct_pattern(ctx: &mut TypeCheckerContext, p: &mut StructPattern, target_is_mutable: bool) -> CompileResult<()>
+fn type_check_struct_pattern(ctx: &mut TypeCheckerContext, p: &mut StructPattern, target_type: &Type, target_is_mutable: bool) -> CompileResult<()>
 {
     if !p.typ.is_unknown() {
         return Ok(());
     }
 
-    let resolved = ctx.resolve(&p.name).ok_or_else(|| unknown_name(&p.span, format!("Unknown struct {}", p.name)))?;
+    // The cases of the sum type which is matched can be used without importing the module which declares it
+    let resolved = match ctx.resolve(&p.name) {
+        Some(resolved) => resolved,
+        None => match *target_type {
+            Type::Sum(ref st) => {
+                let case_suffix = format!("::{}", p.name);
+                st.cases.iter()
+                    .find(|c| c.name.ends_with(&case_suffix))
+                    .map(|c| Symbol::new(&c.name, target_type, false, &p.span, SymbolType::Normal))
+                    .ok_or_else(|| ctx.unknown_name(&p.span, &p.name, format!("Unknown struct {}", p.name)))?
+            },
+            _ => return Err(ctx.unknown_name(&p.span, &p.name, format!("Unknown struct {}", p.name))),
+        },
+    };
     p.name = resolved.name.clone();
-    match resolved.typ
+    // Matching an instantiation of a generic sum type binds the members with their concrete types
+    let typ = if resolved.typ.is_generic() && !target_type.is_generic() && is_instantiation_of(target_type, &resolved.typ) {
+        target_type.clone()
+    } else {
+        resolved.typ
+    };
+
+    match typ
     {
         Type::Sum(ref st) => {
             let idx = st.index_of(&p.name).expect("Internal Compiler Error: cannot determine index of sum type case");
@@ -1036,10 +1420,15 @@ fn type_check_block(ctx: &mut TypeCheckerContext, b: &mut Block, type_hint: Opti
         let typ = type_check_expression(ctx, e, type_hint, target)?;
         if idx == num - 1 {
             b.typ = typ;
+        } else if let Expression::Call(ref c) = *e {
+            if ctx.is_must_use(&c.callee.name) {
+                print_warning(&format!("result of {} must be used", c.callee.name), &c.span);
+            }
         }
     }
 
     ctx.exit_scope();
+    check_unused_values(b);
     valid(b.typ.clone())
 }
 
@@ -1050,9 +1439,10 @@ fn type_check_new(ctx: &mut TypeCheckerContext, n: &mut NewExpression, type_hint
     valid(n.typ.clone())
 }
 
-fn type_check_delete(ctx: &mut TypeCheckerContext, d: &mut DeleteExpression, type_hint: Option<&Type>, target: &Target) -> TypeCheckResult
+fn type_check_delete(ctx: &mut TypeCheckerContext, d: &mut DeleteExpression, target: &Target) -> TypeCheckResult
 {
-    let typ = type_check_expression(ctx, &mut d.inner, type_hint, target)?;
+    // The type hint is for the result of the delete, not for the pointer
+    let typ = type_check_expression(ctx, &mut d.inner, None, target)?;
     match typ
     {
         Type::Pointer(_) => valid(Type::Void),
@@ -1073,9 +1463,28 @@ fn type_check_array_to_slice(ctx: &mut TypeCheckerContext, ats: &mut ArrayToSlic
     }
 }
 
+// Returns the thread local global, the expression is a part of
+fn thread_local_root(ctx: &TypeCheckerContext, e: &Expression) -> Option<String>
+{
+    match *e
+    {
+        Expression::NameRef(ref nr) if ctx.is_thread_local(&nr.name) => Some(nr.name.clone()),
+        Expression::MemberAccess(ref ma) => thread_local_root(ctx, &ma.left),
+        Expression::IndexOperation(ref iop) => thread_local_root(ctx, &iop.target),
+        Expression::SliceOperation(ref sop) => thread_local_root(ctx, &sop.target),
+        _ => None,
+    }
+}
+
 fn type_check_address_of(ctx: &mut TypeCheckerContext, a: &mut AddressOfExpression, target: &Target) -> TypeCheckResult
 {
     let t = type_check_expression(ctx, &mut a.inner, None, target)?;
+    if let Some(name) = thread_local_root(ctx, &a.inner) {
+        if !ctx.in_unsafe_function() {
+            return type_error_result(&a.span,
+                format!("Taking the address of thread local global {} is unsafe, and is only allowed in functions marked @unsafe", name));
+        }
+    }
     a.typ = ptr_type(t);
     valid(a.typ.clone())
 }
@@ -1084,6 +1493,9 @@ fn type_check_dereference(ctx: &mut TypeCheckerContext, a: &mut DereferenceExpre
 {
     let t = type_check_expression(ctx, &mut a.inner, None, target)?;
     if let Type::Pointer(inner) = t {
+        if inner.contains_opaque_value() {
+            return type_error_result(&a.span, format!("Cannot dereference a pointer to {}, its layout is unknown", inner));
+        }
         a.typ = inner.deref().clone();
         valid(a.typ.clone())
     } else {
@@ -1104,13 +1516,46 @@ fn type_check_index_operation(ctx: &mut TypeCheckerContext, iop: &mut IndexOpera
         Type::Pointer(ref inner) => inner.deref().clone(),
         Type::Slice(ref st) => st.element_type.clone(),
         Type::Array(ref at) => at.element_type.clone(),
+        // Indexing a string gives the byte at that position
+        Type::String => Type::UInt(IntSize::I8),
         _ => return type_error_result(&iop.span, format!("Cannot an index an expression of type {}", target_type)),
     };
 
+    if typ.contains_opaque_value() {
+        return type_error_result(&iop.span, format!("Cannot index a pointer to {}, its layout is unknown", typ));
+    }
+
     iop.typ = typ.clone();
     Ok(typ)
 }
 
+fn type_check_slice_bound(ctx: &mut TypeCheckerContext, bound: &mut Expression, target: &Target) -> CompileResult<()>
+{
+    let bound_type = type_check_expression(ctx, bound, None, target)?;
+    if bound_type.is_integer() {
+        Ok(())
+    } else {
+        type_error_result(&bound.span(), format!("The bounds of a slice must be integers, not {}", bound_type))
+    }
+}
+
+fn type_check_slice_operation(ctx: &mut TypeCheckerContext, sop: &mut SliceOperation, target: &Target) -> TypeCheckResult
+{
+    let target_type = type_check_expression(ctx, &mut sop.target, None, target)?;
+    type_check_slice_bound(ctx, &mut sop.start, target)?;
+    type_check_slice_bound(ctx, &mut sop.end, target)?;
+
+    sop.typ = match target_type {
+        Type::Slice(ref st) => slice_type(st.element_type.clone()),
+        Type::Array(ref at) => slice_type(at.element_type.clone()),
+        // A part of a string is a string again, the bounds are byte offsets
+        Type::String => Type::String,
+        _ => return type_error_result(&sop.span, format!("Only arrays, slices and strings can be sliced, not an expression of type {}", target_type)),
+    };
+
+    valid(sop.typ.clone())
+}
+
 
 fn to_regular_assign(a: &mut Assign, int_size: IntSize)
 {
@@ -1140,6 +1585,21 @@ fn to_regular_assign(a: &mut Assign, int_size: IntSize)
 
 fn type_check_assign(ctx: &mut TypeCheckerContext, a: &mut Assign, target: &Target) -> TypeCheckResult
 {
+    // Assigning to a ref argument, stores the value in the variable of the caller
+    let stored_through_pointer = match a.left {
+        AssignTarget::Var(ref mut nr) => if reference_as_pointer(ctx, nr) {Some(nr.clone())} else {None},
+        _ => None,
+    };
+
+    if let Some(nr) = stored_through_pointer {
+        let span = nr.span.clone();
+        a.left = AssignTarget::Dereference(DereferenceExpression{
+            inner: Expression::NameRef(nr),
+            typ: Type::Unknown,
+            span,
+        });
+    }
+
     let dst_type = match a.left {
         AssignTarget::Var(ref mut nr) => {
             type_check_name(ctx, nr, None)?;
@@ -1166,7 +1626,11 @@ fn type_check_assign(ctx: &mut TypeCheckerContext, a: &mut Assign, target: &Targ
         }
 
         AssignTarget::IndexOperation(ref mut iop) => {
-            type_check_index_operation(ctx, iop, target)?
+            let typ = type_check_index_operation(ctx, iop, target)?;
+            if iop.target.get_type(target.int_size) == Type::String {
+                return type_error_result(&iop.span, "Strings are immutable, their bytes cannot be assigned");
+            }
+            typ
         }
     };
 
@@ -1226,8 +1690,14 @@ fn type_check_for(ctx: &mut TypeCheckerContext, f: &mut ForLoop, target: &Target
 fn type_check_cast(ctx: &mut TypeCheckerContext, c: &mut TypeCast, target: &Target) -> TypeCheckResult
 {
     let inner_type = type_check_expression(ctx, &mut c.inner, None, target)?;
+    if resolve_type(ctx, &mut c.destination_type) == TypeResolved::No {
+        return type_error_result(&c.span, format!("Unable to resolve type {}", c.destination_type));
+    }
+
     match (inner_type, &c.destination_type)
     {
+        (Type::Int(_), &Type::Int(_)) |
+        (Type::UInt(_), &Type::UInt(_)) |
         (Type::Int(_), &Type::UInt(_)) |
         (Type::Int(_), &Type::Float(_)) |
         (Type::UInt(_), &Type::Int(_)) |
@@ -1238,16 +1708,177 @@ fn type_check_cast(ctx: &mut TypeCheckerContext, c: &mut TypeCast, target: &Targ
         (Type::Pointer(ref from), &Type::Pointer(_)) if *from.deref() == Type::Void => valid(c.destination_type.clone()),
         (Type::Pointer(_), &Type::Bool) => valid(Type::Bool),
         (Type::Array(ref at), &Type::Pointer(ref to)) if at.element_type == *to.deref() => valid(c.destination_type.clone()),
+        (Type::Pointer(ref inner), &Type::Interface(ref it)) => {
+            check_object_safe(it, &c.span)?;
+            satisfies_interface(ctx, inner, &c.destination_type)
+                .map_err(|msg| type_error(&c.span, format!("Type {} does not implement the interface {}: {}", inner.name(), it.name, msg)))?;
+            valid(c.destination_type.clone())
+        },
         (inner_type, _) => type_error_result(&c.span, format!("Cast from type {} to type {} is not allowed", inner_type, c.destination_type))
     }
 }
 
+fn type_check_integer_arg(ctx: &mut TypeCheckerContext, intrinsic: Intrinsic, arg: &mut Expression, span: &Span, target: &Target) -> CompileResult<Type>
+{
+    let typ = type_check_expression(ctx, arg, None, target)?;
+    if typ.is_integer() {
+        Ok(typ)
+    } else {
+        type_error_result(span, format!("@{} expects an integer, not a {}", intrinsic, typ))
+    }
+}
+
+// Checks the byte slice and offset arguments of the unaligned load and store intrinsics
+fn type_check_byte_offset_args(ctx: &mut TypeCheckerContext, args: &mut Vec<Expression>, target: &Target) -> CompileResult<()>
+{
+    type_check_with_conversion(ctx, &mut args[0], &slice_type(Type::UInt(IntSize::I8)), target)?;
+    type_check_with_conversion(ctx, &mut args[1], &target.native_uint_type, target)
+}
+
+fn type_check_intrinsic(ctx: &mut TypeCheckerContext, intrinsic: Intrinsic, args: &mut Vec<Expression>, typ: &mut Type, span: &Span, target: &Target) -> CompileResult<Type>
+{
+    match intrinsic {
+        Intrinsic::Likely | Intrinsic::Unlikely => {
+            type_check_with_conversion(ctx, &mut args[0], &Type::Bool, target)?;
+            Ok(Type::Bool)
+        }
+
+        Intrinsic::Prefetch => {
+            let arg_type = type_check_expression(ctx, &mut args[0], None, target)?;
+            if let Type::Pointer(_) = arg_type {
+                Ok(Type::Void)
+            } else {
+                type_error_result(span, format!("@{} expects a pointer, not a {}", intrinsic, arg_type))
+            }
+        }
+
+        Intrinsic::Select => {
+            type_check_with_conversion(ctx, &mut args[0], &Type::Bool, target)?;
+            let typ = type_check_expression(ctx, &mut args[1], None, target)?;
+            type_check_with_conversion(ctx, &mut args[2], &typ, target)?;
+            match typ {
+                Type::Void | Type::Optional(_) => type_error_result(span, format!("@{} cannot be used with values of type {}", intrinsic, typ)),
+                _ => Ok(typ),
+            }
+        }
+
+        Intrinsic::ConstantTimeEq => {
+            let typ = type_check_expression(ctx, &mut args[0], None, target)?;
+            type_check_with_conversion(ctx, &mut args[1], &typ, target)?;
+            match typ {
+                Type::Slice(ref st) if st.element_type.is_integer() => Ok(Type::Bool),
+                _ => type_error_result(span, format!("@{} expects slices of integers, not {}", intrinsic, typ)),
+            }
+        }
+
+        Intrinsic::ConstantTimeSelect => {
+            type_check_with_conversion(ctx, &mut args[0], &Type::Bool, target)?;
+            let typ = type_check_expression(ctx, &mut args[1], None, target)?;
+            type_check_with_conversion(ctx, &mut args[2], &typ, target)?;
+            if typ.is_integer() {
+                Ok(typ)
+            } else {
+                type_error_result(span, format!("@{} expects integer values, not {}", intrinsic, typ))
+            }
+        }
+
+        Intrinsic::ToLittleEndian |
+        Intrinsic::ToBigEndian |
+        Intrinsic::FromLittleEndian |
+        Intrinsic::FromBigEndian => type_check_integer_arg(ctx, intrinsic, &mut args[0], span, target),
+
+        Intrinsic::LoadLittleEndian |
+        Intrinsic::LoadBigEndian => {
+            if resolve_type(ctx, typ) == TypeResolved::No {
+                return type_error_result(span, format!("Unable to resolve type {}", typ));
+            }
+
+            if !typ.is_integer() {
+                return type_error_result(span, format!("@{} can only load integers, not a {}", intrinsic, typ));
+            }
+
+            type_check_byte_offset_args(ctx, args, target)?;
+            Ok(typ.clone())
+        }
+
+        Intrinsic::StoreLittleEndian |
+        Intrinsic::StoreBigEndian => {
+            type_check_byte_offset_args(ctx, args, target)?;
+            type_check_integer_arg(ctx, intrinsic, &mut args[2], span, target)?;
+            Ok(Type::Void)
+        }
+
+        Intrinsic::CountOnes |
+        Intrinsic::LeadingZeros |
+        Intrinsic::TrailingZeros => {
+            type_check_integer_arg(ctx, intrinsic, &mut args[0], span, target)?;
+            Ok(target.native_uint_type.clone())
+        }
+
+        Intrinsic::RotateLeft |
+        Intrinsic::RotateRight => {
+            let typ = type_check_integer_arg(ctx, intrinsic, &mut args[0], span, target)?;
+            type_check_with_conversion(ctx, &mut args[1], &typ, target)?;
+            Ok(typ)
+        }
+
+        Intrinsic::Random => Ok(Type::UInt(IntSize::I64)),
+
+        Intrinsic::ParseInt => {
+            type_check_with_conversion(ctx, &mut args[0], &Type::String, target)?;
+            Ok(optional_type(target.native_int_type.clone()))
+        }
+
+        Intrinsic::ParseFloat => {
+            type_check_with_conversion(ctx, &mut args[0], &Type::String, target)?;
+            Ok(optional_type(Type::Float(FloatSize::F64)))
+        }
+
+        Intrinsic::IntToString => {
+            type_check_integer_arg(ctx, intrinsic, &mut args[0], span, target)?;
+            type_check_with_conversion(ctx, &mut args[1], &target.native_uint_type, target)?;
+            // The radix must be known at compile time, so a wrong one cannot fail at runtime
+            match args[1] {
+                Expression::Literal(Literal::UInt(_, radix, _)) if radix >= 2 && radix <= 36 => Ok(Type::String),
+                _ => type_error_result(span, format!("The radix of @{} must be a constant between 2 and 36", intrinsic)),
+            }
+        }
+
+        Intrinsic::FloatToString => {
+            let typ = type_check_expression(ctx, &mut args[0], None, target)?;
+            if let Type::Float(_) = typ {
+                type_check_with_conversion(ctx, &mut args[1], &target.native_uint_type, target)?;
+                Ok(Type::String)
+            } else {
+                type_error_result(span, format!("@{} expects a floating point number, not a {}", intrinsic, typ))
+            }
+        }
+
+        Intrinsic::HexEncode => {
+            type_check_with_conversion(ctx, &mut args[0], &slice_type(Type::UInt(IntSize::I8)), target)?;
+            Ok(Type::String)
+        }
+
+        Intrinsic::HexDecode => {
+            type_check_with_conversion(ctx, &mut args[0], &Type::String, target)?;
+            Ok(optional_type(slice_type(Type::UInt(IntSize::I8))))
+        }
+
+        Intrinsic::FromUtf8 => {
+            type_check_with_conversion(ctx, &mut args[0], &slice_type(Type::UInt(IntSize::I8)), target)?;
+            Ok(optional_type(Type::String))
+        }
+    }
+}
+
 fn type_check_compiler_call(ctx: &mut TypeCheckerContext, cc: &mut CompilerCall, type_hint: Option<&Type>, target: &Target) -> TypeCheckResult
 {
     match *cc {
         CompilerCall::SizeOf(ref mut typ, ref span) => {
             if resolve_type(ctx, typ) == TypeResolved::No {
                 type_error_result(span, format!("Unable to resolve type {}", typ))
+            } else if typ.contains_opaque_value() {
+                type_error_result(span, format!("The size of {} is unknown", typ))
             } else {
                 valid(target.native_uint_type.clone())
             }
@@ -1269,6 +1900,178 @@ fn type_check_compiler_call(ctx: &mut TypeCheckerContext, cc: &mut CompilerCall,
                 type_error_result(span, format!("The first argument of @slice, must be a pointer, not a {}", data_type))
             }
         }
+
+        CompilerCall::Fields(query, ref mut typ, ref span) => {
+            if resolve_type(ctx, typ) == TypeResolved::No {
+                return type_error_result(span, format!("Unable to resolve type {}", typ));
+            }
+
+            match *typ {
+                Type::Struct(ref st) => {
+                    if query == FieldQuery::Count {
+                        return replace_by(Expression::Literal(Literal::UInt(span.clone(), st.members.len() as u64, target.int_size)));
+                    }
+
+                    let elements = st.members.iter()
+                        .map(|m| {
+                            let s = if query == FieldQuery::Names {m.name.clone()} else {format!("{}", m.typ)};
+                            Expression::Literal(Literal::String(span.clone(), s))
+                        })
+                        .collect();
+                    // A slice, like in a generic function, where the number of fields is not known
+                    replace_by(array_to_slice(string_array(elements, span), span.clone()))
+                },
+
+                // Expanded when the generic function gets instantiated
+                Type::Generic(_) if query == FieldQuery::Count => valid(target.native_uint_type.clone()),
+                Type::Generic(_) => valid(slice_type(Type::String)),
+
+                _ => type_error_result(span, format!("@{} expects a struct type, not {}", query, typ)),
+            }
+        }
+
+        CompilerCall::FieldValues(ref mut value, ref span) => {
+            let typ = type_check_expression(ctx, value, None, target)?;
+            match typ {
+                Type::Struct(ref st) => {
+                    // The value gets a name which cannot clash with one in the source, so it is only evaluated once
+                    let name = format!("field_values#{}.{}", span.start.line, span.start.offset);
+                    let mut elements = Vec::with_capacity(st.members.len());
+                    for (idx, m) in st.members.iter().enumerate() {
+                        match m.typ {
+                            Type::String | Type::Char | Type::Bool | Type::Int(_) | Type::UInt(_) | Type::Float(_) => (),
+                            _ => return type_error_result(span, format!("@field_values cannot convert member {} of type {} to a string", m.name, m.typ)),
+                        }
+
+                        let member = member_access(Expression::NameRef(NameRef::new(name.clone(), span.clone())), MemberAccessType::Name(field(&m.name, idx)), span.clone());
+                        elements.push(Expression::CompilerCall(CompilerCall::Format{parts: vec![member], span: span.clone()}));
+                    }
+
+                    let value = name_binding(name, value.deref().deref().clone(), false, span.clone());
+                    let values = array_to_slice(string_array(elements, span), span.clone());
+                    replace_by(block(vec![bindings(vec![value], span.clone()), values], span.clone()))
+                },
+
+                // Expanded when the generic function gets instantiated
+                Type::Generic(_) => valid(slice_type(Type::String)),
+
+                _ => type_error_result(span, format!("@field_values expects a struct value, not {}", typ)),
+            }
+        }
+
+        CompilerCall::OffsetOf{ref mut typ, ref field, ref mut index, ref span} => {
+            if resolve_type(ctx, typ) == TypeResolved::No {
+                return type_error_result(span, format!("Unable to resolve type {}", typ));
+            }
+
+            match *typ {
+                Type::Struct(ref st) => {
+                    if !ctx.is_repr_c(&st.name) {
+                        return type_error_result(span, format!("The layout of {} is controlled by the compiler, add @repr(C) to use @offsetof", typ));
+                    }
+
+                    let (member_idx, _) = find_member_type(&st.members, field, span)?;
+                    *index = member_idx;
+                    valid(target.native_uint_type.clone())
+                },
+
+                // Checked when the generic function gets instantiated
+                Type::Generic(_) => valid(target.native_uint_type.clone()),
+
+                _ => type_error_result(span, format!("@offsetof expects a struct type, not {}", typ)),
+            }
+        }
+
+        CompilerCall::Intrinsic{intrinsic, ref mut args, ref mut typ, ref span} => {
+            let result_type = type_check_intrinsic(ctx, intrinsic, args, typ, span, target)?;
+            *typ = result_type.clone();
+            valid(result_type)
+        }
+
+        CompilerCall::Format{ref mut parts, ..} => {
+            type_check_format_parts(ctx, parts, target)?;
+            valid(Type::String)
+        }
+
+        CompilerCall::Log{ref mut parts, ..} => {
+            type_check_format_parts(ctx, parts, target)?;
+            valid(Type::Void)
+        }
+
+        CompilerCall::AssertEq{ref mut left, ref mut right, ref span} => {
+            type_check_assert_eq(ctx, left, right, span, target)
+        }
+
+        CompilerCall::Assert{ref mut cond, ref mut parts, ..} => {
+            type_check_with_conversion(ctx, cond, &Type::Bool, target)?;
+            type_check_format_parts(ctx, parts, target)?;
+            valid(Type::Void)
+        }
+
+        CompilerCall::Panic{ref mut parts, ..} => {
+            type_check_format_parts(ctx, parts, target)?;
+            valid(Type::Void)
+        }
+    }
+}
+
+// An array literal of strings, with its type filled in
+fn string_array(elements: Vec<Expression>, span: &Span) -> Expression
+{
+    let len = elements.len();
+    let mut lit = array_lit(elements, span.clone());
+    if let Literal::Array(ref mut al) = lit {
+        al.array_type = array_type(Type::String, len);
+    }
+    Expression::Literal(lit)
+}
+
+fn type_check_format_parts(ctx: &mut TypeCheckerContext, parts: &mut Vec<Expression>, target: &Target) -> CompileResult<()>
+{
+    for part in parts.iter_mut() {
+        let typ = type_check_expression(ctx, part, None, target)?;
+        match typ {
+            Type::String | Type::Char | Type::Bool | Type::Int(_) | Type::UInt(_) | Type::Float(_) => (),
+            // Checked when the generic function gets instantiated
+            Type::Generic(_) => (),
+            _ => return type_error_result(&part.span(), format!("Expression of type {} cannot be converted to a string", typ)),
+        }
+    }
+    Ok(())
+}
+
+// Both values get printed when the assertion fails, so they must be something @format can handle, or a sequence of those
+fn type_check_assert_eq(ctx: &mut TypeCheckerContext, left: &mut Expression, right: &mut Expression, span: &Span, target: &Target) -> TypeCheckResult
+{
+    let left_type = type_check_expression(ctx, left, None, target)?;
+    let right_type = type_check_expression(ctx, right, Some(&left_type), target)?;
+    if left_type != right_type {
+        let result = convert_type(ctx, &left_type, &right_type, right, target)
+            .or(convert_type(ctx, &right_type, &left_type, left, target));
+        if !result.is_ok() {
+            return type_error_result(span, format!("@assert_eq expects arguments of the same type (left type: {}, right type: {})", left_type, right_type));
+        }
+    }
+
+    let typ = left.get_type(target.int_size);
+    let printable = |t: &Type| match *t {
+        Type::Char | Type::Bool | Type::Int(_) | Type::UInt(_) | Type::Float(_) => true,
+        _ => false,
+    };
+
+    let supported = match typ {
+        Type::String => true,
+        Type::Array(ref at) => printable(&at.element_type),
+        Type::Slice(ref st) => printable(&st.element_type),
+        // Checked when the generic function gets instantiated
+        Type::Generic(_) => true,
+        ref t => printable(t),
+    };
+
+    if supported {
+        valid(Type::Void)
+    } else {
+        type_error_result(span, format!("@assert_eq is not supported on values of type {}", typ))
     }
 }
 
@@ -1314,7 +2117,12 @@ pub fn type_check_expression(ctx: &mut TypeCheckerContext, e: &mut Expression, t
         Expression::BinaryOp(ref mut op) => type_check_binary_op(ctx, op, target),
         Expression::Literal(ref mut lit) => type_check_literal(ctx, lit, type_hint, target),
         Expression::Call(ref mut c) => type_check_call(ctx, c, target),
-        Expression::NameRef(ref mut nr) => type_check_name(ctx, nr, type_hint),
+        Expression::NameRef(ref mut nr) => {
+            match type_check_source_location(ctx, nr, target) {
+                Some(e) => replace_by(e),
+                None => type_check_name(ctx, nr, type_hint),
+            }
+        },
         Expression::Match(ref mut m) => type_check_match(ctx, m, target),
         Expression::Lambda(ref mut l) => type_check_lambda(ctx, l, type_hint, target),
         Expression::Bindings(ref mut l) => {
@@ -1325,10 +2133,10 @@ pub fn type_check_expression(ctx: &mut TypeCheckerContext, e: &mut Expression, t
         },
         Expression::If(ref mut i) => type_check_if(ctx, i, type_hint, target),
         Expression::Block(ref mut b) => type_check_block(ctx, b, type_hint, target),
-        Expression::StructInitializer(ref mut si) => type_check_struct_initializer(ctx, si, target),
+        Expression::StructInitializer(ref mut si) => type_check_struct_initializer(ctx, si, type_hint, target),
         Expression::MemberAccess(ref mut sma) => type_check_member_access(ctx, sma, target),
         Expression::New(ref mut n) => type_check_new(ctx, n, type_hint, target),
-        Expression::Delete(ref mut d) => type_check_delete(ctx, d, type_hint, target),
+        Expression::Delete(ref mut d) => type_check_delete(ctx, d, target),
         Expression::ArrayToSlice(ref mut ats) => type_check_array_to_slice(ctx, ats, type_hint, target),
         Expression::AddressOf(ref mut a) => type_check_address_of(ctx, a, target),
         Expression::Dereference(ref mut d) => type_check_dereference(ctx, d, target),
@@ -1359,9 +2167,12 @@ pub fn type_check_expression(ctx: &mut TypeCheckerContext, e: &mut Expression, t
         Expression::Cast(ref mut t) => type_check_cast(ctx, t, target),
         Expression::CompilerCall(ref mut cc) => type_check_compiler_call(ctx, cc, type_hint, target),
         Expression::IndexOperation(ref mut iop) => valid(type_check_index_operation(ctx, iop, target)?),
+        Expression::SliceOperation(ref mut sop) => type_check_slice_operation(ctx, sop, target),
         Expression::Return(ref mut r) => {
             if let Some(return_type) = ctx.get_function_return_type() {
-                type_check_with_conversion(ctx, &mut r.expression, &return_type, target)?;
+                // Like the body of a function, the return type is the hint, so Error{code} can become a Result<$v>
+                let typ = type_check_expression(ctx, &mut r.expression, Some(&return_type), target)?;
+                convert_type(ctx, &return_type, &typ, &mut r.expression, target)?;
                 valid(Type::Void)
             } else {
                 type_error_result(&r.span, "return expression outside of a function")
@@ -1380,25 +2191,209 @@ pub fn type_check_expression(ctx: &mut TypeCheckerContext, e: &mut Expression, t
     }
 }
 
+fn check_derives(module: &Module) -> CompileResult<()>
+{
+    for td in module.types.values() {
+        let (name, derives, span) = match *td {
+            TypeDeclaration::Struct(ref sd) |
+            TypeDeclaration::Union(ref sd) => (&sd.name, &sd.derives, &sd.span),
+            TypeDeclaration::Sum(ref st) => (&st.name, &st.derives, &st.span),
+            _ => continue,
+        };
+
+        for d in derives {
+            match &d[..] {
+                // The parser generates the functions, and checks the members
+                "Json" => (),
+                _ => return type_error_result(span, format!("Unknown derive {} on type {}", d, name)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_init_and_fini_functions(module: &Module) -> CompileResult<()>
+{
+    for f in module.functions.values() {
+        if f.init && f.fini {
+            return type_error_result(&f.span, format!("Function {} cannot be both @init and @fini", f.sig.name));
+        }
+
+        if (f.init || f.fini) && (!f.sig.args.is_empty() || f.sig.return_type != Type::Void) {
+            let annotation = if f.init {"@init"} else {"@fini"};
+            return type_error_result(&f.span, format!("{} function {} must take no arguments and return nothing", annotation, f.sig.name));
+        }
+
+        if f.test && (f.init || f.fini || f.export) {
+            return type_error_result(&f.span, format!("Test function {} cannot be @init, @fini or @export", f.sig.name));
+        }
+
+        if f.test && (!f.sig.args.is_empty() || f.sig.return_type != Type::Void || f.is_generic()) {
+            return type_error_result(&f.span, format!("@test function {} must take no arguments and return nothing", f.sig.name));
+        }
+    }
+    Ok(())
+}
+
+// Check that a type used by an exported function can be expressed in C
+fn check_c_type(ctx: &TypeCheckerContext, typ: &Type, func: &Function, seen: &mut HashSet<String>) -> CompileResult<()>
+{
+    match *typ
+    {
+        Type::Void | Type::Int(_) | Type::UInt(_) | Type::Float(_) | Type::Char | Type::Bool | Type::Opaque(_) => Ok(()),
+        Type::Pointer(ref inner) => check_c_type(ctx, inner, func, seen),
+        Type::Array(ref at) => check_c_type(ctx, &at.element_type, func, seen),
+        Type::Struct(ref st) if !ctx.is_repr_c(&st.name) => {
+            type_error_result(&func.span, format!("Exported function {} uses {}, which must be marked @repr(C)", func.sig.name, st.name))
+        },
+        Type::Struct(ref st) | Type::Union(ref st) => {
+            if seen.insert(st.name.clone()) {
+                for m in &st.members {
+                    check_c_type(ctx, &m.typ, func, seen)?;
+                }
+            }
+            Ok(())
+        },
+        _ => type_error_result(&func.span, format!("Exported function {} uses {}, which has no C equivalent", func.sig.name, typ)),
+    }
+}
+
+fn check_exported_functions(ctx: &TypeCheckerContext, module: &Module) -> CompileResult<()>
+{
+    for f in module.functions.values().filter(|f| f.export) {
+        if f.is_generic() {
+            return type_error_result(&f.span, format!("Generic function {} cannot be exported", f.sig.name));
+        }
+
+        let mut seen = HashSet::new();
+        for arg in &f.sig.args {
+            if let Type::Array(_) = arg.typ {
+                return type_error_result(&arg.span, format!("Exported function {} cannot take an array by value", f.sig.name));
+            }
+            check_c_type(ctx, &arg.typ, f, &mut seen)?;
+        }
+
+        if f.sig.return_type != Type::Void && !f.sig.return_type.pass_by_value() {
+            return type_error_result(&f.span,
+                format!("Exported function {} cannot return {}, return it through a pointer argument instead", f.sig.name, f.sig.return_type));
+        }
+        check_c_type(ctx, &f.sig.return_type, f, &mut seen)?;
+    }
+    Ok(())
+}
+
+// An impl block has to implement every function of the interface, and nothing else
+fn check_interface_impls(ctx: &TypeCheckerContext, module: &Module) -> CompileResult<()>
+{
+    for imp in &module.impls {
+        let interface = ctx.resolve(&imp.interface).map(|s| s.typ).unwrap_or(Type::Unknown);
+        let it = match interface {
+            Type::Interface(ref it) => it.clone(),
+            _ => return type_error_result(&imp.span, format!("{} is not an interface", imp.interface)),
+        };
+
+        let concrete = ctx.resolve(&imp.typ)
+            .ok_or_else(|| ctx.unknown_name(&imp.span, &imp.typ, format!("Unknown type {}", imp.typ)))?
+            .typ;
+
+        for func in &it.functions {
+            let impl_func = imp.functions.iter()
+                .filter_map(|name| module.functions.get(name))
+                .find(|f| f.sig.name.ends_with(&format!(".{}", func.name)));
+
+            let impl_func = match impl_func {
+                Some(f) => f,
+                None => return type_error_result(&imp.span,
+                    format!("impl of {} for {} is missing function {}", imp.interface, imp.typ, func.name)),
+            };
+
+            if let Err(msg) = matches_function_signature(&func.typ, &impl_func.sig.typ, &concrete, &interface, &func.name) {
+                return type_error_result(&impl_func.span, format!("Type {} does not implement the interface {}: {}", imp.typ, imp.interface, msg));
+            }
+        }
+
+        for name in &imp.functions {
+            if !it.functions.iter().any(|func| name.ends_with(&format!(".{}", func.name))) {
+                let span = module.functions.get(name).map(|f| f.span.clone()).unwrap_or_else(|| imp.span.clone());
+                return type_error_result(&span, format!("Function {} is not a member of interface {}", name, imp.interface));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn register_annotations(ctx: &mut TypeCheckerContext, module: &Module)
+{
+    for f in module.functions.values() {
+        if let Some(ref msg) = f.deprecated {
+            ctx.add_deprecated(&f.sig.name, msg);
+        }
+
+        if f.must_use {
+            ctx.add_must_use(&f.sig.name);
+        }
+
+        if f.unsafe_code {
+            ctx.add_unsafe_function(&f.sig.name);
+        }
+    }
+
+    for global in module.globals.values() {
+        if global.thread_local {
+            ctx.add_thread_local(&global.name);
+        }
+    }
+
+    for td in module.types.values() {
+        if let TypeDeclaration::Struct(StructDeclaration{ref name, repr_c: true, ..}) = *td {
+            ctx.add_repr_c(name);
+        }
+
+        match *td {
+            TypeDeclaration::Struct(StructDeclaration{ref name, deprecated: Some(ref msg), ..}) |
+            TypeDeclaration::Union(StructDeclaration{ref name, deprecated: Some(ref msg), ..}) |
+            TypeDeclaration::Sum(SumTypeDeclaration{ref name, deprecated: Some(ref msg), ..}) => ctx.add_deprecated(name, msg),
+            _ => (),
+        }
+    }
+}
+
+// Errors in a global or function don't stop type checking, so all of them are reported
 pub fn type_check_module(module: &mut Module, target: &Target, imports: &ImportMap) -> CompileResult<()>
 {
+    let mut diagnostics = Diagnostics::new();
+    diagnostics.check(check_derives(module));
+    diagnostics.check(check_init_and_fini_functions(module));
     loop {
         let mut ctx = TypeCheckerContext::new(ImportSymbolResolver::ImportMap(imports));
+        register_annotations(&mut ctx, module);
         resolve_types(&mut ctx, module, target)?;
+        diagnostics.check(check_exported_functions(&ctx, module));
+        diagnostics.check(check_interface_impls(&ctx, module));
 
-        for global in module.globals.values_mut() {
+        // In declaration order, so a global can use the globals declared before it
+        let mut globals: Vec<&mut GlobalBinding> = module.globals.values_mut().collect();
+        globals.sort_by_key(|g| (g.span.start, g.span.end));
+        for global in globals {
             if global.typ == Type::Unknown {
-                global.typ = type_check_expression(&mut ctx, &mut global.init, None, target)?;
-                ctx.add(Symbol::new(&global.name, &global.typ, global.mutable, &global.span, SymbolType::Global))?;
+                if let Some(typ) = diagnostics.check(type_check_expression(&mut ctx, &mut global.init, None, target)) {
+                    global.typ = typ;
+                    diagnostics.check(ctx.add(Symbol::new(&global.name, &global.typ, global.mutable, &global.span, SymbolType::Global)));
+                }
             }
         }
 
         for f in module.functions.values_mut() {
             if !f.type_checked {
-                type_check_function(&mut ctx, f, target)?;
+                diagnostics.check(type_check_function(&mut ctx, f, target));
             }
         }
 
+        // Generics can only be instantiated when everything type checks
+        if !diagnostics.is_empty() {
+            return diagnostics.into_result();
+        }
+
         let count = module.functions.len();
         instantiate_generics(module, &mut ctx, imports, target)?;
         // As long as we are adding new generic functions, we need to type check the module again
@@ -1,11 +1,14 @@
+use std::collections::HashSet;
 use std::collections::hash_map::{HashMap, Entry};
 use ast::*;
 use compileerror::*;
+use span::Span;
 
 struct Scope
 {
     symbols: HashMap<String, Symbol>,
     function_return_type: Option<Type>,
+    lambda: bool,
 }
 
 
@@ -16,6 +19,7 @@ impl Scope
         Scope {
             symbols: HashMap::new(),
             function_return_type,
+            lambda: false,
         }
     }
 
@@ -69,8 +73,10 @@ impl<'a> ImportSymbolResolver<'a>
         match *self {
             ImportSymbolResolver::ImportMap(ref imports) => {
                 for import in imports.values() {
-                    if let Some(s) = import.resolve(name, false) {
-                        return Some(s)
+                    match import.resolve(name, false) {
+                        Some(ref s) if !s.public => (),
+                        Some(s) => return Some(s),
+                        None => (),
                     }
                 }
 
@@ -82,6 +88,20 @@ impl<'a> ImportSymbolResolver<'a>
             }
         }
     }
+
+    // The module of an imported symbol which is not declared with pub
+    pub fn resolve_private(&self, name: &str) -> Option<String>
+    {
+        match *self {
+            ImportSymbolResolver::ImportMap(ref imports) => {
+                imports.values()
+                    .find(|import| import.resolve(name, false).map(|s| !s.public).unwrap_or(false))
+                    .map(|import| import.namespace.clone())
+            }
+
+            ImportSymbolResolver::ExternalImport(_) => None,
+        }
+    }
 }
 
 pub struct TypeCheckerContext<'a>
@@ -90,6 +110,13 @@ pub struct TypeCheckerContext<'a>
     globals: Scope,
     externals: Scope,
     import_resolver: ImportSymbolResolver<'a>,
+    function_name: Option<String>,
+    deprecated: HashMap<String, String>,
+    must_use: HashSet<String>,
+    repr_c: HashSet<String>,
+    unsafe_functions: HashSet<String>,
+    thread_locals: HashSet<String>,
+    generic_params: HashMap<String, Vec<Type>>,
 }
 
 impl<'a> TypeCheckerContext<'a>
@@ -100,7 +127,14 @@ impl<'a> TypeCheckerContext<'a>
             stack: Vec::new(),
             globals: Scope::new(None),
             externals: Scope::new(None),
-            import_resolver: isr
+            import_resolver: isr,
+            function_name: None,
+            deprecated: HashMap::new(),
+            must_use: HashSet::new(),
+            repr_c: HashSet::new(),
+            unsafe_functions: HashSet::new(),
+            thread_locals: HashSet::new(),
+            generic_params: HashMap::new(),
         }
     }
 
@@ -114,6 +148,13 @@ impl<'a> TypeCheckerContext<'a>
         self.stack.push(Scope::new(function_return_type));
     }
 
+    pub fn enter_lambda_scope(&mut self)
+    {
+        let mut scope = Scope::new(None);
+        scope.lambda = true;
+        self.stack.push(scope);
+    }
+
     pub fn exit_scope(&mut self)
     {
         self.stack.pop();
@@ -121,11 +162,18 @@ impl<'a> TypeCheckerContext<'a>
 
     pub fn resolve(&self, name: &str) -> Option<Symbol>
     {
+        let mut in_lambda = false;
         for sf in self.stack.iter().rev() {
-            if let Some(s) = sf.resolve(name) {
+            if let Some(mut s) = sf.resolve(name) {
+                // Lambdas capture variables by value, so they cannot be modified from within the lambda
+                if in_lambda {
+                    s.mutable = false;
+                }
                 return Some(s);
             }
 
+            in_lambda = in_lambda || sf.lambda;
+
             if sf.function_return_type.is_some() {
                 break;
             }
@@ -142,10 +190,38 @@ impl<'a> TypeCheckerContext<'a>
         self.import_resolver.resolve(name)
     }
 
+    // Resolve a name used in the innermost lambda, if it is a local variable of an enclosing scope
+    // The error for a name which cannot be resolved, private symbols of imported modules get their own error
+    pub fn unknown_name<Msg: Into<String>>(&self, span: &Span, name: &str, msg: Msg) -> CompileError
+    {
+        match self.import_resolver.resolve_private(name) {
+            Some(module) => type_error(span, format!("{} is private to module {}, it has to be declared with pub to be used here", name, module)),
+            None => unknown_name(span, name, msg),
+        }
+    }
+
+    pub fn resolve_capture(&self, name: &str) -> Option<Symbol>
+    {
+        let mut in_lambda = false;
+        for sf in self.stack.iter().rev() {
+            if let Some(s) = sf.resolve(name) {
+                return if in_lambda {Some(s)} else {None};
+            }
+
+            if sf.function_return_type.is_some() {
+                break;
+            }
+            in_lambda = in_lambda || sf.lambda;
+        }
+
+        None
+    }
+
     pub fn add(&mut self, symbol: Symbol) -> CompileResult<()>
     {
         match symbol.symbol_type {
-            SymbolType::Normal => {
+            SymbolType::Normal |
+            SymbolType::Reference => {
                 if let Some(ref mut sf) = self.stack.last_mut() {
                     sf.add(symbol)
                 } else {
@@ -163,6 +239,76 @@ impl<'a> TypeCheckerContext<'a>
         }
     }
 
+    pub fn add_deprecated(&mut self, name: &str, msg: &str)
+    {
+        self.deprecated.insert(name.into(), msg.into());
+    }
+
+    pub fn get_deprecated(&self, name: &str) -> Option<&str>
+    {
+        self.deprecated.get(name).map(|msg| &msg[..])
+    }
+
+    pub fn add_must_use(&mut self, name: &str)
+    {
+        self.must_use.insert(name.into());
+    }
+
+    pub fn is_must_use(&self, name: &str) -> bool
+    {
+        self.must_use.contains(name)
+    }
+
+    pub fn add_repr_c(&mut self, name: &str)
+    {
+        self.repr_c.insert(name.into());
+    }
+
+    pub fn is_repr_c(&self, name: &str) -> bool
+    {
+        self.repr_c.contains(name)
+    }
+
+    pub fn add_unsafe_function(&mut self, name: &str)
+    {
+        self.unsafe_functions.insert(name.into());
+    }
+
+    pub fn in_unsafe_function(&self) -> bool
+    {
+        self.function_name.as_ref().map(|n| self.unsafe_functions.contains(n)).unwrap_or(false)
+    }
+
+    pub fn add_thread_local(&mut self, name: &str)
+    {
+        self.thread_locals.insert(name.into());
+    }
+
+    pub fn is_thread_local(&self, name: &str) -> bool
+    {
+        self.thread_locals.contains(name)
+    }
+
+    pub fn add_generic_params(&mut self, name: &str, params: Vec<Type>)
+    {
+        self.generic_params.insert(name.into(), params);
+    }
+
+    pub fn get_generic_params(&self, name: &str) -> Option<&Vec<Type>>
+    {
+        self.generic_params.get(name)
+    }
+
+    pub fn set_function_name(&mut self, name: Option<String>)
+    {
+        self.function_name = name;
+    }
+
+    pub fn get_function_name(&self) -> Option<&str>
+    {
+        self.function_name.as_ref().map(|n| &n[..])
+    }
+
     pub fn get_function_return_type(&self) -> Option<Type>
     {
         for sf in self.stack.iter().rev() {
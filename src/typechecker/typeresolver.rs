@@ -1,9 +1,12 @@
 use std::collections::HashSet;
 use std::ops::Deref;
+use std::rc::Rc;
 use ast::*;
 use target::Target;
-use compileerror::{CompileResult, unknown_name_result};
+use span::Span;
+use compileerror::{CompileResult, type_error_result};
 use super::typecheckercontext::TypeCheckerContext;
+use super::instantiate::make_concrete_type;
 
 #[derive(Eq, PartialEq, Debug)]
 pub enum TypeResolved
@@ -19,15 +22,84 @@ enum ResolveMode
     Forced,
 }
 
+// Generic types used in typ, in the order in which they first appear
+fn collect_generics(typ: &Type, generics: &mut Vec<Type>)
+{
+    match *typ
+    {
+        Type::Generic(_) => {
+            if !generics.contains(typ) {
+                generics.push(typ.clone());
+            }
+        },
+        Type::Pointer(ref inner) | Type::Optional(ref inner) => collect_generics(inner, generics),
+        Type::Array(ref at) => collect_generics(&at.element_type, generics),
+        Type::Slice(ref st) => collect_generics(&st.element_type, generics),
+        Type::Func(ref ft) => {
+            for a in &ft.args {
+                collect_generics(a, generics);
+            }
+            collect_generics(&ft.return_type, generics);
+        },
+        Type::Struct(ref st) => {
+            for m in &st.members {
+                collect_generics(&m.typ, generics);
+            }
+        },
+        Type::Sum(ref st) => {
+            for c in &st.cases {
+                collect_generics(&c.typ, generics);
+            }
+        },
+        _ => (),
+    }
+}
+
+// Instantiate a generic sum type with the type arguments in Name<arg, ...>
+fn resolve_generic_sum_type(ctx: &TypeCheckerContext, name: &str, typ: &Type, generic_args: &[Type]) -> (Option<Type>, TypeResolved)
+{
+    let params = match ctx.get_generic_params(name) {
+        Some(params) => params.clone(),
+        None => {
+            // Declared in another module, so use the generics in the order they appear in the cases
+            let mut params = Vec::new();
+            collect_generics(typ, &mut params);
+            params
+        },
+    };
+
+    if params.len() != generic_args.len() {
+        return (None, TypeResolved::No);
+    }
+
+    let mut mapping = GenericMapping::new();
+    for (param, arg) in params.iter().zip(generic_args.iter()) {
+        let arg = match resolve_type_helper(ctx, arg) {
+            (Some(resolved), TypeResolved::Yes) => resolved,
+            (None, TypeResolved::Yes) => arg.clone(),
+            _ => return (None, TypeResolved::No),
+        };
+        mapping.insert(param.clone(), arg);
+    }
+
+    match make_concrete_type(ctx, &mapping, typ) {
+        Ok(concrete) => (Some(concrete), TypeResolved::Yes),
+        Err(_) => (None, TypeResolved::No),
+    }
+}
+
 fn resolve_type_helper(ctx: &TypeCheckerContext, typ: &Type) -> (Option<Type>, TypeResolved)
 {
     match *typ
     {
         Type::Unresolved(ref ut) => {
-            if let Some(r) = ctx.resolve(&ut.name) {
-                (Some(r.typ.clone()), TypeResolved::Yes)
-            } else {
-                (None, TypeResolved::No)
+            match ctx.resolve(&ut.name)
+            {
+                Some(ref r) if r.typ.is_sum() && r.typ.is_generic() && !ut.generic_args.is_empty() => {
+                    resolve_generic_sum_type(ctx, &r.name, &r.typ, &ut.generic_args)
+                },
+                Some(r) => (Some(r.typ.clone()), TypeResolved::Yes),
+                None => (None, TypeResolved::No),
             }
         },
 
@@ -40,20 +112,47 @@ fn resolve_type_helper(ctx: &TypeCheckerContext, typ: &Type) -> (Option<Type>, T
             }
         },
 
+        Type::Optional(ref inner) => {
+            let r = resolve_type_helper(ctx, inner);
+            if let (Some(typ), TypeResolved::Yes) = r {
+                (Some(optional_type(typ)), TypeResolved::Yes)
+            } else {
+                r
+            }
+        },
+
+        Type::Slice(ref st) => {
+            let r = resolve_type_helper(ctx, &st.element_type);
+            if let (Some(typ), TypeResolved::Yes) = r {
+                (Some(slice_type(typ)), TypeResolved::Yes)
+            } else {
+                r
+            }
+        },
+
+        Type::Array(ref at) => {
+            let r = resolve_type_helper(ctx, &at.element_type);
+            if let (Some(typ), TypeResolved::Yes) = r {
+                (Some(Type::Array(Rc::new(ArrayType{element_type: typ, len: at.len, generic_len: at.generic_len.clone()}))), TypeResolved::Yes)
+            } else {
+                r
+            }
+        },
+
         Type::Generic(ref gt) => {
             match *gt.deref()
             {
                 GenericType::Any(ref name) => {
                     ctx.resolve(name).map(|r| {
                         if let Type::Interface(_) = r.typ {
-                            (Some(generic_type_with_constraints(vec![r.typ])), TypeResolved::Yes)
+                            (Some(generic_type_with_constraints("", vec![r.typ])), TypeResolved::Yes)
                         } else {
                             (None, TypeResolved::Yes)
                         }
                     }).unwrap_or((None, TypeResolved::Yes))
                 },
 
-                GenericType::Restricted(ref interfaces) => {
+                GenericType::Restricted(ref name, ref interfaces) => {
                     let mut new_interfaces = Vec::new();
                     for interface in interfaces {
                         let r = resolve_type_helper(ctx, interface);
@@ -64,8 +163,10 @@ fn resolve_type_helper(ctx: &TypeCheckerContext, typ: &Type) -> (Option<Type>, T
                         }
                     }
 
-                    (Some(generic_type_with_constraints(new_interfaces)), TypeResolved::Yes)
+                    (Some(generic_type_with_constraints(name, new_interfaces)), TypeResolved::Yes)
                 },
+
+                GenericType::Length(_) => (None, TypeResolved::Yes),
             }
         },
 
@@ -85,6 +186,22 @@ pub fn resolve_type(ctx: &TypeCheckerContext, typ: &mut Type) -> TypeResolved
     }
 }
 
+fn opaque_value_error<T>(span: &Span, typ: &Type) -> CompileResult<T>
+{
+    type_error_result(span, format!("The layout of {} is unknown, it can only be used behind a pointer", typ))
+}
+
+// Only interfaces can be constraints of a generic
+fn non_interface_constraint(typ: &Type) -> Option<&Type>
+{
+    if let Type::Generic(ref gt) = *typ {
+        if let GenericType::Restricted(_, ref constraints) = **gt {
+            return constraints.iter().find(|c| if let Type::Interface(_) = **c {false} else {true});
+        }
+    }
+    None
+}
+
 fn resolve_function_args_and_ret_type(ctx: &mut TypeCheckerContext, sig: &mut FunctionSignature, mode: ResolveMode) -> CompileResult<TypeResolved>
 {
     if sig.typ != Type::Unknown {
@@ -92,7 +209,15 @@ fn resolve_function_args_and_ret_type(ctx: &mut TypeCheckerContext, sig: &mut Fu
     }
 
     if resolve_type(ctx, &mut sig.return_type) == TypeResolved::No {
-        return unknown_name_result(&sig.span, format!("Unknown function return type {}", sig.return_type));
+        if mode == ResolveMode::Lazy {
+            return Ok(TypeResolved::No);
+        } else {
+            return Err(ctx.unknown_name(&sig.span, &sig.return_type.to_string(), format!("Unknown function return type {}", sig.return_type)));
+        }
+    }
+
+    if sig.return_type.contains_opaque_value() {
+        return opaque_value_error(&sig.span, &sig.return_type);
     }
 
     let mut args = Vec::with_capacity(sig.args.len());
@@ -101,10 +226,18 @@ fn resolve_function_args_and_ret_type(ctx: &mut TypeCheckerContext, sig: &mut Fu
             if mode == ResolveMode::Lazy {
                 return Ok(TypeResolved::No);
             } else {
-                return unknown_name_result(&arg.span, format!("Unknown function argument type {}", arg.typ));
+                return Err(ctx.unknown_name(&arg.span, &arg.typ.to_string(), format!("Unknown function argument type {}", arg.typ)));
             }
         }
 
+        if arg.typ.contains_opaque_value() {
+            return opaque_value_error(&arg.span, &arg.typ);
+        }
+
+        if let Some(constraint) = non_interface_constraint(&arg.typ) {
+            return type_error_result(&arg.span, format!("Generic {} can only be constrained by interfaces, {} is not an interface", arg.typ, constraint));
+        }
+
         args.push(arg.typ.clone());
     }
 
@@ -125,14 +258,38 @@ fn resolve_struct_member_types(ctx: &mut TypeCheckerContext, sd: &mut StructDecl
             if mode == ResolveMode::Lazy {
                 return Ok(TypeResolved::No);
             } else {
-                return unknown_name_result(&m.span, format!("Unknown struct member type {}", m.typ));
+                return Err(ctx.unknown_name(&m.span, &m.typ.to_string(), format!("Unknown struct member type {}", m.typ)));
             }
         }
 
+        if m.typ.contains_opaque_value() {
+            return opaque_value_error(&m.span, &m.typ);
+        }
+
         member_types.push(struct_member(&m.name, m.typ.clone()));
     }
 
-    sd.typ = struct_type(&sd.name, member_types);
+    sd.typ = struct_type_with_layout(&sd.name, member_types, sd.repr_c);
+    Ok(TypeResolved::Yes)
+}
+
+fn resolve_union_member_types(ctx: &mut TypeCheckerContext, ud: &mut StructDeclaration, mode: ResolveMode) -> CompileResult<TypeResolved>
+{
+    if ud.typ != Type::Unknown {
+        return Ok(TypeResolved::Yes);
+    }
+
+    if resolve_struct_member_types(ctx, ud, mode)? == TypeResolved::No {
+        return Ok(TypeResolved::No);
+    }
+
+    if ud.typ.is_generic() {
+        return type_error_result(&ud.span, format!("Union {} cannot have generic members", ud.name));
+    }
+
+    // Same members as a struct, but they all share the same storage
+    let members = if let Type::Struct(ref st) = ud.typ {st.members.clone()} else {Vec::new()};
+    ud.typ = union_type(&ud.name, members);
     Ok(TypeResolved::Yes)
 }
 
@@ -162,6 +319,23 @@ fn resolve_sum_case_types(ctx: &mut TypeCheckerContext, st: &mut SumTypeDeclarat
         }
     }
 
+    let mut used_generics = Vec::new();
+    for ct in &case_types {
+        collect_generics(&ct.typ, &mut used_generics);
+    }
+
+    if st.generic_params.is_empty() {
+        // Without declared type parameters, the generics are the parameters in the order they appear
+        if !used_generics.is_empty() {
+            ctx.add_generic_params(&st.name, used_generics);
+        }
+    } else {
+        if let Some(g) = used_generics.iter().find(|g| !st.generic_params.contains(g)) {
+            return type_error_result(&st.span, format!("Generic type {} is not a type parameter of {}", g, st.name));
+        }
+        ctx.add_generic_params(&st.name, st.generic_params.clone());
+    }
+
     if case_types.iter().all(|ct| ct.typ == target.native_uint_type)
     {
         let case_names: Vec<String> = st.cases.iter().map(|c| c.name.clone()).collect();
@@ -229,6 +403,20 @@ fn resolve_all_types(ctx: &mut TypeCheckerContext, module: &mut Module, mode: Re
                 }
             },
 
+            TypeDeclaration::Union(ref mut u) => {
+                if resolve_union_member_types(ctx, u, mode)? == TypeResolved::Yes
+                {
+                    ctx.add(Symbol::new(&u.name, &u.typ, false, &u.span, SymbolType::Normal))?;
+                    num_resolved += 1;
+                }
+            },
+
+            TypeDeclaration::Opaque(ref mut o) => {
+                o.typ = opaque_type(&o.name);
+                ctx.add(Symbol::new(&o.name, &o.typ, false, &o.span, SymbolType::Normal))?;
+                num_resolved += 1;
+            },
+
             TypeDeclaration::Sum(ref mut s) => {
                 if resolve_sum_case_types(ctx, s, mode, target)? == TypeResolved::Yes
                 {
@@ -282,7 +470,7 @@ pub fn resolve_types(ctx: &mut TypeCheckerContext, module: &mut Module, target:
 
     for f in module.functions.values_mut() {
         resolve_function_args_and_ret_type(ctx, &mut f.sig, ResolveMode::Forced)?;
-        ctx.add(Symbol::new(&f.sig.name, &f.sig.typ, false, &f.sig.span, SymbolType::Normal))?;
+        ctx.add(Symbol::new(&f.sig.name, &f.sig.typ, false, &f.sig.span, SymbolType::Normal).with_ref_args(f.sig.ref_args()))?;
     }
 
     for f in module.externals.values_mut() {
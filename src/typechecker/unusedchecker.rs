@@ -0,0 +1,81 @@
+use ast::*;
+use compileerror::{CompileResult, print_warning};
+
+fn is_pure(e: &Expression) -> bool
+{
+    match *e
+    {
+        Expression::Literal(Literal::Array(ref a)) => a.elements.iter().all(is_pure),
+        Expression::Literal(_) |
+        Expression::NameRef(_) |
+        Expression::Nil(_) |
+        Expression::Lambda(_) |
+        Expression::CompilerCall(CompilerCall::SizeOf(_, _)) => true,
+        Expression::UnaryOp(ref op) => is_pure(&op.expression),
+        Expression::BinaryOp(ref op) => is_pure(&op.left) && is_pure(&op.right),
+        Expression::Cast(ref c) => is_pure(&c.inner),
+        Expression::OptionalToBool(ref inner) => is_pure(inner),
+        Expression::ToOptional(ref t) => is_pure(&t.inner),
+        Expression::AddressOf(ref a) => is_pure(&a.inner),
+        Expression::StructInitializer(ref si) => si.member_initializers.iter().all(is_pure),
+        Expression::IndexOperation(ref iop) => is_pure(&iop.target) && is_pure(&iop.index_expr),
+        Expression::SliceOperation(ref sop) => is_pure(&sop.target) && is_pure(&sop.start) && is_pure(&sop.end),
+        Expression::MemberAccess(ref ma) => {
+            match ma.right {
+                MemberAccessType::Call(_) => false,
+                _ => is_pure(&ma.left),
+            }
+        },
+        _ => false,
+    }
+}
+
+fn is_used(name: &str, expressions: &[&Expression]) -> bool
+{
+    let mut used = false;
+    for e in expressions {
+        let _ = e.visit(&mut |e: &Expression| -> CompileResult<()> {
+            if let Expression::NameRef(ref nr) = *e {
+                if nr.name == name {
+                    used = true;
+                }
+            }
+            Ok(())
+        });
+    }
+    used
+}
+
+fn check_bindings(bl: &BindingList, rest_of_block: &[Expression])
+{
+    for (idx, b) in bl.bindings.iter().enumerate()
+    {
+        let name = match b.binding_type {
+            BindingType::Name(ref name) if !name.starts_with('_') => name,
+            _ => continue,
+        };
+
+        let following: Vec<&Expression> = bl.bindings[idx + 1..].iter()
+            .map(|b| &b.init)
+            .chain(rest_of_block.iter())
+            .collect();
+        if !is_used(name, &following) {
+            print_warning(&format!("binding {} is never used", name), &b.span);
+        }
+    }
+}
+
+// Warns about expressions in a block whose value is thrown away without any side effect,
+// and bindings which are never referenced afterwards
+pub fn check_unused_values(b: &Block)
+{
+    let num = b.expressions.len();
+    for (idx, e) in b.expressions.iter().enumerate()
+    {
+        if let Expression::Bindings(ref bl) = *e {
+            check_bindings(bl, &b.expressions[idx + 1..]);
+        } else if idx != num - 1 && is_pure(e) {
+            print_warning("result of expression is not used", &e.span());
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use ast::{Function, RANDOM_SEED_VARIABLE};
+use bytecode::{compile_to_byte_code, test_main_function, optimize_module, remove_bounds_checks, OptimizationLevel};
+use compileerror::{CompileResult, CompileError};
+use ide::check_package;
+use llvmbackend::{CodeGenOptions, OutputType, llvm_code_generation, link};
+use package::Package;
+use packagebuild::{BuildOptions, output_file_name};
+
+/*
+Unit tests: cobrac test file.mhr runs every @test function of a file and its modules. A test passes
+when it returns, and fails when it stops the program, for example with a failed @assert_eq.
+
+Every test gets its own binary, with a main which only calls the test, and runs in its own process,
+so a failing test doesn't stop the other ones. The main function of the file itself is not used.
+*/
+
+enum TestResult
+{
+    Passed,
+    Failed(String),
+}
+
+// The @test functions of all modules, in declaration order
+fn test_functions(pkg: &Package) -> Vec<&Function>
+{
+    let mut module_names: Vec<&String> = pkg.modules.keys().collect();
+    module_names.sort();
+
+    let mut tests = Vec::new();
+    for name in module_names {
+        let mut funcs: Vec<&Function> = pkg.modules[name].functions.values().filter(|f| f.test).collect();
+        funcs.sort_by_key(|f| f.span.start);
+        tests.extend(funcs);
+    }
+    tests
+}
+
+// Name of a test without the package name in front
+fn short_name<'a>(pkg: &Package, name: &'a str) -> &'a str
+{
+    if name.starts_with(&pkg.name) && name[pkg.name.len()..].starts_with("::") {
+        &name[pkg.name.len() + 2..]
+    } else {
+        name
+    }
+}
+
+fn build_test(pkg: &Package, test: &Function, build_options: &BuildOptions) -> CompileResult<String>
+{
+    let target = &build_options.target_machine.target;
+    let mut bc_mod = compile_to_byte_code(pkg, target, build_options.eager_globals, build_options.profile.log_level)?;
+    let main_func = test_main_function(&bc_mod, &test.sig.name, target)
+        .ok_or_else(|| CompileError::Other(format!("No code was generated for test {}", test.sig.name)))?;
+    bc_mod.functions.insert(main_func.sig.name.clone(), main_func);
+
+    if !build_options.profile.bounds_checks {
+        remove_bounds_checks(&mut bc_mod);
+    }
+
+    if build_options.profile.optimize {
+        optimize_module(&mut bc_mod, OptimizationLevel::Normal, build_options.profile.inline_threshold);
+    } else {
+        optimize_module(&mut bc_mod, OptimizationLevel::Minimal, build_options.profile.inline_threshold);
+    }
+
+    let binary_name = short_name(pkg, &test.sig.name).replace("::", "_");
+    let opts = CodeGenOptions{
+        dump_ir: build_options.dump_flags.contains("ir") || build_options.dump_flags.contains("all"),
+        build_dir: format!("{}/{}/tests", build_options.build_directory, pkg.name),
+        output_file_name: output_file_name(&binary_name, OutputType::Binary),
+        output_type: OutputType::Binary,
+        optimize: build_options.profile.optimize,
+        debug_info: build_options.profile.debug_info,
+        intern_strings: build_options.profile.intern_strings,
+        merge_strings: build_options.profile.merge_strings,
+        instrument_heap: false,
+        leak_checks: build_options.profile.leak_checks,
+    };
+
+    let ctx = llvm_code_generation(&bc_mod, &build_options.target_machine, &opts)?;
+    link(&ctx, &opts, &pkg.linker_flags)?;
+    Ok(format!("{}/{}", opts.build_dir, opts.output_file_name))
+}
+
+fn run_test(pkg: &Package, test: &Function, build_options: &BuildOptions, seed: u64) -> CompileResult<(TestResult, Duration)>
+{
+    let binary = match build_test(pkg, test, build_options) {
+        Ok(binary) => binary,
+        Err(e) => return Ok((TestResult::Failed(format!("build failed: {}", e)), Duration::from_secs(0))),
+    };
+
+    let start = Instant::now();
+    let output = Command::new(&binary)
+        .env(RANDOM_SEED_VARIABLE, seed.to_string())
+        .output()
+        .map_err(|e| CompileError::Other(format!("Unable to run {}: {}", binary, e)))?;
+    let duration = start.elapsed();
+
+    if output.status.success() {
+        return Ok((TestResult::Passed, duration));
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let msg = match stderr.trim() {
+        "" => format!("program stopped: {}", output.status),
+        msg => msg.into(),
+    };
+    Ok((TestResult::Failed(msg), duration))
+}
+
+fn format_duration(d: Duration) -> String
+{
+    format!("{}.{:03} ms", d.as_secs() * 1000 + u64::from(d.subsec_millis()), d.subsec_micros() % 1000)
+}
+
+// Run all @test functions of a file, returns the number of failed tests
+pub fn run_unit_tests(path: &Path, build_options: &BuildOptions, seed: u64) -> CompileResult<usize>
+{
+    let pkg = check_package(path, &build_options.target_machine.target, &HashMap::new())?;
+    let tests = test_functions(&pkg);
+    if tests.is_empty() {
+        return Err(CompileError::Other(format!("{} has no @test functions", path.to_string_lossy())));
+    }
+
+    println!("random seed: {}", seed);
+    println!("running {} tests", tests.len());
+    let (mut passed, mut failed) = (0, 0);
+    let mut failures = Vec::new();
+    let mut total = Duration::from_secs(0);
+    for test in tests {
+        let name = short_name(&pkg, &test.sig.name);
+        let (result, duration) = run_test(&pkg, test, build_options, seed)?;
+        total += duration;
+        match result {
+            TestResult::Passed => {
+                println!("test {} ... ok ({})", name, format_duration(duration));
+                passed += 1;
+            },
+            TestResult::Failed(msg) => {
+                println!("test {} ... FAILED ({})", name, format_duration(duration));
+                failures.push((name, msg));
+                failed += 1;
+            },
+        }
+    }
+
+    let _ = fs::remove_dir_all(&build_options.build_directory);
+    for (name, msg) in &failures {
+        println!("\n---- {} ----\n{}", name, msg);
+    }
+
+    println!("\nTests: {} passed, {} failed, in {}", passed, failed, format_duration(total));
+    if failed > 0 {
+        println!("To run with the same random seed, pass --seed {}", seed);
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+    use super::format_duration;
+
+    #[test]
+    fn test_format_duration()
+    {
+        assert_eq!(format_duration(Duration::from_millis(0)), "0.000 ms");
+        assert_eq!(format_duration(Duration::new(2, 3_004_000)), "2003.004 ms");
+    }
+}